@@ -0,0 +1,23 @@
+fn main() {
+    // with the `capi` feature, generate the C header of the functions in `src/capi.rs`;
+    // it is written to the build's `OUT_DIR`, as a build script must not modify the
+    // source tree (see the docs for generating `include/oarfish.h` with cbindgen itself)
+    #[cfg(feature = "capi")]
+    {
+        let crate_dir = std::path::PathBuf::from(
+            std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is set by cargo"),
+        );
+        let out_dir =
+            std::path::PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR is set by cargo"));
+        println!("cargo:rerun-if-changed=src/capi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+            .expect("could not read cbindgen.toml");
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(crate_dir.join("src/capi.rs"))
+            .generate()
+            .expect("could not generate the C header of oarfish")
+            .write_to_file(out_dir.join("oarfish.h"));
+    }
+}
@@ -1,5 +1,10 @@
+use crate::util::barcode_tags::BarcodeSource;
 use crate::util::constants::EMPTY_READ_NAME;
+use crate::util::errors;
 use crate::util::oarfish_types::{InMemoryAlignmentStore, TranscriptInfo};
+use crate::util::profile::{self, Stage};
+use crate::util::progress;
+use crate::util::subsample::ReadSubsample;
 use noodles_bam as bam;
 use noodles_sam::header::record::value::map::tag;
 use noodles_sam::{Header, alignment::RecordBuf};
@@ -9,9 +14,14 @@ use std::path::Path;
 use swapvec::SwapVec;
 use tracing::{error, info};
 
+/// Read the header of the BAM file `aln_file` from `reader`, and check that its records
+/// were produced by minimap2 and, unless `allow_coordinate_sorted` is true (as when the
+/// records are sorted by cell barcode before they are quantified), that they are not
+/// sorted by coordinate.
 pub fn read_and_verify_header<R: io::BufRead>(
     reader: &mut bam::io::Reader<R>,
     aln_file: &Path,
+    allow_coordinate_sorted: bool,
 ) -> anyhow::Result<Header> {
     // read the bam file header, print out some basic info
     let header = reader.read_header()?;
@@ -32,12 +42,12 @@ pub fn read_and_verify_header<R: io::BufRead>(
 
         // we have an SO flag, ensure it's not "coordinate"
         if let Some(so_type) = so_type_opt {
-            if so_type == "coordinate" {
+            if so_type == "coordinate" && !allow_coordinate_sorted {
                 error!("oarfish is not designed to process coordinate sorted BAM files.");
-                anyhow::bail!(
-                "You provided a coordinate-sorted BAM, but oarfish does not support processing these.
-                    You should provide a BAM file collated by record name (which is the \"natural\" minimap2 order)."
-            );
+                return Err(errors::bad_input(
+                    "You provided a coordinate-sorted BAM, but oarfish does not support processing these.
+                    You should provide a BAM file collated by record name (which is the \"natural\" minimap2 order).",
+                ));
             }
         } else {
             // we had no SO flag
@@ -73,6 +83,45 @@ pub fn read_and_verify_header<R: io::BufRead>(
     Ok(header)
 }
 
+/// Check that the reference sequences (names and lengths, in order) in the header `other`
+/// of the BAM file `other_file` are those in the header `first` of the BAM file
+/// `first_file`, so that the records of both files can be quantified as a single sample.
+pub fn verify_headers_agree(
+    first: &Header,
+    first_file: &Path,
+    other: &Header,
+    other_file: &Path,
+) -> anyhow::Result<()> {
+    let first_refs = first.reference_sequences();
+    let other_refs = other.reference_sequences();
+    if first_refs.len() != other_refs.len() {
+        return Err(errors::reference_mismatch(format!(
+            "the BAM file {} has {} reference sequences, but {} has {}; all of the alignments must be against the same reference.",
+            other_file.display(),
+            other_refs.len(),
+            first_file.display(),
+            first_refs.len()
+        )));
+    }
+    for (i, ((name_a, map_a), (name_b, map_b))) in
+        first_refs.iter().zip(other_refs.iter()).enumerate()
+    {
+        if name_a != name_b || map_a.length() != map_b.length() {
+            return Err(errors::reference_mismatch(format!(
+                "reference sequence {} of the BAM file {} ({}, length {}) differs from that of {} ({}, length {}); all of the alignments must be against the same reference.",
+                i,
+                other_file.display(),
+                name_b,
+                map_b.length(),
+                first_file.display(),
+                name_a,
+                map_a.length()
+            )));
+        }
+    }
+    Ok(())
+}
+
 pub enum NextAction {
     SkipUnmapped,
     ProcessSameBarcode,
@@ -81,39 +130,17 @@ pub enum NextAction {
 }
 
 #[inline(always)]
-fn is_same_barcode(rec: &RecordBuf, current_barcode: &[u8]) -> anyhow::Result<bool> {
-    const CB_TAG: [u8; 2] = [b'C', b'B'];
-    let same_barcode = match rec.data().get(&CB_TAG) {
-        None => anyhow::bail!("could not get CB tag value"),
-        Some(v) => match v {
-            noodles_sam::alignment::record_buf::data::field::Value::String(x) => {
-                x.as_slice() == current_barcode
-            }
-            _ => anyhow::bail!("CB tag value had unexpected type!"),
-        },
-    };
-    Ok(same_barcode)
+fn is_same_barcode(
+    rec: &RecordBuf,
+    current_barcode: &[u8],
+    bc_source: &BarcodeSource,
+) -> anyhow::Result<bool> {
+    Ok(bc_source.barcode(rec)? == current_barcode)
 }
 
-/// Takes a collection of [RecordBuf]s, a mutable reference to an [InMemoryAlignmentStore]
-/// as well as the relevant [TranscriptInfo].
-///
-/// This function first sorts the `records` by the read name (ensuring that any primary alignment
-/// of the read comes first), so that reads are collated by name.  Then, it processes in turn the
-/// alignments for each input read, filtering them according to the filters attached to the
-/// `store`.  Subsequently, the alignments are summarized in the `store`. If all `records` are
-/// processed successfully, [Ok]`()` is returned, otherwise the relevant [anyhow::Error] is
-/// returned.
-pub fn sort_and_parse_barcode_records(
-    records: &mut Vec<RecordBuf>,
-    store: &mut InMemoryAlignmentStore,
-    txps: &mut [TranscriptInfo],
-    records_for_read: &mut Vec<RecordBuf>,
-) -> anyhow::Result<()> {
-    records_for_read.clear();
-    let mut prev_read = String::new();
-
-    // first sort records by read name
+/// Sort the alignment `records` of a barcode by read name, ensuring that any primary
+/// alignment of a read comes first, so that the records are collated by read.
+pub fn sort_barcode_records(records: &mut [RecordBuf]) {
     records.sort_unstable_by(|x, y| match x.name().cmp(&y.name()) {
         std::cmp::Ordering::Equal => {
             match (x.flags().is_secondary(), y.flags().is_secondary()) {
@@ -126,6 +153,25 @@ pub fn sort_and_parse_barcode_records(
         }
         x => x,
     });
+}
+
+/// Takes a collection of [RecordBuf]s (sorted with [sort_barcode_records]), a mutable
+/// reference to an [InMemoryAlignmentStore] as well as the relevant [TranscriptInfo].
+///
+/// This function processes in turn the alignments for each input read, filtering them
+/// according to the filters attached to the `store`.  Subsequently, the alignments are
+/// summarized in the `store`. The `records` are moved (rather than copied) into the
+/// groups of `records_for_read`, and dropped once they are summarized. If all `records`
+/// are processed successfully, [Ok]`()` is returned, otherwise the relevant
+/// [anyhow::Error] is returned.
+pub fn parse_barcode_records(
+    records: Vec<RecordBuf>,
+    store: &mut InMemoryAlignmentStore,
+    txps: &mut [TranscriptInfo],
+    records_for_read: &mut Vec<RecordBuf>,
+) -> anyhow::Result<()> {
+    records_for_read.clear();
+    let mut prev_read = Vec::<u8>::new();
 
     // Parse the input alignemnt file, gathering the alignments aggregated
     // by their source read. **Note**: this requires that we have a
@@ -136,33 +182,27 @@ pub fn sort_and_parse_barcode_records(
     // moving to the new version of noodles. Track `https://github.com/zaeleus/noodles/issues/230`
     // to see if it's clear why this is the case
     for record in records {
-        if let Some(rname) = record.name() {
-            let record_copy = record.clone();
-            let rstring: String = String::from_utf8_lossy(rname.as_ref()).into_owned();
-            // if this is an alignment for the same read, then
-            // push it onto our temporary vector.
-            if prev_read == rstring {
-                if let Some(_ref_id) = record.reference_sequence_id() {
-                    records_for_read.push(record_copy);
-                }
-            } else {
-                // otherwise, record the alignment range for the
-                // previous read record.
-                if !prev_read.is_empty() {
-                    store.add_group(txps, records_for_read);
-                    if records_for_read.len() == 1 {
-                        store.inc_unique_alignments();
-                    }
-                    records_for_read.clear();
-                }
-                // the new "prev_read" name is the current read name
-                // so it becomes the first on the new alignment range
-                // vector.
-                prev_read = rstring;
-                if let Some(_ref_id) = record.reference_sequence_id() {
-                    records_for_read.push(record_copy);
+        let Some(rname) = record.name() else {
+            continue;
+        };
+        let rname: &[u8] = rname;
+        // if this is not an alignment for the same read, then record the
+        // alignment range for the previous read record, and the current
+        // read name becomes the new "prev_read".
+        if prev_read.as_slice() != rname {
+            if !prev_read.is_empty() {
+                store.add_group(txps, records_for_read);
+                if records_for_read.len() == 1 {
+                    store.inc_unique_alignments();
                 }
+                records_for_read.clear();
             }
+            prev_read.clear();
+            prev_read.extend_from_slice(rname);
+        }
+        // push the alignment onto our temporary vector.
+        if record.reference_sequence_id().is_some() {
+            records_for_read.push(record);
         }
     }
     // if we end with a non-empty alignment range vector, then
@@ -178,9 +218,10 @@ pub fn sort_and_parse_barcode_records(
 }
 
 #[inline(always)]
-pub fn parse_alignments_for_barcode<R: io::BufRead>(
-    iter: &mut core::iter::Peekable<noodles_bam::io::reader::RecordBufs<R>>,
+pub fn parse_alignments_for_barcode<I: Iterator<Item = io::Result<RecordBuf>>>(
+    iter: &mut core::iter::Peekable<I>,
     current_cb: &[u8],
+    bc_source: &BarcodeSource,
 ) -> anyhow::Result<Vec<noodles_sam::alignment::record_buf::RecordBuf>> {
     //records_for_read.clear();
     let mut records_for_barcode =
@@ -205,7 +246,7 @@ pub fn parse_alignments_for_barcode<R: io::BufRead>(
                     _records_processed += 1;
                     NextAction::SkipUnmapped
                 } else {
-                    let same_barcode = is_same_barcode(record, current_cb)?;
+                    let same_barcode = is_same_barcode(record, current_cb, bc_source)?;
                     if !same_barcode {
                         NextAction::NewBarcode
                     } else {
@@ -225,7 +266,7 @@ pub fn parse_alignments_for_barcode<R: io::BufRead>(
             }
             NextAction::ProcessSameBarcode => {
                 let record = iter.next().unwrap()?;
-                records_for_barcode.push(record.clone());
+                records_for_barcode.push(record);
             }
             NextAction::NewBarcode | NextAction::EndOfFile => {
                 break;
@@ -235,6 +276,17 @@ pub fn parse_alignments_for_barcode<R: io::BufRead>(
     Ok(records_for_barcode)
 }
 
+/// The store, read names and transcript information into which the alignments of
+/// (the reads of) one sample are parsed.
+pub struct ParseTarget<'a, 'h> {
+    pub store: &'a mut InMemoryAlignmentStore<'h>,
+    pub name_vec: &'a mut Option<SwapVec<String>>,
+    pub txps: &'a mut [TranscriptInfo],
+}
+
+/// If `track_read_groups` is true, the read group (`RG` tag) of each read is recorded in
+/// `store.read_lanes`, as the index of the read group in the `header`; reads without a
+/// (known) read group are assigned to a group of their own, following those of the header.
 pub fn parse_alignments<R: io::BufRead>(
     store: &mut InMemoryAlignmentStore,
     name_vec: &mut Option<SwapVec<String>>,
@@ -242,48 +294,126 @@ pub fn parse_alignments<R: io::BufRead>(
     reader: &mut bam::io::Reader<R>,
     txps: &mut [TranscriptInfo],
     check_order_thresh: usize,
-    quiet: bool,
+    track_read_groups: bool,
+    subsample: ReadSubsample,
 ) -> anyhow::Result<()> {
+    let mut targets = [ParseTarget {
+        store,
+        name_vec,
+        txps,
+    }];
+    parse_alignments_into(
+        &mut targets,
+        header,
+        reader,
+        check_order_thresh,
+        track_read_groups,
+        subsample,
+        |_rg| Some(0),
+    )?;
+    Ok(())
+}
+
+/// Parse the alignments into `targets`, one per read group of the `header` (in the order of
+/// the header), so that the reads of each read group are quantified as a separate sample.
+/// Returns the number of reads without a (known) read group, which are not parsed.
+pub fn parse_alignments_by_read_group<R: io::BufRead>(
+    targets: &mut [ParseTarget],
+    header: &Header,
+    reader: &mut bam::io::Reader<R>,
+    check_order_thresh: usize,
+    track_read_groups: bool,
+    subsample: ReadSubsample,
+) -> anyhow::Result<u64> {
+    assert_eq!(targets.len(), header.read_groups().len());
+    parse_alignments_into(
+        targets,
+        header,
+        reader,
+        check_order_thresh,
+        track_read_groups,
+        subsample,
+        |rg| rg.map(usize::from),
+    )
+}
+
+/// Parse the alignments from `reader`, adding the alignments of each read to the target
+/// `route(rg)`, where `rg` is the index (in the `header`) of the read group of the read,
+/// if it has a known one; the reads for which `route` returns `None` are skipped, and their
+/// number is returned. Only the reads in the `subsample` are parsed, and parsing stops once
+/// it is exhausted.
+fn parse_alignments_into<R: io::BufRead, F: Fn(Option<u16>) -> Option<usize>>(
+    targets: &mut [ParseTarget],
+    header: &Header,
+    reader: &mut bam::io::Reader<R>,
+    check_order_thresh: usize,
+    track_read_groups: bool,
+    subsample: ReadSubsample,
+    route: F,
+) -> anyhow::Result<u64> {
     //use blart::TreeMap;
-    use rustc_hash::FxHashSet;
+    use noodles_sam::alignment::record_buf::data::field::Value;
+    use rustc_hash::{FxHashMap, FxHashSet};
+    const RG_TAG: [u8; 2] = [b'R', b'G'];
 
-    let mut read_name_map = FxHashSet::default();
+    let mut read_name_map = FxHashSet::<Vec<u8>>::default();
     read_name_map.reserve(check_order_thresh);
     let mut rg_num = 0_usize;
 
     // we'll need these to keep track of which alignments belong
     // to which reads.
-    let mut prev_read = String::new();
+    let mut prev_read = Vec::<u8>::new();
+    // whether the current read is in the subsample
+    let mut keep_read = true;
+    let mut num_seen = 0_u64;
     let mut num_unmapped = 0_u64;
-    let mut records_for_read = vec![];
+    let mut num_skipped = 0_u64;
+    let mut records_for_read = Vec::<RecordBuf>::new();
+    // records whose buffers are reused for the records that follow, so that
+    // (once the pool has grown to the largest alignment group) reading a record
+    // does not allocate
+    let mut spare_records = Vec::<RecordBuf>::new();
 
-    let pb = if quiet {
-        indicatif::ProgressBar::hidden()
-    } else {
-        indicatif::ProgressBar::new_spinner().with_message("Number of alignments processed")
-    };
+    let pb = progress::counter("Number of alignments processed");
 
-    pb.set_style(
-        indicatif::ProgressStyle::with_template(
-            "[{elapsed_precise}] {spinner:4.green/blue} {msg} {human_pos:>12}",
-        )
-        .unwrap()
-        .tick_chars("⠁⠁⠉⠙⠚⠒⠂⠂⠒⠲⠴⠤⠄⠄⠤⠠⠠⠤⠦⠖⠒⠐⠐⠒⠓⠋⠉⠈⠈"),
-    );
-    pb.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(4));
+    let read_group_ids: FxHashMap<&[u8], u16> = header
+        .read_groups()
+        .keys()
+        .enumerate()
+        .map(|(i, id)| (id.as_ref(), i as u16))
+        .collect();
+    let no_read_group = read_group_ids.len() as u16;
+    // the read group of the record `rec`, if it has a known one
+    let read_group_of = |rec: &RecordBuf| match rec.data().get(&RG_TAG) {
+        Some(Value::String(id)) => read_group_ids.get(id.as_slice()).copied(),
+        _ => None,
+    };
 
-    // Adds the read name for the read corresponding to the provided alignment group
-    // `recs`, **if** we are keeping read names for the purpose of reporting read
-    // assignment probabilities.
-    let mut add_read_name = |recs: &Vec<noodles_sam::alignment::record_buf::RecordBuf>| {
-        if let Some(nvec) = name_vec {
-            let first_aln = recs.first().expect("alignment group should be non-empty");
-            let read_name = first_aln
-                .name()
-                .unwrap_or(bstr::BStr::new(EMPTY_READ_NAME))
-                .to_string();
-            nvec.push(read_name)
-                .expect("cannot push name to read name vector");
+    // Adds the read whose alignment group is `recs` (and whose read group is `rg`) to
+    // its target: the alignments, and, **if** we are keeping read names for the purpose
+    // of reporting read assignment probabilities, the read name, and, if we are tracking
+    // read groups, the read group.
+    let add_read = |target: &mut ParseTarget, recs: &mut Vec<RecordBuf>, rg: Option<u16>| {
+        target.store.num_input_reads += 1;
+        let added = profile::time(Stage::Probabilities, || {
+            target.store.add_group(target.txps, recs)
+        });
+        if added {
+            if let Some(nvec) = target.name_vec {
+                let first_aln = recs.first().expect("alignment group should be non-empty");
+                let read_name = first_aln
+                    .name()
+                    .unwrap_or(bstr::BStr::new(EMPTY_READ_NAME))
+                    .to_string();
+                nvec.push(read_name)
+                    .expect("cannot push name to read name vector");
+            }
+            if track_read_groups {
+                target.store.read_lanes.push(rg.unwrap_or(no_read_group));
+            }
+            if recs.len() == 1 {
+                target.store.inc_unique_alignments();
+            }
         }
     };
 
@@ -294,71 +424,91 @@ pub fn parse_alignments<R: io::BufRead>(
     // *NOTE*: this had to be changed from `records` to `record_bufs` or
     // critical information was missing from the records. This happened when
     // moving to the new version of noodles. Track `https://github.com/zaeleus/noodles/issues/230`
-    // to see if it's clear why this is the case
-    for result in reader.record_bufs(header) {
-        let record = result?;
+    // to see if it's clear why this is the case. The records are decoded into
+    // buffers recycled from the previous reads, rather than freshly allocated.
+    loop {
+        let mut record = spare_records.pop().unwrap_or_default();
+        if profile::time(Stage::Parsing, || {
+            reader.read_record_buf(header, &mut record)
+        })? == 0
+        {
+            break;
+        }
         pb.inc(1);
 
         // unmapped reads don't contribute to quantification
         // but we track them.
         if record.flags().is_unmapped() {
-            num_unmapped += 1;
+            if subsample.is_done(num_seen) {
+                break;
+            }
+            num_seen += 1;
+            if subsample.keep(record.name().map_or(b"".as_slice(), |n| n.as_ref())) {
+                num_unmapped += 1;
+                match route(read_group_of(&record)) {
+                    Some(t) => targets[t].store.num_input_reads += 1,
+                    None => num_skipped += 1,
+                }
+            }
+            spare_records.push(record);
             continue;
         }
-        let record_copy = record.clone();
-        if let Some(rname) = record.name() {
-            let rstring: String = String::from_utf8_lossy(rname.as_ref()).into_owned();
-            // if this is an alignment for the same read, then
-            // push it onto our temporary vector.
-            if prev_read == rstring {
-                if let Some(_ref_id) = record.reference_sequence_id() {
-                    records_for_read.push(record_copy);
-                }
-            } else {
-                // otherwise, record the alignment range for the
-                // previous read record.
-                if !prev_read.is_empty() {
-                    if store.add_group(txps, &mut records_for_read) {
-                        add_read_name(&records_for_read);
-                        if records_for_read.len() == 1 {
-                            store.inc_unique_alignments();
-                        }
-                    }
-                    records_for_read.clear();
-                }
-                // the new "prev_read" name is the current read name
-                // so it becomes the first on the new alignment range
-                // vector.
-                prev_read = rstring;
-                if rg_num < check_order_thresh {
-                    if !read_name_map.insert(prev_read.clone()) {
-                        error!(
-                            "It appears that the input BAM file is not name-collated. oarfish is not designed to process coordinate sorted BAM files."
-                        );
-                        anyhow::bail!(
-                            "You appear to have provided a coordinate-sorted BAM, but oarfish does not support processing these.\n\
-                                    You should provide a BAM file collated by record name (which is the \"natural\" minimap2 order).\n\
-                                    Alignment records for the same read {} were observed twice in a non-contiguous block.",
-                            &prev_read
-                        );
-                    }
-                    rg_num += 1;
+        let Some(rname) = record.name() else {
+            spare_records.push(record);
+            continue;
+        };
+        let rname: &[u8] = rname;
+        // if this is an alignment for the same read, then
+        // push it onto our temporary vector.
+        if prev_read.as_slice() != rname {
+            // otherwise, record the alignment range for the
+            // previous read record.
+            if !prev_read.is_empty() && keep_read {
+                let rg = records_for_read.first().and_then(read_group_of);
+                match route(rg) {
+                    Some(t) => add_read(&mut targets[t], &mut records_for_read, rg),
+                    None => num_skipped += 1,
                 }
-                if let Some(_ref_id) = record.reference_sequence_id() {
-                    records_for_read.push(record_copy);
+            }
+            spare_records.append(&mut records_for_read);
+            if subsample.is_done(num_seen) {
+                prev_read.clear();
+                break;
+            }
+            num_seen += 1;
+            keep_read = subsample.keep(rname);
+            // the new "prev_read" name is the current read name
+            // so it becomes the first on the new alignment range
+            // vector.
+            prev_read.clear();
+            prev_read.extend_from_slice(rname);
+            if rg_num < check_order_thresh {
+                if !read_name_map.insert(prev_read.clone()) {
+                    error!(
+                        "It appears that the input BAM file is not name-collated. oarfish is not designed to process coordinate sorted BAM files."
+                    );
+                    return Err(errors::bad_input(format!(
+                        "You appear to have provided a coordinate-sorted BAM, but oarfish does not support processing these.\n\
+                                You should provide a BAM file collated by record name (which is the \"natural\" minimap2 order).\n\
+                                Alignment records for the same read {} were observed twice in a non-contiguous block.",
+                        String::from_utf8_lossy(&prev_read)
+                    )));
                 }
+                rg_num += 1;
             }
         }
+        if keep_read && record.reference_sequence_id().is_some() {
+            records_for_read.push(record);
+        } else {
+            spare_records.push(record);
+        }
     }
-    // if we end with a non-empty alignment range vector, then
-    // add that group.
-    if !records_for_read.is_empty() {
-        // if we are using read names and we added the group here
-        if store.add_group(txps, &mut records_for_read) {
-            add_read_name(&records_for_read);
-            if records_for_read.len() == 1 {
-                store.inc_unique_alignments();
-            }
+    // add the group of the last read (if any).
+    if !prev_read.is_empty() && keep_read {
+        let rg = records_for_read.first().and_then(read_group_of);
+        match route(rg) {
+            Some(t) => add_read(&mut targets[t], &mut records_for_read, rg),
+            None => num_skipped += 1,
         }
         records_for_read.clear();
     }
@@ -370,5 +520,5 @@ pub fn parse_alignments<R: io::BufRead>(
         num_unmapped.to_formatted_string(&Locale::en)
     );
 
-    Ok(())
+    Ok(num_skipped)
 }
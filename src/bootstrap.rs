@@ -14,3 +14,99 @@ pub fn get_sample_inds<R: Rng + ?Sized>(n: usize, rng: &mut R) -> Vec<usize> {
     inds.sort_unstable();
     inds
 }
+
+/// Group the indices [0, n) of the `n = strata.len()` reads by their stratum, where
+/// `strata[i]` is the stratum of read `i`.
+pub fn stratum_members(strata: &[u16]) -> Vec<Vec<usize>> {
+    let num_strata = strata.iter().max().map_or(0, |m| *m as usize + 1);
+    let mut members = vec![Vec::new(); num_strata];
+    for (i, s) in strata.iter().enumerate() {
+        members[*s as usize].push(i);
+    }
+    members.retain(|m| !m.is_empty());
+    members
+}
+
+/// Append to `inds` a sample (with replacement) of as many of the indices of `m` as it
+/// holds, drawn in blocks of `block_size` consecutive indices of `m` (the moving block
+/// bootstrap), the last block being truncated so that the size of the sample is `m.len()`.
+/// With a `block_size` of 1, this is the ordinary (uniform) resampling of `m`.
+fn extend_with_block_sample<R: Rng + ?Sized>(
+    m: &[usize],
+    block_size: usize,
+    rng: &mut R,
+    inds: &mut Vec<usize>,
+) {
+    let n = m.len();
+    let block_size = block_size.clamp(1, n.max(1));
+    let dist = Uniform::new(0, n + 1 - block_size).expect("could not create distribution");
+    let mut remaining = n;
+    while remaining > 0 {
+        let start = dist.sample(rng);
+        let len = block_size.min(remaining);
+        inds.extend_from_slice(&m[start..start + len]);
+        remaining -= len;
+    }
+}
+
+/// Get a random sample of `n` numbers in the range [0,n), drawn in blocks of `block_size`
+/// consecutive numbers, so that the correlation between neighbouring reads (e.g. of reads
+/// sequenced at the same time) is preserved. The numbers are returned in sorted order.
+pub fn get_block_sample_inds<R: Rng + ?Sized>(
+    n: usize,
+    block_size: usize,
+    rng: &mut R,
+) -> Vec<usize> {
+    let all: Vec<usize> = (0..n).collect();
+    let mut inds = Vec::with_capacity(n);
+    extend_with_block_sample(&all, block_size, rng, &mut inds);
+    inds.sort_unstable();
+    inds
+}
+
+/// Get a random sample of the reads whose indices are grouped by stratum in `members`,
+/// drawing (with replacement, in blocks of `block_size` consecutive reads of the stratum)
+/// as many reads from each stratum as it contains, so that the size of each stratum is
+/// preserved. The indices are returned in sorted order.
+pub fn get_stratified_sample_inds<R: Rng + ?Sized>(
+    members: &[Vec<usize>],
+    block_size: usize,
+    rng: &mut R,
+) -> Vec<usize> {
+    let mut inds = Vec::with_capacity(members.iter().map(|m| m.len()).sum());
+    for m in members {
+        extend_with_block_sample(m, block_size, rng, &mut inds);
+    }
+    inds.sort_unstable();
+    inds
+}
+
+/// The stratum of each read by its length: the reads of known length are divided into (up
+/// to) `num_strata` strata of (nearly) equal size by the quantiles of their lengths, and the
+/// reads of unknown length (0) form a stratum of their own.
+pub fn read_length_strata(read_lengths: &[u32], num_strata: usize) -> Vec<u16> {
+    let mut known: Vec<u32> = read_lengths.iter().copied().filter(|l| *l > 0).collect();
+    known.sort_unstable();
+    // the smallest length of each stratum but the first
+    let mut bounds: Vec<u32> = (1..num_strata.max(1))
+        .filter_map(|k| known.get(k * known.len() / num_strata))
+        .copied()
+        .collect();
+    bounds.dedup();
+    let unknown = bounds.len() as u16 + 1;
+    read_lengths
+        .iter()
+        .map(|l| match l {
+            0 => unknown,
+            l => bounds.partition_point(|b| b <= l) as u16,
+        })
+        .collect()
+}
+
+/// The stratum of each read by the size of its equivalence class (i.e. the number of
+/// transcripts to which it aligns), given by `class_sizes`: 1, 2, 3-4, 5-8, and so on.
+pub fn eq_class_size_strata(class_sizes: impl Iterator<Item = usize>) -> Vec<u16> {
+    class_sizes
+        .map(|n| n.max(1).next_power_of_two().trailing_zeros() as u16)
+        .collect()
+}
@@ -1,32 +1,90 @@
-use crate::alignment_parser;
+use crate::alignment_parser::{self, ParseTarget};
+use crate::bootstrap;
 use crate::em;
 use crate::kde_utils;
-use crate::prog_opts::Args;
+use crate::prog_opts::{Args, BootstrapStrata, CoverageModel, OutputCompression};
+use crate::util::adaptive_sampling;
+use crate::util::alignment_cache;
+use crate::util::allelic;
+use crate::util::bam_output;
+use crate::util::biotypes::{
+    file_safe, summarize_biotypes, write_biotype_summary, write_quant_by_biotype,
+};
+use crate::util::collapse::CollapseRules;
 use crate::util::constants::EMPTY_READ_NAME;
+use crate::util::contaminants::{Contaminants, write_contaminant_quant};
+use crate::util::coverage_comparison::{compare_coverage_estimates, write_coverage_comparison};
+use crate::util::decoys;
+use crate::util::demux::BarcodeDemux;
+use crate::util::digest_utils;
+use crate::util::duplicates::{self, DuplicateResult};
+use crate::util::eq_classes::{EqClasses, write_eq_classes};
+use crate::util::errors::bad_input;
+use crate::util::gene_quant::GeneAggregation;
+use crate::util::indistinguishable;
+use crate::util::infrep_spill::ReplicateSpill;
+use crate::util::internal_priming::InternalPriming;
+use crate::util::lanes::summarize_lanes;
+use crate::util::length_dist::{
+    LengthDistribution, effective_lengths, read_length_dist, write_effective_lengths,
+    write_length_dist,
+};
+use crate::util::logging;
+use crate::util::mem_tracker::MemoryGuard;
+use crate::util::min_reads::redistribute_low_count_txps;
+use crate::util::mm_utils;
 use crate::util::oarfish_types::AlnInfo;
 use crate::util::oarfish_types::DiscardTable;
 use crate::util::oarfish_types::{
-    AlignmentFilters, EMInfo, InMemoryAlignmentStore, InputSourceType, ReadChunkWithNames,
-    ReadSource, TranscriptInfo,
+    AlignmentFilters, CoverageBinning, EMInfo, EmTrace, InMemoryAlignmentStore, InputSourceType,
+    ReadChunkWithNames, ReadSource, TranscriptInfo,
+};
+use crate::util::object_store_io;
+use crate::util::output_schema::add_schema_info;
+use crate::util::profile::{self, Stage};
+use crate::util::progress;
+use crate::util::quick_summary::write_quick_summary;
+use crate::util::read_filter::{ReadFilter, load_read_filter};
+use crate::util::read_function::{read_short_quant_vec, read_txp_biotypes, read_txp_weights};
+use crate::util::read_length_strata::quantify_by_read_length_strata;
+use crate::util::read_quality;
+use crate::util::reorder_buffer::ReorderBuffer;
+use crate::util::resource_usage;
+use crate::util::second_chance::{SecondChanceStats, read_fit};
+use crate::util::spike_ins::{SpikeIns, write_spike_in_quant};
+use crate::util::spline_probability::spline_prob;
+use crate::util::subsample::{self, ReadSubsample};
+use crate::util::taxonomy::{read_taxonomy, summarize_taxa, write_taxon_summary};
+use crate::util::thread_alloc::{ThreadBalancer, queue_fill};
+use crate::util::trimming::{AdapterTrimmer, TrimStats};
+use crate::util::write_function::{
+    write_adaptive_sampling, write_ambiguous_reads, write_duplicates, write_em_trace,
+    write_fusion_candidates, write_infrep_file, write_internal_priming, write_lane_quant,
+    write_out_prob, write_output, write_read_length_strata, write_spilled_infrep_file,
 };
-use crate::util::read_function::read_short_quant_vec;
-use crate::util::write_function::{write_infrep_file, write_out_prob, write_output};
 use crate::{logistic_prob, normalize_read_probs};
 use arrow2::{array::Float64Array, chunk::Chunk, datatypes::Field};
 use crossbeam::channel::Receiver;
 use crossbeam::channel::Sender;
 use crossbeam::channel::bounded;
+use itertools::izip;
 #[allow(unused_imports)]
 use minimap2_sys as mm_ffi;
 //use minimap2_temp as minimap2;
 
+use anyhow::Context;
 use needletail::parse_fastx_file;
 use noodles_bam as bam;
+use noodles_sam::alignment::RecordBuf;
 use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
 use serde_json::json;
-use std::io::BufRead;
+use std::borrow::Cow;
+use std::io::{BufRead, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
 use swapvec::{SwapVec, SwapVecConfig};
-use tracing::{info, warn};
+use tracing::{info, info_span, warn};
 
 /// Produce a [serde_json::Value] that encodes the relevant arguments and
 /// parameters of the run that we wish to record to file. Ultimately, this
@@ -37,64 +95,435 @@ fn get_json_info(
     seqcol_digest: &seqcol_rs::DigestResult,
 ) -> serde_json::Value {
     let prob = if args.model_coverage {
-        "logistic_coverage"
+        args.coverage_model
+            .unwrap_or(CoverageModel::Logistic)
+            .prob_model_name()
     } else {
         "no_coverage"
     };
 
-    let source = if args.alignments.is_some() {
+    let source = if !args.alignments.is_empty() {
         "from_bam"
+    } else if args.from_alignment_cache.is_some() {
+        "from_alignment_cache"
     } else {
         "from_raw_reads"
     };
 
-    json!({
+    let mut info = json!({
         "prob_model" : prob,
         "alignment_source" : source,
         "bin_width" : args.bin_width,
+        "coverage_binning" : CoverageBinning::from_args(args),
         "filter_options" : &emi.eq_map.filter_opts,
         "discard_table" : &emi.eq_map.discard_table,
+        "second_chance" : &emi.eq_map.second_chance,
+        "trimming" : &emi.eq_map.trimming,
+        "internal_priming" : emi.eq_map.internal_priming.as_ref().map(InternalPriming::summary),
+        "demux" : &emi.eq_map.demux,
+        "score_calibration" : emi.eq_map.filter_opts.score_calibration(),
+        "kde" : emi.kde_model.as_ref().map(|m| m.summary(args.kde_model.as_deref())),
         "alignments": &args.alignments,
         "output": &args.output,
+        "output_format": &args.output_format,
+        "compress": &args.compress,
         "verbose": &args.verbose,
+        "log": &args.log,
         "single_cell": &args.single_cell,
         "quiet": &args.quiet,
         "em_max_iter": &args.max_em_iter,
+        "em_min_iter": &args.min_em_iter,
         "em_convergence_thresh": &args.convergence_thresh,
+        "em_trace": &args.em_trace,
+        "deterministic": &args.deterministic,
+        "seed": &args.seed,
         "threads": &args.threads,
+        "io_backend": &args.io_backend,
+        "save_alignment_cache": &args.save_alignment_cache,
+        "from_alignment_cache": &args.from_alignment_cache,
+        "low_mem": &args.low_mem,
+        "max_mem": &args.max_mem,
+        "memory_degradation": emi.eq_map.mem_guard.as_ref().map(|g| &g.degradation),
         "filter_group": &args.filter_group,
         "write_assignment_probs": &emi.eq_map.filter_opts.write_assignment_probs_type,
+        "assignment_probs_shards": &args.assignment_probs_shards,
+        "write_eqclasses": &args.write_eqclasses,
         "short_quant": &args.short_quant,
+        "no_em": &args.no_em,
+        "min_reads_per_txp": &args.min_reads_per_txp,
+        "posterior_mean": &args.posterior_mean,
+        "prior_weight": &args.posterior_mean.then_some(args.prior_weight),
         "num_bootstraps": &args.num_bootstraps,
+        "bootstrap_strata": &args.bootstrap_strata,
+        "bootstrap_length_strata": &(args.bootstrap_strata == BootstrapStrata::ReadLength)
+            .then_some(args.bootstrap_length_strata),
+        "bootstrap_block_size": &args.bootstrap_block_size,
+        "credible_interval": &(args.txp_to_gene.is_some() && args.num_bootstraps > 0)
+            .then_some(args.credible_interval),
+        "indistinguishable_groups": &args.indistinguishable_groups,
+        "group_min_infrv_reduction": &args.group_min_infrv_reduction,
+        "group_quant": &args.group_quant,
+        "allele_specific": &args.allele_specific,
+        "haplotype_suffixes": &args.allele_specific.then_some(&args.haplotype_suffixes),
+        "allelic_pseudocount": &args.allelic_pseudocount,
+        "second_chance_fit": &args.second_chance_fit,
+        "trim_adapters": &args.trim_adapters,
+        "trim_search_len": &args.trim_search_len,
+        "trim_max_error_rate": &args.trim_max_error_rate,
+        "demux_barcodes": &args.demux_barcodes,
+        "demux_samples": &args.demux_samples,
+        "demux_search_len": &args.demux_search_len,
+        "demux_max_error_rate": &args.demux_max_error_rate,
+        "no_read_quality_weighting": &args.no_read_quality_weighting,
+        "calibrate_scores": &args.calibrate_scores,
+        "calibration_reads": &args.calibration_reads,
+        "use_kde": &args.use_kde,
+        "kde_bandwidth": &args.kde_bandwidth,
+        "kde_bin_width": &args.kde_bin_width,
+        "kde_model": &args.kde_model,
+        "kde_model_out": &args.kde_model_out,
+        "length_dist": &args.length_dist,
+        "eff_len_dist": &args.eff_len_dist,
+        "rescue_supplementary": &args.rescue_supplementary,
+        "keep_transcripts": &args.keep_transcripts,
+        "exclude_transcripts": &args.exclude_transcripts,
+        "collapse_rules": &args.collapse_rules,
+        "collapse_versions": &args.collapse_versions,
+        "txp_weights": &args.txp_weights,
+        "read_filter_plugin": &args.read_filter_plugin,
+        "read_filter_config": &args.read_filter_config,
+        "internal_priming_reference": &args.internal_priming_reference,
+        "internal_priming_window": &args.internal_priming_window,
+        "internal_priming_min_a_frac": &args.internal_priming_min_a_frac,
+        "internal_priming_weight": &args.internal_priming_weight,
+        "strict_index_check": &args.strict_index_check,
+        "verify_reference": &args.verify_reference,
+        "control_alignments": &args.control_alignments,
+        "split_by_read_group": &args.split_by_read_group,
+        "first_n_reads": &args.first_n_reads,
+        "subsample_fraction": &args.subsample_fraction,
+        "subsample_seed": &args.subsample_fraction.map(|_| subsample::seed(args)),
+        "txp_to_gene": &args.txp_to_gene,
+        "reference_mismatch": &args.verify_reference.as_ref().map(|_| args.reference_mismatch),
+        "lanes": &args.lanes,
+        "write_bam": &args.write_bam,
+        "archive": &args.archive,
+        "detect_duplicates": &args.detect_duplicates,
+        "collapse_duplicates": &args.collapse_duplicates,
+        "sequencing_summary": &args.sequencing_summary,
+        "dup_end_tolerance": &args.dup_end_tolerance,
+        "dup_length_tolerance": &args.dup_length_tolerance,
+        "adaptive_sampling": &args.adaptive_sampling,
+        "biotypes": &args.biotypes,
+        "split_by_biotype": &args.split_by_biotype,
+        "contaminants": &args.contaminants,
+        "exclude_contaminants": &args.exclude_contaminants,
+        "spike_in_prefix": &args.spike_in_prefix,
+        "spike_in_concentrations": &args.spike_in_concentrations,
+        "taxonomy": &args.taxonomy,
+        "tax_ranks": &args.tax_ranks,
+        "compare_coverage_model": &args.compare_coverage_model,
+        "decoys": &args.decoys,
         "digest": seqcol_digest.to_json()
-    })
+    });
+    add_schema_info(&mut info);
+    info
+}
+
+/// true if the name of each read is needed to look up per-read information,
+/// i.e. its channel (to detect duplicate reads) or its adaptive sampling decision.
+fn needs_per_read_info(args: &Args) -> bool {
+    ((args.detect_duplicates || args.collapse_duplicates) && args.sequencing_summary.is_some())
+        || args.adaptive_sampling.is_some()
+}
+
+/// Load the read filter plugin requested by the user, if any.
+fn get_read_filter(
+    args: &Args,
+    txps_name: &[String],
+) -> anyhow::Result<Option<Box<dyn ReadFilter>>> {
+    args.read_filter_plugin
+        .as_deref()
+        .map(|p| load_read_filter(p, args.read_filter_config.as_deref(), txps_name))
+        .transpose()
+}
+
+/// Find the A-rich stretches of the transcripts for `--internal-priming`, if it was
+/// requested. The transcript sequences are read from `--internal-priming-reference` or,
+/// failing that, from the `--reference` if it is a local FASTA file.
+fn get_internal_priming(
+    args: &Args,
+    txps_name: &[String],
+) -> anyhow::Result<Option<InternalPriming>> {
+    let Some(mode) = args.internal_priming else {
+        return Ok(None);
+    };
+    let reference = match (&args.internal_priming_reference, &args.reference) {
+        (Some(r), _) => r,
+        (None, Some(r)) if !object_store_io::is_remote(r) && crate::is_fasta(r)? => r,
+        _ => {
+            return Err(bad_input(
+                "--internal-priming needs the sequences of the transcripts; pass them as a \
+                 FASTA file with --internal-priming-reference",
+            ));
+        }
+    };
+    InternalPriming::load(
+        reference,
+        txps_name,
+        args.internal_priming_window as usize,
+        args.internal_priming_min_a_frac,
+        mode,
+        args.internal_priming_weight,
+    )
+    .map(Some)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn perform_inference_and_write_output(
     header: &noodles_sam::header::Header,
     store: &mut InMemoryAlignmentStore,
     name_vec: Option<SwapVec<String>>,
     txps: &mut [TranscriptInfo],
     txps_name: &[String],
-    seqcol_digest: seqcol_rs::DigestResult,
+    seqcol_digest: &seqcol_rs::DigestResult,
+    lane_reads: Option<&[usize]>,
+    args: &Args,
+) -> anyhow::Result<()> {
+    // the parsed alignments are saved first, so that later runs can start from them
+    let name_vec = match args.save_alignment_cache {
+        Some(ref path) => alignment_cache::write_alignment_cache(
+            path,
+            header,
+            seqcol_digest,
+            store,
+            name_vec,
+            lane_reads,
+        )?,
+        None => name_vec,
+    };
+
+    // if requested, identify (and possibly remove) duplicate reads; this is done
+    // with respect to the original transcripts, prior to any collapsing.
+    if args.detect_duplicates || args.collapse_duplicates {
+        let (channels, name_vec) = match (&args.sequencing_summary, name_vec) {
+            (Some(summary), Some(name_vec)) => {
+                let channel_map = duplicates::read_channels(summary)?;
+                let (channels, name_vec) = duplicates::channels_of_reads(name_vec, &channel_map);
+                (Some(channels), Some(name_vec))
+            }
+            (_, name_vec) => (None, name_vec),
+        };
+        let dups = duplicates::find_duplicates(
+            store,
+            txps.len(),
+            channels.as_deref(),
+            args.dup_end_tolerance,
+            args.dup_length_tolerance,
+        );
+        write_duplicates(&args.output, txps_name, &dups)?;
+
+        if args.collapse_duplicates {
+            // the coverage of the retained reads must be recomputed from scratch
+            let binning = CoverageBinning::from_args(args);
+            let mut dedup_txps: Vec<TranscriptInfo> = txps
+                .iter()
+                .map(|t| {
+                    if store.filter_opts.model_coverage {
+                        TranscriptInfo::with_len_and_binning(t.len, &binning)
+                    } else {
+                        TranscriptInfo::with_len(t.len)
+                    }
+                })
+                .collect();
+            let mut dedup_store =
+                duplicates::remove_duplicates(store, &dups.is_duplicate, &mut dedup_txps);
+            let name_vec =
+                name_vec.map(|nv| duplicates::remove_duplicate_names(nv, &dups.is_duplicate));
+            collapse_and_infer(
+                header,
+                &mut dedup_store,
+                name_vec,
+                &mut dedup_txps,
+                txps_name,
+                seqcol_digest,
+                lane_reads,
+                Some(&dups),
+                args,
+            )?;
+        } else {
+            collapse_and_infer(
+                header,
+                store,
+                name_vec,
+                txps,
+                txps_name,
+                seqcol_digest,
+                lane_reads,
+                Some(&dups),
+                args,
+            )?;
+        }
+    } else {
+        collapse_and_infer(
+            header,
+            store,
+            name_vec,
+            txps,
+            txps_name,
+            seqcol_digest,
+            lane_reads,
+            None,
+            args,
+        )?;
+    }
+
+    // the fusion candidates always refer to the original transcripts
+    if args.rescue_supplementary {
+        write_fusion_candidates(
+            &args.output,
+            header,
+            &store.discard_table.chimeras,
+            args.compress.unwrap_or(OutputCompression::None),
+        )?;
+    }
+    // as do the internal-priming artifact rates
+    if let Some(ref ip) = store.internal_priming {
+        ip.log();
+        write_internal_priming(
+            &args.output,
+            txps_name,
+            ip.txp_counts(),
+            args.compress.unwrap_or(OutputCompression::None),
+        )?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn collapse_and_infer(
+    header: &noodles_sam::header::Header,
+    store: &mut InMemoryAlignmentStore,
+    name_vec: Option<SwapVec<String>>,
+    txps: &mut [TranscriptInfo],
+    txps_name: &[String],
+    seqcol_digest: &seqcol_rs::DigestResult,
+    lane_reads: Option<&[usize]>,
+    dups: Option<&DuplicateResult>,
+    args: &Args,
+) -> anyhow::Result<()> {
+    // if the user requested that transcripts be collapsed into groups, then
+    // project the alignments onto these groups and quantify the groups instead.
+    if args.collapse_rules.is_some() || args.collapse_versions {
+        let rules = CollapseRules::new(
+            args.collapse_rules.as_deref(),
+            args.collapse_versions,
+            txps,
+            txps_name,
+        )?;
+        let collapsed_header = rules.collapsed_header();
+        let binning = CoverageBinning::from_args(args);
+        let mut collapsed_txps =
+            rules.collapsed_txp_info(store.filter_opts.model_coverage, &binning);
+        let mut collapsed_store =
+            rules.collapse_store(store, &collapsed_header, &mut collapsed_txps);
+        infer_and_write_output(
+            &collapsed_header,
+            &mut collapsed_store,
+            name_vec,
+            &mut collapsed_txps,
+            &rules.group_names,
+            seqcol_digest,
+            lane_reads,
+            dups,
+            args,
+        )
+    } else {
+        infer_and_write_output(
+            header,
+            store,
+            name_vec,
+            txps,
+            txps_name,
+            seqcol_digest,
+            lane_reads,
+            dups,
+            args,
+        )
+    }
+}
+
+/// If the coverage model is used, compute the coverage probabilities of the transcripts
+/// with the model selected by the user, and normalize them over the alignments of each read.
+pub(crate) fn apply_coverage_model(
+    store: &mut InMemoryAlignmentStore,
+    txps: &mut [TranscriptInfo],
+    args: &Args,
+) {
+    if store.filter_opts.model_coverage {
+        let _profile = profile::scope(Stage::Probabilities);
+        //obtaining the Cumulative Distribution Function (CDF) for each transcript
+        match args.coverage_model.unwrap_or(CoverageModel::Logistic) {
+            CoverageModel::Logistic => logistic_prob(txps, args.growth_rate, args.threads),
+            CoverageModel::Binomial => crate::binomial_continuous_prob(txps, args.threads),
+            CoverageModel::Spline => spline_prob(txps, args.threads),
+        }
+        //Normalize the probabilities for the records of each read
+        normalize_read_probs(store, txps);
+    }
+}
+
+/// Run the EM variant selected by the user on `emi`.
+pub(crate) fn run_em(emi: &EMInfo, args: &Args) -> Vec<f64> {
+    let _profile = profile::scope(Stage::Em);
+    // the GPU EM falls back to the CPU if no GPU is found
+    #[cfg(feature = "gpu")]
+    if args.gpu
+        && let Some(counts) = em::em_gpu(emi)
+    {
+        return counts;
+    }
+    if args.deterministic {
+        em::em_par_deterministic(emi, args.threads)
+    } else if args.threads > 4 {
+        em::em_par(emi, args.threads)
+    } else {
+        em::em(emi, args.threads)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn infer_and_write_output(
+    header: &noodles_sam::header::Header,
+    store: &mut InMemoryAlignmentStore,
+    name_vec: Option<SwapVec<String>>,
+    txps: &mut [TranscriptInfo],
+    txps_name: &[String],
+    seqcol_digest: &seqcol_rs::DigestResult,
+    lane_reads: Option<&[usize]>,
+    dups: Option<&DuplicateResult>,
     args: &Args,
 ) -> anyhow::Result<()> {
     // print discard table information in which the user might be interested.
     info!("\ndiscard_table: \n{}\n", store.discard_table.to_table());
+    logging::filter_counts(&store.discard_table);
+    resource_usage::end_stage("alignment_processing");
 
     // if we are using the KDE, create that here.
-    let kde_opt: Option<kders::kde::KDEModel> = if args.use_kde {
-        Some(kde_utils::get_kde_model(txps, store)?)
+    let kde_opt: Option<kde_utils::KdeModel> = if args.use_kde {
+        let model = match args.kde_model {
+            Some(ref path) => kde_utils::KdeModel::load(path)?,
+            None => kde_utils::get_kde_model(txps, store, args)?,
+        };
+        if let Some(ref path) = args.kde_model_out {
+            model.save(path)?;
+        }
+        Some(model)
     } else {
         None
     };
 
-    if store.filter_opts.model_coverage {
-        //obtaining the Cumulative Distribution Function (CDF) for each transcript
-        logistic_prob(txps, args.growth_rate, &args.bin_width, args.threads);
-        //Normalize the probabilities for the records of each read
-        normalize_read_probs(store, txps, &args.bin_width);
-    }
+    apply_coverage_model(store, txps, args);
 
     info!(
         "Total number of alignment records : {}",
@@ -111,9 +540,51 @@ fn perform_inference_and_write_output(
 
     // if we are seeding the quantification estimates with short read
     // abundances, then read those in here.
-    let init_abundances = args.short_quant.as_ref().map(|sr_path| {
-        read_short_quant_vec(sr_path, txps_name).unwrap_or_else(|e| panic!("{}", e))
-    });
+    let init_abundances = args
+        .short_quant
+        .as_ref()
+        .map(|sr_path| read_short_quant_vec(sr_path, txps_name))
+        .transpose()?;
+
+    // if the user provided prior weights for the transcripts, read those in here.
+    let txp_weights = args
+        .txp_weights
+        .as_deref()
+        .map(|p| read_txp_weights(p, txps_name))
+        .transpose()?;
+
+    // if the reference is diploid, pair the haplotypes of each transcript
+    let haplotype_pairs = if args.allele_specific {
+        Some(allelic::haplotype_pairs(
+            txps_name,
+            &args.haplotype_suffixes,
+        )?)
+    } else {
+        None
+    };
+
+    // if requested, first estimate the abundances without the coverage model,
+    // reusing the alignments (and their coverage probabilities) in the store.
+    let no_coverage_counts = if args.compare_coverage_model {
+        store.filter_opts.model_coverage = false;
+        let emi = EMInfo {
+            eq_map: store,
+            txp_info: txps,
+            max_iter: args.max_em_iter,
+            min_iter: args.min_em_iter,
+            convergence_thresh: args.convergence_thresh,
+            init_abundances: init_abundances.clone(),
+            kde_model: None,
+            txp_weights: txp_weights.clone(),
+            trace: None,
+        };
+        info!("estimating abundances without the coverage model.");
+        let counts = run_em(&emi, args);
+        store.filter_opts.model_coverage = true;
+        Some(counts)
+    } else {
+        None
+    };
 
     // wrap up all of the relevant information we need for estimation
     // in an EMInfo struct and then call the EM algorithm.
@@ -121,9 +592,12 @@ fn perform_inference_and_write_output(
         eq_map: store,
         txp_info: txps,
         max_iter: args.max_em_iter,
+        min_iter: args.min_em_iter,
         convergence_thresh: args.convergence_thresh,
         init_abundances,
         kde_model: kde_opt,
+        txp_weights,
+        trace: args.em_trace.then(|| Arc::new(EmTrace::default())),
     };
 
     if args.use_kde {
@@ -141,45 +615,466 @@ fn perform_inference_and_write_output(
         */
     }
 
-    let counts = if args.threads > 4 {
-        em::em_par(&emi, args.threads)
+    let aux_txp_counts = crate::util::aux_counts::get_aux_counts(store, txps)?;
+
+    let counts = if args.no_em {
+        info!("skipping the EM; only uniquely aligned reads will be counted.");
+        aux_txp_counts
+            .iter()
+            .map(|c| c.unique_count as f64)
+            .collect::<Vec<f64>>()
     } else {
-        em::em(&emi, args.threads)
+        run_em(&emi, args)
     };
+    if let Some(ref trace) = emi.trace {
+        write_em_trace(&args.output, &trace.take())?;
+    }
 
-    let aux_txp_counts = crate::util::aux_counts::get_aux_counts(store, txps)?;
+    // if requested, zero the transcripts with too few reads and redistribute their reads
+    let (counts, min_reads_summary) = match args.min_reads_per_txp {
+        Some(min_reads) => {
+            let (counts, summary) = redistribute_low_count_txps(&emi, &counts, min_reads);
+            (counts, Some(summary))
+        }
+        None => (counts, None),
+    };
+    // if requested, report the posterior mean of the counts rather than the MLE
+    let counts = if args.posterior_mean {
+        em::posterior_mean_counts(&counts, args.prior_weight)
+    } else {
+        counts
+    };
+    resource_usage::end_stage("em");
+
+    let coverage_comparison = no_coverage_counts.map(|nc| compare_coverage_estimates(&counts, nc));
+
+    // if requested, quantify separately within read-length strata
+    let strata = args
+        .read_length_strata
+        .as_ref()
+        .map(|bounds| quantify_by_read_length_strata(&emi, bounds, &counts));
 
     // prepare the JSON object we'll write
     // to meta_info.json
-    let json_info = get_json_info(args, &emi, &seqcol_digest);
+    let mut json_info = get_json_info(args, &emi, seqcol_digest);
+    if let Some(ref strata) = strata {
+        json_info["read_length_strata"] = json!(&strata.summaries);
+        json_info["read_length_strata_num_unknown_length"] = json!(strata.num_unknown_length);
+    }
+    if let Some(ref summary) = min_reads_summary {
+        json_info["min_reads_redistribution"] = json!(summary);
+    }
+
+    // if requested, report the length distributions and the effective lengths derived
+    // from them (or from a precomputed distribution)
+    let length_dist = if args.length_dist {
+        let dist = LengthDistribution::from_store(emi.eq_map);
+        let eff_len_hist = match args.eff_len_dist {
+            Some(ref p) => read_length_dist(p)?,
+            None => dist.aligned_lengths.clone(),
+        };
+        let lens: Vec<usize> = emi.txp_info.iter().map(|t| t.len.get()).collect();
+        let eff_lens = effective_lengths(&eff_len_hist, &lens);
+        json_info["length_dist"] = json!(dist.summary(args.eff_len_dist.as_deref()));
+        Some((dist, eff_lens))
+    } else {
+        None
+    };
+    if let Some(dups) = dups {
+        json_info["duplicates"] = json!(dups);
+    }
+    if let Some(ref cmp) = coverage_comparison {
+        json_info["coverage_comparison"] = json!(cmp);
+    }
+
+    // if decoys were added to the index, estimate the false-assignment rate from them
+    let decoy_report = match (args.decoys, emi.eq_map.filter_opts.decoy_start()) {
+        (Some(mode), Some(decoy_start)) => Some(decoys::summarize_decoys(
+            mode,
+            &counts,
+            decoy_start,
+            &emi.eq_map.discard_table.decoys,
+            emi.eq_map.filter_opts.score_threshold(),
+        )),
+        _ => None,
+    };
+    if let Some(ref report) = decoy_report {
+        json_info["decoy_report"] = json!(report);
+    }
+
+    // if the reads came from multiple lanes, quantify each lane separately
+    let lanes = lane_reads.map(|lane_reads| {
+        let lane_paths = args.reads.as_deref().unwrap_or_default();
+        summarize_lanes(&emi, lane_reads, lane_paths, &counts)
+    });
+    if let Some(ref lanes) = lanes {
+        json_info["lanes"] = json!(lanes);
+    }
+
+    // if the user provided adaptive sampling decisions, quantify each decision class separately
+    let (adaptive, name_vec) = match (&args.adaptive_sampling, name_vec) {
+        (Some(decision_path), Some(name_vec)) => {
+            let decisions = adaptive_sampling::read_decisions(decision_path)?;
+            let (read_decisions, name_vec) =
+                adaptive_sampling::decisions_of_reads(name_vec, &decisions);
+            let asr = adaptive_sampling::summarize_adaptive_sampling(
+                &emi,
+                &read_decisions,
+                &decisions,
+                &counts,
+            );
+            (Some(asr), Some(name_vec))
+        }
+        (_, name_vec) => (None, name_vec),
+    };
+    if let Some(ref asr) = adaptive {
+        json_info["adaptive_sampling"] = json!(asr);
+    }
+
+    // if the user provided transcript biotypes, aggregate the estimates by biotype
+    let biotypes = args
+        .biotypes
+        .as_deref()
+        .map(|p| read_txp_biotypes(p, txps_name))
+        .transpose()?;
+    let biotype_summaries = biotypes
+        .as_ref()
+        .map(|bts| summarize_biotypes(header, &counts, bts));
+    if let Some(ref summaries) = biotype_summaries {
+        json_info["biotype_summary"] = json!(summaries);
+    }
+
+    // if the user provided the lineages of the sequences, aggregate the estimates by taxon
+    let taxon_summaries = args
+        .taxonomy
+        .as_deref()
+        .map(|p| read_taxonomy(p, txps_name, &args.tax_ranks))
+        .transpose()?
+        .map(|taxonomy| summarize_taxa(&taxonomy, emi.eq_map, &counts));
+    if let Some(ref summaries) = taxon_summaries {
+        json_info["taxon_summary"] = json!(summaries);
+    }
+
+    // if the user provided the contaminant transcripts, report the reads assigned to each
+    // category of them (and possibly exclude them from the main output)
+    let contaminants = args
+        .contaminants
+        .as_deref()
+        .map(|p| Contaminants::read(p, txps_name))
+        .transpose()?;
+    let contaminant_report = contaminants.as_ref().map(|c| {
+        c.summarize(
+            &counts,
+            emi.eq_map.num_input_reads,
+            args.exclude_contaminants,
+        )
+    });
+    if let Some(ref report) = contaminant_report {
+        json_info["contaminant_summary"] = json!(report);
+    }
+    let excluded = contaminants
+        .as_ref()
+        .filter(|_| args.exclude_contaminants)
+        .map(Contaminants::mask);
+
+    // if the user named the spike-ins, report their reads relative to the endogenous ones
+    let spike_ins = if args.spike_in_prefix.is_empty() {
+        None
+    } else {
+        Some(SpikeIns::new(
+            txps_name,
+            &args.spike_in_prefix,
+            args.spike_in_concentrations.as_deref(),
+        )?)
+    };
+    let spike_in_report = spike_ins
+        .as_ref()
+        .map(|s| s.summarize(&counts, &args.spike_in_prefix));
+    if let Some(ref report) = spike_in_report {
+        json_info["spike_in_summary"] = json!(report);
+    }
+
+    // hand the estimates back to the caller embedding oarfish, if there is one
+    if let Some(ref results) = args.results {
+        let is_reported = |i: usize| !excluded.as_ref().is_some_and(|ex| ex[i]);
+        let (names, lengths) = header
+            .reference_sequences()
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| is_reported(*i))
+            .map(|(_, (name, rmap))| (name.to_string(), rmap.length().get() as u64))
+            .unzip();
+        let reported_counts = counts
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| is_reported(*i))
+            .map(|(_, c)| *c)
+            .collect();
+        results.set_bulk(names, lengths, reported_counts, json_info.clone());
+    }
 
     // write the output
-    write_output(&args.output, json_info, header, &counts, &aux_txp_counts)?;
+    write_output(
+        &args.output,
+        json_info,
+        header,
+        &counts,
+        &aux_txp_counts,
+        excluded.as_deref(),
+        args.output_format,
+        args.compress,
+    )?;
+    if args.write_eqclasses {
+        write_eq_classes(&args.output, &EqClasses::from_em_info(&emi, txps_name))?;
+    }
+    if let Some(ref pairs) = haplotype_pairs {
+        let classes = EqClasses::from_em_info(&emi, txps_name);
+        let estimates =
+            allelic::estimate_allelic_ratios(pairs, &classes, &counts, args.allelic_pseudocount);
+        allelic::write_allelic(&args.output, txps_name, pairs, &estimates, &counts)?;
+    }
+    write_quick_summary(
+        &args.output,
+        header,
+        &counts,
+        emi.eq_map,
+        biotype_summaries.as_deref(),
+        contaminant_report.as_ref(),
+    )?;
+    if let Some(ref contaminants) = contaminants {
+        write_contaminant_quant(
+            &args.output,
+            header,
+            &counts,
+            contaminants,
+            args.compress.unwrap_or(OutputCompression::None),
+        )?;
+    }
+    if let (Some(spike_ins), Some(report)) = (&spike_ins, &spike_in_report) {
+        write_spike_in_quant(
+            &args.output,
+            header,
+            &counts,
+            spike_ins,
+            report,
+            args.compress.unwrap_or(OutputCompression::None),
+        )?;
+    }
+    if let Some(ref biotypes) = biotypes {
+        if let Some(ref summaries) = biotype_summaries {
+            write_biotype_summary(&args.output, summaries)?;
+        }
+        if args.split_by_biotype {
+            write_quant_by_biotype(&args.output, header, &counts, biotypes)?;
+        }
+    }
+    if let Some(ref summaries) = taxon_summaries {
+        write_taxon_summary(&args.output, summaries)?;
+    }
+    if let Some(ref strata) = strata {
+        write_read_length_strata(&args.output, header, strata)?;
+    }
+    if let Some((ref dist, ref eff_lens)) = length_dist {
+        write_length_dist(&args.output, dist)?;
+        write_effective_lengths(&args.output, header, &counts, eff_lens)?;
+    }
+    if let Some(ref cmp) = coverage_comparison {
+        write_coverage_comparison(&args.output, header, &counts, cmp)?;
+    }
+    if let Some(ref lanes) = lanes {
+        write_lane_quant(&args.output, header, lanes)?;
+    }
+    if let Some(ref asr) = adaptive {
+        write_adaptive_sampling(&args.output, header, asr)?;
+    }
+    resource_usage::end_stage("write_output");
+
+    // if the user mapped the transcripts to their genes, aggregate the estimates (and the
+    // replicates, as they are computed) to the genes
+    let genes = args
+        .txp_to_gene
+        .as_deref()
+        .map(|t2g| {
+            GeneAggregation::new(
+                t2g,
+                txps_name,
+                excluded.as_deref(),
+                args.num_bootstraps as usize,
+            )
+        })
+        .transpose()?;
 
     // if the user requested bootstrap replicates,
     // compute and write those out now.
     if args.num_bootstraps > 0 {
-        let breps = em::bootstrap(&emi, args.num_bootstraps, args.threads);
-
-        let mut new_arrays = vec![];
-        let mut bs_fields = vec![];
-        for (i, b) in breps.into_iter().enumerate() {
-            let bs_array = Float64Array::from_vec(b);
-            bs_fields.push(Field::new(
-                format!("bootstrap.{}", i),
-                bs_array.data_type().clone(),
-                false,
-            ));
-            new_arrays.push(bs_array.boxed());
+        let read_groups = emi.eq_map.read_lanes.as_slice();
+        let strata: Option<Cow<[u16]>> = match args.bootstrap_strata {
+            BootstrapStrata::Rg if read_groups.len() == emi.eq_map.len() => {
+                Some(Cow::Borrowed(read_groups))
+            }
+            BootstrapStrata::Rg => {
+                warn!(
+                    "the read group of each read is not available; resampling all reads together."
+                );
+                None
+            }
+            BootstrapStrata::ReadLength => Some(Cow::Owned(bootstrap::read_length_strata(
+                &emi.eq_map.read_lengths,
+                args.bootstrap_length_strata as usize,
+            ))),
+            BootstrapStrata::EqClassSize => Some(Cow::Owned(bootstrap::eq_class_size_strata(
+                emi.eq_map.iter().map(|(alns, _, _)| alns.len()),
+            ))),
+            BootstrapStrata::None => None,
+        };
+        let block_size = args.bootstrap_block_size as usize;
+        let posterior = |b: Vec<f64>| {
+            if args.posterior_mean {
+                em::posterior_mean_counts(&b, args.prior_weight)
+            } else {
+                b
+            }
+        };
+        // the replicates hold the same transcripts as the main output
+        let exclude = |b: Vec<f64>| match excluded {
+            Some(ref ex) => b
+                .into_iter()
+                .zip(ex.iter())
+                .filter(|(_, x)| !**x)
+                .map(|(c, _)| c)
+                .collect(),
+            None => b,
+        };
+
+        // if requested, group the transcripts that the replicates cannot tell apart; as
+        // this needs all of the replicates, they are then held in memory
+        if args.indistinguishable_groups {
+            let breps: Vec<Vec<f64>> = em::bootstrap(
+                &emi,
+                args.num_bootstraps,
+                args.threads,
+                strata.as_deref(),
+                block_size,
+                args.seed,
+            )
+            .into_iter()
+            .map(posterior)
+            .collect();
+            if let Some(ref genes) = genes {
+                for (i, b) in breps.iter().enumerate() {
+                    genes.add_replicate(i, b);
+                }
+            }
+
+            let classes = EqClasses::from_em_info(&emi, txps_name);
+            let groups = indistinguishable::find_groups(
+                &classes,
+                &breps,
+                args.group_min_infrv_reduction as f64,
+            );
+            groups.write(
+                &args.output,
+                txps_name,
+                &breps,
+                args.compress.unwrap_or(OutputCompression::None),
+            )?;
+            if args.group_quant {
+                groups.write_quant(
+                    &args.output,
+                    txps_name,
+                    &counts,
+                    &breps,
+                    args.output_format,
+                    args.compress,
+                )?;
+            }
+
+            let mut new_arrays = vec![];
+            let mut bs_fields = vec![];
+            for (i, b) in breps.into_iter().enumerate() {
+                let b = exclude(b);
+                if let Some(ref results) = args.results {
+                    results.add_replicate(i, args.num_bootstraps as usize, b.clone());
+                }
+                let bs_array = Float64Array::from_vec(b);
+                bs_fields.push(Field::new(
+                    format!("bootstrap.{}", i),
+                    bs_array.data_type().clone(),
+                    false,
+                ));
+                new_arrays.push(bs_array.boxed());
+            }
+            let chunk = Chunk::new(new_arrays);
+            write_infrep_file(
+                &args.output,
+                bs_fields,
+                chunk,
+                args.output_format,
+                args.compress,
+            )?;
+        } else {
+            // otherwise, each replicate is moved to disk as soon as it is done
+            let num_rows = excluded
+                .as_ref()
+                .map_or(emi.txp_info.len(), |ex| ex.iter().filter(|x| !**x).count());
+            let spill = ReplicateSpill::new(num_rows, args.num_bootstraps as usize)?;
+            em::for_each_bootstrap(
+                &emi,
+                args.num_bootstraps,
+                args.threads,
+                strata.as_deref(),
+                block_size,
+                args.seed,
+                |i, b| {
+                    let b = posterior(b);
+                    if let Some(ref genes) = genes {
+                        genes.add_replicate(i, &b);
+                    }
+                    let b = exclude(b);
+                    if let Some(ref results) = args.results {
+                        results.add_replicate(i, args.num_bootstraps as usize, b.clone());
+                    }
+                    spill.write_replicate(i, &b)
+                },
+            )?;
+            write_spilled_infrep_file(&args.output, &spill, args.output_format, args.compress)?;
         }
-        let chunk = Chunk::new(new_arrays);
-        write_infrep_file(&args.output, bs_fields, chunk)?;
+        resource_usage::end_stage("bootstrap");
+    }
+    if let Some(genes) = genes {
+        genes.write(
+            &args.output,
+            &counts,
+            args.credible_interval as f64,
+            args.output_format,
+            args.compress,
+        )?;
     }
 
-    if args.write_assignment_probs.is_some() {
+    if args.no_em {
+        let name_vec =
+            name_vec.expect("cannot write ambiguous reads without valid vector of read names");
+        write_ambiguous_reads(
+            &args.output,
+            &emi,
+            name_vec,
+            txps_name,
+            args.compress.unwrap_or(OutputCompression::None),
+        )?;
+    } else if args.write_assignment_probs.is_some() {
         let name_vec = name_vec
             .expect("cannot write assignment probabilities without valid vector of read names");
-        write_out_prob(&args.output, &emi, &counts, name_vec, txps_name)?;
+        write_out_prob(
+            &args.output,
+            &emi,
+            &counts,
+            name_vec,
+            txps_name,
+            args.assignment_probs_shards as usize,
+            args.output_format,
+            args.compress,
+        )?;
+        resource_usage::end_stage("write_assignment_probs");
     }
 
     Ok(())
@@ -194,18 +1089,12 @@ pub fn quantify_bulk_alignments_from_bam<R: BufRead>(
     args: &Args,
     seqcol_digest: seqcol_rs::DigestResult,
 ) -> anyhow::Result<()> {
-    let mut name_vec = if filter_opts.write_assignment_probs {
-        Some(SwapVec::<String>::with_config(SwapVecConfig {
-            swap_after: Default::default(),
-            batch_size: Default::default(),
-            compression: Some(swapvec::Compression::Lz4),
-        }))
-    } else {
-        None
-    };
+    let mut name_vec = new_name_vec(&filter_opts, args);
     // now parse the actual alignments for the reads and store the results
     // in our in-memory stor
-    let mut store = InMemoryAlignmentStore::new(filter_opts, header);
+    let mut store = new_store(filter_opts, header, args);
+    store.read_filter = get_read_filter(args, txps_name)?;
+    store.internal_priming = get_internal_priming(args, txps_name)?;
     alignment_parser::parse_alignments(
         &mut store,
         &mut name_vec,
@@ -213,7 +1102,8 @@ pub fn quantify_bulk_alignments_from_bam<R: BufRead>(
         reader,
         txps,
         args.sort_check_num,
-        args.quiet,
+        args.bootstrap_strata == BootstrapStrata::Rg,
+        ReadSubsample::from_args(args),
     )?;
     perform_inference_and_write_output(
         header,
@@ -221,12 +1111,212 @@ pub fn quantify_bulk_alignments_from_bam<R: BufRead>(
         name_vec,
         txps,
         txps_name,
-        seqcol_digest,
+        &seqcol_digest,
+        None,
         args,
     )
 }
 
-fn get_source_type(pb: &std::path::Path) -> InputSourceType {
+/// Quantify the alignments saved to the alignment `cache` by an earlier run (with
+/// `--save-alignment-cache`), whose transcripts are those of `header`.
+#[allow(clippy::too_many_arguments)]
+pub fn quantify_bulk_alignments_from_cache(
+    header: &noodles_sam::Header,
+    filter_opts: AlignmentFilters,
+    cache: &std::path::Path,
+    txps: &mut [TranscriptInfo],
+    txps_name: &[String],
+    args: &Args,
+    seqcol_digest: seqcol_rs::DigestResult,
+) -> anyhow::Result<()> {
+    let keep_names = new_name_vec(&filter_opts, args).is_some();
+    let mut store = new_store(filter_opts, header, args);
+    let loaded = alignment_cache::read_alignment_cache(cache, &mut store, txps, keep_names)?;
+    if args.lanes && loaded.lane_reads.is_none() {
+        return Err(bad_input(format!(
+            "--lanes needs the lane of each read, which the alignment cache {} does not hold.",
+            cache.display()
+        )));
+    }
+    resource_usage::end_stage("load_alignment_cache");
+    perform_inference_and_write_output(
+        header,
+        &mut store,
+        loaded.name_vec,
+        txps,
+        txps_name,
+        &seqcol_digest,
+        loaded.lane_reads.as_deref().filter(|_| args.lanes),
+        args,
+    )
+}
+
+/// A new store for the alignments, in the compact representation if `--low-mem` was
+/// requested, which reduces its memory use once `--max-mem` is exceeded.
+fn new_store<'h>(
+    filter_opts: AlignmentFilters,
+    header: &'h noodles_sam::Header,
+    args: &Args,
+) -> InMemoryAlignmentStore<'h> {
+    let mut store = if args.low_mem {
+        InMemoryAlignmentStore::new_low_mem(filter_opts, header)
+    } else {
+        InMemoryAlignmentStore::new(filter_opts, header)
+    };
+    store.mem_guard = MemoryGuard::new();
+    store
+}
+
+/// The (swappable) vector in which the read names are kept, if they are needed for the
+/// output.
+fn new_name_vec(filter_opts: &AlignmentFilters, args: &Args) -> Option<SwapVec<String>> {
+    if filter_opts.write_assignment_probs
+        || args.no_em
+        || needs_per_read_info(args)
+        || args.save_alignment_cache.is_some()
+    {
+        Some(SwapVec::<String>::with_config(SwapVecConfig {
+            swap_after: Default::default(),
+            batch_size: Default::default(),
+            compression: Some(swapvec::Compression::Lz4),
+        }))
+    } else {
+        None
+    }
+}
+
+/// The output prefix of the read group (or demultiplexed sample) `id`, i.e. `<output>.<id>`
+/// (with the characters of `id` that are unsafe in a file name replaced).
+fn sample_output(args: &Args, id: &str) -> PathBuf {
+    args.output
+        .with_additional_extension(&format!(".{}", file_safe(id)))
+}
+
+/// Quantify the reads of each read group of the `header` as a separate sample, from a
+/// single pass over the alignments, writing the output of each to `<output>.<read group id>`.
+/// Returns the output prefixes of the read groups.
+pub fn quantify_bulk_alignments_by_read_group<R: BufRead>(
+    header: &noodles_sam::Header,
+    filter_opts: AlignmentFilters,
+    reader: &mut bam::io::Reader<R>,
+    txps: &[TranscriptInfo],
+    txps_name: &[String],
+    args: &Args,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let read_groups: Vec<String> = header
+        .read_groups()
+        .keys()
+        .map(|id| id.to_string())
+        .collect();
+    if read_groups.is_empty() {
+        anyhow::bail!(
+            "--split-by-read-group was given, but the header of the alignments has no @RG records."
+        );
+    }
+    info!(
+        "quantifying the reads of each of {} read groups separately.",
+        read_groups.len()
+    );
+
+    let internal_priming = get_internal_priming(args, txps_name)?;
+    let mut stores = Vec::with_capacity(read_groups.len());
+    let mut name_vecs = Vec::with_capacity(read_groups.len());
+    let mut rg_txps = Vec::with_capacity(read_groups.len());
+    for _ in read_groups.iter() {
+        let mut store = new_store(filter_opts.clone(), header, args);
+        store.read_filter = get_read_filter(args, txps_name)?;
+        store.internal_priming = internal_priming.clone();
+        stores.push(store);
+        name_vecs.push(new_name_vec(&filter_opts, args));
+        rg_txps.push(txps.to_vec());
+    }
+    let mut targets: Vec<ParseTarget> = stores
+        .iter_mut()
+        .zip(name_vecs.iter_mut())
+        .zip(rg_txps.iter_mut())
+        .map(|((store, name_vec), txps)| ParseTarget {
+            store,
+            name_vec,
+            txps,
+        })
+        .collect();
+    let num_skipped = alignment_parser::parse_alignments_by_read_group(
+        &mut targets,
+        header,
+        reader,
+        args.sort_check_num,
+        args.bootstrap_strata == BootstrapStrata::Rg,
+        ReadSubsample::from_args(args),
+    )?;
+    drop(targets);
+    if num_skipped > 0 {
+        warn!(
+            "{} reads had no (known) read group, and were not quantified.",
+            num_skipped.to_formatted_string(&Locale::en)
+        );
+    }
+
+    let mut outputs = Vec::with_capacity(read_groups.len());
+    for (((id, mut store), name_vec), mut txps) in
+        read_groups.iter().zip(stores).zip(name_vecs).zip(rg_txps)
+    {
+        let _rg_span = info_span!("read_group", id = %id).entered();
+        let rg_args = Args {
+            output: sample_output(args, id),
+            ..args.clone()
+        };
+        info!(
+            "quantifying the {} reads of read group {}.",
+            store.num_input_reads.to_formatted_string(&Locale::en),
+            id
+        );
+        perform_inference_and_write_output(
+            header,
+            &mut store,
+            name_vec,
+            &mut txps,
+            txps_name,
+            &digest_utils::digest_from_header(header)?,
+            None,
+            &rg_args,
+        )?;
+        outputs.push(rg_args.output);
+    }
+    Ok(outputs)
+}
+
+/// The format of the reads in `pb`, inferred from its first bytes (after decompression, if
+/// the file is gzipped): a uBAM file begins with the BAM magic number, a FASTA file with `>`
+/// and a FASTQ file with `@`. Only regular files are inspected, since the bytes read from
+/// a pipe (e.g. under process substitution) could not be read again.
+fn sniff_source_type(pb: &std::path::Path) -> std::io::Result<InputSourceType> {
+    let file = std::fs::File::open(pb)?;
+    if !file.metadata()?.is_file() {
+        return Ok(InputSourceType::Unknown);
+    }
+    let mut head = Vec::with_capacity(4);
+    file.take(4).read_to_end(&mut head)?;
+    if head.starts_with(&[0x1f, 0x8b]) {
+        // gzip, or the BGZF variant of it used by BAM files
+        head.clear();
+        flate2::read::MultiGzDecoder::new(std::fs::File::open(pb)?)
+            .take(4)
+            .read_to_end(&mut head)?;
+    }
+    Ok(match head.as_slice() {
+        [b'B', b'A', b'M', 1] => InputSourceType::Ubam,
+        [b'>' | b'@', ..] => InputSourceType::Fastx,
+        _ => InputSourceType::Unknown,
+    })
+}
+
+/// The format of the reads in `pb`, inferred from its contents if possible, and otherwise
+/// from its suffix.
+pub(crate) fn get_source_type(pb: &std::path::Path) -> InputSourceType {
+    match sniff_source_type(pb) {
+        Ok(InputSourceType::Unknown) | Err(_) => {}
+        Ok(source_type) => return source_type,
+    }
     let faq_endings = vec![
         ".fasta",
         ".fastq",
@@ -234,16 +1324,20 @@ fn get_source_type(pb: &std::path::Path) -> InputSourceType {
         ".FASTQ",
         ".fa",
         ".fq",
+        ".fna",
         ".FA",
         ".FQ",
+        ".FNA",
         ".fasta.gz",
         ".fastq.gz",
         ".FASTA.GZ",
         ".FASTQ.GZ",
         ".fa.gz",
         ".fq.gz",
+        ".fna.gz",
         ".FA.GZ",
         ".FQ.GZ",
+        ".FNA.GZ",
     ];
     let ubam_endings = vec![".bam", ".BAM", ".ubam", ".UBAM"];
     if let Some(ps) = pb.to_str() {
@@ -262,17 +1356,20 @@ fn get_source_type(pb: &std::path::Path) -> InputSourceType {
     InputSourceType::Unknown
 }
 
+/// Map the reads of `read_paths` and quantify them, writing the output to `<output>` or,
+/// if the reads are demultiplexed (with `--demux-barcodes`), the output of each sample to
+/// `<output>.<sample>`. Returns the output prefixes of the quantified samples.
 #[allow(clippy::too_many_arguments)]
 pub fn quantify_bulk_alignments_raw_reads(
     header: &noodles_sam::Header,
-    mut aligner: minimap2::Aligner<minimap2::Built>,
+    mut aligner: mm_utils::PartitionedAligner,
     filter_opts: AlignmentFilters,
     read_paths: &[std::path::PathBuf],
     txps: &mut [TranscriptInfo],
     txps_name: &[String],
     args: &Args,
     seqcol_digest: seqcol_rs::DigestResult,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Vec<PathBuf>> {
     // now parse the actual alignments for the reads and store the results
     // in our in-memory stor
 
@@ -282,16 +1379,67 @@ pub fn quantify_bulk_alignments_raw_reads(
         txp_info_view.push(ti.clone());
     }
 
+    // the barcodes (if any) by which the reads are assigned to the samples of the run,
+    // each of which is quantified separately
+    let demux = args
+        .demux_barcodes
+        .as_deref()
+        .map(|spec| {
+            BarcodeDemux::new(
+                spec,
+                args.demux_samples.as_deref(),
+                args.demux_search_len,
+                args.demux_max_error_rate,
+            )
+        })
+        .transpose()?;
+    let num_samples = demux.as_ref().map_or(1, |d| d.samples().len());
+    // the transcripts of each demultiplexed sample, whose coverage is tracked separately
+    let mut sample_txps: Vec<Vec<TranscriptInfo>> = if demux.is_some() {
+        vec![txps.to_vec(); num_samples]
+    } else {
+        Vec::new()
+    };
+
+    // load the user's read filter (if any, for each sample), and the A-rich stretches of
+    // the transcripts (for --internal-priming), before we start reading
+    let read_filters = (0..num_samples)
+        .map(|_| get_read_filter(args, txps_name))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let internal_priming = get_internal_priming(args, txps_name)?;
+
     // at least one mapping thread, otherwise everything but the fastx parser
-    // and the in memory alignment store populator
+    // and the in memory alignment store populator; the mapping threads are
+    // throttled at runtime when they are not the bottleneck
     let map_threads = args.threads.saturating_sub(2).max(1);
+    let balancer = ThreadBalancer::new("read mapping", map_threads);
 
     let per_thread_cap_kalloc =
         ((args.thread_buff_size as f64) / (args.threads as f64)).ceil() as i64;
-    aligner.mapopt.cap_kalloc = per_thread_cap_kalloc;
+    aligner.update_mapopt(|mapopt| mapopt.cap_kalloc = per_thread_cap_kalloc);
+    // the reads poorly explained by their alignments get a second chance with a more
+    // sensitive aligner
+    let second_chance_aligner = args
+        .second_chance_fit
+        .map(|min_fit| (aligner.with_sensitive_mapopt(), min_fit));
+    // the adapters and primers (if any) trimmed from the reads before they are mapped
+    let trimmer = args
+        .trim_adapters
+        .as_deref()
+        .map(|spec| AdapterTrimmer::new(spec, args.trim_search_len, args.trim_max_error_rate))
+        .transpose()?;
 
     type ReadGroup = ReadChunkWithNames;
-    type AlignmentGroupInfo = (Vec<AlnInfo>, Vec<f32>, Vec<usize>, Option<Vec<String>>);
+    type AlignmentGroupInfo = (
+        Vec<AlnInfo>,
+        Vec<f32>,
+        Vec<usize>,
+        Vec<u32>,
+        Vec<u16>,
+        Vec<u16>,
+        Option<Vec<String>>,
+        Option<u64>,
+    );
 
     let (read_sender, read_receiver): (Sender<ReadGroup>, Receiver<ReadGroup>) =
         bounded(args.threads * 10);
@@ -299,10 +1447,17 @@ pub fn quantify_bulk_alignments_raw_reads(
     const READ_CHUNK_SIZE: usize = 200;
     let mut rpaths = vec![];
     read_paths.clone_into(&mut rpaths);
+    let subsample = ReadSubsample::from_args(args);
 
     // Producer thread: reads sequences and sends them to the channel
-    let producer = std::thread::spawn(move || {
+    let producer_balancer = balancer.clone();
+    let producer = std::thread::spawn(move || -> anyhow::Result<(usize, Vec<usize>)> {
+        profile::name_thread("reader".to_string());
+        // once all reads have been sent, let every mapping thread drain the queue
+        let _release_mappers = producer_balancer.release_on_drop();
         let mut ctr = 0_usize;
+        // the number of reads of the input seen, including those not in the subsample
+        let mut num_seen = 0_u64;
         let mut chunk_size = 0_usize;
         let mut read_chunk = ReadChunkWithNames::new();
 
@@ -315,25 +1470,53 @@ pub fn quantify_bulk_alignments_raw_reads(
             *chunk_size += 1;
             *ctr += 1;
             if *chunk_size >= READ_CHUNK_SIZE {
+                // send the chunk, and prepare for the next one
+                let _profile = profile::scope(Stage::QueueWait);
                 read_sender
-                    .send(read_chunk.clone())
+                    .send(read_chunk.take())
                     .expect("Error sending sequence");
-                // prepare for the next chunk
-                read_chunk.clear();
                 *chunk_size = 0;
             }
         };
 
+        // the number of reads in each input file (lane)
+        let mut lane_reads = Vec::with_capacity(rpaths.len());
+
         // read from either a UBAM or (possibly compressed) FASTX file
-        for read_path in rpaths {
+        for (lane, read_path) in rpaths.into_iter().enumerate() {
+            // a chunk never spans multiple lanes
+            if chunk_size > 0 {
+                read_sender
+                    .send(read_chunk.take())
+                    .expect("Error sending sequence");
+                chunk_size = 0;
+            }
+            read_chunk.lane = lane as u16;
+            let lane_start = ctr;
+            if subsample.is_done(num_seen) {
+                lane_reads.push(0);
+                continue;
+            }
             match get_source_type(&read_path) {
                 InputSourceType::Ubam => {
-                    let mut reader = std::fs::File::open(read_path)
+                    let mut reader = std::fs::File::open(&read_path)
                         .map(bam::io::Reader::new)
-                        .expect("could not create BAM reader");
-                    let header = reader.read_header().expect("could not read BAM header");
-                    for result in reader.record_bufs(&header) {
-                        let record = result.expect("Error reading ubam record");
+                        .with_context(|| format!("could not open {}", read_path.display()))?;
+                    let header = reader.read_header().with_context(|| {
+                        format!("could not read the header of {}", read_path.display())
+                    })?;
+                    let mut records = reader.record_bufs(&header);
+                    while let Some(result) = profile::time(Stage::Parsing, || records.next()) {
+                        if subsample.is_done(num_seen) {
+                            break;
+                        }
+                        num_seen += 1;
+                        let record = result.with_context(|| {
+                            format!("could not read a record of {}", read_path.display())
+                        })?;
+                        if !subsample.keep(record.name().map_or(b"".as_slice(), |n| n.as_ref())) {
+                            continue;
+                        }
                         record.add_to_read_group(&mut read_chunk);
                         mark_chunk(&mut chunk_size, &mut ctr, &mut read_chunk, &read_sender);
                     }
@@ -341,19 +1524,48 @@ pub fn quantify_bulk_alignments_raw_reads(
                 s @ (InputSourceType::Fastx | InputSourceType::Unknown) => {
                     if matches!(s, InputSourceType::Unknown) {
                         warn!(
-                            "could not determine input file type for {} from suffix; assuming (possibly gzipped) fastx",
+                            "could not determine input file type for {} from its contents or suffix; assuming (possibly gzipped) fastx",
                             read_path.display()
                         );
                     }
-                    let mut reader =
-                        parse_fastx_file(read_path).expect("valid path/file to read sequences");
-                    while let Some(result) = reader.next() {
-                        let record = result.expect("Error reading record");
+                    let mut reader = parse_fastx_file(&read_path)
+                        .with_context(|| format!("could not open {}", read_path.display()))?;
+                    let mut first_record = true;
+                    while let Some(result) = {
+                        let _profile = profile::scope(Stage::Parsing);
+                        reader.next()
+                    } {
+                        if subsample.is_done(num_seen) {
+                            break;
+                        }
+                        num_seen += 1;
+                        let record = result.with_context(|| {
+                            format!("could not read a record of {}", read_path.display())
+                        })?;
+                        // the format is detected for each file, so that FASTA and FASTQ
+                        // files can be mixed; only the sequences of the reads are used
+                        if first_record {
+                            first_record = false;
+                            info!(
+                                "reading {} as {}.",
+                                read_path.display(),
+                                if record.qual().is_some() {
+                                    "FASTQ"
+                                } else {
+                                    "FASTA (without base qualities)"
+                                }
+                            );
+                        }
+                        let name = record.id().split(u8::is_ascii_whitespace).next();
+                        if !subsample.keep(name.unwrap_or_default()) {
+                            continue;
+                        }
                         record.add_to_read_group(&mut read_chunk);
                         mark_chunk(&mut chunk_size, &mut ctr, &mut read_chunk, &read_sender);
                     }
                 }
             }
+            lane_reads.push(ctr - lane_start);
         }
         // if any reads remain, send them off
         if chunk_size > 0 {
@@ -361,57 +1573,196 @@ pub fn quantify_bulk_alignments_raw_reads(
                 .send(read_chunk)
                 .expect("Error sending sequence");
         }
-        ctr
+        Ok((ctr, lane_reads))
     });
 
     // we need the scope here so we can borrow the relevant non-'static data
-    let (mut store, name_vec) = std::thread::scope(|s| {
+    let (targets, lane_reads, demux_stats) = std::thread::scope(|s| {
         const ALN_GROUP_CHUNK_LIMIT: usize = 100;
 
+        // if requested, the alignments of each chunk of reads are
+        // written to a BAM file by a dedicated thread
+        let (bam_sender, bam_writer) = match args.write_bam {
+            Some(ref bam_path) => {
+                let (bam_sender, bam_receiver) = bounded::<Vec<RecordBuf>>(args.threads * 10);
+                let writer = s.spawn(move || bam_output::write_bam(bam_path, header, bam_receiver));
+                (Some(bam_sender), Some(writer))
+            }
+            None => (None, None),
+        };
+
         let (aln_group_sender, aln_group_receiver): (
             Sender<AlignmentGroupInfo>,
             Receiver<AlignmentGroupInfo>,
         ) = bounded(args.threads * 100);
 
         // Consumer threads: receive sequences and perform alignment
-        let write_assignment_probs: bool = args.write_assignment_probs.is_some();
+        let keep_read_names: bool = args.write_assignment_probs.is_some()
+            || args.no_em
+            || needs_per_read_info(args)
+            || args.save_alignment_cache.is_some()
+            || args.read_filter_plugin.is_some();
+        // with `--deterministic`, the alignments of each chunk of reads are sent as one batch,
+        // tagged with the index of the chunk, so that they are added to the store in the
+        // order of the input, however the chunks are distributed among the mapping threads
+        let ordered = args.deterministic;
+        {
+            let read_receiver = read_receiver.clone();
+            let aln_group_receiver = aln_group_receiver.clone();
+            let balancer = balancer.clone();
+            s.spawn(move || {
+                balancer.monitor(|| {
+                    (
+                        queue_fill(read_receiver.len(), read_receiver.capacity()),
+                        Some(queue_fill(
+                            aln_group_receiver.len(),
+                            aln_group_receiver.capacity(),
+                        )),
+                    )
+                });
+            });
+        }
         let consumers: Vec<_> = (0..map_threads)
-            .map(|_| {
+            .map(|worker_id| {
                 let receiver = read_receiver.clone();
+                let balancer = balancer.clone();
                 let mut filter = filter_opts.clone();
                 let loc_aligner = aligner.clone();
+                let loc_second_chance = second_chance_aligner.clone();
 
                 let my_txp_info_view = &txp_info_view;
+                let trimmer = trimmer.as_ref();
+                let demux = demux.as_ref();
                 let aln_group_sender = aln_group_sender.clone();
+                let bam_sender = bam_sender.clone();
+                let sample_span = tracing::Span::current();
                 s.spawn(move || {
-                    let mut discard_table = DiscardTable::new();
+                    let _sample_span = sample_span.entered();
+                    profile::name_thread(format!("mapper-{}", worker_id));
+                    // the statistics of the reads are kept for each demultiplexed sample
+                    let mut discard_tables: Vec<DiscardTable> =
+                        (0..num_samples).map(|_| DiscardTable::new()).collect();
+                    let mut second_chance: Vec<Option<SecondChanceStats>> = (0..num_samples)
+                        .map(|_| {
+                            loc_second_chance
+                                .as_ref()
+                                .map(|(_, min_fit)| SecondChanceStats::new(*min_fit))
+                        })
+                        .collect();
+                    let mut trim_stats: Vec<Option<TrimStats>> = (0..num_samples)
+                        .map(|_| {
+                            trimmer
+                                .zip(args.trim_adapters.as_deref())
+                                .map(|(t, spec)| t.new_stats(spec))
+                        })
+                        .collect();
+                    let mut demux_stats = demux.map(BarcodeDemux::new_stats);
 
                     let mut chunk_size = 0_usize;
                     let mut aln_group_alns: Vec<AlnInfo> = Vec::new();
                     let mut aln_group_probs: Vec<f32> = Vec::new();
                     let mut aln_group_boundaries: Vec<usize> = Vec::new();
-                    let mut aln_group_read_names = write_assignment_probs.then(Vec::new);
+                    let mut aln_group_read_lens: Vec<u32> = Vec::new();
+                    let mut aln_group_read_lanes: Vec<u16> = Vec::new();
+                    let mut aln_group_read_samples: Vec<u16> = Vec::new();
+                    let mut aln_group_read_names = keep_read_names.then(Vec::new);
+                    let mut bam_records = bam_sender.as_ref().map(|_| Vec::<RecordBuf>::new());
                     aln_group_boundaries.push(0);
 
                     // get the next chunk of reads
-                    for read_chunk in receiver {
+                    loop {
+                        balancer.wait_turn(worker_id);
+                        let Ok(read_chunk) = profile::time(Stage::QueueWait, || receiver.recv())
+                        else {
+                            break;
+                        };
+                        let lane = read_chunk.lane;
                         // iterate over every read
-                        for (name, seq) in read_chunk.iter() {
+                        for (name, seq, tags) in read_chunk.iter() {
+                            // the sample of the read is found from its barcode, before any
+                            // trimming; reads assigned to no sample are not mapped
+                            let sample = match (demux, demux_stats.as_mut()) {
+                                (Some(demux), Some(stats)) => match demux.assign(seq, stats) {
+                                    Some(sample) => sample,
+                                    None => continue,
+                                },
+                                _ => 0,
+                            };
+                            let seq = match (trimmer, trim_stats[sample].as_mut()) {
+                                (Some(trimmer), Some(stats)) => trimmer.trim(seq, stats),
+                                _ => seq,
+                            };
+                            // a read made entirely of adapters is left unmapped
+                            if seq.is_empty() {
+                                continue;
+                            }
                             // map the next read, with cigar string
                             let map_res_opt =
-                                loc_aligner.map(seq, true, false, None, None, Some(name));
+                                profile::time(Stage::Alignment, || loc_aligner.map(seq, name));
                             if let Ok(mut mappings) = map_res_opt {
-                                let (ag, aprobs) = filter.filter(
-                                    &mut discard_table,
-                                    header,
-                                    my_txp_info_view,
-                                    &mut mappings,
-                                );
+                                // all mappings of the read, before they are filtered, are
+                                // recorded once we know which pass they come from
+                                let mut bam_mappings =
+                                    bam_records.as_ref().map(|_| mappings.clone());
+                                let (mut ag, mut aprobs) =
+                                    profile::time(Stage::Probabilities, || {
+                                        filter.filter(
+                                            &mut discard_tables[sample],
+                                            header,
+                                            my_txp_info_view,
+                                            &mut mappings,
+                                        )
+                                    });
+                                // if the read is poorly explained, re-align it with the more
+                                // sensitive aligner and keep the better fitting alignments;
+                                // the discard table describes the first pass
+                                if let (Some((sensitive, min_fit)), Some(stats)) =
+                                    (&loc_second_chance, second_chance[sample].as_mut())
+                                    && read_fit(&ag, seq.len()) < *min_fit
+                                {
+                                    stats.num_realigned += 1;
+                                    if let Ok(mut rescue_mappings) = sensitive.map(seq, name) {
+                                        let rescue_bam_mappings =
+                                            bam_mappings.as_ref().map(|_| rescue_mappings.clone());
+                                        let (rescue_ag, rescue_aprobs) = filter.filter(
+                                            &mut DiscardTable::new(),
+                                            header,
+                                            my_txp_info_view,
+                                            &mut rescue_mappings,
+                                        );
+                                        if read_fit(&rescue_ag, seq.len())
+                                            > read_fit(&ag, seq.len())
+                                        {
+                                            if ag.is_empty() {
+                                                stats.num_rescued += 1;
+                                            } else {
+                                                stats.num_improved += 1;
+                                            }
+                                            ag = rescue_ag;
+                                            aprobs = rescue_aprobs;
+                                            bam_mappings = rescue_bam_mappings;
+                                        }
+                                    }
+                                }
+                                if let (Some(records), Some(mappings)) =
+                                    (&mut bam_records, &bam_mappings)
+                                {
+                                    bam_output::add_mapping_records(
+                                        name, seq, tags, mappings, header, records,
+                                    )
+                                    .expect("could not convert mappings to BAM records");
+                                }
 
+                                if filter.read_quality_weighting {
+                                    read_quality::weight_by_accuracy(&mut aprobs, tags.accuracy());
+                                }
                                 if !ag.is_empty() {
                                     aln_group_alns.extend_from_slice(&ag);
                                     aln_group_probs.extend_from_slice(&aprobs);
                                     aln_group_boundaries.push(aln_group_alns.len());
+                                    aln_group_read_lens.push(seq.len() as u32);
+                                    aln_group_read_lanes.push(lane);
+                                    aln_group_read_samples.push(sample as u16);
                                     // if we are storing read names
                                     if let Some(ref mut names_vec) = aln_group_read_names {
                                         let name_str = String::from_utf8_lossy(name).into_owned();
@@ -419,20 +1770,28 @@ pub fn quantify_bulk_alignments_raw_reads(
                                     }
                                     chunk_size += 1;
                                 }
-                                if chunk_size >= ALN_GROUP_CHUNK_LIMIT {
+                                if !ordered && chunk_size >= ALN_GROUP_CHUNK_LIMIT {
+                                    let _profile = profile::scope(Stage::QueueWait);
                                     aln_group_sender
                                         .send((
                                             aln_group_alns.clone(),
                                             aln_group_probs.clone(),
                                             aln_group_boundaries.clone(),
+                                            aln_group_read_lens.clone(),
+                                            aln_group_read_lanes.clone(),
+                                            aln_group_read_samples.clone(),
                                             aln_group_read_names,
+                                            None,
                                         ))
                                         .expect("Error sending alignment group");
                                     aln_group_alns.clear();
                                     aln_group_probs.clear();
                                     aln_group_boundaries.clear();
                                     aln_group_boundaries.push(0);
-                                    aln_group_read_names = write_assignment_probs.then(Vec::new);
+                                    aln_group_read_lens.clear();
+                                    aln_group_read_lanes.clear();
+                                    aln_group_read_samples.clear();
+                                    aln_group_read_names = keep_read_names.then(Vec::new);
                                     chunk_size = 0;
                                 }
                             } else {
@@ -442,6 +1801,32 @@ pub fn quantify_bulk_alignments_raw_reads(
                                 );
                             }
                         }
+                        if let (Some(records), Some(sender)) = (&mut bam_records, &bam_sender) {
+                            sender
+                                .send(std::mem::take(records))
+                                .expect("Error sending BAM records");
+                        }
+                        // every chunk is sent, even if none of its reads aligned, so that
+                        // the store need not wait for it
+                        if ordered {
+                            let _profile = profile::scope(Stage::QueueWait);
+                            aln_group_sender
+                                .send((
+                                    std::mem::take(&mut aln_group_alns),
+                                    std::mem::take(&mut aln_group_probs),
+                                    std::mem::replace(&mut aln_group_boundaries, vec![0]),
+                                    std::mem::take(&mut aln_group_read_lens),
+                                    std::mem::take(&mut aln_group_read_lanes),
+                                    std::mem::take(&mut aln_group_read_samples),
+                                    std::mem::replace(
+                                        &mut aln_group_read_names,
+                                        keep_read_names.then(Vec::new),
+                                    ),
+                                    Some(read_chunk.index),
+                                ))
+                                .expect("Error sending alignment group");
+                            chunk_size = 0;
+                        }
                     }
                     if chunk_size > 0 {
                         aln_group_sender
@@ -449,98 +1834,138 @@ pub fn quantify_bulk_alignments_raw_reads(
                                 aln_group_alns,
                                 aln_group_probs,
                                 aln_group_boundaries,
+                                aln_group_read_lens,
+                                aln_group_read_lanes,
+                                aln_group_read_samples,
                                 aln_group_read_names,
+                                None,
                             ))
                             .expect("Error sending alignment group");
                     }
-                    discard_table
+                    (discard_tables, second_chance, trim_stats, demux_stats)
                 })
             })
             .collect();
 
-        #[allow(clippy::useless_asref)]
-        let txps_mut = txps.as_mut();
+        // the transcripts of each sample; without demultiplexing, those of the single sample
+        let mut txps_views: Vec<&mut [TranscriptInfo]> = if sample_txps.is_empty() {
+            vec![&mut *txps]
+        } else {
+            sample_txps.iter_mut().map(Vec::as_mut_slice).collect()
+        };
         let filter_opts_store = filter_opts.clone();
         let aln_group_consumer = s.spawn(move || {
-            let mut name_vec = if filter_opts_store.write_assignment_probs {
-                Some(SwapVec::<String>::with_config(SwapVecConfig {
-                    swap_after: Default::default(),
-                    batch_size: Default::default(),
-                    compression: Some(swapvec::Compression::Lz4),
-                }))
-            } else {
-                None
-            };
+            // the store (and read names) of each sample
+            let mut targets: Vec<(InMemoryAlignmentStore, Option<SwapVec<String>>)> = read_filters
+                .into_iter()
+                .map(|read_filter| {
+                    let mut store = new_store(filter_opts_store.clone(), header, args);
+                    store.read_filter = read_filter;
+                    store.internal_priming = internal_priming.clone();
+                    (store, new_name_vec(&filter_opts_store, args))
+                })
+                .collect();
 
-            let mut store = InMemoryAlignmentStore::new(filter_opts_store, header);
+            let pb = progress::counter("Number of reads mapped");
 
-            let pb = if args.quiet {
-                indicatif::ProgressBar::hidden()
-            } else {
-                indicatif::ProgressBar::new_spinner().with_message("Number of reads mapped")
-            };
-
-            pb.set_style(
-                indicatif::ProgressStyle::with_template(
-                    "[{elapsed_precise}] {spinner:4.green/blue} {msg} {human_pos:>12}",
-                )
-                .unwrap()
-                .tick_chars("⠁⠁⠉⠙⠚⠒⠂⠂⠒⠲⠴⠤⠄⠄⠤⠠⠠⠤⠦⠖⠒⠐⠐⠒⠓⠋⠉⠈⠈"),
-            );
-            pb.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(4));
-
-            for (ags, aprobs, aln_boundaries, read_names) in aln_group_receiver {
-                // if we are getting read names out then we are going to "reverse" them
-                // here so that we can simply pop the strings off the back to get them
-                // in order. We do this since we cannot otherwise "move" a string out of a
-                // Vec.
-                let mut reversed_read_names = if let Some(mut names_vec) = read_names {
-                    names_vec.reverse();
-                    Some(names_vec)
-                } else {
-                    None
+            // puts the batches of a `--deterministic` run back in the order of the input
+            let mut reorder = ReorderBuffer::<AlignmentGroupInfo>::new();
+            for batch in aln_group_receiver {
+                let ready = match batch.7 {
+                    None => vec![batch],
+                    Some(index) => reorder.push(index, batch),
                 };
-
-                for window in aln_boundaries.windows(2) {
-                    pb.inc(1);
-                    let group_start = window[0];
-                    let group_end = window[1];
-                    let ag = &ags[group_start..group_end];
-                    let as_probs = &aprobs[group_start..group_end];
-                    let read_name_opt = if let Some(ref mut names_vec) = reversed_read_names {
-                        names_vec.pop()
+                for (
+                    ags,
+                    aprobs,
+                    aln_boundaries,
+                    read_lens,
+                    read_lanes,
+                    read_samples,
+                    read_names,
+                    _,
+                ) in ready
+                {
+                    // if we are getting read names out then we are going to "reverse" them
+                    // here so that we can simply pop the strings off the back to get them
+                    // in order. We do this since we cannot otherwise "move" a string out of a
+                    // Vec.
+                    let mut reversed_read_names = if let Some(mut names_vec) = read_names {
+                        names_vec.reverse();
+                        Some(names_vec)
                     } else {
                         None
                     };
 
-                    if store.add_filtered_group(ag, as_probs, txps_mut) {
-                        if let Some(ref mut nvec) = name_vec {
-                            let read_name = read_name_opt.unwrap_or(EMPTY_READ_NAME.to_string());
-                            nvec.push(read_name)
-                                .expect("cannot push name to read name vector");
-                        }
-                        if ag.len() == 1 {
-                            store.inc_unique_alignments();
+                    for (window, read_len, read_lane, read_sample) in izip!(
+                        aln_boundaries.windows(2),
+                        read_lens,
+                        read_lanes,
+                        read_samples
+                    ) {
+                        pb.inc(1);
+                        let (store, name_vec) = &mut targets[read_sample as usize];
+                        let txps_mut = &mut *txps_views[read_sample as usize];
+                        let group_start = window[0];
+                        let group_end = window[1];
+                        let ag = &ags[group_start..group_end];
+                        let as_probs = &aprobs[group_start..group_end];
+                        let read_name_opt = if let Some(ref mut names_vec) = reversed_read_names {
+                            names_vec.pop()
+                        } else {
+                            None
+                        };
+
+                        let (ag, as_probs) = if store.read_filter.is_some() {
+                            let read_name = read_name_opt.as_deref().unwrap_or(EMPTY_READ_NAME);
+                            store.apply_read_filter(read_name, read_len, ag, as_probs)
+                        } else {
+                            (Cow::Borrowed(ag), Cow::Borrowed(as_probs))
+                        };
+                        let (ag, as_probs) = store.apply_internal_priming(&ag, &as_probs);
+
+                        if store.add_filtered_group(&ag, &as_probs, read_len, txps_mut) {
+                            if args.lanes || args.bootstrap_strata == BootstrapStrata::Rg {
+                                store.read_lanes.push(read_lane);
+                            }
+                            if let Some(nvec) = name_vec {
+                                let read_name =
+                                    read_name_opt.unwrap_or(EMPTY_READ_NAME.to_string());
+                                nvec.push(read_name)
+                                    .expect("cannot push name to read name vector");
+                            }
+                            if ag.len() == 1 {
+                                store.inc_unique_alignments();
+                            }
                         }
                     }
                 }
             }
             pb.finish_with_message("Finished aligning reads.");
-            (store, name_vec)
+            targets
         });
 
         // Wait for the producer to finish reading
-        let total_reads = producer.join().expect("Producer thread panicked");
+        let (total_reads, lane_reads) = producer.join().expect("Producer thread panicked")?;
 
-        let mut discard_tables: Vec<DiscardTable> = Vec::with_capacity(map_threads);
+        // the statistics of each mapping thread, for each sample
+        let mut worker_stats = Vec::with_capacity(map_threads);
+        let mut demux_stats = demux.as_ref().map(BarcodeDemux::new_stats);
         for consumer in consumers {
-            let dt = consumer.join().expect("Consumer thread panicked");
-            discard_tables.push(dt);
+            let (dts, scs, tss, ds) = consumer.join().expect("Consumer thread panicked");
+            if let (Some(total), Some(ds)) = (demux_stats.as_mut(), ds.as_ref()) {
+                total.merge(ds);
+            }
+            worker_stats.push((dts, scs, tss));
         }
 
         drop(aln_group_sender);
+        drop(bam_sender);
+        if let Some(writer) = bam_writer {
+            writer.join().expect("BAM writer panicked")?;
+        }
 
-        let (mut store, name_vec) = aln_group_consumer
+        let mut targets = aln_group_consumer
             .join()
             .expect("Alignment group consumer panicked");
 
@@ -549,19 +1974,113 @@ pub fn quantify_bulk_alignments_raw_reads(
             total_reads.to_formatted_string(&Locale::en)
         );
 
-        for dt in &discard_tables {
-            store.aggregate_discard_table(dt);
+        let sample_reads = demux
+            .as_ref()
+            .zip(demux_stats.as_ref())
+            .map(|(d, ds)| d.sample_reads(ds));
+        for (sample, (store, _)) in targets.iter_mut().enumerate() {
+            // the statistics of a demultiplexed sample are logged in its context
+            let _demux_span = demux
+                .as_ref()
+                .map(|d| info_span!("sample", name = %d.samples()[sample]).entered());
+            let mut second_chance = args.second_chance_fit.map(SecondChanceStats::new);
+            let mut trimming: Option<TrimStats> = None;
+            for (dts, scs, tss) in worker_stats.iter() {
+                store.aggregate_discard_table(&dts[sample]);
+                if let (Some(total), Some(sc)) = (second_chance.as_mut(), scs[sample].as_ref()) {
+                    total.merge(sc);
+                }
+                match (trimming.as_mut(), tss[sample].as_ref()) {
+                    (Some(total), Some(ts)) => total.merge(ts),
+                    (None, ts) => trimming = ts.cloned(),
+                    _ => {}
+                }
+            }
+            store.num_input_reads = sample_reads
+                .as_ref()
+                .map_or(total_reads, |r| r[sample] as usize);
+            if let Some(ref sc) = second_chance {
+                info!(
+                    "re-aligned {} poorly explained reads; {} gained alignments and {} were better explained.",
+                    sc.num_realigned.to_formatted_string(&Locale::en),
+                    sc.num_rescued.to_formatted_string(&Locale::en),
+                    sc.num_improved.to_formatted_string(&Locale::en)
+                );
+            }
+            store.second_chance = second_chance;
+            if let Some(ref ts) = trimming {
+                info!(
+                    "trimmed adapters from {} of {} reads ({} at their start, {} at their end), removing {} bases.",
+                    (ts.num_trimmed_start + ts.num_trimmed_end - ts.num_trimmed_both)
+                        .to_formatted_string(&Locale::en),
+                    ts.num_reads.to_formatted_string(&Locale::en),
+                    ts.num_trimmed_start.to_formatted_string(&Locale::en),
+                    ts.num_trimmed_end.to_formatted_string(&Locale::en),
+                    ts.num_bases_trimmed.to_formatted_string(&Locale::en)
+                );
+            }
+            store.trimming = trimming;
         }
-        (store, name_vec)
-    });
+        Ok::<_, anyhow::Error>((targets, lane_reads, demux_stats))
+    })?;
 
-    perform_inference_and_write_output(
-        header,
-        &mut store,
-        name_vec,
-        txps,
-        txps_name,
-        seqcol_digest,
-        args,
-    )
+    let (Some(demux), Some(demux_stats)) = (demux.as_ref(), demux_stats.as_ref()) else {
+        let (mut store, name_vec) = targets.into_iter().next().expect("a single sample");
+        perform_inference_and_write_output(
+            header,
+            &mut store,
+            name_vec,
+            txps,
+            txps_name,
+            &seqcol_digest,
+            args.lanes.then_some(lane_reads.as_slice()),
+            args,
+        )?;
+        return Ok(vec![args.output.clone()]);
+    };
+
+    // quantify each of the demultiplexed samples in turn
+    demux.write_report(&args.output, demux_stats)?;
+    let mut outputs = Vec::with_capacity(num_samples);
+    for (sample, ((mut store, name_vec), mut txps)) in
+        targets.into_iter().zip(sample_txps).enumerate()
+    {
+        let name = &demux.samples()[sample];
+        let _sample_span = info_span!("sample", name = %name).entered();
+        if store.num_input_reads == 0 {
+            warn!(
+                "no reads were assigned to sample {}; it is not quantified.",
+                name
+            );
+            continue;
+        }
+        let sample_args = Args {
+            output: sample_output(args, name),
+            ..args.clone()
+        };
+        info!(
+            "quantifying the {} reads of sample {}.",
+            store.num_input_reads.to_formatted_string(&Locale::en),
+            name
+        );
+        store.demux = Some(demux.sample_info(sample, demux_stats));
+        perform_inference_and_write_output(
+            header,
+            &mut store,
+            name_vec,
+            &mut txps,
+            txps_name,
+            &seqcol_digest,
+            None,
+            &sample_args,
+        )?;
+        outputs.push(sample_args.output);
+    }
+    if outputs.is_empty() {
+        return Err(bad_input(
+            "no reads were assigned to any of the expected samples; check the barcodes given \
+             with --demux-barcodes",
+        ));
+    }
+    Ok(outputs)
 }
@@ -0,0 +1,393 @@
+use crate::bulk;
+use crate::prog_opts::Args;
+use crate::util::mm_utils::PartitionedAligner;
+use crate::util::oarfish_types::{
+    AlignmentFilters, AlnInfo, CoverageBinning, DiscardTable, EMInfo, InMemoryAlignmentStore,
+    TranscriptInfo,
+};
+use anyhow::Context;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::warn;
+
+/// The number of reads mapped by a thread at a time.
+const READ_CHUNK_SIZE: usize = 200;
+
+thread_local! {
+    /// The message of the last error raised on this thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(e: anyhow::Error) {
+    let msg = CString::new(format!("{:#}", e))
+        .unwrap_or_else(|_| c"the error message contains a NUL byte".to_owned());
+    LAST_ERROR.with(|l| *l.borrow_mut() = Some(msg));
+}
+
+/// Run `f`, catching a panic so that it does not unwind across the C ABI; a panic is
+/// reported as the last error, and `on_panic` is then returned. Panics are only caught
+/// when oarfish is built to unwind on a panic (as with the `capi` profile).
+fn catch_panic<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|m| m.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_owned());
+        set_last_error(anyhow::anyhow!("oarfish panicked: {}", msg));
+        on_panic
+    })
+}
+
+/// A minimap2 index of the transcripts to quantify, along with the options of the
+/// quantifications that use it.
+pub struct OarfishIndex {
+    args: Args,
+    filter_opts: AlignmentFilters,
+    header: noodles_sam::header::Header,
+    aligner: PartitionedAligner,
+    txps_name: Vec<CString>,
+}
+
+/// The estimates of one buffer of reads.
+pub struct OarfishQuant {
+    counts: Vec<f64>,
+    num_reads: usize,
+    num_aligned_reads: usize,
+    /// the number of reads that minimap2 failed to map (which are not quantified)
+    num_failed_reads: usize,
+}
+
+/// The alignments (and their probabilities) and the length of a read that aligned.
+type ReadAlignments = (Vec<AlnInfo>, Vec<f32>, u32);
+
+/// The index of a chunk of reads, the alignments of its reads that aligned, the table of
+/// its discarded alignments and the number of its reads that could not be mapped.
+type MappedChunk = (usize, Vec<ReadAlignments>, DiscardTable, usize);
+
+fn load_index(reference: &str, options: &str) -> anyhow::Result<OarfishIndex> {
+    // nothing is written, so the output prefix is never used
+    let mut argv = vec!["oarfish", "--reference", reference, "--output", "oarfish"];
+    argv.extend(options.split_whitespace());
+    let mut args = Args::try_parse_without_input(argv)?;
+    crate::enable_implied_coverage_model(&mut args);
+    let filter_opts = crate::get_filter_opts(&args)?;
+    let (header, _, aligner, _) = crate::get_aligner_from_args(&mut args)?;
+    let aligner = aligner.context("no aligner was built for the reference")?;
+    let txps_name = header
+        .reference_sequences()
+        .keys()
+        .map(|n| CString::new(n.to_vec()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(OarfishIndex {
+        args,
+        filter_opts,
+        header,
+        aligner,
+        txps_name,
+    })
+}
+
+/// Map the `reads` to the targets of `index`, and estimate the number of reads arising
+/// from each target with the coverage model (if enabled) and the EM.
+fn quantify_reads(index: &OarfishIndex, reads: &[&[u8]]) -> anyhow::Result<OarfishQuant> {
+    let args = &index.args;
+    let header = &index.header;
+    let binning = CoverageBinning::from_args(args);
+    let mut txps: Vec<TranscriptInfo> = header
+        .reference_sequences()
+        .values()
+        .map(|rmap| {
+            if args.model_coverage {
+                TranscriptInfo::with_len_and_binning(rmap.length(), &binning)
+            } else {
+                TranscriptInfo::with_len(rmap.length())
+            }
+        })
+        .collect();
+
+    // the chunks of reads are claimed by the mapping threads in turn, and the
+    // alignments of each are added to the store in the order of the reads
+    let chunks: Vec<&[&[u8]]> = reads.chunks(READ_CHUNK_SIZE).collect();
+    let next_chunk = AtomicUsize::new(0);
+    let mut mapped: Vec<MappedChunk> = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..args.threads.max(1))
+            .map(|_| {
+                let aligner = index.aligner.clone();
+                let mut filter = index.filter_opts.clone();
+                let (chunks, next_chunk, txps) = (&chunks, &next_chunk, &txps);
+                s.spawn(move || {
+                    let mut done = Vec::new();
+                    loop {
+                        let chunk_id = next_chunk.fetch_add(1, Ordering::Relaxed);
+                        let Some(chunk) = chunks.get(chunk_id) else {
+                            break;
+                        };
+                        let mut discard_table = DiscardTable::new();
+                        let mut alignments = Vec::with_capacity(chunk.len());
+                        let mut num_failed = 0_usize;
+                        for (i, seq) in chunk.iter().enumerate() {
+                            // the reads are named by their index in the buffer
+                            let name = (chunk_id * READ_CHUNK_SIZE + i).to_string();
+                            let mut mappings = match aligner.map(seq, name.as_bytes()) {
+                                Ok(mappings) => mappings,
+                                Err(e) => {
+                                    if num_failed == 0 {
+                                        warn!("could not map read {}: {}", name, e);
+                                    }
+                                    num_failed += 1;
+                                    continue;
+                                }
+                            };
+                            let (ag, probs) =
+                                filter.filter(&mut discard_table, header, txps, &mut mappings);
+                            if !ag.is_empty() {
+                                alignments.push((ag, probs, seq.len() as u32));
+                            }
+                        }
+                        done.push((chunk_id, alignments, discard_table, num_failed));
+                    }
+                    done
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| {
+                w.join()
+                    .map_err(|_| anyhow::anyhow!("a mapping thread panicked"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(|done| done.into_iter().flatten().collect())
+    })?;
+    mapped.sort_unstable_by_key(|(chunk_id, _, _, _)| *chunk_id);
+    let num_failed_reads = mapped.iter().map(|(_, _, _, n)| n).sum();
+
+    let mut store = InMemoryAlignmentStore::new(index.filter_opts.clone(), header);
+    for (_, alignments, discard_table, _) in mapped.iter() {
+        for (ag, probs, read_len) in alignments.iter() {
+            if store.add_filtered_group(ag, probs, *read_len, &mut txps) && ag.len() == 1 {
+                store.inc_unique_alignments();
+            }
+        }
+        store.aggregate_discard_table(discard_table);
+    }
+    store.num_input_reads = reads.len();
+
+    bulk::apply_coverage_model(&mut store, &mut txps, args);
+    let emi = EMInfo {
+        eq_map: &store,
+        txp_info: &txps,
+        max_iter: args.max_em_iter,
+        min_iter: args.min_em_iter,
+        convergence_thresh: args.convergence_thresh,
+        init_abundances: None,
+        kde_model: None,
+        txp_weights: None,
+        trace: None,
+    };
+    let counts = bulk::run_em(&emi, args);
+    Ok(OarfishQuant {
+        counts,
+        num_reads: reads.len(),
+        num_aligned_reads: store.num_aligned_reads(),
+        num_failed_reads,
+    })
+}
+
+/// Load the minimap2 index of the transcripts in `reference`, which is either a FASTA file
+/// (which is indexed) or a minimap2 index. `options` holds further options of the command
+/// line of oarfish, separated by whitespace; it must include `--seq-tech`, and may set e.g.
+/// `--threads`, `--filter-group` or `--model-coverage` for the quantifications that use
+/// this index. Returns NULL on error (see `oarfish_last_error`).
+///
+/// # Safety
+/// `reference` and `options` must be valid NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_index_load(
+    reference: *const c_char,
+    options: *const c_char,
+) -> *mut OarfishIndex {
+    catch_panic(std::ptr::null_mut(), || {
+        if reference.is_null() || options.is_null() {
+            set_last_error(anyhow::anyhow!(
+                "the reference and options must not be NULL"
+            ));
+            return std::ptr::null_mut();
+        }
+        // SAFETY: both strings are valid and NUL-terminated, as required of the caller
+        let (reference, options) = unsafe { (CStr::from_ptr(reference), CStr::from_ptr(options)) };
+        let res = match (reference.to_str(), options.to_str()) {
+            (Ok(reference), Ok(options)) => load_index(reference, options),
+            _ => Err(anyhow::anyhow!(
+                "the reference and options must be valid UTF-8"
+            )),
+        };
+        match res {
+            Ok(index) => Box::into_raw(Box::new(index)),
+            Err(e) => {
+                set_last_error(e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Free an index returned by `oarfish_index_load`.
+///
+/// # Safety
+/// `index` must be NULL, or an index returned by `oarfish_index_load` that has not been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_index_free(index: *mut OarfishIndex) {
+    if !index.is_null() {
+        // SAFETY: the index was allocated by `oarfish_index_load`, and is freed only once
+        catch_panic((), || drop(unsafe { Box::from_raw(index) }));
+    }
+}
+
+/// The number of targets (transcripts) of `index`.
+///
+/// # Safety
+/// `index` must be a valid index returned by `oarfish_index_load`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_index_num_targets(index: *const OarfishIndex) -> usize {
+    // SAFETY: the index is valid, as required of the caller
+    unsafe { &*index }.txps_name.len()
+}
+
+/// The name of the target `i` of `index`, which remains valid until the index is freed,
+/// or NULL if there is no such target.
+///
+/// # Safety
+/// `index` must be a valid index returned by `oarfish_index_load`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_index_target_name(
+    index: *const OarfishIndex,
+    i: usize,
+) -> *const c_char {
+    // SAFETY: the index is valid, as required of the caller
+    unsafe { &*index }
+        .txps_name
+        .get(i)
+        .map_or(std::ptr::null(), |n| n.as_ptr())
+}
+
+/// Quantify the `num_reads` reads whose sequences are `seqs[0..num_reads]`, of lengths
+/// `lens[0..num_reads]` (they need not be NUL-terminated), against `index`. Returns NULL on
+/// error (see `oarfish_last_error`).
+///
+/// # Safety
+/// `index` must be a valid index returned by `oarfish_index_load`, and `seqs` and `lens`
+/// must point to `num_reads` sequences and lengths.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_quantify_reads(
+    index: *const OarfishIndex,
+    seqs: *const *const u8,
+    lens: *const usize,
+    num_reads: usize,
+) -> *mut OarfishQuant {
+    catch_panic(std::ptr::null_mut(), || {
+        if index.is_null() || (num_reads > 0 && (seqs.is_null() || lens.is_null())) {
+            set_last_error(anyhow::anyhow!("the index and reads must not be NULL"));
+            return std::ptr::null_mut();
+        }
+        // SAFETY: the index is valid, and the buffers hold `num_reads` reads, as required of
+        // the caller
+        let (index, reads) = unsafe {
+            let reads: Vec<&[u8]> = if num_reads == 0 {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(seqs, num_reads)
+                    .iter()
+                    .zip(std::slice::from_raw_parts(lens, num_reads))
+                    .map(|(s, l)| std::slice::from_raw_parts(*s, *l))
+                    .collect()
+            };
+            (&*index, reads)
+        };
+        match quantify_reads(index, &reads) {
+            Ok(quant) => Box::into_raw(Box::new(quant)),
+            Err(e) => {
+                set_last_error(e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Copy the estimated number of reads arising from each target (in the order of the targets
+/// of the index) to `counts`, which holds room for `len` values. Returns the number of
+/// targets, which may be more than were copied.
+///
+/// # Safety
+/// `quant` must be valid estimates returned by `oarfish_quantify_reads`, and `counts` must
+/// hold room for `len` values.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_quant_counts(
+    quant: *const OarfishQuant,
+    counts: *mut f64,
+    len: usize,
+) -> usize {
+    // SAFETY: the estimates are valid, as required of the caller
+    let quant = unsafe { &*quant };
+    let n = quant.counts.len().min(len);
+    if n > 0 {
+        // SAFETY: `counts` holds room for at least `n` values
+        unsafe { std::ptr::copy_nonoverlapping(quant.counts.as_ptr(), counts, n) };
+    }
+    quant.counts.len()
+}
+
+/// The number of reads that were quantified.
+///
+/// # Safety
+/// `quant` must be valid estimates returned by `oarfish_quantify_reads`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_quant_num_reads(quant: *const OarfishQuant) -> usize {
+    // SAFETY: the estimates are valid, as required of the caller
+    unsafe { &*quant }.num_reads
+}
+
+/// The number of reads that minimap2 failed to map, which are left out of the estimates
+/// (the first failure is logged).
+///
+/// # Safety
+/// `quant` must be valid estimates returned by `oarfish_quantify_reads`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_quant_num_failed_reads(quant: *const OarfishQuant) -> usize {
+    // SAFETY: the estimates are valid, as required of the caller
+    unsafe { &*quant }.num_failed_reads
+}
+
+/// The number of reads that aligned (validly) to at least one target.
+///
+/// # Safety
+/// `quant` must be valid estimates returned by `oarfish_quantify_reads`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_quant_num_aligned_reads(quant: *const OarfishQuant) -> usize {
+    // SAFETY: the estimates are valid, as required of the caller
+    unsafe { &*quant }.num_aligned_reads
+}
+
+/// Free estimates returned by `oarfish_quantify_reads`.
+///
+/// # Safety
+/// `quant` must be NULL, or estimates returned by `oarfish_quantify_reads` that have not
+/// been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn oarfish_quant_free(quant: *mut OarfishQuant) {
+    if !quant.is_null() {
+        // SAFETY: the estimates were allocated by `oarfish_quantify_reads`, and are freed
+        // only once
+        catch_panic((), || drop(unsafe { Box::from_raw(quant) }));
+    }
+}
+
+/// The message of the last error raised on the calling thread, or NULL if there was none.
+/// The message remains valid until the next error on this thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn oarfish_last_error() -> *const c_char {
+    LAST_ERROR.with(|l| l.borrow().as_ref().map_or(std::ptr::null(), |m| m.as_ptr()))
+}
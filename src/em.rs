@@ -1,40 +1,58 @@
-use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use crate::util::constants;
-use crate::util::oarfish_types::{AlnInfo, EMInfo, TranscriptInfo};
+use crate::util::logging;
+use crate::util::oarfish_types::{
+    AlnGroup, EMInfo, EmIteration, InMemoryAlignmentStore, TranscriptInfo,
+};
+use crate::util::progress;
 use atomic_float::AtomicF64;
 use itertools::izip;
 use num_format::{Locale, ToFormattedString};
-use rand::rng as trng;
-use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng, rng as trng};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+use rayon::slice::ParallelSliceMut;
 use tracing::{info, span, trace};
 
 use crate::bootstrap;
 
-type EqIterateT<'a> = (&'a [AlnInfo], &'a [f32], &'a [f64]);
-
 /// Performs one iteration of the EM algorithm by looping over all
 /// alignments and computing their estimated probability of being
 /// the true alignment (using the abunance estimates from `prev_counts`).
 /// Then, `curr_counts` is computed by summing over the expected assignment
-/// likelihood for all reads mapping to each target.
+/// likelihood for all reads mapping to each target. If `track_ll` is true, the
+/// log-likelihood of the estimates in `prev_counts` is returned (and 0 otherwise).
 #[inline]
+#[allow(clippy::too_many_arguments)]
 fn m_step_par<DFn>(
-    eq_iterates: &[EqIterateT],
+    eq_map: &InMemoryAlignmentStore,
     tinfo: &[TranscriptInfo],
     model_coverage: bool,
     density_fn: DFn,
+    txp_weights: Option<&[f64]>,
     prev_count: &mut [AtomicF64],
     curr_counts: &mut [AtomicF64],
-) where
+    track_ll: bool,
+) -> f64
+where
     DFn: Fn(usize, usize) -> f64 + Sync,
 {
+    let log_total = if track_ll {
+        log_total_count(prev_count.iter().map(|x| x.load(Ordering::Relaxed)))
+    } else {
+        0.0
+    };
     // for (alns, probs, coverage_probs) in eq_map.iter() {
-    eq_iterates.par_iter().for_each_with(
-        &curr_counts,
-        |curr_counts, (alns, probs, coverage_probs)| {
+    (0..eq_map.len())
+        .into_par_iter()
+        .map_with(&curr_counts, |curr_counts, read_idx| {
+            let (alns, probs, coverage_probs) = eq_map.group(read_idx);
             let mut denom = 0.0_f64;
-            for (a, p, cp) in izip!(*alns, *probs, *coverage_probs) {
+            for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
                 // Compute the probability of assignment of the
                 // current read based on this alignment and the
                 // target's estimated abundance.
@@ -46,9 +64,13 @@ fn m_step_par<DFn>(
                 let prob = *p as f64;
                 let cov_prob = if model_coverage { *cp } else { 1.0 };
                 let dens_prob = density_fn(txp_len, aln_len);
+                let txp_weight = txp_weights.map_or(1.0, |w| w[target_id]);
 
-                denom +=
-                    prev_count[target_id].load(Ordering::Relaxed) * prob * cov_prob * dens_prob;
+                denom += prev_count[target_id].load(Ordering::Relaxed)
+                    * prob
+                    * cov_prob
+                    * dens_prob
+                    * txp_weight;
             }
 
             // If this read can be assigned
@@ -56,7 +78,7 @@ fn m_step_par<DFn>(
                 // Loop over all possible assignment locations and proportionally
                 // allocate the read according to our model and current parameter
                 // estimates.
-                for (a, p, cp) in izip!(*alns, *probs, *coverage_probs) {
+                for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
                     let target_id = a.ref_id as usize;
 
                     let txp_len = tinfo[target_id].lenf as usize;
@@ -65,38 +87,62 @@ fn m_step_par<DFn>(
                     let prob = *p as f64;
                     let cov_prob = if model_coverage { *cp } else { 1.0 };
                     let dens_prob = density_fn(txp_len, aln_len);
+                    let txp_weight = txp_weights.map_or(1.0, |w| w[target_id]);
                     let inc = (prev_count[target_id].load(Ordering::Relaxed)
                         * prob
                         * cov_prob
-                        * dens_prob)
+                        * dens_prob
+                        * txp_weight)
                         / denom;
                     //curr_counts[target_id] += inc;
                     curr_counts[target_id].fetch_add(inc, Ordering::AcqRel);
                 }
+                if track_ll {
+                    return denom.ln() - log_total;
+                }
             }
-        },
-    );
+            0.0
+        })
+        .sum()
+}
+
+/// The log of the total of the estimated counts `counts`, by which the likelihood
+/// of each read is normalized.
+#[inline]
+fn log_total_count<I: Iterator<Item = f64>>(counts: I) -> f64 {
+    counts.sum::<f64>().ln()
 }
 
 /// Performs one iteration of the EM algorithm by looping over all
 /// alignments and computing their estimated probability of being
 /// the true alignment (using the abunance estimates from `prev_counts`).
 /// Then, `curr_counts` is computed by summing over the expected assignment
-/// likelihood for all reads mapping to each target.
+/// likelihood for all reads mapping to each target. If `track_ll` is true, the
+/// log-likelihood of the estimates in `prev_counts` is returned (and 0 otherwise).
 #[inline]
-fn m_step<'a, DFn, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])>>(
+#[allow(clippy::too_many_arguments)]
+fn m_step<'a, DFn, I: Iterator<Item = AlnGroup<'a>>>(
     eq_map_iter: I,
     tinfo: &[TranscriptInfo],
     model_coverage: bool,
     density_fn: DFn,
+    txp_weights: Option<&[f64]>,
     prev_count: &mut [f64],
     curr_counts: &mut [f64],
-) where
+    track_ll: bool,
+) -> f64
+where
     DFn: Fn(usize, usize) -> f64,
 {
+    let log_total = if track_ll {
+        log_total_count(prev_count.iter().copied())
+    } else {
+        0.0
+    };
+    let mut ll = 0.0_f64;
     for (alns, probs, coverage_probs) in eq_map_iter {
         let mut denom = 0.0_f64;
-        for (a, p, cp) in izip!(alns, probs, coverage_probs) {
+        for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
             // Compute the probability of assignment of the
             // current read based on this alignment and the
             // target's estimated abundance.
@@ -107,8 +153,9 @@ fn m_step<'a, DFn, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])>>(
             let prob = *p as f64;
             let cov_prob = if model_coverage { *cp } else { 1.0 };
             let dens_prob = density_fn(txp_len, aln_len);
+            let txp_weight = txp_weights.map_or(1.0, |w| w[target_id]);
 
-            denom += prev_count[target_id] * prob * cov_prob * dens_prob;
+            denom += prev_count[target_id] * prob * cov_prob * dens_prob * txp_weight;
         }
 
         // If this read can be assigned
@@ -116,7 +163,7 @@ fn m_step<'a, DFn, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])>>(
             // Loop over all possible assignment locations and proportionally
             // allocate the read according to our model and current parameter
             // estimates.
-            for (a, p, cp) in izip!(alns, probs, coverage_probs) {
+            for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
                 let target_id = a.ref_id as usize;
                 let txp_len = tinfo[target_id].lenf as usize;
                 let aln_len = a.alignment_span() as usize;
@@ -124,12 +171,141 @@ fn m_step<'a, DFn, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])>>(
                 let prob = *p as f64;
                 let cov_prob = if model_coverage { *cp } else { 1.0 };
                 let dens_prob = density_fn(txp_len, aln_len);
+                let txp_weight = txp_weights.map_or(1.0, |w| w[target_id]);
 
-                let inc = (prev_count[target_id] * prob * cov_prob * dens_prob) / denom;
+                let inc =
+                    (prev_count[target_id] * prob * cov_prob * dens_prob * txp_weight) / denom;
                 curr_counts[target_id] += inc;
             }
+            if track_ll {
+                ll += denom.ln() - log_total;
+            }
         }
     }
+    ll
+}
+
+/// The number of reads processed together (and whose contributions are summed
+/// in order) by a single task of [m_step_chunked]. This must not depend on the
+/// number of threads, so that the results do not either.
+const EM_CHUNK_SIZE: usize = 4096;
+
+/// Performs one iteration of the EM algorithm, as in [m_step_par], but such
+/// that the result does not depend on the number of threads or on how the work
+/// is scheduled. The reads are split into fixed-size chunks, and the (sparse)
+/// contributions of each chunk to `curr_counts` are computed in parallel and
+/// summed in read order. The chunks are processed in waves of `wave_chunks`
+/// chunks, and the contributions of each wave are added to `curr_counts` in
+/// chunk order (in parallel over ranges of transcripts), so that every count
+/// is always accumulated in the same order. If `track_ll` is true, the
+/// log-likelihood of the estimates in `prev_count` is returned (and 0 otherwise).
+#[inline]
+#[allow(clippy::too_many_arguments)]
+fn m_step_chunked<DFn>(
+    eq_map: &InMemoryAlignmentStore,
+    tinfo: &[TranscriptInfo],
+    model_coverage: bool,
+    density_fn: DFn,
+    txp_weights: Option<&[f64]>,
+    prev_count: &[f64],
+    curr_counts: &mut [f64],
+    wave_chunks: usize,
+    track_ll: bool,
+) -> f64
+where
+    DFn: Fn(usize, usize) -> f64 + Sync,
+{
+    let log_total = if track_ll {
+        log_total_count(prev_count.iter().copied())
+    } else {
+        0.0
+    };
+    let mut ll = 0.0_f64;
+    let num_reads = eq_map.len();
+    let num_chunks = num_reads.div_ceil(EM_CHUNK_SIZE);
+    let range_len = curr_counts
+        .len()
+        .div_ceil(rayon::current_num_threads() * 4)
+        .max(1);
+
+    for wave_start in (0..num_chunks).step_by(wave_chunks.max(1)) {
+        let wave_end = (wave_start + wave_chunks.max(1)).min(num_chunks);
+        let partials: Vec<(Vec<(u32, f64)>, f64)> = (wave_start..wave_end)
+            .into_par_iter()
+            .map(|chunk| {
+                let mut contribs = Vec::<(u32, f64)>::new();
+                let mut chunk_ll = 0.0_f64;
+                let chunk_end = ((chunk + 1) * EM_CHUNK_SIZE).min(num_reads);
+                for read_idx in (chunk * EM_CHUNK_SIZE)..chunk_end {
+                    let (alns, probs, coverage_probs) = eq_map.group(read_idx);
+                    let mut denom = 0.0_f64;
+                    for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
+                        let target_id = a.ref_id as usize;
+                        let txp_len = tinfo[target_id].lenf as usize;
+                        let aln_len = a.alignment_span() as usize;
+
+                        let prob = *p as f64;
+                        let cov_prob = if model_coverage { *cp } else { 1.0 };
+                        let dens_prob = density_fn(txp_len, aln_len);
+                        let txp_weight = txp_weights.map_or(1.0, |w| w[target_id]);
+
+                        denom += prev_count[target_id] * prob * cov_prob * dens_prob * txp_weight;
+                    }
+
+                    // If this read can be assigned
+                    if denom > constants::EM_DENOM_THRESH {
+                        for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
+                            let target_id = a.ref_id as usize;
+                            let txp_len = tinfo[target_id].lenf as usize;
+                            let aln_len = a.alignment_span() as usize;
+
+                            let prob = *p as f64;
+                            let cov_prob = if model_coverage { *cp } else { 1.0 };
+                            let dens_prob = density_fn(txp_len, aln_len);
+                            let txp_weight = txp_weights.map_or(1.0, |w| w[target_id]);
+
+                            let inc =
+                                (prev_count[target_id] * prob * cov_prob * dens_prob * txp_weight)
+                                    / denom;
+                            contribs.push((a.ref_id, inc));
+                        }
+                        if track_ll {
+                            chunk_ll += denom.ln() - log_total;
+                        }
+                    }
+                }
+                // sum the contributions to each transcript in read order
+                // (the sort is stable)
+                contribs.sort_by_key(|c| c.0);
+                let mut merged = Vec::<(u32, f64)>::with_capacity(contribs.len());
+                for (tid, inc) in contribs {
+                    match merged.last_mut() {
+                        Some((last_tid, acc)) if *last_tid == tid => *acc += inc,
+                        _ => merged.push((tid, inc)),
+                    }
+                }
+                (merged, chunk_ll)
+            })
+            .collect();
+        // the log-likelihoods of the chunks are summed in chunk order, too
+        ll += partials.iter().map(|(_, l)| l).sum::<f64>();
+
+        // add the contributions of the chunks of this wave, in chunk order
+        curr_counts
+            .par_chunks_mut(range_len)
+            .enumerate()
+            .for_each(|(r, counts)| {
+                let lo = r * range_len;
+                let hi = lo + counts.len();
+                for (part, _) in partials.iter() {
+                    let first = part.partition_point(|c| (c.0 as usize) < lo);
+                    for (tid, inc) in part[first..].iter().take_while(|c| (c.0 as usize) < hi) {
+                        counts[*tid as usize - lo] += inc;
+                    }
+                }
+            });
+    }
+    ll
 }
 
 /// The code that actually performs the EM loop in the single-threaded context.
@@ -141,16 +317,53 @@ fn m_step<'a, DFn, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])>>(
 ///
 /// returns:
 /// A [Vec<f64>] represented the expected read assignments to each transcript.
-pub fn do_em<'a, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])> + 'a, F: Fn() -> I>(
+pub fn do_em<'a, I: Iterator<Item = AlnGroup<'a>> + 'a, F: Fn() -> I>(
     em_info: &'a EMInfo,
     make_iter: F,
     do_log: bool,
 ) -> Vec<f64> {
+    let tinfo: &[TranscriptInfo] = em_info.txp_info;
+    let model_coverage = em_info.eq_map.filter_opts.model_coverage;
+    let density_fn = |x, y| -> f64 {
+        match em_info.kde_model {
+            Some(ref kde_model) => kde_model[(x, y)],
+            _ => 1.,
+        }
+    };
+
+    em_loop(
+        em_info,
+        |prev_counts, curr_counts, track_ll| {
+            m_step(
+                make_iter(),
+                tinfo,
+                model_coverage,
+                density_fn,
+                em_info.txp_weights.as_deref(),
+                prev_counts,
+                curr_counts,
+                track_ll,
+            )
+        },
+        do_log,
+    )
+}
+
+/// Run the EM loop, computing the counts of each round from those of the previous
+/// round with `step` (which returns the log-likelihood of the previous counts if its
+/// last argument is true), until convergence (or until the maximum number of iterations
+/// has been reached). Returns the final estimated abundances.
+fn em_loop<S>(em_info: &EMInfo, mut step: S, do_log: bool) -> Vec<f64>
+where
+    S: FnMut(&mut [f64], &mut [f64], bool) -> f64,
+{
     let eq_map = em_info.eq_map;
-    let fops = &eq_map.filter_opts;
     let tinfo: &[TranscriptInfo] = em_info.txp_info;
     let max_iter = em_info.max_iter;
+    let min_iter = em_info.min_iter;
     let convergence_thresh = em_info.convergence_thresh;
+    // only the logged (i.e. main) runs of the EM are traced
+    let trace = em_info.trace.as_deref().filter(|_| do_log);
     let total_weight: f64 = eq_map.num_aligned_reads() as f64;
 
     // initialize the estimated counts for the EM procedure
@@ -167,27 +380,19 @@ pub fn do_em<'a, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])> + 'a,
     }
 
     let mut rel_diff = 0.0_f64;
+    let mut final_rel_diff = f64::INFINITY;
     let mut niter = 0_u32;
-    let mut _fl_prob = 0.5f64;
 
-    let density_fn = |x, y| -> f64 {
-        match em_info.kde_model {
-            Some(ref kde_model) => kde_model[(x, y)],
-            _ => 1.,
-        }
+    let pb = if do_log {
+        progress::em_iterations(max_iter)
+    } else {
+        indicatif::ProgressBar::hidden()
     };
 
     // for up to the maximum number of iterations
     while niter < max_iter {
         // allocate the fragments and compute the new counts
-        m_step(
-            make_iter(),
-            tinfo,
-            fops.model_coverage,
-            density_fn,
-            &mut prev_counts,
-            &mut curr_counts,
-        );
+        let ll = step(&mut prev_counts, &mut curr_counts, trace.is_some());
 
         // compute the relative difference in the parameter estimates
         // between the current and previous rounds
@@ -206,16 +411,23 @@ pub fn do_em<'a, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])> + 'a,
         // clear out the new abundances
         curr_counts.fill(0.0_f64);
 
+        if let Some(trace) = trace {
+            trace.push(EmIteration::new(niter + 1, ll, rel_diff, &prev_counts));
+        }
+
+        final_rel_diff = rel_diff;
         // if the maximum relative difference is small enough
-        // and we've done at least 10 rounds of the EM, then
-        // exit (early stop).
-        if (rel_diff < convergence_thresh) && (niter > 50) {
+        // and we've done at least `min_iter` rounds of the EM,
+        // then exit (early stop).
+        if (rel_diff < convergence_thresh) && (niter + 1 >= min_iter) {
             break;
         }
         // increment the iteration and, if this iteration
         // is a multiple of 10, print out  the maximum relative
         // difference we observed.
         niter += 1;
+        pb.inc(1);
+        pb.set_message(format!("(rel diff {:.3e})", rel_diff));
         if do_log && (niter % 10 == 0) {
             if niter % 100 == 0 {
                 info!(
@@ -233,6 +445,10 @@ pub fn do_em<'a, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])> + 'a,
         }
         rel_diff = 0.0_f64;
     }
+    pb.finish_and_clear();
+    if do_log {
+        logging::em_finished(niter, final_rel_diff, niter < max_iter);
+    }
 
     // set very small abundances to 0
     for x in &mut prev_counts {
@@ -242,14 +458,7 @@ pub fn do_em<'a, I: Iterator<Item = (&'a [AlnInfo], &'a [f32], &'a [f64])> + 'a,
     }
     // perform one more EM round, since we just zeroed out
     // very small abundances
-    m_step(
-        make_iter(),
-        tinfo,
-        fops.model_coverage,
-        density_fn,
-        &mut prev_counts,
-        &mut curr_counts,
-    );
+    step(&mut prev_counts, &mut curr_counts, false);
     //  return the final estimated abundances
     curr_counts
 }
@@ -270,10 +479,47 @@ pub fn em(em_info: &EMInfo, _nthreads: usize) -> Vec<f64> {
     do_em(em_info, make_iter, true)
 }
 
-pub fn do_bootstrap(em_info: &EMInfo) -> Vec<f64> {
-    let mut rng = trng();
+/// Perform the EM algorithm as in [em], but running the E-step of each round on a GPU
+/// (see [GpuEStep](crate::util::gpu_em::GpuEStep)). Returns [None] if no GPU is found, or
+/// the alignments could not be moved to it, in which case the EM should be run on the CPU.
+#[cfg(feature = "gpu")]
+pub fn em_gpu(em_info: &EMInfo) -> Option<Vec<f64>> {
+    let span = span!(tracing::Level::INFO, "em");
+    let _guard = span.enter();
+
+    let gpu = match crate::util::gpu_em::GpuEStep::new(em_info) {
+        Ok(Some(gpu)) => gpu,
+        Ok(None) => {
+            tracing::warn!("no GPU was found; running the EM on the CPU.");
+            return None;
+        }
+        Err(e) => {
+            tracing::warn!(
+                "could not run the EM on the GPU ({:#}); running it on the CPU.",
+                e
+            );
+            return None;
+        }
+    };
+    Some(em_loop(
+        em_info,
+        |prev_counts, curr_counts, track_ll| gpu.step(prev_counts, curr_counts, track_ll),
+        true,
+    ))
+}
+
+pub fn do_bootstrap<R: Rng + ?Sized>(
+    em_info: &EMInfo,
+    strata: Option<&[Vec<usize>]>,
+    block_size: usize,
+    rng: &mut R,
+) -> Vec<f64> {
     let n = em_info.eq_map.len();
-    let inds = bootstrap::get_sample_inds(n, &mut rng);
+    let inds = match strata {
+        Some(members) => bootstrap::get_stratified_sample_inds(members, block_size, rng),
+        None if block_size > 1 => bootstrap::get_block_sample_inds(n, block_size, rng),
+        None => bootstrap::get_sample_inds(n, rng),
+    };
 
     // to not sample the indices but instead just
     // run with all reads sampled once
@@ -289,28 +535,114 @@ pub fn do_bootstrap(em_info: &EMInfo) -> Vec<f64> {
     do_em(em_info, make_iter, false)
 }
 
-pub fn bootstrap(em_info: &EMInfo, num_boot: u32, nthreads: usize) -> Vec<Vec<f64>> {
+/// The posterior mean of the counts of the transcripts under a symmetric Dirichlet prior
+/// of `prior_weight` (pseudo-)reads per transcript, given the (maximum likelihood)
+/// estimates `counts` of the EM: each transcript is given `N (c + w) / (N + T w)` reads,
+/// for `N` reads in total over `T` transcripts, so the total is unchanged.
+pub fn posterior_mean_counts(counts: &[f64], prior_weight: f64) -> Vec<f64> {
+    let total: f64 = counts.iter().sum();
+    let denom = total + counts.len() as f64 * prior_weight;
+    if denom <= 0.0 {
+        return counts.to_vec();
+    }
+    let scale = total / denom;
+    counts.iter().map(|c| (c + prior_weight) * scale).collect()
+}
+
+/// Compute `num_boot` bootstrap replicates of the estimates, returning them all once
+/// they are done (see [for_each_bootstrap]).
+pub fn bootstrap(
+    em_info: &EMInfo,
+    num_boot: u32,
+    nthreads: usize,
+    strata: Option<&[u16]>,
+    block_size: usize,
+    seed: Option<u64>,
+) -> Vec<Vec<f64>> {
+    let breps = Mutex::new(vec![Vec::new(); num_boot as usize]);
+    for_each_bootstrap(
+        em_info,
+        num_boot,
+        nthreads,
+        strata,
+        block_size,
+        seed,
+        |i, b| {
+            breps.lock().expect("bootstrap lock poisoned")[i] = b;
+            Ok(())
+        },
+    )
+    .expect("keeping a replicate in memory can't fail");
+    breps.into_inner().expect("bootstrap lock poisoned")
+}
+
+/// Compute `num_boot` bootstrap replicates of the estimates, in parallel over `nthreads`
+/// threads, handing each to `on_replicate` (along with its index) as soon as it is done,
+/// so that the replicates need not all be held in memory. If `strata` is provided, it
+/// gives the stratum of each read, and the reads are resampled within each stratum. If
+/// `block_size` is larger than 1, the reads are resampled in blocks of that many
+/// consecutive reads (of their stratum, if any). If a `seed` is given, the resampling of
+/// each replicate is determined by the seed and the index of the replicate, so that the
+/// replicates do not depend on the number of threads.
+pub fn for_each_bootstrap<F>(
+    em_info: &EMInfo,
+    num_boot: u32,
+    nthreads: usize,
+    strata: Option<&[u16]>,
+    block_size: usize,
+    seed: Option<u64>,
+    on_replicate: F,
+) -> std::io::Result<()>
+where
+    F: Fn(usize, Vec<f64>) -> std::io::Result<()> + Sync,
+{
     let span = span!(tracing::Level::INFO, "bootstrap");
     let _guard = span.enter();
 
     info!("will collection {num_boot} bootstraps");
+    let members = strata.map(bootstrap::stratum_members);
+    if let Some(ref m) = members {
+        info!("resampling the reads within each of {} strata.", m.len());
+    }
+    if block_size > 1 {
+        info!(
+            "resampling the reads in blocks of {} consecutive reads.",
+            block_size
+        );
+    }
 
     let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(nthreads)
         .build()
         .unwrap();
 
+    // the progress is logged (roughly) every tenth of the replicates
+    let pb = progress::bootstrap_replicates(num_boot);
+    let log_every = (num_boot / 10).max(1);
+    let num_done = AtomicU32::new(0);
     pool.install(|| {
-        (0..num_boot)
-            .into_par_iter()
-            .map(|i| {
-                let span = span!(tracing::Level::INFO, "bootstrap");
-                let _guard = span.enter();
-                info!("evaluating bootstrap replicate {}", i);
-                do_bootstrap(em_info)
-            })
-            .collect()
-    })
+        (0..num_boot).into_par_iter().try_for_each(|i| {
+            let span = span!(tracing::Level::INFO, "bootstrap");
+            let _guard = span.enter();
+            trace!("evaluating bootstrap replicate {}", i);
+            let b = match seed {
+                Some(seed) => {
+                    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(i as u64));
+                    do_bootstrap(em_info, members.as_deref(), block_size, &mut rng)
+                }
+                None => do_bootstrap(em_info, members.as_deref(), block_size, &mut trng()),
+            };
+            on_replicate(i as usize, b)?;
+            pb.inc(1);
+            let done = num_done.fetch_add(1, Ordering::Relaxed) + 1;
+            if done % log_every == 0 || done == num_boot {
+                info!("completed {} of {} bootstrap replicates.", done, num_boot);
+            }
+            Ok(())
+        })
+    })?;
+    pb.finish_and_clear();
+    Ok(())
 }
 
 /// Perform the EM algorithm to estimate the abundances of the
@@ -330,9 +662,10 @@ pub fn em_par(em_info: &EMInfo, nthreads: usize) -> Vec<f64> {
     let fops = &eq_map.filter_opts;
     let tinfo: &[TranscriptInfo] = em_info.txp_info;
     let max_iter = em_info.max_iter;
+    let min_iter = em_info.min_iter;
     let convergence_thresh = em_info.convergence_thresh;
+    let trace = em_info.trace.as_deref();
     let total_weight: f64 = eq_map.num_aligned_reads() as f64;
-    let eq_iterates: Vec<EqIterateT> = eq_map.iter().collect();
     // initialize the estimated counts for the EM procedure
     let prev_counts: Vec<f64>;
     let mut curr_counts: Vec<AtomicF64> = vec![0.0f64; tinfo.len()]
@@ -351,6 +684,7 @@ pub fn em_par(em_info: &EMInfo, nthreads: usize) -> Vec<f64> {
     let mut prev_counts: Vec<AtomicF64> = prev_counts.iter().map(|x| AtomicF64::new(*x)).collect();
 
     let mut rel_diff = 0.0_f64;
+    let mut final_rel_diff = f64::INFINITY;
     let mut niter = 0_u32;
     let mut _fl_prob = 0.5f64;
 
@@ -361,17 +695,21 @@ pub fn em_par(em_info: &EMInfo, nthreads: usize) -> Vec<f64> {
         }
     };
 
+    let pb = progress::em_iterations(max_iter);
+
     pool.install(|| {
         // for up to the maximum number of iterations
         while niter < max_iter {
             // allocate the fragments and compute the new counts
-            m_step_par(
-                &eq_iterates,
+            let ll = m_step_par(
+                eq_map,
                 tinfo,
                 fops.model_coverage,
                 density_fn,
+                em_info.txp_weights.as_deref(),
                 &mut prev_counts,
                 &mut curr_counts,
+                trace.is_some(),
             );
 
             // compute the relative difference in the parameter estimates
@@ -393,16 +731,27 @@ pub fn em_par(em_info: &EMInfo, nthreads: usize) -> Vec<f64> {
                 .par_iter()
                 .for_each(|x| x.store(0.0f64, Ordering::Relaxed)); //fill(0.0_f64);
 
+            if let Some(trace) = trace {
+                let counts: Vec<f64> = prev_counts
+                    .iter()
+                    .map(|x| x.load(Ordering::Relaxed))
+                    .collect();
+                trace.push(EmIteration::new(niter + 1, ll, rel_diff, &counts));
+            }
+
+            final_rel_diff = rel_diff;
             // if the maximum relative difference is small enough
-            // and we've done at least 10 rounds of the EM, then
-            // exit (early stop).
-            if (rel_diff < convergence_thresh) && (niter > 1) {
+            // and we've done at least `min_iter` rounds of the EM,
+            // then exit (early stop).
+            if (rel_diff < convergence_thresh) && (niter + 1 >= min_iter) {
                 break;
             }
             // increment the iteration and, if this iteration
             // is a multiple of 10, print out  the maximum relative
             // difference we observed.
             niter += 1;
+            pb.inc(1);
+            pb.set_message(format!("(rel diff {:.3e})", rel_diff));
             if niter % 10 == 0 {
                 if niter % 100 == 0 {
                     info!(
@@ -420,6 +769,7 @@ pub fn em_par(em_info: &EMInfo, nthreads: usize) -> Vec<f64> {
             }
             rel_diff = 0.0_f64;
         }
+        pb.finish_and_clear();
 
         // set very small abundances to 0
         prev_counts.iter_mut().for_each(|x| {
@@ -431,17 +781,166 @@ pub fn em_par(em_info: &EMInfo, nthreads: usize) -> Vec<f64> {
         // perform one more EM round, since we just zeroed out
         // very small abundances
         m_step_par(
-            &eq_iterates,
+            eq_map,
             tinfo,
             fops.model_coverage,
             density_fn,
+            em_info.txp_weights.as_deref(),
             &mut prev_counts,
             &mut curr_counts,
+            false,
         );
     });
+    logging::em_finished(niter, final_rel_diff, niter < max_iter);
     //  return the final estimated abundances
     curr_counts
         .iter()
         .map(|x| x.load(Ordering::Relaxed))
         .collect::<Vec<f64>>()
 }
+
+/// Perform the EM algorithm to estimate the abundances of the target sequences,
+/// as in [em_par], but guaranteeing that the estimates are bit-identical
+/// regardless of the number of threads used (see [m_step_chunked]).
+pub fn em_par_deterministic(em_info: &EMInfo, nthreads: usize) -> Vec<f64> {
+    let span = span!(tracing::Level::INFO, "em");
+    let _guard = span.enter();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(nthreads)
+        .build()
+        .unwrap();
+
+    let eq_map = em_info.eq_map;
+    let tinfo: &[TranscriptInfo] = em_info.txp_info;
+    let model_coverage = eq_map.filter_opts.model_coverage;
+    let density_fn = |x, y| -> f64 {
+        match em_info.kde_model {
+            Some(ref kde_model) => kde_model[(x, y)],
+            _ => 1.,
+        }
+    };
+    // enough chunks per wave to keep all threads busy
+    let wave_chunks = nthreads * 16;
+
+    pool.install(|| {
+        em_loop(
+            em_info,
+            |prev_counts, curr_counts, track_ll| {
+                m_step_chunked(
+                    eq_map,
+                    tinfo,
+                    model_coverage,
+                    density_fn,
+                    em_info.txp_weights.as_deref(),
+                    prev_counts,
+                    curr_counts,
+                    wave_chunks,
+                    track_ll,
+                )
+            },
+            true,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::em_par;
+    use crate::prog_opts::{ClipMode, IdentityType, LogFormat, ScoreType, SecondaryPolicy};
+    use crate::util::logging;
+    use crate::util::oarfish_types::{
+        AlignmentFilters, AlnInfo, EMInfo, InMemoryAlignmentStore, TranscriptInfo,
+    };
+    use bio_types::strand::Strand;
+    use std::num::NonZeroUsize;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+
+    /// Records the `event` field of the structured events.
+    #[derive(Clone, Default)]
+    struct EventNames(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for EventNames {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "event" {
+                self.0.lock().unwrap().push(value.to_string());
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "event" {
+                self.0.lock().unwrap().push(format!("{:?}", value));
+            }
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for EventNames {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            if event.metadata().target() == logging::EVENT_TARGET {
+                event.record(&mut self.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn em_par_logs_em_finished() {
+        let filters = AlignmentFilters::builder()
+            .five_prime_clip(u32::MAX)
+            .three_prime_clip(i64::MAX)
+            .score_threshold(0.0)
+            .min_aligned_fraction(0.0)
+            .min_aligned_len(1)
+            .min_identity(0.0)
+            .identity_type(IdentityType::GapCompressed)
+            .score_type(ScoreType::As)
+            .clip_mode(ClipMode::Transcript)
+            .secondary_policy(SecondaryPolicy::UseAll)
+            .secondary_top_k(1)
+            .rescue_supplementary(false)
+            .which_strand(Strand::Unknown)
+            .model_coverage(false)
+            .logistic_growth_rate(2.0)
+            .write_assignment_probs(false)
+            .write_assignment_probs_type(None)
+            .build();
+        let header = noodles_sam::Header::default();
+        let mut txps: Vec<TranscriptInfo> = (0..2)
+            .map(|_| TranscriptInfo::with_len(NonZeroUsize::new(1000).unwrap()))
+            .collect();
+        let mut store = InMemoryAlignmentStore::new(filters, &header);
+        let aln = |ref_id| AlnInfo {
+            ref_id,
+            start: 0,
+            end: 500,
+            prob: 1.0,
+            strand: Strand::Forward,
+        };
+        // reads unique to each transcript, and reads shared between them
+        for alns in [vec![aln(0)], vec![aln(1)], vec![aln(0), aln(1)]] {
+            let probs = vec![1.0_f32; alns.len()];
+            store.add_filtered_group(&alns, &probs, 500, &mut txps);
+        }
+        let emi = EMInfo {
+            eq_map: &store,
+            txp_info: &txps,
+            max_iter: 100,
+            min_iter: 1,
+            convergence_thresh: 0.001,
+            init_abundances: None,
+            kde_model: None,
+            txp_weights: None,
+            trace: None,
+        };
+
+        let events = EventNames::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        logging::set_format(LogFormat::Json);
+        let counts = tracing::subscriber::with_default(subscriber, || em_par(&emi, 2));
+        logging::set_format(LogFormat::Text);
+
+        assert!((counts.iter().sum::<f64>() - 3.0).abs() < 1e-6);
+        assert_eq!(*events.0.lock().unwrap(), vec![logging::EM_FINISHED]);
+    }
+}
@@ -0,0 +1,1327 @@
+use anyhow::Context;
+use clap::Parser;
+use path_tools::WithAdditionalExtension;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use core::ffi;
+use minimap2_sys::MmIdx;
+// Or now
+// use minimap2::ffi as mm_ffi;
+//use minimap2_temp as minimap2;
+use num_format::{Locale, ToFormattedString};
+use std::io;
+use std::io::Read;
+use std::sync::Arc;
+
+use tracing::{info, info_span, warn};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::{EnvFilter, fmt, prelude::*, reload};
+
+use noodles_bam as bam;
+use noodles_bgzf as bgzf;
+use noodles_sam::header::record::value as header_val;
+use noodles_sam::header::record::value::Map as HeaderMap;
+
+mod alignment_parser;
+mod bootstrap;
+mod bulk;
+mod em;
+mod prog_opts;
+mod single_cell;
+mod util;
+
+/// The `pyoarfish` Python extension module, which exposes index building, bulk quantification
+/// and single-cell quantification, returning the estimates as numpy (and scipy) objects.
+#[cfg(feature = "python")]
+mod python;
+
+/// A C ABI for embedding the quantification of reads (mapping, the coverage model and the EM)
+/// in other tools; the header `include/oarfish.h` is generated by `build.rs`.
+#[cfg(feature = "capi")]
+mod capi;
+
+use crate::prog_opts::{
+    Args, FilterGroup, ReferenceMismatchMode, ScoreType, SequencingTech, Tool, ToolArgs,
+};
+use crate::util::alignment_cache;
+use crate::util::allelic;
+use crate::util::archive;
+use crate::util::atomic_output;
+use crate::util::balanced_bgzf::BalancedBgzfReader;
+use crate::util::cli_docs;
+use crate::util::decoys;
+use crate::util::digest_utils;
+use crate::util::duplicates;
+use crate::util::eq_classes;
+use crate::util::errors;
+use crate::util::gpu_em;
+use crate::util::isoform_switch;
+use crate::util::logging;
+use crate::util::loom;
+use crate::util::mem_tracker;
+use crate::util::mm_utils;
+use crate::util::normalize_probability::normalize_read_probs;
+use crate::util::oarfish_types::{
+    AlignmentFilters, CoverageBinning, EMInfo, InMemoryAlignmentStore, TranscriptInfo,
+};
+use crate::util::object_store_io;
+use crate::util::output_schema;
+use crate::util::profile::{self, Stage};
+use crate::util::progress;
+use crate::util::provenance;
+use crate::util::read_function::get_excluded_txp_mask;
+use crate::util::reference_mismatch;
+use crate::util::resource_usage;
+use crate::util::saturation;
+use crate::util::sc_merge;
+use crate::util::score_calibration;
+use crate::util::serve;
+use crate::util::sharded_index;
+use crate::util::subsample::{self, ReadSubsample};
+use crate::util::thread_alloc::BamThreadPlan;
+use crate::util::validate;
+use crate::util::{
+    binomial_probability::binomial_continuous_prob, kde_utils, logistic_probability::logistic_prob,
+};
+
+/// The number of bootstrap replicates computed for each sample when screening for
+/// isoform switches, if the user did not request any.
+const DEFAULT_SWITCH_BOOTSTRAPS: u32 = 100;
+/// The number of bootstrap replicates computed to find the groups of indistinguishable
+/// transcripts, if the user did not request any.
+const DEFAULT_GROUP_BOOTSTRAPS: u32 = 100;
+
+type HeaderReaderAlignerDigest = (
+    noodles_sam::header::Header,
+    Option<bam::io::Reader<Box<dyn io::BufRead + Send>>>,
+    Option<mm_utils::PartitionedAligner>,
+    seqcol_rs::DigestResult,
+);
+
+fn is_fasta(fname: &std::path::Path) -> anyhow::Result<bool> {
+    match std::fs::OpenOptions::new().read(true).open(fname) {
+        Ok(mut file) => {
+            let mut first_char = vec![0_u8];
+            file.read_exact(&mut first_char)?;
+            drop(file);
+            Ok(first_char[0] == b'>' || first_char[0] == b'@')
+        }
+        _ => Ok(false),
+    }
+}
+
+/// The digest of the targets of the pre-built minimap2 index `index` (loaded as `aligner`,
+/// with the targets described by `header`).
+fn index_digest(
+    index: &std::path::Path,
+    aligner: &mm_utils::PartitionedAligner,
+    header: &noodles_sam::header::Header,
+) -> anyhow::Result<seqcol_rs::DigestResult> {
+    match digest_utils::read_digest_from_mm2_index(
+        index.to_str().expect("could not convert to string"),
+    ) {
+        // we read a pre-computed digest from an oarfish-constructed
+        // minimap2 index
+        Ok(d) => Ok(d),
+        _ => {
+            // We have been given a minimap2 index, but without the oarfish
+            // footer. Now, we can build the digest we want from the index
+            // itself.
+            warn!(
+                "computing sequence signatures from a minimap2 index that was not built with oarfish."
+            );
+            warn!(
+                "if you are quantifying multiple samples, it will save time to let oarfish build a minimap2 index from the transcriptome reference, so that the reference signature can be reused."
+            );
+            if aligner.num_parts() > 1 {
+                // the sequences of all parts are not held together, so
+                // only their names and lengths contribute to the digest
+                digest_utils::digest_from_header(header)
+            } else {
+                let mmi: Arc<MmIdx> = Arc::clone(aligner.first().idx.as_ref().unwrap());
+                digest_utils::digest_from_index(&mmi)
+            }
+        }
+    }
+}
+
+/// A pre-built minimap2 index, loaded once (by `oarfish serve`) to quantify the reads
+/// of many samples, along with the header describing its targets.
+struct LoadedIndex {
+    path: PathBuf,
+    header: noodles_sam::header::Header,
+    aligner: mm_utils::PartitionedAligner,
+}
+
+impl LoadedIndex {
+    /// Load the minimap2 index at `path`, built with the preset of `seq_tech`.
+    fn load(
+        path: &std::path::Path,
+        seq_tech: &SequencingTech,
+        threads: usize,
+    ) -> anyhow::Result<Self> {
+        if is_fasta(path)? {
+            return Err(errors::bad_input(format!(
+                "{} is a FASTA file rather than an index; build its index first (with `--index-out`)",
+                path.display()
+            )));
+        }
+        let seq_tech = clap::ValueEnum::to_possible_value(seq_tech)
+            .expect("every sequencing technology has a name");
+        let path_str = path
+            .to_str()
+            .context("could not convert the index path to a string")?;
+        let mut args = Args::try_parse_without_input([
+            "oarfish",
+            "--reference",
+            path_str,
+            "--seq-tech",
+            seq_tech.get_name(),
+            "--threads",
+            &threads.to_string(),
+            "--output",
+            path_str,
+        ])?;
+        let (header, _, aligner, _) = get_aligner_from_args(&mut args)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            header,
+            aligner: aligner.expect("an aligner is built in read-based mode"),
+        })
+    }
+
+    /// The header, aligner and digest with which to quantify the reads of the run
+    /// described by `args`, with the mapping options of that run.
+    fn for_run(&self, args: &Args) -> anyhow::Result<HeaderReaderAlignerDigest> {
+        if args.decoys.is_some() || args.index_out.is_some() {
+            anyhow::bail!(
+                "`--decoys` and `--index-out` require building the index of the reference"
+            );
+        }
+        let mut aligner = self.aligner.clone();
+        aligner.update_mapopt(|mapopt| {
+            mapopt.best_n = args.best_n as i32;
+            mapopt.seed = args.seed.map_or(11, |s| s as i32);
+        });
+        let digest = index_digest(&self.path, &aligner, &self.header)?;
+        Ok((self.header.clone(), None, Some(aligner), digest))
+    }
+}
+
+fn get_aligner_from_args(args: &mut Args) -> anyhow::Result<HeaderReaderAlignerDigest> {
+    info!("oarfish is operating in read-based mode");
+
+    let ref_file = args
+        .reference
+        .clone()
+        .ok_or_else(|| errors::bad_input("a --reference is required in read-based mode"))?;
+    // minimap2 reads the reference from local disk, so a remote reference is
+    // staged to a temporary file (removed once the index has been built)
+    let staged_ref = if object_store_io::is_remote(&ref_file) {
+        Some(object_store_io::stage_remote(&ref_file)?)
+    } else {
+        None
+    };
+    let ref_file = staged_ref
+        .as_ref()
+        .map_or(ref_file, |s| s.path().to_path_buf());
+
+    let ref_file_clone = ref_file.clone();
+    // The `ref_file` input argument is either a FASTA file with reference
+    // sequences, in which case we will compute the proper digest in a separate
+    // thread, OR an existing minimap2 index, in which case we won't attempt
+    // to treat it as a FASTA file and we will later get the digest from
+    // the index.
+    let digest_handle = if is_fasta(&ref_file).unwrap_or(false) {
+        Some(std::thread::spawn(move || {
+            info!("generating reference digest");
+            let mut seqcol_obj =
+                seqcol_rs::SeqCol::try_from_fasta_file(&ref_file_clone).map_err(|e| {
+                    errors::bad_input(format!(
+                        "could not read reference {} : {}",
+                        ref_file_clone.display(),
+                        e
+                    ))
+                })?;
+            let digest = seqcol_obj.digest(seqcol_rs::DigestConfig {
+                level: seqcol_rs::DigestLevel::Level1,
+                additional_attr: vec![seqcol_rs::KnownAttr::SortedNameLengthPairs],
+            })?;
+            info!("done");
+            anyhow::Ok(digest)
+        }))
+    } else {
+        // if the input was not a FASTA file, then don't attempt to
+        // write out another index, because we are reading one in!
+        if args.index_out.is_some() {
+            warn!(
+                "The `--index-out` flag is set, but the input already appears to be an index; skipping writing of output index"
+            );
+            args.index_out = None;
+        }
+        info!("Reading existing minimap2 index that was not created by oarfish.");
+        None
+    };
+
+    // with `--decoys`, the index is built from a copy of the reference to
+    // which a decoy of each target has been appended
+    let decoy_ref = match args.decoys {
+        Some(mode) if digest_handle.is_some() => {
+            Some(decoys::write_decoy_reference(&ref_file, mode)?)
+        }
+        Some(_) => anyhow::bail!(
+            "`--decoys` requires the `--reference` to be a FASTA file, rather than an index"
+        ),
+        None => None,
+    };
+    let index_file = decoy_ref
+        .as_ref()
+        .map_or(ref_file.clone(), |d| d.path().to_path_buf());
+
+    let thread_sub = if digest_handle.is_some() { 1 } else { 0 };
+    // set the number of indexing threads
+    let idx_threads = &args.threads.saturating_sub(thread_sub).max(1);
+
+    // if the user requested to write the output index to disk, prepare for that
+    let idx_out_as_str = match args.index_out {
+        Some(ref x) => x
+            .to_str()
+            .ok_or_else(|| {
+                errors::bad_input(format!(
+                    "the --index-out path {} is not valid UTF-8",
+                    x.display()
+                ))
+            })?
+            .to_owned(),
+        None => String::new(),
+    };
+    let idx_output = args.index_out.as_ref().map(|_| idx_out_as_str.as_str());
+
+    let index_err = |e: &str| {
+        errors::bad_input(format!(
+            "could not construct the minimap2 index of {} : {}",
+            index_file.display(),
+            e
+        ))
+    };
+    // create the aligner
+    let mut aligner = match args.seq_tech {
+        Some(SequencingTech::OntCDNA) | Some(SequencingTech::OntDRNA) => {
+            minimap2::Aligner::builder()
+                .map_ont()
+                .with_index_threads(*idx_threads)
+                .with_cigar()
+                .with_index(index_file.clone(), idx_output)
+                .map_err(index_err)?
+        }
+        Some(SequencingTech::PacBio) => minimap2::Aligner::builder()
+            .map_pb()
+            .with_index_threads(*idx_threads)
+            .with_cigar()
+            .with_index(index_file.clone(), idx_output)
+            .map_err(index_err)?,
+        Some(SequencingTech::PacBioHifi) => minimap2::Aligner::builder()
+            .map_hifi()
+            .with_index_threads(*idx_threads)
+            .with_cigar()
+            .with_index(index_file.clone(), idx_output)
+            .map_err(index_err)?,
+        None => {
+            return Err(errors::bad_input(
+                "sequencing tech must be provided in read mode, but it was not!",
+            ));
+        }
+    };
+
+    info!("created aligner index opts : {:?}", aligner.idxopt);
+
+    // if we loaded a pre-built index, make sure it is consistent with the preset
+    if digest_handle.is_none()
+        && let Some(seq_tech) = args.seq_tech.as_ref()
+    {
+        let mmi: Arc<MmIdx> = Arc::clone(
+            aligner
+                .idx
+                .as_ref()
+                .context("the minimap2 index was not loaded")?,
+        );
+        mm_utils::check_index_preset(
+            &mmi,
+            &aligner.idxopt,
+            seq_tech.minimap2_preset(),
+            args.strict_index_check,
+        )?;
+    }
+    // get up to the best_n hits for each read
+    // default value is 100.
+    aligner.mapopt.best_n = args.best_n as i32;
+    // set the seed to that of the run or, by default, to be the
+    // same as what command-line minimap2 uses.
+    aligner.mapopt.seed = args.seed.map_or(11, |s| s as i32);
+
+    // a pre-built index may consist of several parts (e.g. if it was merged from
+    // shards with `oarfish index --merge-sketches`), of which only the first has
+    // been loaded so far
+    let aligner = if digest_handle.is_none() {
+        mm_utils::PartitionedAligner::load_parts(aligner, &index_file, *idx_threads)?
+    } else {
+        mm_utils::PartitionedAligner::from(aligner)
+    };
+
+    let n_seq = aligner.n_seq();
+
+    info!(
+        "index contains {} sequences",
+        n_seq.to_formatted_string(&Locale::en)
+    );
+
+    let mut header = noodles_sam::header::Header::builder();
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub struct SeqMetaData {
+        pub name: String,
+        pub length: u32,
+        pub is_alt: bool,
+    }
+
+    // TODO: better creation of the header
+    {
+        for i in 0..n_seq {
+            let seq = aligner.get_seq(i as usize).with_context(|| {
+                format!(
+                    "{} was not a valid reference sequence index. (n_seq = {})",
+                    i, n_seq
+                )
+            })?;
+            let c_str = unsafe { ffi::CStr::from_ptr(seq.name) };
+            let rust_str = c_str
+                .to_str()
+                .map_err(|e| errors::bad_input(format!("invalid reference sequence name: {}", e)))?
+                .to_string();
+            header = header.add_reference_sequence(
+                rust_str,
+                HeaderMap::<header_val::map::ReferenceSequence>::new(NonZeroUsize::try_from(
+                    seq.len as usize,
+                )?),
+            );
+        }
+    }
+
+    header = header.add_program(
+        "minimap2-rs",
+        HeaderMap::<header_val::map::Program>::default(),
+    );
+    {
+        use header_val::map::program::tag as pg_tag;
+        let cmd_line = std::env::args().collect::<Vec<String>>().join(" ");
+        header = header.add_program(
+            "oarfish",
+            HeaderMap::<header_val::map::Program>::builder()
+                .insert(pg_tag::NAME, "oarfish")
+                .insert(pg_tag::VERSION, env!("CARGO_PKG_VERSION"))
+                .insert(pg_tag::PREVIOUS_PROGRAM_ID, "minimap2-rs")
+                .insert(pg_tag::COMMAND_LINE, cmd_line)
+                .build()?,
+        );
+    }
+
+    let header = header.build();
+
+    let digest = match digest_handle {
+        // we are building the digest from an input fasta file
+        Some(digest_handle_inner) => {
+            let digest = digest_handle_inner.join().map_err(|_| {
+                anyhow::anyhow!("the thread computing the reference digest panicked")
+            })??;
+            // if we created an index, append the digest
+            if let Some(idx_file) = idx_output {
+                digest_utils::append_digest_to_mm2_index(idx_file, &digest)?;
+            }
+            digest
+        }
+        _ => index_digest(&ref_file, &aligner, &header)?,
+    };
+
+    Ok((header, None, Some(aligner), digest))
+}
+
+fn get_filter_opts(args: &Args) -> anyhow::Result<AlignmentFilters> {
+    // set all of the filter options that the user
+    // wants to apply.
+    match args.filter_group {
+        Some(FilterGroup::NoFilters) => {
+            info!("disabling alignment filters.");
+            // override individual parameters if the user passed them in explicitly
+            let fpc = args
+                .five_prime_clip
+                .provided_or_u32("overriding 5' clip with user-provided value", u32::MAX);
+            let tpc = args
+                .three_prime_clip
+                .provided_or_i64("overriding 3' clip with user-provided value", i64::MAX);
+            let st = args
+                .score_threshold
+                .provided_or_f32("overriding score threshold with user-provided value", 0_f32);
+            let maf = args.min_aligned_fraction.provided_or_f32(
+                "overriding min aligned fraction with user-provided value",
+                0_f32,
+            );
+            let mal = args.min_aligned_len.provided_or_u32(
+                "overriding min aligned length with user-provided value",
+                1_u32,
+            );
+            let mid = args
+                .min_identity
+                .provided_or_f32("overriding min identity with user-provided value", 0_f32);
+
+            Ok(AlignmentFilters::builder()
+                .five_prime_clip(fpc)
+                .three_prime_clip(tpc)
+                .score_threshold(st)
+                .min_aligned_fraction(maf)
+                .min_aligned_len(mal)
+                .min_identity(mid)
+                .identity_type(args.identity_type)
+                .score_type(args.score)
+                .clip_mode(args.clip_mode)
+                .secondary_policy(args.secondary_policy)
+                .secondary_top_k(args.secondary_top_k)
+                .rescue_supplementary(args.rescue_supplementary)
+                .which_strand(args.strand_filter)
+                .model_coverage(args.model_coverage)
+                .logistic_growth_rate(args.growth_rate)
+                .write_assignment_probs(args.write_assignment_probs.is_some())
+                .write_assignment_probs_type(args.write_assignment_probs.clone())
+                .read_quality_weighting(!args.no_read_quality_weighting)
+                .build())
+        }
+        Some(FilterGroup::NanocountFilters) => {
+            info!("setting filters to nanocount defaults.");
+            // override individual parameters if the user passed them in explicitly
+            let fpc = args
+                .five_prime_clip
+                .provided_or_u32("overriding 5' clip with user-provided value", u32::MAX);
+            let tpc = args
+                .three_prime_clip
+                .provided_or_i64("overriding 3' clip with user-provided value", 50_i64);
+            let st = args.score_threshold.provided_or_f32(
+                "overriding score threshold with user-provided value",
+                0.95_f32,
+            );
+            let maf = args.min_aligned_fraction.provided_or_f32(
+                "overriding min aligned fraction with user-provided value",
+                0.5_f32,
+            );
+            let mal = args.min_aligned_len.provided_or_u32(
+                "overriding min aligned length with user-provided value",
+                50_u32,
+            );
+            let mid = args
+                .min_identity
+                .provided_or_f32("overriding min identity with user-provided value", 0_f32);
+
+            Ok(AlignmentFilters::builder()
+                .five_prime_clip(fpc)
+                .three_prime_clip(tpc)
+                .score_threshold(st)
+                .min_aligned_fraction(maf)
+                .min_aligned_len(mal)
+                .min_identity(mid)
+                .identity_type(args.identity_type)
+                .score_type(args.score)
+                .clip_mode(args.clip_mode)
+                .secondary_policy(args.secondary_policy)
+                .secondary_top_k(args.secondary_top_k)
+                .rescue_supplementary(args.rescue_supplementary)
+                .which_strand(bio_types::strand::Strand::Forward)
+                .model_coverage(args.model_coverage)
+                .logistic_growth_rate(args.growth_rate)
+                .write_assignment_probs(args.write_assignment_probs.is_some())
+                .write_assignment_probs_type(args.write_assignment_probs.clone())
+                .read_quality_weighting(!args.no_read_quality_weighting)
+                .build())
+        }
+        Some(ref group) => {
+            let preset = group
+                .preset()
+                .expect("sequencing technology filter groups should have a preset");
+            info!("setting filters to {:?} defaults.", group);
+            // override individual parameters if the user passed them in explicitly
+            let fpc = args.five_prime_clip.provided_or_u32(
+                "overriding 5' clip with user-provided value",
+                preset.five_prime_clip,
+            );
+            let tpc = args.three_prime_clip.provided_or_i64(
+                "overriding 3' clip with user-provided value",
+                preset.three_prime_clip,
+            );
+            let st = args.score_threshold.provided_or_f32(
+                "overriding score threshold with user-provided value",
+                preset.score_threshold,
+            );
+            let maf = args.min_aligned_fraction.provided_or_f32(
+                "overriding min aligned fraction with user-provided value",
+                preset.min_aligned_fraction,
+            );
+            let mal = args.min_aligned_len.provided_or_u32(
+                "overriding min aligned length with user-provided value",
+                preset.min_aligned_len,
+            );
+            let mid = args.min_identity.provided_or_f32(
+                "overriding min identity with user-provided value",
+                preset.min_identity,
+            );
+            // the strand filter has no explicit default, so any
+            // orientation other than "both" is taken as an override
+            let strand = if args.strand_filter != bio_types::strand::Strand::Unknown {
+                info!(
+                    "overriding strand filter with user-provided value {:?}",
+                    args.strand_filter
+                );
+                args.strand_filter
+            } else {
+                preset.which_strand
+            };
+
+            Ok(AlignmentFilters::builder()
+                .five_prime_clip(fpc)
+                .three_prime_clip(tpc)
+                .score_threshold(st)
+                .min_aligned_fraction(maf)
+                .min_aligned_len(mal)
+                .min_identity(mid)
+                .identity_type(args.identity_type)
+                .score_type(args.score)
+                .clip_mode(args.clip_mode)
+                .secondary_policy(args.secondary_policy)
+                .secondary_top_k(args.secondary_top_k)
+                .rescue_supplementary(args.rescue_supplementary)
+                .which_strand(strand)
+                .model_coverage(args.model_coverage)
+                .logistic_growth_rate(args.growth_rate)
+                .write_assignment_probs(args.write_assignment_probs.is_some())
+                .write_assignment_probs_type(args.write_assignment_probs.clone())
+                .read_quality_weighting(!args.no_read_quality_weighting)
+                .build())
+        }
+        None => {
+            info!("setting user-provided filter parameters.");
+            Ok(AlignmentFilters::builder()
+                .five_prime_clip(args.five_prime_clip.try_as_u32()?)
+                .three_prime_clip(args.three_prime_clip.try_as_i64()?)
+                .score_threshold(args.score_threshold.try_as_f32()?)
+                .min_aligned_fraction(args.min_aligned_fraction.try_as_f32()?)
+                .min_aligned_len(args.min_aligned_len.try_as_u32()?)
+                .min_identity(args.min_identity.try_as_f32()?)
+                .identity_type(args.identity_type)
+                .score_type(args.score)
+                .clip_mode(args.clip_mode)
+                .secondary_policy(args.secondary_policy)
+                .secondary_top_k(args.secondary_top_k)
+                .rescue_supplementary(args.rescue_supplementary)
+                .which_strand(args.strand_filter)
+                .model_coverage(args.model_coverage)
+                .logistic_growth_rate(args.growth_rate)
+                .write_assignment_probs(args.write_assignment_probs.is_some())
+                .write_assignment_probs_type(args.write_assignment_probs.clone())
+                .read_quality_weighting(!args.no_read_quality_weighting)
+                .build())
+        }
+    }
+}
+
+/// Quantify nested subsamples of the alignments in `alignments`, filtered as in a
+/// quantification run with the `filter_group` (if any), and write the saturation curves
+/// of the sample (see [saturation::saturation_curve]) with the prefix `output`.
+fn run_saturation(
+    alignments: &std::path::Path,
+    output: &std::path::Path,
+    fractions: &[f64],
+    seed: u64,
+    min_reads: f64,
+    filter_group: Option<FilterGroup>,
+    threads: usize,
+) -> anyhow::Result<()> {
+    // the alignments are filtered exactly as `oarfish` would filter them by default, or
+    // with the given filter group
+    let mut qargs: Vec<std::ffi::OsString> = vec![
+        "oarfish".into(),
+        "--alignments".into(),
+        alignments.into(),
+        "--output".into(),
+        output.into(),
+    ];
+    if let Some(fg) = filter_group
+        .as_ref()
+        .and_then(clap::ValueEnum::to_possible_value)
+    {
+        qargs.push("--filter-group".into());
+        qargs.push(fg.get_name().into());
+    }
+    let args = Args::try_parse_from(qargs)?;
+    let filter_opts = get_filter_opts(&args)?;
+
+    let (afile, afile_len) = object_store_io::open_input(alignments, args.io_backend)?;
+    let afile = progress::track_read(afile, afile_len, "BAM traversal");
+    let worker_count = NonZeroUsize::new(threads.max(1)).expect("threads >= 1");
+    let mut reader = bam::io::Reader::from(bgzf::MultithreadedReader::with_worker_count(
+        worker_count,
+        afile,
+    ));
+    let header = alignment_parser::read_and_verify_header(&mut reader, alignments, false)?;
+    let mut txps: Vec<TranscriptInfo> = header
+        .reference_sequences()
+        .iter()
+        .map(|(_, rmap)| TranscriptInfo::with_len(rmap.length()))
+        .collect();
+
+    let mut store = InMemoryAlignmentStore::new(filter_opts, &header);
+    // the subsample of each read is decided by its name, so the names are kept
+    let mut name_vec = Some(duplicates::new_name_vec());
+    alignment_parser::parse_alignments(
+        &mut store,
+        &mut name_vec,
+        &header,
+        &mut reader,
+        &mut txps,
+        args.sort_check_num,
+        false,
+        ReadSubsample::default(),
+    )?;
+    let read_keys: Vec<f64> = name_vec
+        .expect("read names were kept")
+        .into_iter()
+        .map(|name| {
+            let name = name.expect("could not extract read name from file");
+            subsample::read_key(name.trim_end_matches('\0').as_bytes(), seed)
+        })
+        .collect();
+
+    let emi = EMInfo {
+        eq_map: &store,
+        txp_info: &txps,
+        max_iter: args.max_em_iter,
+        min_iter: args.min_em_iter,
+        convergence_thresh: args.convergence_thresh,
+        init_abundances: None,
+        kde_model: None,
+        txp_weights: None,
+        trace: None,
+    };
+    let points = saturation::saturation_curve(&emi, &read_keys, fractions, min_reads, threads);
+    saturation::write_saturation(output, &points)
+}
+
+/// Run the auxiliary tool requested in `targs`.
+fn run_tool<S>(
+    targs: ToolArgs,
+    reload_handle: &reload::Handle<EnvFilter, S>,
+) -> anyhow::Result<()> {
+    match targs.command {
+        Tool::Migrate { outputs, dry_run } => output_schema::migrate_outputs(&outputs, dry_run),
+        Tool::MergeSc {
+            inputs,
+            output,
+            sample_names,
+            barcode_suffix,
+        } => sc_merge::merge_single_cell_outputs(
+            &inputs,
+            sample_names.as_deref(),
+            barcode_suffix,
+            &output,
+        ),
+        Tool::Merge {
+            inputs,
+            output,
+            num_bootstraps,
+            max_em_iter,
+            convergence_thresh,
+            threads,
+        } => eq_classes::merge_replicates(
+            &inputs,
+            &output,
+            num_bootstraps,
+            max_em_iter,
+            convergence_thresh,
+            threads,
+        ),
+        Tool::QuantEq {
+            eq_classes,
+            output,
+            num_bootstraps,
+            max_em_iter,
+            convergence_thresh,
+            prior,
+            threads,
+        } => eq_classes::quant_from_eq_classes(
+            &eq_classes,
+            &output,
+            num_bootstraps,
+            max_em_iter,
+            convergence_thresh,
+            prior,
+            threads,
+        ),
+        Tool::Saturation {
+            alignments,
+            output,
+            fractions,
+            seed,
+            min_reads,
+            filter_group,
+            threads,
+        } => {
+            let fractions: Vec<f64> = fractions.iter().map(|p| *p as f64).collect();
+            run_saturation(
+                &alignments,
+                &output,
+                &fractions,
+                seed,
+                min_reads,
+                filter_group,
+                threads,
+            )
+        }
+        Tool::Unpack {
+            archive,
+            members,
+            output_dir,
+            list,
+        } => archive::unpack_archive(&archive, &members, &output_dir, list),
+        Tool::Index {
+            reference,
+            seq_tech,
+            shard,
+            merge_sketches,
+            output,
+            threads,
+        } => match (shard, merge_sketches) {
+            (Some(shard), _) => sharded_index::build_shard(
+                &reference.ok_or_else(|| errors::bad_input("--shard requires --reference"))?,
+                seq_tech.ok_or_else(|| errors::bad_input("--shard requires --seq-tech"))?,
+                &shard,
+                &output,
+                threads,
+            ),
+            (None, Some(shards)) => {
+                sharded_index::merge_sketches(&shards, reference.as_deref(), &output)
+            }
+            (None, None) => Err(errors::bad_input(
+                "one of --shard or --merge-sketches must be given",
+            )),
+        },
+        Tool::Haplotypes {
+            reference,
+            vcf,
+            output,
+            haplotype_suffixes,
+        } => allelic::write_haplotype_transcriptome(&reference, &vcf, &output, &haplotype_suffixes),
+        Tool::Serve {
+            index,
+            seq_tech,
+            socket,
+            threads,
+        } => serve::serve(&index, &seq_tech, &socket, threads, reload_handle),
+        Tool::SketchCompare { inputs } => digest_utils::sketch_compare(&inputs),
+        Tool::Completions { shell } => cli_docs::write_completions(shell),
+        Tool::Man { output_dir } => cli_docs::write_man_pages(output_dir.as_deref()),
+    }
+}
+
+/// The global allocator registered by the `oarfish` binary, which counts the bytes
+/// allocated so that `--max-mem` can be enforced.
+pub use crate::util::mem_tracker::TrackingAllocator;
+
+/// The exit code with which oarfish stops after the failure `err`, by its class of failure.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    errors::classify(err).exit_code()
+}
+
+/// Set the state of the process that belongs to the run described by `args`: the format
+/// of the log, the progress bars, the capture of the log, the memory limit and the
+/// profile. Every run sets all of it, so that the runs of one process (e.g. the jobs of
+/// `oarfish serve`) don't inherit it from each other. This fails if `--max-mem` is given
+/// but can't be enforced (see [mem_tracker::set_limit]).
+pub(crate) fn configure_run(args: &Args) -> anyhow::Result<()> {
+    logging::set_format(args.log_format);
+    // progress bars would be interleaved with the JSON log
+    progress::init(args.quiet || logging::is_json());
+    progress::set_track_em(true);
+    archive::capture_log(args.archive);
+    mem_tracker::set_limit(args.max_mem)?;
+    profile::set_enabled(args.profile);
+    Ok(())
+}
+
+/// Run oarfish with the arguments given on the command line, either quantifying a
+/// sample or running one of the auxiliary tools.
+pub fn run() -> anyhow::Result<()> {
+    resource_usage::start();
+    let env_filter = logging::default_filter();
+    let (filtered_layer, reload_handle) = tracing_subscriber::reload::Layer::new(env_filter);
+
+    // set up the logging.  Here we will take the
+    // logging level from the environment variable if
+    // it is set.  Otherwise, we'll set the default
+    tracing_subscriber::registry()
+        // log level to INFO.
+        .with(
+            fmt::layer()
+                .with_writer(|| progress::SuspendingStderr)
+                .with_filter(filter_fn(|_| !logging::is_json())),
+        )
+        // with `--log-format json`, the log is instead written as one JSON object per line
+        .with(
+            fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_writer(io::stderr)
+                .with_filter(filter_fn(|_| logging::is_json())),
+        )
+        // a copy of the log, captured only if the output is to be archived
+        .with(
+            fmt::layer()
+                .with_ansi(false)
+                .with_writer(|| archive::LogCapture),
+        )
+        .with(filtered_layer)
+        .init();
+
+    // auxiliary tools (e.g. `oarfish migrate`) have their own arguments
+    if ToolArgs::is_tool_invocation() {
+        return run_tool(ToolArgs::parse(), &reload_handle);
+    }
+
+    let mut args = Args::parse();
+
+    // change the logging filter if the user specified quiet or
+    // verbose, or the verbosity of individual subsystems.
+    logging::set_filter(&reload_handle, &args, false)?;
+    configure_run(&args)?;
+
+    let output = args.output.clone();
+    logging::run_start(&output);
+    let pack_output = args.archive && !args.validate_only;
+    // the outputs are written to a staging directory, and moved into place once the run succeeds
+    let staging = if args.validate_only {
+        None
+    } else {
+        let staging = atomic_output::OutputStaging::new(&args.output, args.resume)?;
+        args.output = staging.staged_output();
+        Some(staging)
+    };
+    let staged_output = args.output.clone();
+    match args.control_alignments.clone() {
+        // the control alignments are validated along with those of the case sample
+        Some(control) if !args.validate_only => {
+            quantify_case_control(args, control, &reload_handle)?
+        }
+        _ => quantify(args, &reload_handle, None)?,
+    }
+    if pack_output {
+        archive::archive_output(&staged_output)?;
+    }
+    if let Some(staging) = staging {
+        staging.commit()?;
+    }
+    logging::run_end(resource_usage::wall_time_secs());
+    Ok(())
+}
+
+/// Quantify the sample given with `--alignments` and the control sample given
+/// with `--control-alignments` (writing to `<output>.control`) with the same
+/// settings, then screen the genes for isoform switches between them.
+fn quantify_case_control<S>(
+    mut args: Args,
+    control: PathBuf,
+    reload_handle: &reload::Handle<EnvFilter, S>,
+) -> anyhow::Result<()> {
+    // the confidence of each switch is assessed with bootstrap replicates
+    if args.num_bootstraps == 0 {
+        info!(
+            "computing {} bootstrap replicates of each sample to assess isoform switches.",
+            DEFAULT_SWITCH_BOOTSTRAPS
+        );
+        args.num_bootstraps = DEFAULT_SWITCH_BOOTSTRAPS;
+    }
+    let control_args = Args {
+        alignments: vec![control],
+        control_alignments: None,
+        output: args.output.with_additional_extension(".control"),
+        ..args.clone()
+    };
+    let txp_to_gene = args
+        .txp_to_gene
+        .clone()
+        .ok_or_else(|| errors::bad_input("--txp-to-gene is required with --control-alignments"))?;
+
+    info!("quantifying the case sample {:?}", args.alignments);
+    quantify(args.clone(), reload_handle, None)?;
+    info!(
+        "quantifying the control sample {:?}",
+        control_args.alignments
+    );
+    quantify(control_args.clone(), reload_handle, None)?;
+
+    isoform_switch::screen_isoform_switches(
+        &args.output,
+        &control_args.output,
+        &txp_to_gene,
+        args.switch_min_reads,
+        args.switch_min_support,
+        &args.output,
+    )
+}
+
+/// Enable the coverage model if it is implied by the other options of `args`.
+fn enable_implied_coverage_model(args: &mut Args) {
+    // the sequencing technology filter groups may also enable the coverage model
+    if let Some(preset) = args.filter_group.as_ref().and_then(FilterGroup::preset)
+        && preset.model_coverage
+        && !args.model_coverage
+    {
+        info!("enabling the coverage model for this filter group.");
+        args.model_coverage = true;
+    }
+
+    // choosing a coverage model enables the coverage model
+    if args.coverage_model.is_some() && !args.model_coverage {
+        args.model_coverage = true;
+    }
+}
+
+/// Quantify the sample described by `args`, mapping its reads (if it is given as reads)
+/// to the already loaded `index`, if any, rather than to the index of `--reference`.
+fn quantify<S>(
+    mut args: Args,
+    reload_handle: &reload::Handle<EnvFilter, S>,
+    index: Option<&LoadedIndex>,
+) -> anyhow::Result<()> {
+    // every event of this run is logged in the context of its sample, named by its output
+    let _sample_span = info_span!("sample", output = %args.output.display()).entered();
+
+    enable_implied_coverage_model(&mut args);
+
+    // a seeded run must produce the same outputs whatever the number of threads
+    if args.seed.is_some() && !args.single_cell && !args.deterministic {
+        info!("running the EM deterministically, as a seed was given.");
+        args.deterministic = true;
+    }
+
+    // the groups of indistinguishable transcripts are found from bootstrap replicates
+    if args.indistinguishable_groups {
+        match args.num_bootstraps {
+            0 => {
+                info!(
+                    "computing {} bootstrap replicates to find the groups of indistinguishable transcripts.",
+                    DEFAULT_GROUP_BOOTSTRAPS
+                );
+                args.num_bootstraps = DEFAULT_GROUP_BOOTSTRAPS;
+            }
+            1 => {
+                return Err(errors::bad_input(
+                    "--indistinguishable-groups needs at least 2 bootstrap replicates",
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    // the coverage model must be applied to compare the estimates made with and without it
+    if args.compare_coverage_model && !args.model_coverage {
+        info!("enabling the coverage model to compare the estimates made with and without it.");
+        args.model_coverage = true;
+    }
+
+    if args.loom && !cfg!(feature = "loom") {
+        anyhow::bail!(loom::LOOM_UNAVAILABLE);
+    }
+    if args.gpu && !cfg!(feature = "gpu") {
+        anyhow::bail!(gpu_em::GPU_UNAVAILABLE);
+    }
+
+    if args.bin_width.is_none() && args.min_bin_width > args.max_bin_width {
+        anyhow::bail!(
+            "--min-bin-width ({}) must not be larger than --max-bin-width ({})",
+            args.min_bin_width,
+            args.max_bin_width
+        );
+    }
+
+    let mut filter_opts = get_filter_opts(&args)?;
+
+    if args.validate_only {
+        return validate::validate_only(&args, &filter_opts);
+    }
+    provenance::start(&args);
+
+    let mut ref_mismatch = None;
+    let (header, reader, aligner, digest) = if let Some(ref cache) = args.from_alignment_cache {
+        // the alignments were parsed by an earlier run; only the reference is read here
+        let cache_header = alignment_cache::read_cache_header(cache)?;
+        cache_header.log(cache);
+        (
+            cache_header.sam_header()?,
+            None,
+            None,
+            cache_header.digest()?,
+        )
+    } else if args.alignments.is_empty() {
+        match index {
+            Some(index) => index.for_run(&args)?,
+            None => get_aligner_from_args(&mut args)?,
+        }
+    } else {
+        // the alignments of a single-cell sample must be collated by cell barcode, which
+        // does not survive concatenating several files
+        if args.single_cell && args.alignments.len() > 1 {
+            return Err(errors::bad_input(format!(
+                "single-cell quantification takes a single BAM file, but {} were given; merge them (e.g. with `samtools merge`) first.",
+                args.alignments.len()
+            )));
+        }
+        let alignments = args.alignments.clone();
+
+        let plan = BamThreadPlan::new(args.threads, args.single_cell);
+        info!(
+            "using {} (up to {}) decompression threads and up to {} quantification workers.",
+            plan.decompression, plan.max_decompression, plan.workers
+        );
+
+        if args.single_cell {
+            args.threads = plan.workers;
+        }
+
+        // parse the header of each file, and ensure that the reads were mapped with minimap2
+        // (as far as we can tell) and that all of the files are against the same reference;
+        // the records that follow the headers are then read as one stream.
+        // coordinate-sorted single-cell inputs are sorted by cell barcode before quantification
+        let mut header = None;
+        let mut records: Option<Box<dyn io::BufRead + Send>> = None;
+        for path in alignments.iter() {
+            let (afile, afile_len) = object_store_io::open_input(path, args.io_backend)?;
+            let afile = progress::track_read(afile, afile_len, "BAM traversal");
+            let decoder =
+                BalancedBgzfReader::new(afile, plan.decompression, plan.max_decompression);
+            let mut file_reader = bam::io::Reader::from(decoder);
+            let file_header = alignment_parser::read_and_verify_header(
+                &mut file_reader,
+                path,
+                args.single_cell && !args.assume_collated,
+            )?;
+            match header {
+                None => header = Some(file_header),
+                Some(ref first) => alignment_parser::verify_headers_agree(
+                    first,
+                    &alignments[0],
+                    &file_header,
+                    path,
+                )?,
+            }
+            // the time the parser waits on the decompression threads is recorded with --profile
+            let file_records =
+                profile::TimedRead::new(file_reader.into_inner(), Stage::Decompression);
+            records = Some(match records {
+                None => Box::new(file_records),
+                Some(prev) => Box::new(prev.chain(file_records)),
+            });
+        }
+        if alignments.len() > 1 {
+            info!(
+                "quantifying the alignments of {} BAM files as a single sample.",
+                alignments.len()
+            );
+        }
+        let header = header.expect("at least one alignment file");
+        let reader = bam::io::Reader::from(records.expect("at least one alignment file"));
+        let seqcol_digest = digest_utils::digest_from_header(&header)?;
+        // if requested, verify the reference sequences of the alignments, and
+        // if they differ (and this is allowed), determine how
+        if let Some(ref reference) = args.verify_reference {
+            let staged_ref = if object_store_io::is_remote(reference) {
+                Some(object_store_io::stage_remote(reference)?)
+            } else {
+                None
+            };
+            let reference = staged_ref
+                .as_ref()
+                .map_or(reference.as_path(), |s| s.path());
+            let ref_is_fasta = is_fasta(reference)?;
+            let fail_on_mismatch = args.reference_mismatch == ReferenceMismatchMode::Fail;
+            if !digest_utils::verify_reference_digest(
+                &seqcol_digest,
+                reference,
+                ref_is_fasta,
+                fail_on_mismatch,
+            )? {
+                let ref_seqs = reference_mismatch::read_reference_seqs(reference, ref_is_fasta)?;
+                let mm = reference_mismatch::compare_references(&header, &ref_seqs);
+                mm.log(reference, header.reference_sequences().len());
+                reference_mismatch::write_reference_mismatch(&args.output, &mm)?;
+                ref_mismatch = Some(mm);
+            }
+        }
+        (header, Some(reader), None, seqcol_digest)
+    };
+
+    provenance::set_reference_digest(&digest);
+
+    let num_ref_seqs = header.reference_sequences().len();
+
+    // where we'll write down the per-transcript information we need
+    // to track.
+    let mut txps: Vec<TranscriptInfo> = Vec::with_capacity(num_ref_seqs);
+    let mut txps_name: Vec<String> = Vec::with_capacity(num_ref_seqs);
+
+    // loop over the transcripts in the header and fill in the relevant
+    // information here.
+    if args.model_coverage {
+        let binning = CoverageBinning::from_args(&args);
+        for (rseq, rmap) in header.reference_sequences().iter() {
+            txps.push(TranscriptInfo::with_len_and_binning(
+                rmap.length(),
+                &binning,
+            ));
+            txps_name.push(rseq.to_string());
+        }
+    } else {
+        for (rseq, rmap) in header.reference_sequences().iter() {
+            txps.push(TranscriptInfo::with_len(rmap.length()));
+            txps_name.push(rseq.to_string());
+        }
+    }
+    info!(
+        "parsed reference information for {} transcripts.",
+        txps.len().to_formatted_string(&Locale::en)
+    );
+
+    // if the user restricted the set of transcripts to quantify, then
+    // alignments to the excluded transcripts will be filtered out.
+    let mut excluded = if args.keep_transcripts.is_some() || args.exclude_transcripts.is_some() {
+        Some(get_excluded_txp_mask(
+            args.keep_transcripts.as_deref(),
+            args.exclude_transcripts.as_deref(),
+            &txps_name,
+        )?)
+    } else {
+        None
+    };
+    // likewise if only the transcripts shared with the verification reference are quantified
+    if let Some(ref mm) = ref_mismatch
+        && args.reference_mismatch == ReferenceMismatchMode::Intersect
+    {
+        let not_shared = mm.excluded_mask(&txps_name);
+        let mask: Vec<bool> = match excluded {
+            Some(ex) => ex
+                .iter()
+                .zip(not_shared.iter())
+                .map(|(a, b)| *a || *b)
+                .collect(),
+            None => not_shared,
+        };
+        if mask.iter().all(|x| *x) {
+            return Err(errors::reference_mismatch(
+                "none of the transcripts in the alignments are shared with the reference; cannot proceed.",
+            ));
+        }
+        excluded = Some(mask);
+    }
+    if let Some(excluded) = excluded {
+        filter_opts.set_excluded_txps(excluded);
+    }
+    if args.decoys.is_some()
+        && let Some(decoy_start) = decoys::decoy_start(&txps_name)
+    {
+        filter_opts.set_decoy_start(decoy_start);
+    }
+    if args.calibrate_scores {
+        if args
+            .alignments
+            .iter()
+            .any(|a| object_store_io::is_remote(a))
+        {
+            anyhow::bail!(
+                "--calibrate-scores requires a first pass over the alignments, so it can't be used with alignments streamed from object storage."
+            );
+        }
+        if args.score != ScoreType::As {
+            anyhow::bail!(
+                "--calibrate-scores calibrates the alignment scores (the AS tag), so it can't be used with --score {:?}.",
+                args.score
+            );
+        }
+        // the calibration is fit to the first reads of the (first) alignment file
+        let alignments = args.alignments.first().expect("alignments are required");
+        if let Some(calibration) =
+            score_calibration::fit_score_calibration(alignments, args.calibration_reads)?
+        {
+            filter_opts.set_score_calibration(calibration);
+        }
+    }
+    resource_usage::end_stage("setup");
+
+    // the output prefixes of the quantified samples
+    let mut outputs = vec![args.output.clone()];
+    if args.single_cell {
+        progress::set_track_em(false);
+        // quiet the subsystems (e.g. the EM) that run once per cell
+        logging::set_filter(reload_handle, &args, true)?;
+
+        single_cell::quantify_single_cell_from_collated_bam(
+            &header,
+            &filter_opts,
+            &mut reader.unwrap(),
+            &mut txps,
+            &args,
+            digest,
+        )?;
+    } else if args.split_by_read_group {
+        outputs = bulk::quantify_bulk_alignments_by_read_group(
+            &header,
+            filter_opts,
+            &mut reader.unwrap(),
+            &txps,
+            &txps_name,
+            &args,
+        )?;
+    } else if let Some(ref cache) = args.from_alignment_cache {
+        bulk::quantify_bulk_alignments_from_cache(
+            &header,
+            filter_opts,
+            cache,
+            &mut txps,
+            &txps_name,
+            &args,
+            digest,
+        )?;
+    } else if !args.alignments.is_empty() {
+        bulk::quantify_bulk_alignments_from_bam(
+            &header,
+            filter_opts,
+            &mut reader.unwrap(),
+            &mut txps,
+            &txps_name,
+            &args,
+            digest,
+        )?;
+    } else {
+        outputs = bulk::quantify_bulk_alignments_raw_reads(
+            &header,
+            aligner.expect("need valid alinger to align reads"),
+            filter_opts,
+            &args.reads.clone().expect("expected read file(s)"),
+            &mut txps,
+            &txps_name,
+            &args,
+            digest,
+        )?;
+    }
+
+    // now that the run is complete, record the resources it used
+    for output in outputs.iter() {
+        resource_usage::add_to_meta_info(output)?;
+    }
+
+    info!("oarfish completed successfully.");
+    Ok(())
+}
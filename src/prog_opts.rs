@@ -1,9 +1,11 @@
+use crate::util::quant_results::QuantResults;
 use clap::{Parser, builder::ArgPredicate};
 use parse_size::parse_size;
 use serde::Serialize;
 use std::fmt;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 use tracing::info;
 
 /// These represent different "meta-options", specific settings
@@ -13,6 +15,90 @@ use tracing::info;
 pub enum FilterGroup {
     NoFilters,
     NanocountFilters,
+    /// filters tuned for Oxford Nanopore cDNA reads
+    OntCdna,
+    /// filters tuned for Oxford Nanopore direct RNA reads
+    OntDrna,
+    /// filters tuned for PacBio HiFi (e.g. Iso-Seq / Kinnex) reads
+    PacbioHifi,
+    /// filters tuned for PacBio continuous long reads (CLR)
+    PacbioClr,
+}
+
+/// The filter settings implied by one of the sequencing technology
+/// specific [FilterGroup]s. Any filter value provided explicitly
+/// by the user takes precedence over the value in the preset.
+#[derive(Clone, Debug)]
+pub struct FilterPreset {
+    pub five_prime_clip: u32,
+    pub three_prime_clip: i64,
+    pub score_threshold: f32,
+    pub min_aligned_fraction: f32,
+    pub min_aligned_len: u32,
+    pub min_identity: f32,
+    pub which_strand: bio_types::strand::Strand,
+    /// if true, the coverage model is enabled (the coverage model
+    /// can not be disabled by a preset if the user requested it).
+    pub model_coverage: bool,
+}
+
+impl FilterGroup {
+    /// Returns the [FilterPreset] for the sequencing technology specific
+    /// filter groups, and [None] for `no-filters` and `nanocount-filters`.
+    pub fn preset(&self) -> Option<FilterPreset> {
+        use bio_types::strand::Strand;
+        match self {
+            FilterGroup::NoFilters | FilterGroup::NanocountFilters => None,
+            // cDNA reads come from either strand, and are frequently
+            // truncated, so don't restrict the position of the alignment
+            FilterGroup::OntCdna => Some(FilterPreset {
+                five_prime_clip: u32::MAX,
+                three_prime_clip: u32::MAX as i64,
+                score_threshold: 0.95,
+                min_aligned_fraction: 0.5,
+                min_aligned_len: 50,
+                min_identity: 0.0,
+                which_strand: Strand::Unknown,
+                model_coverage: true,
+            }),
+            // direct RNA reads are sense-stranded and are sequenced from
+            // the 3' end, so they should end close to the 3' end of the transcript
+            FilterGroup::OntDrna => Some(FilterPreset {
+                five_prime_clip: u32::MAX,
+                three_prime_clip: 50,
+                score_threshold: 0.95,
+                min_aligned_fraction: 0.5,
+                min_aligned_len: 50,
+                min_identity: 0.0,
+                which_strand: Strand::Forward,
+                model_coverage: true,
+            }),
+            // HiFi reads are accurate and (largely) full-length, so the
+            // alignments should cover most of the read with high identity
+            FilterGroup::PacbioHifi => Some(FilterPreset {
+                five_prime_clip: u32::MAX,
+                three_prime_clip: u32::MAX as i64,
+                score_threshold: 0.98,
+                min_aligned_fraction: 0.8,
+                min_aligned_len: 100,
+                min_identity: 0.95,
+                which_strand: Strand::Unknown,
+                model_coverage: false,
+            }),
+            // CLR reads have a high error rate, so be permissive about the
+            // alignment score and identity
+            FilterGroup::PacbioClr => Some(FilterPreset {
+                five_prime_clip: u32::MAX,
+                three_prime_clip: u32::MAX as i64,
+                score_threshold: 0.9,
+                min_aligned_fraction: 0.5,
+                min_aligned_len: 50,
+                min_identity: 0.0,
+                which_strand: Strand::Unknown,
+                model_coverage: false,
+            }),
+        }
+    }
 }
 
 fn parse_strand(arg: &str) -> anyhow::Result<bio_types::strand::Strand> {
@@ -30,6 +116,280 @@ pub enum ReadAssignmentProbOut {
     Compressed,
 }
 
+/// How `oarfish merge-sc` makes the barcodes of the merged samples unique.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BarcodeSuffix {
+    /// suffix only the barcodes that occur in more than one sample
+    Colliding,
+    /// suffix every barcode
+    All,
+}
+
+/// How `--verify-reference` handles alignments whose reference sequences
+/// differ from those of the given reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReferenceMismatchMode {
+    /// exit with an error
+    Fail,
+    /// report the differing transcripts, but quantify all transcripts in the alignments
+    Report,
+    /// report the differing transcripts, and quantify only the transcripts
+    /// shared (with the same length) by the alignments and the reference
+    Intersect,
+}
+
+/// What `--internal-priming` does with the alignments flagged as likely
+/// internal-priming artifacts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InternalPrimingMode {
+    /// report the flagged alignments, but quantify them as usual
+    Flag,
+    /// multiply the alignment probabilities of the flagged alignments by
+    /// `--internal-priming-weight`
+    Downweight,
+    /// discard the flagged alignments
+    Exclude,
+}
+
+/// How the decoy targets used to estimate the false-assignment rate are derived
+/// from the reference targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DecoyMode {
+    /// the (uncomplemented) reverse of each target
+    Reverse,
+    /// a (seeded) random shuffle of the bases of each target
+    Shuffle,
+}
+
+/// A taxonomic rank at which the estimates are aggregated in metatranscriptomics mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaxRank {
+    Domain,
+    Phylum,
+    Class,
+    Order,
+    Family,
+    Genus,
+    Species,
+    Strain,
+}
+
+impl fmt::Display for TaxRank {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TaxRank::Domain => "domain",
+            TaxRank::Phylum => "phylum",
+            TaxRank::Class => "class",
+            TaxRank::Order => "order",
+            TaxRank::Family => "family",
+            TaxRank::Genus => "genus",
+            TaxRank::Species => "species",
+            TaxRank::Strain => "strain",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The format of the log messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// human-readable lines
+    Text,
+    /// one JSON object per line, with the fields of each event (see `logging` for the
+    /// names of the structured events)
+    Json,
+}
+
+/// How the reads are resampled when computing bootstrap replicates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootstrapStrata {
+    /// resample all reads together
+    None,
+    /// resample the reads within each read group (given by the `RG` tag of the alignments
+    /// or, in read mode, by the file passed to `--reads` from which they came), so that the
+    /// proportion of reads from each group is preserved
+    Rg,
+    /// resample the reads within strata of similar read length (see
+    /// `--bootstrap-length-strata`), so that the distribution of read lengths is preserved
+    ReadLength,
+    /// resample the reads within strata by the size of their equivalence class (the number
+    /// of transcripts to which they align: 1, 2, 3-4, 5-8, ...), so that the proportion of
+    /// unique and of ambiguous reads is preserved
+    EqClassSize,
+}
+
+/// The format in which the (bulk) quantification tables, bootstrap replicates and read
+/// assignment probabilities are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// tab-separated text (with the bootstrap replicates written as parquet)
+    Tsv,
+    /// zstd-compressed parquet
+    Parquet,
+    /// the Arrow IPC file format (also known as feather v2)
+    Arrow,
+}
+
+/// The compression applied to the text outputs (and tables) of a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputCompression {
+    /// write the outputs uncompressed
+    None,
+    /// gzip-compress the outputs (adding `.gz` to their names)
+    Gzip,
+    /// zstd-compress the outputs (adding `.zst` to their names)
+    Zstd,
+}
+
+impl OutputCompression {
+    /// The extension appended to the name of a text file compressed in this way.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gzip => ".gz",
+            Self::Zstd => ".zst",
+        }
+    }
+}
+
+impl OutputFormat {
+    /// The extension of the tables written in this format, or [None] for text output.
+    pub fn table_extension(&self) -> Option<&'static str> {
+        match self {
+            Self::Tsv => None,
+            Self::Parquet => Some("pq"),
+            Self::Arrow => Some("arrow"),
+        }
+    }
+}
+
+/// The model of the coverage of the transcripts used to compute the coverage probability
+/// of each alignment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoverageModel {
+    /// penalize the alignments to the bins of each transcript by the departure of the
+    /// bin's coverage from the transcript's mean coverage, using a logistic function
+    /// (the default in bulk mode)
+    Logistic,
+    /// compute the probability of the coverage of each bin under a binomial model
+    /// (the default in single-cell mode)
+    Binomial,
+    /// fit a positional coverage profile (a cubic spline) across the transcripts of
+    /// similar length, as in salmon's positional bias model; this captures e.g. the 3'
+    /// bias of many ONT cDNA libraries
+    Spline,
+}
+
+impl CoverageModel {
+    /// The name recorded as the `prob_model` of the run.
+    pub fn prob_model_name(&self) -> &'static str {
+        match self {
+            CoverageModel::Logistic => "logistic_coverage",
+            CoverageModel::Binomial => "binomial_coverage",
+            CoverageModel::Spline => "spline_coverage",
+        }
+    }
+}
+
+/// How the bandwidth of the kernel of the KDE model is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KdeBandwidth {
+    /// a fixed bandwidth (in bases)
+    Fixed(f64),
+    /// Silverman's rule of thumb (the geometric mean of its bandwidths for the two
+    /// dimensions)
+    Silverman,
+}
+
+/// Parse the value of `--kde-bandwidth`, which is either a bandwidth (in bases) or
+/// `silverman`.
+fn parse_kde_bandwidth(s: &str) -> anyhow::Result<KdeBandwidth> {
+    match s.to_lowercase().as_str() {
+        "silverman" => Ok(KdeBandwidth::Silverman),
+        x => {
+            let h: f64 = x.parse().map_err(|_| {
+                anyhow::anyhow!(
+                    "expected a bandwidth (in bases) or \"silverman\", but got {:?}",
+                    s
+                )
+            })?;
+            anyhow::ensure!(h > 0.0, "the KDE bandwidth must be positive");
+            Ok(KdeBandwidth::Fixed(h))
+        }
+    }
+}
+
+/// Parse the value of `--threads`, which is either a number of threads or
+/// `auto`, in which case all of the available cores are used.
+fn parse_threads(s: &str) -> anyhow::Result<usize> {
+    if s.eq_ignore_ascii_case("auto") {
+        Ok(std::thread::available_parallelism().map_or(1, |n| n.get()))
+    } else {
+        let n = s.parse::<usize>().map_err(|_| {
+            anyhow::anyhow!("expected a number of threads or \"auto\", but got {:?}", s)
+        })?;
+        anyhow::ensure!(n > 0, "the number of threads must be at least 1");
+        Ok(n)
+    }
+}
+
+/// Parse the name of a SAM tag (two characters, e.g. `CB`).
+fn parse_sam_tag(s: &str) -> anyhow::Result<String> {
+    let b = s.as_bytes();
+    anyhow::ensure!(
+        b.len() == 2 && b[0].is_ascii_alphabetic() && b[1].is_ascii_alphanumeric(),
+        "expected a two-character SAM tag (e.g. CB), but got {:?}",
+        s
+    );
+    Ok(s.to_owned())
+}
+
+/// Parse a fraction, which must lie in [0, 1].
+fn parse_fraction(s: &str) -> anyhow::Result<f32> {
+    let f: f32 = s.parse()?;
+    anyhow::ensure!(
+        (0.0..=1.0).contains(&f),
+        "expected a fraction between 0 and 1, but got {}",
+        s
+    );
+    Ok(f)
+}
+
+/// Parse a non-negative (finite) number.
+fn parse_non_negative(s: &str) -> anyhow::Result<f64> {
+    let x: f64 = s.parse()?;
+    anyhow::ensure!(
+        x >= 0.0 && x.is_finite(),
+        "expected a non-negative number, but got {}",
+        s
+    );
+    Ok(x)
+}
+
+/// Parse a single directive of `--log` (e.g. `oarfish::em=debug`).
+fn parse_log_directive(s: &str) -> anyhow::Result<String> {
+    s.parse::<tracing_subscriber::filter::Directive>()
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "expected a logging directive of the form <target>=<level> (e.g. oarfish::em=debug), but got {:?}: {}",
+                s,
+                e
+            )
+        })?;
+    Ok(s.to_owned())
+}
+
 fn parse_assign_prob_out_value(s: &str) -> anyhow::Result<ReadAssignmentProbOut> {
     match s.to_lowercase().as_str() {
         "raw" => Ok(ReadAssignmentProbOut::Uncompressed),
@@ -43,6 +403,70 @@ fn parse_assign_prob_out_value(s: &str) -> anyhow::Result<ReadAssignmentProbOut>
     }
 }
 
+/// How the identity of an alignment is computed for the purpose
+/// of the `--min-identity` filter.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize)]
+pub enum IdentityType {
+    /// gap-compressed identity (as reported by minimap2 in the `de` tag);
+    /// each gap counts as a single difference, regardless of its length
+    GapCompressed,
+    /// BLAST identity; the number of matching columns divided by the
+    /// total number of alignment columns
+    Blast,
+}
+
+/// How the distances used by the `--five-prime-clip` and `--three-prime-clip`
+/// filters are measured.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize)]
+pub enum ClipMode {
+    /// the number of bases of the read left unaligned (soft- or hard-clipped) at its 5'
+    /// and 3' ends, taking the strand of the alignment into account
+    Read,
+    /// the distance of the alignment from the 5' and 3' ends of the transcript (i.e. the
+    /// truncation of the template)
+    Transcript,
+}
+
+/// How local BAM files are read from disk.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize)]
+pub enum IoBackend {
+    /// ordinary (blocking) reads, with decompression performed by a pool of threads
+    Threaded,
+    /// reads submitted ahead through io_uring, so that several are in flight while the
+    /// data already read is decompressed (Linux only; requires the `io-uring` feature)
+    Uring,
+}
+
+/// The score of an alignment used to filter the alignments of a read (with
+/// `--score-threshold`) and to compute their probabilities; larger is better.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize)]
+pub enum ScoreType {
+    /// the mapping quality (`MAPQ`), plus 1 (so that the alignments of a read whose
+    /// alignments all have a MAPQ of 0 are retained, as equally likely)
+    Mapq,
+    /// the alignment score reported by the aligner (the `AS` tag)
+    As,
+    /// the gap-compressed identity (1 minus the `de` tag of minimap2), in thousandths
+    De,
+    /// the alignment score per 1,000 aligned (reference) bases, so that longer alignments
+    /// are not favoured for their length alone
+    AlnlenNormalizedAs,
+}
+
+/// How the secondary alignments of a read (those passing the other
+/// filters) are incorporated into its set of compatible transcripts.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize)]
+pub enum SecondaryPolicy {
+    /// use every alignment whose score is within `--score-threshold` of the best
+    UseAll,
+    /// use only the alignments attaining the best score of the read (ties are kept)
+    BestScoreOnly,
+    /// use only the `--secondary-top-k` highest-scoring alignments of the read
+    TopK,
+    /// discard secondary alignments, using only the primary alignment of the read
+    Drop,
+}
+
 #[derive(Debug, Clone, clap::ValueEnum, Serialize)]
 pub enum SequencingTech {
     OntCDNA,
@@ -51,6 +475,17 @@ pub enum SequencingTech {
     PacBioHifi,
 }
 
+impl SequencingTech {
+    /// The `minimap2` preset used to map reads of this sequencing technology.
+    pub fn minimap2_preset(&self) -> &'static str {
+        match self {
+            SequencingTech::OntCDNA | SequencingTech::OntDRNA => "map-ont",
+            SequencingTech::PacBio => "map-pb",
+            SequencingTech::PacBioHifi => "map-hifi",
+        }
+    }
+}
+
 impl FromStr for SequencingTech {
     type Err = String;
 
@@ -207,12 +642,12 @@ fn parse_filter_f32(arg: &str) -> anyhow::Result<FilterArg> {
 }
 
 /// accurate transcript quantification from long-read RNA-seq data
-#[derive(Parser, Debug, Serialize)]
+#[derive(Parser, Debug, Clone, Serialize)]
 #[clap(author, version, about, long_about = None)]
 #[command(group(
     clap::ArgGroup::new("input")
     .required(true)
-    .args(["alignments", "reads"])
+    .args(["alignments", "reads", "from_alignment_cache"])
 ))]
 pub struct Args {
     /// be quiet (i.e. don't output log messages that aren't at least warnings)
@@ -223,15 +658,98 @@ pub struct Args {
     #[arg(long)]
     pub verbose: bool,
 
-    /// path to the file containing the input alignments
-    #[arg(short, long, help_heading = "alignment mode")]
-    pub alignments: Option<PathBuf>,
+    /// set the verbosity of individual subsystems with comma-separated `<target>=<level>`
+    /// directives (e.g. `oarfish::em=debug,oarfish::single_cell=info`), which take
+    /// precedence over `--quiet` and `--verbose`
+    #[arg(long, value_delimiter = ',', value_parser = parse_log_directive)]
+    pub log: Vec<String>,
+
+    /// the format of the log messages; with `json`, each message is written as a JSON
+    /// object, and the start and end of the run, the end of each stage, the filter counts
+    /// and the convergence of the EM are logged as structured events
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// path to the file containing the input alignments; this may also be an `s3://` or
+    /// `gs://` URL, in which case the alignments are streamed from object storage. Several
+    /// BAM files (e.g. one per flowcell) may be given, whose alignments (against the same
+    /// reference) are quantified together as a single sample
+    #[arg(short, long, num_args = 1.., help_heading = "alignment mode")]
+    pub alignments: Vec<PathBuf>,
+
+    /// verify that the reference sequences (names and lengths) in the header of the
+    /// alignments are those of this reference transcriptome (FASTA file or minimap2
+    /// index built by oarfish), and exit with an error if they are not; this may also
+    /// be an `s3://` or `gs://` URL
+    #[arg(
+        long,
+        help_heading = "alignment mode",
+        requires = "alignments",
+        conflicts_with = "reads"
+    )]
+    pub verify_reference: Option<PathBuf>,
+
+    /// how to handle reference sequences that differ between the alignments and the
+    /// `--verify-reference` reference
+    #[arg(
+        long,
+        help_heading = "alignment mode",
+        requires = "verify_reference",
+        default_value = "fail"
+    )]
+    pub reference_mismatch: ReferenceMismatchMode,
+
+    /// path to the alignments of a control sample; these are quantified with the same
+    /// settings (writing to `<output>.control`), and the genes whose dominant isoform
+    /// differs between the sample given with `--alignments` and the control are reported
+    #[arg(
+        long,
+        help_heading = "alignment mode",
+        requires_all = ["alignments", "txp_to_gene"],
+        conflicts_with_all = ["single_cell", "no_em"]
+    )]
+    pub control_alignments: Option<PathBuf>,
+
+    /// quantify the reads of each read group (`@RG` record of the BAM header) separately,
+    /// from a single pass over the alignments, writing the output of each to
+    /// `<output>.<read group id>`
+    #[arg(
+        long,
+        help_heading = "alignment mode",
+        requires = "alignments",
+        conflicts_with_all = ["single_cell", "control_alignments"]
+    )]
+    pub split_by_read_group: bool,
+
+    /// in a first pass over the alignments, fit a calibration curve mapping the alignment
+    /// scores (normalized by read length) to the probability that an alignment is correct,
+    /// learned from the uniquely and multiply mapping reads, and compute the alignment
+    /// probabilities from it rather than from the fixed transformation of the scores
+    #[arg(
+        long,
+        help_heading = "alignment mode",
+        requires = "alignments",
+        conflicts_with = "single_cell"
+    )]
+    pub calibrate_scores: bool,
+
+    /// the number of reads (from the start of the alignments) used to fit the calibration
+    /// of the alignment scores
+    #[arg(
+        long,
+        help_heading = "alignment mode",
+        requires = "calibrate_scores",
+        default_value_t = 1_000_000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub calibration_reads: u64,
 
     /// path to the file containing the input reads; these can be
     /// in FASTA/Q format (possibly gzipped), or provided in
-    /// uBAM (unaligned BAM) format. The format will be inferred from
-    /// the file suffixes, and if a format cannot be inferred, it will
-    /// be assumed to be (possibly gzipped) FASTA/Q
+    /// uBAM (unaligned BAM) format. The format of each file will be
+    /// inferred from its contents (or, failing that, its suffix), and
+    /// if a format cannot be inferred, it will be assumed to be
+    /// (possibly gzipped) FASTA/Q
     #[arg(
         long,
         help_heading = "raw read mode",
@@ -243,8 +761,97 @@ pub struct Args {
     )]
     pub reads: Option<Vec<PathBuf>>,
 
+    /// treat each of the files passed to `--reads` as a separate lane of the same
+    /// sample; the lanes are quantified jointly, and per-lane assignment statistics,
+    /// estimates and a lane-concordance metric are reported
+    #[arg(long, help_heading = "raw read mode", requires = "reads")]
+    pub lanes: bool,
+
+    /// write the alignments of the reads (all minimap2 mappings, before filtering, along
+    /// with the unmapped reads) to this BAM file while quantifying
+    #[arg(long, help_heading = "raw read mode", requires = "reads")]
+    pub write_bam: Option<PathBuf>,
+
+    /// re-align the reads whose retained alignments cover less than this fraction of the
+    /// read (including the reads with no retained alignment) with a more sensitive set of
+    /// minimap2 parameters (relaxed seeding and chaining), keeping the alignments of the
+    /// second pass if they fit the read better
+    #[arg(
+        long,
+        help_heading = "raw read mode",
+        requires = "reads",
+        value_parser = parse_fraction
+    )]
+    pub second_chance_fit: Option<f32>,
+
+    /// trim adapter and primer sequences from the ends of the reads before mapping them;
+    /// either `ont-cdna` (the SSP and VNP primers of the ONT cDNA kits) or a FASTA file
+    /// holding the sequences to trim (each is searched for in both orientations)
+    #[arg(long, help_heading = "raw read mode", requires = "reads")]
+    pub trim_adapters: Option<String>,
+
+    /// the number of bases at each end of a read searched for the sequences of
+    /// `--trim-adapters`
+    #[arg(
+        long,
+        help_heading = "raw read mode",
+        requires = "trim_adapters",
+        default_value_t = 150
+    )]
+    pub trim_search_len: usize,
+
+    /// the largest fraction of the bases of an adapter or primer that may differ
+    /// (by substitution, insertion or deletion) in a match of `--trim-adapters`
+    #[arg(
+        long,
+        help_heading = "raw read mode",
+        requires = "trim_adapters",
+        default_value_t = 0.2,
+        value_parser = parse_fraction
+    )]
+    pub trim_max_error_rate: f32,
+
+    /// demultiplex the reads of a run of several barcoded samples, quantifying the reads of
+    /// each sample separately and writing its output to `<output>.<sample>`; either
+    /// `ont-native` (the native barcodes NB01-NB12 of the ONT native barcoding kits) or a
+    /// FASTA file holding the barcode sequences (each is searched for in both orientations)
+    #[arg(
+        long,
+        help_heading = "raw read mode",
+        requires = "reads",
+        conflicts_with = "lanes"
+    )]
+    pub demux_barcodes: Option<String>,
+
+    /// the samples expected among the reads with `--demux-barcodes`, one
+    /// `<barcode>\t<sample>` per line; reads with other barcodes are not quantified. By
+    /// default, each barcode is a sample of the same name
+    #[arg(long, help_heading = "raw read mode", requires = "demux_barcodes")]
+    pub demux_samples: Option<PathBuf>,
+
+    /// the number of bases at each end of a read searched for the barcodes of
+    /// `--demux-barcodes`
+    #[arg(
+        long,
+        help_heading = "raw read mode",
+        requires = "demux_barcodes",
+        default_value_t = 150
+    )]
+    pub demux_search_len: usize,
+
+    /// the largest fraction of the bases of a barcode that may differ (by substitution,
+    /// insertion or deletion) in a match of `--demux-barcodes`
+    #[arg(
+        long,
+        help_heading = "raw read mode",
+        requires = "demux_barcodes",
+        default_value_t = 0.2,
+        value_parser = parse_fraction
+    )]
+    pub demux_max_error_rate: f32,
+
     /// path to the file containing the reference transcriptome (or existing index) against which
-    /// to map
+    /// to map; this may also be an `s3://` or `gs://` URL
     #[arg(long, conflicts_with = "alignments", help_heading = "raw read mode")]
     pub reference: Option<PathBuf>,
 
@@ -252,6 +859,25 @@ pub struct Args {
     #[arg(long, conflicts_with = "alignments", help_heading = "raw read mode")]
     pub index_out: Option<PathBuf>,
 
+    /// fail, rather than warn, if the k-mer size, window size or homopolymer compression
+    /// of a pre-built minimap2 index passed as the `--reference` do not match the preset
+    /// implied by `--seq-tech`
+    #[arg(long, conflicts_with = "alignments", help_heading = "raw read mode")]
+    pub strict_index_check: bool,
+
+    /// add a decoy for each target of the reference to the index, and report the reads
+    /// assigned to the decoys as an empirical estimate of the false-assignment rate (along
+    /// with a suggested `--score-threshold`). This is a diagnostic mode: the decoys are
+    /// quantified (and reported) alongside the real targets
+    #[arg(
+        long,
+        value_enum,
+        help_heading = "raw read mode",
+        requires = "reference",
+        conflicts_with = "index_out"
+    )]
+    pub decoys: Option<DecoyMode>,
+
     /// sequencing technology in which to expect reads if using mapping based mode
     #[arg(
         long,
@@ -285,6 +911,47 @@ pub struct Args {
     #[arg(short, long, required = true)]
     pub output: PathBuf,
 
+    /// the format of the quantification table (written to `<output>.quant.pq` or
+    /// `<output>.quant.arrow`, in addition to `<output>.quant`), of the bootstrap replicates
+    /// and of the read assignment probabilities; parquet and Arrow tables can be loaded
+    /// directly by e.g. polars, pandas or duckdb
+    #[arg(long, value_enum, default_value_t = OutputFormat::Tsv, conflicts_with = "single_cell")]
+    pub output_format: OutputFormat,
+
+    /// compress the quantification table (and `<output>.ambig_info.tsv`), the read
+    /// assignment probabilities, the fusion candidate and ambiguous read reports, and the
+    /// bootstrap replicates; text files get a `.gz` or `.zst` suffix, and the compression
+    /// is done on a background thread. Without this option, text outputs are written
+    /// uncompressed and parquet tables are zstd-compressed.
+    #[arg(long, value_enum, conflicts_with = "single_cell")]
+    pub compress: Option<OutputCompression>,
+
+    /// once quantification is complete, pack all of the output files (along with the log of
+    /// the run) into the single seekable zstd archive `<output>.archive.zst`, removing the
+    /// packed files; use `oarfish unpack` to list or extract its contents
+    #[arg(long)]
+    pub archive: bool,
+
+    /// compute the SHA-256 digests of the input files (the alignments, reads, reference or
+    /// alignment cache) recorded in the provenance of the run; otherwise only their paths
+    /// and sizes are recorded. The inputs are hashed in the background, but the metadata of
+    /// the run is written only once they have been hashed
+    #[arg(long)]
+    pub input_hashes: bool,
+
+    /// record the provenance of the run in a `# oarfish_provenance: {...}` comment line at
+    /// the top of each of its tab-separated and text outputs (e.g. `<output>.quant`,
+    /// `<output>.ambig_info.tsv` and `<output>.summary.txt`), so that it stays with them if
+    /// they are copied on their own; tools reading them must then skip lines starting with `#`
+    #[arg(long)]
+    pub tsv_provenance: bool,
+
+    /// check that the input files exist and can be parsed, that the alignment headers and
+    /// reference digests agree and that the options are coherent, then print the resolved
+    /// configuration as JSON and exit, without aligning or quantifying any reads
+    #[arg(long)]
+    pub validate_only: bool,
+
     #[arg(long, help_heading = "filters", value_enum)]
     pub filter_group: Option<FilterGroup>,
 
@@ -310,6 +977,44 @@ pub struct Args {
     #[arg(short = 'l', long, help_heading = "filters", default_value_t = FilterArg::DefaultU32(50), value_parser = parse_filter_u32)]
     pub min_aligned_len: FilterArg,
 
+    /// minimum identity an alignment must have to be considered valid; the identity
+    /// is computed from the CIGAR string and the `NM` (or `de`) tag of the alignment
+    #[arg(long, help_heading = "filters", default_value_t = FilterArg::DefaultF32(0.0), value_parser = parse_filter_f32)]
+    pub min_identity: FilterArg,
+
+    /// how the alignment identity used by `--min-identity` is computed
+    #[arg(long, help_heading = "filters", value_enum, default_value_t = IdentityType::GapCompressed)]
+    pub identity_type: IdentityType,
+
+    /// how the distances limited by `--five-prime-clip` and `--three-prime-clip` are
+    /// measured: from the ends of the transcript covered by the alignment, or as the
+    /// unaligned (clipped) bases at the ends of the read
+    #[arg(long, help_heading = "filters", value_enum, default_value_t = ClipMode::Transcript)]
+    pub clip_mode: ClipMode,
+
+    /// the alignment score used by `--score-threshold` and to compute the alignment
+    /// probabilities; if the `AS` (or `de`) tag of an alignment is missing, its score is
+    /// recomputed from the CIGAR string and the `NM` tag
+    #[arg(long, help_heading = "filters", value_enum, default_value_t = ScoreType::As)]
+    pub score: ScoreType,
+
+    /// how secondary alignments (from the BAM file, or from minimap2's `--best-n` hits)
+    /// are incorporated into the set of transcripts compatible with each read
+    #[arg(long, help_heading = "filters", value_enum, default_value_t = SecondaryPolicy::UseAll)]
+    pub secondary_policy: SecondaryPolicy,
+
+    /// the number of highest-scoring alignments of each read to retain under
+    /// `--secondary-policy top-k`
+    #[arg(long, help_heading = "filters", default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    pub secondary_top_k: u32,
+
+    /// do not weight the alignment probabilities of each read by its predicted accuracy
+    /// (its `rq` tag, as written by PacBio instruments, or otherwise the accuracy implied
+    /// by its base qualities); by default, the alignments of accurate reads are told apart
+    /// more confidently than those of error-prone reads
+    #[arg(long, help_heading = "filters")]
+    pub no_read_quality_weighting: bool,
+
     /// only alignments to this strand will be allowed; options are (fw /+, rc/-, or both/.)
     #[arg(
         short = 'd',
@@ -320,10 +1025,245 @@ pub struct Args {
     )]
     pub strand_filter: bio_types::strand::Strand,
 
+    /// examine supplementary alignments to identify chimeric reads; reads whose supplementary
+    /// alignments fall on a different transcript than their primary alignment are reported as
+    /// fusion candidates, and the portion of the read covered by supplementary alignments to
+    /// the transcript of its primary alignment counts towards its aligned fraction
+    #[arg(long, help_heading = "filters", conflicts_with = "single_cell")]
+    pub rescue_supplementary: bool,
+
+    /// a file listing (one per line) the transcripts to which quantification should be
+    /// restricted; alignments to all other transcripts are discarded
+    #[arg(long, help_heading = "filters")]
+    pub keep_transcripts: Option<PathBuf>,
+
+    /// a file listing (one per line) transcripts that should be excluded from quantification;
+    /// alignments to these transcripts are discarded
+    #[arg(long, help_heading = "filters")]
+    pub exclude_transcripts: Option<PathBuf>,
+
+    /// a (native) shared library implementing the oarfish read filter plugin interface, which
+    /// is called on the alignments of each read (after the built-in filters) and may discard
+    /// any of them; WebAssembly modules are not supported
+    #[arg(long, help_heading = "filters", conflicts_with = "single_cell")]
+    pub read_filter_plugin: Option<PathBuf>,
+
+    /// a configuration string passed to the read filter plugin when it is initialized
+    #[arg(long, help_heading = "filters", requires = "read_filter_plugin")]
+    pub read_filter_config: Option<String>,
+
+    /// flag the alignments whose 3' ends lie just upstream of an A-rich stretch of their
+    /// transcript as likely internal-priming artifacts (reads primed by oligo(dT) within the
+    /// transcript rather than at its poly(A) tail), writing the per-transcript artifact rates
+    /// to `<output>.internal_priming.tsv`; the flagged alignments are reported (`flag`),
+    /// down-weighted (`downweight`) or discarded (`exclude`)
+    #[arg(
+        long,
+        value_enum,
+        help_heading = "filters",
+        conflicts_with = "single_cell"
+    )]
+    pub internal_priming: Option<InternalPrimingMode>,
+
+    /// a FASTA file holding the sequences of the transcripts, in which the A-rich stretches
+    /// are sought; required with `--alignments`, and otherwise defaults to the `--reference`
+    /// (if it is a local FASTA file)
+    #[arg(long, help_heading = "filters", requires = "internal_priming")]
+    pub internal_priming_reference: Option<PathBuf>,
+
+    /// the length of the window following the 3' end of an alignment that is inspected
+    #[arg(
+        long,
+        help_heading = "filters",
+        requires = "internal_priming",
+        default_value_t = 20,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub internal_priming_window: u32,
+
+    /// the smallest fraction of the bases of the window that must be A's for the window to
+    /// be A-rich
+    #[arg(
+        long,
+        help_heading = "filters",
+        requires = "internal_priming",
+        default_value_t = 0.7,
+        value_parser = parse_fraction
+    )]
+    pub internal_priming_min_a_frac: f32,
+
+    /// the factor by which the alignment probabilities of the flagged alignments are
+    /// multiplied with `--internal-priming downweight`
+    #[arg(
+        long,
+        help_heading = "filters",
+        requires = "internal_priming",
+        default_value_t = 0.1,
+        value_parser = parse_fraction
+    )]
+    pub internal_priming_weight: f32,
+
+    /// identify likely duplicate reads (e.g. re-reads of the same molecule in direct RNA
+    /// sequencing); reads whose best alignment is to the same transcript and strand, with a
+    /// nearly identical 3' end and aligned length (and from the same channel, if a
+    /// `--sequencing-summary` is provided) are reported, but still counted
+    #[arg(long, help_heading = "filters", conflicts_with = "single_cell")]
+    pub detect_duplicates: bool,
+
+    /// as `--detect-duplicates`, but also remove all but one read of each set of
+    /// duplicates prior to quantification
+    #[arg(long, help_heading = "filters", conflicts_with = "single_cell")]
+    pub collapse_duplicates: bool,
+
+    /// an ONT sequencing summary file (with `read_id` and `channel` columns); when provided,
+    /// only reads sequenced on the same channel are considered duplicates of each other
+    #[arg(long, help_heading = "filters", conflicts_with = "single_cell")]
+    pub sequencing_summary: Option<PathBuf>,
+
+    /// the maximum distance (in bp) between the 3' ends of the alignments of two reads
+    /// considered to be duplicates
+    #[arg(long, help_heading = "filters", default_value_t = 10)]
+    pub dup_end_tolerance: u32,
+
+    /// the maximum relative difference between the aligned lengths of two reads
+    /// considered to be duplicates
+    #[arg(long, help_heading = "filters", default_value_t = 0.05)]
+    pub dup_length_tolerance: f64,
+
     /// input is assumed to be a single-cell BAM and to have the `CB:z` tag for all read records
     #[arg(long, conflicts_with = "reads")]
     pub single_cell: bool,
 
+    /// produce spliced, unspliced and ambiguous (USA-mode) gene counts for each cell, as
+    /// used by RNA velocity workflows. The alignments must be to a reference containing
+    /// both the spliced and the unspliced (intron-containing) targets of each gene, and
+    /// this is its 3-column (`<target>\t<gene>\t<S|U>`) transcript-to-gene file
+    #[arg(long, requires = "single_cell")]
+    pub usa_t2g: Option<PathBuf>,
+
+    /// the comma-separated tags holding the cell barcode of each record, in order of
+    /// preference; the barcode is taken from the first of these tags that the record has
+    /// (e.g. `CB,CR` falls back to the uncorrected barcode when there is no corrected one)
+    #[arg(
+        long,
+        requires = "single_cell",
+        value_delimiter = ',',
+        default_value = "CB",
+        value_parser = parse_sam_tag
+    )]
+    pub bc_tag: Vec<String>,
+
+    /// the comma-separated tags holding the UMI of each record, in order of preference
+    #[arg(
+        long,
+        requires = "single_cell",
+        value_delimiter = ',',
+        default_value = "UB",
+        value_parser = parse_sam_tag
+    )]
+    pub umi_tag: Vec<String>,
+
+    /// take the cell barcode and UMI of each record from its read name, of the form
+    /// `<read><sep><barcode>[<sep><umi>]` (with the separator given by `--read-name-sep`),
+    /// rather than from its tags
+    #[arg(long, requires = "single_cell", conflicts_with_all = ["bc_tag", "umi_tag"])]
+    pub bc_from_read_name: bool,
+
+    /// the separator of the fields of read names holding the cell barcode and UMI
+    #[arg(long, requires = "bc_from_read_name", default_value_t = '_')]
+    pub read_name_sep: char,
+
+    /// the raw reads (FASTA/Q or unaligned BAM) of a hashtag-multiplexed single-cell
+    /// experiment, with the cell barcode of each read in a `CB:Z` tag (or, for FASTA/Q,
+    /// in a `CB:Z:<barcode>` field of the header comment); the hashtag oligos found in these
+    /// reads are counted for each barcode
+    #[arg(long, requires_all = ["single_cell", "hto_list"])]
+    pub hto_reads: Option<PathBuf>,
+
+    /// the hashtag oligos to count with `--hto-reads`, one `<name>\t<sequence>` per line
+    #[arg(long, requires = "hto_reads")]
+    pub hto_list: Option<PathBuf>,
+
+    /// the spot coordinates of a spatial (e.g. Visium) chemistry, for quantifying each spot
+    /// of a long-read spatial transcriptomics experiment; either a Space Ranger
+    /// `tissue_positions` CSV file, or a `<barcode>\t<x>\t<y>` TSV file. The coordinates of
+    /// each quantified barcode are written to `<output>.spots.tsv`
+    #[arg(long, requires = "single_cell")]
+    pub spot_coordinates: Option<PathBuf>,
+
+    /// a file listing the mitochondrial transcripts (one per line), used to compute the
+    /// fraction of the reads of each cell that are mitochondrial
+    #[arg(long, requires = "single_cell")]
+    pub mito_txps: Option<PathBuf>,
+
+    /// the maximum memory (e.g. `8GB`) taken up by the quantified cells awaiting output; the
+    /// cells are written in the order of the input as they are quantified, and those
+    /// quantified ahead of their turn are spilled to disk once they exceed this limit
+    #[arg(
+        long,
+        requires = "single_cell",
+        default_value = "2GB",
+        value_parser = |s: &str| parse_size(s)
+    )]
+    pub max_sc_mem: u64,
+
+    /// assume that the single-cell alignments are already collated by cell barcode (`CB`
+    /// tag), rather than sorting them by barcode before quantification
+    #[arg(long, requires = "single_cell")]
+    pub assume_collated: bool,
+
+    /// the maximum memory (e.g. `4GB`) taken up by the alignment records held in memory
+    /// while sorting them by cell barcode; beyond this, sorted runs are written to disk
+    #[arg(
+        long,
+        requires = "single_cell",
+        conflicts_with = "assume_collated",
+        default_value = "4GB",
+        value_parser = |s: &str| parse_size(s)
+    )]
+    pub sc_sort_mem: u64,
+
+    /// resume a single-cell run with the same output prefix that failed partway through,
+    /// keeping the output of the cells recorded in its checkpoint and quantifying only
+    /// the remaining cells
+    #[arg(long, requires = "single_cell")]
+    pub resume: bool,
+
+    /// additionally write the counts to the loom file `<output>.loom` (features x cells, with
+    /// `Gene` row and `CellID` column attributes, and, in USA mode, `spliced`, `unspliced`
+    /// and `ambiguous` layers); requires oarfish to be built with the `loom` feature
+    #[arg(long, requires = "single_cell")]
+    pub loom: bool,
+
+    /// skip the per-cell EM and instead count the reads of each cell in each
+    /// transcript-compatibility class (the set of transcripts to which a read aligns), as
+    /// the TCCs of kallisto; the columns of `<output>.count.mtx` are then the classes,
+    /// which are listed in `<output>.ec.txt`
+    #[arg(long, requires = "single_cell", conflicts_with_all = ["usa_t2g", "resume", "loom"])]
+    pub tcc: bool,
+
+    /// the comma-separated prefixes of the names of the transcripts of the two species of a
+    /// mixed-species (barnyard) experiment (e.g. `GRCh38_,mm10_`); each cell is called as one
+    /// species, or as a cross-species doublet, and a count matrix is written for each species
+    #[arg(
+        long,
+        requires = "single_cell",
+        conflicts_with_all = ["usa_t2g", "tcc"],
+        value_delimiter = ','
+    )]
+    pub barnyard: Option<Vec<String>>,
+
+    /// the fraction of the counts of a cell that must come from the transcripts of one
+    /// species for the cell to be called as that species with `--barnyard`; other cells
+    /// are called as mixed
+    #[arg(
+        long,
+        requires = "barnyard",
+        default_value_t = 0.9,
+        value_parser = parse_fraction
+    )]
+    pub barnyard_min_frac: f32,
+
     /// apply the coverage model
     #[arg(long, help_heading = "coverage model", value_parser)]
     pub model_coverage: bool,
@@ -338,6 +1278,21 @@ pub struct Args {
     )]
     pub growth_rate: f64,
 
+    /// the coverage model to apply (implies `--model-coverage`); defaults to `logistic`
+    /// in bulk mode and `binomial` in single-cell mode
+    #[arg(long, help_heading = "coverage model", value_enum)]
+    pub coverage_model: Option<CoverageModel>,
+
+    /// additionally estimate the abundances without the coverage model (sharing all alignment
+    /// processing), and report the per-transcript differences to `<output>.coverage_comparison.tsv`.
+    /// This implies `--model-coverage`, which is used for the main estimates.
+    #[arg(
+        long,
+        help_heading = "coverage model",
+        conflicts_with_all = ["single_cell", "use_kde"]
+    )]
+    pub compare_coverage_model: bool,
+
     /// write output alignment probabilites (optionally compressed) for each mapped read.
     /// If <WRITE_ASSIGNMENT_PROBS> is present, it must be one of `uncompressed` (default) or
     /// `compressed`, which will cause the output file to be lz4 compressed.
@@ -352,32 +1307,467 @@ pub struct Args {
     )]
     pub write_assignment_probs: Option<ReadAssignmentProbOut>,
 
+    /// split the read assignment probabilities across this many output files
+    /// (`<output>.prob.<i>`), each written by its own thread; reads are assigned to
+    /// shards in contiguous blocks, and each shard has its own header.
+    #[arg(
+        long,
+        help_heading = "output read-txps probabilities",
+        default_value_t = 1,
+        requires = "write_assignment_probs",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub assignment_probs_shards: u32,
+
+    /// write the equivalence classes of the reads (with the conditional probabilities
+    /// used by the EM) to `<output>.eqc.tsv.zst`, so that technical replicates can later
+    /// be quantified jointly with `oarfish merge`
+    #[arg(long, help_heading = "EM", conflicts_with_all = ["single_cell", "no_em"])]
+    pub write_eqclasses: bool,
+
+    /// skip the EM entirely; each transcript is assigned only the reads that align
+    /// uniquely to it, and the ambiguous (multi-mapping) reads are written, along
+    /// with their compatible transcripts, to `<output>.ambiguous_reads.tsv`
+    #[arg(
+        long,
+        help_heading = "EM",
+        conflicts_with_all = [
+            "single_cell",
+            "short_quant",
+            "num_bootstraps",
+            "write_assignment_probs",
+            "read_length_strata",
+            "adaptive_sampling",
+            "lanes",
+            "compare_coverage_model"
+        ]
+    )]
+    pub no_em: bool,
+
     /// maximum number of iterations for which to run the EM algorithm
-    #[arg(long, help_heading = "EM", default_value_t = 1000)]
+    #[arg(
+        long,
+        help_heading = "EM",
+        visible_alias = "max-iters",
+        default_value_t = 1000
+    )]
     pub max_em_iter: u32,
 
+    /// minimum number of iterations for which to run the EM algorithm, even if the
+    /// estimates have converged
+    #[arg(
+        long,
+        help_heading = "EM",
+        visible_alias = "min-iters",
+        default_value_t = 50
+    )]
+    pub min_em_iter: u32,
+
     /// maximum number of iterations for which to run the EM algorithm
-    #[arg(long, help_heading = "EM", default_value_t = 1e-3)]
+    #[arg(
+        long,
+        help_heading = "EM",
+        visible_alias = "convergence-tol",
+        default_value_t = 1e-3
+    )]
     pub convergence_thresh: f64,
 
+    /// write the log-likelihood, the largest relative change of an estimate and the number
+    /// of transcripts with a nonzero estimate after each iteration of the EM to
+    /// `<output>.em_trace.tsv`
+    #[arg(long, help_heading = "EM", conflicts_with_all = ["single_cell", "no_em"])]
+    pub em_trace: bool,
+
+    /// after the EM, set the estimates of the transcripts with fewer than this many reads
+    /// to 0, and redistribute their reads over the other transcripts they align to with a
+    /// short EM started from the remaining estimates
+    #[arg(long, help_heading = "EM", conflicts_with_all = ["single_cell", "no_em"])]
+    pub min_reads_per_txp: Option<f64>,
+
+    /// report the posterior mean of the counts under a symmetric Dirichlet prior of
+    /// `--prior-weight` reads per transcript, rather than the maximum likelihood estimates
+    /// of the EM; this shrinks the estimates of lowly expressed transcripts, and applies to
+    /// the bootstrap replicates as well
+    #[arg(
+        long,
+        help_heading = "EM",
+        conflicts_with_all = ["single_cell", "no_em", "min_reads_per_txp"]
+    )]
+    pub posterior_mean: bool,
+
+    /// the weight (in reads per transcript) of the symmetric Dirichlet prior of
+    /// `--posterior-mean`
+    #[arg(
+        long,
+        help_heading = "EM",
+        requires = "posterior_mean",
+        default_value_t = 1.0,
+        value_parser = parse_non_negative
+    )]
+    pub prior_weight: f64,
+
+    /// run the EM such that the estimates are bit-identical regardless of the number
+    /// of threads used (the contributions of the reads to each transcript are summed in
+    /// a fixed order), at a small cost in speed
+    #[arg(long, help_heading = "EM", conflicts_with = "single_cell")]
+    pub deterministic: bool,
+
+    /// run the E-step of the EM on a GPU (in single precision), if one is found, and on
+    /// the CPU otherwise; the bootstrap replicates are computed on the CPU. Requires oarfish
+    /// to be built with the `gpu` feature
+    #[arg(long, help_heading = "EM", conflicts_with = "single_cell")]
+    pub gpu: bool,
+
+    /// the seed of every pseudo-random choice of the run: the seeding of minimap2, the
+    /// resampling of the bootstrap replicates and the subsampling of the reads. In bulk
+    /// mode, this implies `--deterministic`, so that two runs with the same seed and inputs
+    /// produce identical outputs, whatever the number of threads
+    #[arg(long)]
+    pub seed: Option<u64>,
+
     /// number of cores that oarfish will use during different phases
-    /// of quantification. Note: This value will be at least 2 for bulk
-    /// quantification and at least 3 for single-cell quantification due to
-    /// the use of dedicated parsing threads.
-    #[arg(short = 'j', long, default_value_t = 3)]
+    /// of quantification, or `auto` to use all available cores. Note: This value will be
+    /// at least 2 for bulk quantification and at least 3 for single-cell quantification due
+    /// to the use of dedicated parsing threads. The threads are divided between decompression,
+    /// mapping and quantification, and the threads of each of these stages are throttled at
+    /// runtime when they are not the bottleneck.
+    #[arg(short = 'j', long, default_value = "3", value_parser = parse_threads)]
     pub threads: usize,
 
+    /// hold the alignments of each read in a compact representation (with delta-encoded
+    /// transcript ids and quantized probabilities), substantially reducing peak memory
+    /// for deep samples at the cost of somewhat slower processing
+    #[arg(long, conflicts_with = "single_cell")]
+    pub low_mem: bool,
+
+    /// a soft limit (e.g. `32G`) on the memory allocated in bulk mode; once it is exceeded
+    /// while the alignments are read, they are switched to the compact representation of
+    /// `--low-mem` (so the estimates can differ slightly from those of a run without a
+    /// limit), and, if that is not enough, moved in blocks to (memory-mapped) files in
+    /// the temporary directory, rather than the process running out of memory
+    #[arg(long, conflicts_with = "single_cell", value_parser = |s: &str| parse_size(s))]
+    pub max_mem: Option<u64>,
+
+    /// how local BAM files are read; `uring` keeps several reads in flight through io_uring,
+    /// which can help when parsing is bound by the reads from (e.g. NVMe) storage
+    #[arg(long, value_enum, default_value_t = IoBackend::Threaded)]
+    pub io_backend: IoBackend,
+
+    /// once the alignments (or reads) have been parsed and filtered, save the alignments of
+    /// each read, along with the statistics gathered while parsing them, to this file, so
+    /// that later runs (e.g. with other EM or bootstrap settings) can skip the parsing by
+    /// starting from it with `--from-alignment-cache`
+    #[arg(
+        long,
+        conflicts_with_all = ["single_cell", "split_by_read_group", "demux_barcodes"]
+    )]
+    pub save_alignment_cache: Option<PathBuf>,
+
+    /// quantify the alignments saved to this file by `--save-alignment-cache`, rather than
+    /// parsing alignments or mapping reads; the alignments were filtered when the cache was
+    /// written, so the alignment filters of this run are not applied to them
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "single_cell",
+            "split_by_read_group",
+            "calibrate_scores",
+            "save_alignment_cache"
+        ]
+    )]
+    pub from_alignment_cache: Option<PathBuf>,
+
+    /// stop after the first N reads of the input (e.g. for a quick check of the filters
+    /// and the mapping rate before a full run)
+    #[arg(
+        long,
+        help_heading = "subsampling",
+        conflicts_with = "single_cell",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub first_n_reads: Option<u64>,
+
+    /// quantify only this (pseudo-random) fraction of the reads of the input; whether a
+    /// read is kept depends only on its name and the `--subsample-seed`
+    #[arg(
+        long,
+        help_heading = "subsampling",
+        conflicts_with = "single_cell",
+        value_parser = parse_fraction
+    )]
+    pub subsample_fraction: Option<f32>,
+
+    /// the seed that determines which reads are kept with `--subsample-fraction`; defaults
+    /// to the `--seed` of the run, or 0
+    #[arg(long, help_heading = "subsampling", requires = "subsample_fraction")]
+    pub subsample_seed: Option<u64>,
+
     /// location of short read quantification (if provided)
     #[arg(short = 'q', long, help_heading = "EM")]
     pub short_quant: Option<String>,
 
+    /// a tab-separated file with lines of the form `<transcript>\t<group>`; transcripts
+    /// in the same group are collapsed into a single feature prior to running the EM, and
+    /// estimates are reported for the groups
+    #[arg(long, help_heading = "EM", conflicts_with_all = ["single_cell", "short_quant"])]
+    pub collapse_rules: Option<PathBuf>,
+
+    /// collapse transcripts whose names differ only in their version suffix (e.g.
+    /// `ENST00000335137.3` and `ENST00000335137.4`) into a single feature prior to running the EM;
+    /// rules given in `--collapse-rules` take precedence
+    #[arg(long, help_heading = "EM", conflicts_with_all = ["single_cell", "short_quant"])]
+    pub collapse_versions: bool,
+
+    /// a tab-separated file with lines of the form `<transcript>\t<weight>`, giving a
+    /// (non-negative) prior weight for each listed transcript that is multiplied into the
+    /// likelihood of the alignments to it; unlisted transcripts have weight 1, and
+    /// a weight of 0 softly excludes a transcript
+    #[arg(long, help_heading = "EM", conflicts_with_all = ["single_cell", "collapse_rules", "collapse_versions"])]
+    pub txp_weights: Option<PathBuf>,
+
     /// number of bootstrap replicates to produce to assess quantification uncertainty
     #[arg(long, default_value_t = 0)]
     pub num_bootstraps: u32,
 
-    /// width of the bins used in the coverage model
-    #[arg(short, long, help_heading = "coverage model", default_value_t = 100)]
-    pub bin_width: u32,
+    /// the strata within which reads are resampled when computing bootstrap replicates
+    #[arg(long, value_enum, default_value_t = BootstrapStrata::None, conflicts_with = "single_cell")]
+    pub bootstrap_strata: BootstrapStrata,
+
+    /// the number of strata (of nearly equal size, by the quantiles of the read lengths) in
+    /// which the reads are resampled with `--bootstrap-strata read-length`
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    pub bootstrap_length_strata: u32,
+
+    /// resample the reads in blocks of this many consecutive reads (of the input, or of their
+    /// stratum), rather than one at a time, so that the replicates reflect the correlation
+    /// between neighbouring reads (e.g. of reads sequenced at the same time)
+    #[arg(
+        long,
+        default_value_t = 1,
+        conflicts_with = "single_cell",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub bootstrap_block_size: u32,
+
+    /// group the transcripts that cannot be told apart given the reads (as in terminus),
+    /// writing the groups to `<output>.groups.tsv`; transcripts sharing reads are merged
+    /// as long as this reduces the inferential relative variance (InfRV) of their bootstrap
+    /// replicates by at least `--group-min-infrv-reduction`. Unless `--num-bootstraps` is
+    /// given, 100 bootstrap replicates are computed
+    #[arg(long, conflicts_with_all = ["single_cell", "no_em"])]
+    pub indistinguishable_groups: bool,
+
+    /// the smallest relative reduction of the InfRV (with respect to the mean InfRV of the
+    /// two groups) for which two groups of transcripts are merged
+    #[arg(
+        long,
+        requires = "indistinguishable_groups",
+        default_value_t = 0.5,
+        value_parser = parse_fraction
+    )]
+    pub group_min_infrv_reduction: f32,
+
+    /// also report the abundance of each group of `--indistinguishable-groups` (and of each
+    /// ungrouped transcript), with the uncertainty of their summed bootstrap replicates, in
+    /// `<output>.group_quant.tsv` and `<output>.groups.infreps.<ext>`
+    #[arg(long, requires = "indistinguishable_groups")]
+    pub group_quant: bool,
+
+    /// the probability mass of the credible intervals of the gene-level abundances, computed
+    /// from the bootstrap replicates summed per gene, reported in `<output>.gene_quant.tsv`
+    /// when `--txp-to-gene` is given
+    #[arg(long, default_value_t = 0.95, value_parser = parse_fraction)]
+    pub credible_interval: f32,
+
+    /// quantify the haplotypes of a personalized (diploid) transcriptome, in which the two
+    /// haplotypes of a transcript are named with the `--haplotype-suffixes` (e.g. as written
+    /// by `oarfish haplotypes`), and report the allelic ratio of each transcript, with its
+    /// uncertainty, in `<output>.allelic.tsv`
+    #[arg(
+        long,
+        conflicts_with_all = ["single_cell", "no_em", "collapse_rules", "collapse_versions"]
+    )]
+    pub allele_specific: bool,
+
+    /// the (comma-separated) suffixes naming the two haplotypes of each transcript
+    #[arg(
+        long,
+        requires = "allele_specific",
+        value_delimiter = ',',
+        default_value = "_h1,_h2"
+    )]
+    pub haplotype_suffixes: Vec<String>,
+
+    /// the number of reads added to each haplotype (as a symmetric Beta prior) when the reads
+    /// of a transcript are split between its haplotypes, which keeps the split of transcripts
+    /// with few allele-informative reads near 1:1
+    #[arg(long, requires = "allele_specific", default_value_t = 1.0)]
+    pub allelic_pseudocount: f64,
+
+    /// quantify reads separately within the read-length strata delimited by these
+    /// (comma-separated) lengths, and report how the estimates shift across strata.
+    /// For example, `1000,3000` yields the strata [0, 1000), [1000, 3000) and [3000, ∞).
+    #[arg(
+        long,
+        help_heading = "diagnostics",
+        value_delimiter = ',',
+        conflicts_with = "single_cell"
+    )]
+    pub read_length_strata: Option<Vec<u32>>,
+
+    /// report the distributions of the lengths of the aligned reads and of their alignments
+    /// in `<output>.length_dist.tsv`, and the effective length (and TPM) of each transcript
+    /// derived from the aligned-length distribution in `<output>.eff_lens.tsv`
+    #[arg(long, help_heading = "diagnostics", conflicts_with = "single_cell")]
+    pub length_dist: bool,
+
+    /// compute the effective lengths from the precomputed length distribution in this
+    /// (tab-separated, `<length>\t<weight>`) file rather than from the observed alignments,
+    /// e.g. for protocols whose reads are truncated relative to the molecules they derive from
+    #[arg(long, help_heading = "diagnostics", requires = "length_dist")]
+    pub eff_len_dist: Option<PathBuf>,
+
+    /// record the time spent (by each thread) waiting on decompression, parsing, alignment,
+    /// computing the alignment probabilities, in the EM, and blocked on the queues between
+    /// these stages, and write it to `<output>.profile.folded` (in the "folded stacks"
+    /// format of flamegraph tools), to guide the choice of `--threads`
+    #[arg(long, help_heading = "diagnostics")]
+    pub profile: bool,
+
+    /// a tab-separated file mapping each transcript to its gene (`<transcript>\t<gene>`),
+    /// used to screen for isoform switches against `--control-alignments` or, in single-cell
+    /// mode, to count the genes detected in each cell and to write a gene-level count matrix
+    /// alongside the transcript-level one. In bulk mode, the estimates (and any bootstrap
+    /// replicates) are also aggregated to the genes, in `<output>.gene_quant.tsv`
+    #[arg(long, help_heading = "diagnostics")]
+    pub txp_to_gene: Option<PathBuf>,
+
+    /// the minimum number of reads a gene must have in both samples to be screened for
+    /// an isoform switch
+    #[arg(long, help_heading = "diagnostics", default_value_t = 10.0)]
+    pub switch_min_reads: f64,
+
+    /// the minimum fraction of bootstrap replicates supporting an isoform switch for it
+    /// to be flagged as confident
+    #[arg(long, help_heading = "diagnostics", default_value_t = 0.95)]
+    pub switch_min_support: f64,
+
+    /// an ONT adaptive sampling decision file (the comma-separated file, with `read_id` and
+    /// `decision` columns, written by MinKNOW); reads are quantified separately by decision
+    /// (accept, reject, no decision), and estimates corrected for the lower alignment rate of
+    /// rejected reads are reported
+    #[arg(long, help_heading = "diagnostics", conflicts_with = "single_cell")]
+    pub adaptive_sampling: Option<PathBuf>,
+
+    /// a tab-separated file with lines of the form `<transcript>\t<biotype>`; the estimated
+    /// counts and TPMs are aggregated by biotype and reported in `<output>.biotypes.tsv` and
+    /// the run summary
+    #[arg(
+        long,
+        conflicts_with_all = ["single_cell", "collapse_rules", "collapse_versions"]
+    )]
+    pub biotypes: Option<PathBuf>,
+
+    /// also write the estimates of the transcripts of each biotype to a separate file,
+    /// `<output>.<biotype>.quant`
+    #[arg(long, requires = "biotypes")]
+    pub split_by_biotype: bool,
+
+    /// the contaminant transcripts of the reference (e.g. rRNA, mitochondrial or spike-in
+    /// sequences), either as a tab-separated file with lines of the form
+    /// `<transcript>\t<category>` or as a FASTA file whose headers are of the form
+    /// `><transcript> <category>`; the reads assigned to each category are reported in the
+    /// run summary and the estimates of these transcripts in `<output>.contaminants.quant`
+    #[arg(
+        long,
+        conflicts_with_all = ["single_cell", "collapse_rules", "collapse_versions"]
+    )]
+    pub contaminants: Option<PathBuf>,
+
+    /// exclude the contaminant transcripts from the main output (`<output>.quant`); they are
+    /// still quantified, and the total assigned reads (including those of the contaminants)
+    /// are reported in the meta info for normalization
+    #[arg(long, requires = "contaminants", conflicts_with = "control_alignments")]
+    pub exclude_contaminants: bool,
+
+    /// the comma-separated name prefixes of the spike-in transcripts of the reference (e.g.
+    /// `SIRV,ERCC`); the reads of the spike-ins are reported relative to those of the
+    /// endogenous transcripts, and their estimates written to `<output>.spike_ins.quant`
+    #[arg(
+        long,
+        value_delimiter = ',',
+        conflicts_with_all = ["single_cell", "collapse_rules", "collapse_versions"]
+    )]
+    pub spike_in_prefix: Vec<String>,
+
+    /// a tab-separated file with lines of the form `<spike-in>\t<concentration>`, giving the
+    /// expected concentration of each spike-in, to which their estimates are compared
+    #[arg(long, requires = "spike_in_prefix")]
+    pub spike_in_concentrations: Option<PathBuf>,
+
+    /// a tab-separated file with lines of the form `<sequence>\t<lineage>`, where the lineage
+    /// is a `;`-separated list of taxa from the highest to the lowest rank (e.g.
+    /// `d__Bacteria;p__Bacillota;...;s__Escherichia coli`); the estimates are aggregated
+    /// by taxon at each of the `--tax-ranks` and reported in `<output>.taxa.tsv`
+    #[arg(
+        long,
+        help_heading = "metatranscriptomics",
+        conflicts_with_all = ["single_cell", "collapse_rules", "collapse_versions"]
+    )]
+    pub taxonomy: Option<PathBuf>,
+
+    /// the comma-separated taxonomic ranks at which the estimates are aggregated
+    #[arg(
+        long,
+        help_heading = "metatranscriptomics",
+        value_delimiter = ',',
+        requires = "taxonomy",
+        default_value = "species,genus,family"
+    )]
+    pub tax_ranks: Vec<TaxRank>,
+
+    /// width of the bins used in the coverage model on every transcript; if this is not
+    /// given, the width of the bins of each transcript is proportional to its length
+    /// (see `--bins-per-txp`)
+    #[arg(
+        short,
+        long,
+        help_heading = "coverage model",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub bin_width: Option<u32>,
+
+    /// the number of coverage bins into which each transcript is divided, unless
+    /// `--bin-width` is given; the width of the bins is kept within
+    /// `[--min-bin-width, --max-bin-width]`
+    #[arg(
+        long,
+        help_heading = "coverage model",
+        conflicts_with = "bin_width",
+        default_value_t = 20,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub bins_per_txp: u32,
+
+    /// the smallest width of the coverage bins of a transcript
+    #[arg(
+        long,
+        help_heading = "coverage model",
+        conflicts_with = "bin_width",
+        default_value_t = 50,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub min_bin_width: u32,
+
+    /// the largest width of the coverage bins of a transcript
+    #[arg(
+        long,
+        help_heading = "coverage model",
+        conflicts_with = "bin_width",
+        default_value_t = 500,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_bin_width: u32,
 
     /// Number of alignment records to check for name collation when attempting
     /// to validate that the input BAM is name collated.
@@ -387,4 +1777,301 @@ pub struct Args {
     /// use a KDE model of the observed fragment length distribution
     #[arg(short, long, hide = true)]
     pub use_kde: bool,
+
+    /// the bandwidth of the (Gaussian) kernel of the KDE model; either a bandwidth in
+    /// bases or `silverman` (Silverman's rule of thumb)
+    #[arg(
+        long,
+        hide = true,
+        requires = "use_kde",
+        default_value = "50",
+        value_parser = parse_kde_bandwidth
+    )]
+    pub kde_bandwidth: KdeBandwidth,
+
+    /// the width (in bases) of the bins of the grid on which the KDE model is computed
+    #[arg(
+        long,
+        hide = true,
+        requires = "use_kde",
+        default_value_t = 25,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub kde_bin_width: u32,
+
+    /// write the fitted KDE model to this file, so that it can be reused (with
+    /// `--kde-model`) for other samples from the same library prep
+    #[arg(long, hide = true, requires = "use_kde")]
+    pub kde_model_out: Option<PathBuf>,
+
+    /// use the KDE model saved (with `--kde-model-out`) in this file, rather than
+    /// fitting one to this sample
+    #[arg(
+        long,
+        hide = true,
+        requires = "use_kde",
+        conflicts_with_all = ["kde_bandwidth", "kde_bin_width"]
+    )]
+    pub kde_model: Option<PathBuf>,
+
+    /// where the estimates of the run are handed back in memory, when oarfish is embedded
+    /// (e.g. in the Python bindings) rather than run from the command line
+    #[arg(skip)]
+    #[serde(skip)]
+    pub results: Option<Arc<QuantResults>>,
+}
+
+impl Args {
+    /// Parse `argv` as the arguments of a run whose reads are not given on the command
+    /// line (e.g. when only building an index, or when embedding oarfish), so that
+    /// neither `--alignments` nor `--reads` is required.
+    pub fn try_parse_without_input<I, T>(argv: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = <Self as clap::CommandFactory>::command()
+            .mut_group("input", |g| g.required(false))
+            .try_get_matches_from(argv)?;
+        <Self as clap::FromArgMatches>::from_arg_matches(&matches)
+    }
+}
+
+/// auxiliary tools that operate on existing oarfish output
+#[derive(Parser, Debug)]
+#[clap(author, version, about = "auxiliary tools that operate on existing oarfish output", long_about = None)]
+pub struct ToolArgs {
+    #[command(subcommand)]
+    pub command: Tool,
+}
+
+/// The auxiliary tools. These are invoked as `oarfish <tool> ...`, and are
+/// dispatched before the main (quantification) arguments are parsed.
+#[derive(clap::Subcommand, Debug)]
+pub enum Tool {
+    /// upgrade existing oarfish outputs (and the footers of minimap2 indices built by
+    /// oarfish) to the current output schema
+    Migrate {
+        /// output prefixes (i.e. what was passed as `--output`), directories containing
+        /// oarfish outputs, or minimap2 indices built by oarfish, to upgrade
+        #[arg(required = true)]
+        outputs: Vec<PathBuf>,
+        /// report what would be upgraded without modifying any files
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// merge the outputs of several single-cell runs into a single count matrix,
+    /// detecting (and resolving) barcodes that occur in more than one sample
+    MergeSc {
+        /// output prefixes (i.e. what was passed as `--output`) of the single-cell runs to merge
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+        /// the output prefix of the merged matrix
+        #[arg(short, long, required = true)]
+        output: PathBuf,
+        /// comma-separated names of the samples (in the order of the inputs), used to suffix
+        /// colliding barcodes; by default, the final component of each input prefix is used
+        #[arg(long, value_delimiter = ',')]
+        sample_names: Option<Vec<String>>,
+        /// which barcodes to suffix with the name of their sample
+        #[arg(long, value_enum, default_value_t = BarcodeSuffix::Colliding)]
+        barcode_suffix: BarcodeSuffix,
+    },
+    /// merge the bulk outputs of technical replicates (each quantified with
+    /// `--write-eqclasses`) by summing their equivalence classes and re-running the EM
+    Merge {
+        /// output prefixes (i.e. what was passed as `--output`) of the replicates to merge
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+        /// the output prefix of the merged estimates
+        #[arg(short, long, required = true)]
+        output: PathBuf,
+        /// number of bootstrap replicates to produce, resampling the reads of all
+        /// replicates together
+        #[arg(long, default_value_t = 0)]
+        num_bootstraps: u32,
+        /// maximum number of iterations for which to run the EM algorithm
+        #[arg(long, default_value_t = 1000)]
+        max_em_iter: u32,
+        /// the convergence threshold of the EM algorithm
+        #[arg(long, default_value_t = 0.001)]
+        convergence_thresh: f64,
+        /// the number of threads used to compute the bootstrap replicates
+        #[arg(short = 'j', long, default_value_t = 4)]
+        threads: usize,
+    },
+    /// quantify a sample from the equivalence classes written with `--write-eqclasses`,
+    /// without reading its alignments again, e.g. to re-run the EM or the bootstrap with
+    /// different settings
+    QuantEq {
+        /// the equivalence class file (`<output>.eqc.tsv.zst`, or a gzipped or uncompressed
+        /// copy), or the output prefix of the run that wrote it
+        #[arg(long, required = true)]
+        eq_classes: PathBuf,
+        /// the output prefix of the estimates
+        #[arg(short, long, required = true)]
+        output: PathBuf,
+        /// number of bootstrap replicates to produce, resampling the reads of the
+        /// equivalence classes
+        #[arg(long, default_value_t = 0)]
+        num_bootstraps: u32,
+        /// maximum number of iterations for which to run the EM algorithm
+        #[arg(long, default_value_t = 1000)]
+        max_em_iter: u32,
+        /// the convergence threshold of the EM algorithm
+        #[arg(long, default_value_t = 0.001)]
+        convergence_thresh: f64,
+        /// the number of reads added to the estimate of every transcript in each round of
+        /// the EM (a symmetric Dirichlet prior); with 0, the EM is that of quantification
+        #[arg(long, default_value_t = 0.0)]
+        prior: f64,
+        /// the number of threads used to compute the bootstrap replicates
+        #[arg(short = 'j', long, default_value_t = 4)]
+        threads: usize,
+    },
+    /// quantify nested subsamples (e.g. 10%, 25%, 50%, ...) of the reads of a sample, from a
+    /// single pass over its alignments, and report how the number of detected transcripts and
+    /// the estimated counts saturate with depth
+    Saturation {
+        /// the (name-collated) BAM file of the alignments of the sample
+        #[arg(short, long, required = true)]
+        alignments: PathBuf,
+        /// the output prefix; the curves are written to `<output>.saturation.tsv`
+        #[arg(short, long, required = true)]
+        output: PathBuf,
+        /// the comma-separated fractions of the reads to quantify; the full sample is
+        /// always quantified as well
+        #[arg(long, value_delimiter = ',', value_parser = parse_fraction, default_value = "0.1,0.25,0.5,0.75")]
+        fractions: Vec<f32>,
+        /// the seed that determines which reads are in each subsample
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /// the estimated number of reads at which a transcript is counted as detected
+        #[arg(long, default_value_t = 1.0)]
+        min_reads: f64,
+        /// the filter group applied to the alignments (as in quantification)
+        #[arg(long, value_enum)]
+        filter_group: Option<FilterGroup>,
+        /// the number of threads used to decompress the alignments and to quantify the
+        /// subsamples
+        #[arg(short = 'j', long, default_value_t = 4)]
+        threads: usize,
+    },
+    /// list or extract the files of an output archive written with `--archive`
+    Unpack {
+        /// the archive (`<output>.archive.zst`)
+        archive: PathBuf,
+        /// the files to extract; all files are extracted if none are given
+        members: Vec<String>,
+        /// the directory into which the files are extracted
+        #[arg(short, long, default_value = ".")]
+        output_dir: PathBuf,
+        /// list the files of the archive (and their sizes) rather than extracting them
+        #[arg(long)]
+        list: bool,
+    },
+    /// build a minimap2 index in shards, for references too large to index at once: each
+    /// shard of the reference is indexed separately (e.g. in parallel jobs) with `--shard`,
+    /// and the shard indices are then merged into a single (multi-part) index with
+    /// `--merge-sketches`
+    Index {
+        /// the FASTA reference; with `--shard`, the reference of which a shard is indexed, and
+        /// with `--merge-sketches`, the reference whose digest is recorded in the merged index
+        #[arg(long)]
+        reference: Option<PathBuf>,
+        /// the sequencing technology whose minimap2 preset is used to index the shard
+        #[arg(long, value_parser = clap::value_parser!(SequencingTech))]
+        seq_tech: Option<SequencingTech>,
+        /// index the shard `<i>/<n>` of the reference, i.e. the `i`-th (counting from 0) of
+        /// `n` contiguous blocks of its targets
+        #[arg(
+            long,
+            requires_all = ["reference", "seq_tech"],
+            conflicts_with = "merge_sketches",
+            required_unless_present = "merge_sketches"
+        )]
+        shard: Option<String>,
+        /// the shard indices to merge, in the order of their shards
+        #[arg(long, num_args = 2..)]
+        merge_sketches: Option<Vec<PathBuf>>,
+        /// the shard index, or the merged index, to write
+        #[arg(short, long, required = true)]
+        output: PathBuf,
+        /// the number of threads used to index the shard
+        #[arg(short = 'j', long, default_value_t = 4)]
+        threads: usize,
+    },
+    /// write the diploid transcriptome of a sample, for `--allele-specific` quantification:
+    /// each transcript with a heterozygous phased variant is written once for each haplotype
+    /// (named with the `--haplotype-suffixes`), and the others once, under their own name
+    Haplotypes {
+        /// the FASTA file holding the sequences of the transcripts
+        #[arg(long, required = true)]
+        reference: PathBuf,
+        /// the VCF file (possibly gzipped) holding the phased variants of the sample, in the
+        /// coordinates of the transcripts; the genotypes of its first sample are used
+        #[arg(long, required = true)]
+        vcf: PathBuf,
+        /// the FASTA file to write
+        #[arg(short, long, required = true)]
+        output: PathBuf,
+        /// the (comma-separated) suffixes naming the two haplotypes of each transcript
+        #[arg(long, value_delimiter = ',', default_value = "_h1,_h2")]
+        haplotype_suffixes: Vec<String>,
+    },
+    /// keep a pre-built minimap2 index loaded, and quantify the samples of the jobs sent to a
+    /// local (Unix domain) socket against it, so that the index is loaded only once. Each job
+    /// is a line holding a JSON object, `{"args": [...]}`, whose `args` are the options of a
+    /// run of oarfish (other than `--reference` and `--seq-tech`); `{"shutdown": true}` stops
+    /// the server
+    Serve {
+        /// the minimap2 index (e.g. written with `--index-out`) to which the reads of every
+        /// job are mapped
+        #[arg(long, required = true)]
+        index: PathBuf,
+        /// the sequencing technology whose minimap2 preset was used to build the index
+        #[arg(long, required = true, value_parser = clap::value_parser!(SequencingTech))]
+        seq_tech: SequencingTech,
+        /// the path of the socket on which to accept jobs
+        #[arg(long, required = true)]
+        socket: PathBuf,
+        /// the number of threads used to load the index
+        #[arg(short = 'j', long, default_value_t = 4)]
+        threads: usize,
+    },
+    /// compute the seqcol digest of the reference sequences of a FASTA file, a minimap2 index
+    /// built by oarfish, or the header of a BAM file, and print it (as JSON); given two inputs,
+    /// also report whether they describe the same reference sequences, e.g. to check that a BAM
+    /// file was aligned against a given reference before quantifying it
+    SketchCompare {
+        /// the FASTA file, minimap2 index or BAM file whose digest is computed, and optionally
+        /// the second, to which it is compared
+        #[arg(required = true, num_args = 1..=2)]
+        inputs: Vec<PathBuf>,
+    },
+    /// print a completion script for the options of oarfish and its tools, to be sourced by
+    /// (or installed for) the given shell
+    Completions {
+        /// the shell for which to generate the completions
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// print the man page of oarfish to stdout or, with `--output-dir`, write the man pages of
+    /// oarfish (`oarfish.1`) and of each of its tools (e.g. `oarfish-migrate.1`) to a directory
+    Man {
+        /// the directory to which the man pages are written
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+    },
+}
+
+impl ToolArgs {
+    /// Returns true if the first argument on the command line names one
+    /// of the auxiliary [Tool]s.
+    pub fn is_tool_invocation() -> bool {
+        std::env::args_os()
+            .nth(1)
+            .and_then(|a| a.into_string().ok())
+            .is_some_and(|a| <Tool as clap::Subcommand>::has_subcommand(&a))
+    }
 }
@@ -0,0 +1,252 @@
+use crate::prog_opts::Args;
+use crate::util::atomic_output;
+use crate::util::logging;
+use crate::util::quant_results::{BulkEstimates, CellCounts, Estimates, QuantResults};
+use clap::Parser;
+use numpy::IntoPyArray;
+use numpy::ndarray::Array2;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyList, PyTuple};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use tracing_subscriber::{EnvFilter, Registry, fmt, prelude::*, reload};
+
+static RELOAD_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// The handle to the filter of the log, which is set up (writing to stderr) the first time
+/// it is needed.
+fn reload_handle() -> &'static reload::Handle<EnvFilter, Registry> {
+    RELOAD_HANDLE.get_or_init(|| {
+        let (filtered_layer, handle) = reload::Layer::new(logging::default_filter());
+        // the embedding process may already have installed a subscriber, which is then kept
+        let _ = tracing_subscriber::registry()
+            .with(filtered_layer)
+            .with(fmt::layer().with_writer(std::io::stderr))
+            .try_init();
+        handle
+    })
+}
+
+/// Convert the keyword arguments `options` to command-line arguments: `foo_bar=x` becomes
+/// `--foo-bar x`, `foo_bar=True` becomes `--foo-bar` (and `False` or `None` omits the
+/// option), and a list or tuple repeats the option for each of its values.
+fn options_to_argv(options: Option<&Bound<'_, PyDict>>) -> PyResult<Vec<String>> {
+    let mut argv = vec!["oarfish".to_owned()];
+    let Some(options) = options else {
+        return Ok(argv);
+    };
+    for (key, value) in options.iter() {
+        let flag = format!("--{}", key.extract::<String>()?.replace('_', "-"));
+        if value.is_none() {
+            continue;
+        }
+        if value.is_instance_of::<PyBool>() {
+            if value.extract::<bool>()? {
+                argv.push(flag);
+            }
+        } else if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+            for v in value.try_iter()? {
+                argv.push(flag.clone());
+                argv.push(v?.str()?.to_string());
+            }
+        } else {
+            argv.push(flag);
+            argv.push(value.str()?.to_string());
+        }
+    }
+    Ok(argv)
+}
+
+/// Parse the command-line arguments `argv` as the arguments of a quantification run.
+fn parse_args(argv: Vec<String>) -> PyResult<Args> {
+    Args::try_parse_from(argv).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+/// Quantify the sample described by `args`, as `oarfish` does, writing the outputs to
+/// `args.output` once the run has succeeded, and return its estimates.
+fn run_quantify(mut args: Args) -> anyhow::Result<Estimates> {
+    for (given, option) in [
+        (args.control_alignments.is_some(), "control_alignments"),
+        (args.split_by_read_group, "split_by_read_group"),
+        (args.validate_only, "validate_only"),
+        (args.archive, "archive"),
+        // the cells quantified before the checkpoint are not quantified again
+        (args.resume, "resume"),
+    ] {
+        anyhow::ensure!(
+            !given,
+            "the `{}` option is only available from the command line",
+            option
+        );
+    }
+    let handle = reload_handle();
+    logging::set_filter(handle, &args, false)?;
+    crate::configure_run(&args)?;
+
+    let staging = atomic_output::OutputStaging::new(&args.output, args.resume)?;
+    args.output = staging.staged_output();
+    let results = Arc::new(QuantResults::default());
+    args.results = Some(results.clone());
+    crate::quantify(args, handle, None)?;
+    staging.commit()?;
+    Ok(results.take())
+}
+
+/// The metadata `meta_info` of a run, as a Python object.
+fn meta_info_to_py<'py>(
+    py: Python<'py>,
+    meta_info: &serde_json::Value,
+) -> PyResult<Bound<'py, PyAny>> {
+    py.import("json")?
+        .call_method1("loads", (meta_info.to_string(),))
+}
+
+/// The estimates of a bulk sample, as a dict of numpy arrays.
+fn bulk_estimates_to_py(py: Python<'_>, est: BulkEstimates) -> PyResult<Bound<'_, PyDict>> {
+    let bootstraps = if est.bootstraps.is_empty() {
+        None
+    } else {
+        // `reps[b][t]` is the estimate of transcript `t` in replicate `b`
+        let reps = &est.bootstraps;
+        let reps = Array2::from_shape_fn((est.names.len(), reps.len()), |(t, b)| reps[b][t]);
+        Some(reps.into_pyarray(py))
+    };
+
+    let result = PyDict::new(py);
+    result.set_item("meta_info", meta_info_to_py(py, &est.meta_info)?)?;
+    result.set_item("names", est.names)?;
+    result.set_item("lengths", est.lengths.into_pyarray(py))?;
+    result.set_item("num_reads", est.counts.into_pyarray(py))?;
+    result.set_item("bootstraps", bootstraps)?;
+    Ok(result)
+}
+
+/// The count matrix of a single-cell sample, as a dict holding a `scipy.sparse` matrix.
+fn cell_counts_to_py(py: Python<'_>, counts: CellCounts) -> PyResult<Bound<'_, PyDict>> {
+    let shape = (counts.barcodes.len(), counts.num_cols);
+    let matrix = py
+        .import("scipy.sparse")?
+        .call_method1(
+            "coo_matrix",
+            (
+                (
+                    counts.vals.into_pyarray(py),
+                    (counts.rows.into_pyarray(py), counts.cols.into_pyarray(py)),
+                ),
+                shape,
+            ),
+        )?
+        .call_method0("tocsr")?;
+
+    let result = PyDict::new(py);
+    result.set_item("counts", matrix)?;
+    result.set_item("barcodes", counts.barcodes)?;
+    result.set_item("features", counts.features)?;
+    result.set_item("meta_info", meta_info_to_py(py, &counts.meta_info)?)?;
+    Ok(result)
+}
+
+/// Quantify with the arguments `argv`, writing the outputs with the prefix `output`, and
+/// return the estimates of the run.
+fn quantify_with(
+    py: Python<'_>,
+    mut argv: Vec<String>,
+    output: PathBuf,
+) -> PyResult<Bound<'_, PyDict>> {
+    argv.push("--output".to_owned());
+    argv.push(output.display().to_string());
+    let args = parse_args(argv)?;
+    match py.allow_threads(|| run_quantify(args))? {
+        Estimates::Bulk(est) => bulk_estimates_to_py(py, est),
+        Estimates::SingleCell(counts) => cell_counts_to_py(py, counts),
+    }
+}
+
+/// Quantify a bulk sample, from its alignments (`alignments=[...]`) or from its reads
+/// (`reads=[...]`, along with `reference` and `seq_tech`). Any other option of the
+/// command line may be given as a keyword argument. The outputs are written with the
+/// prefix `output`, as they are by `oarfish`.
+///
+/// Returns a dict with the transcript `names`, their `lengths`, the estimated
+/// `num_reads` of each, the `bootstraps` (a transcripts x replicates array, or None)
+/// and the `meta_info` of the run.
+#[pyfunction]
+#[pyo3(name = "quantify", signature = (output, **options))]
+fn py_quantify<'py>(
+    py: Python<'py>,
+    output: PathBuf,
+    options: Option<&Bound<'py, PyDict>>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let argv = options_to_argv(options)?;
+    quantify_with(py, argv, output)
+}
+
+/// Quantify a single-cell sample from its alignments, collated (or sorted) by cell barcode.
+/// Any other option of the command line may be given as a keyword argument. The outputs are
+/// written with the prefix `output`, as they are by `oarfish`.
+///
+/// Returns a dict with the `counts` (a cells x features `scipy.sparse.csr_matrix`), the
+/// cell `barcodes`, the `features` and the `meta_info` of the run.
+#[pyfunction]
+#[pyo3(name = "quantify_single_cell", signature = (alignments, output, **options))]
+fn py_quantify_single_cell<'py>(
+    py: Python<'py>,
+    alignments: PathBuf,
+    output: PathBuf,
+    options: Option<&Bound<'py, PyDict>>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let mut argv = options_to_argv(options)?;
+    argv.push("--single-cell".to_owned());
+    argv.push("--alignments".to_owned());
+    argv.push(alignments.display().to_string());
+    quantify_with(py, argv, output)
+}
+
+/// Build the minimap2 index of the transcriptome `reference` (a FASTA file) for reads of
+/// the sequencing technology `seq_tech`, and write it to `index_out`, along with the digest
+/// of the reference, so that it can be passed as the `reference` of later runs.
+#[pyfunction]
+#[pyo3(name = "build_index", signature = (reference, index_out, seq_tech, threads = 1))]
+fn py_build_index(
+    py: Python<'_>,
+    reference: PathBuf,
+    index_out: PathBuf,
+    seq_tech: &str,
+    threads: usize,
+) -> PyResult<()> {
+    if !crate::is_fasta(&reference)? {
+        return Err(PyValueError::new_err(format!(
+            "{} is not a FASTA file",
+            reference.display()
+        )));
+    }
+    let argv = [
+        "oarfish".to_owned(),
+        "--reference".to_owned(),
+        reference.display().to_string(),
+        "--index-out".to_owned(),
+        index_out.display().to_string(),
+        "--seq-tech".to_owned(),
+        seq_tech.to_owned(),
+        "--threads".to_owned(),
+        threads.to_string(),
+        "--output".to_owned(),
+        index_out.display().to_string(),
+    ];
+    // no reads are needed to build the index
+    let mut args =
+        Args::try_parse_without_input(argv).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    reload_handle();
+    py.allow_threads(|| crate::get_aligner_from_args(&mut args).map(|_| ()))?;
+    Ok(())
+}
+
+#[pymodule]
+fn pyoarfish(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add_function(wrap_pyfunction!(py_build_index, m)?)?;
+    m.add_function(wrap_pyfunction!(py_quantify, m)?)?;
+    m.add_function(wrap_pyfunction!(py_quantify_single_cell, m)?)?;
+    Ok(())
+}
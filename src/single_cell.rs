@@ -1,28 +1,45 @@
 use crate::alignment_parser;
 use crate::em;
-use crate::prog_opts::Args;
+use crate::prog_opts::{Args, CoverageModel};
+use crate::util::barcode_sort;
+use crate::util::barcode_tags::BarcodeSource;
+use crate::util::barnyard::{Barnyard, BarnyardSummary};
+use crate::util::cell_qc::{self, CellQcConfig};
+use crate::util::cell_scheduler::CellScheduler;
+use crate::util::hto::{self, HtoSummary};
+use crate::util::loom;
 use crate::util::oarfish_types::{
-    AlignmentFilters, EMInfo, InMemoryAlignmentStore, TranscriptInfo,
+    AlignmentFilters, CoverageBinning, EMInfo, InMemoryAlignmentStore, TranscriptInfo,
 };
+use crate::util::output_schema::add_schema_info;
+use crate::util::profile::{self, Stage};
+use crate::util::quick_summary::{CellSummary, write_single_cell_summary};
+use crate::util::read_function::{read_txp_genes, read_txp_name_list};
+use crate::util::resource_usage;
+use crate::util::sc_matrix_writer::{CellRecord, CollatedCellWriter, WriterCheckpoint};
+use crate::util::spatial::{self, SpatialSummary};
+use crate::util::spline_probability::spline_prob;
+use crate::util::tcc::TccTable;
+use crate::util::thread_alloc::{ThreadBalancer, queue_fill};
+use crate::util::usa_counts::{UsaMap, read_usa_map};
 use crate::util::write_function;
-use crossbeam::queue::ArrayQueue;
+use either::Either;
 use noodles_bam as bam;
 use noodles_sam::alignment::RecordBuf;
 use path_tools::WithAdditionalExtension;
 use serde_json::json;
-use std::fs::{File, create_dir_all};
-use std::io::{BufRead, Write};
+use std::fs::create_dir_all;
+use std::io::BufRead;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use tracing::{error, info};
+use tracing::{Span, error, info, info_span, warn};
 
-struct QuantOutputInfo {
-    barcode_file: std::io::BufWriter<File>,
-    row_ids: Vec<u32>,
-    col_ids: Vec<u32>,
-    vals: Vec<f32>,
-    row_index: usize,
-}
+/// The number of parsed cells (per worker) that are ordered by size before
+/// being submitted for quantification.
+const CELL_BATCH_PER_THREAD: usize = 2;
+/// The maximum number of cells (per worker) that have been submitted, but
+/// not yet taken up, for quantification.
+const PENDING_CELLS_PER_THREAD: usize = 4;
 
 /// Produce a [serde_json::Value] that encodes the relevant arguments and
 /// parameters of the run that we wish to record to file. Ultimately, this
@@ -30,28 +47,70 @@ struct QuantOutputInfo {
 fn get_single_cell_json_info(
     args: &Args,
     seqcol_digest: &seqcol_rs::DigestResult,
+    hto_summary: Option<&HtoSummary>,
+    spatial_summary: Option<&SpatialSummary>,
+    barnyard_summary: Option<&BarnyardSummary>,
+    resumed_cells: usize,
 ) -> serde_json::Value {
     let prob = if args.model_coverage {
-        "logistic_coverage"
+        args.coverage_model
+            .map_or("logistic_coverage", |m| m.prob_model_name())
     } else {
         "no_coverage"
     };
 
-    json!({
+    let mut info = json!({
         "prob_model" : prob,
         "bin_width" : args.bin_width,
+        "coverage_binning" : CoverageBinning::from_args(args),
         "alignments": &args.alignments,
         "output": &args.output,
         "verbose": &args.verbose,
+        "log": &args.log,
         "single_cell": &args.single_cell,
         "quiet": &args.quiet,
         "em_max_iter": &args.max_em_iter,
+        "em_min_iter": &args.min_em_iter,
         "em_convergence_thresh": &args.convergence_thresh,
         "threads": &args.threads,
+        "io_backend": &args.io_backend,
         "filter_group": &args.filter_group,
         "short_quant": &args.short_quant,
+        "usa_t2g": &args.usa_t2g,
+        "usa_mode": args.usa_t2g.is_some(),
+        "archive": &args.archive,
+        "hto_reads": &args.hto_reads,
+        "hto_list": &args.hto_list,
+        "spot_coordinates": &args.spot_coordinates,
+        "txp_to_gene": &args.txp_to_gene,
+        "gene_matrix": args.txp_to_gene.is_some() && args.usa_t2g.is_none() && !args.tcc,
+        "mito_txps": &args.mito_txps,
+        "max_sc_mem": &args.max_sc_mem,
+        "bc_tag": &args.bc_tag,
+        "umi_tag": &args.umi_tag,
+        "bc_from_read_name": &args.bc_from_read_name,
+        "read_name_sep": &args.read_name_sep,
+        "assume_collated": &args.assume_collated,
+        "sc_sort_mem": &args.sc_sort_mem,
+        "resume": &args.resume,
+        "resumed_cells": resumed_cells,
+        "loom": &args.loom,
+        "tcc": &args.tcc,
+        "barnyard": &args.barnyard,
+        "barnyard_min_frac": &args.barnyard_min_frac,
         "digest": seqcol_digest.to_json()
-    })
+    });
+    if let Some(hs) = hto_summary {
+        info["hto"] = json!(hs);
+    }
+    if let Some(ss) = spatial_summary {
+        info["spatial"] = json!(ss);
+    }
+    if let Some(bs) = barnyard_summary {
+        info["barnyard_summary"] = json!(bs);
+    }
+    add_schema_info(&mut info);
+    info
 }
 
 pub fn quantify_single_cell_from_collated_bam<R: BufRead>(
@@ -72,170 +131,410 @@ pub fn quantify_single_cell_from_collated_bam<R: BufRead>(
         }
     }
 
+    let txps_name: Vec<String> = header
+        .reference_sequences()
+        .keys()
+        .map(|n| n.to_string())
+        .collect();
+    // the gene and splicing status of each target, if producing USA-mode counts
+    let usa_map: Option<UsaMap> = match args.usa_t2g {
+        Some(ref t2g) => Some(read_usa_map(t2g, &txps_name)?),
+        None => None,
+    };
+    let num_cols = usa_map.as_ref().map_or(txps.len(), |m| m.num_cols());
+    let hashtags = match args.hto_list {
+        Some(ref hto_list) => Some(hto::read_hashtags(hto_list)?),
+        None => None,
+    };
+    let spots = match args.spot_coordinates {
+        Some(ref coords) => Some(spatial::read_spot_map(coords)?),
+        None => None,
+    };
+    // outside of USA mode, the genes given in the transcript-to-gene file (if any),
+    // whose counts are written to a gene-level count matrix
+    let t2g_genes = match (usa_map.as_ref(), args.txp_to_gene.as_ref()) {
+        (None, Some(t2g)) => Some(cell_qc::gene_ids(&read_txp_genes(t2g, &txps_name)?)),
+        _ => None,
+    };
+    // with TCC counts, the classes of the reads are counted in place of the EM estimates;
+    // there are then no transcript estimates from which to compute the gene-level counts
+    let tcc_table = args.tcc.then(|| TccTable::new(txps.len()));
+    let gene_matrix_genes = t2g_genes.as_ref().filter(|_| tcc_table.is_none());
+    // the genes (from the USA-mode map, or else the transcript-to-gene file) and the
+    // mitochondrial transcripts, if known, for the per-cell QC metrics
+    let txp_gene = match (usa_map.as_ref(), t2g_genes.as_ref()) {
+        (Some(usa), _) => Some(usa.txp_gene_ids()),
+        (None, Some((gene_ids, _))) => Some(gene_ids.clone()),
+        (None, None) => None,
+    };
+    let mito = match args.mito_txps {
+        Some(ref mito_txps) => {
+            let names = read_txp_name_list(mito_txps)?;
+            let mask: Vec<bool> = txps_name.iter().map(|n| names.contains(n)).collect();
+            if !mask.iter().any(|m| *m) {
+                warn!(
+                    "none of the transcripts in {} are in the reference; the mitochondrial fraction of each cell will be 0.",
+                    mito_txps.display()
+                );
+            }
+            Some(mask)
+        }
+        None => None,
+    };
+    let qc_config = CellQcConfig::new(txp_gene, mito);
+    // the species of the transcripts of a mixed-species experiment
+    let barnyard = match args.barnyard {
+        Some(ref prefixes) => Some(Barnyard::new(
+            prefixes,
+            &txps_name,
+            args.barnyard_min_frac as f64,
+        )?),
+        None => None,
+    };
+    let bc_source = BarcodeSource::from_args(args);
+
+    // the progress of the run is checkpointed, so that it can be resumed if it fails
+    let barcode_path = args.output.with_additional_extension(".barcodes.txt");
+    let checkpoint_path = args.output.with_additional_extension(".sc_checkpoint.json");
+    let checkpoint_input = args
+        .alignments
+        .first()
+        .map_or(String::new(), |p| p.display().to_string());
+    let resume_from = if args.resume {
+        WriterCheckpoint::load(&checkpoint_path, &checkpoint_input)?
+    } else {
+        None
+    };
+    // the barcodes of the cells quantified before the checkpoint, which are skipped
+    let completed_barcodes = match resume_from.as_ref() {
+        Some(ckpt) => ckpt.completed_barcodes(&barcode_path)?,
+        None => Vec::new(),
+    };
+    let resumed_cells = completed_barcodes.len();
+
     let nthreads = args.threads;
     std::thread::scope(|s| {
-        let bc_path = args.output.with_additional_extension(".barcodes.txt");
-        let bc_file = File::create(bc_path)?;
-        let bc_writer = Arc::new(Mutex::new(QuantOutputInfo {
-            barcode_file: std::io::BufWriter::new(bc_file),
-            row_ids: Vec::new(),
-            col_ids: Vec::new(),
-            vals: Vec::new(),
-            row_index: 0usize,
-        }));
+        // the hashtags are counted, from the raw reads, alongside the quantification
+        let hto_handle = match (args.hto_reads.as_ref(), hashtags.as_ref()) {
+            (Some(reads), Some(hashtags)) => {
+                let bc_source = &bc_source;
+                Some(s.spawn(move || hto::count_hashtags(reads, hashtags, bc_source, &args.output)))
+            }
+            _ => None,
+        };
+
+        // unless the input is known to be collated by cell barcode, sort it by barcode
+        let sorted_records = if args.assume_collated {
+            None
+        } else {
+            info!("sorting the alignment records by cell barcode.");
+            let records = barcode_sort::sort_by_barcode(
+                reader,
+                header,
+                &bc_source,
+                args.sc_sort_mem as usize,
+            )?;
+            resource_usage::end_stage("barcode_sort");
+            Some(records)
+        };
+
+        // the per-cell outputs are written in the (collated) order of the cells in
+        // the input, as they are quantified, rather than being accumulated in memory
+        let spot_path = args.output.with_additional_extension(".spots.tsv");
+        let gene_matrix_path = args.output.with_additional_extension(".gene.count.mtx");
+        let bc_writer = Arc::new(Mutex::new(CollatedCellWriter::new(
+            &args.output.with_additional_extension(".count.mtx"),
+            &barcode_path,
+            (
+                &args.output.with_additional_extension(".cell_qc.tsv"),
+                cell_qc::CELL_QC_HEADER,
+            ),
+            gene_matrix_genes.map(|(_, genes)| (gene_matrix_path.as_path(), genes.len())),
+            spots.as_ref().map(|m| {
+                (
+                    spot_path.as_path(),
+                    SpatialSummary {
+                        num_spots: m.num_spots(),
+                        num_barcodes_with_spot: 0,
+                        num_barcodes_without_spot: 0,
+                        num_barcodes_in_tissue: 0,
+                    },
+                )
+            }),
+            args.max_sc_mem as usize,
+            (&checkpoint_path, &checkpoint_input),
+            resume_from,
+        )?));
 
         // the element consists of the vector of records corresponding
         // to this cell, a (read-only) copy of TranscriptInfo which will
-        // be copied and modified by the thread, and the barcode
-        // (represented as a Vec<u8>) for the cell.
-        type QueueElement<'a> = (Vec<RecordBuf>, &'a [TranscriptInfo], Vec<u8>);
+        // be copied and modified by the thread, the barcode
+        // (represented as a Vec<u8>) for the cell and the index of
+        // the cell in the input.
+        type QueueElement<'a> = (Vec<RecordBuf>, &'a [TranscriptInfo], Vec<u8>, usize);
 
-        let q: Arc<ArrayQueue<QueueElement>> = Arc::new(ArrayQueue::new(4 * nthreads));
-        let done_parsing = Arc::new(std::sync::atomic::AtomicBool::new(false));
-        let mut thread_handles: Vec<std::thread::ScopedJoinHandle<'_, anyhow::Result<usize>>> =
-            Vec::with_capacity(nthreads);
+        // the cells are quantified largest first within each batch of parsed
+        // cells, and idle workers steal cells queued for the other workers
+        let (scheduler, local_queues) =
+            CellScheduler::<QueueElement>::new(nthreads, PENDING_CELLS_PER_THREAD * nthreads);
+        // each worker returns the number of cells it quantified, and their summary
+        let mut thread_handles: Vec<
+            std::thread::ScopedJoinHandle<'_, anyhow::Result<(usize, CellSummary)>>,
+        > = Vec::with_capacity(nthreads);
 
-        for _worker_id in 0..nthreads {
-            let in_q = q.clone();
-            let done_parsing = done_parsing.clone();
+        // limit the number of workers running at any time, so that they don't
+        // starve the decompression and parsing of the input
+        let balancer = ThreadBalancer::new("single-cell quantification", nthreads);
+        {
+            let balancer = balancer.clone();
+            let scheduler = scheduler.clone();
+            s.spawn(move || {
+                balancer.monitor(|| {
+                    (
+                        queue_fill(scheduler.num_pending(), Some(scheduler.capacity())),
+                        None,
+                    )
+                });
+            });
+        }
+
+        for (worker_id, local_queue) in local_queues.into_iter().enumerate() {
+            let scheduler = scheduler.clone();
+            let balancer = balancer.clone();
             let num_txps = txps.len();
             let bc_out = bc_writer.clone();
-            let bin_width = args.bin_width;
             let filter_opts = filter_opts.clone();
+            let usa_map = usa_map.as_ref();
+            let spots = spots.as_ref();
+            let qc_config = &qc_config;
+            let bc_source = &bc_source;
+            let t2g_genes = gene_matrix_genes;
+            let tcc_table = tcc_table.as_ref();
+            // the events of each cell are logged in the context of the sample
+            let sample_span = Span::current();
 
             let handle = s.spawn(move || {
-                let mut col_ids = Vec::with_capacity(num_txps);
-                let mut row_ids = Vec::with_capacity(num_txps);
-                let mut vals = Vec::with_capacity(num_txps);
+                let mut gene_counts = Vec::<f64>::new();
                 let mut num_cells = 0_usize;
+                let mut summary = CellSummary::default();
                 let mut records_for_read = Vec::<RecordBuf>::with_capacity(16);
 
-                // while the queue might still be being filled
-                while !done_parsing.load(std::sync::atomic::Ordering::SeqCst) {
+                loop {
+                    // wait while this worker is deactivated
+                    balancer.wait_turn(worker_id);
                     // get the next cell
-                    while let Some(elem) = in_q.pop() {
-                        let mut recs = elem.0;
-                        // new copy of txp info for this barcode
-                        let mut txps = Vec::with_capacity(num_txps);
-                        txps.extend_from_slice(elem.1);
-                        // the barcode of this cell
-                        let barcode = elem.2;
-                        // where we will store the relevant alignment records
-                        let mut store = InMemoryAlignmentStore::new(filter_opts.clone(), header);
+                    let Some(elem) = scheduler.next_job(&local_queue) else {
+                        break;
+                    };
+                    let mut recs = elem.0;
+                    // new copy of txp info for this barcode
+                    let mut txps = Vec::with_capacity(num_txps);
+                    txps.extend_from_slice(elem.1);
+                    // the barcode of this cell
+                    let barcode = elem.2;
+                    // the index of this cell, which is its row of the count matrix
+                    let cell_index = elem.3;
+                    let _cell_span = info_span!(
+                        parent: &sample_span,
+                        "cell",
+                        barcode = %String::from_utf8_lossy(&barcode),
+                        index = cell_index
+                    )
+                    .entered();
+                    // where we will store the relevant alignment records
+                    let mut store = InMemoryAlignmentStore::new(filter_opts.clone(), header);
 
-                        // sort by read name and then parse the records for this cell
-                        alignment_parser::sort_and_parse_barcode_records(
-                            &mut recs,
-                            &mut store,
-                            &mut txps,
-                            &mut records_for_read,
-                        )?;
+                    // sort by read name, take the metrics of the records, and then
+                    // parse the records for this cell
+                    alignment_parser::sort_barcode_records(&mut recs);
+                    let rec_stats = cell_qc::record_stats(bc_source, &recs);
+                    alignment_parser::parse_barcode_records(
+                        recs,
+                        &mut store,
+                        &mut txps,
+                        &mut records_for_read,
+                    )?;
 
-                        if store.filter_opts.model_coverage {
-                            //obtaining the Cumulative Distribution Function (CDF) for each transcript
-                            crate::binomial_continuous_prob(&mut txps, &bin_width, 1);
-                            //Normalize the probabilities for the records of each read
-                            crate::normalize_read_probs(&mut store, &txps, &bin_width);
+                    // with TCC counts, the EM is skipped; the reads of each class are
+                    // counted, and each read is divided equally among its transcripts
+                    // for the metrics of the cell
+                    if let Some(tcc) = tcc_table {
+                        let mut counts = Vec::<f64>::new();
+                        let classes = tcc.cell_counts(&store, &mut counts);
+                        let qc = cell_qc::cell_qc(qc_config, rec_stats, &store, &counts);
+                        let mut qc_line = Vec::new();
+                        cell_qc::write_cell_qc(&mut qc_line, &barcode, &qc)?;
+                        summary.add_cell(&qc, &classes);
+                        if let Some(ref results) = args.results {
+                            results.add_cell(cell_index, &barcode, &classes);
                         }
-
-                        // wrap up all of the relevant information we need for estimation
-                        // in an EMInfo struct and then call the EM algorithm.
-                        let emi = EMInfo {
-                            eq_map: &store,
-                            txp_info: &txps,
-                            max_iter: args.max_em_iter,
-                            convergence_thresh: args.convergence_thresh,
-                            init_abundances: None,
-                            kde_model: None,
-                        };
-                        // run the EM for this cell
-                        let counts = em::em(&emi, 1);
-                        // clear out the vectors where we will store
-                        // the count information for this cell
-                        col_ids.clear();
-                        vals.clear();
-                        for (col_idx, v) in counts.iter().enumerate() {
-                            if *v > 0.0 {
-                                col_ids.push(col_idx as u32);
-                                vals.push((*v) as f32);
-                            }
+                        let mut record = CellRecord::new(barcode, qc_line);
+                        for (class_id, n) in classes {
+                            record.push_entry(cell_index, class_id, n);
                         }
-                        // fill the row ids for this cell; fist
-                        // we size the vector to the correct length
-                        // and fill it with 0s and below we
-                        // fill with the appropriate number (i.e. the
-                        // cell/barcode ID).
-                        row_ids.resize(col_ids.len(), 0_u32);
                         num_cells += 1;
-
-                        let row_index: usize;
                         {
-                            // grab a lock and fill out the count info for
-                            // this cell.
                             let writer_deref = bc_out.lock();
                             let writer = &mut *writer_deref.unwrap();
-                            writeln!(&mut writer.barcode_file, "{}", unsafe {
-                                std::str::from_utf8_unchecked(&barcode)
-                            })?;
+                            writer.push(cell_index, record, spots)?;
+                        }
+                        continue;
+                    }
 
-                            // get the row index and then increment it
-                            row_index = writer.row_index;
-                            writer.row_index += 1;
-                            row_ids.fill(row_index as u32);
+                    if store.filter_opts.model_coverage {
+                        //obtaining the Cumulative Distribution Function (CDF) for each transcript
+                        match args.coverage_model.unwrap_or(CoverageModel::Binomial) {
+                            CoverageModel::Logistic => {
+                                crate::logistic_prob(&mut txps, args.growth_rate, 1)
+                            }
+                            CoverageModel::Binomial => {
+                                crate::binomial_continuous_prob(&mut txps, 1)
+                            }
+                            CoverageModel::Spline => spline_prob(&mut txps, 1),
+                        }
+                        //Normalize the probabilities for the records of each read
+                        crate::normalize_read_probs(&mut store, &txps);
+                    }
 
-                            writer.col_ids.extend_from_slice(&col_ids);
-                            writer.row_ids.extend_from_slice(&row_ids);
-                            writer.vals.extend_from_slice(&vals);
+                    // wrap up all of the relevant information we need for estimation
+                    // in an EMInfo struct and then call the EM algorithm.
+                    let emi = EMInfo {
+                        eq_map: &store,
+                        txp_info: &txps,
+                        max_iter: args.max_em_iter,
+                        min_iter: args.min_em_iter,
+                        convergence_thresh: args.convergence_thresh,
+                        init_abundances: None,
+                        kde_model: None,
+                        txp_weights: None,
+                        trace: None,
+                    };
+                    // run the EM for this cell
+                    let mut counts = profile::time(Stage::Em, || em::em(&emi, 1));
+                    let qc = cell_qc::cell_qc(qc_config, rec_stats, &store, &counts);
+                    // in USA mode, the counts are the spliced, unspliced
+                    // and ambiguous counts of each gene
+                    if let Some(usa) = usa_map {
+                        usa.cell_counts(&store, &counts, &mut gene_counts);
+                        std::mem::swap(&mut counts, &mut gene_counts);
+                    }
+                    // otherwise, the gene-level counts (if requested) are the sums of the
+                    // estimates of the transcripts of each gene, so that the reads shared
+                    // by the isoforms of a gene are assigned to it in full
+                    if let Some((txp_gene, genes)) = t2g_genes {
+                        gene_counts.clear();
+                        gene_counts.resize(genes.len(), 0.0);
+                        for (c, g) in counts.iter().zip(txp_gene.iter()) {
+                            if let Some(g) = g {
+                                gene_counts[*g as usize] += c;
+                            }
                         }
                     }
+                    let mut qc_line = Vec::new();
+                    cell_qc::write_cell_qc(&mut qc_line, &barcode, &qc)?;
+                    let entries: Vec<(u32, f32)> = counts
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, v)| **v > 0.0)
+                        .map(|(col_idx, v)| (col_idx as u32, *v as f32))
+                        .collect();
+                    summary.add_cell(&qc, &entries);
+                    if let Some(ref results) = args.results {
+                        results.add_cell(cell_index, &barcode, &entries);
+                    }
+                    let mut record = CellRecord::new(barcode, qc_line);
+                    for (col_idx, v) in entries {
+                        record.push_entry(cell_index, col_idx, v);
+                    }
+                    if t2g_genes.is_some() {
+                        for (col_idx, v) in gene_counts.iter().enumerate() {
+                            if *v > 0.0 {
+                                record.push_gene_entry(cell_index, col_idx as u32, (*v) as f32);
+                            }
+                        }
+                    }
+                    num_cells += 1;
+
+                    {
+                        // grab a lock and hand over the output of this cell
+                        let writer_deref = bc_out.lock();
+                        let writer = &mut *writer_deref.unwrap();
+                        writer.push(cell_index, record, spots)?;
+                    }
                 }
-                Ok(num_cells)
+                Ok((num_cells, summary))
             });
             thread_handles.push(handle);
         }
 
         // get the data for the next cell
-        let mut peekable_bam_iter = reader.record_bufs(header).peekable();
-        const CB_TAG: [u8; 2] = [b'C', b'B'];
+        let release_workers = balancer.release_on_drop();
+        let mut peekable_bam_iter = match sorted_records {
+            Some(records) => Either::Left(records),
+            None => Either::Right(reader.record_bufs(header)),
+        }
+        .peekable();
         let mut num_cells = 0_usize;
+        let batch_size = CELL_BATCH_PER_THREAD * nthreads;
+        let mut batch = Vec::with_capacity(batch_size);
         // parser thread
         while let Some(next_res) = peekable_bam_iter.peek() {
             let rec = next_res.as_ref().unwrap();
-            let barcode = match rec.data().get(&CB_TAG) {
-                None => anyhow::bail!("could not get CB tag value"),
-                Some(v) => match v {
-                    noodles_sam::alignment::record_buf::data::field::Value::String(x) => {
-                        x.to_ascii_uppercase()
-                    }
-                    _ => anyhow::bail!("CB tag value had unexpected type!"),
-                },
-            };
+            let raw_barcode = bc_source.barcode(rec)?.to_vec();
 
-            let records_for_barcode =
-                alignment_parser::parse_alignments_for_barcode(&mut peekable_bam_iter, &barcode)?;
+            let records_for_barcode = alignment_parser::parse_alignments_for_barcode(
+                &mut peekable_bam_iter,
+                &raw_barcode,
+                &bc_source,
+            )?;
+            let barcode = raw_barcode.to_ascii_uppercase();
 
             num_cells += 1;
+            // the cells quantified before the checkpoint we resume from are skipped, but
+            // they must be the same cells, in the same order, as in the run being resumed
+            if let Some(done) = completed_barcodes.get(num_cells - 1) {
+                anyhow::ensure!(
+                    *done == barcode,
+                    "cell {} of the input has barcode {}, but the checkpointed run had {}; the run can not be resumed",
+                    num_cells,
+                    String::from_utf8_lossy(&barcode),
+                    String::from_utf8_lossy(done)
+                );
+                continue;
+            }
             if num_cells > 1 && num_cells % 100 == 0 {
                 info!("Processed {} cells.", num_cells);
             }
 
-            let mut astore = (records_for_barcode, &(*txps), barcode);
-
-            // push the store on to the work queue
-            while let Err(store) = q.push(astore) {
-                astore = store;
-                while q.is_full() {}
+            // add the cell to the current batch, which is submitted
+            // (largest cells first) once it is full
+            batch.push((
+                records_for_barcode.len(),
+                (records_for_barcode, &(*txps), barcode, num_cells - 1),
+            ));
+            if batch.len() >= batch_size {
+                scheduler.submit_batch(&mut batch);
             }
         }
-
-        done_parsing.store(true, std::sync::atomic::Ordering::SeqCst);
+        scheduler.submit_batch(&mut batch);
+        scheduler.finish();
+        drop(release_workers);
+        anyhow::ensure!(
+            num_cells >= resumed_cells,
+            "the input has {} cells, fewer than the {} of the checkpointed run",
+            num_cells,
+            resumed_cells
+        );
 
         let mut total_cells = 0_usize;
+        let mut summary = CellSummary::default();
+        let num_stolen = scheduler.num_stolen();
         for h in thread_handles {
             let hj = h.join();
             match hj {
-                Ok(Ok(nc)) => {
+                Ok(Ok((nc, worker_summary))) => {
                     total_cells += nc;
+                    summary.merge(worker_summary);
                 }
                 Ok(Err(e)) => {
                     error!("error result from thread {:?}", e);
@@ -246,19 +545,84 @@ pub fn quantify_single_cell_from_collated_bam<R: BufRead>(
             }
         }
 
-        let trimat = {
+        let spatial_summary = {
             let writer_deref = bc_writer.lock();
             let writer = &mut *writer_deref.unwrap();
-            let num_rows = total_cells;
-            sprs::TriMatI::<f32, u32>::from_triplets(
-                (num_rows, txps.len()),
-                writer.row_ids.clone(),
-                writer.col_ids.clone(),
-                writer.vals.clone(),
-            )
+            writer.finish(tcc_table.as_ref().map_or(num_cols, |t| t.num_classes()))?;
+            writer.spatial_summary.take()
         };
-        let info = get_single_cell_json_info(args, &seqcol_digest);
-        write_function::write_single_cell_output(&args.output, info, header, &trimat)?;
+        info!(
+            "quantified {} cells ({} taken over from a busy worker).",
+            total_cells, num_stolen
+        );
+        if let Some(ss) = spatial_summary.as_ref() {
+            info!(
+                "{} of {} barcodes matched a spot ({} in tissue).",
+                ss.num_barcodes_with_spot, total_cells, ss.num_barcodes_in_tissue
+            );
+            if ss.num_barcodes_with_spot == 0 && total_cells > 0 {
+                warn!(
+                    "none of the barcodes matched a spot of {}; check that it describes the chemistry of these data.",
+                    args.spot_coordinates
+                        .as_ref()
+                        .map_or(String::new(), |p| p.display().to_string())
+                );
+            }
+        }
+        resource_usage::end_stage("quantification");
+        // the species of the cells are called from the complete count matrix, so that
+        // the cells of a resumed run are included
+        let barnyard_summary = match barnyard.as_ref() {
+            Some(b) => Some(b.split(&args.output, &txps_name)?),
+            None => None,
+        };
+        let hto_summary = match hto_handle {
+            Some(h) => Some(
+                h.join()
+                    .map_err(|_| anyhow::anyhow!("the hashtag counting thread panicked"))??,
+            ),
+            None => None,
+        };
+        let info = get_single_cell_json_info(
+            args,
+            &seqcol_digest,
+            hto_summary.as_ref(),
+            spatial_summary.as_ref(),
+            barnyard_summary.as_ref(),
+            resumed_cells,
+        );
+        if let Some(ref results) = args.results {
+            let features = match usa_map.as_ref() {
+                Some(m) => m.genes().to_vec(),
+                None => txps_name.clone(),
+            };
+            results.finish_cells(
+                tcc_table.as_ref().map_or(num_cols, |t| t.num_classes()),
+                features,
+                info.clone(),
+            );
+        }
+        write_function::write_single_cell_output(
+            &args.output,
+            info,
+            header,
+            usa_map.as_ref().map(|m| m.genes()),
+            gene_matrix_genes.map(|(_, genes)| genes.as_slice()),
+        )?;
+        if let Some(ref tcc) = tcc_table {
+            tcc.write(&args.output.with_additional_extension(".ec.txt"))?;
+        }
+        write_single_cell_summary(&args.output, summary, resumed_cells, |col| {
+            match (tcc_table.as_ref(), usa_map.as_ref()) {
+                (Some(_), _) => format!("class {}", col),
+                (None, Some(usa)) => usa.col_name(col),
+                (None, None) => txps_name[col].clone(),
+            }
+        })?;
+        if args.loom {
+            loom::write_loom(&args.output, usa_map.is_some())?;
+        }
+        resource_usage::end_stage("write_output");
         Ok(())
     })
 }
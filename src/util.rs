@@ -1,13 +1,82 @@
+pub mod adaptive_sampling;
+pub mod alignment_cache;
+pub mod allelic;
+pub mod archive;
+pub mod atomic_output;
 pub mod aux_counts;
+pub mod balanced_bgzf;
+pub mod bam_output;
+pub mod barcode_sort;
+pub mod barcode_tags;
+pub mod barnyard;
 pub mod binomial_probability;
+pub mod biotypes;
+pub mod cell_qc;
+pub mod cell_scheduler;
+pub mod cli_docs;
+pub mod collapse;
+pub mod compact_store;
+pub mod compressed_writer;
 pub mod constants;
+pub mod contaminants;
 pub mod count_function;
+pub mod coverage_comparison;
+pub mod decoys;
+pub mod demux;
 pub mod digest_utils;
+pub mod duplicates;
+pub mod eq_classes;
+pub mod errors;
+pub mod gene_quant;
+pub mod gpu_em;
+pub mod hto;
+pub mod indistinguishable;
+pub mod infrep_spill;
+pub mod internal_priming;
+pub mod isoform_switch;
 pub mod kde_utils;
+pub mod lanes;
+pub mod length_dist;
+pub mod logging;
 pub mod logistic_probability;
+pub mod loom;
+pub mod mem_tracker;
+pub mod min_reads;
 pub mod mm_utils;
 pub mod normalize_probability;
 pub mod oarfish_types;
+pub mod object_store_io;
+pub mod output_schema;
 pub mod parquet_utils;
+pub mod profile;
+pub mod progress;
+pub mod provenance;
+pub mod quant_results;
+pub mod quick_summary;
+pub mod read_filter;
 pub mod read_function;
+pub mod read_length_strata;
+pub mod read_quality;
+pub mod reference_mismatch;
+pub mod reorder_buffer;
+pub mod resource_usage;
+pub mod saturation;
+pub mod sc_matrix_writer;
+pub mod sc_merge;
+pub mod score_calibration;
+pub mod second_chance;
+pub mod serve;
+pub mod sharded_index;
+pub mod spatial;
+pub mod spike_ins;
+pub mod spline_probability;
+pub mod subsample;
+pub mod taxonomy;
+pub mod tcc;
+pub mod thread_alloc;
+pub mod trimming;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring_reader;
+pub mod usa_counts;
+pub mod validate;
 pub mod write_function;
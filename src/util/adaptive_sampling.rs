@@ -0,0 +1,279 @@
+use crate::util::duplicates::new_name_vec;
+use crate::util::oarfish_types::EMInfo;
+use crate::util::read_length_strata::{quantify_read_subset, tv_distance};
+use anyhow::Context;
+use num_format::{Locale, ToFormattedString};
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use swapvec::SwapVec;
+use tracing::{info, warn};
+
+/// The adaptive sampling decision made for a read, as recorded by MinKNOW.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AsDecision {
+    /// the read was sequenced to completion (`stop_receiving`)
+    Accept,
+    /// the read was ejected from the pore (`unblock`)
+    Reject,
+    /// no decision was made before the read ended (`no_decision`)
+    NoDecision,
+    /// the read does not appear in the decision file
+    Unclassified,
+}
+
+impl AsDecision {
+    pub const ALL: [AsDecision; 4] = [
+        AsDecision::Accept,
+        AsDecision::Reject,
+        AsDecision::NoDecision,
+        AsDecision::Unclassified,
+    ];
+
+    #[inline]
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AsDecision::Accept => "accept",
+            AsDecision::Reject => "reject",
+            AsDecision::NoDecision => "no_decision",
+            AsDecision::Unclassified => "unclassified",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "stop_receiving" | "accept" => Some(AsDecision::Accept),
+            "unblock" | "reject" => Some(AsDecision::Reject),
+            "no_decision" => Some(AsDecision::NoDecision),
+            _ => None,
+        }
+    }
+}
+
+/// Read the `read_id` and `decision` columns of the (comma-separated) adaptive
+/// sampling decision file written by MinKNOW at `path`, returning the decision
+/// made for each read. If a read appears more than once, an accept or reject
+/// decision takes precedence over `no_decision`.
+pub fn read_decisions(path: &Path) -> anyhow::Result<FxHashMap<String, AsDecision>> {
+    let file = std::fs::File::open(path).with_context(|| {
+        format!(
+            "could not open adaptive sampling decision file {}",
+            path.display()
+        )
+    })?;
+    let mut lines = BufReader::new(file).lines();
+    let header = lines.next().with_context(|| {
+        format!(
+            "adaptive sampling decision file {} is empty",
+            path.display()
+        )
+    })??;
+    let cols: Vec<&str> = header.split(',').collect();
+    let col_of = |name: &str| {
+        cols.iter().position(|c| c.trim() == name).with_context(|| {
+            format!(
+                "adaptive sampling decision file {} has no {} column",
+                path.display(),
+                name
+            )
+        })
+    };
+    let read_col = col_of("read_id")?;
+    let decision_col = col_of("decision")?;
+
+    let mut decisions = FxHashMap::default();
+    for (lnum, line) in lines.enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').collect();
+        match (fields.get(read_col), fields.get(decision_col)) {
+            (Some(read), Some(decision)) => {
+                let decision = AsDecision::parse(decision.trim()).with_context(|| {
+                    format!(
+                        "invalid decision {:?} on line {} of adaptive sampling decision file {}",
+                        decision,
+                        lnum + 2,
+                        path.display()
+                    )
+                })?;
+                decisions
+                    .entry(read.trim().to_owned())
+                    .and_modify(|d| {
+                        if *d == AsDecision::NoDecision {
+                            *d = decision;
+                        }
+                    })
+                    .or_insert(decision);
+            }
+            _ => anyhow::bail!(
+                "line {} of adaptive sampling decision file {} has too few columns",
+                lnum + 2,
+                path.display()
+            ),
+        }
+    }
+    info!(
+        "read the adaptive sampling decisions of {} reads.",
+        decisions.len().to_formatted_string(&Locale::en)
+    );
+    Ok(decisions)
+}
+
+/// Look up the decision made for each read in `name_vec` (in store order) in
+/// `decisions`. Since iterating over `name_vec` consumes it, the names are
+/// returned in a new vector along with the decisions.
+pub fn decisions_of_reads(
+    name_vec: SwapVec<String>,
+    decisions: &FxHashMap<String, AsDecision>,
+) -> (Vec<AsDecision>, SwapVec<String>) {
+    let mut read_decisions = Vec::new();
+    let mut names = new_name_vec();
+    for name in name_vec.into_iter() {
+        let name = name.expect("could not extract read name from file");
+        read_decisions.push(
+            decisions
+                .get(name.trim_end_matches('\0'))
+                .copied()
+                .unwrap_or(AsDecision::Unclassified),
+        );
+        names
+            .push(name)
+            .expect("cannot push name to read name vector");
+    }
+    (read_decisions, names)
+}
+
+/// Assignment statistics for the reads of a single adaptive sampling decision class.
+#[derive(Debug, Serialize)]
+pub struct DecisionSummary {
+    pub decision: AsDecision,
+    /// the number of reads of this class in the decision file
+    /// (not available for unclassified reads)
+    pub num_reads: Option<usize>,
+    /// the number of reads of this class having a valid alignment
+    pub num_aligned: usize,
+    /// the fraction of the reads of this class having a valid alignment
+    pub aligned_rate: Option<f64>,
+    /// total variation distance between the relative abundances
+    /// estimated from this class and from all reads
+    pub tv_distance: f64,
+}
+
+/// The per-decision statistics and estimates of an adaptive sampling run;
+/// `counts[c][t]` is the estimated number of reads of decision class `c` (in
+/// the order of [AsDecision::ALL]) arising from transcript `t`, and
+/// `corrected[t]` is the estimated number of reads of transcript `t` after
+/// correcting each class for the reads that failed to align.
+#[derive(Debug, Serialize)]
+pub struct AdaptiveSamplingResult {
+    pub summaries: Vec<DecisionSummary>,
+    /// the fraction of the aligned, classified reads that were accepted
+    pub accepted_frac: f64,
+    #[serde(skip)]
+    pub counts: Vec<Vec<f64>>,
+    #[serde(skip)]
+    pub corrected: Vec<f64>,
+}
+
+/// Quantify the reads of `emi` separately within each adaptive sampling decision
+/// class (given, for each read, in `read_decisions`), and compare the estimates to
+/// the joint estimate `counts`.
+///
+/// Whether a read is accepted or rejected is decided from its first few hundred
+/// bases, so every class is a sample of the captured molecules; enrichment only
+/// biases the estimates because rejected reads are truncated and therefore align
+/// (and pass the filters) far less often than accepted ones. The corrected estimate
+/// thus scales the estimate of each class by the inverse of its alignment rate,
+/// as measured against the number of reads of the class in the decision file.
+pub fn summarize_adaptive_sampling(
+    emi: &EMInfo,
+    read_decisions: &[AsDecision],
+    decisions: &FxHashMap<String, AsDecision>,
+    counts: &[f64],
+) -> AdaptiveSamplingResult {
+    let mut class_inds: Vec<Vec<usize>> = vec![Vec::new(); AsDecision::ALL.len()];
+    for (i, d) in read_decisions.iter().enumerate() {
+        class_inds[d.index()].push(i);
+    }
+    let mut class_reads = [0_usize; 4];
+    for d in decisions.values() {
+        class_reads[d.index()] += 1;
+    }
+
+    let class_counts: Vec<Vec<f64>> = class_inds
+        .iter()
+        .map(|inds| quantify_read_subset(emi, inds, counts.len()))
+        .collect();
+
+    let summaries: Vec<DecisionSummary> = AsDecision::ALL
+        .iter()
+        .zip(class_inds.iter().zip(class_counts.iter()))
+        .map(|(d, (inds, ccounts))| {
+            let num_reads = (*d != AsDecision::Unclassified).then_some(class_reads[d.index()]);
+            DecisionSummary {
+                decision: *d,
+                num_reads,
+                num_aligned: inds.len(),
+                aligned_rate: num_reads
+                    .filter(|n| *n > 0)
+                    .map(|n| inds.len() as f64 / n as f64),
+                tv_distance: tv_distance(ccounts, counts),
+            }
+        })
+        .collect();
+
+    let mut corrected = vec![0.0_f64; counts.len()];
+    for (s, ccounts) in summaries.iter().zip(class_counts.iter()) {
+        // unclassified reads (and classes whose alignment rate can not be
+        // determined) are not corrected
+        let scale = match s.aligned_rate {
+            Some(r) if r > 0.0 => 1.0 / r.min(1.0),
+            _ => 1.0,
+        };
+        for (c, x) in corrected.iter_mut().zip(ccounts.iter()) {
+            *c += scale * x;
+        }
+    }
+
+    let num_accepted = class_inds[AsDecision::Accept.index()].len();
+    let num_classified = num_accepted
+        + class_inds[AsDecision::Reject.index()].len()
+        + class_inds[AsDecision::NoDecision.index()].len();
+    let accepted_frac = if num_classified > 0 {
+        num_accepted as f64 / num_classified as f64
+    } else {
+        0.0
+    };
+
+    for s in summaries.iter() {
+        info!(
+            "adaptive sampling {} : {} aligned reads{}, total variation distance from pooled estimate = {:.4}",
+            s.decision.label(),
+            s.num_aligned.to_formatted_string(&Locale::en),
+            s.aligned_rate
+                .map(|r| format!(" ({:.2}% aligned)", 100.0 * r))
+                .unwrap_or_default(),
+            s.tv_distance
+        );
+    }
+    if num_classified == 0 {
+        warn!(
+            "none of the aligned reads appear in the adaptive sampling decision file; are the read names consistent?"
+        );
+    }
+
+    AdaptiveSamplingResult {
+        summaries,
+        accepted_frac,
+        counts: class_counts,
+        corrected,
+    }
+}
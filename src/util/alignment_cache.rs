@@ -0,0 +1,351 @@
+use crate::util::compact_store::{strand_code, strand_of_code};
+use crate::util::digest_utils;
+use crate::util::duplicates;
+use crate::util::errors::bad_input;
+use crate::util::oarfish_types::{AlnInfo, DiscardTable, InMemoryAlignmentStore, TranscriptInfo};
+use crate::util::second_chance::SecondChanceStats;
+use crate::util::trimming::TrimStats;
+use anyhow::Context;
+use noodles_sam::header::record::value::Map as HeaderMap;
+use noodles_sam::header::record::value::map::ReferenceSequence;
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use swapvec::SwapVec;
+use tracing::info;
+
+/// The magic number at the start of an alignment cache.
+const CACHE_MAGIC: &[u8; 8] = b"OARFALNC";
+/// The version of the layout of the alignment cache.
+const CACHE_FORMAT_VERSION: u32 = 1;
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// The description of the contents of an alignment cache, stored (as JSON) ahead of the
+/// compressed alignments, so that the reference can be set up before they are read.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheHeader {
+    pub oarfish_version: String,
+    /// the name and length of each transcript
+    pub targets: Vec<(String, usize)>,
+    /// the seqcol digest of the reference
+    pub digest: serde_json::Value,
+    /// the filters that were applied to the alignments when they were parsed
+    pub filter_options: serde_json::Value,
+    /// the number of reads with alignments (i.e. of alignment groups)
+    pub num_reads: usize,
+    pub num_alignments: usize,
+    pub num_input_reads: usize,
+    pub num_unique_alignments: usize,
+    /// true if the lane (or read group) of each read is stored
+    pub has_lanes: bool,
+    /// true if the name of each read is stored
+    pub has_names: bool,
+    /// the number of reads in each lane, for `--lanes`
+    pub lane_reads: Option<Vec<usize>>,
+    pub discard_table: DiscardTable,
+    /// the reads supporting each pair of transcripts, for `--rescue-supplementary`
+    pub fusion_pairs: Vec<(u32, u32, u32)>,
+    /// the histogram of the scores of decoy alignments, for `--decoys`
+    pub decoy_score_ratio_hist: Vec<u32>,
+    pub second_chance: Option<SecondChanceStats>,
+    pub trimming: Option<TrimStats>,
+}
+
+impl CacheHeader {
+    /// The header describing the transcripts against which the reads were aligned.
+    pub fn sam_header(&self) -> anyhow::Result<noodles_sam::Header> {
+        let mut header = noodles_sam::Header::builder();
+        for (name, len) in self.targets.iter() {
+            let len = NonZeroUsize::new(*len)
+                .with_context(|| format!("the transcript {} has length 0", name))?;
+            header = header
+                .add_reference_sequence(name.clone(), HeaderMap::<ReferenceSequence>::new(len));
+        }
+        Ok(header.build())
+    }
+
+    pub fn digest(&self) -> anyhow::Result<seqcol_rs::DigestResult> {
+        digest_utils::digest_from_json(&self.digest)
+    }
+
+    /// Log what the cache holds, and how it was made.
+    pub fn log(&self, path: &Path) {
+        info!(
+            "loading {} alignments of {} reads, against {} transcripts, from the alignment cache {} (written by oarfish {}).",
+            self.num_alignments.to_formatted_string(&Locale::en),
+            self.num_reads.to_formatted_string(&Locale::en),
+            self.targets.len().to_formatted_string(&Locale::en),
+            path.display(),
+            self.oarfish_version
+        );
+        info!(
+            "the alignments were filtered when the cache was written, with the filters {}",
+            self.filter_options
+        );
+    }
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0_u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Read the magic number, version and header of the cache at `path`, leaving `reader`
+/// at the start of the compressed alignments.
+fn read_header_from<R: Read>(reader: &mut R, path: &Path) -> anyhow::Result<CacheHeader> {
+    let mut magic = [0_u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .with_context(|| format!("could not read the alignment cache {}", path.display()))?;
+    if &magic != CACHE_MAGIC {
+        return Err(bad_input(format!(
+            "{} is not an oarfish alignment cache",
+            path.display()
+        )));
+    }
+    let version = read_u32(reader)?;
+    if version != CACHE_FORMAT_VERSION {
+        return Err(bad_input(format!(
+            "the alignment cache {} has format version {}, but this version of oarfish reads version {}; re-create it with --save-alignment-cache.",
+            path.display(),
+            version,
+            CACHE_FORMAT_VERSION
+        )));
+    }
+    let mut len = [0_u8; 8];
+    reader.read_exact(&mut len)?;
+    let mut json = vec![0_u8; u64::from_le_bytes(len) as usize];
+    reader.read_exact(&mut json)?;
+    serde_json::from_slice(&json).with_context(|| {
+        format!(
+            "could not parse the header of the alignment cache {}",
+            path.display()
+        )
+    })
+}
+
+/// Read the header of the alignment cache at `path`.
+pub fn read_cache_header(path: &Path) -> anyhow::Result<CacheHeader> {
+    let mut reader = BufReader::new(
+        File::open(path)
+            .with_context(|| format!("could not open the alignment cache {}", path.display()))?,
+    );
+    read_header_from(&mut reader, path)
+}
+
+/// Write the (filtered) alignments of `store`, along with the statistics gathered while
+/// they were parsed and the names of the reads (if `name_vec` holds them), to the alignment
+/// cache at `path`. A cache holds a magic number, a format version and a JSON
+/// [CacheHeader], followed by a zstd stream holding, for each read, its number of
+/// alignments, its length, its lane (if any) and its alignments (the transcript, start,
+/// end, strand and alignment score probability of each), all little-endian, and then the
+/// length and bytes of the name of each read (if any).
+///
+/// Since reading the names consumes `name_vec`, they are returned in a new vector.
+pub fn write_alignment_cache(
+    path: &Path,
+    header: &noodles_sam::Header,
+    seqcol_digest: &seqcol_rs::DigestResult,
+    store: &InMemoryAlignmentStore,
+    name_vec: Option<SwapVec<String>>,
+    lane_reads: Option<&[usize]>,
+) -> anyhow::Result<Option<SwapVec<String>>> {
+    let has_lanes = !store.read_lanes.is_empty();
+    let cache_header = CacheHeader {
+        oarfish_version: env!("CARGO_PKG_VERSION").to_string(),
+        targets: header
+            .reference_sequences()
+            .iter()
+            .map(|(name, rmap)| (name.to_string(), rmap.length().get()))
+            .collect(),
+        digest: seqcol_digest.to_json(),
+        filter_options: serde_json::to_value(&store.filter_opts)?,
+        num_reads: store.len(),
+        num_alignments: store.total_len(),
+        num_input_reads: store.num_input_reads,
+        num_unique_alignments: store.num_unique_alignments,
+        has_lanes,
+        has_names: name_vec.is_some(),
+        lane_reads: lane_reads.map(<[usize]>::to_vec),
+        discard_table: store.discard_table.clone(),
+        fusion_pairs: store
+            .discard_table
+            .chimeras
+            .fusion_pairs
+            .iter()
+            .map(|((a, b), n)| (*a, *b, *n))
+            .collect(),
+        decoy_score_ratio_hist: store.discard_table.decoys.score_ratio_hist.clone(),
+        second_chance: store.second_chance.clone(),
+        trimming: store.trimming.clone(),
+    };
+
+    // the cache is written next to its final location, and moved there once complete
+    let tmp_path = path.to_path_buf().with_additional_extension(".tmp");
+    let mut writer = BufWriter::new(
+        File::create(&tmp_path)
+            .with_context(|| format!("could not create the alignment cache {}", path.display()))?,
+    );
+    writer.write_all(CACHE_MAGIC)?;
+    write_u32(&mut writer, CACHE_FORMAT_VERSION)?;
+    let json = serde_json::to_vec(&cache_header)?;
+    writer.write_all(&(json.len() as u64).to_le_bytes())?;
+    writer.write_all(&json)?;
+
+    let mut encoder = zstd::stream::write::Encoder::new(writer, COMPRESSION_LEVEL)?;
+    for i in 0..store.len() {
+        let (alns, as_probs, _) = store.group(i);
+        write_u32(&mut encoder, alns.len() as u32)?;
+        write_u32(&mut encoder, store.read_lengths[i])?;
+        if has_lanes {
+            encoder.write_all(&store.read_lanes[i].to_le_bytes())?;
+        }
+        for (a, p) in alns.iter().zip(as_probs.iter()) {
+            write_u32(&mut encoder, a.ref_id)?;
+            write_u32(&mut encoder, a.start)?;
+            write_u32(&mut encoder, a.end)?;
+            encoder.write_all(&[strand_code(a.strand) as u8])?;
+            encoder.write_all(&p.to_le_bytes())?;
+        }
+    }
+    let name_vec = match name_vec {
+        Some(name_vec) => {
+            let mut names = duplicates::new_name_vec();
+            for name in name_vec.into_iter() {
+                let name = name.expect("could not extract read name from file");
+                write_u32(&mut encoder, name.len() as u32)?;
+                encoder.write_all(name.as_bytes())?;
+                names
+                    .push(name)
+                    .expect("cannot push name to read name vector");
+            }
+            Some(names)
+        }
+        None => None,
+    };
+    encoder.finish()?.flush()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    info!(
+        "wrote the alignments of {} reads to the alignment cache {}.",
+        cache_header.num_reads.to_formatted_string(&Locale::en),
+        path.display()
+    );
+    Ok(name_vec)
+}
+
+/// The contents of an alignment cache that are not held by the store it is read into.
+pub struct LoadedCache {
+    pub name_vec: Option<SwapVec<String>>,
+    pub lane_reads: Option<Vec<usize>>,
+}
+
+/// Read the alignment cache at `path` into `store` (whose header must be that of the
+/// cache), adding the coverage of the alignments to `txps`. The names of the reads are
+/// read only if `keep_names` is true, in which case the cache must hold them.
+pub fn read_alignment_cache(
+    path: &Path,
+    store: &mut InMemoryAlignmentStore,
+    txps: &mut [TranscriptInfo],
+    keep_names: bool,
+) -> anyhow::Result<LoadedCache> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let cache_header = read_header_from(&mut reader, path)?;
+    if keep_names && !cache_header.has_names {
+        return Err(bad_input(format!(
+            "this run needs the names of the reads, which the alignment cache {} does not hold.",
+            path.display()
+        )));
+    }
+
+    let mut decoder = zstd::stream::read::Decoder::with_buffer(reader)?;
+    let mut alns = Vec::new();
+    let mut as_probs = Vec::new();
+    let corrupt = || {
+        format!(
+            "the alignment cache {} is truncated or corrupt",
+            path.display()
+        )
+    };
+    for _ in 0..cache_header.num_reads {
+        let num_alns = read_u32(&mut decoder).with_context(corrupt)? as usize;
+        let read_len = read_u32(&mut decoder).with_context(corrupt)?;
+        if cache_header.has_lanes {
+            let mut lane = [0_u8; 2];
+            decoder.read_exact(&mut lane).with_context(corrupt)?;
+            store.read_lanes.push(u16::from_le_bytes(lane));
+        }
+        alns.clear();
+        as_probs.clear();
+        for _ in 0..num_alns {
+            let mut rec = [0_u8; 17];
+            decoder.read_exact(&mut rec).with_context(corrupt)?;
+            let field = |i: usize| u32::from_le_bytes(rec[i..i + 4].try_into().expect("4 bytes"));
+            let ref_id = field(0);
+            if ref_id as usize >= txps.len() {
+                anyhow::bail!(corrupt());
+            }
+            alns.push(AlnInfo {
+                ref_id,
+                start: field(4),
+                end: field(8),
+                prob: 0.0_f64,
+                strand: strand_of_code(rec[12] as u32),
+            });
+            as_probs.push(f32::from_le_bytes(rec[13..17].try_into().expect("4 bytes")));
+        }
+        store.add_filtered_group(&alns, &as_probs, read_len, txps);
+    }
+
+    let name_vec = if keep_names {
+        let mut names = duplicates::new_name_vec();
+        let mut name = Vec::new();
+        for _ in 0..cache_header.num_reads {
+            let len = read_u32(&mut decoder).with_context(corrupt)? as usize;
+            name.resize(len, 0);
+            decoder.read_exact(&mut name).with_context(corrupt)?;
+            names
+                .push(String::from_utf8(name.clone()).with_context(corrupt)?)
+                .expect("cannot push name to read name vector");
+        }
+        Some(names)
+    } else {
+        None
+    };
+
+    let CacheHeader {
+        num_input_reads,
+        num_unique_alignments,
+        mut discard_table,
+        fusion_pairs,
+        decoy_score_ratio_hist,
+        second_chance,
+        trimming,
+        lane_reads,
+        ..
+    } = cache_header;
+    discard_table.chimeras.fusion_pairs = fusion_pairs
+        .into_iter()
+        .map(|(a, b, n)| ((a, b), n))
+        .collect();
+    discard_table.decoys.score_ratio_hist = decoy_score_ratio_hist;
+    store.discard_table = discard_table;
+    store.num_input_reads = num_input_reads;
+    store.num_unique_alignments = num_unique_alignments;
+    store.second_chance = second_chance;
+    store.trimming = trimming;
+
+    Ok(LoadedCache {
+        name_vec,
+        lane_reads,
+    })
+}
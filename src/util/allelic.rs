@@ -0,0 +1,473 @@
+use crate::util::eq_classes::EqClasses;
+use crate::util::errors::bad_input;
+use crate::util::provenance;
+use anyhow::Context;
+use needletail::parse_fastx_file;
+use path_tools::WithAdditionalExtension;
+use rustc_hash::FxHashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The number of iterations of the EM that splits the reads of a transcript between its
+/// haplotypes, and the change in the split below which it stops.
+const MAX_SPLIT_ITER: usize = 1000;
+const SPLIT_CONVERGENCE_THRESH: f64 = 1e-8;
+/// The relative difference between the likelihoods of a read for the two haplotypes of a
+/// transcript above which the read is counted as allele-informative.
+const INFORMATIVE_DIFF: f64 = 0.1;
+/// The quantile of the standard normal distribution bounding a 95% interval.
+const Z_95: f64 = 1.959964;
+
+/// A transcript of which the reference holds both haplotypes, as the ids of these.
+#[derive(Debug, Clone)]
+pub struct HaplotypePair {
+    pub name: String,
+    pub haplotypes: [u32; 2],
+}
+
+/// The transcripts of a personalized (diploid) reference whose haplotypes are named by
+/// adding one of two suffixes to the name of the transcript (e.g. `ENST00000335137_h1`
+/// and `ENST00000335137_h2`).
+pub fn haplotype_pairs(
+    txps_name: &[String],
+    suffixes: &[String],
+) -> anyhow::Result<Vec<HaplotypePair>> {
+    let [s1, s2] = suffixes else {
+        return Err(bad_input(format!(
+            "--haplotype-suffixes takes the suffixes of exactly 2 haplotypes, but {} were given",
+            suffixes.len()
+        )));
+    };
+    let mut haps: FxHashMap<&str, [Option<u32>; 2]> = FxHashMap::default();
+    for (i, name) in txps_name.iter().enumerate() {
+        for (h, s) in [s1, s2].into_iter().enumerate() {
+            if let Some(base) = name.strip_suffix(s.as_str()) {
+                haps.entry(base).or_default()[h] = Some(i as u32);
+            }
+        }
+    }
+    let num_unpaired = haps
+        .values()
+        .filter(|h| h[0].is_none() || h[1].is_none())
+        .count();
+    let mut pairs: Vec<HaplotypePair> = haps
+        .into_iter()
+        .filter_map(|(base, h)| match h {
+            [Some(h1), Some(h2)] => Some(HaplotypePair {
+                name: base.to_string(),
+                haplotypes: [h1, h2],
+            }),
+            _ => None,
+        })
+        .collect();
+    pairs.sort_unstable_by_key(|p| p.haplotypes);
+    if pairs.is_empty() {
+        return Err(bad_input(format!(
+            "no transcript has both of the haplotypes named with the suffixes {} and {}",
+            s1, s2
+        )));
+    }
+    info!(
+        "quantifying the haplotypes of {} transcripts ({} others have only one haplotype).",
+        pairs.len(),
+        num_unpaired
+    );
+    Ok(pairs)
+}
+
+/// The allele-specific estimate of a transcript.
+#[derive(Debug, Clone)]
+pub struct AllelicEstimate {
+    /// the reads assigned to the transcript by the EM whose likelihoods differ between
+    /// its haplotypes
+    pub num_informative: f64,
+    /// the fraction of the reads of the transcript from the first haplotype
+    pub ratio: f64,
+    /// the standard error of `ratio`
+    pub ratio_sd: f64,
+}
+
+/// The reads of one equivalence class relevant to a haplotype pair: their number (as
+/// assigned to the pair by the EM) and their likelihoods for each haplotype.
+struct PairReads {
+    weight: f64,
+    lik: [f64; 2],
+}
+
+/// Estimate the allelic ratio of each of the `pairs`, from the equivalence `classes` of the
+/// reads and the abundances `counts` estimated by the EM. The total of each pair is that
+/// of the EM, which is well determined even though the haplotypes share most of their
+/// reads; only its split between the haplotypes is re-estimated, by an EM over the reads
+/// assigned to the pair under a symmetric Beta prior with `pseudocount` reads for each
+/// haplotype. The prior holds the split of a transcript with few allele-informative reads
+/// near 1:1, rather than leaving it to drift with the shared reads.
+pub fn estimate_allelic_ratios(
+    pairs: &[HaplotypePair],
+    classes: &EqClasses,
+    counts: &[f64],
+    pseudocount: f64,
+) -> Vec<AllelicEstimate> {
+    let mut pair_of: FxHashMap<u32, usize> = FxHashMap::default();
+    for (i, p) in pairs.iter().enumerate() {
+        pair_of.insert(p.haplotypes[0], i);
+        pair_of.insert(p.haplotypes[1], i);
+    }
+    let mut reads: Vec<Vec<PairReads>> = (0..pairs.len()).map(|_| Vec::new()).collect();
+    let mut lik_of: FxHashMap<usize, [f64; 2]> = FxHashMap::default();
+    for c in classes.classes.iter() {
+        lik_of.clear();
+        let mut denom = 0.0;
+        for (t, w) in c.txps.iter().zip(c.weights.iter()) {
+            denom += counts[*t as usize] * w;
+            if let Some(&i) = pair_of.get(t) {
+                let h = usize::from(pairs[i].haplotypes[1] == *t);
+                lik_of.entry(i).or_default()[h] = *w;
+            }
+        }
+        if denom <= 0.0 {
+            continue;
+        }
+        for (i, lik) in lik_of.iter() {
+            let [h1, h2] = pairs[*i].haplotypes;
+            let share = (counts[h1 as usize] * lik[0] + counts[h2 as usize] * lik[1]) / denom;
+            if share > 0.0 {
+                reads[*i].push(PairReads {
+                    weight: c.count as f64 * share,
+                    lik: *lik,
+                });
+            }
+        }
+    }
+
+    pairs
+        .iter()
+        .zip(reads.iter())
+        .map(|(p, reads)| {
+            let [h1, h2] = p.haplotypes;
+            let num_reads: f64 = reads.iter().map(|r| r.weight).sum();
+            let num_informative: f64 = reads
+                .iter()
+                .filter(|r| {
+                    let max = r.lik[0].max(r.lik[1]);
+                    max > 0.0 && (r.lik[0] - r.lik[1]).abs() / max > INFORMATIVE_DIFF
+                })
+                .map(|r| r.weight)
+                .sum();
+            // start from the split of the EM
+            let total = counts[h1 as usize] + counts[h2 as usize];
+            let mut ratio = if total > 0.0 {
+                counts[h1 as usize] / total
+            } else {
+                0.5
+            };
+            for _ in 0..MAX_SPLIT_ITER {
+                let mut from_first = 0.0;
+                for r in reads.iter() {
+                    let l1 = ratio * r.lik[0];
+                    let l = l1 + (1.0 - ratio) * r.lik[1];
+                    if l > 0.0 {
+                        from_first += r.weight * l1 / l;
+                    }
+                }
+                let denom = num_reads + 2.0 * pseudocount;
+                if denom <= 0.0 {
+                    break;
+                }
+                let next = (from_first + pseudocount) / denom;
+                let delta = (next - ratio).abs();
+                ratio = next;
+                if delta < SPLIT_CONVERGENCE_THRESH {
+                    break;
+                }
+            }
+            // the standard error from the observed information of the split
+            let mut fisher = 0.0;
+            for r in reads.iter() {
+                let l = ratio * r.lik[0] + (1.0 - ratio) * r.lik[1];
+                if l > 0.0 {
+                    fisher += r.weight * ((r.lik[0] - r.lik[1]) / l).powi(2);
+                }
+            }
+            if pseudocount > 0.0 {
+                fisher += pseudocount / ratio.powi(2) + pseudocount / (1.0 - ratio).powi(2);
+            }
+            // with no information, the split could be anything
+            let ratio_sd = if fisher.is_finite() && fisher > 0.0 {
+                fisher.sqrt().recip()
+            } else {
+                0.5
+            };
+            AllelicEstimate {
+                num_informative,
+                ratio,
+                ratio_sd,
+            }
+        })
+        .collect()
+}
+
+/// Write the allele-specific estimate of each transcript of `pairs` to
+/// `<output>.allelic.tsv`, along with the number of reads of each of its haplotypes
+/// in `counts`.
+pub fn write_allelic(
+    output: &Path,
+    txps_name: &[String],
+    pairs: &[HaplotypePair],
+    estimates: &[AllelicEstimate],
+    counts: &[f64],
+) -> anyhow::Result<()> {
+    let out_path = output
+        .to_path_buf()
+        .with_additional_extension(".allelic.tsv");
+    let mut writer = BufWriter::new(
+        File::create(&out_path)
+            .with_context(|| format!("could not create {}", out_path.display()))?,
+    );
+    provenance::write_tsv_comment(&mut writer)?;
+    writeln!(
+        writer,
+        "tname\thap1\thap2\tnum_reads\tnum_hap1\tnum_hap2\tnum_informative\tallelic_ratio\tratio_sd\tratio_lower\tratio_upper"
+    )?;
+    for (p, e) in pairs.iter().zip(estimates.iter()) {
+        let [h1, h2] = p.haplotypes;
+        let total = counts[h1 as usize] + counts[h2 as usize];
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            p.name,
+            txps_name[h1 as usize],
+            txps_name[h2 as usize],
+            total,
+            total * e.ratio,
+            total * (1.0 - e.ratio),
+            e.num_informative,
+            e.ratio,
+            e.ratio_sd,
+            (e.ratio - Z_95 * e.ratio_sd).max(0.0),
+            (e.ratio + Z_95 * e.ratio_sd).min(1.0)
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// A phased variant of a transcript, as the 0-based position and the reference allele it
+/// replaces, and the allele of each haplotype.
+struct PhasedVariant {
+    pos: usize,
+    reference: Vec<u8>,
+    alleles: [Vec<u8>; 2],
+}
+
+/// The sequence of one haplotype (`h`) of `seq`, with its `variants` (sorted by position)
+/// applied, and the number of variants skipped because they overlap the one before them.
+fn apply_variants(seq: &[u8], variants: &[&PhasedVariant], h: usize) -> (Vec<u8>, usize) {
+    let mut hap = Vec::with_capacity(seq.len());
+    let mut next = 0;
+    let mut skipped = 0;
+    for v in variants {
+        if v.pos < next {
+            skipped += 1;
+            continue;
+        }
+        hap.extend_from_slice(&seq[next..v.pos]);
+        hap.extend_from_slice(&v.alleles[h]);
+        next = v.pos + v.reference.len();
+    }
+    hap.extend_from_slice(&seq[next..]);
+    (hap, skipped)
+}
+
+/// Open the (possibly gzip- or bgzip-compressed) VCF file `path`.
+fn open_vcf(path: &Path) -> anyhow::Result<Box<dyn BufRead>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let gzipped = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    Ok(if gzipped {
+        Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    })
+}
+
+/// Read the heterozygous phased variants of the first sample of the VCF file `vcf`, whose
+/// coordinates are those of the transcripts, keyed by transcript. Variants that do not
+/// pass the filters, are unphased or have symbolic alleles are skipped.
+fn read_phased_variants(vcf: &Path) -> anyhow::Result<FxHashMap<String, Vec<PhasedVariant>>> {
+    let mut variants: FxHashMap<String, Vec<PhasedVariant>> = FxHashMap::default();
+    let (mut num_unphased, mut num_filtered) = (0_usize, 0_usize);
+    for (lno, line) in open_vcf(vcf)?.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 10 {
+            return Err(bad_input(format!(
+                "line {} of {} has no genotype (a sample column is required)",
+                lno + 1,
+                vcf.display()
+            )));
+        }
+        if fields[6] != "PASS" && fields[6] != "." {
+            num_filtered += 1;
+            continue;
+        }
+        let Some(gt_idx) = fields[8].split(':').position(|f| f == "GT") else {
+            num_unphased += 1;
+            continue;
+        };
+        let gt = fields[9].split(':').nth(gt_idx).unwrap_or(".");
+        let Some((a, b)) = gt.split_once('|') else {
+            if let Some((a, b)) = gt.split_once('/')
+                && a != b
+            {
+                num_unphased += 1;
+            }
+            continue;
+        };
+        if a == b {
+            continue;
+        }
+        let pos: usize = fields[1].parse().with_context(|| {
+            format!(
+                "invalid position {} on line {} of {}",
+                fields[1],
+                lno + 1,
+                vcf.display()
+            )
+        })?;
+        let reference = fields[3].as_bytes().to_ascii_uppercase();
+        let mut alts = vec![reference.clone()];
+        alts.extend(
+            fields[4]
+                .split(',')
+                .map(|a| a.as_bytes().to_ascii_uppercase()),
+        );
+        // symbolic alleles (e.g. `<DEL>`, `*` or breakends) cannot be applied
+        let allele = |i: &str| -> Option<Vec<u8>> {
+            let a = alts.get(i.parse::<usize>().ok()?)?;
+            let symbolic = a
+                .iter()
+                .any(|b| matches!(b, b'<' | b'*' | b'[' | b']' | b'.'));
+            (!symbolic).then(|| a.clone())
+        };
+        let (Some(a), Some(b)) = (allele(a), allele(b)) else {
+            num_filtered += 1;
+            continue;
+        };
+        variants
+            .entry(fields[0].to_string())
+            .or_default()
+            .push(PhasedVariant {
+                pos: pos.saturating_sub(1),
+                reference,
+                alleles: [a, b],
+            });
+    }
+    if num_unphased > 0 {
+        warn!(
+            "skipped {} heterozygous variants that are not phased.",
+            num_unphased
+        );
+    }
+    if num_filtered > 0 {
+        warn!(
+            "skipped {} variants that failed the filters or have symbolic alleles.",
+            num_filtered
+        );
+    }
+    for v in variants.values_mut() {
+        v.sort_by_key(|v| v.pos);
+    }
+    Ok(variants)
+}
+
+/// Write the diploid transcriptome of the sample whose phased variants (in the coordinates
+/// of the transcripts) are in `vcf` to `output`: the transcripts of `reference` with a
+/// heterozygous variant are written once for each haplotype, named with the `suffixes`,
+/// and the others (whose haplotypes are identical) once, under their own name.
+pub fn write_haplotype_transcriptome(
+    reference: &Path,
+    vcf: &Path,
+    output: &PathBuf,
+    suffixes: &[String],
+) -> anyhow::Result<()> {
+    let [s1, s2] = suffixes else {
+        return Err(bad_input(format!(
+            "--haplotype-suffixes takes the suffixes of exactly 2 haplotypes, but {} were given",
+            suffixes.len()
+        )));
+    };
+    let variants = read_phased_variants(vcf)?;
+    let mut reader = parse_fastx_file(reference)
+        .with_context(|| format!("could not read reference {}", reference.display()))?;
+    let mut writer = BufWriter::new(
+        File::create(output).with_context(|| format!("could not create {}", output.display()))?,
+    );
+    let (mut num_txps, mut num_phased, mut num_skipped, mut num_mismatched) = (0, 0, 0, 0);
+    while let Some(rec) = reader.next() {
+        let rec = rec?;
+        num_txps += 1;
+        // the name of the sequence is the first word of the header line
+        let id = rec.id();
+        let name = id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id);
+        let name = String::from_utf8_lossy(name);
+        let seq = rec.seq();
+        let txp_variants: Vec<&PhasedVariant> = variants
+            .get(name.as_ref())
+            .map(|vs| {
+                vs.iter()
+                    .filter(|v| {
+                        let matches = seq
+                            .get(v.pos..v.pos + v.reference.len())
+                            .is_some_and(|r| r.eq_ignore_ascii_case(&v.reference));
+                        if !matches {
+                            num_mismatched += 1;
+                        }
+                        matches
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        if txp_variants.is_empty() {
+            writeln!(writer, ">{}", name)?;
+            writer.write_all(&seq)?;
+            writeln!(writer)?;
+            continue;
+        }
+        num_phased += 1;
+        for (h, s) in [s1, s2].into_iter().enumerate() {
+            // both haplotypes skip the same variants
+            let (hap, skipped) = apply_variants(&seq, &txp_variants, h);
+            if h == 0 {
+                num_skipped += skipped;
+            }
+            writeln!(writer, ">{}{}", name, s)?;
+            writer.write_all(&hap)?;
+            writeln!(writer)?;
+        }
+    }
+    writer.flush()?;
+    if num_mismatched > 0 {
+        warn!(
+            "skipped {} variants whose reference allele differs from the transcript sequence.",
+            num_mismatched
+        );
+    }
+    if num_skipped > 0 {
+        warn!(
+            "skipped {} variants overlapping another variant.",
+            num_skipped
+        );
+    }
+    info!(
+        "wrote both haplotypes of {} of the {} transcripts to {}.",
+        num_phased,
+        num_txps,
+        output.display()
+    );
+    Ok(())
+}
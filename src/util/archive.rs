@@ -0,0 +1,559 @@
+use anyhow::{Context, bail};
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::info;
+
+/// The (maximum) number of uncompressed bytes held in each zstd frame of an archive;
+/// a member can be read by decompressing only the frames that hold it.
+const FRAME_SIZE: usize = 1 << 20;
+const COMPRESSION_LEVEL: i32 = 3;
+/// The version of the layout of the archive index.
+const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+/// The magic number of the skippable frame holding the seek table, and of the seek
+/// table footer, as defined by the zstd seekable format.
+const SEEK_TABLE_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+/// The length of the seek table footer (number of frames, descriptor and magic).
+const SEEK_TABLE_FOOTER_LEN: u64 = 9;
+/// The seek table descriptor flag indicating that each entry holds a checksum.
+const SEEK_TABLE_CHECKSUM_FLAG: u8 = 0x80;
+/// The magic number of the skippable frame holding the archive index, which
+/// directly precedes the seek table.
+const INDEX_MAGIC: u32 = 0x184D2A50;
+
+/// The log of the run, captured (if requested) so that it can be added to the archive.
+static CAPTURED_LOG: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// Start capturing the log of this run, to be stored in the archive of its output, if
+/// `enabled`; otherwise, stop capturing (and discard) the log of an earlier run.
+pub fn capture_log(enabled: bool) {
+    *CAPTURED_LOG.lock().unwrap() = enabled.then(Vec::new);
+}
+
+/// A log writer that appends to the captured log (and discards
+/// everything unless [capture_log] has been called).
+pub struct LogCapture;
+
+impl Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(log) = CAPTURED_LOG.lock().unwrap().as_mut() {
+            log.extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A file stored in an archive, at `offset` in the (uncompressed) archive contents.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveMember {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchiveIndex {
+    format_version: u32,
+    oarfish_version: String,
+    members: Vec<ArchiveMember>,
+}
+
+/// The compressed and uncompressed size of a zstd frame of the archive.
+#[derive(Debug, Clone, Copy)]
+struct FrameSizes {
+    compressed: u32,
+    decompressed: u32,
+}
+
+/// Writes an archive in the zstd seekable format; the members are concatenated and
+/// compressed in independent frames of at most [FRAME_SIZE] bytes (each member starting
+/// a new frame), followed by a skippable frame holding the index of the members and then
+/// by the seek table. The archive can therefore be decompressed by any zstd decompressor,
+/// but the members can also be read individually without decompressing the others.
+struct ArchiveWriter<W: Write> {
+    writer: W,
+    frames: Vec<FrameSizes>,
+    members: Vec<ArchiveMember>,
+    offset: u64,
+}
+
+fn write_skippable_frame<W: Write>(writer: &mut W, magic: u32, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&magic.to_le_bytes())?;
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            frames: Vec::new(),
+            members: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    fn add_member<R: Read>(&mut self, name: &str, mut reader: R) -> anyhow::Result<()> {
+        let start = self.offset;
+        let mut buf = Vec::with_capacity(FRAME_SIZE);
+        loop {
+            buf.clear();
+            (&mut reader)
+                .take(FRAME_SIZE as u64)
+                .read_to_end(&mut buf)?;
+            if buf.is_empty() {
+                break;
+            }
+            let compressed = zstd::bulk::compress(&buf, COMPRESSION_LEVEL)?;
+            self.writer.write_all(&compressed)?;
+            self.frames.push(FrameSizes {
+                compressed: compressed.len() as u32,
+                decompressed: buf.len() as u32,
+            });
+            self.offset += buf.len() as u64;
+        }
+        self.members.push(ArchiveMember {
+            name: name.to_owned(),
+            offset: start,
+            size: self.offset - start,
+        });
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<Vec<ArchiveMember>> {
+        let index = ArchiveIndex {
+            format_version: ARCHIVE_FORMAT_VERSION,
+            oarfish_version: env!("CARGO_PKG_VERSION").to_owned(),
+            members: self.members,
+        };
+        write_skippable_frame(&mut self.writer, INDEX_MAGIC, &serde_json::to_vec(&index)?)?;
+
+        let mut seek_table = Vec::with_capacity(8 * self.frames.len() + 9);
+        for f in self.frames.iter() {
+            seek_table.extend_from_slice(&f.compressed.to_le_bytes());
+            seek_table.extend_from_slice(&f.decompressed.to_le_bytes());
+        }
+        seek_table.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        // no checksums are stored
+        seek_table.push(0_u8);
+        seek_table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        write_skippable_frame(&mut self.writer, SEEK_TABLE_MAGIC, &seek_table)?;
+        self.writer.flush()?;
+        Ok(index.members)
+    }
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+/// Reads the members of an archive written by [archive_output].
+pub struct ArchiveReader {
+    file: File,
+    /// the compressed and uncompressed offset, and the sizes, of each frame
+    frames: Vec<(u64, u64, FrameSizes)>,
+    index: ArchiveIndex,
+}
+
+impl ArchiveReader {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+        let not_archive = || format!("{} is not an oarfish archive", path.display());
+        let corrupt = || format!("the archive {} is corrupt or truncated", path.display());
+
+        // the seek table footer
+        let file_len = file.seek(SeekFrom::End(0))?;
+        anyhow::ensure!(file_len >= SEEK_TABLE_FOOTER_LEN + 8, not_archive());
+        let mut footer = [0_u8; SEEK_TABLE_FOOTER_LEN as usize];
+        file.seek(SeekFrom::End(-(SEEK_TABLE_FOOTER_LEN as i64)))?;
+        file.read_exact(&mut footer)?;
+        anyhow::ensure!(read_u32(&footer, 5) == SEEKABLE_MAGIC, not_archive());
+        let num_frames = read_u32(&footer, 0) as u64;
+        let entry_len = if footer[4] & SEEK_TABLE_CHECKSUM_FLAG != 0 {
+            12
+        } else {
+            8
+        };
+
+        // the seek table
+        let table_len = 8 + num_frames * entry_len + SEEK_TABLE_FOOTER_LEN;
+        anyhow::ensure!(file_len >= table_len, not_archive());
+        let mut table = vec![0_u8; (table_len - SEEK_TABLE_FOOTER_LEN) as usize];
+        file.seek(SeekFrom::End(-(table_len as i64)))?;
+        file.read_exact(&mut table)?;
+        anyhow::ensure!(read_u32(&table, 0) == SEEK_TABLE_MAGIC, not_archive());
+        let mut frames = Vec::with_capacity(num_frames as usize);
+        let (mut coffset, mut doffset) = (0_u64, 0_u64);
+        for i in 0..num_frames as usize {
+            let pos = 8 + i * entry_len as usize;
+            let sizes = FrameSizes {
+                compressed: read_u32(&table, pos),
+                decompressed: read_u32(&table, pos + 4),
+            };
+            // the frames are never larger than written, which bounds what is allocated to
+            // read them
+            anyhow::ensure!(sizes.decompressed as usize <= FRAME_SIZE, corrupt());
+            frames.push((coffset, doffset, sizes));
+            coffset += sizes.compressed as u64;
+            doffset += sizes.decompressed as u64;
+        }
+
+        // the index directly follows the compressed frames, and fills the space up to the
+        // seek table
+        let index_end = file_len - table_len;
+        anyhow::ensure!(coffset + 8 <= index_end, corrupt());
+        let mut index_header = [0_u8; 8];
+        file.seek(SeekFrom::Start(coffset))?;
+        file.read_exact(&mut index_header)?;
+        anyhow::ensure!(read_u32(&index_header, 0) == INDEX_MAGIC, not_archive());
+        let index_len = read_u32(&index_header, 4) as u64;
+        anyhow::ensure!(index_len == index_end - coffset - 8, corrupt());
+        let mut index_buf = vec![0_u8; index_len as usize];
+        file.read_exact(&mut index_buf)?;
+        let index: ArchiveIndex = serde_json::from_slice(&index_buf)
+            .with_context(|| format!("could not parse the index of {}", path.display()))?;
+        if index.format_version > ARCHIVE_FORMAT_VERSION {
+            bail!(
+                "{} has archive format version {}, but this version of oarfish only understands versions up to {}; please upgrade oarfish.",
+                path.display(),
+                index.format_version,
+                ARCHIVE_FORMAT_VERSION
+            );
+        }
+        anyhow::ensure!(
+            index.members.iter().all(|m| m
+                .offset
+                .checked_add(m.size)
+                .is_some_and(|end| end <= doffset)),
+            corrupt()
+        );
+        Ok(Self {
+            file,
+            frames,
+            index,
+        })
+    }
+
+    pub fn members(&self) -> &[ArchiveMember] {
+        &self.index.members
+    }
+
+    /// Read the contents of the `i`-th member, decompressing only the frames that hold it.
+    pub fn read_member(&mut self, i: usize) -> anyhow::Result<Vec<u8>> {
+        let m = &self.index.members[i];
+        let (start, end) = (m.offset, m.offset + m.size);
+        let mut data = Vec::with_capacity(m.size as usize);
+        let mut compressed = Vec::new();
+        for (coffset, doffset, sizes) in self.frames.iter() {
+            let frame_end = doffset + sizes.decompressed as u64;
+            if frame_end <= start || *doffset >= end {
+                continue;
+            }
+            compressed.resize(sizes.compressed as usize, 0);
+            self.file.seek(SeekFrom::Start(*coffset))?;
+            self.file.read_exact(&mut compressed)?;
+            let frame = zstd::bulk::decompress(&compressed, sizes.decompressed as usize)?;
+            anyhow::ensure!(
+                frame.len() == sizes.decompressed as usize,
+                "the archive member {} is corrupt",
+                m.name
+            );
+            let lo = start.saturating_sub(*doffset) as usize;
+            let hi = (end.min(frame_end) - doffset) as usize;
+            data.extend_from_slice(&frame[lo..hi]);
+        }
+        anyhow::ensure!(
+            data.len() as u64 == m.size,
+            "the archive member {} is truncated",
+            m.name
+        );
+        Ok(data)
+    }
+}
+
+/// The path of the archive of the output with prefix `output`.
+pub fn archive_path(output: &Path) -> PathBuf {
+    output
+        .to_path_buf()
+        .with_additional_extension(".archive.zst")
+}
+
+/// Pack all of the files written with the output prefix `output` (i.e. the files
+/// `<output>.*`), along with the captured log of the run, into the seekable archive
+/// `<output>.archive.zst`, and remove the packed files once the archive has been
+/// checked.
+pub fn archive_output(output: &Path) -> anyhow::Result<()> {
+    let dir = match output.parent() {
+        Some(p) if p != Path::new("") => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let Some(prefix) = output.file_name().and_then(|n| n.to_str()) else {
+        bail!(
+            "could not determine the file name of the output prefix {}",
+            output.display()
+        );
+    };
+    let archive = archive_path(output);
+    let archive_name = archive.file_name().map(|n| n.to_os_string());
+    let member_prefix = format!("{}.", prefix);
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if Some(&name) == archive_name.as_ref() || !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(name) = name.to_str()
+            && name.starts_with(&member_prefix)
+        {
+            files.push(name.to_owned());
+        }
+    }
+    files.sort();
+
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&archive)
+        .with_context(|| format!("could not create {}", archive.display()))?;
+    let mut writer = ArchiveWriter::new(BufWriter::new(write));
+    for name in files.iter() {
+        let path = dir.join(name);
+        let file =
+            File::open(&path).with_context(|| format!("could not open {}", path.display()))?;
+        writer.add_member(name, file)?;
+    }
+    if let Some(log) = CAPTURED_LOG.lock().unwrap().as_ref() {
+        writer.add_member(&format!("{}.log", prefix), log.as_slice())?;
+    }
+    let members = writer.finish()?;
+
+    // check that the archive can be read back before removing the packed files
+    let reader = ArchiveReader::open(&archive)?;
+    anyhow::ensure!(
+        reader.members().len() == members.len()
+            && reader
+                .members()
+                .iter()
+                .zip(members.iter())
+                .all(|(a, b)| a.name == b.name && a.size == b.size),
+        "the archive {} could not be verified; the output files have been kept",
+        archive.display()
+    );
+    for name in files.iter() {
+        std::fs::remove_file(dir.join(name))?;
+    }
+    info!(
+        "packed {} output files ({} bytes) into {}.",
+        members.len().to_formatted_string(&Locale::en),
+        members
+            .iter()
+            .map(|m| m.size)
+            .sum::<u64>()
+            .to_formatted_string(&Locale::en),
+        archive.display()
+    );
+    Ok(())
+}
+
+/// List the members of `archive` or, unless `list` is set, extract the members named
+/// `members` (or all members, if none are named) into `output_dir`.
+pub fn unpack_archive(
+    archive: &Path,
+    members: &[String],
+    output_dir: &Path,
+    list: bool,
+) -> anyhow::Result<()> {
+    let mut reader = ArchiveReader::open(archive)?;
+    if list {
+        for m in reader.members() {
+            println!("{}\t{}", m.name, m.size);
+        }
+        return Ok(());
+    }
+
+    for name in members {
+        anyhow::ensure!(
+            reader.members().iter().any(|m| m.name == *name),
+            "{} is not a member of {}",
+            name,
+            archive.display()
+        );
+    }
+    create_dir_all(output_dir)?;
+    let selected: Vec<usize> = (0..reader.members().len())
+        .filter(|i| members.is_empty() || members.contains(&reader.members()[*i].name))
+        .collect();
+    for i in selected.iter() {
+        let data = reader.read_member(*i)?;
+        let name = &reader.members()[*i].name;
+        // members are plain file names, and are never written outside of `output_dir`
+        anyhow::ensure!(
+            Path::new(name)
+                .file_name()
+                .is_some_and(|n| n == name.as_str()),
+            "the archive member {:?} is not a plain file name",
+            name
+        );
+        let mut out = File::create(output_dir.join(name))?;
+        out.write_all(&data)?;
+    }
+    info!(
+        "extracted {} files from {} into {}.",
+        selected.len().to_formatted_string(&Locale::en),
+        archive.display(),
+        output_dir.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("oarfish-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn members_round_trip() {
+        let path = temp_path("archive-round-trip.zst");
+        // a member spanning several frames, an empty member and a small one
+        let large: Vec<u8> = (0..(2 * FRAME_SIZE + 17))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let small = b"tname\tlen\tnum_reads\n".to_vec();
+        let mut writer = ArchiveWriter::new(BufWriter::new(File::create(&path).unwrap()));
+        writer.add_member("large.bin", &large[..]).unwrap();
+        writer.add_member("empty.txt", &b""[..]).unwrap();
+        writer.add_member("small.quant", &small[..]).unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = ArchiveReader::open(&path).unwrap();
+        let names: Vec<&str> = reader.members().iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["large.bin", "empty.txt", "small.quant"]);
+        let offsets: Vec<(u64, u64)> = reader
+            .members()
+            .iter()
+            .map(|m| (m.offset, m.size))
+            .collect();
+        let large_len = large.len() as u64;
+        assert_eq!(
+            offsets,
+            vec![
+                (0, large_len),
+                (large_len, 0),
+                (large_len, small.len() as u64)
+            ]
+        );
+        assert_eq!(reader.read_member(2).unwrap(), small);
+        assert_eq!(reader.read_member(1).unwrap(), Vec::<u8>::new());
+        assert_eq!(reader.read_member(0).unwrap(), large);
+
+        // the archive is also a plain zstd stream of the concatenated members
+        let all = zstd::stream::decode_all(File::open(&path).unwrap()).unwrap();
+        assert_eq!(all, [large, small].concat());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_newer_format_versions() {
+        let path = temp_path("archive-version.zst");
+        let mut file = File::create(&path).unwrap();
+        let index = ArchiveIndex {
+            format_version: ARCHIVE_FORMAT_VERSION + 1,
+            oarfish_version: env!("CARGO_PKG_VERSION").to_owned(),
+            members: vec![],
+        };
+        write_skippable_frame(&mut file, INDEX_MAGIC, &serde_json::to_vec(&index).unwrap())
+            .unwrap();
+        let mut seek_table = 0_u32.to_le_bytes().to_vec();
+        seek_table.push(0_u8);
+        seek_table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        write_skippable_frame(&mut file, SEEK_TABLE_MAGIC, &seek_table).unwrap();
+        drop(file);
+
+        let err = ArchiveReader::open(&path).err().unwrap();
+        assert!(err.to_string().contains("archive format version"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Write a small archive of two members to `path`, returning its contents.
+    fn small_archive(path: &Path) -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(BufWriter::new(File::create(path).unwrap()));
+        writer
+            .add_member("a.quant", &b"tname\tlen\tnum_reads\n"[..])
+            .unwrap();
+        writer.add_member("b.txt", &[7_u8; 1000][..]).unwrap();
+        writer.finish().unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn rejects_corrupt_frame_sizes() {
+        let path = temp_path("archive-frame-sizes.zst");
+        let mut bytes = small_archive(&path);
+        // claim that the first frame decompresses to more bytes than it holds, so that the
+        // second member seems to start within it
+        let pos = bytes.len() - (8 + 2 * 8 + SEEK_TABLE_FOOTER_LEN as usize) + 8 + 4;
+        let size = read_u32(&bytes, pos) + 10;
+        bytes[pos..pos + 4].copy_from_slice(&size.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+
+        // the members end within the frames claimed by the seek table, so the archive
+        // opens, but its members can't be read
+        let mut reader = ArchiveReader::open(&path).unwrap();
+        for i in 0..2 {
+            let err = reader.read_member(i).err().unwrap();
+            assert!(err.to_string().contains("is corrupt"));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_corrupt_index_lengths() {
+        let path = temp_path("archive-index-len.zst");
+        let mut bytes = small_archive(&path);
+        // the length of the index, which follows the two frames
+        let frames_len = ArchiveReader::open(&path)
+            .unwrap()
+            .frames
+            .iter()
+            .map(|f| f.2.compressed as usize)
+            .sum::<usize>();
+        bytes[frames_len + 4..frames_len + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let err = ArchiveReader::open(&path).err().unwrap();
+        assert!(err.to_string().contains("corrupt or truncated"));
+
+        // a truncated archive (here, with its frames cut short) is rejected too
+        let bytes = small_archive(&path);
+        let table_start = bytes.len() - (8 + 2 * 8 + SEEK_TABLE_FOOTER_LEN as usize);
+        let truncated = [&bytes[..frames_len / 2], &bytes[table_start..]].concat();
+        std::fs::write(&path, truncated).unwrap();
+        assert!(ArchiveReader::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = temp_path("not-an-archive.zst");
+        std::fs::write(&path, zstd::bulk::compress(b"not an archive", 3).unwrap()).unwrap();
+        let err = ArchiveReader::open(&path).err().unwrap();
+        assert!(err.to_string().contains("is not an oarfish archive"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
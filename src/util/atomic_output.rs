@@ -0,0 +1,153 @@
+use crate::util::provenance;
+use anyhow::{Context, bail};
+use path_tools::WithAdditionalExtension;
+use serde_json::json;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The extension of the sentinel file written once every output of a run is in place.
+pub const COMPLETE_EXT: &str = ".oarfish_complete";
+
+/// The outputs of a run, written to a staging directory next to their final location
+/// and moved into place only once the run has succeeded, so that the outputs of a
+/// crashed or killed run are never mistaken for complete ones.
+pub struct OutputStaging {
+    /// the output prefix requested by the user
+    output: PathBuf,
+    /// the directory holding the outputs while the run is in progress
+    staging_dir: PathBuf,
+}
+
+/// The directory holding the output prefix `output`.
+fn output_dir(output: &Path) -> PathBuf {
+    match output.parent() {
+        Some(p) if p != Path::new("") => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+impl OutputStaging {
+    /// Prepare the staging of the outputs with prefix `output`. The sentinel of any previous
+    /// run with this prefix is removed. The staging directory of an interrupted run is
+    /// cleared, unless the run is `resume`d, in which case its checkpoint is kept.
+    pub fn new(output: &Path, resume: bool) -> anyhow::Result<Self> {
+        let Some(name) = output.file_name().and_then(|n| n.to_str()) else {
+            bail!(
+                "could not determine the file name of the output prefix {}",
+                output.display()
+            );
+        };
+        let dir = output_dir(output);
+        fs::create_dir_all(&dir)?;
+
+        let sentinel = output.with_additional_extension(COMPLETE_EXT);
+        if sentinel.exists() {
+            fs::remove_file(&sentinel)
+                .with_context(|| format!("could not remove {}", sentinel.display()))?;
+        }
+
+        let staging_dir = dir.join(format!(".{}.oarfish_tmp", name));
+        if staging_dir.exists() && !resume {
+            warn!(
+                "removing the partial outputs of an earlier run in {}.",
+                staging_dir.display()
+            );
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        fs::create_dir_all(&staging_dir)?;
+        Ok(Self {
+            output: output.to_path_buf(),
+            staging_dir,
+        })
+    }
+
+    /// The output prefix to which the run should write its outputs.
+    pub fn staged_output(&self) -> PathBuf {
+        self.staging_dir
+            .join(self.output.file_name().expect("output has a file name"))
+    }
+
+    /// Move every output of the run into its final location, then write the
+    /// `<output>.oarfish_complete` sentinel, recording the digest of the reference
+    /// (if known), the files of the run and its provenance.
+    pub fn commit(self) -> anyhow::Result<()> {
+        let dir = output_dir(&self.output);
+        let digest = read_digest(&self.staged_output());
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.staging_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let dest = dir.join(&name);
+            if dest.is_dir() && !dest.is_symlink() {
+                fs::remove_dir_all(&dest)?;
+            }
+            fs::rename(entry.path(), &dest).with_context(|| {
+                format!(
+                    "could not move {} to {}",
+                    entry.path().display(),
+                    dest.display()
+                )
+            })?;
+            files.push(name.to_string_lossy().into_owned());
+        }
+        files.sort();
+        fs::remove_dir(&self.staging_dir)?;
+
+        // the sentinel is itself written atomically, so that it is either absent or complete
+        let sentinel = self.output.with_additional_extension(COMPLETE_EXT);
+        let tmp = sentinel.with_additional_extension(".tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer_pretty(
+                &mut writer,
+                &json!({
+                    "oarfish_version": env!("CARGO_PKG_VERSION"),
+                    "digest": digest,
+                    "files": files,
+                    "provenance": provenance::json(),
+                }),
+            )?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp, &sentinel)?;
+        info!(
+            "wrote {} outputs; the run is marked complete by {}.",
+            files.len(),
+            sentinel.display()
+        );
+        Ok(())
+    }
+}
+
+/// The digest of the reference recorded in the `meta_info.json` of the staged output
+/// `output`, or of the first output of the run that has one (e.g. when the output is
+/// split by read group).
+fn read_digest(output: &Path) -> serde_json::Value {
+    let read = |path: &Path| -> Option<serde_json::Value> {
+        let file = File::open(path).ok()?;
+        let info: serde_json::Value = serde_json::from_reader(BufReader::new(file)).ok()?;
+        info.get("digest").cloned()
+    };
+    if let Some(digest) = read(&output.with_additional_extension(".meta_info.json")) {
+        return digest;
+    }
+    let mut infos: Vec<PathBuf> = fs::read_dir(output_dir(output))
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.ends_with(".meta_info.json"))
+        })
+        .collect();
+    infos.sort();
+    infos
+        .iter()
+        .find_map(|p| read(p))
+        .unwrap_or(serde_json::Value::Null)
+}
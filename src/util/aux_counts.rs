@@ -32,7 +32,7 @@ pub fn get_aux_counts(
 
     for (alns, probs, coverage_probs) in store.iter() {
         let is_unique = alns.len() == 1;
-        for (a, _p, _cp) in izip!(alns, probs, coverage_probs) {
+        for (a, _p, _cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
             // Compute the probability of assignment of the
             // current read based on this alignment and the
             // target's estimated abundance.
@@ -0,0 +1,303 @@
+use crate::util::reorder_buffer::ReorderBuffer;
+use crate::util::thread_alloc::{ThreadBalancer, queue_fill};
+use crossbeam::channel::{Receiver, Sender, bounded};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read};
+use std::sync::Arc;
+
+/// The number of blocks that may wait in each queue (before and after decompression)
+/// per decompression worker.
+const BLOCKS_PER_WORKER: usize = 8;
+/// The length of the fixed part of the header of a BGZF block (up to `XLEN`).
+const HEADER_LEN: usize = 12;
+/// The length of the trailer (CRC32 and uncompressed size) of a BGZF block.
+const TRAILER_LEN: usize = 8;
+
+/// A block of the stream and its index in the stream; compressed before the workers,
+/// and decompressed after them.
+type Block = (u64, io::Result<Vec<u8>>);
+
+/// A reader of a BGZF-compressed (e.g. `bam`) stream, whose blocks are decompressed by a
+/// pool of worker threads. Unlike [noodles_bgzf::MultithreadedReader], the number of
+/// workers that run is adjusted at runtime by a [ThreadBalancer], from the fill of the
+/// queues around them: workers are paused when the queue of compressed blocks runs dry
+/// (reading the input is the bottleneck) or the queue of decompressed blocks backs up
+/// (parsing, or the stages after it, are the bottleneck), and resumed when the queue of
+/// compressed blocks backs up. The decompressed blocks are returned in order.
+pub struct BalancedBgzfReader {
+    blocks: Receiver<Block>,
+    reorder: ReorderBuffer<io::Result<Vec<u8>>>,
+    ready: VecDeque<io::Result<Vec<u8>>>,
+    block: Vec<u8>,
+    pos: usize,
+    balancer: Arc<ThreadBalancer>,
+}
+
+impl BalancedBgzfReader {
+    /// Read the BGZF stream `inner` with up to `max_workers` decompression workers, of
+    /// which `initial_workers` are active at the start.
+    pub fn new<R: Read + Send + 'static>(
+        inner: R,
+        initial_workers: usize,
+        max_workers: usize,
+    ) -> Self {
+        let max_workers = max_workers.max(1);
+        let balancer =
+            ThreadBalancer::with_active("bgzf decompression", max_workers, initial_workers);
+        let (raw_tx, raw_rx) = bounded::<Block>(BLOCKS_PER_WORKER * max_workers);
+        let (out_tx, out_rx) = bounded::<Block>(BLOCKS_PER_WORKER * max_workers);
+
+        // once the input is exhausted (or can't be read), all of the workers are
+        // released, so that they drain the queue of compressed blocks and exit
+        let reader_balancer = balancer.clone();
+        std::thread::spawn(move || {
+            let _release_workers = reader_balancer.release_on_drop();
+            read_blocks(inner, &raw_tx);
+        });
+
+        for worker_id in 0..max_workers {
+            let balancer = balancer.clone();
+            let raw_rx = raw_rx.clone();
+            let out_tx = out_tx.clone();
+            std::thread::spawn(move || {
+                loop {
+                    balancer.wait_turn(worker_id);
+                    let Ok((i, block)) = raw_rx.recv() else {
+                        break;
+                    };
+                    let block = block.and_then(|b| inflate_block(&b));
+                    if out_tx.send((i, block)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let monitor_balancer = balancer.clone();
+        let monitor_out_rx = out_rx.clone();
+        std::thread::spawn(move || {
+            monitor_balancer.monitor(|| {
+                (
+                    queue_fill(raw_rx.len(), raw_rx.capacity()),
+                    Some(queue_fill(monitor_out_rx.len(), monitor_out_rx.capacity())),
+                )
+            });
+        });
+
+        Self {
+            blocks: out_rx,
+            reorder: ReorderBuffer::new(),
+            ready: VecDeque::new(),
+            block: Vec::new(),
+            pos: 0,
+            balancer,
+        }
+    }
+
+    /// The next decompressed block of the stream, or [None] once all have been returned.
+    fn next_block(&mut self) -> Option<io::Result<Vec<u8>>> {
+        loop {
+            if let Some(block) = self.ready.pop_front() {
+                return Some(block);
+            }
+            let (i, block) = self.blocks.recv().ok()?;
+            self.ready.extend(self.reorder.push(i, block));
+        }
+    }
+}
+
+impl Drop for BalancedBgzfReader {
+    fn drop(&mut self) {
+        // wake any paused workers, so that they see that the stream is no longer read
+        self.balancer.release_all();
+    }
+}
+
+impl BufRead for BalancedBgzfReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // skip over empty blocks (e.g. the end-of-file marker)
+        while self.pos >= self.block.len() {
+            match self.next_block() {
+                Some(block) => {
+                    self.block = block?;
+                    self.pos = 0;
+                }
+                None => break,
+            }
+        }
+        Ok(&self.block[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.block.len());
+    }
+}
+
+impl Read for BalancedBgzfReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let avail = self.fill_buf()?;
+            let n = avail.len().min(buf.len());
+            buf[..n].copy_from_slice(&avail[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Send the (compressed) blocks of `inner`, in order, to `blocks`, stopping after the
+/// last block, on the first error, or once the blocks are no longer received.
+fn read_blocks<R: Read>(mut inner: R, blocks: &Sender<Block>) {
+    for i in 0_u64.. {
+        let block = match read_block(&mut inner) {
+            Ok(Some(b)) => Ok(b),
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        let failed = block.is_err();
+        if blocks.send((i, block)).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Fill `buf` from `inner`, returning the number of bytes read, which is less than the
+/// length of `buf` only at the end of the stream.
+fn read_up_to<R: Read>(inner: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match inner.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Read the next (compressed) block of `inner`, or [None] at the end of the stream.
+fn read_block<R: Read>(inner: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0_u8; HEADER_LEN];
+    match read_up_to(inner, &mut header)? {
+        0 => return Ok(None),
+        HEADER_LEN => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated BGZF block header",
+            ));
+        }
+    }
+    // the gzip magic number, the deflate method, and the FEXTRA flag
+    if header[0..3] != [0x1f, 0x8b, 0x08] || header[3] & 0x04 == 0 {
+        return Err(invalid_data("invalid BGZF block header"));
+    }
+    let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let mut block = header.to_vec();
+    block.resize(HEADER_LEN + xlen, 0);
+    inner.read_exact(&mut block[HEADER_LEN..])?;
+    let block_size = block_size(&block[HEADER_LEN..])
+        .ok_or_else(|| invalid_data("BGZF block header without a block size"))?;
+    if block_size < block.len() + TRAILER_LEN {
+        return Err(invalid_data("invalid BGZF block size"));
+    }
+    let start = block.len();
+    block.resize(block_size, 0);
+    inner.read_exact(&mut block[start..])?;
+    Ok(Some(block))
+}
+
+/// The total size of a block, from the `BC` subfield of the extra field `extra` of
+/// its header.
+fn block_size(mut extra: &[u8]) -> Option<usize> {
+    while extra.len() >= 4 {
+        let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        let data = extra.get(4..4 + len)?;
+        if extra[0..2] == *b"BC" && len == 2 {
+            return Some(u16::from_le_bytes([data[0], data[1]]) as usize + 1);
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+/// Decompress the (complete, as read by [read_block]) block `block`, checking its
+/// uncompressed size and CRC32.
+fn inflate_block(block: &[u8]) -> io::Result<Vec<u8>> {
+    let xlen = u16::from_le_bytes([block[10], block[11]]) as usize;
+    let body = &block[HEADER_LEN + xlen..];
+    let (cdata, trailer) = body.split_at(body.len() - TRAILER_LEN);
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]) as usize;
+    let mut data = Vec::with_capacity(size);
+    flate2::read::DeflateDecoder::new(cdata).read_to_end(&mut data)?;
+    let mut check = flate2::Crc::new();
+    check.update(&data);
+    if data.len() != size || check.sum() != crc {
+        return Err(invalid_data("corrupt BGZF block"));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Compress `data` into BGZF blocks of at most 64 KiB of input each.
+    fn bgzf(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in data.chunks(0xff00).chain(std::iter::once(&[][..])) {
+            let mut enc = flate2::write::DeflateEncoder::new(Vec::new(), Default::default());
+            enc.write_all(chunk).unwrap();
+            let cdata = enc.finish().unwrap();
+            let bsize = (HEADER_LEN + 6 + cdata.len() + TRAILER_LEN - 1) as u16;
+            out.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 6, 0]);
+            out.extend_from_slice(b"BC");
+            out.extend_from_slice(&2_u16.to_le_bytes());
+            out.extend_from_slice(&bsize.to_le_bytes());
+            out.extend_from_slice(&cdata);
+            let mut crc = flate2::Crc::new();
+            crc.update(chunk);
+            out.extend_from_slice(&crc.sum().to_le_bytes());
+            out.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn reads_blocks_in_order() {
+        let data: Vec<u8> = (0..500_000_u32).flat_map(|i| i.to_le_bytes()).collect();
+        let mut reader = BalancedBgzfReader::new(io::Cursor::new(bgzf(&data)), 1, 4);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn rejects_truncated_streams() {
+        let mut compressed = bgzf(&[7_u8; 100_000]);
+        // cut the end-of-file marker short
+        compressed.truncate(compressed.len() - 10);
+        let mut reader = BalancedBgzfReader::new(io::Cursor::new(compressed), 2, 2);
+        let mut out = Vec::new();
+        assert!(reader.read_to_end(&mut out).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_blocks() {
+        let mut compressed = bgzf(&[7_u8; 100_000]);
+        // the CRC32 of the first block
+        let bsize = u16::from_le_bytes([compressed[16], compressed[17]]) as usize + 1;
+        compressed[bsize - TRAILER_LEN] ^= 0xff;
+        let mut reader = BalancedBgzfReader::new(io::Cursor::new(compressed), 2, 2);
+        let mut out = Vec::new();
+        assert!(reader.read_to_end(&mut out).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::util::oarfish_types::{AlnRecordLike, ReadTags};
+use crate::util::provenance;
+use crossbeam::channel::Receiver;
+use noodles_bam as bam;
+use noodles_core::Position;
+use noodles_sam::alignment::io::Write as _;
+use noodles_sam::alignment::record::Flags;
+use noodles_sam::alignment::record::MappingQuality;
+use noodles_sam::alignment::record::cigar::Op;
+use noodles_sam::alignment::record::cigar::op::Kind;
+use noodles_sam::alignment::record::data::field::Tag;
+use noodles_sam::alignment::record_buf::data::field::Value;
+use noodles_sam::alignment::record_buf::{Cigar, Data, RecordBuf, Sequence};
+use num_format::{Locale, ToFormattedString};
+use std::fs::File;
+use std::path::Path;
+use tracing::info;
+
+/// The `tp` (type of alignment) tag written by minimap2.
+const ALIGNMENT_TYPE_TAG: Tag = Tag::new(b't', b'p');
+
+#[inline]
+fn cigar_kind(code: u8) -> Kind {
+    match code {
+        0 => Kind::Match,
+        1 => Kind::Insertion,
+        2 => Kind::Deletion,
+        3 => Kind::Skip,
+        4 => Kind::SoftClip,
+        5 => Kind::HardClip,
+        6 => Kind::Pad,
+        7 => Kind::SequenceMatch,
+        _ => Kind::SequenceMismatch,
+    }
+}
+
+#[inline]
+pub(crate) fn revcomp(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|b| match b.to_ascii_uppercase() {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' | b'U' => b'A',
+            _ => b'N',
+        })
+        .collect()
+}
+
+/// Convert the minimap2 `mappings` of the read `name`, with sequence `seq`, into
+/// BAM records (in the same way as minimap2 itself), appending them to `records`.
+/// If the read has no mappings, a single unmapped record is added. The sequence is
+/// only stored for the primary alignment (soft-clipped); secondary and supplementary
+/// alignments are hard-clipped and carry no sequence. The `rq` and `np` tags of a read
+/// from a uBAM input are kept on each of its records.
+pub fn add_mapping_records(
+    name: &[u8],
+    seq: &[u8],
+    tags: ReadTags,
+    mappings: &[minimap2::Mapping],
+    header: &noodles_sam::header::Header,
+    records: &mut Vec<RecordBuf>,
+) -> anyhow::Result<()> {
+    let name = name.strip_suffix(b"\0").unwrap_or(name);
+    // the tags of the read in the input uBAM are kept on each of its records
+    let mut read_data = Data::default();
+    if let Some(rq) = tags.rq {
+        read_data.insert(ReadTags::RQ, Value::Float(rq));
+    }
+    if let Some(np) = tags.np {
+        read_data.insert(ReadTags::NP, Value::from(np));
+    }
+    if mappings.is_empty() {
+        records.push(
+            RecordBuf::builder()
+                .set_name(name)
+                .set_flags(Flags::UNMAPPED)
+                .set_sequence(Sequence::from(seq.to_vec()))
+                .set_data(read_data)
+                .build(),
+        );
+        return Ok(());
+    }
+
+    for m in mappings {
+        if m.is_unmapped() {
+            continue;
+        }
+        let ref_id = m.ref_id(header)?;
+
+        let is_rev = m.is_reverse_complemented();
+        let mut flags = Flags::empty();
+        if is_rev {
+            flags |= Flags::REVERSE_COMPLEMENTED;
+        }
+        if m.is_supplementary {
+            flags |= Flags::SUPPLEMENTARY;
+        } else if !m.is_primary {
+            flags |= Flags::SECONDARY;
+        }
+        let is_primary = m.is_primary && !m.is_supplementary;
+
+        // the clips, in the orientation of the alignment
+        let qlen = seq.len();
+        let (lclip, rclip) = if is_rev {
+            (qlen - m.query_end as usize, m.query_start as usize)
+        } else {
+            (m.query_start as usize, qlen - m.query_end as usize)
+        };
+        let clip_kind = if is_primary {
+            Kind::SoftClip
+        } else {
+            Kind::HardClip
+        };
+        let mut ops = Vec::new();
+        if lclip > 0 {
+            ops.push(Op::new(clip_kind, lclip));
+        }
+        if let Some(cigar) = m.alignment.as_ref().and_then(|a| a.cigar.as_ref()) {
+            ops.extend(
+                cigar
+                    .iter()
+                    .map(|(len, op)| Op::new(cigar_kind(*op), *len as usize)),
+            );
+        }
+        if rclip > 0 {
+            ops.push(Op::new(clip_kind, rclip));
+        }
+
+        let mut data = read_data.clone();
+        if let Some(ref aln) = m.alignment {
+            data.insert(Tag::EDIT_DISTANCE, Value::from(aln.nm));
+            if let Some(score) = aln.alignment_score {
+                data.insert(Tag::ALIGNMENT_SCORE, Value::from(score as i32));
+            }
+        }
+        let aln_type = if m.is_primary { b'P' } else { b'S' };
+        data.insert(ALIGNMENT_TYPE_TAG, Value::Character(aln_type));
+
+        let mut builder = RecordBuf::builder()
+            .set_name(name)
+            .set_flags(flags)
+            .set_reference_sequence_id(ref_id)
+            .set_alignment_start(Position::try_from(m.target_start as usize + 1)?)
+            .set_cigar(Cigar::from(ops))
+            .set_data(data);
+        if let Some(mapq) = MappingQuality::new(m.mapq.min(254) as u8) {
+            builder = builder.set_mapping_quality(mapq);
+        }
+        if is_primary {
+            let seq = if is_rev { revcomp(seq) } else { seq.to_vec() };
+            builder = builder.set_sequence(Sequence::from(seq));
+        }
+        records.push(builder.build());
+    }
+    Ok(())
+}
+
+/// Write the records received on `receiver` (in batches) to the BAM file at `path`,
+/// with header `header`, until all senders have been dropped. The provenance of the
+/// run (if it was recorded) is added to the header as a `@CO` line.
+pub fn write_bam(
+    path: &Path,
+    header: &noodles_sam::header::Header,
+    receiver: Receiver<Vec<RecordBuf>>,
+) -> anyhow::Result<()> {
+    let mut writer = bam::io::Writer::new(File::create(path)?);
+    let mut out_header = header.clone();
+    if let Some(p) = provenance::header_json() {
+        out_header
+            .comments_mut()
+            .push(format!("{}:{}", provenance::PROVENANCE_KEY, p).into());
+    }
+    writer.write_header(&out_header)?;
+    let mut num_records = 0_usize;
+    for records in receiver {
+        for record in records.iter() {
+            writer.write_alignment_record(header, record)?;
+        }
+        num_records += records.len();
+    }
+    writer.try_finish()?;
+    info!(
+        "wrote {} alignment records to {}",
+        num_records.to_formatted_string(&Locale::en),
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use crate::util::barcode_tags::BarcodeSource;
+use crate::util::object_store_io::StagedFile;
+use noodles_bam as bam;
+use noodles_sam::alignment::RecordBuf;
+use noodles_sam::alignment::io::Write as _;
+use num_format::{Locale, ToFormattedString};
+use rayon::slice::ParallelSliceMut;
+use rustc_hash::FxHashSet;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufRead};
+use tracing::info;
+
+/// A rough estimate of the memory taken up by a record beyond its variable-length
+/// fields (including that of its tags).
+const RECORD_OVERHEAD_BYTES: usize = 256;
+
+/// A reader of a sorted run written to disk.
+type RunReader = bam::io::Reader<Box<dyn BufRead>>;
+
+/// An estimate of the memory taken up by `rec`, and its `barcode`, while it is held
+/// in a run.
+fn record_bytes(rec: &RecordBuf, barcode: &[u8]) -> usize {
+    RECORD_OVERHEAD_BYTES
+        + barcode.len()
+        + rec.name().map_or(0, |n| n.len())
+        + rec.sequence().len()
+        + rec.quality_scores().len()
+        + 16 * rec.cigar().as_ref().len()
+}
+
+/// Sort the records of `run` by barcode (keeping the order of the records of each
+/// barcode) and write them to the `run_idx`-th temporary run file.
+fn write_run(
+    header: &noodles_sam::Header,
+    run: &mut [(Vec<u8>, RecordBuf)],
+    run_idx: usize,
+) -> anyhow::Result<StagedFile> {
+    run.par_sort_by(|a, b| a.0.cmp(&b.0));
+    let path = std::env::temp_dir().join(format!(
+        "oarfish-{}-sc-sort-{}.bam",
+        std::process::id(),
+        run_idx
+    ));
+    // the file is removed, even if writing it fails, once this is dropped
+    let staged = StagedFile::new(path);
+    let mut writer = bam::io::Writer::new(File::create(staged.path())?);
+    writer.write_header(header)?;
+    for (_, rec) in run.iter() {
+        writer.write_alignment_record(header, rec)?;
+    }
+    writer.try_finish()?;
+    Ok(staged)
+}
+
+/// The (mapped) records of a single-cell BAM file, in the order of their cell barcodes.
+pub enum BarcodeSortedRecords {
+    /// all of the records fit in memory, and were sorted there
+    InMemory(std::vec::IntoIter<(Vec<u8>, RecordBuf)>),
+    /// the records were sorted in runs written to disk, which are merged as
+    /// the records are read
+    Merged(RunMerger),
+}
+
+/// Merges the sorted runs written to disk; `heads[r]` holds the next record of run
+/// `r` and `heap` the barcodes of these records.
+pub struct RunMerger {
+    header: noodles_sam::Header,
+    bc_source: BarcodeSource,
+    readers: Vec<RunReader>,
+    heads: Vec<Option<RecordBuf>>,
+    heap: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+    _runs: Vec<StagedFile>,
+}
+
+impl RunMerger {
+    fn new(
+        header: &noodles_sam::Header,
+        bc_source: &BarcodeSource,
+        runs: Vec<StagedFile>,
+    ) -> anyhow::Result<Self> {
+        let mut merger = Self {
+            header: header.clone(),
+            bc_source: bc_source.clone(),
+            readers: Vec::with_capacity(runs.len()),
+            heads: vec![None; runs.len()],
+            heap: BinaryHeap::with_capacity(runs.len()),
+            _runs: Vec::new(),
+        };
+        for (r, run) in runs.iter().enumerate() {
+            let inner: Box<dyn BufRead> =
+                Box::new(bam::io::Reader::new(File::open(run.path())?).into_inner());
+            let mut reader = bam::io::Reader::from(inner);
+            reader.read_header()?;
+            merger.readers.push(reader);
+            merger.advance(r)?;
+        }
+        merger._runs = runs;
+        Ok(merger)
+    }
+
+    /// Read the next record of run `r` into `heads[r]`.
+    fn advance(&mut self, r: usize) -> anyhow::Result<()> {
+        let mut rec = RecordBuf::default();
+        if self.readers[r].read_record_buf(&self.header, &mut rec)? > 0 {
+            let barcode = self.bc_source.barcode(&rec)?.to_vec();
+            self.heap.push(Reverse((barcode, r)));
+            self.heads[r] = Some(rec);
+        }
+        Ok(())
+    }
+
+    fn next_record(&mut self) -> anyhow::Result<Option<RecordBuf>> {
+        let Some(Reverse((_, r))) = self.heap.pop() else {
+            return Ok(None);
+        };
+        let rec = self.heads[r].take();
+        self.advance(r)?;
+        Ok(rec)
+    }
+}
+
+impl Iterator for BarcodeSortedRecords {
+    type Item = io::Result<RecordBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            BarcodeSortedRecords::InMemory(iter) => iter.next().map(|(_, rec)| Ok(rec)),
+            BarcodeSortedRecords::Merged(merger) => {
+                merger.next_record().map_err(io::Error::other).transpose()
+            }
+        }
+    }
+}
+
+/// Sort the mapped records read from `reader` by their cell barcodes (found as described
+/// by `bc_source`), so that the records
+/// of each cell are collated. Runs of records taking up at most `max_mem` bytes (in
+/// total) are sorted in memory (in parallel) and written to temporary files, while the
+/// following records are read, and the runs are merged as the sorted records are
+/// consumed; if the records fit in a single run, they are not written to disk at all.
+pub fn sort_by_barcode<R: BufRead>(
+    reader: &mut bam::io::Reader<R>,
+    header: &noodles_sam::Header,
+    bc_source: &BarcodeSource,
+    max_mem: usize,
+) -> anyhow::Result<BarcodeSortedRecords> {
+    // one run is filled while the previous one is sorted and written
+    let max_run_bytes = (max_mem / 2).max(1);
+
+    let (mut run, mut runs, num_records, collated) =
+        std::thread::scope(|s| -> anyhow::Result<_> {
+            let (run_tx, run_rx) = crossbeam::channel::bounded::<Vec<(Vec<u8>, RecordBuf)>>(0);
+            let run_writer = s.spawn(move || -> anyhow::Result<Vec<StagedFile>> {
+                let mut runs = Vec::new();
+                for mut run in run_rx {
+                    runs.push(write_run(header, &mut run, runs.len())?);
+                }
+                Ok(runs)
+            });
+
+            let mut run = Vec::<(Vec<u8>, RecordBuf)>::new();
+            let mut run_bytes = 0_usize;
+            let mut num_records = 0_usize;
+            // track whether the input was already collated by barcode
+            let mut collated = true;
+            let mut finished_barcodes = FxHashSet::<Vec<u8>>::default();
+            let mut current_barcode = Vec::<u8>::new();
+            for result in reader.record_bufs(header) {
+                let rec = result?;
+                // unmapped reads don't contribute to quantification
+                if rec.flags().is_unmapped() {
+                    continue;
+                }
+                let barcode = bc_source.barcode(&rec)?.to_vec();
+                if collated && barcode != current_barcode {
+                    let prev = std::mem::replace(&mut current_barcode, barcode.clone());
+                    finished_barcodes.insert(prev);
+                    collated = !finished_barcodes.contains(&barcode);
+                }
+                run_bytes += record_bytes(&rec, &barcode);
+                run.push((barcode, rec));
+                num_records += 1;
+                if run_bytes > max_run_bytes {
+                    if run_tx.send(std::mem::take(&mut run)).is_err() {
+                        // the writer has failed; its error is reported below
+                        break;
+                    }
+                    run_bytes = 0;
+                }
+            }
+            drop(run_tx);
+            let runs = run_writer
+                .join()
+                .map_err(|_| anyhow::anyhow!("the thread writing the sorted runs panicked"))??;
+            Ok((run, runs, num_records, collated))
+        })?;
+
+    if collated {
+        info!(
+            "the input was already collated by cell barcode; pass --assume-collated to skip sorting such inputs."
+        );
+    }
+    if runs.is_empty() {
+        run.par_sort_by(|a, b| a.0.cmp(&b.0));
+        info!(
+            "sorted {} records by cell barcode in memory.",
+            num_records.to_formatted_string(&Locale::en)
+        );
+        return Ok(BarcodeSortedRecords::InMemory(run.into_iter()));
+    }
+
+    if !run.is_empty() {
+        runs.push(write_run(header, &mut run, runs.len())?);
+    }
+    info!(
+        "sorted {} records by cell barcode in {} runs on disk.",
+        num_records.to_formatted_string(&Locale::en),
+        runs.len()
+    );
+    Ok(BarcodeSortedRecords::Merged(RunMerger::new(
+        header, bc_source, runs,
+    )?))
+}
@@ -0,0 +1,119 @@
+use crate::prog_opts::Args;
+use anyhow::bail;
+use noodles_sam::alignment::RecordBuf;
+use noodles_sam::alignment::record_buf::data::field::Value;
+
+/// Where the cell barcode and the UMI of each single-cell alignment record are found;
+/// either in the first of a list of tags that the record has (e.g. `CB`, then `CR`),
+/// or in the fields of the read name (e.g. `<read>_<barcode>_<umi>`).
+#[derive(Clone, Debug)]
+pub struct BarcodeSource {
+    bc_tags: Vec<[u8; 2]>,
+    umi_tags: Vec<[u8; 2]>,
+    /// if set, the barcode and UMI are the second and third fields of the read
+    /// name, delimited by this separator
+    read_name_sep: Option<u8>,
+}
+
+/// The value of the first of `tags` that `rec` has, which must be a string.
+fn first_tag<'a>(rec: &'a RecordBuf, tags: &[[u8; 2]]) -> anyhow::Result<Option<&'a [u8]>> {
+    for tag in tags {
+        match rec.data().get(tag) {
+            None => continue,
+            Some(Value::String(x)) => return Ok(Some(x.as_slice())),
+            Some(_) => bail!(
+                "{} tag value had unexpected type!",
+                String::from_utf8_lossy(tag)
+            ),
+        }
+    }
+    Ok(None)
+}
+
+fn tag_names(tags: &[[u8; 2]]) -> String {
+    tags.iter()
+        .map(|t| String::from_utf8_lossy(t).into_owned())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl BarcodeSource {
+    pub fn from_args(args: &Args) -> Self {
+        let to_tags = |names: &[String]| {
+            names
+                .iter()
+                .map(|n| {
+                    let b = n.as_bytes();
+                    [b[0], b[1]]
+                })
+                .collect()
+        };
+        Self {
+            bc_tags: to_tags(&args.bc_tag),
+            umi_tags: to_tags(&args.umi_tag),
+            read_name_sep: args.bc_from_read_name.then_some(args.read_name_sep as u8),
+        }
+    }
+
+    /// The `i`-th field of the name of `rec`, if the barcodes are taken from read names.
+    fn read_name_field<'a>(&self, rec: &'a RecordBuf, i: usize) -> Option<&'a [u8]> {
+        let sep = self.read_name_sep?;
+        let name: &[u8] = rec.name()?.as_ref();
+        name.split(|c| *c == sep).nth(i).filter(|f| !f.is_empty())
+    }
+
+    /// The cell barcode of `rec`; it is an error for a record to have no barcode.
+    pub fn barcode<'a>(&self, rec: &'a RecordBuf) -> anyhow::Result<&'a [u8]> {
+        if self.read_name_sep.is_some() {
+            return match self.read_name_field(rec, 1) {
+                Some(bc) => Ok(bc),
+                None => bail!(
+                    "could not get the cell barcode from the read name {:?}",
+                    rec.name().map(|n| n.to_string())
+                ),
+            };
+        }
+        match first_tag(rec, &self.bc_tags)? {
+            Some(bc) => Ok(bc),
+            None => bail!(
+                "could not get the cell barcode ({} tag) of read {:?}",
+                tag_names(&self.bc_tags),
+                rec.name().map(|n| n.to_string())
+            ),
+        }
+    }
+
+    /// The cell barcode of `rec`, or [None] if it has none.
+    pub fn barcode_opt<'a>(&self, rec: &'a RecordBuf) -> Option<&'a [u8]> {
+        if self.read_name_sep.is_some() {
+            self.read_name_field(rec, 1)
+        } else {
+            first_tag(rec, &self.bc_tags).ok().flatten()
+        }
+    }
+
+    /// The UMI of `rec`, if it has one.
+    pub fn umi<'a>(&self, rec: &'a RecordBuf) -> Option<&'a [u8]> {
+        if self.read_name_sep.is_some() {
+            self.read_name_field(rec, 2)
+        } else {
+            first_tag(rec, &self.umi_tags).ok().flatten()
+        }
+    }
+
+    /// The cell barcode of a FASTA/Q record with header `id`; either a field of the read
+    /// name, or a `<tag>:Z:<barcode>` field of the header comment for the first of the
+    /// barcode tags present.
+    pub fn fastx_barcode<'a>(&self, id: &'a [u8]) -> Option<&'a [u8]> {
+        if let Some(sep) = self.read_name_sep {
+            let name = id.split(|c| c.is_ascii_whitespace()).next()?;
+            return name.split(|c| *c == sep).nth(1).filter(|f| !f.is_empty());
+        }
+        self.bc_tags.iter().find_map(|tag| {
+            id.split(|c| c.is_ascii_whitespace()).skip(1).find_map(|f| {
+                f.strip_prefix(tag.as_slice())
+                    .and_then(|f| f.strip_prefix(b":Z:"))
+            })
+        })
+    }
+}
@@ -0,0 +1,302 @@
+use crate::util::biotypes::file_safe;
+use crate::util::errors::bad_input;
+use crate::util::provenance;
+use anyhow::Context;
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// The header line of the per-barcode species calls.
+const BARNYARD_HEADER: &str = "barcode\tspecies\tcount_a\tcount_b\tfrac_a";
+
+/// The species of the transcripts of a mixed-species (barnyard) experiment, told apart
+/// by the prefixes of their names.
+pub struct Barnyard {
+    /// the prefixes of the names of the transcripts of the two species
+    prefixes: [String; 2],
+    /// the names of the two species in the outputs
+    labels: [String; 2],
+    /// the species (0 or 1) of each transcript, if its name has one of the prefixes
+    txp_species: Vec<Option<u8>>,
+    /// the fraction of the counts of a cell that must come from one species for the
+    /// cell to be called as that species
+    min_frac: f64,
+}
+
+/// The species call of a barcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpeciesCall {
+    Species(u8),
+    /// a cell with substantial counts from both species (a cross-species doublet)
+    Mixed,
+    /// a barcode with no counts from either species
+    Empty,
+}
+
+/// The number of barcodes called as each species, and the estimated doublet rate.
+#[derive(Debug, Clone, Serialize)]
+pub struct BarnyardSummary {
+    pub prefixes: [String; 2],
+    pub min_frac: f64,
+    pub num_cells_a: usize,
+    pub num_cells_b: usize,
+    pub num_mixed: usize,
+    pub num_empty: usize,
+    /// the fraction of the called cells (those of either species, or mixed) that are mixed
+    pub collision_rate: f64,
+    /// the estimated fraction of the called cells that are doublets, including those of two
+    /// cells of the same species, which can not be seen (`collision_rate / (2 p_a p_b)`, with
+    /// `p_a` and `p_b` the fractions of the single cells of each species)
+    pub doublet_rate: Option<f64>,
+}
+
+/// The name of the species whose transcripts have the prefix `prefix` in the outputs:
+/// the prefix without any trailing punctuation (e.g. `GRCh38` for `GRCh38_`).
+fn species_label(prefix: &str) -> String {
+    file_safe(prefix.trim_end_matches(|c: char| !c.is_ascii_alphanumeric()))
+}
+
+fn read_lines(path: &Path) -> anyhow::Result<Vec<String>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    BufReader::new(file)
+        .lines()
+        .map(|l| l.with_context(|| format!("could not read {}", path.display())))
+        .collect()
+}
+
+fn write_lines<'a>(path: &Path, lines: impl Iterator<Item = &'a str>) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(
+        File::create(path).with_context(|| format!("could not create {}", path.display()))?,
+    );
+    for l in lines {
+        writeln!(writer, "{}", l)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+impl Barnyard {
+    /// The species of the transcripts `txps_name`, whose names start with one of the two
+    /// `prefixes`; each species must have at least one transcript.
+    pub fn new(prefixes: &[String], txps_name: &[String], min_frac: f64) -> anyhow::Result<Self> {
+        let [a, b] = prefixes else {
+            return Err(bad_input(format!(
+                "--barnyard takes the prefixes of the transcripts of exactly 2 species, but {} were given",
+                prefixes.len()
+            )));
+        };
+        if a.starts_with(b.as_str()) || b.starts_with(a.as_str()) {
+            return Err(bad_input(format!(
+                "the --barnyard prefixes {} and {} must not be prefixes of one another",
+                a, b
+            )));
+        }
+        let labels = [species_label(a), species_label(b)];
+        if labels[0].is_empty() || labels[1].is_empty() || labels[0] == labels[1] {
+            return Err(bad_input(format!(
+                "the --barnyard prefixes {} and {} do not give distinct names for the species",
+                a, b
+            )));
+        }
+        let txp_species: Vec<Option<u8>> = txps_name
+            .iter()
+            .map(|n| {
+                if n.starts_with(a.as_str()) {
+                    Some(0)
+                } else if n.starts_with(b.as_str()) {
+                    Some(1)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for (s, p) in [a, b].iter().enumerate() {
+            if !txp_species.contains(&Some(s as u8)) {
+                return Err(bad_input(format!(
+                    "no transcript name starts with the --barnyard prefix {}",
+                    p
+                )));
+            }
+        }
+        let num_other = txp_species.iter().filter(|s| s.is_none()).count();
+        if num_other > 0 {
+            warn!(
+                "{} transcripts start with neither --barnyard prefix; their counts are not used to call the species of the cells.",
+                num_other.to_formatted_string(&Locale::en)
+            );
+        }
+        Ok(Self {
+            prefixes: [a.clone(), b.clone()],
+            labels,
+            txp_species,
+            min_frac,
+        })
+    }
+
+    fn call(&self, counts: [f64; 2]) -> SpeciesCall {
+        let total = counts[0] + counts[1];
+        if total <= 0.0 {
+            SpeciesCall::Empty
+        } else if counts[0] / total >= self.min_frac {
+            SpeciesCall::Species(0)
+        } else if counts[1] / total >= self.min_frac {
+            SpeciesCall::Species(1)
+        } else {
+            SpeciesCall::Mixed
+        }
+    }
+
+    /// Call the species of each cell of the single-cell output with the prefix `output`
+    /// (whose count matrix has a column per transcript of `txps_name`), writing the calls to
+    /// `<output>.barnyard.tsv` and, for each species, the counts of the cells called as
+    /// that species over its transcripts to `<output>.<species>.count.mtx` (with its
+    /// `.barcodes.txt` and `.features.txt`).
+    pub fn split(&self, output: &Path, txps_name: &[String]) -> anyhow::Result<BarnyardSummary> {
+        let barcodes = read_lines(&output.with_additional_extension(".barcodes.txt"))?;
+        let mtx_path = output.with_additional_extension(".count.mtx");
+        let counts: sprs::TriMatI<f32, u32> = sprs::io::read_matrix_market(&mtx_path)
+            .with_context(|| format!("could not read the count matrix {}", mtx_path.display()))?;
+        anyhow::ensure!(
+            counts.rows() == barcodes.len() && counts.cols() == self.txp_species.len(),
+            "the count matrix {} is {} x {}, but there are {} barcodes and {} transcripts",
+            mtx_path.display(),
+            counts.rows(),
+            counts.cols(),
+            barcodes.len(),
+            self.txp_species.len()
+        );
+
+        // the counts of each cell from the transcripts of each species
+        let mut species_counts = vec![[0.0_f64; 2]; barcodes.len()];
+        for (v, (r, c)) in counts.triplet_iter() {
+            if let Some(s) = self.txp_species[c as usize] {
+                species_counts[r as usize][s as usize] += *v as f64;
+            }
+        }
+        let calls: Vec<SpeciesCall> = species_counts.iter().map(|c| self.call(*c)).collect();
+
+        let calls_path = output.with_additional_extension(".barnyard.tsv");
+        let mut writer = BufWriter::new(
+            File::create(&calls_path)
+                .with_context(|| format!("could not create {}", calls_path.display()))?,
+        );
+        provenance::write_tsv_comment(&mut writer)?;
+        writeln!(writer, "{}", BARNYARD_HEADER)?;
+        for ((bc, c), call) in barcodes.iter().zip(species_counts.iter()).zip(calls.iter()) {
+            let species = match call {
+                SpeciesCall::Species(s) => self.labels[*s as usize].as_str(),
+                SpeciesCall::Mixed => "mixed",
+                SpeciesCall::Empty => "NA",
+            };
+            let total = c[0] + c[1];
+            let frac_a = if total > 0.0 {
+                format!("{:.4}", c[0] / total)
+            } else {
+                String::from("NA")
+            };
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                bc, species, c[0], c[1], frac_a
+            )?;
+        }
+        writer.flush()?;
+
+        // the matrix of each species, over its cells and transcripts
+        let mut num_cells = [0_usize; 2];
+        for (s, label) in self.labels.iter().enumerate() {
+            let mut new_row = vec![None; barcodes.len()];
+            let mut species_barcodes = Vec::new();
+            for (r, call) in calls.iter().enumerate() {
+                if *call == SpeciesCall::Species(s as u8) {
+                    new_row[r] = Some(species_barcodes.len() as u32);
+                    species_barcodes.push(barcodes[r].as_str());
+                }
+            }
+            let mut new_col = vec![None; self.txp_species.len()];
+            let mut num_cols = 0_u32;
+            for (c, txp_s) in self.txp_species.iter().enumerate() {
+                if *txp_s == Some(s as u8) {
+                    new_col[c] = Some(num_cols);
+                    num_cols += 1;
+                }
+            }
+            let mut row_ids = Vec::<u32>::new();
+            let mut col_ids = Vec::<u32>::new();
+            let mut vals = Vec::<f32>::new();
+            for (v, (r, c)) in counts.triplet_iter() {
+                if let (Some(r), Some(c)) = (new_row[r as usize], new_col[c as usize]) {
+                    row_ids.push(r);
+                    col_ids.push(c);
+                    vals.push(*v);
+                }
+            }
+            let trimat = sprs::TriMatI::<f32, u32>::from_triplets(
+                (species_barcodes.len(), num_cols as usize),
+                row_ids,
+                col_ids,
+                vals,
+            );
+            let species_prefix = output.with_additional_extension(&format!(".{}", label));
+            sprs::io::write_matrix_market(
+                species_prefix.with_additional_extension(".count.mtx"),
+                &trimat,
+            )?;
+            write_lines(
+                &species_prefix.with_additional_extension(".barcodes.txt"),
+                species_barcodes.iter().copied(),
+            )?;
+            write_lines(
+                &species_prefix.with_additional_extension(".features.txt"),
+                txps_name
+                    .iter()
+                    .zip(self.txp_species.iter())
+                    .filter(|(_, txp_s)| **txp_s == Some(s as u8))
+                    .map(|(f, _)| f.as_str()),
+            )?;
+            num_cells[s] = species_barcodes.len();
+        }
+
+        let num_mixed = calls.iter().filter(|c| **c == SpeciesCall::Mixed).count();
+        let num_empty = calls.iter().filter(|c| **c == SpeciesCall::Empty).count();
+        let num_called = num_cells[0] + num_cells[1] + num_mixed;
+        let collision_rate = if num_called > 0 {
+            num_mixed as f64 / num_called as f64
+        } else {
+            0.0
+        };
+        let num_single = (num_cells[0] + num_cells[1]) as f64;
+        let doublet_rate = (num_cells[0] > 0 && num_cells[1] > 0).then(|| {
+            let p_a = num_cells[0] as f64 / num_single;
+            let p_b = num_cells[1] as f64 / num_single;
+            (collision_rate / (2.0 * p_a * p_b)).min(1.0)
+        });
+        info!(
+            "barnyard: {} {} cells, {} {} cells and {} mixed cells (a collision rate of {:.2}%{}).",
+            num_cells[0].to_formatted_string(&Locale::en),
+            self.labels[0],
+            num_cells[1].to_formatted_string(&Locale::en),
+            self.labels[1],
+            num_mixed.to_formatted_string(&Locale::en),
+            100.0 * collision_rate,
+            doublet_rate.map_or(String::new(), |d| format!(
+                ", for an estimated doublet rate of {:.2}%",
+                100.0 * d
+            ))
+        );
+        Ok(BarnyardSummary {
+            prefixes: self.prefixes.clone(),
+            min_frac: self.min_frac,
+            num_cells_a: num_cells[0],
+            num_cells_b: num_cells[1],
+            num_mixed,
+            num_empty,
+            collision_rate,
+            doublet_rate,
+        })
+    }
+}
@@ -177,7 +177,7 @@ pub fn binomial_probability(
     normalized_prob
 }
 
-pub fn binomial_continuous_prob(txps: &mut [TranscriptInfo], bins: &u32, threads: usize) {
+pub fn binomial_continuous_prob(txps: &mut [TranscriptInfo], threads: usize) {
     use tracing::info;
     use tracing::info_span;
 
@@ -185,21 +185,16 @@ pub fn binomial_continuous_prob(txps: &mut [TranscriptInfo], bins: &u32, threads
     info!("computing coverage probabilities");
 
     let compute_txp_coverage_probs = |_i: usize, t: &mut TranscriptInfo| {
-        let temp_prob: Vec<f64> = if *bins != 0 {
-            let min_cov = t.total_weight / 100.;
-            t.coverage_bins.iter_mut().for_each(|elem| *elem += min_cov);
-            let (bin_counts, bin_lengths) = t.get_normalized_counts_and_lengths();
-
-            let distinct_rate: f64 = bin_counts
-                .iter()
-                .zip(bin_lengths.iter())
-                .map(|(&count, &length)| (count as f64) / (length as f64))
-                .sum();
-            binomial_probability(&bin_counts, &bin_lengths, distinct_rate)
-        } else {
-            std::unimplemented!("coverage model with 0 bins is not currently implemented");
-        };
-        t.coverage_prob = temp_prob;
+        let min_cov = t.total_weight / 100.;
+        t.coverage_bins.iter_mut().for_each(|elem| *elem += min_cov);
+        let (bin_counts, bin_lengths) = t.get_normalized_counts_and_lengths();
+
+        let distinct_rate: f64 = bin_counts
+            .iter()
+            .zip(bin_lengths.iter())
+            .map(|(&count, &length)| (count as f64) / (length as f64))
+            .sum();
+        t.coverage_prob = binomial_probability(&bin_counts, &bin_lengths, distinct_rate);
     };
 
     // if we are requesting only a single thread, then don't bother with
@@ -0,0 +1,155 @@
+use crate::util::provenance;
+use path_tools::WithAdditionalExtension;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// The biotype assigned to transcripts that do not appear in the biotype file.
+pub const UNANNOTATED_BIOTYPE: &str = "unannotated";
+
+/// The aggregate estimates of all transcripts of a single biotype.
+#[derive(Debug, Serialize)]
+pub struct BiotypeSummary {
+    pub biotype: String,
+    /// the number of transcripts of this biotype
+    pub num_txps: usize,
+    /// the number of transcripts of this biotype with a non-zero estimated count
+    pub num_expressed: usize,
+    /// the estimated number of reads arising from transcripts of this biotype
+    pub num_reads: f64,
+    /// the total TPM of the transcripts of this biotype
+    pub tpm: f64,
+}
+
+/// The TPM of each transcript, given its estimated count `counts` and length `lens`.
+fn tpms(counts: &[f64], lens: &[usize]) -> Vec<f64> {
+    let rates: Vec<f64> = counts
+        .iter()
+        .zip(lens.iter())
+        .map(|(c, l)| c / *l as f64)
+        .collect();
+    let rate_sum: f64 = rates.iter().sum();
+    rates
+        .iter()
+        .map(|r| {
+            if rate_sum > 0.0 {
+                1e6 * r / rate_sum
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+/// Aggregate the estimated `counts` (and the corresponding TPMs) of the transcripts
+/// in `header` by their `biotypes`, returning one summary per biotype, ordered by
+/// decreasing number of reads.
+pub fn summarize_biotypes(
+    header: &noodles_sam::header::Header,
+    counts: &[f64],
+    biotypes: &[String],
+) -> Vec<BiotypeSummary> {
+    let lens: Vec<usize> = header
+        .reference_sequences()
+        .values()
+        .map(|rmap| rmap.length().get())
+        .collect();
+    let tpm = tpms(counts, &lens);
+
+    let mut by_biotype: HashMap<&str, BiotypeSummary> = HashMap::new();
+    for ((bt, c), t) in biotypes.iter().zip(counts.iter()).zip(tpm.iter()) {
+        let s = by_biotype
+            .entry(bt.as_str())
+            .or_insert_with(|| BiotypeSummary {
+                biotype: bt.clone(),
+                num_txps: 0,
+                num_expressed: 0,
+                num_reads: 0.0,
+                tpm: 0.0,
+            });
+        s.num_txps += 1;
+        s.num_expressed += (*c > 0.0) as usize;
+        s.num_reads += c;
+        s.tpm += t;
+    }
+
+    let mut summaries: Vec<BiotypeSummary> = by_biotype.into_values().collect();
+    summaries.sort_by(|a, b| {
+        b.num_reads
+            .total_cmp(&a.num_reads)
+            .then_with(|| a.biotype.cmp(&b.biotype))
+    });
+    summaries
+}
+
+/// Write the per-biotype aggregates in `summaries` to `<output>.biotypes.tsv`.
+pub fn write_biotype_summary(output: &PathBuf, summaries: &[BiotypeSummary]) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".biotypes.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    writeln!(writer, "biotype\tnum_txps\tnum_expressed\tnum_reads\tTPM")?;
+    for s in summaries {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            s.biotype, s.num_txps, s.num_expressed, s.num_reads, s.tpm
+        )?;
+    }
+    Ok(())
+}
+
+/// A version of `name` (e.g. a biotype) that is safe to use as part of a file name.
+pub(crate) fn file_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Write the estimated `counts` of the transcripts of each biotype to a separate
+/// file, `<output>.<biotype>.quant`, in the same format as `<output>.quant`.
+pub fn write_quant_by_biotype(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    counts: &[f64],
+    biotypes: &[String],
+) -> io::Result<()> {
+    let mut writers: HashMap<&str, BufWriter<std::fs::File>> = HashMap::new();
+    for (i, (rseq, rmap)) in header.reference_sequences().iter().enumerate() {
+        let bt = biotypes[i].as_str();
+        let writer = match writers.entry(bt) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let out_path =
+                    output.with_additional_extension(&format!(".{}.quant", file_safe(bt)));
+                let write = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(out_path)?;
+                let mut writer = BufWriter::new(write);
+                writeln!(writer, "tname\tlen\tnum_reads")?;
+                e.insert(writer)
+            }
+        };
+        writeln!(writer, "{}\t{}\t{}", rseq, rmap.length(), counts[i])?;
+    }
+    for (_, mut w) in writers {
+        w.flush()?;
+    }
+    Ok(())
+}
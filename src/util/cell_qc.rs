@@ -0,0 +1,188 @@
+use crate::util::barcode_tags::BarcodeSource;
+use crate::util::oarfish_types::InMemoryAlignmentStore;
+use noodles_sam::alignment::RecordBuf;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::io::Write;
+
+/// The header line of the per-cell QC file.
+pub const CELL_QC_HEADER: &str = "barcode\treads\tumis\tassigned_reads\tmapping_rate\tdetected_transcripts\tdetected_genes\tmito_fraction\tmean_read_length";
+
+/// The (optional) annotation of the targets used to compute the per-cell QC metrics.
+pub struct CellQcConfig {
+    /// the gene id of each target, if the genes are known
+    txp_gene: Option<Vec<Option<u32>>>,
+    /// `mito[i]` is true if target `i` is mitochondrial, if these are known
+    mito: Option<Vec<bool>>,
+}
+
+impl CellQcConfig {
+    pub fn new(txp_gene: Option<Vec<Option<u32>>>, mito: Option<Vec<bool>>) -> Self {
+        Self { txp_gene, mito }
+    }
+}
+
+/// Assign consecutive ids to the genes named in `txp_genes` (the gene of each target),
+/// returning the id of the gene of each target and the names of the genes (in id order).
+pub fn gene_ids(txp_genes: &[Option<String>]) -> (Vec<Option<u32>>, Vec<String>) {
+    let mut ids: FxHashMap<&str, u32> = FxHashMap::default();
+    let mut names = Vec::new();
+    let txp_gene_ids = txp_genes
+        .iter()
+        .map(|g| {
+            g.as_deref().map(|g| {
+                *ids.entry(g).or_insert_with(|| {
+                    names.push(g.to_owned());
+                    names.len() as u32 - 1
+                })
+            })
+        })
+        .collect();
+    (txp_gene_ids, names)
+}
+
+/// The QC metrics of a single cell.
+pub struct CellQc {
+    /// the number of distinct reads with records for this barcode
+    reads: usize,
+    /// the number of distinct UMIs, if the records have them
+    umis: Option<usize>,
+    /// the number of reads with an alignment passing the filters
+    assigned_reads: usize,
+    detected_transcripts: usize,
+    detected_genes: Option<usize>,
+    /// the fraction of the estimated reads assigned to mitochondrial targets
+    mito_fraction: Option<f64>,
+    /// the mean length of the (primary) reads
+    mean_read_length: f64,
+}
+
+impl CellQc {
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    pub fn umis(&self) -> Option<usize> {
+        self.umis
+    }
+
+    pub fn assigned_reads(&self) -> usize {
+        self.assigned_reads
+    }
+
+    pub fn detected_transcripts(&self) -> usize {
+        self.detected_transcripts
+    }
+}
+
+/// The QC metrics of a cell that are computed from its alignment records, before these
+/// are consumed by the parsing of the alignments.
+pub struct RecordStats {
+    /// the number of distinct reads with records for this barcode
+    reads: usize,
+    /// the number of distinct UMIs, if the records have them
+    umis: Option<usize>,
+    /// the mean length of the (primary) reads
+    mean_read_length: f64,
+}
+
+/// Compute the [RecordStats] of the cell with the alignment `records` (sorted by read
+/// name). The UMIs of the records are found as described by `bc_source`.
+pub fn record_stats(bc_source: &BarcodeSource, records: &[RecordBuf]) -> RecordStats {
+    let mut reads = 0_usize;
+    let mut umis: FxHashSet<&[u8]> = FxHashSet::default();
+    let mut total_len = 0_u64;
+    let mut num_with_len = 0_u64;
+    let mut prev_name = None;
+    for rec in records {
+        if rec.name() != prev_name {
+            reads += 1;
+            prev_name = rec.name();
+        }
+        let flags = rec.flags();
+        if flags.is_secondary() || flags.is_supplementary() {
+            continue;
+        }
+        if let Some(umi) = bc_source.umi(rec) {
+            umis.insert(umi);
+        }
+        let len = rec.sequence().len();
+        if len > 0 {
+            total_len += len as u64;
+            num_with_len += 1;
+        }
+    }
+    RecordStats {
+        reads,
+        umis: (!umis.is_empty()).then_some(umis.len()),
+        mean_read_length: if num_with_len > 0 {
+            total_len as f64 / num_with_len as f64
+        } else {
+            0.0
+        },
+    }
+}
+
+/// Compute the QC metrics of the cell with the record metrics `stats` (see
+/// [record_stats]), whose filtered alignments are in `store` and whose estimated
+/// target counts are `counts`.
+pub fn cell_qc(
+    config: &CellQcConfig,
+    stats: RecordStats,
+    store: &InMemoryAlignmentStore,
+    counts: &[f64],
+) -> CellQc {
+    let detected_genes = config.txp_gene.as_ref().map(|txp_gene| {
+        counts
+            .iter()
+            .zip(txp_gene.iter())
+            .filter_map(|(c, g)| if *c > 0.0 { *g } else { None })
+            .collect::<FxHashSet<u32>>()
+            .len()
+    });
+    let mito_fraction = config.mito.as_ref().map(|mito| {
+        let total: f64 = counts.iter().sum();
+        let in_mito: f64 = counts
+            .iter()
+            .zip(mito.iter())
+            .filter(|(_, m)| **m)
+            .map(|(c, _)| *c)
+            .sum();
+        if total > 0.0 { in_mito / total } else { 0.0 }
+    });
+
+    CellQc {
+        reads: stats.reads,
+        umis: stats.umis,
+        assigned_reads: store.len(),
+        detected_transcripts: counts.iter().filter(|c| **c > 0.0).count(),
+        detected_genes,
+        mito_fraction,
+        mean_read_length: stats.mean_read_length,
+    }
+}
+
+fn or_na<T: ToString>(x: Option<T>) -> String {
+    x.map_or(String::from("NA"), |x| x.to_string())
+}
+
+/// Write the QC metrics `qc` of the cell with barcode `barcode` as a line of the QC file.
+pub fn write_cell_qc<W: Write>(writer: &mut W, barcode: &[u8], qc: &CellQc) -> std::io::Result<()> {
+    let mapping_rate = if qc.reads > 0 {
+        qc.assigned_reads as f64 / qc.reads as f64
+    } else {
+        0.0
+    };
+    writeln!(
+        writer,
+        "{}\t{}\t{}\t{}\t{:.4}\t{}\t{}\t{}\t{:.1}",
+        String::from_utf8_lossy(barcode),
+        qc.reads,
+        or_na(qc.umis),
+        qc.assigned_reads,
+        mapping_rate,
+        qc.detected_transcripts,
+        or_na(qc.detected_genes),
+        or_na(qc.mito_fraction.map(|f| format!("{:.4}", f))),
+        qc.mean_read_length
+    )
+}
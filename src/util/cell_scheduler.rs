@@ -0,0 +1,142 @@
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use crossbeam::utils::Backoff;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// How long an idle worker (or the producer, waiting for room) sleeps once
+/// spinning has not produced any work.
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+/// Schedules per-cell jobs over a fixed set of workers. Cells vary enormously in
+/// their number of reads, so a simple FIFO queue leaves workers idle behind a few
+/// very large cells. Instead, the producer submits cells in batches, each of which
+/// is ordered by decreasing size (so that the largest cells seen so far are started
+/// first), into a global injector; each worker takes jobs from its own deque, refills
+/// it in batches from the injector, and, when both are empty, steals from the deques
+/// of the other workers.
+///
+/// The number of submitted jobs that have not yet been taken by a worker is bounded
+/// by `capacity`, so that the producer can not run arbitrarily far ahead of the workers.
+pub struct CellScheduler<T> {
+    injector: Injector<T>,
+    stealers: Vec<Stealer<T>>,
+    capacity: usize,
+    pending: AtomicUsize,
+    done: AtomicBool,
+    num_stolen: AtomicUsize,
+}
+
+impl<T> CellScheduler<T> {
+    /// A scheduler for `num_workers` workers holding at most `capacity` pending jobs,
+    /// along with the local deque of each worker (to be moved into the worker).
+    pub fn new(num_workers: usize, capacity: usize) -> (Arc<Self>, Vec<Worker<T>>) {
+        let locals: Vec<Worker<T>> = (0..num_workers).map(|_| Worker::new_fifo()).collect();
+        let stealers = locals.iter().map(|w| w.stealer()).collect();
+        (
+            Arc::new(Self {
+                injector: Injector::new(),
+                stealers,
+                capacity: capacity.max(1),
+                pending: AtomicUsize::new(0),
+                done: AtomicBool::new(false),
+                num_stolen: AtomicUsize::new(0),
+            }),
+            locals,
+        )
+    }
+
+    /// The number of submitted jobs that have not yet been taken by a worker.
+    #[inline]
+    pub fn num_pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of jobs that were stolen from the deque of another worker.
+    pub fn num_stolen(&self) -> usize {
+        self.num_stolen.load(Ordering::Relaxed)
+    }
+
+    /// Submit the jobs of `batch`, each paired with its size, largest first, waiting
+    /// for room as necessary.
+    pub fn submit_batch(&self, batch: &mut Vec<(usize, T)>) {
+        batch.sort_by(|a, b| b.0.cmp(&a.0));
+        for (_, job) in batch.drain(..) {
+            let backoff = Backoff::new();
+            while self.num_pending() >= self.capacity {
+                if backoff.is_completed() {
+                    std::thread::sleep(IDLE_WAIT);
+                } else {
+                    backoff.snooze();
+                }
+            }
+            self.pending.fetch_add(1, Ordering::AcqRel);
+            self.injector.push(job);
+        }
+    }
+
+    /// Signal that no more jobs will be submitted.
+    pub fn finish(&self) {
+        self.done.store(true, Ordering::SeqCst);
+    }
+
+    /// Try once to find a job for the worker with the local deque `local`.
+    fn find_job(&self, local: &Worker<T>) -> Option<T> {
+        if let Some(job) = local.pop() {
+            return Some(job);
+        }
+        loop {
+            match self.injector.steal_batch_and_pop(local) {
+                Steal::Success(job) => return Some(job),
+                Steal::Retry => continue,
+                Steal::Empty => break,
+            }
+        }
+        loop {
+            let mut retry = false;
+            for s in self.stealers.iter() {
+                match s.steal() {
+                    Steal::Success(job) => {
+                        self.num_stolen.fetch_add(1, Ordering::Relaxed);
+                        return Some(job);
+                    }
+                    Steal::Retry => retry = true,
+                    Steal::Empty => {}
+                }
+            }
+            if !retry {
+                return None;
+            }
+        }
+    }
+
+    /// The next job for the worker with the local deque `local`, waiting until one is
+    /// available; returns [None] once all jobs have been submitted and taken.
+    pub fn next_job(&self, local: &Worker<T>) -> Option<T> {
+        let backoff = Backoff::new();
+        loop {
+            // read this before looking for work, so that no job submitted
+            // before the scheduler was finished can be missed
+            let done = self.done.load(Ordering::SeqCst);
+            if let Some(job) = self.find_job(local) {
+                // jobs batched into the local deque are counted
+                // as pending until they are taken from it
+                self.pending.fetch_sub(1, Ordering::AcqRel);
+                return Some(job);
+            }
+            if done {
+                return None;
+            }
+            if backoff.is_completed() {
+                std::thread::sleep(IDLE_WAIT);
+            } else {
+                backoff.snooze();
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::prog_opts::{Args, Tool};
+use clap::{Command, CommandFactory, Subcommand};
+use std::fs::{File, create_dir_all};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+const BIN_NAME: &str = "oarfish";
+
+/// The command line of oarfish as a whole: the quantification options, along with the
+/// auxiliary tools as subcommands (as they are dispatched by `main`).
+fn oarfish_command() -> Command {
+    Tool::augment_subcommands(Args::command())
+        .name(BIN_NAME)
+        .bin_name(BIN_NAME)
+}
+
+/// Write the completion script of oarfish for `shell` to stdout.
+pub fn write_completions(shell: clap_complete::Shell) -> anyhow::Result<()> {
+    let mut cmd = oarfish_command();
+    clap_complete::generate(shell, &mut cmd, BIN_NAME, &mut io::stdout());
+    Ok(())
+}
+
+fn write_man_page<W: Write>(cmd: Command, writer: &mut W) -> anyhow::Result<()> {
+    clap_mangen::Man::new(cmd).render(writer)?;
+    Ok(())
+}
+
+/// Write the man page of oarfish to stdout or, if `output_dir` is given, write the man
+/// pages of oarfish (`oarfish.1`) and of each of its tools (`oarfish-<tool>.1`) there.
+pub fn write_man_pages(output_dir: Option<&Path>) -> anyhow::Result<()> {
+    let cmd = oarfish_command();
+    let Some(dir) = output_dir else {
+        return write_man_page(cmd, &mut io::stdout().lock());
+    };
+
+    create_dir_all(dir)?;
+    for sub in cmd.get_subcommands() {
+        let name = format!("{}-{}", BIN_NAME, sub.get_name());
+        let path = dir.join(format!("{}.1", name));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        write_man_page(sub.clone().name(name), &mut writer)?;
+        writer.flush()?;
+    }
+    let path = dir.join(format!("{}.1", BIN_NAME));
+    let mut writer = BufWriter::new(File::create(&path)?);
+    write_man_page(cmd, &mut writer)?;
+    writer.flush()?;
+    info!("wrote the man pages to {}.", dir.display());
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use crate::util::oarfish_types::{
+    AlnInfo, CoverageBinning, InMemoryAlignmentStore, TranscriptInfo,
+};
+use anyhow::Context;
+use noodles_sam::header::record::value as header_val;
+use noodles_sam::header::record::value::Map as HeaderMap;
+use num_format::{Locale, ToFormattedString};
+use rustc_hash::FxHashMap;
+use std::io::{BufRead, BufReader};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Describes how the transcripts of the reference are collapsed into
+/// groups prior to running the EM. Each transcript belongs to exactly
+/// one group, and transcripts not mentioned by any rule form singleton
+/// groups.
+pub struct CollapseRules {
+    /// the group to which each transcript (by id) belongs
+    pub group_of: Vec<u32>,
+    /// the name of each group
+    pub group_names: Vec<String>,
+    /// the length of each group (the maximum length of its members)
+    pub group_lens: Vec<NonZeroUsize>,
+}
+
+/// If `name` ends with a version suffix (e.g. `ENST00000335137.4`), return
+/// the name with the version suffix removed, otherwise return `name`.
+fn strip_version(name: &str) -> &str {
+    match name.rsplit_once('.') {
+        Some((base, ver)) if !ver.is_empty() && ver.bytes().all(|c| c.is_ascii_digit()) => base,
+        _ => name,
+    }
+}
+
+/// Read a rules file consisting of (tab-separated) lines of the form
+/// `<transcript_name>\t<group_name>`. Empty lines and lines starting
+/// with `#` are ignored.
+fn read_rules_file(path: &Path) -> anyhow::Result<FxHashMap<String, String>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("could not open collapse rules file {}", path.display()))?;
+    let mut rules = FxHashMap::default();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('\t') {
+            Some((txp, group)) => {
+                rules.insert(txp.trim().to_owned(), group.trim().to_owned());
+            }
+            None => anyhow::bail!(
+                "line {} of collapse rules file {} was not of the form <transcript>\\t<group>",
+                lnum + 1,
+                path.display()
+            ),
+        }
+    }
+    Ok(rules)
+}
+
+impl CollapseRules {
+    /// Build the collapsing rules for the transcripts in `txps_name` (with
+    /// information `txps`). The explicit rules in `rules_file` (if provided)
+    /// take precedence; otherwise, if `collapse_versions` is true, transcripts
+    /// are grouped by their name with any version suffix removed.
+    pub fn new(
+        rules_file: Option<&Path>,
+        collapse_versions: bool,
+        txps: &[TranscriptInfo],
+        txps_name: &[String],
+    ) -> anyhow::Result<Self> {
+        let rules = match rules_file {
+            Some(p) => read_rules_file(p)?,
+            None => FxHashMap::default(),
+        };
+
+        let mut group_ids = FxHashMap::<String, u32>::default();
+        let mut group_of = Vec::with_capacity(txps_name.len());
+        let mut group_names = Vec::new();
+        let mut group_lens: Vec<NonZeroUsize> = Vec::new();
+        let mut num_ruled = 0_usize;
+
+        for (name, tinfo) in txps_name.iter().zip(txps.iter()) {
+            let group_name = if let Some(g) = rules.get(name) {
+                num_ruled += 1;
+                g.as_str()
+            } else if collapse_versions {
+                strip_version(name)
+            } else {
+                name.as_str()
+            };
+
+            let next_id = group_ids.len() as u32;
+            let gid = *group_ids.entry(group_name.to_owned()).or_insert(next_id);
+            if gid == next_id {
+                group_names.push(group_name.to_owned());
+                group_lens.push(tinfo.len);
+            } else {
+                let gl = &mut group_lens[gid as usize];
+                *gl = (*gl).max(tinfo.len);
+            }
+            group_of.push(gid);
+        }
+
+        if num_ruled < rules.len() {
+            warn!(
+                "{} transcripts in the collapse rules file did not appear in the reference and were ignored.",
+                (rules.len() - num_ruled).to_formatted_string(&Locale::en)
+            );
+        }
+        info!(
+            "collapsed {} transcripts into {} groups.",
+            txps_name.len().to_formatted_string(&Locale::en),
+            group_names.len().to_formatted_string(&Locale::en)
+        );
+
+        Ok(Self {
+            group_of,
+            group_names,
+            group_lens,
+        })
+    }
+
+    /// Build a header whose reference sequences are the collapsed groups.
+    pub fn collapsed_header(&self) -> noodles_sam::header::Header {
+        let mut header = noodles_sam::header::Header::builder();
+        for (name, len) in self.group_names.iter().zip(self.group_lens.iter()) {
+            header = header.add_reference_sequence(
+                name.clone(),
+                HeaderMap::<header_val::map::ReferenceSequence>::new(*len),
+            );
+        }
+        header.build()
+    }
+
+    /// Build the [TranscriptInfo] for each collapsed group.
+    pub fn collapsed_txp_info(
+        &self,
+        model_coverage: bool,
+        binning: &CoverageBinning,
+    ) -> Vec<TranscriptInfo> {
+        self.group_lens
+            .iter()
+            .map(|len| {
+                if model_coverage {
+                    TranscriptInfo::with_len_and_binning(*len, binning)
+                } else {
+                    TranscriptInfo::with_len(*len)
+                }
+            })
+            .collect()
+    }
+
+    /// Project the alignments in `store` onto the collapsed groups, producing
+    /// a new store over `header` (which should be obtained from
+    /// [CollapseRules::collapsed_header]). If a read aligns to multiple members
+    /// of the same group, only the member alignment with the highest alignment
+    /// probability is retained. The order of the reads is preserved.
+    pub fn collapse_store<'h>(
+        &self,
+        store: &InMemoryAlignmentStore,
+        header: &'h noodles_sam::header::Header,
+        txps: &mut [TranscriptInfo],
+    ) -> InMemoryAlignmentStore<'h> {
+        let mut cstore = store.empty_like(header);
+        cstore.aggregate_discard_table(&store.discard_table);
+        cstore.num_input_reads = store.num_input_reads;
+        cstore.second_chance = store.second_chance.clone();
+        cstore.trimming = store.trimming.clone();
+        cstore.internal_priming = store.internal_priming.clone();
+        cstore.demux = store.demux.clone();
+
+        let mut alns: Vec<AlnInfo> = Vec::new();
+        let mut probs: Vec<f32> = Vec::new();
+        for ((ag, aprobs, _cprobs), read_len) in store.iter().zip(store.read_lengths.iter()) {
+            alns.clear();
+            probs.clear();
+            for (a, p) in ag.iter().zip(aprobs.iter()) {
+                let gid = self.group_of[a.ref_id as usize];
+                if let Some(pos) = alns.iter().position(|x| x.ref_id == gid) {
+                    if *p > probs[pos] {
+                        alns[pos] = AlnInfo {
+                            ref_id: gid,
+                            ..a.clone()
+                        };
+                        probs[pos] = *p;
+                    }
+                } else {
+                    alns.push(AlnInfo {
+                        ref_id: gid,
+                        ..a.clone()
+                    });
+                    probs.push(*p);
+                }
+            }
+            if cstore.add_filtered_group(&alns, &probs, *read_len, txps) && alns.len() == 1 {
+                cstore.inc_unique_alignments();
+            }
+        }
+        // every read retains at least one (collapsed) alignment
+        cstore.read_lanes = store.read_lanes.clone();
+        cstore
+    }
+}
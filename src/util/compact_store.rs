@@ -0,0 +1,456 @@
+use crate::util::oarfish_types::AlnInfo;
+use bio_types::strand::Strand;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Distinguishes the files to which the blocks of [CompactAlignments] are spilled.
+static NUM_SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// The size (in bytes) of each block of the arena. Blocks are allocated
+/// once with this capacity and never grow, so that (unlike a single growing
+/// `Vec`) no reallocation, with its transient doubling of memory, takes place.
+const ARENA_BLOCK_SIZE: usize = 1 << 22;
+
+/// Probabilities in [0, 1] are linearly quantized to this many levels.
+const PROB_SCALE: f64 = u16::MAX as f64;
+
+/// The most bytes that a single encoded alignment can occupy: three
+/// varints (transcript id delta, start, span and strand) and two
+/// quantized probabilities.
+const MAX_ENCODED_ALN_LEN: usize = 3 * 5 + 2 * 2;
+
+/// A block of the arena that has been moved to disk (see [CompactAlignments::spill]).
+/// Its contents are mapped into memory from a file that has already been unlinked,
+/// so the pages can be evicted by the kernel (and re-read on demand) under memory
+/// pressure, and the file is removed from disk once the mapping is dropped.
+#[derive(Debug)]
+struct MappedBlock {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is owned exclusively by the block, and is only written through
+// `&mut self`.
+unsafe impl Send for MappedBlock {}
+unsafe impl Sync for MappedBlock {}
+
+impl MappedBlock {
+    /// Write `data` (which must be non-empty) to a new file in `dir`, and map it.
+    fn new(data: &[u8], dir: &Path) -> io::Result<Self> {
+        let path = dir.join(format!(
+            "oarfish-{}-aln-spill-{}.bin",
+            std::process::id(),
+            NUM_SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let written = file.write_all(data);
+        // the mapping (created below) keeps the contents alive
+        std::fs::remove_file(&path)?;
+        written?;
+        // SAFETY: the file is open for reading and writing, and holds `data.len()` bytes
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                data.len(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len: data.len(),
+        })
+    }
+}
+
+impl Drop for MappedBlock {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` describe a live mapping created in [MappedBlock::new]
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// A block of the arena of [CompactAlignments], held either in memory or on disk.
+#[derive(Debug)]
+enum Block {
+    Mem(Vec<u8>),
+    Mapped(MappedBlock),
+}
+
+impl Block {
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Block::Mem(v) => v,
+            // SAFETY: the mapping holds `len` initialized bytes
+            Block::Mapped(m) => unsafe { std::slice::from_raw_parts(m.ptr, m.len) },
+        }
+    }
+
+    #[inline]
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            Block::Mem(v) => v,
+            // SAFETY: the mapping holds `len` initialized bytes, and is writable
+            Block::Mapped(m) => unsafe { std::slice::from_raw_parts_mut(m.ptr, m.len) },
+        }
+    }
+}
+
+#[inline]
+fn quantize(p: f64) -> u16 {
+    if p <= 0.0 {
+        0
+    } else {
+        // never round a non-zero probability down to 0
+        ((p.min(1.0) * PROB_SCALE).round() as u16).max(1)
+    }
+}
+
+#[inline]
+fn dequantize(q: u16) -> f64 {
+    q as f64 / PROB_SCALE
+}
+
+#[inline]
+pub(crate) fn strand_code(s: Strand) -> u32 {
+    match s {
+        Strand::Forward => 0,
+        Strand::Reverse => 1,
+        Strand::Unknown => 2,
+    }
+}
+
+#[inline]
+pub(crate) fn strand_of_code(c: u32) -> Strand {
+    match c {
+        0 => Strand::Forward,
+        1 => Strand::Reverse,
+        _ => Strand::Unknown,
+    }
+}
+
+#[inline]
+fn push_varint(buf: &mut Vec<u8>, mut v: u32) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+#[inline]
+fn read_varint(buf: &[u8], pos: &mut usize) -> u32 {
+    let mut v = 0_u32;
+    let mut shift = 0;
+    loop {
+        let b = buf[*pos];
+        *pos += 1;
+        v |= ((b & 0x7f) as u32) << shift;
+        if b & 0x80 == 0 {
+            return v;
+        }
+        shift += 7;
+    }
+}
+
+#[inline]
+fn read_u16(buf: &[u8], pos: &mut usize) -> u16 {
+    let v = u16::from_le_bytes([buf[*pos], buf[*pos + 1]]);
+    *pos += 2;
+    v
+}
+
+/// A compact encoding of the alignments of each read, used in place of the
+/// separate `Vec`s of the [crate::util::oarfish_types::InMemoryAlignmentStore]
+/// when `--low-mem` is requested.
+///
+/// The alignments of each read are sorted by transcript id and written
+/// contiguously into an arena of fixed-size blocks, as
+///  * the number of alignments (varint), then for each alignment
+///  * the difference between its transcript id and that of the previous alignment (varint),
+///  * its start position (varint),
+///  * its span and strand, as `(end - start) << 2 | strand` (varint),
+///  * its alignment score probability, quantized to a `u16`,
+///  * its coverage probability, quantized to a `u16` (0 until it is set).
+///
+/// This takes roughly a third of the space of the uncompressed representation,
+/// at the cost of decoding the alignments of each read whenever they are visited.
+/// Probabilities are represented with an absolute precision of ~1.5e-5.
+///
+/// When memory runs short (see `--max-mem`), the filled blocks can be moved to
+/// disk with [CompactAlignments::spill].
+#[derive(Debug)]
+pub struct CompactAlignments {
+    blocks: Vec<Block>,
+    // the location of the encoded alignments of each read,
+    // as `(block index << 32) | offset within block`
+    group_locs: Vec<u64>,
+    num_alignments: usize,
+    // scratch space used to sort the alignments of a read
+    order: Vec<usize>,
+}
+
+impl Default for CompactAlignments {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompactAlignments {
+    pub fn new() -> Self {
+        Self {
+            blocks: vec![],
+            group_locs: vec![],
+            num_alignments: 0,
+            order: vec![],
+        }
+    }
+
+    /// The number of reads whose alignments are stored.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.group_locs.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.group_locs.is_empty()
+    }
+
+    /// The total number of alignments stored (across all reads).
+    #[inline]
+    pub fn num_alignments(&self) -> usize {
+        self.num_alignments
+    }
+
+    /// Append the alignments `alns` of a read, with alignment score
+    /// probabilities `as_probs`.
+    pub fn push(&mut self, alns: &[AlnInfo], as_probs: &[f32]) {
+        let max_len = 5 + alns.len() * MAX_ENCODED_ALN_LEN;
+        let needs_block = match self.blocks.last() {
+            Some(Block::Mem(b)) => b.capacity() - b.len() < max_len,
+            _ => true,
+        };
+        if needs_block {
+            self.blocks.push(Block::Mem(Vec::with_capacity(
+                ARENA_BLOCK_SIZE.max(max_len),
+            )));
+        }
+        let block_idx = self.blocks.len() - 1;
+        let Block::Mem(block) = &mut self.blocks[block_idx] else {
+            unreachable!("the last block of the arena is held in memory");
+        };
+        self.group_locs
+            .push(((block_idx as u64) << 32) | block.len() as u64);
+
+        self.order.clear();
+        self.order.extend(0..alns.len());
+        self.order.sort_unstable_by_key(|i| alns[*i].ref_id);
+
+        push_varint(block, alns.len() as u32);
+        let mut prev_ref = 0_u32;
+        for i in self.order.iter() {
+            let a = &alns[*i];
+            push_varint(block, a.ref_id - prev_ref);
+            push_varint(block, a.start);
+            push_varint(block, (a.alignment_span() << 2) | strand_code(a.strand));
+            block.extend_from_slice(&quantize(as_probs[*i] as f64).to_le_bytes());
+            block.extend_from_slice(&0_u16.to_le_bytes());
+            prev_ref = a.ref_id;
+        }
+        self.num_alignments += alns.len();
+    }
+
+    #[inline]
+    fn group_start(&self, i: usize) -> (&[u8], usize) {
+        let loc = self.group_locs[i];
+        (
+            self.blocks[(loc >> 32) as usize].as_slice(),
+            (loc & 0xffff_ffff) as usize,
+        )
+    }
+
+    /// Decode the alignments of read `i`, along with their alignment score and
+    /// coverage probabilities, into `alns`, `as_probs` and `cov_probs`.
+    pub fn decode(
+        &self,
+        i: usize,
+        alns: &mut Vec<AlnInfo>,
+        as_probs: &mut Vec<f32>,
+        cov_probs: &mut Vec<f64>,
+    ) {
+        let (block, mut pos) = self.group_start(i);
+        let n = read_varint(block, &mut pos) as usize;
+        alns.reserve(n);
+        as_probs.reserve(n);
+        cov_probs.reserve(n);
+        let mut ref_id = 0_u32;
+        for _ in 0..n {
+            ref_id += read_varint(block, &mut pos);
+            let start = read_varint(block, &mut pos);
+            let span_strand = read_varint(block, &mut pos);
+            alns.push(AlnInfo {
+                ref_id,
+                start,
+                end: start + (span_strand >> 2),
+                prob: 0.0_f64,
+                strand: strand_of_code(span_strand & 0b11),
+            });
+            as_probs.push(dequantize(read_u16(block, &mut pos)) as f32);
+            cov_probs.push(dequantize(read_u16(block, &mut pos)));
+        }
+    }
+
+    /// Set the coverage probabilities of the alignments of read `i` (in the
+    /// order in which they are returned by [CompactAlignments::decode]).
+    pub fn set_coverage_probs(&mut self, i: usize, probs: &[f64]) {
+        let loc = self.group_locs[i];
+        let block = self.blocks[(loc >> 32) as usize].as_mut_slice();
+        let mut pos = (loc & 0xffff_ffff) as usize;
+        let n = read_varint(block, &mut pos) as usize;
+        assert_eq!(
+            n,
+            probs.len(),
+            "wrong number of coverage probabilities for read"
+        );
+        for p in probs {
+            // skip the transcript id, start and span
+            for _ in 0..3 {
+                read_varint(block, &mut pos);
+            }
+            // skip the alignment score probability
+            pos += 2;
+            block[pos..pos + 2].copy_from_slice(&quantize(*p).to_le_bytes());
+            pos += 2;
+        }
+    }
+
+    /// Move the blocks of the arena held in memory, other than the one currently
+    /// being filled, to files in `dir` (which are mapped back into memory on demand).
+    /// Returns the number of blocks, and of bytes, moved.
+    pub fn spill(&mut self, dir: &Path) -> io::Result<(usize, usize)> {
+        let num_filled = self.blocks.len().saturating_sub(1);
+        let mut num_blocks = 0_usize;
+        let mut num_bytes = 0_usize;
+        for block in self.blocks[..num_filled].iter_mut() {
+            if let Block::Mem(data) = block {
+                if data.is_empty() {
+                    continue;
+                }
+                let mapped = MappedBlock::new(data, dir)?;
+                num_blocks += 1;
+                num_bytes += mapped.len;
+                *block = Block::Mapped(mapped);
+            }
+        }
+        Ok((num_blocks, num_bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aln(ref_id: u32, start: u32, end: u32, strand: Strand) -> AlnInfo {
+        AlnInfo {
+            ref_id,
+            start,
+            end,
+            prob: 0.0,
+            strand,
+        }
+    }
+
+    #[test]
+    fn quantization_round_trips_within_precision() {
+        for p in [0.0, 1e-6, 1e-3, 0.1, 0.25, 0.5, 0.999, 1.0] {
+            let q = dequantize(quantize(p));
+            assert!((q - p).abs() <= 0.5 / PROB_SCALE + 1e-12, "{} -> {}", p, q);
+        }
+        // non-zero probabilities are never rounded down to 0, and out of range ones are clamped
+        assert!(dequantize(quantize(1e-9)) > 0.0);
+        assert_eq!(quantize(-0.5), 0);
+        assert_eq!(dequantize(quantize(2.0)), 1.0);
+    }
+
+    #[test]
+    fn alignments_round_trip() {
+        let alns = vec![
+            aln(70_000, 1_000_000, 1_002_500, Strand::Reverse),
+            aln(3, 0, 150, Strand::Forward),
+            aln(3, 200, 900, Strand::Unknown),
+        ];
+        let as_probs = vec![0.5_f32, 0.25, 1.0];
+        let mut store = CompactAlignments::new();
+        store.push(&alns, &as_probs);
+        store.push(&[], &[]);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.num_alignments(), 3);
+
+        let (mut dec, mut dec_as, mut dec_cov) = (vec![], vec![], vec![]);
+        store.decode(0, &mut dec, &mut dec_as, &mut dec_cov);
+        // the alignments come back sorted by transcript id
+        let ids: Vec<u32> = dec.iter().map(|a| a.ref_id).collect();
+        assert_eq!(ids, vec![3, 3, 70_000]);
+        for (a, p) in dec.iter().zip(dec_as.iter()) {
+            let i = alns
+                .iter()
+                .position(|b| b.ref_id == a.ref_id && b.start == a.start)
+                .unwrap();
+            assert_eq!((a.end, a.strand), (alns[i].end, alns[i].strand));
+            assert!((*p - as_probs[i]).abs() < 1e-4);
+        }
+        assert!(dec_cov.iter().all(|c| *c == 0.0));
+
+        store.set_coverage_probs(0, &[0.1, 0.2, 0.7]);
+        let (mut dec, mut dec_as, mut dec_cov) = (vec![], vec![], vec![]);
+        store.decode(0, &mut dec, &mut dec_as, &mut dec_cov);
+        for (c, e) in dec_cov.iter().zip([0.1, 0.2, 0.7]) {
+            assert!((c - e).abs() <= 0.5 / PROB_SCALE + 1e-12);
+        }
+
+        let (mut dec, mut dec_as, mut dec_cov) = (vec![], vec![], vec![]);
+        store.decode(1, &mut dec, &mut dec_as, &mut dec_cov);
+        assert!(dec.is_empty() && dec_as.is_empty() && dec_cov.is_empty());
+    }
+
+    #[test]
+    fn spilled_blocks_decode_identically() {
+        let mut store = CompactAlignments::new();
+        // enough reads to fill more than one block
+        let num_reads = 2 * ARENA_BLOCK_SIZE / MAX_ENCODED_ALN_LEN;
+        for r in 0..num_reads as u32 {
+            store.push(&[aln(r % 1000, r, r + 100, Strand::Forward)], &[0.5]);
+        }
+        let decode_all = |store: &CompactAlignments| {
+            let (mut alns, mut as_probs, mut cov_probs) = (vec![], vec![], vec![]);
+            for i in 0..store.len() {
+                store.decode(i, &mut alns, &mut as_probs, &mut cov_probs);
+            }
+            (alns, as_probs)
+        };
+        let (before, before_as) = decode_all(&store);
+
+        let (num_blocks, _) = store.spill(&std::env::temp_dir()).unwrap();
+        assert!(num_blocks > 0);
+        store.set_coverage_probs(0, &[1.0]);
+        let (after, after_as) = decode_all(&store);
+        assert_eq!(before, after);
+        assert_eq!(before_as, after_as);
+    }
+}
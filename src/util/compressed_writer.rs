@@ -0,0 +1,200 @@
+use crate::prog_opts::OutputCompression;
+use crossbeam::channel::{Sender, bounded};
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+/// The (approximate) size, in bytes, of the chunks handed to the background thread.
+const CHUNK_SIZE: usize = 1 << 20;
+/// The number of chunks that may be queued for the background thread before the
+/// writing side blocks.
+const CHUNK_QUEUE_LEN: usize = 8;
+/// The zstd compression level of the outputs.
+const ZSTD_LEVEL: i32 = 3;
+
+/// An output file, written through the encoder of its compression (if any).
+pub(crate) enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<File>>),
+    Lz4(lz4::Encoder<File>),
+}
+
+impl Encoder {
+    /// Create the file at `path`, compressed with `compression`.
+    pub(crate) fn create(path: &Path, compression: OutputCompression) -> io::Result<Self> {
+        let file = BufWriter::with_capacity(CHUNK_SIZE, File::create(path)?);
+        Ok(match compression {
+            OutputCompression::None => Self::Plain(file),
+            OutputCompression::Gzip => {
+                Self::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+            }
+            OutputCompression::Zstd => {
+                Self::Zstd(zstd::stream::write::Encoder::new(file, ZSTD_LEVEL)?)
+            }
+        })
+    }
+
+    /// Create the lz4-compressed file at `path`.
+    pub(crate) fn create_lz4(path: &Path) -> io::Result<Self> {
+        Ok(Self::Lz4(
+            lz4::EncoderBuilder::new()
+                .level(4)
+                .build(File::create(path)?)?,
+        ))
+    }
+
+    /// Complete the compressed stream (if any), and flush the file.
+    pub(crate) fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Plain(mut w) => w.flush(),
+            Self::Gzip(w) => w.finish()?.flush(),
+            Self::Zstd(w) => w.finish()?.flush(),
+            Self::Lz4(w) => {
+                let (_output, result) = w.finish();
+                result
+            }
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(w) => w.write(buf),
+            Self::Gzip(w) => w.write(buf),
+            Self::Zstd(w) => w.write(buf),
+            Self::Lz4(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+            Self::Zstd(w) => w.flush(),
+            Self::Lz4(w) => w.flush(),
+        }
+    }
+}
+
+/// The path of the file `path` once compressed with `compression`.
+pub fn compressed_path(path: &Path, compression: OutputCompression) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(compression.extension());
+    PathBuf::from(p)
+}
+
+/// A writer whose output is collected into large chunks that are passed, through a
+/// bounded channel, to a background thread that compresses them and writes them to
+/// the file, so that the compression does not hold up the writing side. The writer
+/// must be completed with [CompressedWriter::finish], which reports any error
+/// encountered by the background thread.
+pub struct CompressedWriter {
+    buf: Vec<u8>,
+    tx: Option<Sender<Vec<u8>>>,
+    handle: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl CompressedWriter {
+    /// Create the file `path` (with the extension of `compression` appended), to be
+    /// compressed with `compression`.
+    pub fn create(path: &Path, compression: OutputCompression) -> io::Result<Self> {
+        let mut encoder = Encoder::create(&compressed_path(path, compression), compression)?;
+        let (tx, rx) = bounded::<Vec<u8>>(CHUNK_QUEUE_LEN);
+        let handle = std::thread::spawn(move || -> io::Result<()> {
+            for chunk in rx {
+                encoder.write_all(&chunk)?;
+            }
+            encoder.finish()
+        });
+        Ok(Self {
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            tx: Some(tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Hand the buffered output to the background thread.
+    fn send_buf(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        let sent = self.tx.as_ref().map(|tx| tx.send(chunk));
+        match sent {
+            Some(Ok(())) => Ok(()),
+            // the background thread has exited early, so report its error
+            _ => Err(self.join().err().unwrap_or_else(|| {
+                io::Error::other("the output compression thread exited unexpectedly")
+            })),
+        }
+    }
+
+    fn join(&mut self) -> io::Result<()> {
+        // closing the channel lets the background thread finish
+        self.tx = None;
+        match self.handle.take() {
+            Some(h) => h
+                .join()
+                .map_err(|_| io::Error::other("the output compression thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+
+    /// Write out the remaining output and wait for the background thread to complete
+    /// the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.send_buf()?;
+        self.join()
+    }
+}
+
+impl Write for CompressedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_buf()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buf()
+    }
+}
+
+impl Drop for CompressedWriter {
+    fn drop(&mut self) {
+        // if the writer was not finished, still complete the file
+        if self.handle.is_some() {
+            let _ = self.send_buf();
+            let _ = self.join();
+        }
+    }
+}
+
+/// Open the text file `path` for reading or, if it does not exist, its gzip- or
+/// zstd-compressed version (`path` with a `.gz` or `.zst` suffix), as written with
+/// `--compress`.
+pub fn open_maybe_compressed(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    if path.exists() {
+        return Ok(Box::new(BufReader::new(File::open(path)?)));
+    }
+    let gz_path = compressed_path(path, OutputCompression::Gzip);
+    if gz_path.exists() {
+        return Ok(Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(
+            File::open(gz_path)?,
+        ))));
+    }
+    let zst_path = compressed_path(path, OutputCompression::Zstd);
+    if zst_path.exists() {
+        return Ok(Box::new(BufReader::new(zstd::stream::read::Decoder::new(
+            File::open(zst_path)?,
+        )?)));
+    }
+    // report the missing uncompressed file
+    Ok(Box::new(BufReader::new(File::open(path)?)))
+}
@@ -0,0 +1,222 @@
+use crate::prog_opts::OutputCompression;
+use crate::util::compressed_writer::CompressedWriter;
+use crate::util::provenance;
+use anyhow::bail;
+use path_tools::WithAdditionalExtension;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The category of the contaminant sequences whose category is not given.
+pub const DEFAULT_CATEGORY: &str = "contaminant";
+
+/// The contaminant transcripts of the reference (e.g. rRNA, mitochondrial or spike-in
+/// sequences), each in a category.
+pub struct Contaminants {
+    /// the names of the categories, in the order in which they are first listed
+    pub categories: Vec<String>,
+    /// the index (in `categories`) of the category of each transcript, or [None] if
+    /// the transcript is not a contaminant
+    pub txp_category: Vec<Option<usize>>,
+}
+
+/// The estimated reads of the contaminant transcripts of one category.
+#[derive(Debug, Serialize)]
+pub struct CategorySummary {
+    pub category: String,
+    /// the number of transcripts of this category in the reference
+    pub num_txps: usize,
+    /// the estimated number of reads arising from the transcripts of this category
+    pub num_reads: f64,
+    /// the fraction of the assigned reads arising from the transcripts of this category
+    pub frac_assigned: f64,
+}
+
+/// The contaminant screening report of a sample.
+#[derive(Debug, Serialize)]
+pub struct ContaminantReport {
+    pub categories: Vec<CategorySummary>,
+    /// the estimated number of reads arising from all contaminant transcripts
+    pub num_contaminant_reads: f64,
+    /// the estimated number of reads assigned to all transcripts, including the
+    /// contaminants (e.g. for normalizing the estimates when these are excluded)
+    pub num_assigned_reads: f64,
+    /// the fraction of the assigned reads arising from contaminant transcripts
+    pub frac_assigned: f64,
+    /// the fraction of the input reads arising from contaminant transcripts, if the
+    /// number of input reads is known
+    pub frac_input: Option<f64>,
+    /// true if the contaminant transcripts were excluded from the main output
+    pub excluded: bool,
+}
+
+fn fraction(num: f64, denom: f64) -> f64 {
+    if denom > 0.0 { num / denom } else { 0.0 }
+}
+
+impl Contaminants {
+    /// Read the contaminant transcripts from `path`, which is either a tab-separated file
+    /// with lines of the form `<transcript>\t<category>` (or just `<transcript>`, in the
+    /// category [DEFAULT_CATEGORY]; empty lines and lines starting with `#` are ignored),
+    /// or a FASTA file, whose records name the contaminant transcripts and whose header
+    /// descriptions (if any) begin with their category.
+    pub fn read(path: &Path, txps_name: &[String]) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let mut listed = HashMap::<String, String>::new();
+        let mut is_fasta = None;
+        for (lnum, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fasta = *is_fasta.get_or_insert(line.starts_with('>'));
+            let (name, category) = if fasta {
+                let Some(record) = line.strip_prefix('>') else {
+                    continue;
+                };
+                let mut fields = record.split_whitespace();
+                (fields.next(), fields.next())
+            } else {
+                if line.starts_with('#') {
+                    continue;
+                }
+                let mut fields = line.split('\t').map(str::trim);
+                (fields.next(), fields.next().filter(|c| !c.is_empty()))
+            };
+            let Some(name) = name.filter(|n| !n.is_empty()) else {
+                bail!(
+                    "line {} of contaminant file {} does not name a transcript",
+                    lnum + 1,
+                    path.display()
+                );
+            };
+            listed.insert(
+                name.to_owned(),
+                category.unwrap_or(DEFAULT_CATEGORY).to_owned(),
+            );
+        }
+
+        let mut categories = Vec::<String>::new();
+        let mut num_found = 0_usize;
+        let txp_category = txps_name
+            .iter()
+            .map(|name| {
+                listed.get(name).map(|cat| {
+                    num_found += 1;
+                    match categories.iter().position(|c| c == cat) {
+                        Some(i) => i,
+                        None => {
+                            categories.push(cat.clone());
+                            categories.len() - 1
+                        }
+                    }
+                })
+            })
+            .collect();
+        if num_found < listed.len() {
+            warn!(
+                "{} of the contaminant sequences in {} are not in the reference, and will not be screened; contaminant sequences must be part of the reference the reads are aligned to.",
+                listed.len() - num_found,
+                path.display()
+            );
+        }
+        info!(
+            "screening {} contaminant transcripts in {} categories ({}).",
+            num_found,
+            categories.len(),
+            categories.join(", ")
+        );
+        Ok(Self {
+            categories,
+            txp_category,
+        })
+    }
+
+    /// For each transcript, true if it is a contaminant.
+    pub fn mask(&self) -> Vec<bool> {
+        self.txp_category.iter().map(Option::is_some).collect()
+    }
+
+    /// Summarize the estimated reads (`counts`) of the contaminant transcripts, by category,
+    /// relative to all assigned reads and to the `num_input_reads` (0 if unknown).
+    pub fn summarize(
+        &self,
+        counts: &[f64],
+        num_input_reads: usize,
+        excluded: bool,
+    ) -> ContaminantReport {
+        let mut categories: Vec<CategorySummary> = self
+            .categories
+            .iter()
+            .map(|c| CategorySummary {
+                category: c.clone(),
+                num_txps: 0,
+                num_reads: 0.0,
+                frac_assigned: 0.0,
+            })
+            .collect();
+        for (cat, c) in self.txp_category.iter().zip(counts.iter()) {
+            if let Some(cat) = cat {
+                categories[*cat].num_txps += 1;
+                categories[*cat].num_reads += c;
+            }
+        }
+        let num_assigned_reads: f64 = counts.iter().sum();
+        for s in categories.iter_mut() {
+            s.frac_assigned = fraction(s.num_reads, num_assigned_reads);
+        }
+        let num_contaminant_reads: f64 = categories.iter().map(|s| s.num_reads).sum();
+        let report = ContaminantReport {
+            categories,
+            num_contaminant_reads,
+            num_assigned_reads,
+            frac_assigned: fraction(num_contaminant_reads, num_assigned_reads),
+            frac_input: (num_input_reads > 0)
+                .then(|| fraction(num_contaminant_reads, num_input_reads as f64)),
+            excluded,
+        };
+        for s in report.categories.iter() {
+            info!(
+                "{}: {:.2} reads ({:.2}% of assigned reads).",
+                s.category,
+                s.num_reads,
+                100.0 * s.frac_assigned
+            );
+        }
+        report
+    }
+}
+
+/// Write the estimated `counts` of the contaminant transcripts (those of `contaminants`)
+/// to `<output>.contaminants.quant`, in the format of `<output>.quant` with an additional
+/// `category` column.
+pub fn write_contaminant_quant(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    counts: &[f64],
+    contaminants: &Contaminants,
+    compression: OutputCompression,
+) -> anyhow::Result<()> {
+    let out_path = output.with_additional_extension(".contaminants.quant");
+    let mut writer = CompressedWriter::create(&out_path, compression)?;
+    provenance::write_tsv_comment(&mut writer)?;
+    writeln!(writer, "tname\tlen\tnum_reads\tcategory")?;
+    for (i, (rseq, rmap)) in header.reference_sequences().iter().enumerate() {
+        if let Some(cat) = contaminants.txp_category[i] {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}",
+                rseq,
+                rmap.length(),
+                counts[i],
+                contaminants.categories[cat]
+            )?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use crate::util::provenance;
+use crate::util::read_length_strata::tv_distance;
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use tracing::info;
+
+/// The pseudocount added to both estimates when computing the fold change
+/// of a transcript's estimate, so that low-abundance transcripts do not
+/// dominate the transcripts reported as changed.
+const FOLD_CHANGE_PSEUDOCOUNT: f64 = 1.0;
+/// Transcripts whose estimate changes by at least this (absolute) log2 fold
+/// change are counted as changed by the coverage model.
+const CHANGED_LOG2_FC: f64 = 1.0;
+
+/// A comparison of the estimates obtained with and without the coverage model.
+#[derive(Debug, Serialize)]
+pub struct CoverageComparison {
+    /// total variation distance between the relative abundances
+    /// estimated with and without the coverage model
+    pub tv_distance: f64,
+    /// the total number of reads whose assignment changes between the two estimates
+    pub num_reads_moved: f64,
+    /// the number of transcripts expressed in either estimate
+    pub num_expressed: usize,
+    /// the number of transcripts whose estimate changes at least twofold
+    pub num_changed: usize,
+    /// the number of transcripts expressed only with the coverage model
+    pub num_gained: usize,
+    /// the number of transcripts expressed only without the coverage model
+    pub num_lost: usize,
+    #[serde(skip)]
+    pub no_coverage_counts: Vec<f64>,
+}
+
+#[inline]
+fn log2_fold_change(with_cov: f64, no_cov: f64) -> f64 {
+    ((with_cov + FOLD_CHANGE_PSEUDOCOUNT) / (no_cov + FOLD_CHANGE_PSEUDOCOUNT)).log2()
+}
+
+/// Compare the estimated `counts` obtained with the coverage model to the
+/// estimates `no_coverage_counts` obtained from the same reads without it.
+pub fn compare_coverage_estimates(
+    counts: &[f64],
+    no_coverage_counts: Vec<f64>,
+) -> CoverageComparison {
+    let mut num_reads_moved = 0.0_f64;
+    let mut num_expressed = 0_usize;
+    let mut num_changed = 0_usize;
+    let mut num_gained = 0_usize;
+    let mut num_lost = 0_usize;
+    for (c, n) in counts.iter().zip(no_coverage_counts.iter()) {
+        num_reads_moved += (c - n).abs();
+        if *c > 0.0 || *n > 0.0 {
+            num_expressed += 1;
+            num_changed += (log2_fold_change(*c, *n).abs() >= CHANGED_LOG2_FC) as usize;
+            num_gained += (*n == 0.0) as usize;
+            num_lost += (*c == 0.0) as usize;
+        }
+    }
+    // each moved read is counted once on the transcript it left
+    // and once on the transcript it moved to
+    num_reads_moved /= 2.0;
+
+    let cmp = CoverageComparison {
+        tv_distance: tv_distance(counts, &no_coverage_counts),
+        num_reads_moved,
+        num_expressed,
+        num_changed,
+        num_gained,
+        num_lost,
+        no_coverage_counts,
+    };
+    info!(
+        "coverage model comparison : {:.1} reads reassigned, {} of {} expressed transcripts changed at least twofold, total variation distance = {:.4}",
+        cmp.num_reads_moved,
+        cmp.num_changed.to_formatted_string(&Locale::en),
+        cmp.num_expressed.to_formatted_string(&Locale::en),
+        cmp.tv_distance
+    );
+    cmp
+}
+
+/// Write the estimates obtained with (`counts`) and without the coverage
+/// model, along with their difference, to `<output>.coverage_comparison.tsv`.
+pub fn write_coverage_comparison(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    counts: &[f64],
+    cmp: &CoverageComparison,
+) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".coverage_comparison.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    writeln!(
+        writer,
+        "tname\tlen\tnum_reads_coverage\tnum_reads_no_coverage\tdiff\tlog2fc"
+    )?;
+    for (i, (rseq, rmap)) in header.reference_sequences().iter().enumerate() {
+        let (c, n) = (counts[i], cmp.no_coverage_counts[i]);
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            rseq,
+            rmap.length(),
+            c,
+            n,
+            c - n,
+            log2_fold_change(c, n)
+        )?;
+    }
+    Ok(())
+}
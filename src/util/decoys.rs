@@ -0,0 +1,168 @@
+use crate::prog_opts::DecoyMode;
+use crate::util::oarfish_types::{DECOY_SCORE_BINS, DecoyTable};
+use crate::util::object_store_io::StagedFile;
+use anyhow::Context;
+use needletail::parse_fastx_file;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// The prefix of the name of each decoy target.
+pub const DECOY_PREFIX: &str = "oarfish_decoy_";
+/// The seed of the random number generator used to shuffle the targets, so that
+/// the same decoys are produced on every run.
+const DECOY_SEED: u64 = 0x0a4f_15e5;
+/// The suggested score threshold is the one at which a decoy alignment would be
+/// retained for at most this fraction of the reads whose best alignment is to a
+/// real target.
+const DECOY_PASS_FRACTION: f64 = 0.001;
+const FASTA_LINE_WIDTH: usize = 80;
+
+fn write_fasta_record<W: Write>(writer: &mut W, name: &str, seq: &[u8]) -> anyhow::Result<()> {
+    writeln!(writer, ">{}", name)?;
+    for line in seq.chunks(FASTA_LINE_WIDTH) {
+        writer.write_all(line)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Write a copy of the FASTA `reference`, followed by a decoy (derived according to
+/// `mode`) of each of its targets, to a temporary file from which the index is built.
+/// The decoys follow all of the real targets, so that they occupy the largest target
+/// ids, and are named by prefixing the name of their target with [DECOY_PREFIX].
+pub fn write_decoy_reference(reference: &Path, mode: DecoyMode) -> anyhow::Result<StagedFile> {
+    let local = std::env::temp_dir().join(format!("oarfish-{}-decoys.fa", std::process::id()));
+    // the file is removed, even if writing it fails, once this is dropped
+    let staged = StagedFile::new(local);
+    let mut writer = BufWriter::new(File::create(staged.path())?);
+    let mut rng = StdRng::seed_from_u64(DECOY_SEED);
+
+    // the real targets are written in a first pass, and their decoys in a second
+    for decoy_pass in [false, true] {
+        let mut reader = parse_fastx_file(reference)
+            .with_context(|| format!("could not read {}", reference.display()))?;
+        while let Some(result) = reader.next() {
+            let record = result?;
+            let id = record.id();
+            let name = id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id);
+            let name = String::from_utf8_lossy(name);
+            let mut seq = record.seq().into_owned();
+            if decoy_pass {
+                match mode {
+                    DecoyMode::Reverse => seq.reverse(),
+                    DecoyMode::Shuffle => seq.shuffle(&mut rng),
+                }
+                write_fasta_record(&mut writer, &format!("{}{}", DECOY_PREFIX, name), &seq)?;
+            } else {
+                write_fasta_record(&mut writer, &name, &seq)?;
+            }
+        }
+    }
+    writer.flush()?;
+    info!(
+        "wrote the reference, with {:?} decoys, to {}.",
+        mode,
+        staged.path().display()
+    );
+    Ok(staged)
+}
+
+/// The id of the first decoy target, given the names of all targets.
+pub fn decoy_start(txps_name: &[String]) -> Option<usize> {
+    txps_name.iter().position(|n| n.starts_with(DECOY_PREFIX))
+}
+
+/// The empirical estimate of the false-assignment rate obtained from the decoys.
+#[derive(Debug, Serialize)]
+pub struct DecoyReport {
+    pub mode: DecoyMode,
+    pub num_targets: usize,
+    pub num_decoys: usize,
+    /// the (estimated) number of reads assigned to the real targets
+    pub target_reads: f64,
+    /// the (estimated) number of reads assigned to the decoys
+    pub decoy_reads: f64,
+    /// the estimated fraction of the reads assigned to the real targets that are
+    /// false assignments; since the decoys match the real targets in number and
+    /// length, as many reads are expected to be falsely assigned to the real
+    /// targets as are assigned to the decoys
+    pub false_assignment_rate: f64,
+    /// reads whose best alignment is to a decoy
+    pub reads_best_decoy: u32,
+    /// reads whose best alignment is to a real target
+    pub reads_best_target: u32,
+    pub score_threshold: f32,
+    /// reads whose best alignment is to a real target, but which retained an
+    /// alignment to a decoy under the current score threshold
+    pub reads_with_decoy_retained: u64,
+    /// the score threshold at which an alignment to a decoy would be retained
+    /// for at most 0.1% of the reads whose best alignment is to a real target
+    pub suggested_score_threshold: f32,
+}
+
+/// Summarize the reads assigned to the decoys (the targets with ids `decoy_start`
+/// and larger), given the estimated read `counts` of all targets and the decoy
+/// alignments recorded in `table` while the alignments were filtered.
+pub fn summarize_decoys(
+    mode: DecoyMode,
+    counts: &[f64],
+    decoy_start: usize,
+    table: &DecoyTable,
+    score_threshold: f32,
+) -> DecoyReport {
+    let target_reads: f64 = counts[..decoy_start].iter().sum();
+    let decoy_reads: f64 = counts[decoy_start..].iter().sum();
+    let false_assignment_rate = if target_reads > 0.0 {
+        (decoy_reads / target_reads).min(1.0)
+    } else {
+        0.0
+    };
+
+    let hist = &table.score_ratio_hist;
+    let threshold_bin =
+        ((score_threshold * DECOY_SCORE_BINS as f32) as usize).min(DECOY_SCORE_BINS);
+    let retained_from = |bin: usize| -> u64 { hist.iter().skip(bin).map(|c| *c as u64).sum() };
+    let reads_with_decoy_retained = retained_from(threshold_bin);
+    let max_retained = (DECOY_PASS_FRACTION * table.best_target as f64) as u64;
+    let suggested_score_threshold = if reads_with_decoy_retained <= max_retained {
+        score_threshold
+    } else {
+        let bin = (threshold_bin..=DECOY_SCORE_BINS)
+            .find(|b| retained_from(*b) <= max_retained)
+            .unwrap_or(DECOY_SCORE_BINS);
+        (bin as f32 / DECOY_SCORE_BINS as f32).min(1.0)
+    };
+
+    let report = DecoyReport {
+        mode,
+        num_targets: decoy_start,
+        num_decoys: counts.len() - decoy_start,
+        target_reads,
+        decoy_reads,
+        false_assignment_rate,
+        reads_best_decoy: table.best_decoy,
+        reads_best_target: table.best_target,
+        score_threshold,
+        reads_with_decoy_retained,
+        suggested_score_threshold,
+    };
+    info!(
+        "{:.1} reads were assigned to the decoys and {:.1} to the real targets; the estimated false-assignment rate is {:.3}%.",
+        report.decoy_reads,
+        report.target_reads,
+        100.0 * report.false_assignment_rate
+    );
+    if report.suggested_score_threshold > score_threshold {
+        warn!(
+            "{} reads retained an alignment to a decoy under the score threshold of {}; consider a `--score-threshold` of {}.",
+            report.reads_with_decoy_retained, score_threshold, report.suggested_score_threshold
+        );
+    }
+    report
+}
@@ -0,0 +1,406 @@
+use crate::util::errors::bad_input;
+use crate::util::provenance;
+use crate::util::trimming::{Adapter, best_match};
+use anyhow::Context;
+use needletail::parse_fastx_file;
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// The name of the built-in set of the native barcodes of the ONT native barcoding kits.
+pub const ONT_NATIVE: &str = "ont-native";
+
+/// The native barcodes NB01-NB12 of the ONT native barcoding kits (e.g. EXP-NBD104).
+const ONT_NATIVE_BARCODES: [(&str, &[u8]); 12] = [
+    ("NB01", b"CACAAAGACACCGACAACTTTCTT"),
+    ("NB02", b"ACAGACGACTACAAACGGAATCGA"),
+    ("NB03", b"CCTGGTAACTGGGACACAAGACTC"),
+    ("NB04", b"TAGGGAAACACGATAGAATCCGAA"),
+    ("NB05", b"AAGGTTACACAAACCCTGGACAAG"),
+    ("NB06", b"GACTACTTTCTGCCTTTGCGAGAA"),
+    ("NB07", b"AAGGATTCATTCCCACGGTAACAC"),
+    ("NB08", b"ACGTAACTTGGTTTGTTCCCTGAA"),
+    ("NB09", b"AACCAAGACTCGCTGTGCCTAGTT"),
+    ("NB10", b"GAGAGGACAAAGGTTTCAACGCTT"),
+    ("NB11", b"TCCATTCCCTCCGATAGATGAAAC"),
+    ("NB12", b"TCCGATTCTGCTTCTTTCTACCTG"),
+];
+
+/// The number of reads assigned to each barcode, and of those that could not be assigned.
+#[derive(Debug, Default, Clone)]
+pub struct DemuxStats {
+    /// the number of reads whose best match is each barcode
+    pub barcode_reads: Vec<u64>,
+    /// the number of reads matching no barcode
+    pub num_no_match: u64,
+    /// the number of reads matching two barcodes equally well
+    pub num_ambiguous: u64,
+}
+
+impl DemuxStats {
+    pub fn merge(&mut self, other: &Self) {
+        for (n, m) in self
+            .barcode_reads
+            .iter_mut()
+            .zip(other.barcode_reads.iter())
+        {
+            *n += m;
+        }
+        self.num_no_match += other.num_no_match;
+        self.num_ambiguous += other.num_ambiguous;
+    }
+}
+
+/// The demultiplexing of the run, as recorded in the `meta_info.json` of each sample.
+#[derive(Debug, Clone, Serialize)]
+pub struct DemuxSample {
+    /// the name of the sample
+    pub sample: String,
+    /// the barcodes of the sample
+    pub barcodes: Vec<String>,
+    /// the number of reads assigned to the sample
+    pub num_reads: u64,
+    /// the number of reads of the run
+    pub num_run_reads: u64,
+    /// the fraction of the reads of the run that were assigned to no sample
+    pub unassigned_fraction: f64,
+}
+
+/// Assigns the reads of a multiplexed run to its samples by the barcodes found at either
+/// of their ends.
+pub struct BarcodeDemux {
+    barcodes: Vec<Adapter>,
+    /// the number of bases at each end of a read that are searched
+    search_len: usize,
+    /// the largest fraction of the bases of a barcode that may be edited in a match
+    max_error_rate: f32,
+    /// the names of the expected samples
+    samples: Vec<String>,
+    /// the sample of each barcode, if it is expected
+    barcode_sample: Vec<Option<u16>>,
+}
+
+/// Read the expected samples from `path`, with lines of the form `<barcode>\t<sample>`.
+fn read_sample_sheet(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let file = File::open(path)
+        .with_context(|| format!("could not open the sample sheet {}", path.display()))?;
+    let mut entries = Vec::new();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next()) {
+            (Some(barcode), Some(sample)) if !sample.trim().is_empty() => {
+                entries.push((barcode.trim().to_owned(), sample.trim().to_owned()));
+            }
+            _ => {
+                return Err(bad_input(format!(
+                    "line {} of the sample sheet {} is not of the form <barcode>\\t<sample>",
+                    lnum + 1,
+                    path.display()
+                )));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+impl BarcodeDemux {
+    /// A demultiplexer for the barcodes `spec`, either the name of a built-in set
+    /// ([`ONT_NATIVE`]) or the path of a FASTA file holding the barcode sequences. The
+    /// expected samples are read from `sample_sheet` if it is given; otherwise, each
+    /// barcode is a sample of the same name.
+    pub fn new(
+        spec: &str,
+        sample_sheet: Option<&Path>,
+        search_len: usize,
+        max_error_rate: f32,
+    ) -> anyhow::Result<Self> {
+        let named: Vec<(String, Vec<u8>)> = if spec == ONT_NATIVE {
+            ONT_NATIVE_BARCODES
+                .iter()
+                .map(|(name, seq)| (name.to_string(), seq.to_vec()))
+                .collect()
+        } else {
+            let mut reader = parse_fastx_file(Path::new(spec))
+                .with_context(|| format!("could not read the barcodes in {}", spec))?;
+            let mut named = Vec::new();
+            while let Some(rec) = reader.next() {
+                let rec = rec?;
+                let id = rec.id();
+                let name = id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id);
+                named.push((
+                    String::from_utf8_lossy(name).into_owned(),
+                    rec.seq().to_ascii_uppercase(),
+                ));
+            }
+            named
+        };
+        if named.is_empty() {
+            return Err(bad_input(format!(
+                "no barcode sequences were found in {}",
+                spec
+            )));
+        }
+        let index: FxHashMap<&str, usize> = named
+            .iter()
+            .enumerate()
+            .map(|(i, (n, _))| (n.as_str(), i))
+            .collect();
+
+        let mut samples: Vec<String> = Vec::new();
+        let mut barcode_sample = vec![None; named.len()];
+        match sample_sheet {
+            Some(path) => {
+                for (barcode, sample) in read_sample_sheet(path)? {
+                    let Some(&b) = index.get(barcode.as_str()) else {
+                        return Err(bad_input(format!(
+                            "the barcode {} of the sample sheet {} is not one of the barcodes of {}",
+                            barcode,
+                            path.display(),
+                            spec
+                        )));
+                    };
+                    if barcode_sample[b].is_some() {
+                        return Err(bad_input(format!(
+                            "the barcode {} is listed more than once in the sample sheet {}",
+                            barcode,
+                            path.display()
+                        )));
+                    }
+                    let s = match samples.iter().position(|n| *n == sample) {
+                        Some(s) => s,
+                        None => {
+                            samples.push(sample);
+                            samples.len() - 1
+                        }
+                    };
+                    barcode_sample[b] = Some(s as u16);
+                }
+                if samples.is_empty() {
+                    return Err(bad_input(format!(
+                        "no samples were listed in the sample sheet {}",
+                        path.display()
+                    )));
+                }
+            }
+            None => {
+                for (b, (name, _)) in named.iter().enumerate() {
+                    samples.push(name.clone());
+                    barcode_sample[b] = Some(b as u16);
+                }
+            }
+        }
+        info!(
+            "demultiplexing the reads into {} samples by the {} barcodes of {}, searching the {} bases at each end of the reads.",
+            samples.len(),
+            named.len(),
+            spec,
+            search_len
+        );
+        Ok(Self {
+            barcodes: named
+                .into_iter()
+                .map(|(name, seq)| Adapter::new(name, seq))
+                .collect(),
+            search_len,
+            max_error_rate,
+            samples,
+            barcode_sample,
+        })
+    }
+
+    /// The names of the expected samples.
+    pub fn samples(&self) -> &[String] {
+        &self.samples
+    }
+
+    /// Empty statistics for the barcodes of this demultiplexer.
+    pub fn new_stats(&self) -> DemuxStats {
+        DemuxStats {
+            barcode_reads: vec![0; self.barcodes.len()],
+            ..Default::default()
+        }
+    }
+
+    /// The fewest edits of a match of barcode `b` (in either orientation) within either
+    /// end of the read, given as its `start` and its reversed `rev_end`.
+    fn barcode_dist(&self, b: &Adapter, start: &[u8], rev_end: &[u8]) -> Option<usize> {
+        let max_dist = (self.max_error_rate * b.len as f32).floor() as usize;
+        let at_start = b
+            .forward
+            .iter()
+            .filter_map(|p| best_match(p, start, max_dist).map(|(d, _)| d));
+        let at_end = b
+            .reversed
+            .iter()
+            .filter_map(|p| best_match(p, rev_end, max_dist).map(|(d, _)| d));
+        at_start.chain(at_end).min()
+    }
+
+    /// The sample of the read `seq`, recording its barcode in `stats`. A read is assigned
+    /// to the barcode it matches with the fewest edits, unless another barcode matches it
+    /// equally well; reads matching no barcode, matching two equally well, or whose
+    /// barcode is not of an expected sample are not assigned to any sample.
+    pub fn assign(&self, seq: &[u8], stats: &mut DemuxStats) -> Option<usize> {
+        let w = self.search_len.min(seq.len());
+        let start = &seq[..w];
+        let rev_end: Vec<u8> = seq[seq.len() - w..].iter().rev().copied().collect();
+        // the best and second best distances, and the barcode with the best
+        let mut best: Option<(usize, usize)> = None;
+        let mut second: Option<usize> = None;
+        for (k, b) in self.barcodes.iter().enumerate() {
+            let Some(dist) = self.barcode_dist(b, start, &rev_end) else {
+                continue;
+            };
+            match best {
+                Some((d, _)) if dist >= d => {
+                    if second.is_none_or(|s| dist < s) {
+                        second = Some(dist);
+                    }
+                }
+                _ => {
+                    second = best.map(|(d, _)| d);
+                    best = Some((dist, k));
+                }
+            }
+        }
+        match best {
+            None => {
+                stats.num_no_match += 1;
+                None
+            }
+            Some((d, _)) if second == Some(d) => {
+                stats.num_ambiguous += 1;
+                None
+            }
+            Some((_, k)) => {
+                stats.barcode_reads[k] += 1;
+                self.barcode_sample[k].map(usize::from)
+            }
+        }
+    }
+
+    /// The number of reads of each sample, according to `stats`.
+    pub fn sample_reads(&self, stats: &DemuxStats) -> Vec<u64> {
+        let mut reads = vec![0_u64; self.samples.len()];
+        for (n, s) in stats.barcode_reads.iter().zip(self.barcode_sample.iter()) {
+            if let Some(s) = s {
+                reads[*s as usize] += n;
+            }
+        }
+        reads
+    }
+
+    /// The barcodes of the sample `s`.
+    fn sample_barcodes(&self, s: usize) -> Vec<String> {
+        self.barcodes
+            .iter()
+            .zip(self.barcode_sample.iter())
+            .filter(|(_, bs)| **bs == Some(s as u16))
+            .map(|(b, _)| b.name.clone())
+            .collect()
+    }
+
+    /// The record of the demultiplexing of the run (whose reads are counted in `stats`)
+    /// for the sample `s`, for its `meta_info.json`.
+    pub fn sample_info(&self, s: usize, stats: &DemuxStats) -> DemuxSample {
+        let num_run_reads = self.num_run_reads(stats);
+        let num_assigned: u64 = self.sample_reads(stats).iter().sum();
+        DemuxSample {
+            sample: self.samples[s].clone(),
+            barcodes: self.sample_barcodes(s),
+            num_reads: self.sample_reads(stats)[s],
+            num_run_reads,
+            unassigned_fraction: unassigned_fraction(num_run_reads, num_assigned),
+        }
+    }
+
+    fn num_run_reads(&self, stats: &DemuxStats) -> u64 {
+        stats.barcode_reads.iter().sum::<u64>() + stats.num_no_match + stats.num_ambiguous
+    }
+
+    /// Write the number of reads of each barcode (and of the reads assigned to no sample)
+    /// to `<output>.demux.tsv`, and log the fraction of the reads that were not assigned.
+    pub fn write_report(&self, output: &Path, stats: &DemuxStats) -> anyhow::Result<()> {
+        let num_run_reads = self.num_run_reads(stats);
+        let frac = |n: u64| {
+            if num_run_reads > 0 {
+                n as f64 / num_run_reads as f64
+            } else {
+                0.0
+            }
+        };
+        let out_path = output.with_additional_extension(".demux.tsv");
+        let mut writer = BufWriter::new(
+            File::create(&out_path)
+                .with_context(|| format!("could not create {}", out_path.display()))?,
+        );
+        provenance::write_tsv_comment(&mut writer)?;
+        writeln!(writer, "barcode\tsample\tnum_reads\tfraction")?;
+        let mut num_unexpected = 0_u64;
+        for ((b, s), n) in self
+            .barcodes
+            .iter()
+            .zip(self.barcode_sample.iter())
+            .zip(stats.barcode_reads.iter())
+        {
+            let sample = match s {
+                Some(s) => self.samples[*s as usize].as_str(),
+                None => {
+                    num_unexpected += n;
+                    "NA"
+                }
+            };
+            writeln!(writer, "{}\t{}\t{}\t{:.6}", b.name, sample, n, frac(*n))?;
+        }
+        writeln!(
+            writer,
+            "ambiguous\tNA\t{}\t{:.6}",
+            stats.num_ambiguous,
+            frac(stats.num_ambiguous)
+        )?;
+        writeln!(
+            writer,
+            "unassigned\tNA\t{}\t{:.6}",
+            stats.num_no_match,
+            frac(stats.num_no_match)
+        )?;
+        writer.flush()?;
+
+        let num_assigned: u64 = self.sample_reads(stats).iter().sum();
+        info!(
+            "assigned {} of {} reads to a sample; {} matched no barcode, {} matched two barcodes equally well and {} had the barcode of no expected sample ({:.2}% unassigned).",
+            num_assigned.to_formatted_string(&Locale::en),
+            num_run_reads.to_formatted_string(&Locale::en),
+            stats.num_no_match.to_formatted_string(&Locale::en),
+            stats.num_ambiguous.to_formatted_string(&Locale::en),
+            num_unexpected.to_formatted_string(&Locale::en),
+            100.0 * unassigned_fraction(num_run_reads, num_assigned)
+        );
+        if num_unexpected > 0 {
+            warn!(
+                "{} reads carry the barcode of no expected sample; see {} for their barcodes.",
+                num_unexpected.to_formatted_string(&Locale::en),
+                out_path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn unassigned_fraction(num_run_reads: u64, num_assigned: u64) -> f64 {
+    if num_run_reads > 0 {
+        (num_run_reads - num_assigned) as f64 / num_run_reads as f64
+    } else {
+        0.0
+    }
+}
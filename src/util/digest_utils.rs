@@ -1,13 +1,20 @@
+use crate::util::errors;
 use anyhow::{Context, bail};
 use minimap2_sys::MmIdx;
+use noodles_bam as bam;
 use seqcol_rs;
+use serde_json::json;
 use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::Arc;
 use tracing::{debug, info, warn};
 
 const DIGEST_VERSION: u8 = 3;
 
+/// The magic bytes that end the oarfish footer of a minimap2 index.
+const OARFISH_FOOTER_MAGIC: &str = "OARFISHSIG";
+
 pub(crate) fn append_digest_to_mm2_index(
     idx_file: &str,
     digest: &seqcol_rs::DigestResult,
@@ -26,7 +33,6 @@ pub(crate) fn append_digest_to_mm2_index(
         let version = DIGEST_VERSION;
         let ver_bytes = version.to_le_bytes();
 
-        const OARFISH_FOOTER_MAGIC: &str = "OARFISHSIG";
         writer.write_all(json_str.as_bytes())?;
         writer.write_all(&len_bytes)?;
         writer.write_all(&ver_bytes)?;
@@ -47,7 +53,6 @@ pub(crate) fn read_digest_from_mm2_index(
     if std::fs::exists(idx_file)? {
         let mut file = std::fs::OpenOptions::new().read(true).open(idx_file)?;
 
-        const OARFISH_FOOTER_MAGIC: &str = "OARFISHSIG";
         let magic_len = OARFISH_FOOTER_MAGIC.len() as i64;
         file.seek(std::io::SeekFrom::End(-magic_len))?;
 
@@ -83,48 +88,7 @@ pub(crate) fn read_digest_from_mm2_index(
             let value: serde_json::Value = serde_json::from_slice(&buf)?;
 
             debug!("{}", value);
-            let seq_col_digests_value = value
-                .get("seqcol_digest")
-                .expect("seqcol_digest should exist");
-            let seq_col_digests = seq_col_digests_value
-                .as_object()
-                .expect("seqcol_digest should be an object");
-
-            // ensure we have the appropriate values
-            let _lengths = seq_col_digests
-                .get("lengths")
-                .expect("lengths field should exist")
-                .is_string();
-            let _names = seq_col_digests
-                .get("names")
-                .expect("names field should exist")
-                .is_string();
-            let _seqs = seq_col_digests
-                .get("sequences")
-                .expect("sequences field should exist")
-                .is_string();
-            let _sorted_name_length_pairs = seq_col_digests
-                .get("sorted_name_length_pairs")
-                .expect("sorted_name_length_pairs field should exist")
-                .is_string();
-
-            let sha_digests = value["sha256_digests"]
-                .as_object()
-                .expect("should be an object");
-            let sha256_names = sha_digests["sha256_names"]
-                .as_str()
-                .expect("should be a string");
-            let sha256_seqs = sha_digests["sha256_seqs"]
-                .as_str()
-                .expect("should be a string");
-
-            Ok(seqcol_rs::DigestResult {
-                sq_digest: seqcol_rs::DigestLevelResult::Level1(seqcol_rs::Level1Digest {
-                    digests: seq_col_digests_value.clone(),
-                }),
-                sha256_seqs: Some(sha256_seqs.to_owned()),
-                sha256_names: Some(sha256_names.to_owned()),
-            })
+            digest_from_json(&value)
         } else {
             bail!("minimap2 index did not have an oarfish footer!");
         }
@@ -133,6 +97,97 @@ pub(crate) fn read_digest_from_mm2_index(
     }
 }
 
+/// The version and the length (in bytes) of the oarfish footer of the minimap2 index
+/// `idx_file`, or `None` if it has no oarfish footer.
+fn mm2_index_footer(idx_file: &Path) -> anyhow::Result<Option<(u8, u64)>> {
+    let mut file = std::fs::File::open(idx_file)
+        .with_context(|| format!("could not open {}", idx_file.display()))?;
+    let magic_len = OARFISH_FOOTER_MAGIC.len() as u64;
+    // the magic, preceded by the version and the length of the digest
+    let trailer_len = magic_len + 1 + 8;
+    if file.metadata()?.len() < trailer_len {
+        return Ok(None);
+    }
+    let mut trailer = vec![0_u8; trailer_len as usize];
+    file.seek(std::io::SeekFrom::End(-(trailer_len as i64)))?;
+    file.read_exact(&mut trailer)?;
+    if &trailer[9..] != OARFISH_FOOTER_MAGIC.as_bytes() {
+        return Ok(None);
+    }
+    let sig_len = u64::from_le_bytes(trailer[..8].try_into().expect("8 bytes"));
+    Ok(Some((trailer[8], trailer_len + sig_len)))
+}
+
+/// true if `path` is a minimap2 index with an oarfish footer.
+pub(crate) fn has_mm2_index_footer(path: &Path) -> bool {
+    path.is_file() && matches!(mm2_index_footer(path), Ok(Some(_)))
+}
+
+/// Upgrade the oarfish footer of the minimap2 index `idx_file` to the current version of
+/// the digest, replacing the old footer in place (unless `dry_run`). Returns false if the
+/// footer is already current.
+pub(crate) fn migrate_mm2_index_footer(idx_file: &Path, dry_run: bool) -> anyhow::Result<bool> {
+    let (version, footer_len) = mm2_index_footer(idx_file)?
+        .with_context(|| format!("{} has no oarfish footer", idx_file.display()))?;
+    if version >= DIGEST_VERSION {
+        return Ok(false);
+    }
+    let idx_str = idx_file
+        .to_str()
+        .with_context(|| format!("{} is not a valid UTF-8 path", idx_file.display()))?;
+    let digest = read_digest_from_mm2_index(idx_str).with_context(|| {
+        format!(
+            "the footer of {} (version {}) can not be upgraded; please re-create the index",
+            idx_file.display(),
+            version
+        )
+    })?;
+    if !dry_run {
+        let file = std::fs::OpenOptions::new().write(true).open(idx_file)?;
+        let len = file.metadata()?.len();
+        file.set_len(len - footer_len)?;
+        drop(file);
+        append_digest_to_mm2_index(idx_str, &digest)?;
+    }
+    Ok(true)
+}
+
+/// Rebuild a `DigestResult` from its JSON representation (as produced by
+/// `DigestResult::to_json`).
+pub(crate) fn digest_from_json(
+    value: &serde_json::Value,
+) -> anyhow::Result<seqcol_rs::DigestResult> {
+    let seq_col_digests_value = value
+        .get("seqcol_digest")
+        .context("seqcol_digest should exist")?;
+    let seq_col_digests = seq_col_digests_value
+        .as_object()
+        .context("seqcol_digest should be an object")?;
+
+    // ensure we have the appropriate values
+    for field in ["lengths", "names", "sorted_name_length_pairs"] {
+        if !seq_col_digests.get(field).is_some_and(|v| v.is_string()) {
+            bail!("the {} field of seqcol_digest should be a string", field);
+        }
+    }
+
+    // the sequence digests are only known if the digest was computed from the sequences
+    let sha_digest = |key: &str| {
+        value
+            .get("sha256_digests")
+            .and_then(|d| d.get(key))
+            .and_then(|d| d.as_str())
+            .map(str::to_owned)
+    };
+    Ok(seqcol_rs::DigestResult {
+        sq_digest: seqcol_rs::DigestLevelResult::Level1(seqcol_rs::Level1Digest {
+            digests: seq_col_digests_value.clone(),
+        }),
+        sha256_seqs: sha_digest("sha256_seqs"),
+        sha256_names: sha_digest("sha256_names"),
+    })
+}
+
 pub(crate) fn digest_from_header(
     header: &noodles_sam::header::Header,
 ) -> anyhow::Result<seqcol_rs::DigestResult> {
@@ -170,3 +225,211 @@ pub(crate) fn digest_from_index(mmi: &Arc<MmIdx>) -> anyhow::Result<seqcol_rs::D
     info!("done calculating seqcol digest");
     Ok(d)
 }
+
+/// The digest of the attribute `attr` (e.g. `names` or `lengths`) of the
+/// level 1 seqcol digest `d`, if it is present.
+fn level1_attr<'a>(d: &'a seqcol_rs::DigestResult, attr: &str) -> Option<&'a str> {
+    match d.sq_digest {
+        seqcol_rs::DigestLevelResult::Level1(ref l1) => l1.digests.get(attr)?.as_str(),
+        _ => None,
+    }
+}
+
+/// How the (name, length) pairs of the digests `a` and `b`, which are known to differ,
+/// disagree.
+fn mismatch_detail(a: &seqcol_rs::DigestResult, b: &seqcol_rs::DigestResult) -> &'static str {
+    let same = |attr| level1_attr(a, attr) == level1_attr(b, attr);
+    match (same("names"), same("lengths")) {
+        (true, _) => "the sequence names agree, but their lengths differ",
+        (false, true) => "the sequence lengths agree, but their names differ",
+        (false, false) => "both the sequence names and lengths differ",
+    }
+}
+
+/// Verify that the reference sequences named in the alignment header, with digest
+/// `header_digest`, are those of the reference at `reference`, which is either a
+/// FASTA file (if `is_fasta` is true) or a minimap2 index built by oarfish. Since
+/// the alignment header records only the names and lengths of the sequences, these
+/// are what is compared. If the collections of (name, length) pairs differ, an error
+/// is returned if `fail_on_mismatch` is true, and `false` otherwise; a warning is
+/// issued if only the order of the sequences differs.
+pub(crate) fn verify_reference_digest(
+    header_digest: &seqcol_rs::DigestResult,
+    reference: &std::path::Path,
+    is_fasta: bool,
+    fail_on_mismatch: bool,
+) -> anyhow::Result<bool> {
+    let ref_digest = if is_fasta {
+        info!(
+            "generating reference digest of {} for verification",
+            reference.display()
+        );
+        let mut seqcol_obj = seqcol_rs::SeqCol::try_from_fasta_file(reference)
+            .with_context(|| format!("could not read reference {}", reference.display()))?;
+        seqcol_obj.digest(seqcol_rs::DigestConfig {
+            level: seqcol_rs::DigestLevel::Level1,
+            additional_attr: vec![seqcol_rs::KnownAttr::SortedNameLengthPairs],
+        })?
+    } else {
+        read_digest_from_mm2_index(
+            reference
+                .to_str()
+                .context("could not convert reference path to string")?,
+        )
+        .with_context(|| {
+            format!(
+                "{} is neither a FASTA file nor a minimap2 index built by oarfish, so its digest can not be verified",
+                reference.display()
+            )
+        })?
+    };
+
+    const PAIRS: &str = "sorted_name_length_pairs";
+    let (Some(hdr_pairs), Some(ref_pairs)) = (
+        level1_attr(header_digest, PAIRS),
+        level1_attr(&ref_digest, PAIRS),
+    ) else {
+        bail!(
+            "could not obtain the {} digests needed to verify the reference",
+            PAIRS
+        );
+    };
+    if hdr_pairs != ref_pairs {
+        if !fail_on_mismatch {
+            return Ok(false);
+        }
+        return Err(errors::reference_mismatch(format!(
+            "the reference sequences in the alignment header do not match those of {} ({}); were the reads aligned against a different annotation?",
+            reference.display(),
+            mismatch_detail(header_digest, &ref_digest)
+        )));
+    }
+    if level1_attr(header_digest, "names") != level1_attr(&ref_digest, "names") {
+        warn!(
+            "the reference sequences in the alignment header are those of {}, but in a different order.",
+            reference.display()
+        );
+    }
+    info!(
+        "verified that the alignments are against the reference sequences of {}.",
+        reference.display()
+    );
+    Ok(true)
+}
+
+/// The minimap2 index magic number, at the start of every index file.
+const MM2_INDEX_MAGIC: &[u8; 4] = b"MMI\x02";
+
+/// The digest of the input `path` of `oarfish sketch-compare`, along with the kind of
+/// the input: a FASTA file, a minimap2 index built by oarfish, or (otherwise) a BAM file,
+/// of whose header the digest is computed.
+fn digest_of_input(path: &Path) -> anyhow::Result<(&'static str, seqcol_rs::DigestResult)> {
+    if !path.is_file() {
+        return Err(errors::bad_input(format!(
+            "{} does not exist",
+            path.display()
+        )));
+    }
+    if crate::is_fasta(path)? {
+        info!("computing the digest of the FASTA file {}", path.display());
+        let mut seqcol_obj = seqcol_rs::SeqCol::try_from_fasta_file(path)
+            .with_context(|| format!("could not read the FASTA file {}", path.display()))?;
+        let digest = seqcol_obj.digest(seqcol_rs::DigestConfig {
+            level: seqcol_rs::DigestLevel::Level1,
+            additional_attr: vec![seqcol_rs::KnownAttr::SortedNameLengthPairs],
+        })?;
+        return Ok(("fasta", digest));
+    }
+
+    let mut magic = [0_u8; 4];
+    let is_index =
+        std::fs::File::open(path)?.read_exact(&mut magic).is_ok() && &magic == MM2_INDEX_MAGIC;
+    if is_index {
+        let digest = read_digest_from_mm2_index(
+            path.to_str()
+                .context("could not convert the index path to a string")?,
+        )
+        .map_err(|_| {
+            errors::bad_input(format!(
+                "the minimap2 index {} was not built by oarfish, so it records no digest; compare the FASTA file from which it was built instead",
+                path.display()
+            ))
+        })?;
+        Ok(("minimap2_index", digest))
+    } else {
+        let mut reader = std::fs::File::open(path).map(bam::io::Reader::new)?;
+        let header = reader.read_header().map_err(|e| {
+            errors::bad_input(format!(
+                "{} is neither a FASTA file, a minimap2 index nor a BAM file ({})",
+                path.display(),
+                e
+            ))
+        })?;
+        Ok(("bam", digest_from_header(&header)?))
+    }
+}
+
+/// Compute the seqcol digest of each of the `inputs` (FASTA files, minimap2 indices built by
+/// oarfish or BAM files) and print them, as JSON, to stdout. Given two inputs, also print
+/// whether they describe the same reference sequences (as far as their digests can tell), and
+/// fail with a reference mismatch if they do not.
+pub(crate) fn sketch_compare(inputs: &[PathBuf]) -> anyhow::Result<()> {
+    let mut digests = Vec::with_capacity(inputs.len());
+    let mut described = Vec::with_capacity(inputs.len());
+    for path in inputs {
+        let (kind, digest) = digest_of_input(path)?;
+        described.push(json!({
+            "path": path,
+            "kind": kind,
+            "digest": digest.to_json(),
+        }));
+        digests.push(digest);
+    }
+
+    let mut report = json!({ "inputs": described });
+    let mut mismatch = None;
+    if let [a, b] = digests.as_slice() {
+        const PAIRS: &str = "sorted_name_length_pairs";
+        let (Some(a_pairs), Some(b_pairs)) = (level1_attr(a, PAIRS), level1_attr(b, PAIRS)) else {
+            bail!(
+                "could not obtain the {} digests needed to compare the inputs",
+                PAIRS
+            );
+        };
+        // the sequences themselves are only known to the digests of FASTA files and indices
+        let seqs_differ = matches!(
+            (level1_attr(a, "sequences"), level1_attr(b, "sequences")),
+            (Some(x), Some(y)) if x != y
+        );
+        let (compatible, verdict) = if a_pairs != b_pairs {
+            (false, mismatch_detail(a, b).to_owned())
+        } else if seqs_differ {
+            (
+                false,
+                "the sequence names and lengths agree, but the sequences differ".to_owned(),
+            )
+        } else if level1_attr(a, "names") != level1_attr(b, "names") {
+            (
+                true,
+                "the same sequences, but in a different order".to_owned(),
+            )
+        } else {
+            (true, "the same sequences, in the same order".to_owned())
+        };
+        report["compatible"] = json!(compatible);
+        report["verdict"] = json!(verdict);
+        if !compatible {
+            mismatch = Some(format!(
+                "{} and {} do not describe the same reference sequences ({})",
+                inputs[0].display(),
+                inputs[1].display(),
+                verdict
+            ));
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    match mismatch {
+        Some(msg) => Err(errors::reference_mismatch(msg)),
+        None => Ok(()),
+    }
+}
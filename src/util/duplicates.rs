@@ -0,0 +1,259 @@
+use crate::util::oarfish_types::{AlnInfo, InMemoryAlignmentStore, TranscriptInfo};
+use anyhow::Context;
+use bio_types::strand::Strand;
+use num_format::{Locale, ToFormattedString};
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use swapvec::{SwapVec, SwapVecConfig};
+use tracing::{info, warn};
+
+/// The channel assigned to reads that do not appear in the sequencing summary.
+const UNKNOWN_CHANNEL: u32 = u32::MAX;
+
+/// The result of duplicate detection over the reads of an alignment store.
+#[derive(Debug, Serialize)]
+pub struct DuplicateResult {
+    /// true for each read (in store order) that is a duplicate of an earlier read
+    #[serde(skip)]
+    pub is_duplicate: Vec<bool>,
+    /// the number of duplicate reads whose best alignment is to each transcript
+    #[serde(skip)]
+    pub per_txp: Vec<u32>,
+    /// the total number of duplicate reads
+    pub num_duplicates: usize,
+    /// the number of reads examined
+    pub num_reads: usize,
+    /// true if the channel of each read was taken into account
+    pub used_channels: bool,
+}
+
+/// Read the `read_id` and `channel` columns of the (tab-separated) ONT
+/// sequencing summary file at `path`, returning the channel of each read.
+pub fn read_channels(path: &Path) -> anyhow::Result<FxHashMap<String, u32>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("could not open sequencing summary {}", path.display()))?;
+    let mut lines = BufReader::new(file).lines();
+    let header = lines
+        .next()
+        .with_context(|| format!("sequencing summary {} is empty", path.display()))??;
+    let cols: Vec<&str> = header.split('\t').collect();
+    let col_of = |name: &str| {
+        cols.iter().position(|c| c.trim() == name).with_context(|| {
+            format!(
+                "sequencing summary {} has no {} column",
+                path.display(),
+                name
+            )
+        })
+    };
+    let read_col = col_of("read_id")?;
+    let channel_col = col_of("channel")?;
+
+    let mut channels = FxHashMap::default();
+    for (lnum, line) in lines.enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        match (fields.get(read_col), fields.get(channel_col)) {
+            (Some(read), Some(channel)) => {
+                let channel = channel.trim().parse::<u32>().with_context(|| {
+                    format!(
+                        "invalid channel {:?} on line {} of sequencing summary {}",
+                        channel,
+                        lnum + 2,
+                        path.display()
+                    )
+                })?;
+                channels.insert(read.trim().to_owned(), channel);
+            }
+            _ => anyhow::bail!(
+                "line {} of sequencing summary {} has too few columns",
+                lnum + 2,
+                path.display()
+            ),
+        }
+    }
+    info!(
+        "read the channels of {} reads from the sequencing summary.",
+        channels.len().to_formatted_string(&Locale::en)
+    );
+    Ok(channels)
+}
+
+pub fn new_name_vec() -> SwapVec<String> {
+    SwapVec::<String>::with_config(SwapVecConfig {
+        swap_after: Default::default(),
+        batch_size: Default::default(),
+        compression: Some(swapvec::Compression::Lz4),
+    })
+}
+
+/// Look up the channel of each read in `name_vec` (in store order) in `channels`.
+/// Since iterating over `name_vec` consumes it, the names are returned in a new
+/// vector along with the channels.
+pub fn channels_of_reads(
+    name_vec: SwapVec<String>,
+    channels: &FxHashMap<String, u32>,
+) -> (Vec<u32>, SwapVec<String>) {
+    let mut read_channels = Vec::new();
+    let mut names = new_name_vec();
+    let mut num_missing = 0_usize;
+    for name in name_vec.into_iter() {
+        let name = name.expect("could not extract read name from file");
+        let channel = channels
+            .get(name.trim_end_matches('\0'))
+            .copied()
+            .unwrap_or_else(|| {
+                num_missing += 1;
+                UNKNOWN_CHANNEL
+            });
+        read_channels.push(channel);
+        names
+            .push(name)
+            .expect("cannot push name to read name vector");
+    }
+    if num_missing > 0 {
+        warn!(
+            "{} reads were not present in the sequencing summary; they will only be compared to each other.",
+            num_missing.to_formatted_string(&Locale::en)
+        );
+    }
+    (read_channels, names)
+}
+
+/// The position of the 3' end of the read along the transcript. For reads
+/// aligned to the reverse strand, this is the leftmost aligned position.
+#[inline]
+fn three_prime_end(a: &AlnInfo) -> u32 {
+    if a.strand == Strand::Reverse {
+        a.start
+    } else {
+        a.end
+    }
+}
+
+/// Identify duplicate reads in `store`. Each read is represented by its best
+/// alignment; two reads are duplicates if these alignments are to the same
+/// transcript and strand, their 3' ends are within `end_tol` bp of each other and
+/// their aligned lengths differ by at most a fraction `len_tol` of the longer one.
+/// If `channels` is provided, reads must also have been sequenced on the same channel.
+/// The first read (in order of 3' end) of each set of duplicates is retained.
+pub fn find_duplicates(
+    store: &InMemoryAlignmentStore,
+    num_txps: usize,
+    channels: Option<&[u32]>,
+    end_tol: u32,
+    len_tol: f64,
+) -> DuplicateResult {
+    // (transcript, is reverse, channel, 3' end, aligned length, read index)
+    let mut keys: Vec<(u32, bool, u32, u32, u32, usize)> = store
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (alns, probs, _))| {
+            let best = alns
+                .iter()
+                .zip(probs.iter())
+                .max_by(|(_, p1), (_, p2)| p1.total_cmp(p2))
+                .map(|(a, _)| a)?;
+            let channel = channels.map_or(0, |c| c[i]);
+            Some((
+                best.ref_id,
+                best.strand == Strand::Reverse,
+                channel,
+                three_prime_end(best),
+                best.alignment_span(),
+                i,
+            ))
+        })
+        .collect();
+    keys.sort_unstable();
+
+    let mut is_duplicate = vec![false; store.len()];
+    let mut per_txp = vec![0_u32; num_txps];
+    let mut num_duplicates = 0_usize;
+    // keys[lo..cur] are the earlier reads in the same group whose 3' ends
+    // are within `end_tol` of the current read
+    let mut lo = 0_usize;
+    for cur in 0..keys.len() {
+        let (tid, rev, channel, end3, len, ridx) = keys[cur];
+        while lo < cur && {
+            let (ltid, lrev, lchannel, lend3, _, _) = keys[lo];
+            (ltid, lrev, lchannel) != (tid, rev, channel) || lend3 + end_tol < end3
+        } {
+            lo += 1;
+        }
+        let is_dup = keys[lo..cur].iter().any(|&(_, _, _, _, olen, oidx)| {
+            let max_len = len.max(olen).max(1) as f64;
+            !is_duplicate[oidx] && (len.abs_diff(olen) as f64) <= len_tol * max_len
+        });
+        if is_dup {
+            is_duplicate[ridx] = true;
+            per_txp[tid as usize] += 1;
+            num_duplicates += 1;
+        }
+    }
+
+    info!(
+        "found {} duplicate reads among {} aligned reads.",
+        num_duplicates.to_formatted_string(&Locale::en),
+        store.len().to_formatted_string(&Locale::en)
+    );
+    DuplicateResult {
+        is_duplicate,
+        per_txp,
+        num_duplicates,
+        num_reads: store.len(),
+        used_channels: channels.is_some(),
+    }
+}
+
+/// Build a new alignment store containing the reads of `store` that are not
+/// marked in `is_duplicate`. Since adding reads to a store accumulates their
+/// coverage, `txps` should be freshly constructed transcript information.
+pub fn remove_duplicates<'h>(
+    store: &InMemoryAlignmentStore<'h>,
+    is_duplicate: &[bool],
+    txps: &mut [TranscriptInfo],
+) -> InMemoryAlignmentStore<'h> {
+    let mut dstore = store.empty_like(store.aln_header);
+    dstore.aggregate_discard_table(&store.discard_table);
+    dstore.num_input_reads = store.num_input_reads;
+    dstore.second_chance = store.second_chance.clone();
+    dstore.trimming = store.trimming.clone();
+    dstore.internal_priming = store.internal_priming.clone();
+    dstore.demux = store.demux.clone();
+    for (((alns, probs, _), read_len), dup) in store
+        .iter()
+        .zip(store.read_lengths.iter())
+        .zip(is_duplicate.iter())
+    {
+        if !dup && dstore.add_filtered_group(&alns, &probs, *read_len, txps) && alns.len() == 1 {
+            dstore.inc_unique_alignments();
+        }
+    }
+    dstore.read_lanes = store
+        .read_lanes
+        .iter()
+        .zip(is_duplicate.iter())
+        .filter_map(|(l, dup)| (!dup).then_some(*l))
+        .collect();
+    dstore
+}
+
+/// Remove the names of the reads marked in `is_duplicate` from `name_vec`.
+pub fn remove_duplicate_names(name_vec: SwapVec<String>, is_duplicate: &[bool]) -> SwapVec<String> {
+    let mut names = new_name_vec();
+    for (name, dup) in name_vec.into_iter().zip(is_duplicate.iter()) {
+        let name = name.expect("could not extract read name from file");
+        if !dup {
+            names
+                .push(name)
+                .expect("cannot push name to read name vector");
+        }
+    }
+    names
+}
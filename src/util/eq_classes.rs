@@ -0,0 +1,618 @@
+use crate::bootstrap;
+use crate::prog_opts::OutputFormat;
+use crate::util::constants;
+use crate::util::errors::bad_input;
+use crate::util::oarfish_types::EMInfo;
+use crate::util::output_schema::{add_schema_info, read_output_info};
+use crate::util::provenance;
+use crate::util::write_function::write_infrep_file;
+use anyhow::Context;
+use arrow2::{array::Float64Array, chunk::Chunk, datatypes::Field};
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use rand::rng as trng;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use serde_json::json;
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// The first line of an equivalence class file.
+const EQC_MAGIC: &str = "# oarfish equivalence classes v1";
+/// The zstd compression level of an equivalence class file.
+const EQC_COMPRESSION_LEVEL: i32 = 3;
+/// The first bytes of a zstd-compressed file.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// The first bytes of a gzip-compressed file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The reads whose alignments are to the same set of transcripts; `weights[i]` is the
+/// mean (over these reads) of the conditional probability of the read arising from
+/// `txps[i]`, as used by the EM, normalized over the alignments of each read.
+#[derive(Debug, Clone)]
+pub struct EqClass {
+    pub txps: Vec<u32>,
+    pub weights: Vec<f64>,
+    pub count: u64,
+}
+
+/// The equivalence classes of the reads of a sample, and the transcripts (names and
+/// lengths) against which it was quantified.
+#[derive(Debug)]
+pub struct EqClasses {
+    pub names: Vec<String>,
+    pub lens: Vec<usize>,
+    pub classes: Vec<EqClass>,
+}
+
+/// Accumulates the equivalence classes of reads, keyed by their (sorted) transcripts.
+#[derive(Default)]
+struct EqClassBuilder {
+    classes: FxHashMap<Vec<u32>, (Vec<f64>, u64)>,
+}
+
+impl EqClassBuilder {
+    /// Add `count` reads with the (sorted) transcripts `txps`, whose summed weights are
+    /// `weight_sums`.
+    fn add(&mut self, txps: Vec<u32>, weight_sums: &[f64], count: u64) {
+        let (sums, n) = self
+            .classes
+            .entry(txps)
+            .or_insert_with(|| (vec![0.0; weight_sums.len()], 0));
+        sums.iter_mut()
+            .zip(weight_sums.iter())
+            .for_each(|(s, w)| *s += w);
+        *n += count;
+    }
+
+    fn build(self, names: Vec<String>, lens: Vec<usize>) -> EqClasses {
+        let mut classes: Vec<EqClass> = self
+            .classes
+            .into_iter()
+            .map(|(txps, (sums, count))| EqClass {
+                txps,
+                weights: sums.iter().map(|s| s / count as f64).collect(),
+                count,
+            })
+            .collect();
+        // a deterministic order, so that the output does not depend on the hashing
+        classes.sort_unstable_by(|a, b| a.txps.cmp(&b.txps));
+        EqClasses {
+            names,
+            lens,
+            classes,
+        }
+    }
+}
+
+impl EqClasses {
+    /// Collect the equivalence classes of the reads in the store of `emi`. The weight
+    /// of each alignment is the product of the terms the EM multiplies by the abundance
+    /// of its transcript (the alignment and coverage probabilities, the density of the
+    /// KDE model and the transcript weight), so that the EM over the classes reproduces
+    /// that over the reads.
+    pub fn from_em_info(emi: &EMInfo, names: &[String]) -> Self {
+        let model_coverage = emi.eq_map.filter_opts.model_coverage;
+        let mut builder = EqClassBuilder::default();
+        let mut read_weights = FxHashMap::<u32, f64>::default();
+        for (alns, probs, coverage_probs) in emi.eq_map.iter() {
+            read_weights.clear();
+            for (a, p, cp) in itertools::izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
+                let target_id = a.ref_id as usize;
+                let cov_prob = if model_coverage { *cp } else { 1.0 };
+                let dens_prob = emi.kde_model.as_ref().map_or(1.0, |m| {
+                    m[(
+                        emi.txp_info[target_id].lenf as usize,
+                        a.alignment_span() as usize,
+                    )]
+                });
+                let txp_weight = emi.txp_weights.as_ref().map_or(1.0, |w| w[target_id]);
+                *read_weights.entry(a.ref_id).or_insert(0.0) +=
+                    *p as f64 * cov_prob * dens_prob * txp_weight;
+            }
+            let total: f64 = read_weights.values().sum();
+            if total <= constants::EM_DENOM_THRESH {
+                continue;
+            }
+            let mut entries: Vec<(u32, f64)> =
+                read_weights.iter().map(|(t, w)| (*t, w / total)).collect();
+            entries.sort_unstable_by_key(|e| e.0);
+            let (txps, weights): (Vec<u32>, Vec<f64>) = entries.into_iter().unzip();
+            builder.add(txps, &weights, 1);
+        }
+        let lens = emi.txp_info.iter().map(|t| t.len.get()).collect();
+        builder.build(names.to_vec(), lens)
+    }
+
+    pub fn num_reads(&self) -> u64 {
+        self.classes.iter().map(|c| c.count).sum()
+    }
+}
+
+/// Write the equivalence classes `eqc` to `<output>.eqc.tsv.zst`.
+pub fn write_eq_classes(output: &Path, eqc: &EqClasses) -> anyhow::Result<()> {
+    let out_path = output.with_additional_extension(".eqc.tsv.zst");
+    let file = File::create(&out_path)
+        .with_context(|| format!("could not create {}", out_path.display()))?;
+    let mut writer =
+        zstd::stream::write::Encoder::new(BufWriter::new(file), EQC_COMPRESSION_LEVEL)?;
+
+    writeln!(writer, "{}", EQC_MAGIC)?;
+    writeln!(writer, "{}\t{}", eqc.names.len(), eqc.classes.len())?;
+    for (name, len) in eqc.names.iter().zip(eqc.lens.iter()) {
+        writeln!(writer, "{}\t{}", name, len)?;
+    }
+    for c in eqc.classes.iter() {
+        write!(writer, "{}", c.txps.len())?;
+        for t in c.txps.iter() {
+            write!(writer, "\t{}", t)?;
+        }
+        for w in c.weights.iter() {
+            write!(writer, "\t{}", w)?;
+        }
+        writeln!(writer, "\t{}", c.count)?;
+    }
+    writer.finish()?.flush()?;
+    info!(
+        "wrote {} equivalence classes to {}.",
+        eqc.classes.len().to_formatted_string(&Locale::en),
+        out_path.display()
+    );
+    Ok(())
+}
+
+/// Read the equivalence classes written (with `--write-eqclasses`) with the output
+/// prefix `prefix`.
+pub fn read_eq_classes(prefix: &Path) -> anyhow::Result<EqClasses> {
+    // the output must be of a schema version that can be read, and of a bulk run
+    let out = read_output_info(prefix)?;
+    anyhow::ensure!(
+        !out.is_single_cell(),
+        "{} is the output of a single-cell run, which has no equivalence classes",
+        prefix.display()
+    );
+    let path = prefix.with_additional_extension(".eqc.tsv.zst");
+    let file = File::open(&path).with_context(|| {
+        format!(
+            "could not open {}; was {} quantified with --write-eqclasses?",
+            path.display(),
+            prefix.display()
+        )
+    })?;
+    parse_eq_classes(
+        BufReader::new(zstd::stream::read::Decoder::new(file)?),
+        &path,
+    )
+}
+
+/// Read the equivalence class file `path`, which may be compressed with zstd (as written
+/// with `--write-eqclasses`) or gzip, or not compressed at all; if `path` does not exist,
+/// it is taken as the output prefix of a run with `--write-eqclasses`.
+pub fn read_eq_class_file(path: &Path) -> anyhow::Result<EqClasses> {
+    if !path.exists() {
+        return read_eq_classes(path);
+    }
+    let mut file = BufReader::new(
+        File::open(path).with_context(|| format!("could not open {}", path.display()))?,
+    );
+    let magic = file.fill_buf()?;
+    let reader: Box<dyn BufRead> = if magic.starts_with(&ZSTD_MAGIC) {
+        Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(
+            file,
+        )?))
+    } else if magic.starts_with(&GZIP_MAGIC) {
+        Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(file)))
+    } else {
+        Box::new(file)
+    };
+    parse_eq_classes(reader, path)
+}
+
+/// Parse the equivalence classes read by `reader` from the file `path`.
+fn parse_eq_classes(reader: impl BufRead, path: &Path) -> anyhow::Result<EqClasses> {
+    let mut lines = reader.lines();
+    let mut next_line = || -> anyhow::Result<String> {
+        lines
+            .next()
+            .transpose()?
+            .with_context(|| format!("{} ended unexpectedly", path.display()))
+    };
+
+    anyhow::ensure!(
+        next_line()? == EQC_MAGIC,
+        "{} is not an oarfish equivalence class file",
+        path.display()
+    );
+    let counts = next_line()?;
+    let (num_txps, num_classes) = counts
+        .split_once('\t')
+        .and_then(|(t, c)| Some((t.parse::<usize>().ok()?, c.parse::<usize>().ok()?)))
+        .with_context(|| format!("malformed header in {}", path.display()))?;
+
+    let mut names = Vec::with_capacity(num_txps);
+    let mut lens = Vec::with_capacity(num_txps);
+    for _ in 0..num_txps {
+        let line = next_line()?;
+        let (name, len) = line
+            .rsplit_once('\t')
+            .and_then(|(n, l)| Some((n.to_owned(), l.parse::<usize>().ok()?)))
+            .with_context(|| {
+                format!("malformed transcript line {:?} in {}", line, path.display())
+            })?;
+        names.push(name);
+        lens.push(len);
+    }
+
+    let mut classes = Vec::with_capacity(num_classes);
+    for _ in 0..num_classes {
+        let line = next_line()?;
+        let class = (|| -> Option<EqClass> {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let k: usize = fields.first()?.parse().ok()?;
+            if fields.len() != 2 * k + 2 {
+                return None;
+            }
+            let txps = fields[1..=k]
+                .iter()
+                .map(|t| t.parse::<u32>().ok().filter(|t| (*t as usize) < num_txps))
+                .collect::<Option<Vec<u32>>>()?;
+            let weights = fields[k + 1..=2 * k]
+                .iter()
+                .map(|w| w.parse::<f64>().ok())
+                .collect::<Option<Vec<f64>>>()?;
+            let count = fields[2 * k + 1].parse().ok()?;
+            Some(EqClass {
+                txps,
+                weights,
+                count,
+            })
+        })()
+        .with_context(|| {
+            format!(
+                "malformed equivalence class {:?} in {}",
+                line,
+                path.display()
+            )
+        })?;
+        classes.push(class);
+    }
+    Ok(EqClasses {
+        names,
+        lens,
+        classes,
+    })
+}
+
+/// Combine the equivalence classes of several samples; the weights of a class present
+/// in more than one sample are averaged over the reads of all of them. All of the
+/// samples must have been quantified against the same transcripts.
+fn combine_eq_classes(inputs: &[PathBuf], samples: Vec<EqClasses>) -> anyhow::Result<EqClasses> {
+    let mut builder = EqClassBuilder::default();
+    let mut targets: Option<(Vec<String>, Vec<usize>)> = None;
+    for (prefix, eqc) in inputs.iter().zip(samples) {
+        match targets {
+            Some((ref names, ref lens)) => anyhow::ensure!(
+                *names == eqc.names && *lens == eqc.lens,
+                "the transcripts of {} differ from those of {}; only samples quantified against the same transcripts can be merged",
+                prefix.display(),
+                inputs[0].display()
+            ),
+            None => targets = Some((eqc.names, eqc.lens)),
+        }
+        for c in eqc.classes {
+            let sums: Vec<f64> = c.weights.iter().map(|w| w * c.count as f64).collect();
+            builder.add(c.txps, &sums, c.count);
+        }
+    }
+    let (names, lens) = targets.unwrap_or_default();
+    Ok(builder.build(names, lens))
+}
+
+/// One round of the EM over the equivalence classes, where `class_counts[c]` is the
+/// number of reads of class `c`.
+fn eqc_em_step(eqc: &EqClasses, class_counts: &[u64], prev: &[f64], curr: &mut [f64]) {
+    for (c, n) in eqc.classes.iter().zip(class_counts.iter()) {
+        if *n == 0 {
+            continue;
+        }
+        let denom: f64 = c
+            .txps
+            .iter()
+            .zip(c.weights.iter())
+            .map(|(t, w)| prev[*t as usize] * w)
+            .sum();
+        if denom > constants::EM_DENOM_THRESH {
+            let scale = *n as f64 / denom;
+            for (t, w) in c.txps.iter().zip(c.weights.iter()) {
+                curr[*t as usize] += prev[*t as usize] * w * scale;
+            }
+        }
+    }
+}
+
+/// Estimate the abundances of the transcripts from the equivalence classes, with
+/// `class_counts[c]` reads in class `c`, with the same EM (and convergence criterion)
+/// as the quantification of the reads. `prior` reads are added to the estimate of every
+/// transcript after each round (a symmetric Dirichlet prior, as a MAP estimate), but not
+/// to the final estimates; with a `prior` of 0, this is the EM of the quantification.
+fn eqc_em(
+    eqc: &EqClasses,
+    class_counts: &[u64],
+    max_iter: u32,
+    convergence_thresh: f64,
+    prior: f64,
+) -> Vec<f64> {
+    let num_txps = eqc.names.len();
+    let total: f64 = class_counts.iter().sum::<u64>() as f64;
+    let mut prev = vec![total / num_txps.max(1) as f64; num_txps];
+    let mut curr = vec![0.0_f64; num_txps];
+    let mut niter = 0_u32;
+    while niter < max_iter {
+        eqc_em_step(eqc, class_counts, &prev, &mut curr);
+        if prior > 0.0 {
+            curr.iter_mut().for_each(|x| *x += prior);
+        }
+        let rel_diff = prev
+            .iter()
+            .zip(curr.iter())
+            .filter(|(p, _)| **p > constants::MIN_READ_THRESH)
+            .map(|(p, c)| (c - p) / p)
+            .fold(0.0_f64, f64::max);
+        std::mem::swap(&mut prev, &mut curr);
+        curr.fill(0.0);
+        if rel_diff < convergence_thresh && niter > 50 {
+            break;
+        }
+        niter += 1;
+    }
+    // set very small abundances to 0, and perform one more round
+    for x in prev.iter_mut() {
+        if *x < constants::MIN_READ_THRESH {
+            *x = 0.0;
+        }
+    }
+    eqc_em_step(eqc, class_counts, &prev, &mut curr);
+    curr
+}
+
+/// Resample the reads of the equivalence classes (uniformly, with replacement), returning
+/// the number of resampled reads of each class.
+fn resample_class_counts(eqc: &EqClasses) -> Vec<u64> {
+    let mut cum = Vec::with_capacity(eqc.classes.len());
+    let mut acc = 0_usize;
+    for c in eqc.classes.iter() {
+        acc += c.count as usize;
+        cum.push(acc);
+    }
+    let mut counts = vec![0_u64; eqc.classes.len()];
+    for i in bootstrap::get_sample_inds(acc, &mut trng()) {
+        counts[cum.partition_point(|e| *e <= i)] += 1;
+    }
+    counts
+}
+
+/// Write the estimates `counts` of the transcripts of the equivalence classes `eqc` to
+/// `<output>.quant`, the run information `info` to `<output>.meta_info.json` and, if
+/// `num_bootstraps > 0`, the bootstrap replicates (computed by resampling the reads of
+/// the classes, with the given EM settings) to `<output>.infreps.pq`.
+#[allow(clippy::too_many_arguments)]
+fn write_eqc_estimates(
+    output: &Path,
+    eqc: &EqClasses,
+    counts: &[f64],
+    mut info: serde_json::Value,
+    num_bootstraps: u32,
+    max_iter: u32,
+    convergence_thresh: f64,
+    prior: f64,
+    threads: usize,
+) -> anyhow::Result<()> {
+    if let Some(p) = output.parent() {
+        if p != Path::new("") {
+            create_dir_all(p)?;
+        }
+    }
+
+    add_schema_info(&mut info);
+    {
+        let info_path = output.with_additional_extension(".meta_info.json");
+        let write = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&info_path)
+            .with_context(|| format!("could not create {}", info_path.display()))?;
+        serde_json::ser::to_writer_pretty(write, &info)?;
+    }
+    {
+        let quant_path = output.with_additional_extension(".quant");
+        let write = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&quant_path)
+            .with_context(|| format!("could not create {}", quant_path.display()))?;
+        let mut writer = BufWriter::new(write);
+        provenance::write_tsv_comment(&mut writer)?;
+        writeln!(writer, "tname\tlen\tnum_reads")?;
+        for ((name, len), c) in eqc.names.iter().zip(eqc.lens.iter()).zip(counts.iter()) {
+            writeln!(writer, "{}\t{}\t{}", name, len, c)?;
+        }
+        writer.flush()?;
+    }
+
+    if num_bootstraps > 0 {
+        info!("will collect {num_bootstraps} bootstraps");
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let breps: Vec<Vec<f64>> = pool.install(|| {
+            (0..num_bootstraps)
+                .into_par_iter()
+                .map(|_| {
+                    let resampled = resample_class_counts(eqc);
+                    eqc_em(eqc, &resampled, max_iter, convergence_thresh, prior)
+                })
+                .collect()
+        });
+        let mut new_arrays = vec![];
+        let mut bs_fields = vec![];
+        for (i, b) in breps.into_iter().enumerate() {
+            let bs_array = Float64Array::from_vec(b);
+            bs_fields.push(Field::new(
+                format!("bootstrap.{}", i),
+                bs_array.data_type().clone(),
+                false,
+            ));
+            new_arrays.push(bs_array.boxed());
+        }
+        write_infrep_file(
+            output,
+            bs_fields,
+            Chunk::new(new_arrays),
+            OutputFormat::Parquet,
+            None,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The contribution of one of the merged replicates.
+#[derive(Debug, Serialize)]
+struct ReplicateSummary {
+    prefix: PathBuf,
+    num_reads: u64,
+    num_eq_classes: usize,
+}
+
+/// Merge the technical replicates with the output prefixes `inputs` (each quantified
+/// with `--write-eqclasses`) by summing their equivalence classes and re-running the EM
+/// over the combined classes, writing the estimates (and, if `num_bootstraps > 0`, the
+/// bootstrap replicates computed by resampling the reads of all replicates together)
+/// with the prefix `output`.
+pub fn merge_replicates(
+    inputs: &[PathBuf],
+    output: &PathBuf,
+    num_bootstraps: u32,
+    max_iter: u32,
+    convergence_thresh: f64,
+    threads: usize,
+) -> anyhow::Result<()> {
+    let mut samples = Vec::with_capacity(inputs.len());
+    let mut summaries = Vec::with_capacity(inputs.len());
+    for prefix in inputs {
+        let eqc = read_eq_classes(prefix)?;
+        info!(
+            "replicate {} : {} reads in {} equivalence classes",
+            prefix.display(),
+            eqc.num_reads().to_formatted_string(&Locale::en),
+            eqc.classes.len().to_formatted_string(&Locale::en)
+        );
+        summaries.push(ReplicateSummary {
+            prefix: prefix.clone(),
+            num_reads: eqc.num_reads(),
+            num_eq_classes: eqc.classes.len(),
+        });
+        samples.push(eqc);
+    }
+    let eqc = combine_eq_classes(inputs, samples)?;
+    let class_counts: Vec<u64> = eqc.classes.iter().map(|c| c.count).collect();
+    info!(
+        "merged {} reads into {} equivalence classes.",
+        eqc.num_reads().to_formatted_string(&Locale::en),
+        eqc.classes.len().to_formatted_string(&Locale::en)
+    );
+
+    let counts = eqc_em(&eqc, &class_counts, max_iter, convergence_thresh, 0.0);
+
+    let info = json!({
+        "merged_replicates": &summaries,
+        "num_reads": eqc.num_reads(),
+        "num_eq_classes": eqc.classes.len(),
+        "num_bootstraps": num_bootstraps,
+        "em_max_iter": max_iter,
+        "em_convergence_thresh": convergence_thresh,
+    });
+    write_eqc_estimates(
+        output,
+        &eqc,
+        &counts,
+        info,
+        num_bootstraps,
+        max_iter,
+        convergence_thresh,
+        0.0,
+        threads,
+    )?;
+
+    info!(
+        "merged {} replicates into {}.",
+        inputs.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Quantify the transcripts from the equivalence classes `eq_classes` (a file written with
+/// `--write-eqclasses`, or the output prefix of the run that wrote it), without reading the
+/// alignments again, writing the estimates (and, if `num_bootstraps > 0`, the bootstrap
+/// replicates) with the prefix `output`.
+pub fn quant_from_eq_classes(
+    eq_classes: &Path,
+    output: &Path,
+    num_bootstraps: u32,
+    max_iter: u32,
+    convergence_thresh: f64,
+    prior: f64,
+    threads: usize,
+) -> anyhow::Result<()> {
+    if !(prior >= 0.0 && prior.is_finite()) {
+        return Err(bad_input(format!(
+            "--prior must be a non-negative number of reads, but got {}",
+            prior
+        )));
+    }
+    let eqc = read_eq_class_file(eq_classes)?;
+    let class_counts: Vec<u64> = eqc.classes.iter().map(|c| c.count).collect();
+    info!(
+        "read {} reads in {} equivalence classes, over {} transcripts, from {}.",
+        eqc.num_reads().to_formatted_string(&Locale::en),
+        eqc.classes.len().to_formatted_string(&Locale::en),
+        eqc.names.len().to_formatted_string(&Locale::en),
+        eq_classes.display()
+    );
+
+    let counts = eqc_em(&eqc, &class_counts, max_iter, convergence_thresh, prior);
+
+    let info = json!({
+        "eq_classes": eq_classes,
+        "num_reads": eqc.num_reads(),
+        "num_eq_classes": eqc.classes.len(),
+        "num_bootstraps": num_bootstraps,
+        "em_max_iter": max_iter,
+        "em_convergence_thresh": convergence_thresh,
+        "em_prior": prior,
+    });
+    write_eqc_estimates(
+        output,
+        &eqc,
+        &counts,
+        info,
+        num_bootstraps,
+        max_iter,
+        convergence_thresh,
+        prior,
+        threads,
+    )?;
+
+    info!(
+        "wrote the estimates from the equivalence classes to {}.",
+        output.display()
+    );
+    Ok(())
+}
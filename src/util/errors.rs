@@ -0,0 +1,95 @@
+use std::fmt;
+use std::io;
+
+/// The classes of failure of a run, each reported by its own exit code, so that workflow
+/// managers can tell the failures worth retrying (e.g. with more memory or disk space)
+/// from those that will fail again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureClass {
+    /// any other failure, including bugs in oarfish
+    Other,
+    /// the inputs or options are missing, malformed or inconsistent
+    BadInput,
+    /// the alignments or the index do not match the reference
+    ReferenceMismatch,
+    /// the run ran out of memory, disk space or another resource of the system
+    ResourceExhausted,
+}
+
+impl FailureClass {
+    /// The exit code with which oarfish stops after a failure of this class (clap uses
+    /// 2 for errors on the command line).
+    pub fn exit_code(self) -> u8 {
+        match self {
+            FailureClass::Other => 1,
+            FailureClass::BadInput => 3,
+            FailureClass::ReferenceMismatch => 4,
+            FailureClass::ResourceExhausted => 5,
+        }
+    }
+}
+
+/// An error whose class of failure is known where it is raised.
+#[derive(Debug)]
+pub struct OarfishError {
+    class: FailureClass,
+    message: String,
+}
+
+impl fmt::Display for OarfishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for OarfishError {}
+
+fn classified(class: FailureClass, message: impl Into<String>) -> anyhow::Error {
+    anyhow::Error::new(OarfishError {
+        class,
+        message: message.into(),
+    })
+}
+
+/// An error for inputs or options that are missing, malformed or inconsistent.
+pub fn bad_input(message: impl Into<String>) -> anyhow::Error {
+    classified(FailureClass::BadInput, message)
+}
+
+/// An error for alignments or an index that do not match the reference.
+pub fn reference_mismatch(message: impl Into<String>) -> anyhow::Error {
+    classified(FailureClass::ReferenceMismatch, message)
+}
+
+/// The class of failure implied by the I/O error `e`, if any.
+fn io_class(e: &io::Error) -> Option<FailureClass> {
+    match e.raw_os_error() {
+        Some(libc::ENOSPC | libc::EDQUOT | libc::ENOMEM | libc::EMFILE | libc::ENFILE) => {
+            return Some(FailureClass::ResourceExhausted);
+        }
+        _ => {}
+    }
+    match e.kind() {
+        io::ErrorKind::OutOfMemory => Some(FailureClass::ResourceExhausted),
+        io::ErrorKind::NotFound
+        | io::ErrorKind::PermissionDenied
+        | io::ErrorKind::InvalidData
+        | io::ErrorKind::UnexpectedEof => Some(FailureClass::BadInput),
+        _ => None,
+    }
+}
+
+/// The class of the failure `err`: that of the first error in its chain of causes whose
+/// class is known, either because it was raised as an [`OarfishError`] or because it is
+/// an I/O error of a recognized kind.
+pub fn classify(err: &anyhow::Error) -> FailureClass {
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<OarfishError>() {
+            return e.class;
+        }
+        if let Some(class) = cause.downcast_ref::<io::Error>().and_then(io_class) {
+            return class;
+        }
+    }
+    FailureClass::Other
+}
@@ -0,0 +1,185 @@
+use crate::prog_opts::{OutputCompression, OutputFormat};
+use crate::util::compressed_writer::CompressedWriter;
+use crate::util::provenance;
+use crate::util::read_function::read_txp_genes;
+use crate::util::write_function::write_infrep_file;
+use arrow2::{array::Float64Array, chunk::Chunk, datatypes::Field};
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use rustc_hash::FxHashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::info;
+
+/// The value at quantile `q` of the (sorted) `vals`, interpolating linearly between
+/// the closest two values.
+fn quantile(vals: &[f64], q: f64) -> f64 {
+    let pos = q * (vals.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    vals[lo] + (vals[hi] - vals[lo]) * (pos - lo as f64)
+}
+
+/// Aggregates the estimates of the transcripts, and their bootstrap replicates, to the
+/// level of their genes. The abundance of a gene in each replicate is the sum of the
+/// abundances of its transcripts in that replicate, so that the uncertainty of the gene
+/// reflects the (typically negative) covariance of its isoforms, which share reads,
+/// rather than being (over)estimated from the uncertainties of the isoforms.
+pub struct GeneAggregation {
+    gene_names: Vec<String>,
+    /// the gene (an index into `gene_names`) of each transcript, if it has one and is
+    /// reported
+    gene_of: Vec<Option<u32>>,
+    num_txps: Vec<u32>,
+    /// the abundance of each gene in each replicate, indexed by replicate
+    reps: Mutex<Vec<Vec<f64>>>,
+}
+
+impl GeneAggregation {
+    /// Read the gene of each transcript (in `txps_name`) from the transcript-to-gene file
+    /// `t2g`, to aggregate the estimates and `num_reps` replicates. The transcripts
+    /// `excluded` from the output are left out of their genes.
+    pub fn new(
+        t2g: &Path,
+        txps_name: &[String],
+        excluded: Option<&[bool]>,
+        num_reps: usize,
+    ) -> anyhow::Result<Self> {
+        let txp_genes = read_txp_genes(t2g, txps_name)?;
+        let mut gene_ids: FxHashMap<String, u32> = FxHashMap::default();
+        let mut gene_names = Vec::new();
+        let mut num_txps = Vec::new();
+        let mut gene_of = Vec::with_capacity(txp_genes.len());
+        for (t, gene) in txp_genes.into_iter().enumerate() {
+            let gene = gene.filter(|_| !excluded.is_some_and(|ex| ex[t]));
+            gene_of.push(gene.map(|g| {
+                let id = *gene_ids.entry(g).or_insert_with_key(|g| {
+                    gene_names.push(g.clone());
+                    num_txps.push(0);
+                    gene_names.len() as u32 - 1
+                });
+                num_txps[id as usize] += 1;
+                id
+            }));
+        }
+        info!(
+            "aggregating the estimates of the transcripts to {} genes.",
+            gene_names.len().to_formatted_string(&Locale::en)
+        );
+        // every replicate starts with one (zero) entry per gene, so that the replicates
+        // can be read gene by gene even if one of them is never added
+        let reps = vec![vec![0.0_f64; gene_names.len()]; num_reps];
+        Ok(Self {
+            gene_names,
+            gene_of,
+            num_txps,
+            reps: Mutex::new(reps),
+        })
+    }
+
+    /// The sum of the abundances `counts` of the transcripts of each gene.
+    pub fn gene_sums(&self, counts: &[f64]) -> Vec<f64> {
+        let mut sums = vec![0.0_f64; self.gene_names.len()];
+        for (g, c) in self.gene_of.iter().zip(counts.iter()) {
+            if let Some(g) = g {
+                sums[*g as usize] += c;
+            }
+        }
+        sums
+    }
+
+    /// Add the abundances of the genes in replicate `rep`, given the abundances `counts`
+    /// of the transcripts in that replicate.
+    pub fn add_replicate(&self, rep: usize, counts: &[f64]) {
+        let sums = self.gene_sums(counts);
+        self.reps.lock().expect("gene replicate lock poisoned")[rep] = sums;
+    }
+
+    /// Write the estimated number of reads of each gene, given those of the transcripts in
+    /// `counts`, to `<output>.gene_quant.tsv` and, if there are replicates, the mean,
+    /// variance and central `ci_level` credible interval of the abundance of each gene over
+    /// the replicates, along with the gene-level replicates themselves to
+    /// `<output>.genes.infreps.<ext>`.
+    pub fn write(
+        self,
+        output: &Path,
+        counts: &[f64],
+        ci_level: f64,
+        format: OutputFormat,
+        compression: Option<OutputCompression>,
+    ) -> anyhow::Result<()> {
+        let gene_counts = self.gene_sums(counts);
+        let reps = self
+            .reps
+            .into_inner()
+            .expect("gene replicate lock poisoned");
+        let has_reps = !reps.is_empty();
+
+        let out_path = output
+            .to_path_buf()
+            .with_additional_extension(".gene_quant.tsv");
+        let mut writer =
+            CompressedWriter::create(&out_path, compression.unwrap_or(OutputCompression::None))?;
+        provenance::write_tsv_comment(&mut writer)?;
+        if has_reps {
+            writeln!(
+                writer,
+                "gene\tnum_txps\tnum_reads\tbootstrap_mean\tbootstrap_var\tci_low\tci_high"
+            )?;
+        } else {
+            writeln!(writer, "gene\tnum_txps\tnum_reads")?;
+        }
+        let alpha = (1.0 - ci_level) / 2.0;
+        let mut vals = vec![0.0_f64; reps.len()];
+        for (g, name) in self.gene_names.iter().enumerate() {
+            write!(writer, "{}\t{}\t{}", name, self.num_txps[g], gene_counts[g])?;
+            if has_reps {
+                for (v, r) in vals.iter_mut().zip(reps.iter()) {
+                    *v = r[g];
+                }
+                let n = vals.len() as f64;
+                let mean = vals.iter().sum::<f64>() / n;
+                let var =
+                    vals.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1.0).max(1.0);
+                vals.sort_unstable_by(f64::total_cmp);
+                write!(
+                    writer,
+                    "\t{}\t{}\t{}\t{}",
+                    mean,
+                    var,
+                    quantile(&vals, alpha),
+                    quantile(&vals, 1.0 - alpha)
+                )?;
+            }
+            writeln!(writer)?;
+        }
+        writer.finish()?;
+        info!(
+            "wrote the estimates of {} genes to {}.",
+            self.gene_names.len().to_formatted_string(&Locale::en),
+            out_path.display()
+        );
+
+        if has_reps {
+            let mut arrays = vec![];
+            let mut fields = vec![];
+            for (i, b) in reps.into_iter().enumerate() {
+                let array = Float64Array::from_vec(b);
+                fields.push(Field::new(
+                    format!("bootstrap.{}", i),
+                    array.data_type().clone(),
+                    false,
+                ));
+                arrays.push(array.boxed());
+            }
+            write_infrep_file(
+                &output.to_path_buf().with_additional_extension(".genes"),
+                fields,
+                Chunk::new(arrays),
+                format,
+                compression,
+            )?;
+        }
+        Ok(())
+    }
+}
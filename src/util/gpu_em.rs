@@ -0,0 +1,451 @@
+//! The E-step of the bulk EM on a GPU (through wgpu), for deep samples whose EM is
+//! dominated by the computation of the assignment probabilities of the reads.
+//!
+//! The alignments of the reads are laid out once, in read order, as the transcript and
+//! the (static) likelihood of each alignment. Each round of the EM then runs two kernels:
+//! the first computes the probability of each alignment of a read given the abundances of
+//! the previous round, and the second sums these probabilities for each transcript (over
+//! its alignments, listed in a transposed index), so that no floating-point atomics are
+//! needed and the result does not depend on the order in which the reads are processed.
+//! The computations are done in single precision.
+
+/// The error reported when the GPU EM is requested from a build without it.
+pub const GPU_UNAVAILABLE: &str =
+    "this build of oarfish does not support the GPU EM; rebuild it with `--features gpu`";
+
+#[cfg(feature = "gpu")]
+pub use imp::GpuEStep;
+
+#[cfg(feature = "gpu")]
+mod imp {
+    use crate::util::constants;
+    use crate::util::oarfish_types::EMInfo;
+    use anyhow::Context;
+    use itertools::izip;
+    use num_format::{Locale, ToFormattedString};
+    use tracing::info;
+    use wgpu::util::DeviceExt;
+
+    /// The number of threads of each workgroup of both kernels.
+    const WORKGROUP_SIZE: u32 = 256;
+    /// The largest number of workgroups that may be dispatched along a dimension.
+    const MAX_GROUPS_PER_DIM: u32 = 65_535;
+
+    /// The kernel computing, for each read, the probability of each of its alignments given
+    /// the abundances `prev`, along with the (scaled) likelihood `read_denom` of the read.
+    const E_STEP_SHADER: &str = r#"
+struct Params {
+    num_items: u32,
+    row_stride: u32,
+    denom_thresh: f32,
+    _pad: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> read_offsets: array<u32>;
+@group(0) @binding(2) var<storage, read> aln_txp: array<u32>;
+@group(0) @binding(3) var<storage, read> aln_weight: array<f32>;
+@group(0) @binding(4) var<storage, read> prev: array<f32>;
+@group(0) @binding(5) var<storage, read_write> resp: array<f32>;
+@group(0) @binding(6) var<storage, read_write> read_denom: array<f32>;
+
+@compute @workgroup_size(256)
+fn e_step(@builtin(global_invocation_id) gid: vec3<u32>) {
+    let r = gid.x + gid.y * params.row_stride;
+    if (r >= params.num_items) {
+        return;
+    }
+    let lo = read_offsets[r];
+    let hi = read_offsets[r + 1u];
+    var denom = 0.0;
+    for (var a = lo; a < hi; a++) {
+        denom += prev[aln_txp[a]] * aln_weight[a];
+    }
+    read_denom[r] = denom;
+    for (var a = lo; a < hi; a++) {
+        if (denom > params.denom_thresh) {
+            resp[a] = prev[aln_txp[a]] * aln_weight[a] / denom;
+        } else {
+            resp[a] = 0.0;
+        }
+    }
+}
+"#;
+
+    /// The kernel summing the probabilities of the alignments to each transcript.
+    const ACCUMULATE_SHADER: &str = r#"
+struct Params {
+    num_items: u32,
+    row_stride: u32,
+    denom_thresh: f32,
+    _pad: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> txp_offsets: array<u32>;
+@group(0) @binding(2) var<storage, read> txp_alns: array<u32>;
+@group(0) @binding(3) var<storage, read> resp: array<f32>;
+@group(0) @binding(4) var<storage, read_write> curr: array<f32>;
+
+@compute @workgroup_size(256)
+fn accumulate(@builtin(global_invocation_id) gid: vec3<u32>) {
+    let t = gid.x + gid.y * params.row_stride;
+    if (t >= params.num_items) {
+        return;
+    }
+    var total = 0.0;
+    for (var i = txp_offsets[t]; i < txp_offsets[t + 1u]; i++) {
+        total += resp[txp_alns[i]];
+    }
+    curr[t] = total;
+}
+"#;
+
+    /// The parameters of a kernel, as laid out in its uniform buffer.
+    #[repr(C)]
+    #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+    struct Params {
+        num_items: u32,
+        row_stride: u32,
+        denom_thresh: f32,
+        _pad: u32,
+    }
+
+    /// The number of workgroups (along x and y) covering `n` items, and the number of
+    /// items in each row of the dispatch.
+    fn dispatch_dims(n: usize) -> (u32, u32, u32) {
+        let groups = (n as u32).div_ceil(WORKGROUP_SIZE).max(1);
+        let x = groups.min(MAX_GROUPS_PER_DIM);
+        (x, groups.div_ceil(x), x * WORKGROUP_SIZE)
+    }
+
+    /// A compute kernel, with the bind group of its buffers and its dispatch size.
+    struct Kernel {
+        pipeline: wgpu::ComputePipeline,
+        bind_group: wgpu::BindGroup,
+        groups: (u32, u32),
+    }
+
+    impl Kernel {
+        fn new(
+            device: &wgpu::Device,
+            source: &str,
+            entry_point: &str,
+            num_items: usize,
+            buffers: &[&wgpu::Buffer],
+        ) -> Self {
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(entry_point),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
+            });
+            let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &module,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+            let (x, y, row_stride) = dispatch_dims(num_items);
+            let params = Params {
+                num_items: num_items as u32,
+                row_stride,
+                denom_thresh: constants::EM_DENOM_THRESH as f32,
+                _pad: 0,
+            };
+            let params_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("params"),
+                contents: bytemuck::bytes_of(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            let entries: Vec<wgpu::BindGroupEntry> = std::iter::once(&params_buf)
+                .chain(buffers.iter().copied())
+                .enumerate()
+                .map(|(i, b)| wgpu::BindGroupEntry {
+                    binding: i as u32,
+                    resource: b.as_entire_binding(),
+                })
+                .collect();
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(entry_point),
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &entries,
+            });
+            Self {
+                pipeline,
+                bind_group,
+                groups: (x, y),
+            }
+        }
+
+        fn dispatch(&self, encoder: &mut wgpu::CommandEncoder) {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &self.bind_group, &[]);
+            pass.dispatch_workgroups(self.groups.0, self.groups.1, 1);
+        }
+    }
+
+    /// The E-step of the EM of a sample, with its alignments held on a GPU.
+    pub struct GpuEStep {
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        e_step: Kernel,
+        accumulate: Kernel,
+        prev_buf: wgpu::Buffer,
+        curr_buf: wgpu::Buffer,
+        denom_buf: wgpu::Buffer,
+        curr_readback: wgpu::Buffer,
+        denom_readback: wgpu::Buffer,
+        /// the log of the factor by which the likelihoods of the alignments of each read
+        /// were divided (so that the largest is 1), to avoid their underflow in single
+        /// precision; this does not change the assignment probabilities of the read
+        log_scales: Vec<f64>,
+    }
+
+    impl GpuEStep {
+        /// Copy the alignments of `em_info` to the first GPU found, returning [None] if
+        /// there is none, and an error if the alignments do not fit on it.
+        pub fn new(em_info: &EMInfo) -> anyhow::Result<Option<Self>> {
+            let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+            let Some(adapter) =
+                pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::HighPerformance,
+                    compatible_surface: None,
+                    force_fallback_adapter: false,
+                }))
+            else {
+                return Ok(None);
+            };
+            // a software adapter would be slower than the CPU EM
+            let adapter_info = adapter.get_info();
+            if adapter_info.device_type == wgpu::DeviceType::Cpu {
+                return Ok(None);
+            }
+            let limits = adapter.limits();
+            let (device, queue) = pollster::block_on(adapter.request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("oarfish EM"),
+                    required_features: wgpu::Features::empty(),
+                    required_limits: limits.clone(),
+                    memory_hints: wgpu::MemoryHints::Performance,
+                },
+                None,
+            ))
+            .context("could not open the GPU device")?;
+
+            // lay out the alignments of the reads, with their likelihoods
+            let eq_map = em_info.eq_map;
+            let tinfo = em_info.txp_info;
+            let model_coverage = eq_map.filter_opts.model_coverage;
+            let num_reads = eq_map.len();
+            let num_txps = tinfo.len();
+            let mut read_offsets = Vec::with_capacity(num_reads + 1);
+            let mut aln_txp = Vec::<u32>::new();
+            let mut aln_weight = Vec::<f32>::new();
+            let mut log_scales = Vec::with_capacity(num_reads);
+            let mut weights = Vec::<f64>::new();
+            read_offsets.push(0_u32);
+            for r in 0..num_reads {
+                let (alns, probs, coverage_probs) = eq_map.group(r);
+                weights.clear();
+                for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
+                    let target_id = a.ref_id as usize;
+                    let cov_prob = if model_coverage { *cp } else { 1.0 };
+                    let dens_prob = match em_info.kde_model {
+                        Some(ref kde_model) => {
+                            kde_model[(tinfo[target_id].lenf as usize, a.alignment_span() as usize)]
+                        }
+                        None => 1.0,
+                    };
+                    let txp_weight = em_info.txp_weights.as_ref().map_or(1.0, |w| w[target_id]);
+                    weights.push(*p as f64 * cov_prob * dens_prob * txp_weight);
+                    aln_txp.push(a.ref_id);
+                }
+                let scale = weights.iter().copied().fold(0.0_f64, f64::max);
+                let scale = if scale > 0.0 { scale } else { 1.0 };
+                aln_weight.extend(weights.iter().map(|w| (w / scale) as f32));
+                log_scales.push(scale.ln());
+                read_offsets.push(
+                    u32::try_from(aln_txp.len()).context("too many alignments for the GPU EM")?,
+                );
+            }
+
+            anyhow::ensure!(!aln_txp.is_empty(), "there are no alignments to process");
+
+            // the transposed index, listing the alignments to each transcript
+            let mut txp_offsets = vec![0_u32; num_txps + 1];
+            for t in aln_txp.iter() {
+                txp_offsets[*t as usize + 1] += 1;
+            }
+            for t in 0..num_txps {
+                txp_offsets[t + 1] += txp_offsets[t];
+            }
+            let mut fill = txp_offsets.clone();
+            let mut txp_alns = vec![0_u32; aln_txp.len()];
+            for (a, t) in aln_txp.iter().enumerate() {
+                txp_alns[fill[*t as usize] as usize] = a as u32;
+                fill[*t as usize] += 1;
+            }
+
+            let largest = (aln_txp.len().max(num_reads + 1).max(num_txps + 1) * 4) as u64;
+            anyhow::ensure!(
+                largest <= limits.max_storage_buffer_binding_size as u64,
+                "the {} alignments do not fit in a buffer of {}",
+                aln_txp.len().to_formatted_string(&Locale::en),
+                adapter_info.name
+            );
+
+            let storage = |label: &str, contents: &[u8], extra: wgpu::BufferUsages| {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(label),
+                    contents,
+                    usage: wgpu::BufferUsages::STORAGE | extra,
+                })
+            };
+            let none = wgpu::BufferUsages::empty();
+            let read_offsets_buf =
+                storage("read_offsets", bytemuck::cast_slice(&read_offsets), none);
+            let aln_txp_buf = storage("aln_txp", bytemuck::cast_slice(&aln_txp), none);
+            let aln_weight_buf = storage("aln_weight", bytemuck::cast_slice(&aln_weight), none);
+            let txp_offsets_buf = storage("txp_offsets", bytemuck::cast_slice(&txp_offsets), none);
+            let txp_alns_buf = storage("txp_alns", bytemuck::cast_slice(&txp_alns), none);
+            let prev_buf = storage(
+                "prev",
+                bytemuck::cast_slice(&vec![0_f32; num_txps]),
+                wgpu::BufferUsages::COPY_DST,
+            );
+            let curr_buf = storage(
+                "curr",
+                bytemuck::cast_slice(&vec![0_f32; num_txps]),
+                wgpu::BufferUsages::COPY_SRC,
+            );
+            let resp_buf = storage(
+                "resp",
+                bytemuck::cast_slice(&vec![0_f32; aln_txp.len().max(1)]),
+                none,
+            );
+            let denom_buf = storage(
+                "read_denom",
+                bytemuck::cast_slice(&vec![0_f32; num_reads.max(1)]),
+                wgpu::BufferUsages::COPY_SRC,
+            );
+            let readback = |label: &str, len: usize| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(label),
+                    size: (len.max(1) * 4) as u64,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            };
+            let curr_readback = readback("curr_readback", num_txps);
+            let denom_readback = readback("denom_readback", num_reads);
+
+            let e_step = Kernel::new(
+                &device,
+                E_STEP_SHADER,
+                "e_step",
+                num_reads,
+                &[
+                    &read_offsets_buf,
+                    &aln_txp_buf,
+                    &aln_weight_buf,
+                    &prev_buf,
+                    &resp_buf,
+                    &denom_buf,
+                ],
+            );
+            let accumulate = Kernel::new(
+                &device,
+                ACCUMULATE_SHADER,
+                "accumulate",
+                num_txps,
+                &[&txp_offsets_buf, &txp_alns_buf, &resp_buf, &curr_buf],
+            );
+            info!(
+                "running the E-step of the EM on {} ({} alignments).",
+                adapter_info.name,
+                aln_txp.len().to_formatted_string(&Locale::en)
+            );
+            Ok(Some(Self {
+                device,
+                queue,
+                e_step,
+                accumulate,
+                prev_buf,
+                curr_buf,
+                denom_buf,
+                curr_readback,
+                denom_readback,
+                log_scales,
+            }))
+        }
+
+        /// Read the contents of the mapped-readable buffer `buf`.
+        fn read_back(&self, buf: &wgpu::Buffer) -> Vec<f32> {
+            let slice = buf.slice(..);
+            let (tx, rx) = std::sync::mpsc::channel();
+            slice.map_async(wgpu::MapMode::Read, move |r| {
+                let _ = tx.send(r);
+            });
+            self.device.poll(wgpu::Maintain::Wait);
+            rx.recv()
+                .expect("the GPU buffer mapping was dropped")
+                .expect("could not map the GPU buffer");
+            let values = bytemuck::cast_slice::<u8, f32>(&slice.get_mapped_range()).to_vec();
+            buf.unmap();
+            values
+        }
+
+        /// One round of the EM, computing `curr_counts` from `prev_counts` as in the CPU
+        /// E-step. If `track_ll` is true, the log-likelihood of `prev_counts` is returned
+        /// (and 0 otherwise).
+        pub fn step(&self, prev_counts: &[f64], curr_counts: &mut [f64], track_ll: bool) -> f64 {
+            let prev: Vec<f32> = prev_counts.iter().map(|x| *x as f32).collect();
+            self.queue
+                .write_buffer(&self.prev_buf, 0, bytemuck::cast_slice(&prev));
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            self.e_step.dispatch(&mut encoder);
+            self.accumulate.dispatch(&mut encoder);
+            encoder.copy_buffer_to_buffer(
+                &self.curr_buf,
+                0,
+                &self.curr_readback,
+                0,
+                self.curr_readback.size(),
+            );
+            if track_ll {
+                encoder.copy_buffer_to_buffer(
+                    &self.denom_buf,
+                    0,
+                    &self.denom_readback,
+                    0,
+                    self.denom_readback.size(),
+                );
+            }
+            self.queue.submit(Some(encoder.finish()));
+
+            for (c, x) in curr_counts
+                .iter_mut()
+                .zip(self.read_back(&self.curr_readback))
+            {
+                *c = x as f64;
+            }
+            if !track_ll {
+                return 0.0;
+            }
+            let log_total = prev_counts.iter().sum::<f64>().ln();
+            self.read_back(&self.denom_readback)
+                .iter()
+                .zip(self.log_scales.iter())
+                .filter(|(d, _)| **d as f64 > constants::EM_DENOM_THRESH)
+                .map(|(d, s)| (*d as f64).ln() + s - log_total)
+                .sum()
+        }
+    }
+}
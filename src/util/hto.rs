@@ -0,0 +1,309 @@
+use crate::bulk::get_source_type;
+use crate::util::bam_output::revcomp;
+use crate::util::barcode_tags::BarcodeSource;
+use crate::util::oarfish_types::InputSourceType;
+use anyhow::{Context, bail};
+use needletail::parse_fastx_file;
+use noodles_bam as bam;
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Only this many bases at each end of a (longer) read are searched for a hashtag,
+/// as the hashtag oligos are short molecules whose reads are not much longer than
+/// the oligo itself.
+const HTO_SCAN_WINDOW: usize = 200;
+/// The marker of a sequence matching more than one hashtag with a single mismatch.
+const AMBIGUOUS_HTO: u32 = u32::MAX;
+
+/// The hashtag oligos (HTOs) used to multiplex the samples of a single-cell experiment.
+pub struct HashtagSet {
+    names: Vec<String>,
+    len: usize,
+    /// every sequence within one mismatch of a hashtag (or of its reverse complement),
+    /// and the hashtag it matches
+    lookup: FxHashMap<Vec<u8>, u32>,
+}
+
+/// Read the hashtags from the file at `path`, with lines of the form `<name>\t<sequence>`;
+/// all hashtag sequences must have the same length.
+pub fn read_hashtags(path: &Path) -> anyhow::Result<HashtagSet> {
+    let file = File::open(path)
+        .with_context(|| format!("could not open hashtag list {}", path.display()))?;
+    let mut names = Vec::new();
+    let mut seqs = Vec::new();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, seq)) = line.split_once('\t') else {
+            bail!(
+                "line {} of hashtag list {} was not of the form <name>\\t<sequence>",
+                lnum + 1,
+                path.display()
+            );
+        };
+        let seq = seq.trim().to_ascii_uppercase().into_bytes();
+        anyhow::ensure!(
+            seq.iter().all(|b| matches!(b, b'A' | b'C' | b'G' | b'T')),
+            "the hashtag {} in {} is not a DNA sequence",
+            name,
+            path.display()
+        );
+        names.push(name.trim().to_owned());
+        seqs.push(seq);
+    }
+    anyhow::ensure!(
+        !seqs.is_empty(),
+        "the hashtag list {} is empty",
+        path.display()
+    );
+    let len = seqs[0].len();
+    anyhow::ensure!(
+        seqs.iter().all(|s| s.len() == len),
+        "the hashtags in {} do not all have the same length",
+        path.display()
+    );
+
+    // exact matches take precedence over those with a mismatch, and a sequence within
+    // one mismatch of several hashtags is ambiguous
+    let mut lookup: FxHashMap<Vec<u8>, u32> = FxHashMap::default();
+    for (i, s) in seqs.iter().enumerate() {
+        for orient in [s.clone(), revcomp(s)] {
+            if let Some(j) = lookup.insert(orient, i as u32) {
+                anyhow::ensure!(
+                    j == i as u32,
+                    "the hashtags {} and {} have the same (or reverse complementary) sequences",
+                    names[j as usize],
+                    names[i]
+                );
+            }
+        }
+    }
+    let mut variants: FxHashMap<Vec<u8>, u32> = FxHashMap::default();
+    for (i, s) in seqs.iter().enumerate() {
+        for orient in [s.clone(), revcomp(s)] {
+            for p in 0..len {
+                for b in [b'A', b'C', b'G', b'T', b'N'] {
+                    if orient[p] == b {
+                        continue;
+                    }
+                    let mut v = orient.clone();
+                    v[p] = b;
+                    if lookup.contains_key(&v) {
+                        continue;
+                    }
+                    variants
+                        .entry(v)
+                        .and_modify(|j| {
+                            if *j != i as u32 {
+                                *j = AMBIGUOUS_HTO;
+                            }
+                        })
+                        .or_insert(i as u32);
+                }
+            }
+        }
+    }
+    lookup.extend(variants);
+    info!(
+        "read {} hashtags of length {} from {}.",
+        names.len(),
+        len,
+        path.display()
+    );
+    Ok(HashtagSet { names, len, lookup })
+}
+
+impl HashtagSet {
+    /// The hashtag found in `seq` (searching only the ends of long reads), or [None] if
+    /// no hashtag, or more than one distinct hashtag, is found. The second value is true
+    /// if more than one hashtag was found.
+    fn find(&self, seq: &[u8]) -> (Option<u32>, bool) {
+        let seq = seq.to_ascii_uppercase();
+        let regions: [&[u8]; 2] = if seq.len() <= 2 * HTO_SCAN_WINDOW {
+            [&seq, &[]]
+        } else {
+            [&seq[..HTO_SCAN_WINDOW], &seq[seq.len() - HTO_SCAN_WINDOW..]]
+        };
+        let mut found = None;
+        for region in regions.iter().filter(|r| r.len() >= self.len) {
+            for w in region.windows(self.len) {
+                match self.lookup.get(w) {
+                    Some(&i) if i != AMBIGUOUS_HTO => match found {
+                        None => found = Some(i),
+                        Some(j) if j != i => return (None, true),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+        (found, false)
+    }
+}
+
+/// A summary of the hashtag counting pass.
+#[derive(Debug, Default, Serialize)]
+pub struct HtoSummary {
+    pub num_reads: u64,
+    /// reads without a (`CB:Z:`) cell barcode
+    pub num_reads_without_barcode: u64,
+    /// reads in which exactly one hashtag was found
+    pub num_reads_with_hashtag: u64,
+    /// reads in which more than one distinct hashtag was found
+    pub num_reads_ambiguous: u64,
+    pub num_barcodes: usize,
+}
+
+/// The hashtag counts of each barcode, with the barcodes in order of first appearance.
+struct HashtagCounts {
+    barcode_ids: FxHashMap<Vec<u8>, usize>,
+    barcodes: Vec<Vec<u8>>,
+    counts: Vec<Vec<u32>>,
+    num_hashtags: usize,
+    summary: HtoSummary,
+}
+
+impl HashtagCounts {
+    fn add_read(&mut self, hashtags: &HashtagSet, barcode: Option<&[u8]>, seq: &[u8]) {
+        self.summary.num_reads += 1;
+        let Some(barcode) = barcode else {
+            self.summary.num_reads_without_barcode += 1;
+            return;
+        };
+        match hashtags.find(seq) {
+            (Some(h), _) => {
+                self.summary.num_reads_with_hashtag += 1;
+                let barcode = barcode.to_ascii_uppercase();
+                let id = match self.barcode_ids.get(&barcode) {
+                    Some(id) => *id,
+                    None => {
+                        self.barcodes.push(barcode.clone());
+                        self.counts.push(vec![0; self.num_hashtags]);
+                        self.barcode_ids.insert(barcode, self.barcodes.len() - 1);
+                        self.barcodes.len() - 1
+                    }
+                };
+                self.counts[id][h as usize] += 1;
+            }
+            (None, true) => self.summary.num_reads_ambiguous += 1,
+            (None, false) => {}
+        }
+    }
+}
+
+/// Count the hashtags of `hashtags` found in the raw reads at `reads` (FASTA/Q, or
+/// unaligned BAM), for each cell barcode (found as described by `bc_source`; for a FASTA/Q
+/// record, in a `CB:Z:<barcode>` field of its header comment, or in its read name). The counts are
+/// written to `<output>.hto.count.mtx`, with the barcodes (rows) in
+/// `<output>.hto.barcodes.txt` and the hashtags (columns) in `<output>.hto.features.txt`.
+pub fn count_hashtags(
+    reads: &Path,
+    hashtags: &HashtagSet,
+    bc_source: &BarcodeSource,
+    output: &PathBuf,
+) -> anyhow::Result<HtoSummary> {
+    let mut counts = HashtagCounts {
+        barcode_ids: FxHashMap::default(),
+        barcodes: Vec::new(),
+        counts: Vec::new(),
+        num_hashtags: hashtags.names.len(),
+        summary: HtoSummary::default(),
+    };
+    match get_source_type(reads) {
+        InputSourceType::Ubam => {
+            let mut reader = File::open(reads)
+                .map(bam::io::Reader::new)
+                .with_context(|| format!("could not open {}", reads.display()))?;
+            let header = reader.read_header()?;
+            for result in reader.record_bufs(&header) {
+                let record = result?;
+                let barcode = bc_source.barcode_opt(&record);
+                counts.add_read(hashtags, barcode, record.sequence().as_ref());
+            }
+        }
+        s @ (InputSourceType::Fastx | InputSourceType::Unknown) => {
+            if matches!(s, InputSourceType::Unknown) {
+                warn!(
+                    "could not determine input file type for {} from its contents or suffix; assuming (possibly gzipped) fastx",
+                    reads.display()
+                );
+            }
+            let mut reader = parse_fastx_file(reads)
+                .with_context(|| format!("could not read {}", reads.display()))?;
+            while let Some(result) = reader.next() {
+                let record = result?;
+                counts.add_read(
+                    hashtags,
+                    bc_source.fastx_barcode(record.id()),
+                    &record.seq(),
+                );
+            }
+        }
+    }
+    counts.summary.num_barcodes = counts.barcodes.len();
+
+    let mut row_ids = Vec::new();
+    let mut col_ids = Vec::new();
+    let mut vals = Vec::new();
+    for (r, row) in counts.counts.iter().enumerate() {
+        for (c, v) in row.iter().enumerate().filter(|(_, v)| **v > 0) {
+            row_ids.push(r as u32);
+            col_ids.push(c as u32);
+            vals.push(*v as f32);
+        }
+    }
+    let trimat = sprs::TriMatI::<f32, u32>::from_triplets(
+        (counts.barcodes.len(), hashtags.names.len()),
+        row_ids,
+        col_ids,
+        vals,
+    );
+    sprs::io::write_matrix_market(output.with_additional_extension(".hto.count.mtx"), &trimat)?;
+
+    let barcodes: Vec<String> = counts
+        .barcodes
+        .iter()
+        .map(|b| String::from_utf8_lossy(b).into_owned())
+        .collect();
+    for (ext, lines) in [
+        (".hto.barcodes.txt", &barcodes),
+        (".hto.features.txt", &hashtags.names),
+    ] {
+        let out_path = output.with_additional_extension(ext);
+        let write = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&out_path)
+            .with_context(|| format!("could not create {}", out_path.display()))?;
+        let mut writer = BufWriter::new(write);
+        for l in lines.iter() {
+            writeln!(writer, "{}", l)?;
+        }
+    }
+
+    let s = &counts.summary;
+    if s.num_reads_without_barcode == s.num_reads && s.num_reads > 0 {
+        warn!(
+            "none of the reads in {} had a cell barcode; no hashtags were counted.",
+            reads.display()
+        );
+    }
+    info!(
+        "found a hashtag in {} of {} reads, for {} barcodes ({} reads had more than one hashtag).",
+        s.num_reads_with_hashtag.to_formatted_string(&Locale::en),
+        s.num_reads.to_formatted_string(&Locale::en),
+        s.num_barcodes.to_formatted_string(&Locale::en),
+        s.num_reads_ambiguous.to_formatted_string(&Locale::en)
+    );
+    Ok(counts.summary)
+}
@@ -0,0 +1,285 @@
+use crate::prog_opts::{OutputCompression, OutputFormat};
+use crate::util::compressed_writer::CompressedWriter;
+use crate::util::eq_classes::EqClasses;
+use crate::util::provenance;
+use crate::util::write_function::write_infrep_file;
+use arrow2::{array::Float64Array, chunk::Chunk, datatypes::Field};
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::io::Write;
+use std::path::Path;
+use tracing::info;
+
+/// The pseudo-count added to the mean of the replicates in the denominator of the
+/// InfRV, so that lowly expressed transcripts do not dominate.
+const INFRV_PSEUDO_COUNT: f64 = 5.0;
+/// The shift added to the InfRV, so that it is positive (and the relative reduction of
+/// the InfRV of well-quantified transcripts is small).
+const INFRV_SHIFT: f64 = 0.01;
+
+/// The inferential relative variance (InfRV) of the replicates `reps` of an abundance:
+/// the variance beyond that expected of a Poisson count, relative to the mean.
+fn infrv(reps: &[f64]) -> f64 {
+    let n = reps.len() as f64;
+    let mean = reps.iter().sum::<f64>() / n;
+    let var = reps.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1.0).max(1.0);
+    (var - mean).max(0.0) / (mean + INFRV_PSEUDO_COUNT) + INFRV_SHIFT
+}
+
+/// The mean and standard deviation of the replicates `reps`.
+fn mean_sd(reps: &[f64]) -> (f64, f64) {
+    let n = reps.len() as f64;
+    let mean = reps.iter().sum::<f64>() / n;
+    let var = reps.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1.0).max(1.0);
+    (mean, var.sqrt())
+}
+
+/// The groups of transcripts that the reads cannot tell apart, each as the (sorted) ids
+/// of its members; only groups of at least two transcripts are held.
+#[derive(Debug, Default)]
+pub struct IndistinguishableGroups {
+    pub groups: Vec<Vec<u32>>,
+}
+
+/// Group the transcripts that cannot be told apart given the reads, in the manner of
+/// terminus: the transcripts (and then groups) that share reads, according to the
+/// equivalence `classes`, are merged greedily in rounds, as long as the InfRV of the
+/// summed bootstrap replicates `breps` (one vector of abundances per replicate) of a
+/// pair is at least a fraction `min_reduction` below the mean of their InfRVs.
+pub fn find_groups(
+    classes: &EqClasses,
+    breps: &[Vec<f64>],
+    min_reduction: f64,
+) -> IndistinguishableGroups {
+    // the pairs of transcripts that share at least one read
+    let mut edges: FxHashSet<(u32, u32)> = FxHashSet::default();
+    for c in classes.classes.iter().filter(|c| c.txps.len() > 1) {
+        for (i, a) in c.txps.iter().enumerate() {
+            for b in &c.txps[i + 1..] {
+                edges.insert((*a.min(b), *a.max(b)));
+            }
+        }
+    }
+
+    // the replicates and members of each group, keyed by its representative transcript
+    let mut reps: FxHashMap<u32, Vec<f64>> = FxHashMap::default();
+    let mut members: FxHashMap<u32, Vec<u32>> = FxHashMap::default();
+    let mut rep_of: FxHashMap<u32, u32> = FxHashMap::default();
+    for t in edges.iter().flat_map(|(a, b)| [*a, *b]) {
+        reps.entry(t)
+            .or_insert_with(|| breps.iter().map(|b| b[t as usize]).collect());
+        members.entry(t).or_insert_with(|| vec![t]);
+        rep_of.insert(t, t);
+    }
+    let mut group_infrv: FxHashMap<u32, f64> = reps.iter().map(|(t, r)| (*t, infrv(r))).collect();
+
+    let mut summed = vec![0.0_f64; breps.len()];
+    loop {
+        // the pairs whose merging would reduce their InfRV enough, best first
+        let mut candidates: Vec<(f64, u32, u32)> = Vec::new();
+        for (a, b) in edges.iter() {
+            for ((s, x), y) in summed.iter_mut().zip(reps[a].iter()).zip(reps[b].iter()) {
+                *s = x + y;
+            }
+            let mean_infrv = 0.5 * (group_infrv[a] + group_infrv[b]);
+            let reduction = 1.0 - infrv(&summed) / mean_infrv;
+            if reduction >= min_reduction {
+                candidates.push((reduction, *a, *b));
+            }
+        }
+        if candidates.is_empty() {
+            break;
+        }
+        candidates.sort_unstable_by(|x, y| y.0.total_cmp(&x.0).then((x.1, x.2).cmp(&(y.1, y.2))));
+
+        // merge each pair neither of whose groups was already merged in this round
+        let mut merged: FxHashSet<u32> = FxHashSet::default();
+        for (_, a, b) in candidates {
+            if merged.contains(&a) || merged.contains(&b) {
+                continue;
+            }
+            merged.insert(a);
+            merged.insert(b);
+            let b_reps = reps.remove(&b).expect("group replicates");
+            let a_reps = reps.get_mut(&a).expect("group replicates");
+            a_reps
+                .iter_mut()
+                .zip(b_reps.iter())
+                .for_each(|(x, y)| *x += y);
+            group_infrv.insert(a, infrv(a_reps));
+            group_infrv.remove(&b);
+            let b_members = members.remove(&b).expect("group members");
+            for m in b_members.iter() {
+                rep_of.insert(*m, a);
+            }
+            members
+                .get_mut(&a)
+                .expect("group members")
+                .extend(b_members);
+        }
+
+        // the edges between the groups that remain
+        edges = edges
+            .into_iter()
+            .filter_map(|(a, b)| {
+                let (a, b) = (rep_of[&a], rep_of[&b]);
+                (a != b).then(|| (a.min(b), a.max(b)))
+            })
+            .collect();
+    }
+
+    let mut groups: Vec<Vec<u32>> = members
+        .into_values()
+        .filter(|m| m.len() > 1)
+        .map(|mut m| {
+            m.sort_unstable();
+            m
+        })
+        .collect();
+    groups.sort_unstable();
+    let num_grouped: usize = groups.iter().map(Vec::len).sum();
+    info!(
+        "found {} groups of indistinguishable transcripts, holding {} transcripts (at most {} in a group).",
+        groups.len().to_formatted_string(&Locale::en),
+        num_grouped.to_formatted_string(&Locale::en),
+        groups.iter().map(Vec::len).max().unwrap_or(0)
+    );
+    IndistinguishableGroups { groups }
+}
+
+impl IndistinguishableGroups {
+    /// The name of group `i`.
+    fn group_name(i: usize) -> String {
+        format!("group_{}", i + 1)
+    }
+
+    /// Write the groups to `<output>.groups.tsv`, one line per group, with its members
+    /// (by their names `txps_name`), the InfRV of its summed replicates `breps` and the
+    /// mean InfRV of its members.
+    pub fn write(
+        &self,
+        output: &Path,
+        txps_name: &[String],
+        breps: &[Vec<f64>],
+        compression: OutputCompression,
+    ) -> anyhow::Result<()> {
+        let out_path = output
+            .to_path_buf()
+            .with_additional_extension(".groups.tsv");
+        let mut writer = CompressedWriter::create(&out_path, compression)?;
+        provenance::write_tsv_comment(&mut writer)?;
+        writeln!(writer, "group\tnum_txps\ttxps\tinfrv\tmean_txp_infrv")?;
+        let mut reps = vec![0.0_f64; breps.len()];
+        for (i, g) in self.groups.iter().enumerate() {
+            let mut txp_infrv = 0.0;
+            reps.fill(0.0);
+            for t in g.iter() {
+                let txp_reps: Vec<f64> = breps.iter().map(|b| b[*t as usize]).collect();
+                txp_infrv += infrv(&txp_reps);
+                reps.iter_mut()
+                    .zip(txp_reps.iter())
+                    .for_each(|(x, y)| *x += y);
+            }
+            let names: Vec<&str> = g.iter().map(|t| txps_name[*t as usize].as_str()).collect();
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                Self::group_name(i),
+                g.len(),
+                names.join(","),
+                infrv(&reps),
+                txp_infrv / g.len() as f64
+            )?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Write the abundance of each group (and of each transcript in no group) to
+    /// `<output>.group_quant.tsv`, with the mean, standard deviation and InfRV of its
+    /// summed bootstrap replicates `breps`, and the replicates themselves to
+    /// `<output>.groups.infreps.<ext>`.
+    pub fn write_quant(
+        &self,
+        output: &Path,
+        txps_name: &[String],
+        counts: &[f64],
+        breps: &[Vec<f64>],
+        format: OutputFormat,
+        compression: Option<OutputCompression>,
+    ) -> anyhow::Result<()> {
+        // the features reported: each group, then each ungrouped transcript
+        let singletons: Vec<u32> = (0..txps_name.len() as u32).collect();
+        let mut grouped = vec![false; txps_name.len()];
+        let mut features: Vec<(String, &[u32])> = Vec::with_capacity(txps_name.len());
+        for (i, g) in self.groups.iter().enumerate() {
+            g.iter().for_each(|t| grouped[*t as usize] = true);
+            features.push((Self::group_name(i), g.as_slice()));
+        }
+        for (t, name) in txps_name.iter().enumerate() {
+            if !grouped[t] {
+                features.push((name.clone(), &singletons[t..t + 1]));
+            }
+        }
+
+        let group_reps: Vec<Vec<f64>> = breps
+            .iter()
+            .map(|b| {
+                features
+                    .iter()
+                    .map(|(_, m)| m.iter().map(|t| b[*t as usize]).sum())
+                    .collect()
+            })
+            .collect();
+
+        let out_path = output
+            .to_path_buf()
+            .with_additional_extension(".group_quant.tsv");
+        let mut writer =
+            CompressedWriter::create(&out_path, compression.unwrap_or(OutputCompression::None))?;
+        provenance::write_tsv_comment(&mut writer)?;
+        writeln!(
+            writer,
+            "name\tnum_txps\tnum_reads\tbootstrap_mean\tbootstrap_sd\tinfrv"
+        )?;
+        let mut reps = vec![0.0_f64; breps.len()];
+        for (j, (name, m)) in features.iter().enumerate() {
+            for (r, g) in reps.iter_mut().zip(group_reps.iter()) {
+                *r = g[j];
+            }
+            let (mean, sd) = mean_sd(&reps);
+            let num_reads: f64 = m.iter().map(|t| counts[*t as usize]).sum();
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                name,
+                m.len(),
+                num_reads,
+                mean,
+                sd,
+                infrv(&reps)
+            )?;
+        }
+        writer.finish()?;
+
+        let mut arrays = vec![];
+        let mut fields = vec![];
+        for (i, b) in group_reps.into_iter().enumerate() {
+            let array = Float64Array::from_vec(b);
+            fields.push(Field::new(
+                format!("bootstrap.{}", i),
+                array.data_type().clone(),
+                false,
+            ));
+            arrays.push(array.boxed());
+        }
+        write_infrep_file(
+            &output.to_path_buf().with_additional_extension(".groups"),
+            fields,
+            Chunk::new(arrays),
+            format,
+            compression,
+        )
+    }
+}
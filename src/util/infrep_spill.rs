@@ -0,0 +1,68 @@
+use crate::util::object_store_io::StagedFile;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+
+/// The bootstrap replicates of a run, written to a temporary file as each is computed,
+/// so that they need not all be held in memory until the last one is done. Each replicate
+/// is stored (as little-endian `f64`s) at the position given by its index, whatever the
+/// order in which the replicates complete, and the file is removed once this is dropped.
+pub struct ReplicateSpill {
+    file: Mutex<File>,
+    // declared after `file`, so that the file is closed before it is removed
+    _staged: StagedFile,
+    num_rows: usize,
+    num_reps: usize,
+}
+
+impl ReplicateSpill {
+    /// A spill file for `num_reps` replicates of `num_rows` values each, in the temporary
+    /// directory.
+    pub fn new(num_rows: usize, num_reps: usize) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("oarfish-{}-infreps.bin", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            _staged: StagedFile::new(path),
+            num_rows,
+            num_reps,
+        })
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.num_rows
+    }
+
+    pub fn num_reps(&self) -> usize {
+        self.num_reps
+    }
+
+    /// Write the values of replicate `rep`.
+    pub fn write_replicate(&self, rep: usize, values: &[f64]) -> io::Result<()> {
+        assert_eq!(values.len(), self.num_rows, "replicate of the wrong length");
+        let mut buf = Vec::with_capacity(values.len() * 8);
+        for v in values {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        let mut file = self.file.lock().expect("spill file lock poisoned");
+        file.seek(SeekFrom::Start((rep * self.num_rows * 8) as u64))?;
+        file.write_all(&buf)
+    }
+
+    /// Read the values of rows `start..start + len` of replicate `rep`.
+    pub fn read_rows(&self, rep: usize, start: usize, len: usize) -> io::Result<Vec<f64>> {
+        let mut buf = vec![0_u8; len * 8];
+        let mut file = self.file.lock().expect("spill file lock poisoned");
+        file.seek(SeekFrom::Start(((rep * self.num_rows + start) * 8) as u64))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().expect("8 bytes")))
+            .collect())
+    }
+}
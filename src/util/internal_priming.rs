@@ -0,0 +1,246 @@
+use crate::prog_opts::InternalPrimingMode;
+use crate::util::errors::reference_mismatch;
+use crate::util::oarfish_types::AlnInfo;
+use anyhow::Context;
+use needletail::parse_fastx_file;
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// The number of bases by which the 3' end of an alignment may fall short of, or run
+/// past, the start of an A-rich window for the alignment to be flagged (the aligner may
+/// stop a few bases early, or extend a few bases into the A-rich stretch by chance).
+const END_SLACK: u32 = 5;
+
+/// The positions of each transcript that are followed by an A-rich window, as sorted,
+/// disjoint, half-open ranges of the start positions of these windows.
+#[derive(Debug, Default)]
+struct ARichSites {
+    ranges: Vec<Vec<(u32, u32)>>,
+}
+
+impl ARichSites {
+    /// The start positions of the windows of `window` bases of `seq` holding at least
+    /// `min_a` A's; windows that would run past the end of `seq` are not considered.
+    fn find(seq: &[u8], window: usize, min_a: usize) -> Vec<(u32, u32)> {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        if seq.len() < window {
+            return ranges;
+        }
+        let is_a = |b: u8| usize::from(b.eq_ignore_ascii_case(&b'A'));
+        let mut num_a: usize = seq[..window].iter().map(|b| is_a(*b)).sum();
+        for p in 0..=(seq.len() - window) {
+            if p > 0 {
+                num_a = num_a + is_a(seq[p + window - 1]) - is_a(seq[p - 1]);
+            }
+            if num_a >= min_a {
+                let p = p as u32;
+                match ranges.last_mut() {
+                    Some(last) if last.1 == p => last.1 = p + 1,
+                    _ => ranges.push((p, p + 1)),
+                }
+            }
+        }
+        ranges
+    }
+
+    /// true if an A-rich window of transcript `tid` starts within [`END_SLACK`] bases
+    /// of `end`.
+    fn is_flagged(&self, tid: usize, end: u32) -> bool {
+        let Some(ranges) = self.ranges.get(tid) else {
+            return false;
+        };
+        let lo = end.saturating_sub(END_SLACK);
+        let hi = end + END_SLACK;
+        let i = ranges.partition_point(|r| r.1 <= lo);
+        ranges.get(i).is_some_and(|r| r.0 <= hi)
+    }
+}
+
+/// The outcome of the detection of internal-priming artifacts, over all reads.
+#[derive(Debug, Clone, Serialize)]
+pub struct InternalPrimingSummary {
+    /// what was done with the flagged alignments
+    pub mode: InternalPrimingMode,
+    /// the number of alignments inspected
+    pub num_alignments: u64,
+    /// the number of alignments flagged as likely internal-priming artifacts
+    pub num_flagged_alignments: u64,
+    /// the number of reads with at least one flagged alignment
+    pub num_flagged_reads: u64,
+    /// the number of reads all of whose alignments were flagged (which are excluded from
+    /// quantification with `--internal-priming exclude`)
+    pub num_all_flagged_reads: u64,
+}
+
+/// Flags the alignments whose 3' ends lie just upstream of an A-rich stretch of their
+/// transcript, where the oligo(dT) primer of the reverse transcription may have primed
+/// within the transcript rather than at its poly(A) tail. Depending on the mode, the
+/// flagged alignments are only counted, down-weighted or excluded.
+#[derive(Debug, Clone)]
+pub struct InternalPriming {
+    sites: Arc<ARichSites>,
+    mode: InternalPrimingMode,
+    weight: f32,
+    /// the number of alignments, and of flagged alignments, to each transcript
+    txp_counts: Vec<(u32, u32)>,
+    summary: InternalPrimingSummary,
+}
+
+impl InternalPriming {
+    /// Find the A-rich windows (of `window` bases, at least `min_a_frac` of which are A's)
+    /// of the transcripts `txps_name`, whose sequences are read from the FASTA file
+    /// `reference`.
+    pub fn load(
+        reference: &Path,
+        txps_name: &[String],
+        window: usize,
+        min_a_frac: f32,
+        mode: InternalPrimingMode,
+        weight: f32,
+    ) -> anyhow::Result<Self> {
+        let tid_of: FxHashMap<&str, usize> = txps_name
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.as_str(), i))
+            .collect();
+        let min_a = ((min_a_frac * window as f32).ceil() as usize).max(1);
+        let mut ranges = vec![Vec::new(); txps_name.len()];
+        let mut num_found = 0_usize;
+        let mut reader = parse_fastx_file(reference).with_context(|| {
+            format!(
+                "could not read the transcript sequences in {}",
+                reference.display()
+            )
+        })?;
+        while let Some(rec) = reader.next() {
+            let rec = rec?;
+            // the name of the sequence is the first word of the header line
+            let id = rec.id();
+            let name = id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id);
+            let Some(&tid) = tid_of.get(String::from_utf8_lossy(name).as_ref()) else {
+                continue;
+            };
+            ranges[tid] = ARichSites::find(&rec.seq(), window, min_a);
+            num_found += 1;
+        }
+        if num_found == 0 {
+            return Err(reference_mismatch(format!(
+                "none of the sequences in {} are transcripts being quantified, so internal \
+                 priming cannot be detected",
+                reference.display()
+            )));
+        }
+        if num_found < txps_name.len() {
+            warn!(
+                "{} of the {} transcripts have no sequence in {}; their alignments will not \
+                 be flagged as internal-priming artifacts.",
+                txps_name.len() - num_found,
+                txps_name.len(),
+                reference.display()
+            );
+        }
+        let num_sites: usize = ranges.iter().flatten().map(|r| (r.1 - r.0) as usize).sum();
+        info!(
+            "found {} positions followed by an A-rich window ({} of {} bases) in {} transcripts.",
+            num_sites, min_a, window, num_found
+        );
+        Ok(Self {
+            sites: Arc::new(ARichSites { ranges }),
+            mode,
+            weight,
+            txp_counts: vec![(0, 0); txps_name.len()],
+            summary: InternalPrimingSummary {
+                mode,
+                num_alignments: 0,
+                num_flagged_alignments: 0,
+                num_flagged_reads: 0,
+                num_all_flagged_reads: 0,
+            },
+        })
+    }
+
+    /// Flag the alignments `alns` (with alignment score probabilities `as_probs`) of a
+    /// read, returning its alignments (and probabilities) once the flagged ones have been
+    /// down-weighted or excluded, as requested. The 3' end of an alignment is its end on
+    /// the transcript, whichever the strand of the read.
+    pub fn apply<'a>(
+        &mut self,
+        alns: &'a [AlnInfo],
+        as_probs: &'a [f32],
+    ) -> (Cow<'a, [AlnInfo]>, Cow<'a, [f32]>) {
+        if alns.is_empty() {
+            return (Cow::Borrowed(alns), Cow::Borrowed(as_probs));
+        }
+        let flagged: Vec<bool> = alns
+            .iter()
+            .map(|a| self.sites.is_flagged(a.ref_id as usize, a.end))
+            .collect();
+        let num_flagged = flagged.iter().filter(|f| **f).count();
+        for (a, f) in alns.iter().zip(flagged.iter()) {
+            let counts = &mut self.txp_counts[a.ref_id as usize];
+            counts.0 += 1;
+            counts.1 += u32::from(*f);
+        }
+        self.summary.num_alignments += alns.len() as u64;
+        self.summary.num_flagged_alignments += num_flagged as u64;
+        if num_flagged == 0 {
+            return (Cow::Borrowed(alns), Cow::Borrowed(as_probs));
+        }
+        self.summary.num_flagged_reads += 1;
+        if num_flagged == alns.len() {
+            self.summary.num_all_flagged_reads += 1;
+        }
+        match self.mode {
+            InternalPrimingMode::Flag => (Cow::Borrowed(alns), Cow::Borrowed(as_probs)),
+            InternalPrimingMode::Downweight => {
+                let probs = as_probs
+                    .iter()
+                    .zip(flagged.iter())
+                    .map(|(p, f)| if *f { p * self.weight } else { *p })
+                    .collect();
+                (Cow::Borrowed(alns), Cow::Owned(probs))
+            }
+            InternalPrimingMode::Exclude => {
+                let (kept_alns, kept_probs): (Vec<AlnInfo>, Vec<f32>) = alns
+                    .iter()
+                    .zip(as_probs.iter())
+                    .zip(flagged.iter())
+                    .filter(|(_, f)| !**f)
+                    .map(|((a, p), _)| (a.clone(), *p))
+                    .unzip();
+                (Cow::Owned(kept_alns), Cow::Owned(kept_probs))
+            }
+        }
+    }
+
+    /// The number of alignments, and of flagged alignments, to each transcript.
+    pub fn txp_counts(&self) -> &[(u32, u32)] {
+        &self.txp_counts
+    }
+
+    pub fn summary(&self) -> &InternalPrimingSummary {
+        &self.summary
+    }
+
+    /// Log the number of reads and alignments that were flagged.
+    pub fn log(&self) {
+        let s = &self.summary;
+        info!(
+            "{} of {} alignments ({} reads, {} of them with no other alignment) were flagged \
+             as likely internal-priming artifacts{}.",
+            s.num_flagged_alignments,
+            s.num_alignments,
+            s.num_flagged_reads,
+            s.num_all_flagged_reads,
+            match s.mode {
+                InternalPrimingMode::Flag => "",
+                InternalPrimingMode::Downweight => " and down-weighted",
+                InternalPrimingMode::Exclude => " and excluded",
+            }
+        );
+    }
+}
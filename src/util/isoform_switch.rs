@@ -0,0 +1,215 @@
+use crate::util::compressed_writer::open_maybe_compressed;
+use crate::util::parquet_utils::read_f64_columns;
+use crate::util::provenance;
+use crate::util::read_function::read_txp_genes;
+use anyhow::Context;
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use rustc_hash::FxHashMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The estimates of one sample, as read back from its output.
+struct SampleEstimates {
+    txps_name: Vec<String>,
+    counts: Vec<f64>,
+    /// `bootstraps[b][t]` is the estimate of transcript `t` in replicate `b`
+    bootstraps: Vec<Vec<f64>>,
+}
+
+/// Read the estimates (`<prefix>.quant`) and bootstrap replicates
+/// (`<prefix>.infreps.pq` or `<prefix>.infreps.arrow`) written with the output prefix `prefix`.
+fn read_sample_estimates(prefix: &PathBuf) -> anyhow::Result<SampleEstimates> {
+    let quant_path = prefix.with_additional_extension(".quant");
+    let reader = open_maybe_compressed(&quant_path)
+        .with_context(|| format!("could not open {}", quant_path.display()))?;
+    let mut txps_name = Vec::new();
+    let mut counts = Vec::new();
+    // skip the provenance comment (with `--tsv-provenance`) and the header
+    let lines = reader
+        .lines()
+        .filter(|l| !l.as_ref().is_ok_and(|l| l.starts_with('#')));
+    for line in lines.skip(1) {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        let (Some(name), Some(count)) = (fields.first(), fields.get(2)) else {
+            anyhow::bail!("malformed line {:?} in {}", line, quant_path.display());
+        };
+        txps_name.push((*name).to_owned());
+        counts.push(count.parse::<f64>()?);
+    }
+    // the replicates are written as an Arrow IPC file with `--output-format arrow`
+    let mut infreps_path = prefix.with_additional_extension(".infreps.pq");
+    let arrow_path = prefix.with_additional_extension(".infreps.arrow");
+    if !infreps_path.exists() && arrow_path.exists() {
+        infreps_path = arrow_path;
+    }
+    let bootstraps = read_f64_columns(&infreps_path)?;
+    Ok(SampleEstimates {
+        txps_name,
+        counts,
+        bootstraps,
+    })
+}
+
+/// The index (within `txps`) of the transcript of `txps` with the largest estimate in
+/// `counts`, or [None] if none of them is expressed.
+fn dominant(txps: &[usize], counts: &[f64]) -> Option<usize> {
+    let (i, c) = txps
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| counts[**a].total_cmp(&counts[**b]))?;
+    (counts[*c] > 0.0).then_some(i)
+}
+
+/// A gene whose dominant isoform differs between the case and the control sample.
+struct IsoformSwitch {
+    gene: String,
+    case_dominant: usize,
+    control_dominant: usize,
+    case_gene_reads: f64,
+    control_gene_reads: f64,
+    /// the fraction of the gene's reads assigned to the dominant isoform of the case,
+    /// in the case and in the control
+    case_frac: f64,
+    control_frac: f64,
+    /// the fraction of the paired bootstrap replicates in which the same switch is observed
+    support: f64,
+}
+
+/// Screen the genes (given by the transcript-to-gene file `txp_to_gene`) for isoform
+/// switches between the case sample (with output prefix `case`) and the control sample
+/// (with output prefix `control`), both quantified against the same transcripts and with
+/// bootstrap replicates. A gene is reported if it has at least two transcripts and at
+/// least `min_reads` reads in each sample, and its dominant (most abundant) isoform
+/// differs between the samples. The support of a switch is the fraction of the paired
+/// bootstrap replicates in which the same pair of isoforms is dominant; switches with a
+/// support of at least `min_support` are flagged as confident. The switches are written
+/// to `<output>.isoform_switches.tsv`.
+pub fn screen_isoform_switches(
+    case: &PathBuf,
+    control: &PathBuf,
+    txp_to_gene: &Path,
+    min_reads: f64,
+    min_support: f64,
+    output: &PathBuf,
+) -> anyhow::Result<()> {
+    let case_est = read_sample_estimates(case)?;
+    let control_est = read_sample_estimates(control)?;
+    anyhow::ensure!(
+        case_est.txps_name == control_est.txps_name,
+        "the case and control samples were not quantified against the same transcripts"
+    );
+    let num_reps = case_est.bootstraps.len().min(control_est.bootstraps.len());
+    if num_reps == 0 {
+        warn!("no bootstrap replicates are available; the support of each switch is unknown.");
+    }
+
+    // group the transcripts by gene, keeping the genes in order of first appearance
+    let genes = read_txp_genes(txp_to_gene, &case_est.txps_name)?;
+    let mut gene_ids: FxHashMap<&str, usize> = FxHashMap::default();
+    let mut gene_txps: Vec<(&str, Vec<usize>)> = Vec::new();
+    for (t, g) in genes.iter().enumerate() {
+        if let Some(g) = g {
+            let gid = *gene_ids.entry(g.as_str()).or_insert_with(|| {
+                gene_txps.push((g.as_str(), Vec::new()));
+                gene_txps.len() - 1
+            });
+            gene_txps[gid].1.push(t);
+        }
+    }
+
+    let mut switches = Vec::new();
+    let mut num_screened = 0_usize;
+    for (gene, txps) in gene_txps.iter().filter(|(_, txps)| txps.len() > 1) {
+        let case_reads: f64 = txps.iter().map(|t| case_est.counts[*t]).sum();
+        let control_reads: f64 = txps.iter().map(|t| control_est.counts[*t]).sum();
+        if case_reads < min_reads || control_reads < min_reads {
+            continue;
+        }
+        num_screened += 1;
+        let (Some(case_dom), Some(control_dom)) = (
+            dominant(txps, &case_est.counts),
+            dominant(txps, &control_est.counts),
+        ) else {
+            continue;
+        };
+        if case_dom == control_dom {
+            continue;
+        }
+
+        let num_supporting = (0..num_reps)
+            .filter(|b| {
+                dominant(txps, &case_est.bootstraps[*b]) == Some(case_dom)
+                    && dominant(txps, &control_est.bootstraps[*b]) == Some(control_dom)
+            })
+            .count();
+        let t = txps[case_dom];
+        switches.push(IsoformSwitch {
+            gene: gene.to_string(),
+            case_dominant: t,
+            control_dominant: txps[control_dom],
+            case_gene_reads: case_reads,
+            control_gene_reads: control_reads,
+            case_frac: case_est.counts[t] / case_reads,
+            control_frac: control_est.counts[t] / control_reads,
+            support: if num_reps > 0 {
+                num_supporting as f64 / num_reps as f64
+            } else {
+                f64::NAN
+            },
+        });
+    }
+    switches.sort_by(|a, b| {
+        b.support.total_cmp(&a.support).then_with(|| {
+            (b.case_frac - b.control_frac)
+                .abs()
+                .total_cmp(&(a.case_frac - a.control_frac).abs())
+        })
+    });
+
+    let out_path = output.with_additional_extension(".isoform_switches.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&out_path)
+        .with_context(|| format!("could not create {}", out_path.display()))?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+    writeln!(
+        writer,
+        "gene\tcase_dominant\tcontrol_dominant\tcase_gene_reads\tcontrol_gene_reads\tcase_frac\tcontrol_frac\tdelta_frac\tsupport\tconfident"
+    )?;
+    let txps_name = &case_est.txps_name;
+    let mut num_confident = 0_usize;
+    for s in switches.iter() {
+        let confident = s.support >= min_support;
+        num_confident += confident as usize;
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            s.gene,
+            txps_name[s.case_dominant],
+            txps_name[s.control_dominant],
+            s.case_gene_reads,
+            s.control_gene_reads,
+            s.case_frac,
+            s.control_frac,
+            s.case_frac - s.control_frac,
+            s.support,
+            confident
+        )?;
+    }
+
+    info!(
+        "isoform switch screen : {} of {} screened genes switch their dominant isoform ({} with a bootstrap support of at least {}).",
+        switches.len().to_formatted_string(&Locale::en),
+        num_screened.to_formatted_string(&Locale::en),
+        num_confident.to_formatted_string(&Locale::en),
+        min_support
+    );
+    Ok(())
+}
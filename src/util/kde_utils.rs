@@ -1,17 +1,230 @@
+use crate::prog_opts::{Args, KdeBandwidth};
 use crate::util::oarfish_types::{InMemoryAlignmentStore, TranscriptInfo};
+use anyhow::Context;
 use itertools::izip;
-use kders::kde::{GridDimensions, KDEModel};
+use kders::kde::{GridDimensions, KDEGrid, KDEModel};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::ops::Index;
+use std::path::{Path, PathBuf};
 use tracing::info;
 
+/// The zstd compression level of a saved KDE model.
+const MODEL_COMPRESSION_LEVEL: i32 = 3;
+
+/// A kernel density estimate (fit by [kders], with a Gaussian kernel) of the joint
+/// distribution of the lengths of the transcripts and of the alignments to them, held
+/// as the density of each bin of its grid so that it can be saved and reused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KdeModel {
+    /// the largest transcript length covered by the grid
+    pub width: usize,
+    /// the largest aligned length covered by the grid
+    pub height: usize,
+    pub bin_width: usize,
+    /// the bandwidth (in bases) of the kernel
+    pub bandwidth: f64,
+    /// the density of each bin, in row-major order (by transcript length)
+    density: Vec<f64>,
+}
+
+/// A summary of a KDE model, recorded in `meta_info.json`.
+#[derive(Debug, Serialize)]
+pub struct KdeSummary {
+    pub bandwidth: f64,
+    pub bin_width: usize,
+    pub width: usize,
+    pub height: usize,
+    /// the file from which the model was loaded, or [None] if it was fit to this sample
+    pub source: Option<PathBuf>,
+}
+
+impl KdeModel {
+    fn num_rows(&self) -> usize {
+        self.width / self.bin_width + 1
+    }
+
+    fn num_cols(&self) -> usize {
+        self.height / self.bin_width + 1
+    }
+
+    /// Copy the density of each bin of the grid (of `bin_width` bases, covering lengths
+    /// up to `width` and `height`) from the model `kde`, fit with `bandwidth`.
+    fn from_kders(
+        kde: &KDEModel,
+        width: usize,
+        height: usize,
+        bin_width: usize,
+        bandwidth: f64,
+    ) -> Self {
+        let mut model = Self {
+            width,
+            height,
+            bin_width,
+            bandwidth,
+            density: Vec::new(),
+        };
+        // each bin is represented by its center (or the last length within the grid)
+        let center = |i: usize, max: usize| (i * bin_width + bin_width / 2).min(max);
+        model.density = (0..model.num_rows())
+            .flat_map(|r| (0..model.num_cols()).map(move |c| (r, c)))
+            .map(|(r, c)| kde[(center(r, width), center(c, height))])
+            .collect();
+        model
+    }
+
+    /// Write the model, as zstd-compressed JSON, to `path`.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("could not create KDE model file {}", path.display()))?;
+        let mut encoder =
+            zstd::stream::write::Encoder::new(BufWriter::new(file), MODEL_COMPRESSION_LEVEL)?;
+        serde_json::to_writer(&mut encoder, self)?;
+        encoder.finish()?;
+        info!("wrote the KDE model to {}.", path.display());
+        Ok(())
+    }
+
+    /// Read a model written by [KdeModel::save] from `path`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("could not open KDE model file {}", path.display()))?;
+        let decoder = zstd::stream::read::Decoder::new(BufReader::new(file))?;
+        let model: Self = serde_json::from_reader(decoder)
+            .with_context(|| format!("could not parse KDE model file {}", path.display()))?;
+        anyhow::ensure!(
+            model.bin_width > 0 && model.density.len() == model.num_rows() * model.num_cols(),
+            "the KDE model in {} is malformed",
+            path.display()
+        );
+        info!(
+            "read a KDE model (bandwidth = {:.1}) from {}.",
+            model.bandwidth,
+            path.display()
+        );
+        Ok(model)
+    }
+
+    pub fn summary(&self, source: Option<&Path>) -> KdeSummary {
+        KdeSummary {
+            bandwidth: self.bandwidth,
+            bin_width: self.bin_width,
+            width: self.width,
+            height: self.height,
+            source: source.map(Path::to_path_buf),
+        }
+    }
+}
+
+/// The density of the bin containing (transcript length, aligned length); lengths
+/// beyond the extent of the grid (e.g. of a model fit to another sample) fall in its
+/// last row or column.
+impl Index<(usize, usize)> for KdeModel {
+    type Output = f64;
+
+    fn index(&self, (x, y): (usize, usize)) -> &f64 {
+        let r = (x / self.bin_width).min(self.num_rows() - 1);
+        let c = (y / self.bin_width).min(self.num_cols() - 1);
+        &self.density[r * self.num_cols() + c]
+    }
+}
+
+/// Silverman's rule-of-thumb bandwidth (in bases) for the marginal histogram `hist`
+/// of `n` observations, in bins of width `bin_width`; for a product kernel in two
+/// dimensions this is `min(sd, IQR / 1.349) * n^(-1/6)`.
+fn silverman_bandwidth(hist: &[f64], n: f64, bin_width: usize) -> f64 {
+    let bw = bin_width as f64;
+    let center = |i: usize| (i as f64 + 0.5) * bw;
+    let mean = hist
+        .iter()
+        .enumerate()
+        .map(|(i, w)| center(i) * w)
+        .sum::<f64>()
+        / n;
+    let var = hist
+        .iter()
+        .enumerate()
+        .map(|(i, w)| (center(i) - mean).powi(2) * w)
+        .sum::<f64>()
+        / n;
+    let quantile = |q: f64| {
+        let mut acc = 0.0;
+        hist.iter()
+            .position(|w| {
+                acc += w;
+                acc >= q * n
+            })
+            .map_or(0.0, center)
+    };
+    let iqr = quantile(0.75) - quantile(0.25);
+    let sigma = if iqr > 0.0 {
+        var.sqrt().min(iqr / 1.349)
+    } else {
+        var.sqrt()
+    };
+    (sigma * n.powf(-1.0 / 6.0)).max(bw)
+}
+
+/// The (transcript length, aligned length) of each alignment in `store`, with the
+/// weight `1 / #alignments` of its read.
+fn observations<'a>(
+    txps: &'a [TranscriptInfo],
+    store: &'a InMemoryAlignmentStore,
+) -> impl Iterator<Item = (usize, usize, f64)> + 'a {
+    store.iter().flat_map(move |(ainfs, _aprobs, _cprobs)| {
+        let w = 1. / (ainfs.len() as f64);
+        ainfs
+            .iter()
+            .map(|ainf| {
+                (
+                    txps[ainf.ref_id as usize].lenf as usize,
+                    ainf.alignment_span() as usize,
+                    w,
+                )
+            })
+            .collect::<Vec<_>>()
+    })
+}
+
+/// The bandwidth (in bases) with which the observations in `store` are smoothed, as
+/// chosen by `bandwidth`. As [kders] smooths both lengths with the same bandwidth,
+/// Silverman's rule gives the geometric mean of its bandwidths for the two lengths.
+fn choose_bandwidth(
+    txps: &[TranscriptInfo],
+    store: &InMemoryAlignmentStore,
+    (width, height): (usize, usize),
+    bin_width: usize,
+    bandwidth: KdeBandwidth,
+) -> f64 {
+    match bandwidth {
+        KdeBandwidth::Fixed(h) => h,
+        KdeBandwidth::Silverman => {
+            let mut row_hist = vec![0.0_f64; width / bin_width + 1];
+            let mut col_hist = vec![0.0_f64; height / bin_width + 1];
+            let mut total = 0.0_f64;
+            for (x, y, w) in observations(txps, store) {
+                row_hist[x / bin_width] += w;
+                col_hist[y / bin_width] += w;
+                total += w;
+            }
+            let hx = silverman_bandwidth(&row_hist, total, bin_width);
+            let hy = silverman_bandwidth(&col_hist, total, bin_width);
+            (hx * hy).sqrt()
+        }
+    }
+}
+
 pub fn get_kde_model(
     txps: &[TranscriptInfo],
     store: &InMemoryAlignmentStore,
-) -> anyhow::Result<KDEModel> {
+    args: &Args,
+) -> anyhow::Result<KdeModel> {
     let mut max_x: f64 = 0_f64;
     let mut max_y: f64 = 0_f64;
 
     for (ainfs, _aprobs, _cprobs) in store.iter() {
-        for ainf in ainfs {
+        for ainf in ainfs.iter() {
             let txp_len = txps[ainf.ref_id as usize].lenf;
             let aln_len = ainf.alignment_span() as f64;
             max_x = max_x.max(txp_len);
@@ -26,46 +239,55 @@ pub fn get_kde_model(
 
     info!("KDE grid maxima = ({}, {})", gd.width, gd.height);
 
-    let kernel_bandwidth = 50_f64;
-    let bin_width = 25_usize;
-
-    let mut grid = kders::kde::KDEGrid::new(gd, bin_width, Some(kernel_bandwidth));
+    anyhow::ensure!(
+        store.len() > 0,
+        "there are no alignments from which to fit the KDE model"
+    );
+    let bin_width = args.kde_bin_width as usize;
+    let (width, height) = (gd.width - 1, gd.height - 1);
+    let kernel_bandwidth =
+        choose_bandwidth(txps, store, (width, height), bin_width, args.kde_bandwidth);
+    info!(
+        "fitting the KDE model with bandwidth = {:.1}.",
+        kernel_bandwidth
+    );
 
-    for (ainfs, _aprobs, _cprobs) in store.iter() {
-        let w = 1. / (ainfs.len() as f64);
-        for ainf in ainfs {
-            let txp_len = txps[ainf.ref_id as usize].lenf;
-            let aln_len = ainf.alignment_span();
-            grid.add_observation(txp_len as usize, aln_len as usize, w);
-        }
+    let mut grid = KDEGrid::new(gd, bin_width, Some(kernel_bandwidth));
+    for (x, y, w) in observations(txps, store) {
+        grid.add_observation(x, y, w);
     }
 
     let density = grid.get_kde()?;
-    Ok(density)
+    Ok(KdeModel::from_kders(
+        &density,
+        width,
+        height,
+        bin_width,
+        kernel_bandwidth,
+    ))
 }
 
 #[allow(unused)]
 pub fn refresh_kde_model(
     txps: &[TranscriptInfo],
     store: &InMemoryAlignmentStore,
-    kde_model: &KDEModel,
+    kde_model: &KdeModel,
     counts: &[f64],
-) -> anyhow::Result<KDEModel> {
+) -> anyhow::Result<KdeModel> {
+    info!(
+        "KDE grid maxima = ({}, {})",
+        kde_model.width, kde_model.height
+    );
+
     let gd = GridDimensions {
-        width: kde_model.width,
-        height: kde_model.height,
+        width: kde_model.width + 1,
+        height: kde_model.height + 1,
     };
-
-    info!("KDE grid maxima = ({}, {})", gd.width, gd.height);
-
-    let kernel_bandwidth = 50_f64;
-    let bin_width = 25_usize;
-
-    let mut grid = kders::kde::KDEGrid::new(gd, bin_width, Some(kernel_bandwidth));
+    let mut grid = KDEGrid::new(gd, kde_model.bin_width, Some(kde_model.bandwidth));
 
     for (ainfs, aprobs, cprobs) in store.iter() {
         let mut denom = 0.0_f64;
-        for (a, p, _cp) in izip!(ainfs, aprobs, cprobs) {
+        for (a, p, _cp) in izip!(ainfs.iter(), aprobs.iter(), cprobs.iter()) {
             // Compute the probability of assignment of the
             // current read based on this alignment and the
             // target's estimated abundance.
@@ -83,7 +305,7 @@ pub fn refresh_kde_model(
             // Loop over all possible assignment locations and proportionally
             // allocate the read according to our model and current parameter
             // estimates.
-            for (a, p, _cp) in izip!(ainfs, aprobs, cprobs) {
+            for (a, p, _cp) in izip!(ainfs.iter(), aprobs.iter(), cprobs.iter()) {
                 let target_id = a.ref_id as usize;
                 let prob = *p as f64;
                 let cov_prob = 1.0; //if model_coverage { *cp } else { 1.0 };
@@ -91,10 +313,92 @@ pub fn refresh_kde_model(
                 let aln_len = a.alignment_span();
                 let flprob = kde_model[(txp_len as usize, aln_len as usize)];
                 let w = (counts[target_id] * prob * cov_prob * flprob) / denom;
-                grid.add_observation(txp_len as usize, aln_len as usize, w);
+                // lengths beyond the grid (of a model fit to another sample) are
+                // counted at its edge
+                grid.add_observation(
+                    (txp_len as usize).min(kde_model.width),
+                    (aln_len as usize).min(kde_model.height),
+                    w,
+                );
             }
         }
     }
     info!("filled grid; computing KDE");
-    grid.get_kde()
+    let density = grid.get_kde()?;
+    Ok(KdeModel::from_kders(
+        &density,
+        kde_model.width,
+        kde_model.height,
+        kde_model.bin_width,
+        kde_model.bandwidth,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::kde_utils::{KdeModel, silverman_bandwidth};
+    use kders::kde::{GridDimensions, KDEGrid};
+
+    /// A model fit by kders with the default bandwidth (50) and bin width (25), along
+    /// with the same model wrapped as a [KdeModel].
+    fn fit_default() -> (kders::kde::KDEModel, KdeModel) {
+        let (width, height) = (2_000, 1_500);
+        let gd = GridDimensions {
+            width: width + 1,
+            height: height + 1,
+        };
+        let mut grid = KDEGrid::new(gd, 25, Some(50.0));
+        for (x, y, w) in [(1_000, 900, 1.0), (1_200, 1_100, 0.5), (300, 250, 0.5)] {
+            grid.add_observation(x, y, w);
+        }
+        let kde = grid.get_kde().expect("the KDE should be computed");
+        let model = KdeModel::from_kders(&kde, width, height, 25, 50.0);
+        (kde, model)
+    }
+
+    #[test]
+    fn default_model_reproduces_kders() {
+        let (kde, model) = fit_default();
+        for x in (12..2_000).step_by(25) {
+            for y in (12..1_500).step_by(25) {
+                assert_eq!(model[(x, y)], kde[(x, y)]);
+            }
+        }
+    }
+
+    #[test]
+    fn lengths_beyond_the_grid_take_the_edge_density() {
+        let (_, model) = fit_default();
+        assert_eq!(model[(10_000, 700)], model[(2_000, 700)]);
+        assert_eq!(model[(700, 10_000)], model[(700, 1_500)]);
+        assert_eq!(model[(10_000, 10_000)], model[(2_000, 1_500)]);
+    }
+
+    #[test]
+    fn saved_model_is_reloaded_unchanged() {
+        let (_, model) = fit_default();
+        let path =
+            std::env::temp_dir().join(format!("oarfish_kde_{}.json.zst", std::process::id()));
+        model.save(&path).expect("the model should be saved");
+        let loaded = KdeModel::load(&path).expect("the model should be loaded");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.width, model.width);
+        assert_eq!(loaded.height, model.height);
+        assert_eq!(loaded.bin_width, model.bin_width);
+        assert_eq!(loaded.bandwidth, model.bandwidth);
+        assert_eq!(loaded.density, model.density);
+    }
+
+    #[test]
+    fn silverman_bandwidth_is_at_least_a_bin() {
+        // all of the observations in one bin
+        assert_eq!(silverman_bandwidth(&[0.0, 10.0, 0.0], 10.0, 25), 25.0);
+        // two equal masses 80 bases apart: sd = 40 (the IQR is also 80, so the sd is the
+        // smaller spread), scaled by n^(-1/6)
+        let mut hist = vec![0.0; 9];
+        hist[0] = 32.0;
+        hist[8] = 32.0;
+        let h = silverman_bandwidth(&hist, 64.0, 10);
+        assert!((h - 40.0 * 64_f64.powf(-1.0 / 6.0)).abs() < 1e-9);
+    }
 }
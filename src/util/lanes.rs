@@ -0,0 +1,143 @@
+use crate::util::oarfish_types::EMInfo;
+use crate::util::read_length_strata::{quantify_read_subset, tv_distance};
+use serde::Serialize;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+/// A lane whose total variation distance from the pooled estimate exceeds
+/// this multiple of the median distance (across lanes) is flagged as discordant.
+const DISCORDANT_TV_FACTOR: f64 = 3.0;
+/// A lane whose alignment rate is below this fraction of the median
+/// alignment rate (across lanes) is flagged as discordant.
+const DISCORDANT_ALIGN_RATE_FRAC: f64 = 0.5;
+
+/// Assignment and QC statistics for a single lane of the sample.
+#[derive(Debug, Serialize)]
+pub struct LaneSummary {
+    /// the input file holding the reads of this lane
+    pub path: PathBuf,
+    /// the number of reads in the lane
+    pub num_reads: usize,
+    /// the number of reads in the lane having a valid alignment
+    pub num_aligned: usize,
+    /// the fraction of the reads of the lane having a valid alignment
+    pub aligned_rate: f64,
+    /// total variation distance between the relative abundances
+    /// estimated from this lane and from all lanes.
+    pub tv_distance: f64,
+    /// true if this lane appears inconsistent with the other lanes
+    pub discordant: bool,
+}
+
+/// The per-lane statistics and estimates of a sample; `counts[l][t]` is
+/// the estimated number of reads in lane `l` arising from transcript `t`.
+#[derive(Debug, Serialize)]
+pub struct LaneResult {
+    pub summaries: Vec<LaneSummary>,
+    /// 1 minus the mean pairwise total variation distance between the
+    /// relative abundances estimated from each pair of lanes; 1 indicates
+    /// perfectly concordant lanes.
+    pub concordance: f64,
+    #[serde(skip)]
+    pub counts: Vec<Vec<f64>>,
+}
+
+fn median(vals: &[f64]) -> f64 {
+    let mut v = vals.to_vec();
+    v.sort_by(|a, b| a.total_cmp(b));
+    match v.len() {
+        0 => 0.0,
+        n if n % 2 == 1 => v[n / 2],
+        n => 0.5 * (v[n / 2 - 1] + v[n / 2]),
+    }
+}
+
+/// Quantify each lane of the sample independently (using the lane of each read
+/// recorded in the alignment store of `emi`) and compare the per-lane estimates
+/// to each other, and to the joint estimate `counts`. The number of input reads
+/// of each lane is given in `lane_reads`, and the corresponding input files in
+/// `lane_paths`.
+pub fn summarize_lanes(
+    emi: &EMInfo,
+    lane_reads: &[usize],
+    lane_paths: &[PathBuf],
+    counts: &[f64],
+) -> LaneResult {
+    let num_lanes = lane_reads.len();
+    let mut lane_inds: Vec<Vec<usize>> = vec![Vec::new(); num_lanes];
+    for (i, lane) in emi.eq_map.read_lanes.iter().enumerate() {
+        lane_inds[*lane as usize].push(i);
+    }
+
+    let lane_counts: Vec<Vec<f64>> = lane_inds
+        .iter()
+        .map(|inds| quantify_read_subset(emi, inds, counts.len()))
+        .collect();
+
+    let mut summaries: Vec<LaneSummary> = lane_inds
+        .iter()
+        .zip(lane_counts.iter())
+        .zip(lane_reads.iter().zip(lane_paths.iter()))
+        .map(|((inds, lcounts), (nreads, path))| LaneSummary {
+            path: path.clone(),
+            num_reads: *nreads,
+            num_aligned: inds.len(),
+            aligned_rate: if *nreads > 0 {
+                inds.len() as f64 / *nreads as f64
+            } else {
+                0.0
+            },
+            tv_distance: tv_distance(lcounts, counts),
+            discordant: false,
+        })
+        .collect();
+
+    let mut pair_dist = 0.0_f64;
+    let mut num_pairs = 0_usize;
+    for i in 0..num_lanes {
+        for j in (i + 1)..num_lanes {
+            pair_dist += tv_distance(&lane_counts[i], &lane_counts[j]);
+            num_pairs += 1;
+        }
+    }
+    let concordance = if num_pairs > 0 {
+        1.0 - pair_dist / num_pairs as f64
+    } else {
+        1.0
+    };
+
+    // flag lanes that are outliers with respect to the other lanes
+    if num_lanes > 2 {
+        let med_tv = median(&summaries.iter().map(|x| x.tv_distance).collect::<Vec<_>>());
+        let med_rate = median(&summaries.iter().map(|x| x.aligned_rate).collect::<Vec<_>>());
+        for s in summaries.iter_mut() {
+            s.discordant = (med_tv > 0.0 && s.tv_distance > DISCORDANT_TV_FACTOR * med_tv)
+                || s.aligned_rate < DISCORDANT_ALIGN_RATE_FRAC * med_rate;
+        }
+    }
+
+    for (l, s) in summaries.iter().enumerate() {
+        info!(
+            "lane {} ({}) : {} reads, {:.2}% aligned, total variation distance from pooled estimate = {:.4}",
+            l,
+            s.path.display(),
+            s.num_reads,
+            100.0 * s.aligned_rate,
+            s.tv_distance
+        );
+        if s.discordant {
+            warn!(
+                "lane {} ({}) is discordant with the other lanes of the sample and may have failed.",
+                l,
+                s.path.display()
+            );
+        }
+    }
+    info!("lane concordance = {:.4}", concordance);
+
+    LaneResult {
+        summaries,
+        concordance,
+        counts: lane_counts,
+    }
+}
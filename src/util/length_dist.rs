@@ -0,0 +1,256 @@
+use crate::util::oarfish_types::InMemoryAlignmentStore;
+use crate::util::provenance;
+use anyhow::bail;
+use path_tools::WithAdditionalExtension;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// The empirical distributions of the lengths of the aligned reads, and of the
+/// lengths of their alignments, as (length, weight) histograms.
+#[derive(Debug, Default)]
+pub struct LengthDistribution {
+    /// the number of reads of each length (reads of unknown length are left out)
+    pub read_lengths: BTreeMap<u32, f64>,
+    /// the number of alignments spanning each length of the reference, where each
+    /// of the alignments of a read carries weight `1 / #alignments`
+    pub aligned_lengths: BTreeMap<u32, f64>,
+}
+
+/// Summary statistics of a length histogram.
+#[derive(Debug, Serialize)]
+pub struct LengthStats {
+    pub mean: f64,
+    pub median: u32,
+    pub min: u32,
+    pub max: u32,
+}
+
+/// A summary of the length distributions, and of the distribution used to compute
+/// the effective lengths of the transcripts.
+#[derive(Debug, Serialize)]
+pub struct LengthDistSummary {
+    pub num_reads: f64,
+    pub read_length: Option<LengthStats>,
+    pub aligned_length: Option<LengthStats>,
+    /// the precomputed distribution used for the effective lengths, or [None] if the
+    /// empirical aligned-length distribution was used
+    pub eff_len_dist: Option<PathBuf>,
+}
+
+fn length_stats(hist: &BTreeMap<u32, f64>) -> Option<LengthStats> {
+    let total: f64 = hist.values().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mean = hist.iter().map(|(l, w)| *l as f64 * w).sum::<f64>() / total;
+    let mut acc = 0.0;
+    let median = hist
+        .iter()
+        .find(|(_, w)| {
+            acc += *w;
+            acc >= total / 2.0
+        })
+        .map(|(l, _)| *l)?;
+    Some(LengthStats {
+        mean,
+        median,
+        min: *hist.keys().next()?,
+        max: *hist.keys().next_back()?,
+    })
+}
+
+impl LengthDistribution {
+    /// Collect the length distributions of the reads parsed into `store`.
+    pub fn from_store(store: &InMemoryAlignmentStore) -> Self {
+        let mut dist = Self::default();
+        for ((alns, _probs, _coverage_probs), read_len) in
+            store.iter().zip(store.read_lengths.iter())
+        {
+            if alns.is_empty() {
+                continue;
+            }
+            if *read_len > 0 {
+                *dist.read_lengths.entry(*read_len).or_insert(0.0) += 1.0;
+            }
+            let w = 1.0 / alns.len() as f64;
+            for a in alns.iter() {
+                *dist
+                    .aligned_lengths
+                    .entry(a.alignment_span())
+                    .or_insert(0.0) += w;
+            }
+        }
+        dist
+    }
+
+    pub fn summary(&self, eff_len_dist: Option<&Path>) -> LengthDistSummary {
+        LengthDistSummary {
+            num_reads: self.read_lengths.values().sum(),
+            read_length: length_stats(&self.read_lengths),
+            aligned_length: length_stats(&self.aligned_lengths),
+            eff_len_dist: eff_len_dist.map(Path::to_path_buf),
+        }
+    }
+}
+
+/// Read a precomputed length distribution from the tab-separated file at `path`, with
+/// lines of the form `<length>\t<weight>`. A header line is allowed; if it names an
+/// `aligned_count` column (as in the `length_dist.tsv` written by oarfish), the weights
+/// are taken from that column. Empty lines and lines starting with `#` are ignored.
+pub fn read_length_dist(path: &Path) -> anyhow::Result<BTreeMap<u32, f64>> {
+    let file = File::open(path)?;
+    let mut hist = BTreeMap::new();
+    let mut weight_col = 1;
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let parsed = match (fields.first(), fields.get(weight_col)) {
+            (Some(l), Some(w)) => l
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .zip(w.trim().parse::<f64>().ok()),
+            _ => None,
+        };
+        match parsed {
+            Some((l, w)) if w >= 0.0 => {
+                *hist.entry(l).or_insert(0.0) += w;
+            }
+            _ if lnum == 0 => {
+                // a header line
+                if let Some(c) = fields.iter().position(|f| f.trim() == "aligned_count") {
+                    weight_col = c;
+                }
+            }
+            _ => bail!(
+                "line {} of length distribution file {} was not of the form <length>\\t<weight>",
+                lnum + 1,
+                path.display()
+            ),
+        }
+    }
+    if hist.values().sum::<f64>() <= 0.0 {
+        bail!(
+            "the length distribution file {} had no lengths of positive weight",
+            path.display()
+        );
+    }
+    info!(
+        "read a length distribution of {} distinct lengths from {}.",
+        hist.len(),
+        path.display()
+    );
+    Ok(hist)
+}
+
+/// The effective length of each transcript of length `lens[t]`, given the distribution
+/// `hist` of the lengths of the alignments; that is, the expected number of positions at
+/// which an alignment, with a length drawn from `hist` conditioned on fitting within the
+/// transcript, can start. Transcripts shorter than every length in `hist` keep their
+/// length, and effective lengths are at least 1.
+pub fn effective_lengths(hist: &BTreeMap<u32, f64>, lens: &[usize]) -> Vec<f64> {
+    // cumulative weights and first moments of the lengths, in increasing order of length
+    let mut cum = Vec::with_capacity(hist.len());
+    let (mut w_acc, mut lw_acc) = (0.0_f64, 0.0_f64);
+    for (l, w) in hist.iter() {
+        w_acc += w;
+        lw_acc += *l as f64 * w;
+        cum.push((*l, w_acc, lw_acc));
+    }
+    lens.iter()
+        .map(|len| {
+            let n = cum.partition_point(|(l, _, _)| (*l as usize) <= *len);
+            let lenf = *len as f64;
+            if n == 0 {
+                return lenf;
+            }
+            let (_, w, lw) = cum[n - 1];
+            if w <= 0.0 {
+                return lenf;
+            }
+            (lenf - lw / w + 1.0).max(1.0)
+        })
+        .collect()
+}
+
+/// Write the length distributions in `dist` to `<output>.length_dist.tsv`.
+pub fn write_length_dist(output: &PathBuf, dist: &LengthDistribution) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".length_dist.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    writeln!(writer, "length\tread_count\taligned_count")?;
+    let lengths: std::collections::BTreeSet<u32> = dist
+        .read_lengths
+        .keys()
+        .chain(dist.aligned_lengths.keys())
+        .copied()
+        .collect();
+    for l in lengths {
+        writeln!(
+            writer,
+            "{}\t{}\t{}",
+            l,
+            dist.read_lengths.get(&l).copied().unwrap_or(0.0),
+            dist.aligned_lengths.get(&l).copied().unwrap_or(0.0)
+        )?;
+    }
+    writer.flush()
+}
+
+/// Write the effective length `eff_lens[t]` of each transcript in `header`, and the TPM
+/// computed from its estimated count `counts[t]` and effective length, to
+/// `<output>.eff_lens.tsv`.
+pub fn write_effective_lengths(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    counts: &[f64],
+    eff_lens: &[f64],
+) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".eff_lens.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    let rates: Vec<f64> = counts
+        .iter()
+        .zip(eff_lens.iter())
+        .map(|(c, l)| c / l)
+        .collect();
+    let rate_sum: f64 = rates.iter().sum();
+
+    writeln!(writer, "tname\tlen\teff_len\tTPM")?;
+    for (i, (rseq, rmap)) in header.reference_sequences().iter().enumerate() {
+        let tpm = if rate_sum > 0.0 {
+            1e6 * rates[i] / rate_sum
+        } else {
+            0.0
+        };
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            rseq,
+            rmap.length(),
+            eff_lens[i],
+            tpm
+        )?;
+    }
+    writer.flush()
+}
@@ -0,0 +1,139 @@
+use crate::prog_opts::{Args, LogFormat};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::info;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{EnvFilter, reload};
+
+/// The target of the structured events logged with `--log-format json`; it lies outside
+/// of the `oarfish` targets, so that the events are not silenced along with the
+/// subsystems quieted in single-cell mode.
+pub const EVENT_TARGET: &str = "oarfish_events";
+
+/// The names (the `event` field) of the structured events; these are stable across
+/// versions, so that workflow managers can rely on them.
+pub const RUN_START: &str = "run_start";
+pub const RUN_END: &str = "run_end";
+pub const STAGE_END: &str = "stage_end";
+pub const FILTER_COUNTS: &str = "filter_counts";
+pub const EM_FINISHED: &str = "em_finished";
+
+/// Whether the log messages are written as JSON.
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+
+/// Set the format of the log messages.
+pub fn set_format(format: LogFormat) {
+    JSON_FORMAT.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// Whether the log messages are written as JSON (and so the structured events logged).
+pub fn is_json() -> bool {
+    JSON_FORMAT.load(Ordering::Relaxed)
+}
+
+/// Log the start of the run writing to `output`.
+pub fn run_start(output: &std::path::Path) {
+    if is_json() {
+        info!(
+            target: EVENT_TARGET,
+            event = RUN_START,
+            version = env!("CARGO_PKG_VERSION"),
+            output = %output.display()
+        );
+    }
+}
+
+/// Log the successful end of the run, after `wall_secs` seconds.
+pub fn run_end(wall_secs: f64) {
+    if is_json() {
+        info!(target: EVENT_TARGET, event = RUN_END, wall_secs);
+    }
+}
+
+/// Log the end of the stage `stage`, which took `elapsed_secs` seconds; the next stage
+/// starts when this one ends.
+pub fn stage_end(stage: &str, elapsed_secs: f64) {
+    if is_json() {
+        info!(target: EVENT_TARGET, event = STAGE_END, stage, elapsed_secs);
+    }
+}
+
+/// Log the number of alignments and reads discarded by each filter, as a JSON object.
+pub fn filter_counts<T: Serialize>(counts: &T) {
+    if is_json() {
+        let counts = serde_json::to_string(counts).unwrap_or_default();
+        info!(target: EVENT_TARGET, event = FILTER_COUNTS, counts = %counts);
+    }
+}
+
+/// Log the end of an EM run after `iterations` rounds, with the final maximum relative
+/// difference `rel_diff` between rounds.
+pub fn em_finished(iterations: u32, rel_diff: f64, converged: bool) {
+    if is_json() {
+        info!(
+            target: EVENT_TARGET,
+            event = EM_FINISHED,
+            iterations,
+            rel_diff,
+            converged
+        );
+    }
+}
+
+/// The filter used before the arguments have been parsed; the level is taken from
+/// the `RUST_LOG` environment variable if it is set, and is INFO otherwise.
+pub fn default_filter() -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy()
+}
+
+/// The filter of the log messages for the run described by `args`, in single-cell
+/// mode if `single_cell` is true. The base level is set by `--quiet` or `--verbose`
+/// (or else the `RUST_LOG` environment variable), and single-cell mode quiets the
+/// subsystems (e.g. the EM) that run once per cell. The directives passed with `--log`
+/// are added last, and so override those of the same targets.
+fn filter_for(args: &Args, single_cell: bool) -> EnvFilter {
+    let (base, defaults): (EnvFilter, &[&str]) = match (args.quiet, args.verbose, single_cell) {
+        (true, _, false) => (EnvFilter::new("WARN"), &[]),
+        (_, true, false) => (EnvFilter::new("TRACE"), &[]),
+        (false, false, false) => (default_filter(), &[]),
+        (true, _, true) => (
+            EnvFilter::new("WARN"),
+            &["oarfish=warn", "oarfish::single_cell=warn"],
+        ),
+        (_, true, true) => (
+            EnvFilter::new("TRACE"),
+            &["oarfish=info", "oarfish::single_cell=trace"],
+        ),
+        // be quiet about normal things in single-cell mode e.g. EM iterations, and only
+        // print out info for oarfish::single_cell events.
+        (false, false, true) => (
+            EnvFilter::new("INFO"),
+            &["oarfish=warn", "oarfish::single_cell=info"],
+        ),
+    };
+    defaults
+        .iter()
+        .copied()
+        .chain(args.log.iter().map(String::as_str))
+        .fold(base, |filter, d| {
+            filter.add_directive(d.parse().expect("directives are validated when parsed"))
+        })
+}
+
+/// Replace the filter of the log messages behind `handle` by that for the run
+/// described by `args` (see [filter_for]).
+pub fn set_filter<S>(
+    handle: &reload::Handle<EnvFilter, S>,
+    args: &Args,
+    single_cell: bool,
+) -> anyhow::Result<()> {
+    if !single_cell && !args.quiet && !args.verbose && args.log.is_empty() {
+        // keep the filter taken from the environment
+        return Ok(());
+    }
+    let filter = filter_for(args, single_cell);
+    handle.modify(|f| *f = filter)?;
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::path::Path;
+
+/// The version of the loom specification followed by the written files.
+#[cfg(feature = "loom")]
+const LOOM_SPEC_VERSION: &str = "3.0.0";
+/// The number of features (rows of the loom matrix) densified and written at a time.
+#[cfg(feature = "loom")]
+const ROWS_PER_BLOCK: usize = 256;
+/// The (maximum) extent of the HDF5 chunks of each matrix, in each dimension.
+#[cfg(feature = "loom")]
+const CHUNK_DIM: usize = 64;
+
+/// Write the single-cell counts with the output prefix `output` (i.e. `<output>.count.mtx`,
+/// `<output>.barcodes.txt` and `<output>.features.txt`) to the loom file `<output>.loom`,
+/// whose main matrix holds the counts of each feature (row) in each cell (column), with
+/// the `Gene` row attribute and the `CellID` column attribute. For USA-mode counts, the
+/// main matrix (and the `spliced` layer) holds the spliced counts, and the `unspliced` and
+/// `ambiguous` layers hold the others, as expected by velocyto-style workflows.
+#[cfg(feature = "loom")]
+pub fn write_loom(output: &Path, usa_mode: bool) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use hdf5::types::VarLenUnicode;
+    use ndarray::Array2;
+    use path_tools::WithAdditionalExtension;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+    use tracing::{info, warn};
+
+    let read_lines = |ext: &str| -> anyhow::Result<Vec<VarLenUnicode>> {
+        let path = output.with_additional_extension(ext);
+        let file =
+            File::open(&path).with_context(|| format!("could not open {}", path.display()))?;
+        BufReader::new(file)
+            .lines()
+            .map(|l| {
+                l?.parse::<VarLenUnicode>()
+                    .map_err(|e| anyhow::anyhow!("invalid line in {}: {}", path.display(), e))
+            })
+            .collect()
+    };
+    let barcodes = read_lines(".barcodes.txt")?;
+    let features = read_lines(".features.txt")?;
+
+    let mtx_path = output.with_additional_extension(".count.mtx");
+    let counts: sprs::TriMatI<f32, u32> = sprs::io::read_matrix_market(&mtx_path)
+        .with_context(|| format!("could not read the count matrix {}", mtx_path.display()))?;
+    let num_cells = barcodes.len();
+    let num_feats = features.len();
+    let num_layers = if usa_mode { 3 } else { 1 };
+    anyhow::ensure!(
+        counts.rows() == num_cells && counts.cols() == num_layers * num_feats,
+        "the count matrix {} is {} x {}, but there are {} barcodes and {} columns expected",
+        mtx_path.display(),
+        counts.rows(),
+        counts.cols(),
+        num_cells,
+        num_layers * num_feats
+    );
+    let loom_path = output.with_additional_extension(".loom");
+    if num_cells == 0 || num_feats == 0 {
+        warn!(
+            "there are no cells or no features; not writing {}",
+            loom_path.display()
+        );
+        return Ok(());
+    }
+    // the columns of the (cell x feature) count matrix are the rows of the loom matrix
+    let by_feature: sprs::CsMatI<f32, u32> = counts.to_csc();
+
+    let file = hdf5::File::create(&loom_path)
+        .with_context(|| format!("could not create {}", loom_path.display()))?;
+    let chunk = (CHUNK_DIM.min(num_feats), CHUNK_DIM.min(num_cells));
+    let new_matrix = |group: &hdf5::Group, name: &str| -> hdf5::Result<hdf5::Dataset> {
+        group
+            .new_dataset::<f32>()
+            .shape((num_feats, num_cells))
+            .chunk(chunk)
+            .deflate(4)
+            .create(name)
+    };
+    let write_layer = |ds: &hdf5::Dataset, layer: usize| -> anyhow::Result<()> {
+        for start in (0..num_feats).step_by(ROWS_PER_BLOCK) {
+            let end = (start + ROWS_PER_BLOCK).min(num_feats);
+            let mut block = Array2::<f32>::zeros((end - start, num_cells));
+            for f in start..end {
+                if let Some(col) = by_feature.outer_view(layer * num_feats + f) {
+                    for (cell, v) in col.iter() {
+                        block[[f - start, cell]] = *v;
+                    }
+                }
+            }
+            ds.write_slice(&block, (start..end, ..))?;
+        }
+        Ok(())
+    };
+
+    let matrix = new_matrix(&file, "matrix")?;
+    write_layer(&matrix, 0)?;
+    let layers = file.create_group("layers")?;
+    if usa_mode {
+        for (layer, name) in ["spliced", "unspliced", "ambiguous"].iter().enumerate() {
+            let ds = new_matrix(&layers, name)?;
+            write_layer(&ds, layer)?;
+        }
+    }
+
+    let row_attrs = file.create_group("row_attrs")?;
+    row_attrs
+        .new_dataset_builder()
+        .with_data(features.as_slice())
+        .create("Gene")?;
+    let col_attrs = file.create_group("col_attrs")?;
+    col_attrs
+        .new_dataset_builder()
+        .with_data(barcodes.as_slice())
+        .create("CellID")?;
+    file.create_group("row_graphs")?;
+    file.create_group("col_graphs")?;
+
+    let version: VarLenUnicode = LOOM_SPEC_VERSION
+        .parse()
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    let attrs = file.create_group("attrs")?;
+    attrs
+        .new_dataset::<VarLenUnicode>()
+        .create("LOOM_SPEC_VERSION")?
+        .write_scalar(&version)?;
+    file.new_attr::<VarLenUnicode>()
+        .create("LOOM_SPEC_VERSION")?
+        .write_scalar(&version)?;
+
+    info!(
+        "wrote the counts of {} cells to {}.",
+        num_cells,
+        loom_path.display()
+    );
+    Ok(())
+}
+
+/// Without the `loom` feature, loom output is not available.
+#[cfg(not(feature = "loom"))]
+pub fn write_loom(_output: &Path, _usa_mode: bool) -> anyhow::Result<()> {
+    anyhow::bail!(LOOM_UNAVAILABLE)
+}
+
+/// The error reported when loom output is requested from a build without it.
+pub const LOOM_UNAVAILABLE: &str = "this build of oarfish does not support loom output; rebuild it with `--features loom` (which requires the HDF5 library)";
@@ -0,0 +1,223 @@
+use crate::util::errors;
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::{info, warn};
+
+/// The number of reads added to a store between successive checks of the memory
+/// in use against `--max-mem`.
+pub const MEM_CHECK_INTERVAL: usize = 1 << 16;
+
+/// The bytes currently allocated through the [TrackingAllocator].
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// The most bytes allocated at any one time through the [TrackingAllocator].
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// The soft limit on the memory in use (`--max-mem`), or 0 if there is none.
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// A global allocator that forwards to the [System] allocator, while keeping count
+/// of the bytes currently allocated, so that the alignment stores can degrade
+/// gracefully (see [MemoryGuard]) as the soft limit set by `--max-mem` is approached,
+/// rather than the process being killed for exceeding the memory of a shared node.
+/// It is registered by the `oarfish` binary; when it is not (e.g. when oarfish is used
+/// as a library), the memory in use is taken to be the resident memory of the process
+/// instead (see [memory_in_use]).
+pub struct TrackingAllocator;
+
+impl TrackingAllocator {
+    #[inline]
+    fn add(size: usize) {
+        let now = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK_ALLOCATED.fetch_max(now, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn sub(size: usize) {
+        ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+// SAFETY: all allocation is delegated to the system allocator; only the counters are
+// updated here.
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            Self::add(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            Self::add(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        Self::sub(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            Self::sub(layout.size());
+            Self::add(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// The bytes currently allocated (0 if the [TrackingAllocator] is not in use).
+#[inline]
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+/// true if allocations are being counted (i.e. the [TrackingAllocator] is the
+/// global allocator).
+pub fn is_tracking() -> bool {
+    PEAK_ALLOCATED.load(Ordering::Relaxed) > 0
+}
+
+/// The resident set size of the process, in bytes, as given by `/proc/self/statm`, or
+/// [None] where it is not available (i.e. other than on Linux).
+pub fn resident_bytes() -> Option<usize> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: `sysconf` has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (page_size > 0).then(|| pages * page_size as usize)
+}
+
+/// The memory in use, in bytes: the bytes allocated if the [TrackingAllocator] is in
+/// use, and otherwise the resident memory of the process, if it can be determined.
+pub fn memory_in_use() -> Option<usize> {
+    if is_tracking() {
+        Some(allocated())
+    } else {
+        resident_bytes()
+    }
+}
+
+/// Set the soft limit on the memory in use (`None` for no limit). This fails if a
+/// limit is given but the memory in use can't be measured (i.e. allocations are not
+/// being tracked, and the resident memory isn't available on this platform), rather
+/// than the limit silently having no effect.
+pub fn set_limit(limit: Option<u64>) -> anyhow::Result<()> {
+    if limit.is_some() && memory_in_use().is_none() {
+        LIMIT.store(0, Ordering::Relaxed);
+        return Err(errors::bad_input(
+            "--max-mem was given, but the memory in use can't be measured on this platform (allocations are not tracked when oarfish is used as a library, and the resident memory is only available on Linux)",
+        ));
+    }
+    LIMIT.store(limit.unwrap_or(0) as usize, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The soft limit on the memory in use, if there is one.
+pub fn limit() -> Option<usize> {
+    match LIMIT.load(Ordering::Relaxed) {
+        0 => None,
+        l => Some(l),
+    }
+}
+
+/// true if a limit is set, and the memory in use exceeds it.
+#[inline]
+pub fn over_limit() -> bool {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    limit > 0 && memory_in_use().is_some_and(|m| m > limit)
+}
+
+/// How an alignment store reduced its memory use once the limit set by
+/// `--max-mem` was exceeded, as recorded in the `meta_info.json` file.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryDegradation {
+    pub max_mem: usize,
+    /// the number of reads in the store when it was converted to the compact
+    /// (`--low-mem`) representation, if it was
+    pub compacted_at_read: Option<usize>,
+    /// how the degradation affects the estimates, if it does
+    pub note: Option<String>,
+    /// the number of blocks of compact alignments moved to disk
+    pub num_blocks_spilled: usize,
+    /// the number of bytes of compact alignments moved to disk
+    pub bytes_spilled: usize,
+    /// the error that prevented (further) spilling to disk, if any
+    pub spill_error: Option<String>,
+}
+
+/// Monitors the memory in use while an alignment store is filled, and decides how
+/// the store should reduce its footprint once `--max-mem` is exceeded: first by
+/// converting to the compact representation, and then by moving the filled blocks of
+/// that representation to (memory-mapped) files in the temporary directory.
+#[derive(Debug, Clone)]
+pub struct MemoryGuard {
+    pub spill_dir: PathBuf,
+    pub degradation: MemoryDegradation,
+}
+
+impl MemoryGuard {
+    /// A guard for the limit set with [set_limit], if there is one.
+    pub fn new() -> Option<Self> {
+        limit().map(|max_mem| Self {
+            spill_dir: std::env::temp_dir(),
+            degradation: MemoryDegradation {
+                max_mem,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// true if memory should be released (the store is checked only every
+    /// [MEM_CHECK_INTERVAL] reads, and not at all once spilling has failed).
+    #[inline]
+    pub fn should_check(&self, num_reads: usize) -> bool {
+        num_reads % MEM_CHECK_INTERVAL == 0
+            && self.degradation.spill_error.is_none()
+            && over_limit()
+    }
+
+    pub fn record_compaction(&mut self, num_reads: usize) {
+        warn!(
+            "the memory in use ({} bytes) exceeds --max-mem ({} bytes) after {} reads; switching to the compact (--low-mem) representation of the alignments, so the estimates can differ slightly from those of a run without --max-mem.",
+            memory_in_use().unwrap_or(0),
+            self.degradation.max_mem,
+            num_reads
+        );
+        self.degradation.compacted_at_read = Some(num_reads);
+        self.degradation.note = Some(
+            "the alignment and coverage probabilities were quantized to 16 bits when the alignments were compacted, so the estimates can differ slightly from those of a run without --max-mem".to_string(),
+        );
+    }
+
+    pub fn record_spill(&mut self, res: std::io::Result<(usize, usize)>) {
+        match res {
+            Ok((0, _)) => {}
+            Ok((nblocks, nbytes)) => {
+                self.degradation.num_blocks_spilled += nblocks;
+                self.degradation.bytes_spilled += nbytes;
+                info!(
+                    "the memory in use ({} bytes) exceeds --max-mem; moved {} blocks ({} bytes) of alignments to {}.",
+                    memory_in_use().unwrap_or(0),
+                    nblocks,
+                    nbytes,
+                    self.spill_dir.display()
+                );
+            }
+            Err(e) => {
+                warn!(
+                    "could not move alignments to {} to stay within --max-mem: {}; continuing in memory.",
+                    self.spill_dir.display(),
+                    e
+                );
+                self.degradation.spill_error = Some(e.to_string());
+            }
+        }
+    }
+}
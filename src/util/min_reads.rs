@@ -0,0 +1,92 @@
+use crate::em;
+use crate::util::oarfish_types::EMInfo;
+use num_format::{Locale, ToFormattedString};
+use serde::Serialize;
+use tracing::info;
+
+/// The maximum number of iterations of the EM that redistributes the reads of the
+/// transcripts zeroed by `--min-reads-per-txp`; it starts from the converged
+/// estimates, so it needs far fewer iterations than the full EM.
+const REDISTRIBUTION_MAX_ITER: u32 = 100;
+
+/// How the reads of the transcripts estimated to have fewer than `min_reads` reads were
+/// redistributed.
+#[derive(Debug, Serialize)]
+pub struct MinReadsSummary {
+    pub min_reads: f64,
+    /// the number of transcripts with a (nonzero) estimate below `min_reads`, set to 0
+    pub num_txps_zeroed: usize,
+    /// the number of reads assigned to those transcripts by the EM
+    pub num_reads_zeroed: f64,
+    /// the number of those reads reassigned to the remaining transcripts
+    pub num_reads_reassigned: f64,
+    /// the number of those reads that align only to zeroed transcripts, and so are no
+    /// longer assigned to any transcript
+    pub num_reads_dropped: f64,
+}
+
+/// Set the estimates of the transcripts with fewer than `min_reads` reads in `counts`
+/// to 0, and re-run a short EM on `emi`, started from the remaining estimates, to
+/// redistribute their reads over the other transcripts their reads align to. Returns the
+/// new estimates and a summary of the reads moved.
+pub fn redistribute_low_count_txps(
+    emi: &EMInfo,
+    counts: &[f64],
+    min_reads: f64,
+) -> (Vec<f64>, MinReadsSummary) {
+    let mut init = counts.to_vec();
+    let mut num_txps_zeroed = 0_usize;
+    let mut num_reads_zeroed = 0.0_f64;
+    for c in init.iter_mut().filter(|c| **c > 0.0 && **c < min_reads) {
+        num_txps_zeroed += 1;
+        num_reads_zeroed += *c;
+        *c = 0.0;
+    }
+    if num_txps_zeroed == 0 {
+        return (
+            counts.to_vec(),
+            MinReadsSummary {
+                min_reads,
+                num_txps_zeroed,
+                num_reads_zeroed,
+                num_reads_reassigned: 0.0,
+                num_reads_dropped: 0.0,
+            },
+        );
+    }
+
+    // an abundance of 0 remains 0 in every round of the EM, so the zeroed transcripts
+    // are excluded from the redistribution
+    let redist_emi = EMInfo {
+        eq_map: emi.eq_map,
+        txp_info: emi.txp_info,
+        max_iter: REDISTRIBUTION_MAX_ITER,
+        min_iter: emi.min_iter.min(REDISTRIBUTION_MAX_ITER),
+        convergence_thresh: emi.convergence_thresh,
+        init_abundances: Some(init),
+        kde_model: emi.kde_model.clone(),
+        txp_weights: emi.txp_weights.clone(),
+        trace: None,
+    };
+    let new_counts = em::do_em(&redist_emi, || redist_emi.eq_map.iter(), false);
+
+    let total_before: f64 = counts.iter().sum();
+    let total_after: f64 = new_counts.iter().sum();
+    let num_reads_dropped = (total_before - total_after).clamp(0.0, num_reads_zeroed);
+    let summary = MinReadsSummary {
+        min_reads,
+        num_txps_zeroed,
+        num_reads_zeroed,
+        num_reads_reassigned: num_reads_zeroed - num_reads_dropped,
+        num_reads_dropped,
+    };
+    info!(
+        "zeroed {} transcripts with fewer than {} reads, holding {:.1} reads: {:.1} were reassigned to other transcripts and {:.1} (aligning only to zeroed transcripts) were dropped.",
+        summary.num_txps_zeroed.to_formatted_string(&Locale::en),
+        min_reads,
+        summary.num_reads_zeroed,
+        summary.num_reads_reassigned,
+        summary.num_reads_dropped
+    );
+    (new_counts, summary)
+}
@@ -1,7 +1,19 @@
+use crate::util::errors;
 use crate::util::mm_utils;
-use minimap2_sys::{MmIdx, mm_idx_seq_t};
-use std::ffi::CStr;
+use anyhow::Context;
+use minimap2::Mapping;
+use minimap2_sys::{
+    MmIdx, mm_idx_destroy, mm_idx_reader_close, mm_idx_reader_open, mm_idx_reader_read,
+    mm_idx_seq_t, mm_idxopt_t, mm_mapopt_t, mm_mapopt_update,
+};
+use std::ffi::{CStr, CString};
+use std::path::Path;
 use std::sync::Arc;
+use tracing::{info, warn};
+
+//https://github.com/lh3/minimap2/blob/618d33515e5853c4576d5a3d126fdcda28f0e8a4/minimap.h#L12
+// #define MM_I_HPC          0x1
+const MM_I_HPC: i32 = 0x1;
 
 //https://github.com/lh3/minimap2/blob/618d33515e5853c4576d5a3d126fdcda28f0e8a4/mmpriv.h#L32
 // #define mm_seq4_get(s, i)    ((s)[(i)>>3] >> (((i)&7)<<2) & 0xf)
@@ -69,3 +81,208 @@ impl Iterator for MMIdxNameSeqIter {
 }
 
 impl ExactSizeIterator for MMIdxNameSeqIter {}
+
+/// Check that the k-mer size, window size and homopolymer compression of the
+/// pre-built index `idx` match those of the index options `idxopt` of the
+/// minimap2 preset `preset` with which the reads will be mapped. A mismatch
+/// is reported as a warning or, if `strict` is true, as an error.
+pub(crate) fn check_index_preset(
+    idx: &Arc<MmIdx>,
+    idxopt: &mm_idxopt_t,
+    preset: &str,
+    strict: bool,
+) -> anyhow::Result<()> {
+    let idx_hpc = (idx.flag & MM_I_HPC) != 0;
+    let opt_hpc = (idxopt.flag as i32 & MM_I_HPC) != 0;
+    let mut mismatches = vec![];
+    if idx.k != idxopt.k as i32 {
+        mismatches.push(format!("k = {} (expected {})", idx.k, idxopt.k));
+    }
+    if idx.w != idxopt.w as i32 {
+        mismatches.push(format!("w = {} (expected {})", idx.w, idxopt.w));
+    }
+    if idx_hpc != opt_hpc {
+        mismatches.push(format!(
+            "homopolymer compression {} (expected {})",
+            if idx_hpc { "on" } else { "off" },
+            if opt_hpc { "on" } else { "off" }
+        ));
+    }
+
+    if mismatches.is_empty() {
+        info!(
+            "the minimap2 index parameters (k = {}, w = {}) match the {} preset.",
+            idx.k, idx.w, preset
+        );
+        return Ok(());
+    }
+    let msg = format!(
+        "the minimap2 index was not built with the {} preset implied by --seq-tech: {}. Mapping with inconsistent parameters can reduce sensitivity; rebuild the index (e.g. by passing the FASTA reference with --index-out) or choose the matching --seq-tech.",
+        preset,
+        mismatches.join(", ")
+    );
+    if strict {
+        return Err(errors::bad_input(format!(
+            "{} (--strict-index-check is set)",
+            msg
+        )));
+    }
+    warn!("{}", msg);
+    Ok(())
+}
+
+/// A minimap2 aligner over an index that may consist of several parts (such as an index
+/// merged from shards with `oarfish index --merge-sketches`). Each read is mapped to every
+/// part, and the target ids of the mappings to a part are offset by the number of targets
+/// in the preceding parts.
+#[derive(Clone)]
+pub struct PartitionedAligner {
+    /// the aligner of each part, and the id of its first target
+    parts: Vec<(minimap2::Aligner<minimap2::Built>, i32)>,
+}
+
+impl From<minimap2::Aligner<minimap2::Built>> for PartitionedAligner {
+    fn from(aligner: minimap2::Aligner<minimap2::Built>) -> Self {
+        Self {
+            parts: vec![(aligner, 0)],
+        }
+    }
+}
+
+impl PartitionedAligner {
+    /// Load the parts of the minimap2 index at `index` following the first, which has
+    /// already been loaded by `first`; the aligners of these parts share its options.
+    pub fn load_parts(
+        first: minimap2::Aligner<minimap2::Built>,
+        index: &Path,
+        threads: usize,
+    ) -> anyhow::Result<Self> {
+        let path = CString::new(
+            index
+                .to_str()
+                .context("could not convert the index path to a string")?,
+        )?;
+        // SAFETY: the path and the index options are valid for the duration of the call
+        let reader = unsafe { mm_idx_reader_open(path.as_ptr(), &first.idxopt, std::ptr::null()) };
+        anyhow::ensure!(
+            !reader.is_null(),
+            "could not open the index {}",
+            index.display()
+        );
+
+        let mut offset = first.n_seq() as i32;
+        let mut parts = vec![(first.clone(), 0)];
+        // SAFETY: the reader is valid, and each part that it returns is either used
+        // below, or (the first, which was already loaded) destroyed here
+        unsafe {
+            let first_part = mm_idx_reader_read(reader, threads as i32);
+            if !first_part.is_null() {
+                mm_idx_destroy(first_part);
+            }
+            loop {
+                let part = mm_idx_reader_read(reader, threads as i32);
+                if part.is_null() {
+                    break;
+                }
+                let mut aligner = first.clone();
+                mm_mapopt_update(&mut aligner.mapopt, part);
+                let n_seq = (*part).n_seq as i32;
+                aligner.idx = Some(Arc::new(*part));
+                parts.push((aligner, offset));
+                offset += n_seq;
+            }
+            mm_idx_reader_close(reader);
+        }
+        if parts.len() > 1 {
+            info!(
+                "the index {} has {} parts; each read will be mapped to every part.",
+                index.display(),
+                parts.len()
+            );
+        }
+        Ok(Self { parts })
+    }
+
+    /// The aligner of the first part of the index.
+    pub fn first(&self) -> &minimap2::Aligner<minimap2::Built> {
+        &self.parts[0].0
+    }
+
+    pub fn num_parts(&self) -> usize {
+        self.parts.len()
+    }
+
+    /// Apply `f` to the mapping options of the aligner of every part.
+    pub fn update_mapopt<F: Fn(&mut mm_mapopt_t)>(&mut self, f: F) {
+        for (aligner, _) in self.parts.iter_mut() {
+            f(&mut aligner.mapopt);
+        }
+    }
+
+    /// A copy of this aligner with more sensitive mapping options (relaxed seeding and
+    /// chaining), used to re-align the reads that map poorly under the default options.
+    pub fn with_sensitive_mapopt(&self) -> Self {
+        let mut sensitive = self.clone();
+        sensitive.update_mapopt(|mapopt| {
+            mapopt.min_cnt = mapopt.min_cnt.min(2);
+            mapopt.min_chain_score = (mapopt.min_chain_score / 2).max(10);
+            mapopt.min_dp_max = (mapopt.min_dp_max / 2).max(10);
+            if mapopt.mid_occ > 0 {
+                mapopt.mid_occ *= 2;
+            }
+        });
+        sensitive
+    }
+
+    /// The number of targets in all parts of the index.
+    pub fn n_seq(&self) -> u32 {
+        self.parts.iter().map(|(a, _)| a.n_seq()).sum()
+    }
+
+    /// The target with id `i` (counting the targets of all parts).
+    pub fn get_seq(&self, i: usize) -> Option<&mm_idx_seq_t> {
+        let (aligner, offset) = self
+            .parts
+            .iter()
+            .rev()
+            .find(|(_, offset)| *offset as usize <= i)?;
+        aligner.get_seq(i - *offset as usize)
+    }
+
+    /// Map the read `seq` (named `name`), with cigar strings, to every part of the index.
+    pub fn map(&self, seq: &[u8], name: &[u8]) -> Result<Vec<Mapping>, &'static str> {
+        if self.parts.len() == 1 {
+            return self.parts[0]
+                .0
+                .map(seq, true, false, None, None, Some(name));
+        }
+        let mut mappings = Vec::new();
+        for (aligner, offset) in self.parts.iter() {
+            let mut part_mappings = aligner.map(seq, true, false, None, None, Some(name))?;
+            for m in part_mappings.iter_mut() {
+                m.target_id += offset;
+            }
+            mappings.append(&mut part_mappings);
+        }
+        // each part reports its own primary mapping, of which only the best remains primary
+        let is_primary = |m: &Mapping| m.is_primary && !m.is_supplementary;
+        let score = |m: &Mapping| {
+            m.alignment
+                .as_ref()
+                .and_then(|a| a.alignment_score)
+                .unwrap_or(0)
+        };
+        let best = mappings
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| is_primary(m))
+            .max_by_key(|(_, m)| score(m))
+            .map(|(i, _)| i);
+        for (i, m) in mappings.iter_mut().enumerate() {
+            if Some(i) != best && is_primary(m) {
+                m.is_primary = false;
+            }
+        }
+        Ok(mappings)
+    }
+}
@@ -2,17 +2,13 @@ use crate::util::oarfish_types::{InMemoryAlignmentStore, TranscriptInfo};
 use tracing::{error, info, instrument};
 
 #[instrument(skip(store, txp_info))]
-pub fn normalize_read_probs(
-    store: &mut InMemoryAlignmentStore,
-    txp_info: &[TranscriptInfo],
-    bin_width: &u32,
-) {
+pub fn normalize_read_probs(store: &mut InMemoryAlignmentStore, txp_info: &[TranscriptInfo]) {
     let mut normalize_probs_temp: Vec<f64> = vec![];
-    let mut normalized_coverage_prob: Vec<f64> = vec![];
 
     info!("normalizing read probabilities");
     //iterate over all alignments in the bam file
-    for (alns, _as_probs, _coverage_prob) in store.iter() {
+    for read_idx in 0..store.len() {
+        let (alns, _, _) = store.group(read_idx);
         let mut nprob_sum = 0.0f64;
         //iterate over the alignments of a read
         for a in alns.iter() {
@@ -21,8 +17,10 @@ pub fn normalize_read_probs(
             let end_aln: f64 = a.end as f64;
             let tlen: f64 = txp_info[target_id].len.get() as f64;
             let coverage_probability: &Vec<f64> = &txp_info[target_id].coverage_prob;
-            let bin_length: f64 = *bin_width as f64; //txp_info[target_id].len.get() as f32;
-            let start_bin: usize = (start_aln / bin_length) as usize;
+            // the bins of each transcript may have a different width
+            let bin_length: f64 = txp_info[target_id].bin_length();
+            let start_bin: usize =
+                ((start_aln / bin_length) as usize).min(coverage_probability.len() - 1);
             let end_bin: usize =
                 ((end_aln / bin_length) as usize).min(coverage_probability.len() - 1);
 
@@ -60,15 +58,15 @@ pub fn normalize_read_probs(
         }
         let sum_normalize_probs_temp: f64 = if nprob_sum > 0.0 { nprob_sum } else { 1.0 };
 
-        // extend the total normalized vector with the current normalized sums.
-        normalized_coverage_prob.extend(
-            normalize_probs_temp
-                .iter()
-                .map(|&prob| prob / sum_normalize_probs_temp),
-        );
+        // record the normalized probabilities of the alignments of this read
+        // (the alignments borrow from the store, so release them first).
+        drop(alns);
+        normalize_probs_temp
+            .iter_mut()
+            .for_each(|prob| *prob /= sum_normalize_probs_temp);
+        store.set_coverage_probs(read_idx, &normalize_probs_temp);
 
         normalize_probs_temp.clear();
     }
-    store.coverage_probabilities = normalized_coverage_prob;
     info!("done");
 }
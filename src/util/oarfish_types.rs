@@ -1,8 +1,10 @@
+use rustc_hash::FxHashMap;
 use serde::Deserialize;
+use std::borrow::Cow;
 use std::fmt;
 use std::num::NonZeroUsize;
+use std::sync::Arc;
 
-use kders::kde::KDEModel;
 use std::iter::FromIterator;
 use tabled::builder::Builder;
 use tabled::settings::Style;
@@ -15,13 +17,25 @@ use bstr::{B, ByteSlice};
 //use minimap2_temp as minimap2;
 use minimap2;
 use noodles_sam as sam;
+use sam::alignment::record::cigar::op::Kind as CigarKind;
+use sam::alignment::record::data::field::Value as AlnValue;
 use sam::{Header, alignment::record::data::field::tag::Tag as AlnTag};
 
 #[allow(unused_imports)]
 use tracing::{error, info, warn};
 
-use crate::prog_opts::ReadAssignmentProbOut;
-use crate::util::constants::EMPTY_READ_NAME;
+use crate::prog_opts::{ClipMode, IdentityType, ReadAssignmentProbOut, ScoreType};
+use crate::util::compact_store::CompactAlignments;
+use crate::util::constants::{EMPTY_READ_NAME, MIN_READ_THRESH};
+use crate::util::demux::DemuxSample;
+use crate::util::internal_priming::InternalPriming;
+use crate::util::kde_utils::KdeModel;
+use crate::util::mem_tracker::MemoryGuard;
+use crate::util::read_filter::ReadFilter;
+use crate::util::read_quality;
+use crate::util::score_calibration::ScoreCalibration;
+use crate::util::second_chance::SecondChanceStats;
+use crate::util::trimming::TrimStats;
 
 // how we can get our raw input
 pub(crate) enum InputSourceType {
@@ -30,6 +44,31 @@ pub(crate) enum InputSourceType {
     Unknown,
 }
 
+/// The per-read tags of a uBAM record that are carried along with the read through
+/// mapping (PacBio writes these for every read), along with the accuracy of the read
+/// implied by its base qualities.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ReadTags {
+    /// the predicted accuracy of the read (the `rq` tag)
+    pub rq: Option<f32>,
+    /// the number of passes of the polymerase over the molecule (the `np` tag)
+    pub np: Option<i32>,
+    /// the accuracy of the read implied by its base qualities, if it has any
+    pub base_accuracy: Option<f32>,
+}
+
+impl ReadTags {
+    pub const RQ: AlnTag = AlnTag::new(b'r', b'q');
+    pub const NP: AlnTag = AlnTag::new(b'n', b'p');
+
+    /// The predicted accuracy of the read: its `rq` tag if it has one, and otherwise
+    /// the accuracy implied by its base qualities.
+    #[inline(always)]
+    pub fn accuracy(&self) -> Option<f32> {
+        self.rq.or(self.base_accuracy)
+    }
+}
+
 // need both FASTX and UBAM to be able to act as an
 // input source
 pub(crate) trait ReadSource {
@@ -49,7 +88,13 @@ impl ReadSource for needletail::parser::SequenceRecord<'_> {
         };
 
         // put this read on the current chunk
-        rg.add_id_and_read(read_name, &self.seq());
+        let tags = ReadTags {
+            base_accuracy: self
+                .qual()
+                .and_then(|q| read_quality::accuracy_from_quals(q.iter().copied(), 33)),
+            ..ReadTags::default()
+        };
+        rg.add_id_and_read(read_name, &self.seq(), tags);
     }
 }
 
@@ -64,31 +109,56 @@ impl ReadSource for noodles_sam::alignment::RecordBuf {
             EMPTY_READ_NAME.as_bytes()
         };
 
+        let data = self.data();
+        let tags = ReadTags {
+            rq: match data.get(&ReadTags::RQ) {
+                Some(sam::alignment::record_buf::data::field::Value::Float(rq)) => Some(*rq),
+                _ => None,
+            },
+            np: data
+                .get(&ReadTags::NP)
+                .and_then(|np| np.as_int())
+                .and_then(|np| i32::try_from(np).ok()),
+            base_accuracy: read_quality::accuracy_from_quals(
+                self.quality_scores().as_ref().iter().copied(),
+                0,
+            ),
+        };
+
         // put this read on the current chunk
-        rg.add_id_and_read(read_name, self.sequence().as_ref());
+        rg.add_id_and_read(read_name, self.sequence().as_ref(), tags);
     }
 }
 
 #[derive(Clone)]
 pub(crate) struct ReadChunkWithNames {
+    /// the lane (input file) from which the reads in this chunk came
+    pub lane: u16,
+    /// the position of this chunk among the chunks of the input
+    pub index: u64,
     read_seq: Vec<u8>,
     read_names: Vec<u8>,
     seq_sep: Vec<usize>,
     name_sep: Vec<usize>,
+    read_tags: Vec<ReadTags>,
 }
 
 impl ReadChunkWithNames {
     pub fn new() -> Self {
         Self {
+            lane: 0,
+            index: 0,
             read_seq: Vec::new(),
             read_names: Vec::new(),
             seq_sep: vec![0usize],
             name_sep: vec![0usize],
+            read_tags: Vec::new(),
         }
     }
 
     #[inline(always)]
-    pub fn add_id_and_read(&mut self, id: &[u8], read: &[u8]) {
+    pub fn add_id_and_read(&mut self, id: &[u8], read: &[u8], tags: ReadTags) {
+        self.read_tags.push(tags);
         self.read_names.extend_from_slice(id);
         self.read_names.push(b'\0');
         self.read_seq.extend_from_slice(read);
@@ -96,14 +166,16 @@ impl ReadChunkWithNames {
         self.seq_sep.push(self.read_seq.len());
     }
 
+    /// Take the reads of this chunk, leaving in its place an empty chunk of the same
+    /// lane that follows it in the input.
     #[inline(always)]
-    pub fn clear(&mut self) {
-        self.read_names.clear();
-        self.read_seq.clear();
-        self.name_sep.clear();
-        self.name_sep.push(0);
-        self.seq_sep.clear();
-        self.seq_sep.push(0);
+    pub fn take(&mut self) -> Self {
+        let next = Self {
+            lane: self.lane,
+            index: self.index + 1,
+            ..Self::new()
+        };
+        std::mem::replace(self, next)
     }
 
     pub fn iter(&self) -> ReadChunkIter {
@@ -120,7 +192,7 @@ pub struct ReadChunkIter<'a> {
 }
 
 impl<'a> Iterator for ReadChunkIter<'a> {
-    type Item = (&'a [u8], &'a [u8]);
+    type Item = (&'a [u8], &'a [u8], ReadTags);
 
     #[inline(always)]
     fn next(&mut self) -> Option<Self::Item> {
@@ -130,7 +202,7 @@ impl<'a> Iterator for ReadChunkIter<'a> {
                 &self.chunk.read_names[self.chunk.name_sep[i]..self.chunk.name_sep[i + 1]];
             let seq: &[u8] = &self.chunk.read_seq[self.chunk.seq_sep[i]..self.chunk.seq_sep[i + 1]];
             self.pos += 1;
-            Some((name, seq))
+            Some((name, seq, self.chunk.read_tags[i]))
         } else {
             None
         }
@@ -158,8 +230,34 @@ pub trait AlnRecordLike {
     fn aln_start(&self) -> u32;
     fn aln_end(&self) -> u32;
     fn is_supp(&self) -> bool;
-    #[allow(dead_code)]
+    fn is_secondary(&self) -> bool;
+    fn aln_identity(&self, kind: IdentityType) -> Option<f32>;
     fn name(&self) -> Option<String>;
+    fn mapq(&self) -> Option<u8>;
+    /// The number of bases of the read left unaligned at its 5' and 3' ends (in the
+    /// orientation of the read, so that the clips of a reverse-strand alignment are
+    /// swapped relative to its CIGAR string).
+    fn read_clips(&self) -> Option<(u32, u32)>;
+    /// The alignment score recomputed from the CIGAR string and the `NM` tag (see
+    /// [score_from_cigar_ops]), for alignments lacking an `AS` tag.
+    fn recomputed_aln_score(&self) -> Option<i64>;
+
+    /// The score of the alignment of type `kind`, on an integer scale where larger is
+    /// better; the `AS` score is recomputed if the alignment does not have one.
+    fn typed_score(&self, kind: ScoreType) -> Option<i64> {
+        match kind {
+            ScoreType::As => self.aln_score().or_else(|| self.recomputed_aln_score()),
+            ScoreType::Mapq => self.mapq().map(|q| q as i64 + 1),
+            ScoreType::De => self
+                .aln_identity(IdentityType::GapCompressed)
+                .map(|i| (i as f64 * 1000.0).round() as i64),
+            ScoreType::AlnlenNormalizedAs => {
+                let score = self.aln_score().or_else(|| self.recomputed_aln_score())?;
+                let span = self.aln_span().filter(|s| *s > 0)?;
+                Some((score as f64 * 1000.0 / span as f64).round() as i64)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -195,6 +293,83 @@ impl From<u8> for CigarOp {
     }
 }
 
+impl From<CigarKind> for CigarOp {
+    fn from(k: CigarKind) -> Self {
+        match k {
+            CigarKind::Match => CigarOp::Match,
+            CigarKind::Insertion => CigarOp::Insertion,
+            CigarKind::Deletion => CigarOp::Deletion,
+            CigarKind::Skip => CigarOp::Skip,
+            CigarKind::SoftClip => CigarOp::SoftClip,
+            CigarKind::HardClip => CigarOp::HardClip,
+            CigarKind::Pad => CigarOp::Pad,
+            CigarKind::SequenceMatch => CigarOp::SequenceMatch,
+            CigarKind::SequenceMismatch => CigarOp::SequenceMismatch,
+        }
+    }
+}
+
+/// Compute the identity (of type `kind`) of an alignment from its CIGAR
+/// operations `ops` and its edit distance `nm` (the `NM` tag, which counts
+/// mismatches as well as inserted and deleted bases). Returns [None] if the
+/// alignment has no aligned columns.
+pub fn identity_from_cigar_ops<I: Iterator<Item = (CigarOp, u32)>>(
+    ops: I,
+    nm: u32,
+    kind: IdentityType,
+) -> Option<f32> {
+    let mut match_cols = 0_u64;
+    let mut gap_len = 0_u64;
+    let mut gap_opens = 0_u64;
+    for (op, len) in ops {
+        match op {
+            CigarOp::Match | CigarOp::SequenceMatch | CigarOp::SequenceMismatch => {
+                match_cols += len as u64
+            }
+            CigarOp::Insertion | CigarOp::Deletion => {
+                gap_len += len as u64;
+                gap_opens += 1;
+            }
+            _ => {}
+        }
+    }
+    let nm = nm as u64;
+    match kind {
+        IdentityType::Blast => {
+            let cols = match_cols + gap_len;
+            (cols > 0).then(|| (cols.saturating_sub(nm) as f32) / (cols as f32))
+        }
+        IdentityType::GapCompressed => {
+            let cols = match_cols + gap_opens;
+            let mismatches = nm.saturating_sub(gap_len);
+            (cols > 0).then(|| 1.0 - ((mismatches + gap_opens) as f32) / (cols as f32))
+        }
+    }
+}
+
+/// Compute the alignment score of an alignment from its CIGAR operations `ops` and its
+/// edit distance `nm`, with the default scoring of minimap2 for long reads: 2 for a
+/// match, -4 for a mismatch, and -(4 + 2 * len) for a gap of length `len`.
+pub fn score_from_cigar_ops<I: Iterator<Item = (CigarOp, u32)>>(ops: I, nm: u32) -> i64 {
+    let mut match_cols = 0_i64;
+    let mut gap_len = 0_i64;
+    let mut gap_penalty = 0_i64;
+    for (op, len) in ops {
+        match op {
+            CigarOp::Match | CigarOp::SequenceMatch | CigarOp::SequenceMismatch => {
+                match_cols += len as i64
+            }
+            CigarOp::Insertion | CigarOp::Deletion => {
+                gap_len += len as i64;
+                gap_penalty += 4 + 2 * len as i64;
+            }
+            _ => {}
+        }
+    }
+    let mismatches = (nm as i64 - gap_len).clamp(0, match_cols);
+    2 * (match_cols - mismatches) - 4 * mismatches - gap_penalty
+}
+
 /// from noodles: https://docs.rs/noodles-sam/latest/src/noodles_sam/alignment/record/cigar/op/kind.rs.html
 impl CigarOp {
     #[allow(dead_code)]
@@ -283,9 +458,40 @@ impl AlnRecordLike for minimap2::Mapping {
         self.is_supplementary
     }
 
+    fn is_secondary(&self) -> bool {
+        !self.is_primary && !self.is_supplementary
+    }
+
+    fn aln_identity(&self, kind: IdentityType) -> Option<f32> {
+        let aln = self.alignment.as_ref()?;
+        let cigar = aln.cigar.as_ref()?;
+        let ops = cigar.iter().map(|(len, op)| (CigarOp::from(*op), *len));
+        identity_from_cigar_ops(ops, aln.nm.max(0) as u32, kind)
+    }
+
     fn name(&self) -> Option<String> {
         self.query_name.as_ref().map(|q| q.to_string())
     }
+
+    fn mapq(&self) -> Option<u8> {
+        Some(self.mapq.min(254) as u8)
+    }
+
+    fn read_clips(&self) -> Option<(u32, u32)> {
+        // the query coordinates of minimap2 are on the forward strand of the read
+        let qlen = self.query_len?.get();
+        Some((
+            self.query_start.max(0) as u32,
+            (qlen - self.query_end).max(0) as u32,
+        ))
+    }
+
+    fn recomputed_aln_score(&self) -> Option<i64> {
+        let aln = self.alignment.as_ref()?;
+        let cigar = aln.cigar.as_ref()?;
+        let ops = cigar.iter().map(|(len, op)| (CigarOp::from(*op), *len));
+        Some(score_from_cigar_ops(ops, aln.nm.max(0) as u32))
+    }
 }
 
 pub trait NoodlesAlignmentLike {}
@@ -322,13 +528,10 @@ impl<T: NoodlesAlignmentLike + noodles_sam::alignment::Record> AlnRecordLike for
     }
 
     fn aln_score(&self) -> Option<i64> {
-        let x = self
-            .data()
-            .get(&AlnTag::ALIGNMENT_SCORE)
-            .unwrap()
+        self.data()
+            .get(&AlnTag::ALIGNMENT_SCORE)?
             .expect("could not get value")
-            .as_int();
-        x
+            .as_int()
     }
 
     fn aln_start(&self) -> u32 {
@@ -351,9 +554,73 @@ impl<T: NoodlesAlignmentLike + noodles_sam::alignment::Record> AlnRecordLike for
             .is_supplementary()
     }
 
+    fn is_secondary(&self) -> bool {
+        self.flags()
+            .expect("alignment record should have flags")
+            .is_secondary()
+    }
+
+    fn aln_identity(&self, kind: IdentityType) -> Option<f32> {
+        let data = self.data();
+        // minimap2 records the gap-compressed divergence directly
+        if kind == IdentityType::GapCompressed
+            && let Some(Ok(AlnValue::Float(de))) = data.get(&AlnTag::new(b'd', b'e'))
+        {
+            return Some(1.0 - de);
+        }
+        let nm = data.get(&AlnTag::EDIT_DISTANCE)?.ok()?.as_int()?;
+        let cigar = self.cigar();
+        let ops = cigar
+            .iter()
+            .filter_map(|op| op.ok())
+            .map(|op| (CigarOp::from(op.kind()), op.len() as u32));
+        identity_from_cigar_ops(ops, nm.max(0) as u32, kind)
+    }
+
     fn name(&self) -> Option<String> {
         self.name().map(|n| n.to_string())
     }
+
+    fn mapq(&self) -> Option<u8> {
+        // a MAPQ of 255 (unavailable) is read as None
+        self.mapping_quality()?.ok().map(|q| q.get())
+    }
+
+    fn read_clips(&self) -> Option<(u32, u32)> {
+        let is_clip = |k: &CigarKind| matches!(k, CigarKind::SoftClip | CigarKind::HardClip);
+        let ops: Vec<(CigarKind, u32)> = self
+            .cigar()
+            .iter()
+            .map(|op| op.map(|op| (op.kind(), op.len() as u32)))
+            .collect::<std::io::Result<_>>()
+            .ok()?;
+        let leading: u32 = ops
+            .iter()
+            .take_while(|(k, _)| is_clip(k))
+            .map(|(_, l)| l)
+            .sum();
+        let trailing: u32 = ops
+            .iter()
+            .rev()
+            .take_while(|(k, _)| is_clip(k))
+            .map(|(_, l)| l)
+            .sum();
+        if self.is_reverse_complemented() {
+            Some((trailing, leading))
+        } else {
+            Some((leading, trailing))
+        }
+    }
+
+    fn recomputed_aln_score(&self) -> Option<i64> {
+        let nm = self.data().get(&AlnTag::EDIT_DISTANCE)?.ok()?.as_int()?;
+        let cigar = self.cigar();
+        let ops = cigar
+            .iter()
+            .filter_map(|op| op.ok())
+            .map(|op| (CigarOp::from(op.kind()), op.len() as u32));
+        Some(score_from_cigar_ops(ops, nm.max(0) as u32))
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -366,7 +633,6 @@ pub struct AlnInfo {
 }
 
 impl AlnInfo {
-    #[allow(dead_code)]
     pub fn alignment_span(&self) -> u32 {
         self.end - self.start
     }
@@ -443,6 +709,9 @@ pub struct EMInfo<'eqm, 'tinfo, 'h> {
     // maximum number of iterations the EM will run
     // before returning an estimate.
     pub max_iter: u32,
+    // minimum number of iterations the EM will run,
+    // even if it has converged.
+    pub min_iter: u32,
     // the EM will terminate early if *all* parameters
     // have converged within this threshold of relative
     // change between two subsequent iterations.
@@ -453,14 +722,114 @@ pub struct EMInfo<'eqm, 'tinfo, 'h> {
     pub init_abundances: Option<Vec<f64>>,
     /// holds the KDE model if we will be using one
     /// and [None] otherwise
-    pub kde_model: Option<KDEModel>,
+    pub kde_model: Option<KdeModel>,
+    /// an optional weight for each transcript, multiplied into the
+    /// likelihood of every alignment to that transcript
+    pub txp_weights: Option<Vec<f64>>,
+    /// if present, the iterations of the (logged) runs of the EM
+    /// are recorded here
+    pub trace: Option<Arc<EmTrace>>,
+}
+
+/// The state of the estimates after one iteration of the EM.
+#[derive(Debug, Clone, Copy)]
+pub struct EmIteration {
+    pub iteration: u32,
+    /// the log-likelihood of the estimates entering the iteration
+    pub log_likelihood: f64,
+    /// the largest relative change of an estimate in the iteration
+    pub max_rel_diff: f64,
+    /// the number of transcripts whose estimate is above [MIN_READ_THRESH]
+    pub num_active_txps: usize,
+}
+
+impl EmIteration {
+    pub fn new(iteration: u32, log_likelihood: f64, max_rel_diff: f64, counts: &[f64]) -> Self {
+        Self {
+            iteration,
+            log_likelihood,
+            max_rel_diff,
+            num_active_txps: counts.iter().filter(|c| **c > MIN_READ_THRESH).count(),
+        }
+    }
+}
+
+/// The iterations of the EM, recorded for `--em-trace`.
+#[derive(Debug, Default)]
+pub struct EmTrace {
+    iterations: std::sync::Mutex<Vec<EmIteration>>,
+}
+
+impl EmTrace {
+    pub fn push(&self, it: EmIteration) {
+        self.iterations.lock().expect("EM trace lock").push(it);
+    }
+
+    /// Remove and return the iterations recorded so far.
+    pub fn take(&self) -> Vec<EmIteration> {
+        std::mem::take(&mut *self.iterations.lock().expect("EM trace lock"))
+    }
+}
+
+/// How each transcript is divided into the bins of the coverage model. A single bin
+/// width over-smooths the coverage of short transcripts and under-smooths that of long
+/// ones, so by default the width of the bins is proportional to the length of the
+/// transcript, within fixed bounds.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoverageBinning {
+    /// bins of the same width on every transcript
+    Fixed { bin_width: u32 },
+    /// `bins_per_txp` bins on each transcript, with widths kept within
+    /// `[min_width, max_width]`
+    Adaptive {
+        bins_per_txp: u32,
+        min_width: u32,
+        max_width: u32,
+    },
+}
+
+impl CoverageBinning {
+    pub fn from_args(args: &crate::prog_opts::Args) -> Self {
+        match args.bin_width {
+            Some(bin_width) => CoverageBinning::Fixed { bin_width },
+            None => CoverageBinning::Adaptive {
+                bins_per_txp: args.bins_per_txp,
+                min_width: args.min_bin_width,
+                max_width: args.max_bin_width,
+            },
+        }
+    }
+
+    /// The number of bins of a transcript of length `len`.
+    pub fn num_bins(&self, len: usize) -> usize {
+        let width = match *self {
+            CoverageBinning::Fixed { bin_width } => bin_width as usize,
+            CoverageBinning::Adaptive {
+                bins_per_txp,
+                min_width,
+                max_width,
+            } => (len / bins_per_txp as usize).clamp(min_width as usize, max_width as usize),
+        };
+        len.div_ceil(width.max(1)).max(1)
+    }
 }
 
+/// Holds the per-transcript information used by the coverage model.
+///
+/// *Note*: coverage is accumulated directly into fixed-width bins as
+/// alignments are added (see [TranscriptInfo::add_interval]), so no
+/// per-read start / end positions are retained here; the memory used by
+/// a transcript is proportional to its number of bins regardless of how
+/// many reads align to it.
 #[derive(Clone, Debug, PartialEq)]
 pub struct TranscriptInfo {
     pub len: NonZeroUsize,
+    // the total weight of the alignments added to this transcript
     pub total_weight: f64,
+    // the (fractional) number of alignments covering each bin
     pub coverage_bins: Vec<f64>,
+    // the coverage probability of each bin, derived from `coverage_bins`
     pub coverage_prob: Vec<f64>,
     pub lenf: f64,
 }
@@ -486,16 +855,22 @@ impl TranscriptInfo {
             lenf: len.get() as f64,
         }
     }
-    pub fn with_len_and_bin_width(len: NonZeroUsize, bin_width: u32) -> Self {
+    pub fn with_len_and_binning(len: NonZeroUsize, binning: &CoverageBinning) -> Self {
         Self {
             len,
             total_weight: 0.0_f64,
-            coverage_bins: vec![0.0_f64; ((len.get() as f64) / (bin_width as f64)).ceil() as usize],
+            coverage_bins: vec![0.0_f64; binning.num_bins(len.get())],
             coverage_prob: Vec::new(),
             lenf: len.get() as f64,
         }
     }
 
+    /// The (mean) width of the coverage bins of this transcript.
+    #[inline(always)]
+    pub fn bin_length(&self) -> f64 {
+        self.lenf / self.coverage_bins.len().max(1) as f64
+    }
+
     #[inline(always)]
     pub fn get_normalized_counts_and_lengths(&self) -> (Vec<f32>, Vec<f32>) {
         let num_intervals = self.coverage_bins.len();
@@ -580,16 +955,105 @@ pub struct InMemoryAlignmentStore<'h> {
     pub alignments: Vec<AlnInfo>,
     pub as_probabilities: Vec<f32>,
     pub coverage_probabilities: Vec<f64>,
+    // the length of each read (in the same order as the
+    // alignment groups); 0 if the length is unknown
+    pub read_lengths: Vec<u32>,
+    // the lane (or, for alignment input, the read group) from
+    // which each read was obtained (in the same order as the
+    // alignment groups); empty unless these are being tracked
+    pub read_lanes: Vec<u16>,
     // holds the boundaries between records for different reads
     boundaries: Vec<usize>,
+    // if present, the alignments are held here (in compact form)
+    // rather than in `alignments`, `as_probabilities` and
+    // `coverage_probabilities`
+    compact: Option<CompactAlignments>,
+    // if present, the store reduces its memory use once the limit
+    // set by `--max-mem` is exceeded while it is being filled
+    // (see [InMemoryAlignmentStore::relieve_memory_pressure])
+    pub mem_guard: Option<MemoryGuard>,
+    // a user-provided filter applied to the alignments of each read
+    // (see [InMemoryAlignmentStore::apply_read_filter])
+    pub read_filter: Option<Box<dyn ReadFilter>>,
     pub discard_table: DiscardTable,
     pub num_unique_alignments: usize,
+    // the number of reads in the input, including those that
+    // were unmapped or had no alignment passing the filters;
+    // 0 if unknown
+    pub num_input_reads: usize,
+    // the outcome of the second-chance re-alignment of poorly explained
+    // reads, if it was performed
+    pub second_chance: Option<SecondChanceStats>,
+    // the outcome of the trimming of adapters and primers from the
+    // reads, if it was performed
+    pub trimming: Option<TrimStats>,
+    // the detection of internal-priming artifacts among the alignments
+    // (see [InMemoryAlignmentStore::apply_internal_priming])
+    pub internal_priming: Option<InternalPriming>,
+    // the sample of a demultiplexed run to which the reads of this
+    // store were assigned
+    pub demux: Option<DemuxSample>,
 }
 
+/// The alignments of a read, along with their alignment score and coverage
+/// probabilities. These are borrowed from the store, unless the store is
+/// compact, in which case they are decoded on demand.
+pub type AlnGroup<'a> = (Cow<'a, [AlnInfo]>, Cow<'a, [f32]>, Cow<'a, [f64]>);
+
 impl InMemoryAlignmentStore<'_> {
     #[inline]
     pub fn len(&self) -> usize {
-        self.boundaries.len().saturating_sub(1)
+        match self.compact {
+            Some(ref c) => c.len(),
+            None => self.boundaries.len().saturating_sub(1),
+        }
+    }
+
+    /// true if the alignments are held in the compact (`--low-mem`) representation
+    #[inline]
+    pub fn is_low_mem(&self) -> bool {
+        self.compact.is_some()
+    }
+
+    /// The alignments of read `i`, along with their alignment score and
+    /// coverage probabilities.
+    #[inline]
+    pub fn group(&self, i: usize) -> AlnGroup<'_> {
+        match self.compact {
+            Some(ref c) => {
+                let mut alns = Vec::new();
+                let mut as_probs = Vec::new();
+                let mut cov_probs = Vec::new();
+                c.decode(i, &mut alns, &mut as_probs, &mut cov_probs);
+                (
+                    Cow::Owned(alns),
+                    Cow::Owned(as_probs),
+                    Cow::Owned(cov_probs),
+                )
+            }
+            None => {
+                let start = self.boundaries[i];
+                let end = self.boundaries[i + 1];
+                (
+                    Cow::Borrowed(&self.alignments[start..end]),
+                    Cow::Borrowed(&self.as_probabilities[start..end]),
+                    Cow::Borrowed(&self.coverage_probabilities[start..end]),
+                )
+            }
+        }
+    }
+
+    /// Set the coverage probabilities of the alignments of read `i` (in
+    /// the order in which they are returned by [InMemoryAlignmentStore::group]).
+    pub fn set_coverage_probs(&mut self, i: usize, probs: &[f64]) {
+        match self.compact {
+            Some(ref mut c) => c.set_coverage_probs(i, probs),
+            None => {
+                let start = self.boundaries[i];
+                let end = self.boundaries[i + 1];
+                self.coverage_probabilities[start..end].copy_from_slice(probs);
+            }
+        }
     }
 
     pub fn aggregate_discard_table(&mut self, table: &DiscardTable) {
@@ -603,21 +1067,13 @@ pub struct InMemoryAlignmentStoreSamplingWithReplacementIter<'a, 'h, 'b> {
 }
 
 impl<'a> Iterator for InMemoryAlignmentStoreSamplingWithReplacementIter<'a, '_, '_> {
-    type Item = (&'a [AlnInfo], &'a [f32], &'a [f64]);
+    type Item = AlnGroup<'a>;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(next_ind) = self.rand_inds.next() {
-            let start = self.store.boundaries[*next_ind];
-            let end = self.store.boundaries[*next_ind + 1];
-            Some((
-                &self.store.alignments[start..end],
-                &self.store.as_probabilities[start..end],
-                &self.store.coverage_probabilities[start..end],
-            ))
-        } else {
-            None
-        }
+        self.rand_inds
+            .next()
+            .map(|next_ind| self.store.group(*next_ind))
     }
 
     #[inline]
@@ -634,21 +1090,15 @@ pub struct InMemoryAlignmentStoreIter<'a, 'h> {
 }
 
 impl<'a> Iterator for InMemoryAlignmentStoreIter<'a, '_> {
-    type Item = (&'a [AlnInfo], &'a [f32], &'a [f64]);
+    type Item = AlnGroup<'a>;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        if self.idx + 1 >= self.store.boundaries.len() {
+        if self.idx >= self.store.len() {
             None
         } else {
-            let start = self.store.boundaries[self.idx];
-            let end = self.store.boundaries[self.idx + 1];
             self.idx += 1;
-            Some((
-                &self.store.alignments[start..end],
-                &self.store.as_probabilities[start..end],
-                &self.store.coverage_probabilities[start..end],
-            ))
+            Some(self.store.group(self.idx - 1))
         }
     }
 
@@ -671,10 +1121,74 @@ impl<'h> InMemoryAlignmentStore<'h> {
             alignments: vec![],
             as_probabilities: vec![],
             coverage_probabilities: vec![],
+            read_lengths: vec![],
+            read_lanes: vec![],
             boundaries: vec![0],
+            compact: None,
+            mem_guard: None,
+            read_filter: None,
             discard_table: DiscardTable::new(),
             num_unique_alignments: 0,
+            num_input_reads: 0,
+            second_chance: None,
+            trimming: None,
+            internal_priming: None,
+            demux: None,
+        }
+    }
+
+    /// Create a store that holds its alignments in a compact representation
+    /// (see [CompactAlignments]), which takes considerably less memory, but
+    /// must be decoded each time the alignments are visited.
+    pub fn new_low_mem(fo: AlignmentFilters, header: &'h Header) -> Self {
+        let mut store = Self::new(fo, header);
+        store.compact = Some(CompactAlignments::new());
+        store
+    }
+
+    /// Create an empty store with the same filters and representation
+    /// as this one, whose alignments refer to `header`.
+    pub fn empty_like<'a>(&self, header: &'a Header) -> InMemoryAlignmentStore<'a> {
+        let mut store = if self.is_low_mem() {
+            InMemoryAlignmentStore::new_low_mem(self.filter_opts.clone(), header)
+        } else {
+            InMemoryAlignmentStore::new(self.filter_opts.clone(), header)
+        };
+        if self.mem_guard.is_some() {
+            store.mem_guard = MemoryGuard::new();
+        }
+        store
+    }
+
+    /// Reduce the memory taken up by the store once the limit set by `--max-mem`
+    /// is exceeded: convert it to the compact representation if it is not already
+    /// held in that form, and otherwise move the filled blocks of the compact
+    /// representation to disk.
+    #[cold]
+    fn relieve_memory_pressure(&mut self) {
+        let Some(mut guard) = self.mem_guard.take() else {
+            return;
+        };
+        match self.compact {
+            Some(ref mut c) => guard.record_spill(c.spill(&guard.spill_dir)),
+            None => {
+                guard.record_compaction(self.len());
+                let mut compact = CompactAlignments::new();
+                for w in self.boundaries.windows(2) {
+                    // coverage probabilities are not yet set while the store is filled
+                    compact.push(
+                        &self.alignments[w[0]..w[1]],
+                        &self.as_probabilities[w[0]..w[1]],
+                    );
+                }
+                self.alignments = vec![];
+                self.as_probabilities = vec![];
+                self.coverage_probabilities = vec![];
+                self.boundaries = vec![0];
+                self.compact = Some(compact);
+            }
         }
+        self.mem_guard = Some(guard);
     }
 
     pub fn iter(&self) -> InMemoryAlignmentStoreIter {
@@ -704,20 +1218,95 @@ impl<'h> InMemoryAlignmentStore<'h> {
         ag: &mut Vec<T>,
     ) -> bool {
         if !ag.is_empty() {
-            let (alns, as_probs) =
+            // secondary records need not carry the sequence, so
+            // look for the first record that does.
+            let read_len = ag
+                .iter()
+                .filter_map(|x| x.opt_sequence_len())
+                .find(|l| *l > 0)
+                .unwrap_or(0) as u32;
+            let accuracy = if self.filter_opts.read_quality_weighting {
+                read_quality::record_accuracy(ag)
+            } else {
+                None
+            };
+            let (alns, mut as_probs) =
                 self.filter_opts
                     .filter(&mut self.discard_table, self.aln_header, txps, ag);
-            self.add_filtered_group(&alns, &as_probs, txps)
+            read_quality::weight_by_accuracy(&mut as_probs, accuracy);
+            if self.read_filter.is_some() {
+                let name = ag
+                    .first()
+                    .and_then(AlnRecordLike::name)
+                    .unwrap_or_else(|| EMPTY_READ_NAME.to_string());
+                let (alns, as_probs) = self.apply_read_filter(&name, read_len, &alns, &as_probs);
+                let (alns, as_probs) = self.apply_internal_priming(&alns, &as_probs);
+                self.add_filtered_group(&alns, &as_probs, read_len, txps)
+            } else {
+                let (alns, as_probs) = self.apply_internal_priming(&alns, &as_probs);
+                self.add_filtered_group(&alns, &as_probs, read_len, txps)
+            }
         } else {
             false
         }
     }
 
+    /// Apply the user-provided read filter (if any) to the alignments `alns` (with
+    /// alignment score probabilities `as_probs`) of the read `name`, returning the
+    /// alignments (and probabilities) that it retains.
+    pub fn apply_read_filter<'a>(
+        &mut self,
+        name: &str,
+        read_len: u32,
+        alns: &'a [AlnInfo],
+        as_probs: &'a [f32],
+    ) -> (Cow<'a, [AlnInfo]>, Cow<'a, [f32]>) {
+        let Some(ref mut read_filter) = self.read_filter else {
+            return (Cow::Borrowed(alns), Cow::Borrowed(as_probs));
+        };
+        if alns.is_empty() {
+            return (Cow::Borrowed(alns), Cow::Borrowed(as_probs));
+        }
+        let mut keep = vec![true; alns.len()];
+        if !read_filter.filter(name, read_len, alns, as_probs, &mut keep) {
+            keep.fill(false);
+        }
+        let num_kept = keep.iter().filter(|k| **k).count();
+        if num_kept == alns.len() {
+            return (Cow::Borrowed(alns), Cow::Borrowed(as_probs));
+        }
+        self.discard_table.discard_plugin += (alns.len() - num_kept) as u32;
+        let (kept_alns, kept_probs): (Vec<AlnInfo>, Vec<f32>) = alns
+            .iter()
+            .zip(as_probs.iter())
+            .zip(keep.iter())
+            .filter(|(_, k)| **k)
+            .map(|((a, p), _)| (a.clone(), *p))
+            .unzip();
+        (Cow::Owned(kept_alns), Cow::Owned(kept_probs))
+    }
+
+    /// Flag the alignments `alns` (with alignment score probabilities `as_probs`) of a
+    /// read that are likely internal-priming artifacts, if requested, returning the
+    /// alignments (and probabilities) once the flagged ones have been down-weighted or
+    /// excluded (see [InternalPriming::apply]).
+    pub fn apply_internal_priming<'a>(
+        &mut self,
+        alns: &'a [AlnInfo],
+        as_probs: &'a [f32],
+    ) -> (Cow<'a, [AlnInfo]>, Cow<'a, [f32]>) {
+        match self.internal_priming {
+            Some(ref mut ip) => ip.apply(alns, as_probs),
+            None => (Cow::Borrowed(alns), Cow::Borrowed(as_probs)),
+        }
+    }
+
     #[inline(always)]
     pub fn add_filtered_group(
         &mut self,
         alns: &[AlnInfo],
         as_probs: &[f32],
+        read_len: u32,
         txps: &mut [TranscriptInfo],
     ) -> bool {
         if !alns.is_empty() {
@@ -725,11 +1314,24 @@ impl<'h> InMemoryAlignmentStore<'h> {
                 let tid = a.ref_id as usize;
                 txps[tid].add_interval(a.start, a.end, 1.0_f64);
             }
-            self.alignments.extend_from_slice(alns);
-            self.as_probabilities.extend_from_slice(as_probs);
-            self.coverage_probabilities
-                .extend(vec![0.0_f64; alns.len()]);
-            self.boundaries.push(self.alignments.len());
+            if let Some(ref mut c) = self.compact {
+                c.push(alns, as_probs);
+            } else {
+                self.alignments.extend_from_slice(alns);
+                self.as_probabilities.extend_from_slice(as_probs);
+                self.coverage_probabilities
+                    .extend(vec![0.0_f64; alns.len()]);
+                self.boundaries.push(self.alignments.len());
+            }
+            self.read_lengths.push(read_len);
+            let num_reads = self.read_lengths.len();
+            if self
+                .mem_guard
+                .as_ref()
+                .is_some_and(|g| g.should_check(num_reads))
+            {
+                self.relieve_memory_pressure();
+            }
             true
         } else {
             false
@@ -738,7 +1340,10 @@ impl<'h> InMemoryAlignmentStore<'h> {
 
     #[inline(always)]
     pub fn total_len(&self) -> usize {
-        self.alignments.len()
+        match self.compact {
+            Some(ref c) => c.num_alignments(),
+            None => self.alignments.len(),
+        }
     }
 
     #[inline(always)]
@@ -783,9 +1388,49 @@ pub struct AlignmentFilters {
     /// must be aligned under this alignment in
     /// order for this alignment to be  retained
     min_aligned_len: u32,
+    /// The minimum identity (of type `identity_type`)
+    /// that an alignment must have in order to be
+    /// retained.
+    min_identity: f32,
+    /// How the identity of an alignment is computed.
+    identity_type: IdentityType,
+    /// The score of an alignment used by the score threshold
+    /// and to compute the alignment probabilities.
+    score_type: ScoreType,
+    /// Whether the 5' and 3' clip distances are measured on
+    /// the transcript or as the clipped bases of the read.
+    clip_mode: ClipMode,
+    /// How the secondary alignments of a read are used.
+    secondary_policy: SecondaryPolicy,
+    /// The number of alignments retained under [SecondaryPolicy::TopK].
+    secondary_top_k: u32,
+    /// If true, supplementary alignments are examined (before being
+    /// discarded) to identify chimeric reads, and the query sequence
+    /// they cover counts towards the aligned fraction of the read.
+    rescue_supplementary: bool,
     /// Determines which alignments we should consider
     /// as valid during quantificationwhich_strand.
     which_strand: bio_types::strand::Strand,
+    /// If present, `excluded_txps[i]` is true if alignments
+    /// to transcript `i` should be discarded.
+    #[builder(default)]
+    #[serde(skip)]
+    excluded_txps: Option<Arc<Vec<bool>>>,
+    /// If present, the targets with this and larger ids are decoys
+    /// (see `--decoys`), whose alignments are recorded in the
+    /// [DecoyTable] of the discard table.
+    #[builder(default)]
+    #[serde(skip)]
+    decoy_start: Option<usize>,
+    // If set, the calibration curve used to compute the alignment
+    // probabilities from the (length-normalized) alignment scores.
+    #[builder(default)]
+    #[serde(skip)]
+    score_calibration: Option<Arc<ScoreCalibration>>,
+    // True if the alignment probabilities of each read are weighted
+    // by its predicted accuracy (see [read_quality::weight_by_accuracy]).
+    #[builder(default)]
+    pub read_quality_weighting: bool,
     // True if we are enabling our coverage model and
     // false otherwise.
     pub model_coverage: bool,
@@ -798,19 +1443,88 @@ pub struct AlignmentFilters {
     pub write_assignment_probs_type: Option<ReadAssignmentProbOut>,
 }
 
+/// Records the chimeric reads (reads having supplementary
+/// alignments) observed when rescuing supplementary alignments.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChimeraTable {
+    /// reads with a supplementary alignment to the same transcript
+    /// as the primary alignment (structural artifacts)
+    pub same_transcript: u32,
+    /// reads with a supplementary alignment to a different transcript
+    /// than the primary alignment (putative fusions)
+    pub fusion_candidates: u32,
+    /// the number of reads supporting each (unordered) pair of
+    /// transcripts, keyed by (smaller id, larger id)
+    #[serde(skip)]
+    pub fusion_pairs: FxHashMap<(u32, u32), u32>,
+}
+
+impl ChimeraTable {
+    pub fn aggregate(&mut self, other: &Self) {
+        self.same_transcript += other.same_transcript;
+        self.fusion_candidates += other.fusion_candidates;
+        for (k, v) in other.fusion_pairs.iter() {
+            *self.fusion_pairs.entry(*k).or_insert(0) += v;
+        }
+    }
+}
+
+/// The number of bins of the histogram of decoy alignment scores
+/// (relative to the best score of the read) in a [DecoyTable].
+pub const DECOY_SCORE_BINS: usize = 1000;
+
+/// Records how the reads align to the decoy targets (see `--decoys`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DecoyTable {
+    /// reads whose best alignment is to a decoy
+    pub best_decoy: u32,
+    /// reads whose best alignment is to a real target
+    pub best_target: u32,
+    /// for the reads whose best alignment is to a real target but which
+    /// also align to a decoy, a histogram (with [DECOY_SCORE_BINS] bins)
+    /// of the best decoy alignment score as a fraction of the best score
+    #[serde(skip)]
+    pub score_ratio_hist: Vec<u32>,
+}
+
+impl DecoyTable {
+    pub fn aggregate(&mut self, other: &Self) {
+        self.best_decoy += other.best_decoy;
+        self.best_target += other.best_target;
+        if self.score_ratio_hist.len() < other.score_ratio_hist.len() {
+            self.score_ratio_hist
+                .resize(other.score_ratio_hist.len(), 0);
+        }
+        for (a, b) in self
+            .score_ratio_hist
+            .iter_mut()
+            .zip(other.score_ratio_hist.iter())
+        {
+            *a += b;
+        }
+    }
+}
+
 /// This structure records information about
 /// the number of alignments (and reads) discarded
 /// due to the application of `AlignmentFilters`.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscardTable {
     discard_5p: u32,
     discard_3p: u32,
     discard_score: u32,
     discard_aln_frac: u32,
     discard_aln_len: u32,
+    discard_identity: u32,
+    discard_excluded: u32,
     discard_ori: u32,
     discard_supp: u32,
+    discard_secondary: u32,
+    discard_plugin: u32,
     valid_best_aln: u32,
+    pub chimeras: ChimeraTable,
+    #[serde(skip)]
+    pub decoys: DecoyTable,
 }
 
 impl DiscardTable {
@@ -821,9 +1535,15 @@ impl DiscardTable {
             discard_score: 0,
             discard_aln_frac: 0,
             discard_aln_len: 0,
+            discard_identity: 0,
+            discard_excluded: 0,
             discard_ori: 0,
             discard_supp: 0,
+            discard_secondary: 0,
+            discard_plugin: 0,
             valid_best_aln: 0,
+            chimeras: ChimeraTable::default(),
+            decoys: DecoyTable::default(),
         }
     }
 
@@ -833,9 +1553,15 @@ impl DiscardTable {
         self.discard_score += other.discard_score;
         self.discard_aln_frac += other.discard_aln_frac;
         self.discard_aln_len += other.discard_aln_len;
+        self.discard_identity += other.discard_identity;
+        self.discard_excluded += other.discard_excluded;
         self.discard_ori += other.discard_ori;
         self.discard_supp += other.discard_supp;
+        self.discard_secondary += other.discard_secondary;
+        self.discard_plugin += other.discard_plugin;
         self.valid_best_aln += other.valid_best_aln;
+        self.chimeras.aggregate(&other.chimeras);
+        self.decoys.aggregate(&other.decoys);
     }
 }
 
@@ -846,9 +1572,15 @@ impl DiscardTable {
         let dscore = format!("{}", self.discard_score);
         let dfrac = format!("{}", self.discard_aln_frac);
         let dlen = format!("{}", self.discard_aln_len);
+        let dident = format!("{}", self.discard_identity);
+        let dexcl = format!("{}", self.discard_excluded);
         let dori = format!("{}", self.discard_ori);
         let dsupp = format!("{}", self.discard_supp);
+        let dsec = format!("{}", self.discard_secondary);
+        let dplug = format!("{}", self.discard_plugin);
         let vread = format!("{}", self.valid_best_aln);
+        let csame = format!("{}", self.chimeras.same_transcript);
+        let cfus = format!("{}", self.chimeras.fusion_candidates);
 
         let data = vec![
             ["reason", "count"],
@@ -857,9 +1589,15 @@ impl DiscardTable {
             ["score too low", &dscore],
             ["aligned fraction too low", &dfrac],
             ["aligned length too short", &dlen],
+            ["identity too low", &dident],
+            ["excluded transcript", &dexcl],
             ["inconsistent orientation", &dori],
             ["supplementary alignment", &dsupp],
+            ["secondary alignment policy", &dsec],
+            ["read filter plugin", &dplug],
             ["reads with valid best alignment", &vread],
+            ["chimeric reads (same transcript)", &csame],
+            ["chimeric reads (fusion candidate)", &cfus],
         ];
         let mut binding = Builder::from_iter(data).build();
         let table = binding.with(Style::rounded());
@@ -899,6 +1637,18 @@ impl fmt::Display for DiscardTable {
             self.discard_aln_len
         )
         .expect("couldn't format discard table.");
+        writeln!(
+            f,
+            "discarded because of alignment identity {}",
+            self.discard_identity
+        )
+        .expect("couldn't format discard table.");
+        writeln!(
+            f,
+            "discarded because transcript is excluded {}",
+            self.discard_excluded
+        )
+        .expect("couldn't format discard table.");
         writeln!(
             f,
             "discarded because of aligned orientation {}",
@@ -910,10 +1660,186 @@ impl fmt::Display for DiscardTable {
             "discarded because alignment is supplemental {}",
             self.discard_supp
         )
+        .expect("couldn't format discard table.");
+        writeln!(
+            f,
+            "discarded because of secondary alignment policy {}",
+            self.discard_secondary
+        )
+        .expect("couldn't format discard table.");
+        writeln!(
+            f,
+            "discarded by the read filter plugin {}",
+            self.discard_plugin
+        )
     }
 }
 
 impl AlignmentFilters {
+    /// Restrict the transcripts to which alignments are retained; alignments
+    /// to transcript `i` will be discarded if `excluded[i]` is true.
+    pub fn set_excluded_txps(&mut self, excluded: Vec<bool>) {
+        self.excluded_txps = Some(Arc::new(excluded));
+    }
+
+    /// Mark the targets with ids `decoy_start` and larger as decoys, whose
+    /// alignments will be recorded in the [DecoyTable] of the discard table.
+    pub fn set_decoy_start(&mut self, decoy_start: usize) {
+        self.decoy_start = Some(decoy_start);
+    }
+
+    /// The id of the first decoy target, if decoys are in use.
+    pub fn decoy_start(&self) -> Option<usize> {
+        self.decoy_start
+    }
+
+    /// Compute the alignment probabilities from the alignment scores using the
+    /// fitted `calibration`, rather than the fixed transformation of the scores.
+    pub fn set_score_calibration(&mut self, calibration: ScoreCalibration) {
+        self.score_calibration = Some(Arc::new(calibration));
+    }
+
+    /// The calibration of the alignment scores, if one was fit.
+    pub fn score_calibration(&self) -> Option<&ScoreCalibration> {
+        self.score_calibration.as_deref()
+    }
+
+    pub fn score_threshold(&self) -> f32 {
+        self.score_threshold
+    }
+
+    /// Record, in `discard_table`, whether the best alignment of the group `ag`
+    /// (with alignment scores `scores`) is to a decoy or to a real target and, in
+    /// the latter case, the best score of an alignment to a decoy relative to the
+    /// best score of the read.
+    fn record_decoy_scores<T: AlnRecordLike>(
+        &self,
+        discard_table: &mut DiscardTable,
+        aln_header: &Header,
+        ag: &[T],
+        scores: &[i32],
+        decoy_start: usize,
+    ) {
+        let mut best_target: Option<i32> = None;
+        let mut best_decoy: Option<i32> = None;
+        for (x, s) in ag.iter().zip(scores.iter()) {
+            let Ok(tid) = x.ref_id(aln_header) else {
+                continue;
+            };
+            let best = if tid >= decoy_start {
+                &mut best_decoy
+            } else {
+                &mut best_target
+            };
+            *best = Some(best.map_or(*s, |b| b.max(*s)));
+        }
+
+        let decoys = &mut discard_table.decoys;
+        match (best_target, best_decoy) {
+            (Some(t), d) if d.is_none_or(|d| t >= d) => {
+                decoys.best_target += 1;
+                if let Some(d) = d
+                    && t > 0
+                {
+                    if decoys.score_ratio_hist.is_empty() {
+                        decoys.score_ratio_hist = vec![0; DECOY_SCORE_BINS];
+                    }
+                    let ratio = d.max(0) as f32 / t as f32;
+                    let bin =
+                        ((ratio * DECOY_SCORE_BINS as f32) as usize).min(DECOY_SCORE_BINS - 1);
+                    decoys.score_ratio_hist[bin] += 1;
+                }
+            }
+            (_, Some(_)) => decoys.best_decoy += 1,
+            _ => {}
+        }
+    }
+
+    /// Examine the supplementary alignments in the group `ag`, recording in
+    /// `discard_table` whether they align to the same transcript as the primary
+    /// alignment (a structural artifact) or to a different transcript (a
+    /// putative fusion). Returns the number of bases of the read (of length
+    /// `seq_len`) covered by the supplementary alignments to the transcript of
+    /// the primary alignment, but not by the primary alignment itself; the
+    /// segments are stitched in the coordinates of the read, so that bases
+    /// covered by several alignments are counted once.
+    fn record_chimeric_segments<T: AlnRecordLike>(
+        &self,
+        discard_table: &mut DiscardTable,
+        aln_header: &Header,
+        ag: &[T],
+        seq_len: u32,
+    ) -> u32 {
+        let Some(primary) = ag.iter().find(|x| !x.is_unmapped() && !x.is_supp()) else {
+            return 0;
+        };
+        let Ok(ptid) = primary.ref_id(aln_header) else {
+            return 0;
+        };
+        // the interval of the read covered by the alignment `x`, in the
+        // orientation of the read
+        let read_interval = |x: &T| {
+            x.read_clips()
+                .filter(|(c5, c3)| seq_len > 0 && c5 + c3 < seq_len)
+                .map(|(c5, c3)| (c5, seq_len - c3))
+        };
+
+        let mut segments: Vec<(u32, u32)> = Vec::new();
+        let mut saw_same = false;
+        let mut partners: Vec<u32> = Vec::new();
+        for x in ag.iter().filter(|x| !x.is_unmapped() && x.is_supp()) {
+            let Ok(stid) = x.ref_id(aln_header) else {
+                continue;
+            };
+            if stid == ptid {
+                saw_same = true;
+                segments.extend(read_interval(x));
+            } else if !partners.contains(&(stid as u32)) {
+                partners.push(stid as u32);
+            }
+        }
+
+        // the bases covered by the primary and the same-transcript supplementary
+        // alignments, less those covered by the primary alignment alone
+        let supp_span = match read_interval(primary) {
+            Some((pstart, pend)) if !segments.is_empty() => {
+                segments.push((pstart, pend));
+                segments.sort_unstable();
+                let mut covered = 0_u32;
+                let mut curr: Option<(u32, u32)> = None;
+                for (start, end) in segments {
+                    curr = match curr {
+                        Some((cs, ce)) if start <= ce => Some((cs, ce.max(end))),
+                        Some((cs, ce)) => {
+                            covered += ce - cs;
+                            Some((start, end))
+                        }
+                        None => Some((start, end)),
+                    };
+                }
+                if let Some((cs, ce)) = curr {
+                    covered += ce - cs;
+                }
+                covered - (pend - pstart)
+            }
+            _ => 0,
+        };
+
+        let chimeras = &mut discard_table.chimeras;
+        if saw_same {
+            chimeras.same_transcript += 1;
+        }
+        if !partners.is_empty() {
+            chimeras.fusion_candidates += 1;
+            let ptid = ptid as u32;
+            for stid in partners {
+                let key = (ptid.min(stid), ptid.max(stid));
+                *chimeras.fusion_pairs.entry(key).or_insert(0) += 1;
+            }
+        }
+        supp_span
+    }
+
     /// Applies the filters defined by this AlignmentFilters struct
     /// to the alignments provided in `ag`, a vector of alignments representing
     /// a group of contiguous alignments for the same target.
@@ -950,17 +1876,34 @@ impl AlignmentFilters {
             .find_map(|x| x.opt_sequence_len().map(|y| y as u32))
             .unwrap_or(0_u32);
 
+        // if we are rescuing chimeric reads, examine the supplementary
+        // alignments before they are discarded below.
+        let supp_span = if self.rescue_supplementary {
+            self.record_chimeric_segments(discard_table, aln_header, ag, seq_len)
+        } else {
+            0_u32
+        };
+
         // apply the filter criteria to determine what alignments to retain
         ag.retain(|x| {
             // we ony want to retain mapped reads
             if !x.is_unmapped() {
                 let tid = x.ref_id(aln_header).expect("valid ref id");
 
+                // the alignment is to a transcript that has been
+                // excluded from quantification
+                if let Some(ref excluded) = self.excluded_txps
+                    && excluded[tid]
+                {
+                    discard_table.discard_excluded += 1;
+                    return false;
+                }
+
                 // get the alignment span
                 let aln_span = x.aln_span().unwrap() as u32;
 
-                // get the alignment score, as computed by the aligner
-                let score = x.aln_score().unwrap_or(i32::MIN as i64) as i32;
+                // get the alignment score, of the chosen type
+                let score = x.typed_score(self.score_type).unwrap_or(i32::MIN as i64) as i32;
 
                 // the alignment is to the - strand
                 let is_rc = x.is_reverse_complemented();
@@ -1001,16 +1944,37 @@ impl AlignmentFilters {
                     return false;
                 }
 
-                // not too far from the 3' end
-                let filt_3p =
-                    (x.aln_end() as i64) <= (txps[tid].len.get() as i64 - self.three_prime_clip);
+                // the alignment is of sufficiently high identity; if the
+                // identity cannot be determined, the alignment is retained
+                if self.min_identity > 0.0
+                    && let Some(ident) = x.aln_identity(self.identity_type)
+                    && ident < self.min_identity
+                {
+                    discard_table.discard_identity += 1;
+                    return false;
+                }
+
+                // not too far from the 3' and 5' ends, either of the transcript
+                // or of the read (if alignments are filtered on their clipping)
+                let (filt_3p, filt_5p) = match self.clip_mode {
+                    ClipMode::Transcript => (
+                        (x.aln_end() as i64)
+                            <= (txps[tid].len.get() as i64 - self.three_prime_clip),
+                        x.aln_start() >= self.five_prime_clip,
+                    ),
+                    // if the clips are unknown, the alignment is retained
+                    ClipMode::Read => x.read_clips().map_or((false, false), |(c5, c3)| {
+                        (
+                            c3 as i64 >= self.three_prime_clip,
+                            c5 >= self.five_prime_clip,
+                        )
+                    }),
+                };
                 if filt_3p {
                     discard_table.discard_3p += 1;
                     return false;
                 }
 
-                // not too far from the 5' end
-                let filt_5p = x.aln_start() >= self.five_prime_clip;
                 if filt_5p {
                     discard_table.discard_5p += 1;
                     return false;
@@ -1040,6 +2004,12 @@ impl AlignmentFilters {
             // There were no valid alignments
             return (vec![], vec![]);
         }
+        // the read sequence covered by supplementary alignments to the
+        // transcript of the primary alignment also counts as aligned
+        if supp_span > 0 && seq_len > 0 {
+            aln_frac_at_best_retained =
+                (aln_frac_at_best_retained + (supp_span as f32) / (seq_len as f32)).min(1.0);
+        }
         if aln_frac_at_best_retained < self.min_aligned_fraction {
             // The best retained alignment did not have sufficient
             // coverage to be kept
@@ -1050,32 +2020,93 @@ impl AlignmentFilters {
         // if we got here, then we have a valid "best" alignment
         discard_table.valid_best_aln += 1;
 
-        let mut probabilities = Vec::<f32>::with_capacity(ag.len());
         let mscore = best_retained_score as f32;
         let inv_max_score = 1.0 / mscore;
 
         // get a vector of all of the scores
         let mut scores: Vec<i32> = ag
             .iter_mut()
-            .map(|a| a.aln_score().unwrap_or(0) as i32)
+            .map(|a| a.typed_score(self.score_type).unwrap_or(0) as i32)
             .collect();
 
+        // if decoys are in use, record how the read aligns to them
+        if let Some(decoy_start) = self.decoy_start {
+            self.record_decoy_scores(discard_table, aln_header, ag, &scores, decoy_start);
+        }
+
         let _min_allowed_score = self.score_threshold * mscore;
 
         for score in scores.iter_mut() {
-            const SCORE_PROB_DENOM: f32 = 5.0;
             let fscore = *score as f32;
             let score_ok = (fscore * inv_max_score) >= self.score_threshold; //>= thresh_score;
-            if score_ok {
-                //let f = ((fscore - mscore) / (mscore - min_allowed_score)) * SCORE_PROB_DENOM;
-                let f = (fscore - mscore) / SCORE_PROB_DENOM;
-                probabilities.push(f.exp());
-            } else {
+            if !score_ok {
                 *score = i32::MIN;
                 discard_table.discard_score += 1;
             }
         }
 
+        // apply the secondary alignment policy to the alignments that
+        // passed the score filter.
+        match self.secondary_policy {
+            SecondaryPolicy::UseAll => {}
+            SecondaryPolicy::BestScoreOnly => {
+                for score in scores.iter_mut().filter(|s| **s > i32::MIN) {
+                    if *score < best_retained_score {
+                        *score = i32::MIN;
+                        discard_table.discard_secondary += 1;
+                    }
+                }
+            }
+            SecondaryPolicy::TopK => {
+                let mut kept: Vec<usize> = (0..scores.len())
+                    .filter(|i| scores[*i] > i32::MIN)
+                    .collect();
+                if kept.len() > self.secondary_top_k as usize {
+                    // stable, so ties are broken in favor of the earlier record
+                    kept.sort_by(|a, b| scores[*b].cmp(&scores[*a]));
+                    for i in kept.into_iter().skip(self.secondary_top_k as usize) {
+                        scores[i] = i32::MIN;
+                        discard_table.discard_secondary += 1;
+                    }
+                }
+            }
+            SecondaryPolicy::Drop => {
+                let has_primary = ag
+                    .iter()
+                    .zip(scores.iter())
+                    .any(|(a, s)| *s > i32::MIN && !a.is_secondary());
+                // if the primary alignment itself was filtered, then keep
+                // the secondary alignments rather than losing the read.
+                if has_primary {
+                    for (a, s) in ag.iter().zip(scores.iter_mut()) {
+                        if *s > i32::MIN && a.is_secondary() {
+                            *s = i32::MIN;
+                            discard_table.discard_secondary += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        const SCORE_PROB_DENOM: f32 = 5.0;
+        let probabilities: Vec<f32> = match self.score_calibration.as_deref() {
+            // the probability of each alignment, relative to that of the best alignment
+            Some(cal) if seq_len > 0 => {
+                let best_prob = cal.prob(best_retained_score, seq_len as usize);
+                scores
+                    .iter()
+                    .filter(|s| **s > i32::MIN)
+                    .map(|s| cal.prob(*s, seq_len as usize) / best_prob)
+                    .collect()
+            }
+            _ => scores
+                .iter()
+                .filter(|s| **s > i32::MIN)
+                //let f = ((fscore - mscore) / (mscore - min_allowed_score)) * SCORE_PROB_DENOM;
+                .map(|s| ((*s as f32 - mscore) / SCORE_PROB_DENOM).exp())
+                .collect(),
+        };
+
         let mut score_it = scores.iter();
         ag.retain(|_| *score_it.next().unwrap() > i32::MIN);
         assert_eq!(ag.len(), probabilities.len());
@@ -1091,7 +2122,8 @@ impl AlignmentFilters {
 
 #[cfg(test)]
 mod tests {
-    use crate::util::oarfish_types::AlnInfo;
+    use crate::prog_opts::{IdentityType, SecondaryPolicy};
+    use crate::util::oarfish_types::{AlnInfo, CigarOp, identity_from_cigar_ops};
     use bio_types::strand::Strand;
 
     #[test]
@@ -1105,4 +2137,20 @@ mod tests {
         };
         assert_eq!(ainf.alignment_span(), 100);
     }
+
+    #[test]
+    fn identity_is_correct() {
+        // 90M 5I 5M 3D 100M with NM = 2 mismatches + 5 + 3 = 10
+        let ops = vec![
+            (CigarOp::Match, 90),
+            (CigarOp::Insertion, 5),
+            (CigarOp::Match, 5),
+            (CigarOp::Deletion, 3),
+            (CigarOp::Match, 100),
+        ];
+        let blast = identity_from_cigar_ops(ops.clone().into_iter(), 10, IdentityType::Blast);
+        assert_eq!(blast, Some(193.0 / 203.0));
+        let gc = identity_from_cigar_ops(ops.into_iter(), 10, IdentityType::GapCompressed);
+        assert_eq!(gc, Some(1.0 - 4.0 / 197.0));
+    }
 }
@@ -0,0 +1,277 @@
+use crate::prog_opts::IoBackend;
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+use crate::util::errors::bad_input;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::util::uring_reader::UringReader;
+use anyhow::Context;
+use crossbeam::channel::{Receiver, bounded};
+use object_store::aws::AmazonS3Builder;
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::{BackoffConfig, ObjectStore, RetryConfig};
+use std::collections::VecDeque;
+use std::fs::{File, remove_file};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use url::Url;
+
+/// The size of each range read of a remote object.
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+/// The number of range reads that are in flight at once.
+const CONCURRENT_REQUESTS: usize = 4;
+/// The number of fetched chunks that may be waiting to be read.
+const PREFETCH_CHUNKS: usize = 4;
+/// The number of times a range read is attempted before giving up; this is in addition
+/// to the retries of the individual requests by the object store client, and also
+/// covers failures while the body of the response is being received.
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Returns true if `path` is an object storage (`s3://` or `gs://`) URL.
+pub fn is_remote(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|p| p.starts_with("s3://") || p.starts_with("gs://"))
+}
+
+fn retry_config() -> RetryConfig {
+    RetryConfig {
+        max_retries: 10,
+        retry_timeout: Duration::from_secs(300),
+        backoff: BackoffConfig::default(),
+    }
+}
+
+/// The object store holding the object at `url`, and the path of the object within it.
+/// The credentials are taken from the environment (e.g. `AWS_ACCESS_KEY_ID` and
+/// `AWS_REGION`, or `GOOGLE_APPLICATION_CREDENTIALS`).
+fn open_store(url: &str) -> anyhow::Result<(Arc<dyn ObjectStore>, object_store::path::Path)> {
+    let parsed = Url::parse(url).with_context(|| format!("{} is not a valid URL", url))?;
+    let store: Arc<dyn ObjectStore> = match parsed.scheme() {
+        "s3" => Arc::new(
+            AmazonS3Builder::from_env()
+                .with_url(url)
+                .with_retry(retry_config())
+                .build()?,
+        ),
+        "gs" => Arc::new(
+            GoogleCloudStorageBuilder::from_env()
+                .with_url(url)
+                .with_retry(retry_config())
+                .build()?,
+        ),
+        s => anyhow::bail!("unsupported object storage scheme {:?} in {}", s, url),
+    };
+    let path = object_store::path::Path::from_url_path(parsed.path())?;
+    Ok((store, path))
+}
+
+/// Read the given `range` of the object at `path`, retrying (with exponential backoff)
+/// up to [MAX_ATTEMPTS] times.
+async fn fetch_range(
+    store: Arc<dyn ObjectStore>,
+    path: object_store::path::Path,
+    range: std::ops::Range<u64>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match store.get_range(&path, range.clone()).await {
+            Ok(bytes) => return Ok(Vec::from(bytes)),
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                warn!(
+                    "reading bytes {}..{} of {} failed (attempt {} of {}) : {}; retrying.",
+                    range.start, range.end, path, attempt, MAX_ATTEMPTS, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "could not read bytes {}..{} of {} after {} attempts",
+                        range.start, range.end, path, MAX_ATTEMPTS
+                    )
+                });
+            }
+        }
+    }
+}
+
+/// Streams an object from object storage; a background thread issues concurrent range
+/// reads of consecutive chunks of the object and hands them over in order.
+pub struct RemoteReader {
+    chunks: Receiver<anyhow::Result<Vec<u8>>>,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl RemoteReader {
+    /// Open the object at `url`, returning the reader and the size of the object.
+    pub fn open(url: &str) -> anyhow::Result<(Self, u64)> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+        let (store, path) = open_store(url)?;
+        let size = runtime
+            .block_on(store.head(&path))
+            .with_context(|| format!("could not access {}", url))?
+            .size;
+
+        let (tx, rx) = bounded(PREFETCH_CHUNKS);
+        std::thread::spawn(move || {
+            let mut in_flight = VecDeque::with_capacity(CONCURRENT_REQUESTS);
+            let mut next_offset = 0_u64;
+            loop {
+                while in_flight.len() < CONCURRENT_REQUESTS && next_offset < size {
+                    let end = (next_offset + CHUNK_SIZE).min(size);
+                    in_flight.push_back(runtime.spawn(fetch_range(
+                        store.clone(),
+                        path.clone(),
+                        next_offset..end,
+                    )));
+                    next_offset = end;
+                }
+                let Some(handle) = in_flight.pop_front() else {
+                    break;
+                };
+                let chunk = runtime
+                    .block_on(handle)
+                    .unwrap_or_else(|e| Err(anyhow::anyhow!("range read task failed : {}", e)));
+                let failed = chunk.is_err();
+                // stop if the reader has gone away, or the object can not be read
+                if tx.send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok((
+            Self {
+                chunks: rx,
+                current: Vec::new(),
+                pos: 0,
+            },
+            size,
+        ))
+    }
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => {
+                    self.current = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(io::Error::other(e)),
+                // the whole object has been read
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Open the input at `path`, which is either a local file or an object storage URL,
+/// returning a reader and the size of the input. Local files are read with the given
+/// `backend`.
+pub fn open_input(path: &Path, backend: IoBackend) -> anyhow::Result<(Box<dyn Read + Send>, u64)> {
+    if is_remote(path) {
+        let url = path.to_str().expect("object storage URLs are valid UTF-8");
+        info!("streaming {} from object storage.", url);
+        let (reader, size) = RemoteReader::open(url)?;
+        Ok((Box::new(reader), size))
+    } else if backend == IoBackend::Uring {
+        open_uring(path)
+    } else {
+        let file =
+            File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+        let len = file.metadata()?.len();
+        Ok((Box::new(file), len))
+    }
+}
+
+/// Open the local file at `path` to be read through io_uring, falling back to ordinary
+/// reads if an io_uring can not be created.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn open_uring(path: &Path) -> anyhow::Result<(Box<dyn Read + Send>, u64)> {
+    match UringReader::open(path) {
+        Ok((reader, size)) => Ok((Box::new(reader), size)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("could not open {}", path.display()))
+        }
+        Err(e) => {
+            warn!(
+                "could not read {} through io_uring ({}); using ordinary reads instead.",
+                path.display(),
+                e
+            );
+            open_input(path, IoBackend::Threaded)
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+fn open_uring(_path: &Path) -> anyhow::Result<(Box<dyn Read + Send>, u64)> {
+    Err(bad_input(
+        "--io-backend uring requires oarfish to be built for Linux with the `io-uring` feature",
+    ))
+}
+
+/// A temporary local file (such as a copy of a remote input), which is removed
+/// when this is dropped.
+pub struct StagedFile {
+    path: PathBuf,
+}
+
+impl StagedFile {
+    /// Take ownership of the (temporary) file at `path`.
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if let Err(e) = remove_file(&self.path) {
+            warn!(
+                "could not remove the staged file {} : {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Download the object at the URL `path` to a temporary local file, for inputs
+/// (such as references) that must be read from local disk.
+pub fn stage_remote(path: &Path) -> anyhow::Result<StagedFile> {
+    let url = path.to_str().expect("object storage URLs are valid UTF-8");
+    let name = url.rsplit('/').next().unwrap_or("object");
+    let local = std::env::temp_dir().join(format!("oarfish-{}-{}", std::process::id(), name));
+    let (mut reader, size) = RemoteReader::open(url)?;
+    // the file is removed, even if the download fails, once this is dropped
+    let staged = StagedFile::new(local);
+    let mut writer = BufWriter::new(File::create(staged.path())?);
+    io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    info!(
+        "staged {} ({} bytes) to {}.",
+        url,
+        size,
+        staged.path().display()
+    );
+    Ok(staged)
+}
@@ -0,0 +1,411 @@
+use crate::util::compressed_writer::open_maybe_compressed;
+use crate::util::digest_utils;
+use crate::util::provenance;
+use anyhow::Context;
+use path_tools::WithAdditionalExtension;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The version of the schema of the output written by this version of oarfish.
+/// This should be incremented whenever the layout or meaning of any of the
+/// output files (or of the keys of `meta_info.json`) changes, and a corresponding
+/// upgrade step should be added to [upgrade_info].
+///
+/// Schema history:
+///  * 1 : outputs written before the schema was versioned (no `schema_version` key).
+///  * 2 : `meta_info.json` records `schema_version` and `oarfish_version`.
+///  * 3 : the `alignments` key of `meta_info.json` is a list of the input BAM files.
+pub const OUTPUT_SCHEMA_VERSION: u64 = 3;
+
+/// The oldest schema version that [read_output_info] will accept (upgrading it
+/// in memory to the current schema).
+pub const MIN_SUPPORTED_SCHEMA_VERSION: u64 = 1;
+
+/// The header expected at the top of a bulk `.quant` file.
+const QUANT_HEADER: &str = "tname\tlen\tnum_reads";
+
+/// A column of a (tab-separated) output file that was renamed by a schema version.
+struct ColumnRename {
+    /// the schema version that renamed the column
+    version: u64,
+    /// the file holding the column, as the suffix of the output prefix (e.g. `.quant`)
+    file: &'static str,
+    from: &'static str,
+    to: &'static str,
+}
+
+/// The columns renamed by each schema version, which are renamed (in this order) in the
+/// outputs of older schema versions when they are migrated, and in their headers when
+/// they are read. When a column is renamed, [OUTPUT_SCHEMA_VERSION] is incremented and
+/// the rename is added here; no column has been renamed by schema versions 2 and 3.
+const COLUMN_RENAMES: &[ColumnRename] = &[];
+
+/// The `header` of the file `file` of an output of schema version `from`, with its columns
+/// given their names in the current schema according to `renames`.
+fn upgrade_header(header: &str, file: &str, from: u64, renames: &[ColumnRename]) -> String {
+    header
+        .split('\t')
+        .map(|col| {
+            renames
+                .iter()
+                .filter(|r| r.version > from && r.file == file)
+                .fold(col, |c, r| if c == r.from { r.to } else { c })
+        })
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// Returns the keys that identify the schema of the output, to be added to
+/// the `meta_info.json` of every run.
+pub fn schema_json() -> Value {
+    json!({
+        "schema_version": OUTPUT_SCHEMA_VERSION,
+        "oarfish_version": env!("CARGO_PKG_VERSION"),
+    })
+}
+
+/// Insert the keys of [schema_json], and the provenance of the run (if it was recorded),
+/// into the (object) `info`.
+pub fn add_schema_info(info: &mut Value) {
+    if let (Some(obj), Value::Object(schema)) = (info.as_object_mut(), schema_json()) {
+        obj.extend(schema);
+        if let Some(p) = provenance::json() {
+            obj.insert("provenance".to_owned(), p);
+        }
+    }
+}
+
+/// The schema version of the output described by `info`; outputs written
+/// before the schema was versioned are schema version 1.
+pub fn schema_version(info: &Value) -> u64 {
+    info.get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(1)
+}
+
+/// Upgrade the `meta_info.json` contents `info`, of schema version `from`, to
+/// the current schema version in place.
+pub fn upgrade_info(info: &mut Value, from: u64) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        info.is_object(),
+        "the meta_info.json file does not contain a JSON object"
+    );
+    let mut version = from;
+    while version < OUTPUT_SCHEMA_VERSION {
+        match version {
+            1 => {
+                // the version of oarfish that wrote a v1 output was never recorded
+                info["oarfish_version"] = json!("unknown");
+                info["schema_version"] = json!(2);
+            }
+            2 => {
+                // a single input BAM file (or none, in read mode) was recorded as a string (or null)
+                let alignments = match info.get("alignments") {
+                    Some(Value::String(path)) => json!([path]),
+                    Some(Value::Array(paths)) => Value::Array(paths.clone()),
+                    _ => json!([]),
+                };
+                info["alignments"] = alignments;
+                info["schema_version"] = json!(3);
+            }
+            v => anyhow::bail!("no upgrade path from output schema version {}", v),
+        }
+        version += 1;
+    }
+    info["migrated_from_schema_version"] = json!(from);
+    Ok(())
+}
+
+/// The (upgraded) metadata of an existing oarfish output.
+pub struct OutputInfo {
+    /// the output prefix (i.e. what was passed as `--output`)
+    pub prefix: PathBuf,
+    /// the contents of `meta_info.json`, upgraded to the current schema
+    pub info: Value,
+    /// the schema version that the output was written with
+    pub original_schema_version: u64,
+}
+
+impl OutputInfo {
+    /// true if this output was produced in single-cell mode
+    pub fn is_single_cell(&self) -> bool {
+        self.info
+            .get("single_cell")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// true if this output holds USA-mode (spliced, unspliced and ambiguous) gene counts
+    pub fn is_usa_mode(&self) -> bool {
+        self.info
+            .get("usa_mode")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// true if this output holds transcript-compatibility class (TCC) counts
+    pub fn is_tcc(&self) -> bool {
+        self.info
+            .get("tcc")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// The `header` line of the tab-separated file `file` (e.g. `.quant`) of this output,
+    /// with its columns given their names in the current schema, so that the readers of
+    /// the output need only know the current names.
+    pub fn upgrade_header(&self, file: &str, header: &str) -> String {
+        upgrade_header(header, file, self.original_schema_version, COLUMN_RENAMES)
+    }
+}
+
+/// Read the metadata of the oarfish output with prefix `prefix`, accepting
+/// any schema version from [MIN_SUPPORTED_SCHEMA_VERSION] up to the current
+/// one. Older metadata is upgraded in memory (nothing is written).
+pub fn read_output_info(prefix: &Path) -> anyhow::Result<OutputInfo> {
+    let prefix = prefix.to_path_buf();
+    let info_path = prefix.with_additional_extension(".meta_info.json");
+    let file = std::fs::File::open(&info_path)
+        .with_context(|| format!("could not open {}", info_path.display()))?;
+    let mut info: Value = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("could not parse {}", info_path.display()))?;
+
+    let original_schema_version = schema_version(&info);
+    if original_schema_version > OUTPUT_SCHEMA_VERSION {
+        anyhow::bail!(
+            "{} has output schema version {}, but this version of oarfish only understands versions up to {}; please upgrade oarfish.",
+            info_path.display(),
+            original_schema_version,
+            OUTPUT_SCHEMA_VERSION
+        );
+    }
+    if original_schema_version < MIN_SUPPORTED_SCHEMA_VERSION {
+        anyhow::bail!(
+            "{} has output schema version {}, which is no longer supported (the oldest supported version is {}).",
+            info_path.display(),
+            original_schema_version,
+            MIN_SUPPORTED_SCHEMA_VERSION
+        );
+    }
+    if original_schema_version < OUTPUT_SCHEMA_VERSION {
+        upgrade_info(&mut info, original_schema_version)?;
+    }
+
+    Ok(OutputInfo {
+        prefix,
+        info,
+        original_schema_version,
+    })
+}
+
+/// Check that the `.quant` file of the bulk output `out` has the expected header (once
+/// its columns are renamed as in the current schema).
+fn check_quant_header(out: &OutputInfo) -> anyhow::Result<()> {
+    let quant_path = out.prefix.with_additional_extension(".quant");
+    let mut reader = open_maybe_compressed(&quant_path)
+        .with_context(|| format!("could not open {}", quant_path.display()))?;
+    // the header follows the provenance comment, if there is one
+    let mut header = String::new();
+    while header.is_empty() || header.starts_with('#') {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 {
+            break;
+        }
+    }
+    anyhow::ensure!(
+        out.upgrade_header(".quant", header.trim_end()) == QUANT_HEADER,
+        "unexpected header in {}; expected {:?} but found {:?}",
+        quant_path.display(),
+        QUANT_HEADER,
+        header.trim_end()
+    );
+    Ok(())
+}
+
+/// Rename the columns of the (uncompressed) tab-separated files of the output `out` that
+/// were renamed since its schema version, keeping the original of each rewritten file in
+/// `<file>.v<N>.bak`.
+fn migrate_columns(out: &OutputInfo) -> anyhow::Result<()> {
+    let mut files: Vec<&str> = COLUMN_RENAMES
+        .iter()
+        .filter(|r| r.version > out.original_schema_version)
+        .map(|r| r.file)
+        .collect();
+    files.sort_unstable();
+    files.dedup();
+    for file in files {
+        let path = out.prefix.with_additional_extension(file);
+        if !path.exists() {
+            continue;
+        }
+        let backup_path =
+            path.with_additional_extension(&format!(".v{}.bak", out.original_schema_version));
+        std::fs::rename(&path, &backup_path)
+            .with_context(|| format!("could not back up {} before migrating", path.display()))?;
+        let lines = BufReader::new(std::fs::File::open(&backup_path)?).lines();
+        let mut writer = BufWriter::new(std::fs::File::create(&path)?);
+        // the header follows the provenance comment, if there is one
+        let mut renamed = false;
+        for line in lines {
+            let line = line?;
+            if renamed || line.starts_with('#') {
+                writeln!(writer, "{}", line)?;
+            } else {
+                writeln!(writer, "{}", out.upgrade_header(file, &line))?;
+                renamed = true;
+            }
+        }
+        writer.flush()?;
+        info!("renamed the columns of {}.", path.display());
+    }
+    Ok(())
+}
+
+/// Find the output prefixes to consider given `path`, which is either an output
+/// prefix, or a directory, in which case every output (identified by its
+/// `meta_info.json` file) directly within the directory is returned.
+fn find_output_prefixes(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    const INFO_SUFFIX: &str = ".meta_info.json";
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut prefixes = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if let Some(fname) = entry_path.file_name().and_then(|f| f.to_str())
+            && let Some(stem) = fname.strip_suffix(INFO_SUFFIX)
+        {
+            prefixes.push(path.join(stem));
+        }
+    }
+    prefixes.sort();
+    if prefixes.is_empty() {
+        warn!("found no oarfish outputs in {}", path.display());
+    }
+    Ok(prefixes)
+}
+
+/// Upgrade the oarfish footer of the minimap2 index `path` to the current version of the
+/// reference digest, as described in [migrate_outputs].
+fn migrate_index(path: &Path, dry_run: bool) -> anyhow::Result<()> {
+    if digest_utils::migrate_mm2_index_footer(path, dry_run)? {
+        info!(
+            "{}upgraded the footer of the index {} to the current version.",
+            if dry_run { "(dry run) " } else { "" },
+            path.display()
+        );
+    } else {
+        info!(
+            "the footer of the index {} is already at the current version.",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Upgrade the outputs given by `paths` (each an output prefix, or a directory
+/// containing outputs) to the current output schema, renaming the columns that were
+/// renamed since. The original metadata of each upgraded output is kept in
+/// `<prefix>.meta_info.json.v<N>.bak` (and each rewritten table in `<file>.v<N>.bak`).
+/// A path may also be a minimap2 index built by oarfish, whose footer (holding the digest
+/// of the reference) is upgraded to the current version in place. If `dry_run` is true,
+/// report what would be done without modifying any files.
+pub fn migrate_outputs(paths: &[PathBuf], dry_run: bool) -> anyhow::Result<()> {
+    for path in paths {
+        if digest_utils::has_mm2_index_footer(path) {
+            migrate_index(path, dry_run)?;
+            continue;
+        }
+        for prefix in find_output_prefixes(path)? {
+            let out = read_output_info(&prefix)?;
+            if !out.is_single_cell() {
+                check_quant_header(&out)?;
+            }
+
+            if out.original_schema_version == OUTPUT_SCHEMA_VERSION {
+                info!(
+                    "{} is already at output schema version {}.",
+                    prefix.display(),
+                    OUTPUT_SCHEMA_VERSION
+                );
+                continue;
+            }
+
+            info!(
+                "{}upgrading {} from output schema version {} to {}.",
+                if dry_run { "(dry run) " } else { "" },
+                prefix.display(),
+                out.original_schema_version,
+                OUTPUT_SCHEMA_VERSION
+            );
+            if dry_run {
+                continue;
+            }
+
+            let info_path = prefix.with_additional_extension(".meta_info.json");
+            let backup_ext = format!(".meta_info.json.v{}.bak", out.original_schema_version);
+            let backup_path = prefix.with_additional_extension(&backup_ext);
+            std::fs::copy(&info_path, &backup_path).with_context(|| {
+                format!("could not back up {} before migrating", info_path.display())
+            })?;
+            migrate_columns(&out)?;
+
+            let write = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&info_path)
+                .with_context(|| format!("could not create {}", info_path.display()))?;
+            serde_json::ser::to_writer_pretty(write, &out.info)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RENAMES: &[ColumnRename] = &[
+        ColumnRename {
+            version: 4,
+            file: ".quant",
+            from: "len",
+            to: "length",
+        },
+        ColumnRename {
+            version: 5,
+            file: ".quant",
+            from: "length",
+            to: "tlen",
+        },
+        ColumnRename {
+            version: 5,
+            file: ".ambig_info.tsv",
+            from: "num_reads",
+            to: "reads",
+        },
+    ];
+
+    #[test]
+    fn renames_since_the_schema_are_applied_in_order() {
+        let header = "tname\tlen\tnum_reads";
+        assert_eq!(
+            upgrade_header(header, ".quant", 3, RENAMES),
+            "tname\ttlen\tnum_reads"
+        );
+        assert_eq!(
+            upgrade_header("tname\tlength\tnum_reads", ".quant", 4, RENAMES),
+            "tname\ttlen\tnum_reads"
+        );
+    }
+
+    #[test]
+    fn current_and_other_files_are_unchanged() {
+        let header = "tname\tlen\tnum_reads";
+        assert_eq!(upgrade_header(header, ".quant", 5, RENAMES), header);
+        assert_eq!(upgrade_header(header, ".infreps.tsv", 3, RENAMES), header);
+    }
+}
@@ -1,44 +1,178 @@
+use crate::prog_opts::{OutputCompression, OutputFormat};
+use crate::util::provenance;
+use anyhow::Context;
 use arrow2::{
-    array::Array,
+    array::{Array, Float64Array},
     chunk::Chunk,
     datatypes::Schema,
+    io::ipc,
+    io::parquet::read,
     io::parquet::write::{
         CompressionOptions, Encoding, FileWriter, RowGroupIterator, Version, WriteOptions,
         transverse,
     },
 };
 use std::fs::File;
+use std::path::Path;
 
-/// Write a chunk of values `chunk` to a file specified at the
-/// provided `path` using `scheme`. This raises an [anyhow::Error] if
-/// there is an error in creating the file or writing the contents.
-pub(crate) fn write_chunk_to_file(
-    path: &str,
+/// Writes a table, as a sequence of chunks sharing a schema, to either a parquet file
+/// (each chunk becoming a row group) or an Arrow IPC file (each chunk becoming a
+/// record batch).
+pub(crate) enum TableWriter {
+    Parquet {
+        writer: FileWriter<File>,
+        schema: Schema,
+        options: WriteOptions,
+    },
+    Arrow(ipc::write::FileWriter<File>),
+}
+
+impl TableWriter {
+    /// Create the file at `path`, to hold a table of `schema` in `format`, which
+    /// must be a tabular (i.e. not text) format, compressed with `compression`. By
+    /// default, parquet files are zstd-compressed and Arrow files are uncompressed;
+    /// as the Arrow IPC format does not support gzip, gzip compression of an Arrow
+    /// file is done with zstd instead. The provenance of the run (if it was recorded)
+    /// is added to the metadata of the schema.
+    pub(crate) fn try_new(
+        path: &Path,
+        mut schema: Schema,
+        format: OutputFormat,
+        compression: Option<OutputCompression>,
+    ) -> anyhow::Result<Self> {
+        schema.metadata.extend(provenance::table_metadata());
+        let file =
+            File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+        match format {
+            OutputFormat::Parquet => {
+                let options = WriteOptions {
+                    write_statistics: true,
+                    compression: match compression {
+                        Some(OutputCompression::None) => CompressionOptions::Uncompressed,
+                        Some(OutputCompression::Gzip) => CompressionOptions::Gzip(None),
+                        Some(OutputCompression::Zstd) | None => CompressionOptions::Zstd(None),
+                    },
+                    version: Version::V2,
+                    data_pagesize_limit: None,
+                };
+                let writer = FileWriter::try_new(file, schema.clone(), options)?;
+                Ok(Self::Parquet {
+                    writer,
+                    schema,
+                    options,
+                })
+            }
+            OutputFormat::Arrow => {
+                let compression = match compression {
+                    Some(OutputCompression::Gzip | OutputCompression::Zstd) => {
+                        Some(ipc::write::Compression::ZSTD)
+                    }
+                    Some(OutputCompression::None) | None => None,
+                };
+                let options = ipc::write::WriteOptions { compression };
+                Ok(Self::Arrow(ipc::write::FileWriter::try_new(
+                    file, schema, None, options,
+                )?))
+            }
+            OutputFormat::Tsv => anyhow::bail!("cannot write a table as tab-separated text"),
+        }
+    }
+
+    pub(crate) fn write(&mut self, chunk: Chunk<Box<dyn Array>>) -> anyhow::Result<()> {
+        match self {
+            Self::Parquet {
+                writer,
+                schema,
+                options,
+            } => {
+                let encodings = schema
+                    .fields
+                    .iter()
+                    .map(|f| transverse(&f.data_type, |_| Encoding::Plain))
+                    .collect::<Vec<Vec<Encoding>>>();
+                let row_groups = RowGroupIterator::try_new(
+                    std::iter::once(Ok(chunk)),
+                    schema,
+                    *options,
+                    encodings,
+                )?;
+                for group in row_groups {
+                    writer.write(group?)?;
+                }
+            }
+            Self::Arrow(writer) => writer.write(&chunk, None)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Self::Parquet { mut writer, .. } => {
+                let _size = writer.end(None)?;
+            }
+            Self::Arrow(mut writer) => writer.finish()?,
+        }
+        Ok(())
+    }
+}
+
+/// Write the single chunk `chunk`, with schema `schema`, as a table in `format`
+/// (compressed with `compression`) to `path`.
+pub(crate) fn write_table(
+    path: &Path,
     schema: Schema,
     chunk: Chunk<Box<dyn Array>>,
+    format: OutputFormat,
+    compression: Option<OutputCompression>,
 ) -> anyhow::Result<()> {
-    let options = WriteOptions {
-        write_statistics: true,
-        compression: CompressionOptions::Zstd(None),
-        version: Version::V2,
-        data_pagesize_limit: None,
-    };
+    let mut writer = TableWriter::try_new(path, schema, format, compression)?;
+    writer.write(chunk)?;
+    writer.finish()
+}
 
-    // Create a new empty file
-    let file = File::create(path)?;
+/// The chunks read from a parquet or an Arrow IPC file.
+type ChunkIter = Box<dyn Iterator<Item = arrow2::error::Result<Chunk<Box<dyn Array>>>>>;
 
-    let mut writer = FileWriter::try_new(file, schema.clone(), options)?;
+/// Read every (`f64`-valued) column of the parquet (or, if its extension is `.arrow`,
+/// Arrow IPC) file at `path`, such as the bootstrap replicates written by [write_table],
+/// returning the values of each column in turn.
+pub(crate) fn read_f64_columns(path: &Path) -> anyhow::Result<Vec<Vec<f64>>> {
+    let mut file =
+        File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let (num_cols, chunks): (usize, ChunkIter) = if path.extension().is_some_and(|e| e == "arrow") {
+        let metadata = ipc::read::read_file_metadata(&mut file)?;
+        let num_cols = metadata.schema.fields.len();
+        (
+            num_cols,
+            Box::new(ipc::read::FileReader::new(file, metadata, None, None)),
+        )
+    } else {
+        let metadata = read::read_metadata(&mut file)?;
+        let schema = read::infer_schema(&metadata)?;
+        let num_cols = schema.fields.len();
+        (
+            num_cols,
+            Box::new(read::FileReader::new(
+                file,
+                metadata.row_groups,
+                schema,
+                None,
+                None,
+                None,
+            )),
+        )
+    };
 
-    let iter = vec![Ok(chunk)];
-    let encodings = schema
-        .fields
-        .iter()
-        .map(|f| transverse(&f.data_type, |_| Encoding::Plain))
-        .collect::<Vec<Vec<Encoding>>>();
-    let row_groups = RowGroupIterator::try_new(iter.into_iter(), &schema, options, encodings)?;
-    for group in row_groups {
-        writer.write(group?)?;
+    let mut cols = vec![Vec::<f64>::new(); num_cols];
+    for chunk in chunks {
+        let chunk = chunk?;
+        for (col, arr) in cols.iter_mut().zip(chunk.arrays()) {
+            let arr = arr
+                .as_any()
+                .downcast_ref::<Float64Array>()
+                .with_context(|| format!("a column of {} is not of type f64", path.display()))?;
+            col.extend_from_slice(arr.values());
+        }
     }
-    let _size = writer.end(None)?;
-    Ok(())
+    Ok(cols)
 }
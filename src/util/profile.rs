@@ -0,0 +1,255 @@
+use path_tools::WithAdditionalExtension;
+use rustc_hash::FxHashMap;
+use serde_json::json;
+use std::cell::RefCell;
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Instant;
+use tracing::info;
+
+/// Whether `--profile` was given; when it was not, [scope] does nothing.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The (self) time spent in each stack of stages by each thread that has recorded any.
+static THREADS: LazyLock<Mutex<Vec<Arc<ThreadTimes>>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+/// A stage of the pipeline whose time is recorded with `--profile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// waiting for the decompressed bytes of a BAM file
+    Decompression,
+    /// decoding alignment records, or reading raw reads
+    Parsing,
+    /// mapping raw reads with minimap2
+    Alignment,
+    /// filtering the alignments of each read and computing their (alignment score and
+    /// coverage) probabilities
+    Probabilities,
+    /// the EM algorithm
+    Em,
+    /// blocked on a full (or empty) queue between the stages of the pipeline; large values
+    /// indicate that the stage on the other side of the queue is the bottleneck
+    QueueWait,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Decompression => "decompression",
+            Stage::Parsing => "parsing",
+            Stage::Alignment => "alignment",
+            Stage::Probabilities => "probabilities",
+            Stage::Em => "em",
+            Stage::QueueWait => "queue_wait",
+        }
+    }
+}
+
+/// The times recorded by one thread: the self time (in nanoseconds, excluding that of
+/// nested stages) of each stack of stages, keyed by the `;`-separated names of the stack.
+struct ThreadTimes {
+    name: String,
+    self_nanos: Mutex<FxHashMap<String, u64>>,
+}
+
+/// A stage in progress on the current thread.
+struct Frame {
+    stage: Stage,
+    start: Instant,
+    child_nanos: u64,
+}
+
+struct ThreadState {
+    times: Arc<ThreadTimes>,
+    stack: Vec<Frame>,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<ThreadState>> = const { RefCell::new(None) };
+    static THREAD_NAME: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Enable (or disable) the recording of the time spent in each [Stage], discarding the
+/// times recorded by an earlier run of the process.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    for t in THREADS.lock().expect("profile lock poisoned").iter() {
+        t.self_nanos.lock().expect("profile lock poisoned").clear();
+    }
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Name the current thread (e.g. `mapper-2`) in the profile; this must be called before
+/// the thread records any time. Threads that are not named are identified by their
+/// own name, if they have one, or by their id.
+pub fn name_thread(name: String) {
+    if is_enabled() {
+        THREAD_NAME.with(|n| *n.borrow_mut() = Some(name));
+    }
+}
+
+fn with_state<T>(f: impl FnOnce(&mut ThreadState) -> T) -> T {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let state = s.get_or_insert_with(|| {
+            let name = THREAD_NAME.with(|n| n.borrow().clone()).unwrap_or_else(|| {
+                let t = std::thread::current();
+                t.name()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("{:?}", t.id()))
+            });
+            let times = Arc::new(ThreadTimes {
+                name,
+                self_nanos: Mutex::new(FxHashMap::default()),
+            });
+            THREADS
+                .lock()
+                .expect("profile lock poisoned")
+                .push(times.clone());
+            ThreadState {
+                times,
+                stack: Vec::new(),
+            }
+        });
+        f(state)
+    })
+}
+
+/// Records the time from its creation until it is dropped as spent in its stage
+/// (see [scope]).
+pub struct Scope(());
+
+/// Start recording the time spent in `stage` on the current thread, until the returned
+/// guard is dropped, if profiling is enabled. Scopes may be nested, in which case the
+/// time of the inner scope is attributed to the stack of both stages.
+#[inline]
+pub fn scope(stage: Stage) -> Option<Scope> {
+    if !is_enabled() {
+        return None;
+    }
+    with_state(|s| {
+        s.stack.push(Frame {
+            stage,
+            start: Instant::now(),
+            child_nanos: 0,
+        })
+    });
+    Some(Scope(()))
+}
+
+/// Run `f`, recording the time it takes as spent in `stage` (see [scope]).
+#[inline]
+pub fn time<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+    let _scope = scope(stage);
+    f()
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        with_state(|s| {
+            let Some(frame) = s.stack.pop() else {
+                return;
+            };
+            let elapsed = frame.start.elapsed().as_nanos() as u64;
+            let mut key = String::new();
+            for f in s.stack.iter() {
+                key.push_str(f.stage.name());
+                key.push(';');
+            }
+            key.push_str(frame.stage.name());
+            *s.times
+                .self_nanos
+                .lock()
+                .expect("profile lock poisoned")
+                .entry(key)
+                .or_insert(0) += elapsed.saturating_sub(frame.child_nanos);
+            if let Some(parent) = s.stack.last_mut() {
+                parent.child_nanos += elapsed;
+            }
+        })
+    }
+}
+
+/// A reader that records the time spent waiting in its reads as spent in `stage`; e.g.
+/// wrapping a bgzf reader, the time the parser waits for the decompression threads.
+pub struct TimedRead<R> {
+    inner: R,
+    stage: Stage,
+}
+
+impl<R> TimedRead<R> {
+    pub fn new(inner: R, stage: Stage) -> Self {
+        Self { inner, stage }
+    }
+}
+
+impl<R: Read> Read for TimedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let _scope = scope(self.stage);
+        self.inner.read(buf)
+    }
+}
+
+impl<R: BufRead> BufRead for TimedRead<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let _scope = scope(self.stage);
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+/// Write the profile recorded so far to `<output>.profile.folded`, in the "folded stacks"
+/// format read by flamegraph tools (`<thread>;<stage>[;<stage>...] <microseconds>` on
+/// each line), and return the total time (in seconds) of each stage, summed over the
+/// threads, along with the number of threads that recorded it.
+pub fn write_profile(output: &Path) -> anyhow::Result<serde_json::Value> {
+    let threads = THREADS.lock().expect("profile lock poisoned");
+    let path = output
+        .to_path_buf()
+        .with_additional_extension(".profile.folded");
+    let mut writer = BufWriter::new(std::fs::File::create(&path)?);
+    // the self time of each stage, and the number of threads that recorded it
+    let mut totals: FxHashMap<String, (f64, usize)> = FxHashMap::default();
+    for t in threads.iter() {
+        let times = t.self_nanos.lock().expect("profile lock poisoned");
+        let mut stacks: Vec<(&String, &u64)> = times.iter().collect();
+        stacks.sort();
+        let mut thread_stages = Vec::new();
+        for (stack, nanos) in stacks {
+            writeln!(writer, "{};{} {}", t.name, stack, nanos / 1000)?;
+            // the innermost stage of the stack
+            let stage = stack.rsplit(';').next().unwrap_or(stack);
+            let e = totals.entry(stage.to_string()).or_insert((0.0, 0));
+            e.0 += *nanos as f64 * 1e-9;
+            if !thread_stages.contains(&stage) {
+                thread_stages.push(stage);
+                e.1 += 1;
+            }
+        }
+    }
+    writer.flush()?;
+
+    let mut stages: Vec<_> = totals.into_iter().collect();
+    stages.sort_by(|a, b| b.1.0.total_cmp(&a.1.0));
+    for (stage, (secs, nthreads)) in stages.iter() {
+        info!(
+            "profile: {:.2}s in {} (over {} threads).",
+            secs, stage, nthreads
+        );
+    }
+    info!("wrote the profile of the run to {}.", path.display());
+    let summary: serde_json::Map<String, serde_json::Value> = stages
+        .into_iter()
+        .map(|(stage, (secs, nthreads))| (stage, json!({ "secs": secs, "threads": nthreads })))
+        .collect();
+    Ok(serde_json::Value::Object(summary))
+}
@@ -0,0 +1,127 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// true if progress bars should be drawn
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// true if the iterations of the EM should be tracked
+static TRACK_EM: AtomicBool = AtomicBool::new(true);
+
+/// All progress bars are drawn through this, so that concurrent bars
+/// (e.g. BAM traversal and alignment processing) are interleaved rather
+/// than overwriting each other, and so that log messages can be written
+/// without corrupting the bars (see [SuspendingStderr]).
+static MULTI: LazyLock<MultiProgress> =
+    LazyLock::new(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr_with_hz(4)));
+
+const TICK_CHARS: &str = "⠁⠁⠉⠙⠚⠒⠂⠂⠒⠲⠴⠤⠄⠄⠤⠠⠠⠤⠦⠖⠒⠐⠐⠒⠓⠋⠉⠈⠈";
+
+/// Enable the drawing of progress bars, unless the user asked for `quiet`
+/// output or stderr is not a terminal.
+pub fn init(quiet: bool) {
+    let enabled = !quiet && io::stderr().is_terminal();
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Set whether the iterations of the EM should be tracked; this is
+/// disabled in single-cell mode, where many short EM runs (one per
+/// cell) proceed concurrently.
+pub fn set_track_em(track: bool) {
+    TRACK_EM.store(track, Ordering::Relaxed);
+}
+
+fn add(pb: ProgressBar) -> ProgressBar {
+    if ENABLED.load(Ordering::Relaxed) {
+        MULTI.add(pb)
+    } else {
+        ProgressBar::hidden()
+    }
+}
+
+/// A spinner counting the items (e.g. reads or alignments) processed
+/// so far, along with the rate at which they are being processed.
+pub fn counter(msg: &'static str) -> ProgressBar {
+    let pb = add(ProgressBar::new_spinner().with_message(msg));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {spinner:4.green/blue} {msg} {human_pos:>12} ({per_sec})",
+        )
+        .unwrap()
+        .tick_chars(TICK_CHARS),
+    );
+    pb
+}
+
+/// A bar tracking the iterations of the EM algorithm, up to `max_iter`.
+pub fn em_iterations(max_iter: u32) -> ProgressBar {
+    if !TRACK_EM.load(Ordering::Relaxed) {
+        return ProgressBar::hidden();
+    }
+    let pb = add(ProgressBar::new(max_iter as u64));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:30.green/blue} EM iteration {pos}/{len} {msg}",
+        )
+        .unwrap(),
+    );
+    pb
+}
+
+/// A bar tracking the bootstrap replicates completed, out of `num_boot`.
+pub fn bootstrap_replicates(num_boot: u32) -> ProgressBar {
+    let pb = add(ProgressBar::new(num_boot as u64));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:30.green/blue} bootstrap replicate {pos}/{len} (eta {eta})",
+        )
+        .unwrap(),
+    );
+    pb
+}
+
+/// Wraps a reader, tracking the number of bytes read from it (out of
+/// the total `len`) in a progress bar with an ETA. The bar is removed
+/// once the end of the input is reached.
+pub struct ProgressReader<R> {
+    inner: R,
+    pb: ProgressBar,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.pb.finish_and_clear();
+        } else {
+            self.pb.inc(n as u64);
+        }
+        Ok(n)
+    }
+}
+
+/// Track the progress of reading `len` bytes from `inner`.
+pub fn track_read<R: Read>(inner: R, len: u64, msg: &'static str) -> ProgressReader<R> {
+    let pb = add(ProgressBar::new(len).with_message(msg));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:30.green/blue} {msg} {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta})",
+        )
+        .unwrap(),
+    );
+    ProgressReader { inner, pb }
+}
+
+/// A writer to stderr that hides any progress bars while writing, so
+/// that log messages are interleaved cleanly with the bars.
+pub struct SuspendingStderr;
+
+impl Write for SuspendingStderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        MULTI.suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
@@ -0,0 +1,271 @@
+use crate::prog_opts::Args;
+use crate::util::object_store_io;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// The key under which the provenance of a run is stored in the metadata of its tables
+/// and in the `@CO` line of its BAM output.
+pub const PROVENANCE_KEY: &str = "oarfish_provenance";
+
+/// What is known of the provenance of the current run.
+struct Provenance {
+    /// the parts of the provenance known as the run starts
+    base: Value,
+    digest: Option<Value>,
+    /// the thread describing (and hashing) the input files, while it runs, so that hashing
+    /// large inputs does not delay the run
+    pending_inputs: Option<JoinHandle<Vec<Value>>>,
+    inputs: Vec<Value>,
+    /// whether the provenance is also recorded in the tab-separated and text outputs
+    in_tsv: bool,
+}
+
+static PROVENANCE: Mutex<Option<Provenance>> = Mutex::new(None);
+
+/// The current time, in UTC, as an RFC 3339 timestamp (e.g. `2025-01-31T12:00:00Z`).
+fn utc_timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()) as i64;
+    format_utc(secs)
+}
+
+/// The time `secs` seconds after the Unix epoch, in UTC, as an RFC 3339 timestamp.
+fn format_utc(secs: i64) -> String {
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // the civil date of the day `days` since the epoch (after H. Hinnant's `civil_from_days`)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+/// The name of the host on which oarfish is running, if it can be determined.
+fn hostname() -> Option<String> {
+    let mut buf = [0_u8; 256];
+    // SAFETY: the buffer is valid for writes of its length
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// The SHA-256 digest (in hex) of the file at `path`.
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0_u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The size and (unless `hash` is false) the SHA-256 digest of each of the local `paths`;
+/// remote inputs, and those that are not regular files (e.g. pipes), are listed without
+/// either.
+fn describe_inputs(paths: Vec<PathBuf>, hash: bool) -> Vec<Value> {
+    paths
+        .into_iter()
+        .map(|path| {
+            if object_store_io::is_remote(&path) || !path.is_file() {
+                return json!({ "path": path, "size": null, "sha256": null });
+            }
+            let size = std::fs::metadata(&path).map(|m| m.len()).ok();
+            let sha256 = hash
+                .then(|| match sha256_file(&path) {
+                    Ok(h) => Some(h),
+                    Err(e) => {
+                        warn!("could not hash the input {}: {}", path.display(), e);
+                        None
+                    }
+                })
+                .flatten();
+            json!({ "path": path, "size": size, "sha256": sha256 })
+        })
+        .collect()
+}
+
+/// Start recording the provenance of the run described by `args`: the version of oarfish,
+/// its command line and resolved options, the time at which the run started and the host
+/// on which it runs. The input files (the alignments, reads, reference or alignment cache)
+/// are described, and hashed if `--input-hashes` was given, by a background thread.
+pub fn start(args: &Args) {
+    let base = json!({
+        "oarfish_version": env!("CARGO_PKG_VERSION"),
+        "command_line": std::env::args().collect::<Vec<String>>(),
+        "options": args,
+        "timestamp": utc_timestamp(),
+        "hostname": hostname(),
+    });
+    let paths: Vec<PathBuf> = args
+        .alignments
+        .iter()
+        .chain(args.control_alignments.iter())
+        .chain(args.reads.iter().flatten())
+        .chain(args.reference.iter())
+        .chain(args.from_alignment_cache.iter())
+        .cloned()
+        .collect();
+    let hash = args.input_hashes;
+    let pending_inputs = std::thread::spawn(move || describe_inputs(paths, hash));
+    *PROVENANCE.lock().expect("provenance lock poisoned") = Some(Provenance {
+        base,
+        digest: None,
+        pending_inputs: Some(pending_inputs),
+        inputs: Vec::new(),
+        in_tsv: args.tsv_provenance,
+    });
+}
+
+/// Record the digest of the reference of the current run.
+pub fn set_reference_digest(digest: &seqcol_rs::DigestResult) {
+    if let Some(p) = PROVENANCE
+        .lock()
+        .expect("provenance lock poisoned")
+        .as_mut()
+    {
+        p.digest = Some(digest.to_json());
+    }
+}
+
+/// The provenance of the current run known as it starts (i.e. without the reference
+/// digest or the input hashes), if a run has started; this is what is recorded in the
+/// header of the BAM output, which is written from the start of the run.
+pub fn header_json() -> Option<Value> {
+    PROVENANCE
+        .lock()
+        .expect("provenance lock poisoned")
+        .as_ref()
+        .map(|p| p.base.clone())
+}
+
+/// The full provenance of the current run, if a run has started (waiting, if need be,
+/// for the inputs to be hashed); this is what is recorded in `meta_info.json` and in the
+/// sentinel of the run, which are written once its other outputs are.
+pub fn json() -> Option<Value> {
+    let mut guard = PROVENANCE.lock().expect("provenance lock poisoned");
+    let p = guard.as_mut()?;
+    if let Some(handle) = p.pending_inputs.take() {
+        p.inputs = handle.join().unwrap_or_else(|_| {
+            warn!("the thread hashing the input files panicked.");
+            Vec::new()
+        });
+    }
+    let mut v = p.base.clone();
+    v["reference_digest"] = json!(p.digest);
+    v["inputs"] = json!(p.inputs);
+    Some(v)
+}
+
+/// The provenance of the current run without its inputs, if a run has started; unlike
+/// [json], this never waits for the inputs to be hashed.
+fn json_without_inputs() -> Option<Value> {
+    let guard = PROVENANCE.lock().expect("provenance lock poisoned");
+    let p = guard.as_ref()?;
+    let mut v = p.base.clone();
+    v["reference_digest"] = json!(p.digest);
+    Some(v)
+}
+
+/// The key-value metadata recording the provenance of the current run in a (parquet or
+/// Arrow) table, if a run has started. The tables are written while the inputs may still
+/// be hashed, so their provenance leaves out the inputs (see [json]).
+pub fn table_metadata() -> BTreeMap<String, String> {
+    json_without_inputs()
+        .map(|v| BTreeMap::from([(PROVENANCE_KEY.to_owned(), v.to_string())]))
+        .unwrap_or_default()
+}
+
+/// Whether the provenance of the current run is recorded in its tab-separated and text
+/// outputs (with `--tsv-provenance`).
+fn in_tsv() -> bool {
+    PROVENANCE
+        .lock()
+        .expect("provenance lock poisoned")
+        .as_ref()
+        .is_some_and(|p| p.in_tsv)
+}
+
+/// The comment line (`# oarfish_provenance: {...}`) recording the full provenance of the
+/// current run at the top of its tab-separated and text outputs, if a run has started
+/// with `--tsv-provenance`.
+pub fn tsv_comment() -> Option<String> {
+    if !in_tsv() {
+        return None;
+    }
+    json().map(|v| format!("# {}: {}", PROVENANCE_KEY, v))
+}
+
+/// Write the provenance comment line ([tsv_comment]), if there is one, to `writer`, at the
+/// top of a tab-separated or text output.
+pub fn write_tsv_comment<W: Write>(writer: &mut W) -> io::Result<()> {
+    if let Some(comment) = tsv_comment() {
+        writeln!(writer, "{}", comment)?;
+    }
+    Ok(())
+}
+
+/// As [write_tsv_comment], for the outputs that are written as the run proceeds (e.g. the
+/// per-cell QC metrics), whose provenance, like that of the tables, leaves out the inputs,
+/// so that writing them does not wait for the inputs to be hashed.
+pub fn write_tsv_comment_without_inputs<W: Write>(writer: &mut W) -> io::Result<()> {
+    if in_tsv()
+        && let Some(v) = json_without_inputs()
+    {
+        writeln!(writer, "# {}: {}", PROVENANCE_KEY, v)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(-1), "1969-12-31T23:59:59Z");
+        // leap days, including those of the century rules
+        assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_utc(4_107_542_399), "2100-02-28T23:59:59Z");
+        assert_eq!(format_utc(1_738_324_800), "2025-01-31T12:00:00Z");
+        assert_eq!(format_utc(253_402_300_799), "9999-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn timestamp_is_rfc3339() {
+        let ts = utc_timestamp();
+        assert_eq!(ts.len(), "2025-01-31T12:00:00Z".len());
+        assert!(ts.ends_with('Z') && ts.as_bytes()[10] == b'T');
+        assert!(ts.as_str() >= "2025");
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The estimates of a bulk sample.
+#[derive(Debug, Default)]
+pub struct BulkEstimates {
+    /// the names and lengths of the reported transcripts
+    pub names: Vec<String>,
+    pub lengths: Vec<u64>,
+    /// the estimated number of reads of each reported transcript
+    pub counts: Vec<f64>,
+    /// the estimates of the reported transcripts in each bootstrap replicate, indexed by
+    /// replicate (empty if no replicates were computed)
+    pub bootstraps: Vec<Vec<f64>>,
+    pub meta_info: serde_json::Value,
+}
+
+/// The count matrix of a single-cell sample, as (0-based) `(row, col, value)` triplets;
+/// the row of a cell is its index in the collated input.
+#[derive(Debug, Default)]
+pub struct CellCounts {
+    pub rows: Vec<u32>,
+    pub cols: Vec<u32>,
+    pub vals: Vec<f32>,
+    pub num_cols: usize,
+    /// the barcode of each row
+    pub barcodes: Vec<String>,
+    /// the name of each column
+    pub features: Vec<String>,
+    pub meta_info: serde_json::Value,
+}
+
+/// The estimates of a run, as they are written to its outputs.
+#[derive(Debug)]
+pub enum Estimates {
+    Bulk(BulkEstimates),
+    SingleCell(CellCounts),
+}
+
+/// Holds the estimates of a run for a caller that embeds oarfish (e.g. the Python
+/// bindings), so that it can use them directly rather than read them back from the
+/// outputs. It is filled, as the outputs are written, by a run whose [Args] carry it.
+///
+/// [Args]: crate::prog_opts::Args
+#[derive(Debug, Default)]
+pub struct QuantResults {
+    bulk: Mutex<BulkEstimates>,
+    cells: Mutex<CellCounts>,
+    is_single_cell: AtomicBool,
+}
+
+impl QuantResults {
+    /// Record the estimates `counts` of the transcripts named `names` (of lengths `lengths`)
+    /// and the metadata `meta_info` of a bulk sample.
+    pub fn set_bulk(
+        &self,
+        names: Vec<String>,
+        lengths: Vec<u64>,
+        counts: Vec<f64>,
+        meta_info: serde_json::Value,
+    ) {
+        let mut bulk = self.bulk.lock().expect("results lock poisoned");
+        bulk.names = names;
+        bulk.lengths = lengths;
+        bulk.counts = counts;
+        bulk.meta_info = meta_info;
+    }
+
+    /// Record the estimates `rep` of replicate `i` (of `num_reps`) of a bulk sample.
+    pub fn add_replicate(&self, i: usize, num_reps: usize, rep: Vec<f64>) {
+        let mut bulk = self.bulk.lock().expect("results lock poisoned");
+        if bulk.bootstraps.len() < num_reps {
+            bulk.bootstraps.resize(num_reps, Vec::new());
+        }
+        bulk.bootstraps[i] = rep;
+    }
+
+    /// Record the non-zero counts `entries` (as `(col, value)` pairs) of the cell with
+    /// barcode `barcode` and (0-based) row `row`.
+    pub fn add_cell(&self, row: usize, barcode: &[u8], entries: &[(u32, f32)]) {
+        let mut cells = self.cells.lock().expect("results lock poisoned");
+        if cells.barcodes.len() <= row {
+            cells.barcodes.resize(row + 1, String::new());
+        }
+        cells.barcodes[row] = String::from_utf8_lossy(barcode).into_owned();
+        for &(col, val) in entries {
+            cells.rows.push(row as u32);
+            cells.cols.push(col);
+            cells.vals.push(val);
+        }
+    }
+
+    /// Record the `num_cols` columns, named `features`, and the metadata `meta_info` of
+    /// the count matrix of a single-cell sample, once all of its cells are quantified.
+    pub fn finish_cells(
+        &self,
+        num_cols: usize,
+        features: Vec<String>,
+        meta_info: serde_json::Value,
+    ) {
+        let mut cells = self.cells.lock().expect("results lock poisoned");
+        cells.num_cols = num_cols;
+        cells.features = features;
+        cells.meta_info = meta_info;
+        self.is_single_cell.store(true, Ordering::Release);
+    }
+
+    /// Take the estimates recorded by the run.
+    pub fn take(&self) -> Estimates {
+        if self.is_single_cell.load(Ordering::Acquire) {
+            Estimates::SingleCell(std::mem::take(
+                &mut *self.cells.lock().expect("results lock poisoned"),
+            ))
+        } else {
+            Estimates::Bulk(std::mem::take(
+                &mut *self.bulk.lock().expect("results lock poisoned"),
+            ))
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use crate::util::biotypes::BiotypeSummary;
+use crate::util::cell_qc::CellQc;
+use crate::util::contaminants::ContaminantReport;
+use crate::util::oarfish_types::InMemoryAlignmentStore;
+use crate::util::provenance;
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// The number of transcripts (or, in single-cell mode, features) listed in `summary.txt`.
+const SUMMARY_TOP_N: usize = 25;
+/// The number of transcripts listed in the condensed summary written to the log.
+const LOG_TOP_N: usize = 5;
+
+/// The headline statistics of a (bulk) run, along with its most
+/// highly-expressed transcripts.
+struct QuickSummary {
+    num_input_reads: usize,
+    num_aligned_reads: usize,
+    num_unique_reads: usize,
+    num_assigned_reads: f64,
+    num_expressed: usize,
+    num_txps: usize,
+    /// (name, length, num_reads, TPM) of the top transcripts by TPM
+    top: Vec<(String, usize, f64, f64)>,
+}
+
+fn percent(num: f64, denom: usize) -> String {
+    if denom > 0 {
+        format!("{:.2}%", 100.0 * num / denom as f64)
+    } else {
+        "NA".to_string()
+    }
+}
+
+impl QuickSummary {
+    fn new(
+        header: &noodles_sam::header::Header,
+        counts: &[f64],
+        store: &InMemoryAlignmentStore,
+    ) -> Self {
+        let lens: Vec<usize> = header
+            .reference_sequences()
+            .values()
+            .map(|rmap| rmap.length().get())
+            .collect();
+        let rates: Vec<f64> = counts
+            .iter()
+            .zip(lens.iter())
+            .map(|(c, l)| c / *l as f64)
+            .collect();
+        let rate_sum: f64 = rates.iter().sum();
+        let tpm = |i: usize| {
+            if rate_sum > 0.0 {
+                1e6 * rates[i] / rate_sum
+            } else {
+                0.0
+            }
+        };
+
+        let mut order: Vec<usize> = (0..counts.len()).collect();
+        order.sort_unstable_by(|a, b| rates[*b].total_cmp(&rates[*a]));
+        let top = order
+            .iter()
+            .take(SUMMARY_TOP_N)
+            .filter(|i| counts[**i] > 0.0)
+            .map(|i| {
+                let (name, _) = header
+                    .reference_sequences()
+                    .get_index(*i)
+                    .expect("transcript should be present in the header");
+                (name.to_string(), lens[*i], counts[*i], tpm(*i))
+            })
+            .collect();
+
+        Self {
+            num_input_reads: store.num_input_reads,
+            num_aligned_reads: store.num_aligned_reads(),
+            num_unique_reads: store.unique_alignments(),
+            num_assigned_reads: counts.iter().sum(),
+            num_expressed: counts.iter().filter(|c| **c > 0.0).count(),
+            num_txps: counts.len(),
+            top,
+        }
+    }
+
+    fn write_stats<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let input = if self.num_input_reads > 0 {
+            self.num_input_reads.to_formatted_string(&Locale::en)
+        } else {
+            "unknown".to_string()
+        };
+        writeln!(w, "input reads            : {}", input)?;
+        writeln!(
+            w,
+            "aligned reads          : {} ({} of input)",
+            self.num_aligned_reads.to_formatted_string(&Locale::en),
+            percent(self.num_aligned_reads as f64, self.num_input_reads)
+        )?;
+        writeln!(
+            w,
+            "uniquely aligned reads : {} ({} of aligned)",
+            self.num_unique_reads.to_formatted_string(&Locale::en),
+            percent(self.num_unique_reads as f64, self.num_aligned_reads)
+        )?;
+        writeln!(
+            w,
+            "assigned reads         : {:.2} ({} of aligned)",
+            self.num_assigned_reads,
+            percent(self.num_assigned_reads, self.num_aligned_reads)
+        )?;
+        writeln!(
+            w,
+            "expressed transcripts  : {} of {}",
+            self.num_expressed.to_formatted_string(&Locale::en),
+            self.num_txps.to_formatted_string(&Locale::en)
+        )
+    }
+}
+
+/// Write a short, human-readable summary of the run (the headline library
+/// statistics, the [SUMMARY_TOP_N] transcripts with the highest TPM and, if
+/// available, the aggregate estimates of each biotype and the reads of each
+/// contaminant category) of a bulk run to `<output>.summary.txt`, and a condensed
+/// version of it to the log. The summary of a single-cell run is written by
+/// [write_single_cell_summary].
+pub fn write_quick_summary(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    counts: &[f64],
+    store: &InMemoryAlignmentStore,
+    biotypes: Option<&[BiotypeSummary]>,
+    contaminants: Option<&ContaminantReport>,
+) -> io::Result<()> {
+    let summary = QuickSummary::new(header, counts, store);
+
+    let out_path = output.with_additional_extension(".summary.txt");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    writeln!(writer, "oarfish {} run summary", env!("CARGO_PKG_VERSION"))?;
+    writeln!(writer)?;
+    summary.write_stats(&mut writer)?;
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "top {} transcripts by TPM:",
+        SUMMARY_TOP_N.min(summary.top.len())
+    )?;
+    writeln!(writer, "rank\ttname\tlen\tnum_reads\tTPM")?;
+    for (rank, (name, len, nreads, tpm)) in summary.top.iter().enumerate() {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{:.2}\t{:.2}",
+            rank + 1,
+            name,
+            len,
+            nreads,
+            tpm
+        )?;
+    }
+    if let Some(biotypes) = biotypes {
+        writeln!(writer)?;
+        writeln!(writer, "reads by biotype:")?;
+        writeln!(writer, "biotype\tnum_expressed\tnum_reads\tTPM")?;
+        for s in biotypes {
+            writeln!(
+                writer,
+                "{}\t{}\t{:.2}\t{:.2}",
+                s.biotype, s.num_expressed, s.num_reads, s.tpm
+            )?;
+        }
+    }
+    if let Some(report) = contaminants {
+        writeln!(writer)?;
+        writeln!(writer, "reads by contaminant category:")?;
+        writeln!(writer, "category\tnum_txps\tnum_reads\tfrac_assigned")?;
+        for s in report.categories.iter() {
+            writeln!(
+                writer,
+                "{}\t{}\t{:.2}\t{:.4}",
+                s.category, s.num_txps, s.num_reads, s.frac_assigned
+            )?;
+        }
+        if report.excluded {
+            writeln!(
+                writer,
+                "(contaminant transcripts are excluded from the main output)"
+            )?;
+        }
+    }
+    writer.flush()?;
+
+    let mut stats = Vec::new();
+    summary.write_stats(&mut stats)?;
+    let top = summary
+        .top
+        .iter()
+        .take(LOG_TOP_N)
+        .map(|(name, _, _, tpm)| format!("{} ({:.1} TPM)", name, tpm))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let by_biotype = biotypes
+        .map(|bts| {
+            let total_reads: f64 = bts.iter().map(|s| s.num_reads).sum();
+            let bts = bts
+                .iter()
+                .take(LOG_TOP_N)
+                .map(|s| {
+                    let frac = if total_reads > 0.0 {
+                        s.num_reads / total_reads
+                    } else {
+                        0.0
+                    };
+                    format!("{} ({:.1}%)", s.biotype, 100.0 * frac)
+                })
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!("\nreads by biotype: {}", bts)
+        })
+        .unwrap_or_default();
+    let by_contaminant = contaminants
+        .map(|report| {
+            format!(
+                "\ncontaminant reads: {:.1}% of assigned",
+                100.0 * report.frac_assigned
+            )
+        })
+        .unwrap_or_default();
+    info!(
+        "run summary:\n{}top transcripts: {}{}{}",
+        String::from_utf8_lossy(&stats),
+        top,
+        by_biotype,
+        by_contaminant
+    );
+    Ok(())
+}
+
+/// The aggregate statistics of the cells of a single-cell run, gathered from the QC
+/// metrics and counts of each cell as it is quantified.
+#[derive(Debug, Default)]
+pub struct CellSummary {
+    reads: Vec<usize>,
+    /// the UMIs of the cells whose records have them
+    umis: Vec<usize>,
+    assigned_reads: Vec<usize>,
+    detected_transcripts: Vec<usize>,
+    /// the total count of each feature (column of the count matrix) over the cells
+    feature_counts: Vec<f64>,
+}
+
+impl CellSummary {
+    /// Add the cell with QC metrics `qc` and the nonzero `entries` (column, count) of
+    /// its row of the count matrix.
+    pub fn add_cell(&mut self, qc: &CellQc, entries: &[(u32, f32)]) {
+        self.reads.push(qc.reads());
+        self.umis.extend(qc.umis());
+        self.assigned_reads.push(qc.assigned_reads());
+        self.detected_transcripts.push(qc.detected_transcripts());
+        for (col, v) in entries {
+            let col = *col as usize;
+            if col >= self.feature_counts.len() {
+                self.feature_counts.resize(col + 1, 0.0);
+            }
+            self.feature_counts[col] += *v as f64;
+        }
+    }
+
+    /// Add the cells summarized in `other` (e.g. by another worker).
+    pub fn merge(&mut self, other: CellSummary) {
+        self.reads.extend(other.reads);
+        self.umis.extend(other.umis);
+        self.assigned_reads.extend(other.assigned_reads);
+        self.detected_transcripts.extend(other.detected_transcripts);
+        if other.feature_counts.len() > self.feature_counts.len() {
+            self.feature_counts.resize(other.feature_counts.len(), 0.0);
+        }
+        for (c, o) in self.feature_counts.iter_mut().zip(other.feature_counts) {
+            *c += o;
+        }
+    }
+
+    pub fn num_cells(&self) -> usize {
+        self.reads.len()
+    }
+}
+
+/// The median of `values` (which are reordered), or [None] if there are none.
+fn median(values: &mut [usize]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    Some(if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) as f64 / 2.0
+    } else {
+        values[mid] as f64
+    })
+}
+
+fn or_na(x: Option<f64>) -> String {
+    x.map_or(String::from("NA"), |x| format!("{:.1}", x))
+}
+
+/// Write a short, human-readable summary of a single-cell run (the number of cells,
+/// their total and median reads, UMIs, assigned reads and detected transcripts, and the
+/// [SUMMARY_TOP_N] features, named by `feature_name`, with the highest total count over
+/// the cells) to `<output>.summary.txt`, and a condensed version of it to the log. The
+/// `num_resumed` cells taken over from a checkpointed run are not part of `summary`.
+pub fn write_single_cell_summary<F: Fn(usize) -> String>(
+    output: &Path,
+    mut summary: CellSummary,
+    num_resumed: usize,
+    feature_name: F,
+) -> io::Result<()> {
+    let num_cells = summary.num_cells();
+    let total_reads: usize = summary.reads.iter().sum();
+    let total_assigned: usize = summary.assigned_reads.iter().sum();
+    let mut order: Vec<usize> = (0..summary.feature_counts.len())
+        .filter(|i| summary.feature_counts[*i] > 0.0)
+        .collect();
+    order
+        .sort_unstable_by(|a, b| summary.feature_counts[*b].total_cmp(&summary.feature_counts[*a]));
+    order.truncate(SUMMARY_TOP_N);
+    let top: Vec<(String, f64)> = order
+        .iter()
+        .map(|i| (feature_name(*i), summary.feature_counts[*i]))
+        .collect();
+
+    let mut stats = Vec::new();
+    writeln!(
+        stats,
+        "cells                          : {}",
+        num_cells.to_formatted_string(&Locale::en)
+    )?;
+    if num_resumed > 0 {
+        writeln!(
+            stats,
+            "cells resumed from checkpoint  : {} (not included in these statistics)",
+            num_resumed.to_formatted_string(&Locale::en)
+        )?;
+    }
+    writeln!(
+        stats,
+        "reads                          : {}",
+        total_reads.to_formatted_string(&Locale::en)
+    )?;
+    writeln!(
+        stats,
+        "assigned reads                 : {} ({} of reads)",
+        total_assigned.to_formatted_string(&Locale::en),
+        percent(total_assigned as f64, total_reads)
+    )?;
+    writeln!(
+        stats,
+        "median reads per cell          : {}",
+        or_na(median(&mut summary.reads))
+    )?;
+    writeln!(
+        stats,
+        "median UMIs per cell           : {}",
+        or_na(median(&mut summary.umis))
+    )?;
+    writeln!(
+        stats,
+        "median assigned reads per cell : {}",
+        or_na(median(&mut summary.assigned_reads))
+    )?;
+    writeln!(
+        stats,
+        "median detected transcripts    : {}",
+        or_na(median(&mut summary.detected_transcripts))
+    )?;
+
+    let out_path = output.with_additional_extension(".summary.txt");
+    let mut writer = BufWriter::new(File::create(out_path)?);
+    provenance::write_tsv_comment(&mut writer)?;
+    writeln!(
+        writer,
+        "oarfish {} single-cell run summary",
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(writer)?;
+    writer.write_all(&stats)?;
+    writeln!(writer)?;
+    writeln!(writer, "top {} features by total count:", top.len())?;
+    writeln!(writer, "rank\tfeature\ttotal_count")?;
+    for (rank, (name, count)) in top.iter().enumerate() {
+        writeln!(writer, "{}\t{}\t{:.2}", rank + 1, name, count)?;
+    }
+    writer.flush()?;
+
+    let top = top
+        .iter()
+        .take(LOG_TOP_N)
+        .map(|(name, count)| format!("{} ({:.1})", name, count))
+        .collect::<Vec<String>>()
+        .join(", ");
+    info!(
+        "run summary:\n{}top features: {}",
+        String::from_utf8_lossy(&stats),
+        top
+    );
+    Ok(())
+}
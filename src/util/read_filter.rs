@@ -0,0 +1,249 @@
+use crate::util::oarfish_types::AlnInfo;
+use anyhow::Context;
+use bio_types::strand::Strand;
+use std::ffi::{CString, c_char, c_void};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// The version of the read filter plugin interface described below. A plugin
+/// must report this version from `oarfish_read_filter_abi_version`.
+pub const READ_FILTER_ABI_VERSION: u32 = 1;
+
+/// A user-provided filter, called on the alignments of each read that pass
+/// the built-in filters (see [crate::util::oarfish_types::AlignmentFilters]),
+/// and before the read is added to the alignment store.
+pub trait ReadFilter: Send + fmt::Debug {
+    /// Decide which of the alignments `alns` (with alignment score probabilities
+    /// `as_probs`) of the read `name`, of length `read_len`, should be kept. On
+    /// entry, every entry of `keep` is true; the filter sets the entries of the
+    /// alignments to be discarded to false. Returns false if the read should be
+    /// discarded altogether.
+    fn filter(
+        &mut self,
+        name: &str,
+        read_len: u32,
+        alns: &[AlnInfo],
+        as_probs: &[f32],
+        keep: &mut [bool],
+    ) -> bool;
+}
+
+/// An alignment, as passed to a read filter plugin.
+#[repr(C)]
+pub struct PluginAlignment {
+    /// the index of the transcript (in the order of the names passed to
+    /// `oarfish_read_filter_init`)
+    pub ref_id: u32,
+    pub start: u32,
+    pub end: u32,
+    /// 1 for the forward strand, -1 for the reverse strand, 0 if unknown
+    pub strand: i8,
+    /// the probability of the alignment derived from its score
+    pub score_prob: f32,
+}
+
+/// The alignments of a read, as passed to a read filter plugin.
+#[repr(C)]
+pub struct PluginAlignmentGroup {
+    /// the (NUL-terminated) name of the read
+    pub read_name: *const c_char,
+    /// the length of the read, or 0 if unknown
+    pub read_len: u32,
+    pub num_alignments: usize,
+    pub alignments: *const PluginAlignment,
+}
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type InitFn = unsafe extern "C" fn(*const c_char, usize, *const *const c_char) -> *mut c_void;
+type ApplyFn = unsafe extern "C" fn(*mut c_void, *const PluginAlignmentGroup, *mut u8) -> i32;
+type FreeFn = unsafe extern "C" fn(*mut c_void);
+
+/// A read filter loaded from a native shared library (e.g. a Rust `cdylib`)
+/// exporting the following C ABI functions:
+///
+///  * `uint32_t oarfish_read_filter_abi_version(void)` : returns [READ_FILTER_ABI_VERSION].
+///  * `void* oarfish_read_filter_init(const char* config, size_t num_refs, const char* const* ref_names)` :
+///    (optional) called once with the user-provided configuration string (or NULL) and the names
+///    of the transcripts; returns the state passed to the other functions.
+///  * `int32_t oarfish_read_filter_apply(void* state, const PluginAlignmentGroup* group, uint8_t* keep)` :
+///    called on each read; `keep` holds one entry (initially 1) per alignment, to be set to 0 for
+///    each alignment that should be discarded. Returns non-zero to discard the read altogether.
+///  * `void oarfish_read_filter_free(void* state)` : (optional) called once, when the filter is dropped,
+///    with the state returned by `oarfish_read_filter_init`; it is not called if the plugin has no
+///    init function, or if that returned NULL.
+///
+/// The functions are never called concurrently. WebAssembly modules are not supported.
+pub struct DylibReadFilter {
+    path: PathBuf,
+    state: *mut c_void,
+    apply: ApplyFn,
+    free: Option<FreeFn>,
+    // scratch space for the arguments passed to `apply`
+    plugin_alns: Vec<PluginAlignment>,
+    plugin_keep: Vec<u8>,
+    // the library must outlive the function pointers taken from it
+    _lib: libloading::Library,
+}
+
+// SAFETY: the plugin state is only ever accessed through `&mut self`, so it is
+// never used from more than one thread at a time.
+unsafe impl Send for DylibReadFilter {}
+
+impl fmt::Debug for DylibReadFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DylibReadFilter")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl DylibReadFilter {
+    /// Load the read filter plugin at `path`, initializing it with the configuration
+    /// string `config` (if provided) and the names of the transcripts `txps_name`.
+    pub fn load(path: &Path, config: Option<&str>, txps_name: &[String]) -> anyhow::Result<Self> {
+        // SAFETY: loading a library runs its initialization routines; the user
+        // is trusted to provide a well-behaved plugin.
+        let lib = unsafe { libloading::Library::new(path) }
+            .with_context(|| format!("could not load read filter plugin {}", path.display()))?;
+
+        // SAFETY: the symbols are declared with the signatures given by the plugin interface.
+        let (abi_version, init, apply, free) = unsafe {
+            let abi_version = *lib
+                .get::<AbiVersionFn>(b"oarfish_read_filter_abi_version\0")
+                .context(
+                    "the read filter plugin does not export oarfish_read_filter_abi_version",
+                )?;
+            let init = lib
+                .get::<InitFn>(b"oarfish_read_filter_init\0")
+                .ok()
+                .map(|f| *f);
+            let apply = *lib
+                .get::<ApplyFn>(b"oarfish_read_filter_apply\0")
+                .context("the read filter plugin does not export oarfish_read_filter_apply")?;
+            let free = lib
+                .get::<FreeFn>(b"oarfish_read_filter_free\0")
+                .ok()
+                .map(|f| *f);
+            (abi_version, init, apply, free)
+        };
+
+        // SAFETY: takes no arguments
+        let version = unsafe { abi_version() };
+        anyhow::ensure!(
+            version == READ_FILTER_ABI_VERSION,
+            "the read filter plugin {} implements version {} of the plugin interface, but this version of oarfish requires version {}",
+            path.display(),
+            version,
+            READ_FILTER_ABI_VERSION
+        );
+
+        let state = match init {
+            Some(init) => {
+                let config = config
+                    .map(CString::new)
+                    .transpose()
+                    .context("the read filter configuration can not contain NUL characters")?;
+                let names = txps_name
+                    .iter()
+                    .map(|n| CString::new(n.as_str()))
+                    .collect::<Result<Vec<CString>, _>>()
+                    .context("transcript names can not contain NUL characters")?;
+                let name_ptrs: Vec<*const c_char> = names.iter().map(|n| n.as_ptr()).collect();
+                // SAFETY: the strings outlive the call
+                unsafe {
+                    init(
+                        config.as_ref().map_or(std::ptr::null(), |c| c.as_ptr()),
+                        name_ptrs.len(),
+                        name_ptrs.as_ptr(),
+                    )
+                }
+            }
+            None => std::ptr::null_mut(),
+        };
+
+        info!("loaded read filter plugin {}", path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            state,
+            apply,
+            free,
+            plugin_alns: Vec::new(),
+            plugin_keep: Vec::new(),
+            _lib: lib,
+        })
+    }
+}
+
+impl ReadFilter for DylibReadFilter {
+    fn filter(
+        &mut self,
+        name: &str,
+        read_len: u32,
+        alns: &[AlnInfo],
+        as_probs: &[f32],
+        keep: &mut [bool],
+    ) -> bool {
+        let read_name = CString::new(name.trim_end_matches('\0')).unwrap_or_default();
+        self.plugin_alns.clear();
+        self.plugin_alns.extend(
+            alns.iter()
+                .zip(as_probs.iter())
+                .map(|(a, p)| PluginAlignment {
+                    ref_id: a.ref_id,
+                    start: a.start,
+                    end: a.end,
+                    strand: match a.strand {
+                        Strand::Forward => 1,
+                        Strand::Reverse => -1,
+                        Strand::Unknown => 0,
+                    },
+                    score_prob: *p,
+                }),
+        );
+        self.plugin_keep.clear();
+        self.plugin_keep.resize(alns.len(), 1);
+
+        let group = PluginAlignmentGroup {
+            read_name: read_name.as_ptr(),
+            read_len,
+            num_alignments: self.plugin_alns.len(),
+            alignments: self.plugin_alns.as_ptr(),
+        };
+        // SAFETY: `group` and the buffers it points to outlive the call, and
+        // `plugin_keep` holds one entry per alignment.
+        let discard = unsafe { (self.apply)(self.state, &group, self.plugin_keep.as_mut_ptr()) };
+
+        for (k, pk) in keep.iter_mut().zip(self.plugin_keep.iter()) {
+            *k = *pk != 0;
+        }
+        discard == 0
+    }
+}
+
+impl Drop for DylibReadFilter {
+    fn drop(&mut self) {
+        if let Some(free) = self.free {
+            if !self.state.is_null() {
+                // SAFETY: `state` was returned by the plugin's init function and is freed once
+                unsafe { free(self.state) };
+            }
+        }
+    }
+}
+
+/// Load the read filter plugin at `path` (see [DylibReadFilter] for the interface it
+/// must implement), initializing it with `config` and the transcript names `txps_name`.
+pub fn load_read_filter(
+    path: &Path,
+    config: Option<&str>,
+    txps_name: &[String],
+) -> anyhow::Result<Box<dyn ReadFilter>> {
+    if path.extension().is_some_and(|e| e == "wasm") {
+        anyhow::bail!(
+            "WebAssembly read filters are not currently supported; please compile the filter {} as a native shared library (e.g. a Rust cdylib) instead.",
+            path.display()
+        );
+    }
+    Ok(Box::new(DylibReadFilter::load(path, config, txps_name)?))
+}
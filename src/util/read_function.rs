@@ -1,9 +1,13 @@
+use crate::util::biotypes::UNANNOTATED_BIOTYPE;
+use crate::util::errors;
 use crate::util::oarfish_types::ShortReadRecord;
 use anyhow::bail;
 use csv::ReaderBuilder;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use tracing::warn;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::{info, warn};
 
 /// Read the short read quantification from the file `short_read_path`
 pub fn read_short_quant_vec(
@@ -23,10 +27,7 @@ pub fn read_short_quant_vec(
     let records: HashMap<String, ShortReadRecord> = rdr
         .deserialize()
         .collect::<Result<Vec<ShortReadRecord>, csv::Error>>()
-        .unwrap_or_else(|err| {
-            eprintln!("Failed to deserialize CSV records: {}", err);
-            std::process::exit(1);
-        })
+        .map_err(|err| errors::bad_input(format!("Failed to deserialize CSV records: {}", err)))?
         .into_iter()
         .map(|rec| (rec.name.clone(), rec))
         .collect();
@@ -75,3 +76,226 @@ pub fn read_short_quant_vec(
 
     Ok(ordered_rec)
 }
+
+/// Read a file listing one transcript name per line (empty lines
+/// and lines starting with `#` are ignored).
+pub fn read_txp_name_list(path: &Path) -> anyhow::Result<HashSet<String>> {
+    let file = File::open(path)?;
+    let mut names = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let name = line.trim();
+        if !name.is_empty() && !name.starts_with('#') {
+            names.insert(name.to_owned());
+        }
+    }
+    Ok(names)
+}
+
+/// Determine which of the transcripts in `txps_name` should be excluded
+/// from quantification. If `keep_path` is provided, only the transcripts
+/// listed there are retained, and any transcripts listed in `exclude_path`
+/// are subsequently removed. The returned vector is `true` for each
+/// transcript that should be excluded.
+pub fn get_excluded_txp_mask(
+    keep_path: Option<&Path>,
+    exclude_path: Option<&Path>,
+    txps_name: &[String],
+) -> anyhow::Result<Vec<bool>> {
+    let mut excluded = vec![false; txps_name.len()];
+
+    if let Some(p) = keep_path {
+        let keep = read_txp_name_list(p)?;
+        let mut num_found = 0_usize;
+        for (name, ex) in txps_name.iter().zip(excluded.iter_mut()) {
+            if keep.contains(name) {
+                num_found += 1;
+            } else {
+                *ex = true;
+            }
+        }
+        if num_found < keep.len() {
+            warn!(
+                "{} transcripts in the keep list {} did not appear in the reference.",
+                keep.len() - num_found,
+                p.display()
+            );
+        }
+    }
+
+    if let Some(p) = exclude_path {
+        let exclude = read_txp_name_list(p)?;
+        for (name, ex) in txps_name.iter().zip(excluded.iter_mut()) {
+            if exclude.contains(name) {
+                *ex = true;
+            }
+        }
+    }
+
+    let num_excluded = excluded.iter().filter(|x| **x).count();
+    if num_excluded == txps_name.len() {
+        bail!(
+            "the provided transcript keep / exclude lists exclude every transcript in the reference; cannot proceed."
+        );
+    }
+    info!(
+        "excluding {} of {} transcripts from quantification.",
+        num_excluded,
+        txps_name.len()
+    );
+    Ok(excluded)
+}
+
+/// Read a file of per-transcript weights, consisting of (tab-separated) lines of
+/// the form `<transcript_name>\t<weight>` (empty lines and lines starting with `#`
+/// are ignored). The returned vector holds the weight of each transcript in
+/// `txps_name`; transcripts not listed in the file have a weight of 1.
+pub fn read_txp_weights(path: &Path, txps_name: &[String]) -> anyhow::Result<Vec<f64>> {
+    let file = File::open(path)?;
+    let mut weights = HashMap::new();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, weight)) = line.split_once('\t') else {
+            bail!(
+                "line {} of transcript weight file {} was not of the form <transcript>\\t<weight>",
+                lnum + 1,
+                path.display()
+            );
+        };
+        let weight = match weight.trim().parse::<f64>() {
+            Ok(w) if w.is_finite() && w >= 0.0 => w,
+            _ => bail!(
+                "line {} of transcript weight file {} has an invalid weight {:?}; weights must be non-negative numbers",
+                lnum + 1,
+                path.display(),
+                weight.trim()
+            ),
+        };
+        weights.insert(name.trim().to_owned(), weight);
+    }
+
+    let mut num_found = 0_usize;
+    let txp_weights: Vec<f64> = txps_name
+        .iter()
+        .map(|name| match weights.get(name) {
+            Some(w) => {
+                num_found += 1;
+                *w
+            }
+            None => 1.0_f64,
+        })
+        .collect();
+    if num_found < weights.len() {
+        warn!(
+            "{} transcripts in the weight file {} did not appear in the reference.",
+            weights.len() - num_found,
+            path.display()
+        );
+    }
+    if txp_weights.iter().all(|w| *w == 0.0) {
+        bail!(
+            "the provided transcript weights are 0 for every transcript in the reference; cannot proceed."
+        );
+    }
+    info!(
+        "read weights for {} of {} transcripts ({} with weight 0).",
+        num_found,
+        txps_name.len(),
+        txp_weights.iter().filter(|w| **w == 0.0).count()
+    );
+    Ok(txp_weights)
+}
+
+/// Read the biotype of each transcript from the tab-separated file at `path`, with
+/// lines of the form `<transcript>\t<biotype>` (empty lines and lines starting with
+/// `#` are ignored). Returns the biotype of each transcript in `txps_name`; transcripts
+/// not listed in the file are given the biotype [UNANNOTATED_BIOTYPE].
+pub fn read_txp_biotypes(path: &Path, txps_name: &[String]) -> anyhow::Result<Vec<String>> {
+    let file = File::open(path)?;
+    let mut biotypes = HashMap::new();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, biotype)) = line.split_once('\t') else {
+            bail!(
+                "line {} of biotype file {} was not of the form <transcript>\\t<biotype>",
+                lnum + 1,
+                path.display()
+            );
+        };
+        biotypes.insert(name.trim().to_owned(), biotype.trim().to_owned());
+    }
+
+    let mut num_found = 0_usize;
+    let txp_biotypes: Vec<String> = txps_name
+        .iter()
+        .map(|name| match biotypes.get(name) {
+            Some(bt) => {
+                num_found += 1;
+                bt.clone()
+            }
+            None => UNANNOTATED_BIOTYPE.to_owned(),
+        })
+        .collect();
+    if num_found < txps_name.len() {
+        warn!(
+            "{} transcripts in the reference did not appear in the biotype file {}; they will be reported as {}.",
+            txps_name.len() - num_found,
+            path.display(),
+            UNANNOTATED_BIOTYPE
+        );
+    }
+    info!(
+        "read biotypes for {} of {} transcripts.",
+        num_found,
+        txps_name.len()
+    );
+    Ok(txp_biotypes)
+}
+
+/// Read the gene of each transcript from the tab-separated file at `path`, with
+/// lines of the form `<transcript>\t<gene>` (empty lines and lines starting with
+/// `#` are ignored). Returns the gene of each transcript in `txps_name`, or [None]
+/// for transcripts not listed in the file.
+pub fn read_txp_genes(path: &Path, txps_name: &[String]) -> anyhow::Result<Vec<Option<String>>> {
+    let file = File::open(path)?;
+    let mut genes = HashMap::new();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, gene)) = line.split_once('\t') else {
+            bail!(
+                "line {} of transcript-to-gene file {} was not of the form <transcript>\\t<gene>",
+                lnum + 1,
+                path.display()
+            );
+        };
+        genes.insert(name.trim().to_owned(), gene.trim().to_owned());
+    }
+
+    let txp_genes: Vec<Option<String>> = txps_name.iter().map(|n| genes.get(n).cloned()).collect();
+    let num_found = txp_genes.iter().filter(|g| g.is_some()).count();
+    if num_found < txps_name.len() {
+        warn!(
+            "{} transcripts did not appear in the transcript-to-gene file {}; they will be ignored.",
+            txps_name.len() - num_found,
+            path.display()
+        );
+    }
+    info!(
+        "read genes for {} of {} transcripts.",
+        num_found,
+        txps_name.len()
+    );
+    Ok(txp_genes)
+}
@@ -0,0 +1,131 @@
+use crate::em;
+use crate::util::oarfish_types::EMInfo;
+use serde::Serialize;
+use tracing::{info, warn};
+
+/// Summary of the quantification performed within a single
+/// read-length stratum.
+#[derive(Debug, Serialize)]
+pub struct StratumSummary {
+    /// inclusive lower bound on the length of reads in this stratum
+    pub min_len: u32,
+    /// exclusive upper bound on the length of reads in this
+    /// stratum ([None] if the stratum is unbounded)
+    pub max_len: Option<u32>,
+    /// the number of (aligned) reads falling in this stratum
+    pub num_reads: usize,
+    /// total variation distance between the relative abundances
+    /// estimated from this stratum and from all reads.
+    pub tv_distance: f64,
+}
+
+impl StratumSummary {
+    /// The name used for this stratum in output column headers
+    pub fn label(&self) -> String {
+        match self.max_len {
+            Some(m) => format!("len_{}_{}", self.min_len, m),
+            None => format!("len_{}_inf", self.min_len),
+        }
+    }
+}
+
+/// The per-stratum estimates; `counts[s][t]` is the estimated
+/// number of reads in stratum `s` arising from transcript `t`.
+pub struct StrataResult {
+    pub summaries: Vec<StratumSummary>,
+    pub counts: Vec<Vec<f64>>,
+    /// the number of reads whose length is unknown (e.g. whose records
+    /// carry no sequence), which are left out of every stratum
+    pub num_unknown_length: usize,
+}
+
+/// Returns the index of the stratum, delimited by the sorted
+/// boundaries `bounds`, into which a read of length `len` falls, or
+/// [None] if its length is unknown (recorded as 0).
+#[inline]
+fn stratum_of(len: u32, bounds: &[u32]) -> Option<usize> {
+    (len > 0).then(|| bounds.partition_point(|b| *b <= len))
+}
+
+/// Total variation distance between the relative abundances implied by `a` and `b`.
+pub fn tv_distance(a: &[f64], b: &[f64]) -> f64 {
+    let sa: f64 = a.iter().sum();
+    let sb: f64 = b.iter().sum();
+    if sa <= 0.0 || sb <= 0.0 {
+        return 0.0;
+    }
+    0.5 * a
+        .iter()
+        .zip(b.iter())
+        .map(|(x, y)| ((x / sa) - (y / sb)).abs())
+        .sum::<f64>()
+}
+
+/// Run the EM using only the reads of `emi` whose indices are in `inds`,
+/// returning the estimated counts for each of the `num_txps` transcripts.
+pub fn quantify_read_subset(emi: &EMInfo, inds: &[usize], num_txps: usize) -> Vec<f64> {
+    if inds.is_empty() {
+        vec![0.0_f64; num_txps]
+    } else {
+        let make_iter = || emi.eq_map.random_sampling_iter(inds);
+        em::do_em(emi, make_iter, false)
+    }
+}
+
+/// Partition the reads in `emi` by read length according to `bounds`, and
+/// run the EM independently within each resulting stratum. The estimates of
+/// each stratum are compared against the estimates obtained from all reads
+/// (`counts`); large divergences between strata are indicative of
+/// truncation or internal-priming artifacts.
+pub fn quantify_by_read_length_strata(
+    emi: &EMInfo,
+    bounds: &[u32],
+    counts: &[f64],
+) -> StrataResult {
+    let mut bounds = bounds.to_vec();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let num_strata = bounds.len() + 1;
+    let mut strata_inds: Vec<Vec<usize>> = vec![Vec::new(); num_strata];
+    let mut num_unknown_length = 0_usize;
+    for (i, rl) in emi.eq_map.read_lengths.iter().enumerate() {
+        match stratum_of(*rl, &bounds) {
+            Some(s) => strata_inds[s].push(i),
+            None => num_unknown_length += 1,
+        }
+    }
+    if num_unknown_length > 0 {
+        warn!(
+            "{} reads of unknown length (e.g. without a sequence in their records) are left out of the read length strata.",
+            num_unknown_length
+        );
+    }
+
+    let mut summaries = Vec::with_capacity(num_strata);
+    let mut strata_counts = Vec::with_capacity(num_strata);
+    for (s, inds) in strata_inds.iter().enumerate() {
+        let scounts = quantify_read_subset(emi, inds, counts.len());
+
+        let summary = StratumSummary {
+            min_len: if s == 0 { 0 } else { bounds[s - 1] },
+            max_len: bounds.get(s).copied(),
+            num_reads: inds.len(),
+            tv_distance: tv_distance(&scounts, counts),
+        };
+        info!(
+            "read length stratum {} : {} reads, total variation distance from pooled estimate = {:.4}",
+            summary.label(),
+            summary.num_reads,
+            summary.tv_distance
+        );
+        summaries.push(summary);
+        strata_counts.push(scounts);
+    }
+
+    StrataResult {
+        summaries,
+        counts: strata_counts,
+        num_unknown_length,
+    }
+}
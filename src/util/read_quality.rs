@@ -0,0 +1,87 @@
+use crate::util::oarfish_types::ReadTags;
+use noodles_sam::alignment::record::Record;
+use noodles_sam::alignment::record::data::field::Value;
+use std::sync::LazyLock;
+
+/// The predicted accuracy of a read at which the alignment score probabilities are used
+/// as they are (Q20); the probabilities of more accurate reads are sharpened, and those
+/// of less accurate reads flattened.
+const REFERENCE_ACCURACY: f32 = 0.99;
+/// The bounds of the exponent applied to the alignment score probabilities of a read.
+const MIN_SHARPNESS: f32 = 0.5;
+const MAX_SHARPNESS: f32 = 2.0;
+
+/// The error probability of each phred-scaled base quality.
+static ERROR_PROBS: LazyLock<[f64; 256]> = LazyLock::new(|| {
+    let mut probs = [0.0; 256];
+    for (q, p) in probs.iter_mut().enumerate() {
+        *p = 10_f64.powf(-(q as f64) / 10.0);
+    }
+    probs
+});
+
+/// The predicted accuracy of a read with the phred-scaled base qualities `quals` (each
+/// offset by `offset`, e.g. 33 in a FASTQ file): one minus the mean error probability
+/// of its bases. Returns `None` if the read has no qualities, or if they are missing
+/// (a BAM record without qualities holds 255 for each base).
+pub fn accuracy_from_quals(quals: impl IntoIterator<Item = u8>, offset: u8) -> Option<f32> {
+    let mut num_bases = 0_usize;
+    let mut sum_err = 0.0_f64;
+    for q in quals {
+        if q == 255 {
+            return None;
+        }
+        sum_err += ERROR_PROBS[q.saturating_sub(offset) as usize];
+        num_bases += 1;
+    }
+    (num_bases > 0).then(|| (1.0 - sum_err / num_bases as f64) as f32)
+}
+
+/// The predicted accuracy of the read whose alignment records are `records`: its `rq`
+/// tag, if any record has one, and otherwise the accuracy implied by the base qualities
+/// of the first record holding them (secondary records need not carry them).
+pub fn record_accuracy<R: Record>(records: &[R]) -> Option<f32> {
+    for r in records {
+        if let Some(Ok(Value::Float(rq))) = r.data().get(&ReadTags::RQ) {
+            return Some(rq);
+        }
+    }
+    records.iter().find_map(|r| {
+        let quals = r.quality_scores();
+        if quals.is_empty() {
+            return None;
+        }
+        accuracy_from_quals(quals.iter().map_while(Result::ok), 0)
+    })
+}
+
+/// The phred-scaled quality of the predicted accuracy `rq`.
+fn phred(rq: f32) -> f32 {
+    -10.0 * (1.0 - rq).max(1e-6).log10()
+}
+
+/// The exponent applied to the alignment score probabilities of a read of predicted
+/// accuracy `rq`: the ratio of its phred-scaled quality to that of the reference
+/// accuracy, clamped to [`MIN_SHARPNESS`, `MAX_SHARPNESS`].
+pub fn sharpness(rq: f32) -> f32 {
+    if !(0.0..=1.0).contains(&rq) {
+        return 1.0;
+    }
+    (phred(rq) / phred(REFERENCE_ACCURACY)).clamp(MIN_SHARPNESS, MAX_SHARPNESS)
+}
+
+/// Weight the alignment score probabilities `probs` of a read (relative to its best
+/// alignment) by the predicted accuracy `rq` of the read, if known: the differences
+/// between the scores of the alignments of an accurate read are more telling than
+/// those of an error-prone read.
+pub fn weight_by_accuracy(probs: &mut [f32], rq: Option<f32>) {
+    let Some(rq) = rq else {
+        return;
+    };
+    let s = sharpness(rq);
+    if s != 1.0 {
+        for p in probs.iter_mut() {
+            *p = p.powf(s);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::util::provenance;
+use anyhow::Context;
+use needletail::parse_fastx_file;
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::ffi::CStr;
+use std::fs::{OpenOptions, create_dir_all};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The differences between the reference sequences named in the alignment
+/// header and those of the reference given for verification.
+#[derive(Debug)]
+pub struct ReferenceMismatch {
+    /// transcripts in the alignment header that are absent from the reference
+    pub only_in_alignments: Vec<(String, usize)>,
+    /// transcripts in the reference that are absent from the alignment header
+    pub only_in_reference: Vec<(String, usize)>,
+    /// transcripts present in both, but with different lengths, given as
+    /// (name, length in the alignment header, length in the reference)
+    pub length_mismatch: Vec<(String, usize, usize)>,
+}
+
+/// Read the names and lengths of the sequences of the reference at `reference`,
+/// which is either a FASTA file (if `is_fasta` is true) or a minimap2 index.
+pub fn read_reference_seqs(
+    reference: &Path,
+    is_fasta: bool,
+) -> anyhow::Result<Vec<(String, usize)>> {
+    let mut seqs = Vec::new();
+    if is_fasta {
+        let mut reader = parse_fastx_file(reference)
+            .with_context(|| format!("could not read reference {}", reference.display()))?;
+        while let Some(rec) = reader.next() {
+            let rec = rec?;
+            // the name of the sequence is the first word of the header line
+            let id = rec.id();
+            let name = id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id);
+            seqs.push((String::from_utf8_lossy(name).into_owned(), rec.num_bases()));
+        }
+    } else {
+        let aligner = minimap2::Aligner::builder()
+            .with_index(reference, None)
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "could not load minimap2 index {} : {}",
+                    reference.display(),
+                    e
+                )
+            })?;
+        for i in 0..aligner.n_seq() {
+            let seq = aligner
+                .get_seq(i as usize)
+                .with_context(|| format!("{} is not a valid reference sequence index", i))?;
+            let c_str = unsafe { CStr::from_ptr(seq.name) };
+            seqs.push((c_str.to_str()?.to_string(), seq.len as usize));
+        }
+    }
+    Ok(seqs)
+}
+
+/// Compare the reference sequences of the alignment `header` to the
+/// (name, length) pairs `ref_seqs` of the reference.
+pub fn compare_references(
+    header: &noodles_sam::header::Header,
+    ref_seqs: &[(String, usize)],
+) -> ReferenceMismatch {
+    let ref_lens: FxHashMap<&str, usize> = ref_seqs.iter().map(|(n, l)| (n.as_str(), *l)).collect();
+    let mut in_header: FxHashSet<String> = FxHashSet::default();
+    let mut only_in_alignments = Vec::new();
+    let mut length_mismatch = Vec::new();
+    for (rseq, rmap) in header.reference_sequences().iter() {
+        let name = rseq.to_string();
+        let len = rmap.length().get();
+        match ref_lens.get(name.as_str()) {
+            None => only_in_alignments.push((name.clone(), len)),
+            Some(rlen) if *rlen != len => length_mismatch.push((name.clone(), len, *rlen)),
+            _ => {}
+        }
+        in_header.insert(name);
+    }
+    let only_in_reference: Vec<(String, usize)> = ref_seqs
+        .iter()
+        .filter(|(n, _)| !in_header.contains(n))
+        .cloned()
+        .collect();
+
+    ReferenceMismatch {
+        only_in_alignments,
+        only_in_reference,
+        length_mismatch,
+    }
+}
+
+impl ReferenceMismatch {
+    /// Log the extent of the mismatch between the alignment header and `reference`.
+    pub fn log(&self, reference: &Path, num_header_txps: usize) {
+        warn!(
+            "the reference sequences in the alignment header differ from those of {} : {} of {} transcripts in the alignments are absent from the reference, {} transcripts of the reference are absent from the alignments, and {} transcripts have a different length.",
+            reference.display(),
+            self.only_in_alignments
+                .len()
+                .to_formatted_string(&Locale::en),
+            num_header_txps.to_formatted_string(&Locale::en),
+            self.only_in_reference
+                .len()
+                .to_formatted_string(&Locale::en),
+            self.length_mismatch.len().to_formatted_string(&Locale::en),
+        );
+    }
+
+    /// A mask over the transcripts named `txps_name` (in header order) that is true for
+    /// the transcripts that are not shared, with the same length, by the reference.
+    pub fn excluded_mask(&self, txps_name: &[String]) -> Vec<bool> {
+        let not_shared: FxHashSet<&str> = self
+            .only_in_alignments
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .chain(self.length_mismatch.iter().map(|(n, _, _)| n.as_str()))
+            .collect();
+        let mask: Vec<bool> = txps_name
+            .iter()
+            .map(|n| not_shared.contains(n.as_str()))
+            .collect();
+        info!(
+            "quantifying only the {} transcripts shared by the alignments and the reference.",
+            mask.iter()
+                .filter(|x| !**x)
+                .count()
+                .to_formatted_string(&Locale::en)
+        );
+        mask
+    }
+}
+
+/// Write the transcripts that differ between the alignment header and
+/// the reference to `<output>.reference_mismatch.tsv`.
+pub fn write_reference_mismatch(output: &PathBuf, mm: &ReferenceMismatch) -> io::Result<()> {
+    if let Some(p) = output.parent() {
+        if p != Path::new("") {
+            create_dir_all(p)?;
+        }
+    }
+    let out_path = output.with_additional_extension(".reference_mismatch.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    writeln!(writer, "tname\tstatus\taln_len\tref_len")?;
+    for (n, l) in mm.only_in_alignments.iter() {
+        writeln!(writer, "{}\tonly_in_alignments\t{}\tNA", n, l)?;
+    }
+    for (n, l) in mm.only_in_reference.iter() {
+        writeln!(writer, "{}\tonly_in_reference\tNA\t{}", n, l)?;
+    }
+    for (n, al, rl) in mm.length_mismatch.iter() {
+        writeln!(writer, "{}\tlength_mismatch\t{}\t{}", n, al, rl)?;
+    }
+    Ok(())
+}
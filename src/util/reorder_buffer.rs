@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+
+/// Restores the order of items that are produced (e.g. by several worker threads) out of
+/// order; each item carries its index in the original order, and the items are released
+/// in order of their indices, starting from 0, once all of their predecessors are.
+#[derive(Debug)]
+pub struct ReorderBuffer<T> {
+    /// the items that arrived ahead of their turn, by index
+    pending: BTreeMap<u64, T>,
+    next_index: u64,
+}
+
+impl<T> Default for ReorderBuffer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new() -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            next_index: 0,
+        }
+    }
+
+    /// Add the item `item` with index `index`, and return the items (possibly none) that
+    /// are now ready, in order.
+    pub fn push(&mut self, index: u64, item: T) -> Vec<T> {
+        self.pending.insert(index, item);
+        let mut ready = Vec::new();
+        while let Some(item) = self.pending.remove(&self.next_index) {
+            ready.push(item);
+            self.next_index += 1;
+        }
+        ready
+    }
+
+    /// The number of items held back, waiting for an item with a lower index.
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn releases_items_in_order() {
+        let mut buf = ReorderBuffer::new();
+        assert!(buf.push(2, "c").is_empty());
+        assert!(buf.push(1, "b").is_empty());
+        assert_eq!(buf.num_pending(), 2);
+        assert_eq!(buf.push(0, "a"), vec!["a", "b", "c"]);
+        assert_eq!(buf.push(3, "d"), vec!["d"]);
+        assert!(buf.push(5, "f").is_empty());
+        assert_eq!(buf.push(4, "e"), vec!["e", "f"]);
+        assert_eq!(buf.num_pending(), 0);
+    }
+
+    #[test]
+    fn order_does_not_depend_on_arrival() {
+        // a fixed permutation of 0..64, as the chunks might arrive from the mapping threads
+        let arrival: Vec<u64> = (0..64).map(|i| (i * 37 + 11) % 64).collect();
+        let mut buf = ReorderBuffer::new();
+        let mut out = Vec::new();
+        for i in arrival {
+            out.extend(buf.push(i, i));
+        }
+        assert_eq!(out, (0..64).collect::<Vec<u64>>());
+        assert_eq!(buf.num_pending(), 0);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::util::logging;
+use crate::util::profile;
+use anyhow::Context;
+use path_tools::WithAdditionalExtension;
+use serde::Serialize;
+use serde_json::json;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+/// The wall-clock time spent in each stage of the run so far, along with the
+/// time at which the run (and the current stage) began.
+struct StageTimes {
+    run_start: Instant,
+    stage_start: Instant,
+    stages: Vec<(&'static str, f64)>,
+}
+
+static STAGE_TIMES: LazyLock<Mutex<StageTimes>> = LazyLock::new(|| {
+    let now = Instant::now();
+    Mutex::new(StageTimes {
+        run_start: now,
+        stage_start: now,
+        stages: Vec::new(),
+    })
+});
+
+/// Start tracking the resource usage of this run. This should be called
+/// as early as possible, since the wall time of the run is measured from
+/// the first call to this function (or to [end_stage]).
+pub fn start() {
+    LazyLock::force(&STAGE_TIMES);
+}
+
+/// Restart the tracking of the resource usage for a new run within the same process (e.g.
+/// a job of `oarfish serve`), so that its wall time and stages are measured from now on.
+/// The CPU time, peak memory and I/O remain those of the whole process.
+pub fn restart() {
+    let mut st = STAGE_TIMES.lock().expect("resource usage lock poisoned");
+    let now = Instant::now();
+    st.run_start = now;
+    st.stage_start = now;
+    st.stages.clear();
+}
+
+/// Record the end of the stage named `name`, which is taken to have begun
+/// when the previous stage ended (or when the run started).
+pub fn end_stage(name: &'static str) {
+    let mut st = STAGE_TIMES.lock().expect("resource usage lock poisoned");
+    let now = Instant::now();
+    let elapsed = now.duration_since(st.stage_start).as_secs_f64();
+    st.stage_start = now;
+    st.stages.push((name, elapsed));
+    logging::stage_end(name, elapsed);
+}
+
+/// CPU time and peak memory of the process, as reported by `getrusage`.
+#[derive(Debug, Serialize)]
+struct RUsage {
+    user_cpu_secs: f64,
+    system_cpu_secs: f64,
+    peak_rss_bytes: u64,
+}
+
+fn get_rusage() -> Option<RUsage> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: `usage` is a valid pointer to a `rusage` that getrusage fills in
+    let ret = unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) };
+    if ret != 0 {
+        return None;
+    }
+    // SAFETY: getrusage succeeded, so `usage` has been initialized
+    let usage = unsafe { usage.assume_init() };
+    let secs = |tv: libc::timeval| tv.tv_sec as f64 + (tv.tv_usec as f64) * 1e-6;
+    // ru_maxrss is reported in bytes on macOS and in KiB elsewhere
+    let rss_scale = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    Some(RUsage {
+        user_cpu_secs: secs(usage.ru_utime),
+        system_cpu_secs: secs(usage.ru_stime),
+        peak_rss_bytes: (usage.ru_maxrss as u64) * rss_scale,
+    })
+}
+
+/// The bytes read and written by the process. This information is only
+/// available on Linux (from `/proc/self/io`).
+#[derive(Debug, Default, Serialize)]
+struct IoBytes {
+    /// bytes read, including those served from the page cache
+    read_bytes_total: u64,
+    /// bytes written, including those not yet flushed to storage
+    written_bytes_total: u64,
+    /// bytes actually fetched from the storage layer
+    read_bytes_storage: u64,
+    /// bytes actually sent to the storage layer
+    written_bytes_storage: u64,
+}
+
+fn get_io_bytes() -> Option<IoBytes> {
+    let contents = std::fs::read_to_string("/proc/self/io").ok()?;
+    let mut io = IoBytes::default();
+    for line in contents.lines() {
+        if let Some((key, val)) = line.split_once(':') {
+            let val = val.trim().parse::<u64>().ok()?;
+            match key {
+                "rchar" => io.read_bytes_total = val,
+                "wchar" => io.written_bytes_total = val,
+                "read_bytes" => io.read_bytes_storage = val,
+                "write_bytes" => io.written_bytes_storage = val,
+                _ => {}
+            }
+        }
+    }
+    Some(io)
+}
+
+/// The wall time of the run so far, in seconds.
+pub fn wall_time_secs() -> f64 {
+    let st = STAGE_TIMES.lock().expect("resource usage lock poisoned");
+    st.run_start.elapsed().as_secs_f64()
+}
+
+/// Returns a summary of the resources used by the run so far: the wall time of
+/// each stage and of the whole run, the CPU time, the peak resident set size
+/// and the bytes read and written.
+pub fn summary_json() -> serde_json::Value {
+    let st = STAGE_TIMES.lock().expect("resource usage lock poisoned");
+    let stages: serde_json::Map<String, serde_json::Value> = st
+        .stages
+        .iter()
+        .map(|(name, secs)| (name.to_string(), json!(secs)))
+        .collect();
+    json!({
+        "wall_time_secs": st.run_start.elapsed().as_secs_f64(),
+        "stage_wall_time_secs": stages,
+        "rusage": get_rusage(),
+        "io": get_io_bytes(),
+    })
+}
+
+/// Add the summary of the resources used by the run (see [summary_json]) to
+/// the `meta_info.json` file already written for the output prefix `output`, along
+/// with the time spent in each stage of the pipeline if `--profile` was given (see
+/// [profile::write_profile]). This is done once the run is complete, so that every
+/// stage is accounted for.
+pub fn add_to_meta_info(output: &PathBuf) -> anyhow::Result<()> {
+    let info_path = output.with_additional_extension(".meta_info.json");
+    let mut info: serde_json::Value = {
+        let file = std::fs::File::open(&info_path)?;
+        serde_json::from_reader(BufReader::new(file))?
+    };
+    info["resource_usage"] = summary_json();
+    if profile::is_enabled() {
+        info["profile"] = profile::write_profile(output)?;
+    }
+
+    let write = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&info_path)
+        .with_context(|| format!("could not create {}", info_path.display()))?;
+    serde_json::ser::to_writer_pretty(write, &info)?;
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use crate::em;
+use crate::util::oarfish_types::EMInfo;
+use crate::util::provenance;
+use crate::util::read_length_strata::{quantify_read_subset, tv_distance};
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+/// The quantification of one (nested) subsample of the reads.
+#[derive(Debug)]
+pub struct SaturationPoint {
+    /// the fraction of the reads in the subsample
+    pub fraction: f64,
+    /// the number of (aligned) reads in the subsample
+    pub num_reads: usize,
+    /// the number of transcripts whose estimated count is at least the detection threshold
+    pub num_detected: usize,
+    /// total variation distance between the relative abundances estimated from the
+    /// subsample and from all reads
+    pub tv_distance: f64,
+    /// the median, over the transcripts detected in the full sample, of the relative
+    /// difference between the count estimated from the subsample (scaled up by
+    /// 1 / `fraction`) and from all reads
+    pub median_rel_error: f64,
+}
+
+fn median(mut vals: Vec<f64>) -> f64 {
+    vals.sort_unstable_by(f64::total_cmp);
+    match vals.len() {
+        0 => 0.0,
+        n if n % 2 == 1 => vals[n / 2],
+        n => 0.5 * (vals[n / 2 - 1] + vals[n / 2]),
+    }
+}
+
+/// Quantify the nested subsamples of the reads of `emi` given by `fractions`, where the
+/// subsample of fraction `p` holds the reads whose `read_keys` (one per read, in [0, 1))
+/// are below `p`, and summarize how the number of detected transcripts (those with an
+/// estimated count of at least `min_reads`) and the estimated counts change with depth.
+/// The full sample (fraction 1) is always quantified, as the reference for the counts.
+pub fn saturation_curve(
+    emi: &EMInfo,
+    read_keys: &[f64],
+    fractions: &[f64],
+    min_reads: f64,
+    threads: usize,
+) -> Vec<SaturationPoint> {
+    let mut fractions = fractions.to_vec();
+    fractions.retain(|p| *p < 1.0);
+    fractions.sort_unstable_by(f64::total_cmp);
+    fractions.dedup();
+    let num_txps = emi.txp_info.len();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+    let (full, subsamples) = pool.install(|| {
+        rayon::join(
+            || em::em(emi, threads),
+            || {
+                fractions
+                    .par_iter()
+                    .map(|p| {
+                        let inds: Vec<usize> = read_keys
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, k)| **k < *p)
+                            .map(|(i, _)| i)
+                            .collect();
+                        let counts = quantify_read_subset(emi, &inds, num_txps);
+                        (*p, inds.len(), counts)
+                    })
+                    .collect::<Vec<_>>()
+            },
+        )
+    });
+
+    let detected_in_full: Vec<usize> = (0..num_txps).filter(|t| full[*t] >= min_reads).collect();
+    let point = |fraction: f64, num_reads: usize, counts: &[f64]| SaturationPoint {
+        fraction,
+        num_reads,
+        num_detected: counts.iter().filter(|c| **c >= min_reads).count(),
+        tv_distance: tv_distance(counts, &full),
+        median_rel_error: median(
+            detected_in_full
+                .iter()
+                .map(|t| (counts[*t] / fraction - full[*t]).abs() / full[*t])
+                .collect(),
+        ),
+    };
+    let mut points: Vec<SaturationPoint> = subsamples
+        .iter()
+        .map(|(p, n, counts)| point(*p, *n, counts))
+        .collect();
+    points.push(point(1.0, read_keys.len(), &full));
+
+    for p in points.iter() {
+        info!(
+            "{:.0}% of the reads ({}): {} transcripts detected, median relative error {:.3}",
+            100.0 * p.fraction,
+            p.num_reads.to_formatted_string(&Locale::en),
+            p.num_detected.to_formatted_string(&Locale::en),
+            p.median_rel_error
+        );
+    }
+    // the gain in detected transcripts from the last increment of depth indicates
+    // whether sequencing more deeply would detect many more
+    if let [.., prev, last] = points.as_slice()
+        && last.num_detected > 0
+    {
+        info!(
+            "the last {:.0}% of the reads detected {:.1}% more transcripts.",
+            100.0 * (last.fraction - prev.fraction),
+            100.0 * last.num_detected.saturating_sub(prev.num_detected) as f64
+                / prev.num_detected.max(1) as f64
+        );
+    }
+    points
+}
+
+/// Write the saturation curves `points` to `<output>.saturation.tsv`.
+pub fn write_saturation(output: &Path, points: &[SaturationPoint]) -> anyhow::Result<()> {
+    let path = output.with_additional_extension(".saturation.tsv");
+    let mut writer = BufWriter::new(File::create(&path)?);
+    provenance::write_tsv_comment(&mut writer)?;
+    writeln!(
+        writer,
+        "fraction\tnum_reads\tnum_detected\ttv_distance\tmedian_rel_error"
+    )?;
+    for p in points {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            p.fraction, p.num_reads, p.num_detected, p.tv_distance, p.median_rel_error
+        )?;
+    }
+    writer.flush()?;
+    info!("wrote the saturation curves to {}.", path.display());
+    Ok(())
+}
@@ -0,0 +1,544 @@
+use crate::util::object_store_io::StagedFile;
+use crate::util::provenance;
+use crate::util::spatial::{self, SpatialSummary, SpotMap};
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The header of the Matrix Market count matrix (as written by `sprs`).
+const MTX_HEADER: &str = "%%MatrixMarket matrix coordinate real general\n";
+/// The width of the size line of the count matrix, which is written as blanks and
+/// filled in once the number of cells and non-zero entries is known.
+const MTX_SIZE_LINE_WIDTH: usize = 64;
+/// A checkpoint of the output is written every time this many cells have been written.
+const CHECKPOINT_INTERVAL_CELLS: usize = 1000;
+
+/// The state of the per-cell outputs once the first `num_cells` cells (in collated
+/// order) have been written, from which a failed run can be resumed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriterCheckpoint {
+    /// the input whose cells were being quantified
+    input: String,
+    num_cells: usize,
+    nnz: u64,
+    gene_nnz: u64,
+    /// the lengths of the output files once these cells were written
+    barcodes_len: u64,
+    qc_len: u64,
+    matrix_len: u64,
+    gene_matrix_len: Option<u64>,
+    spots_len: Option<u64>,
+    spatial_summary: Option<SpatialSummary>,
+}
+
+impl WriterCheckpoint {
+    /// Load the checkpoint at `path` of a run quantifying the cells of `input`, if
+    /// there is one; it is an error for the checkpoint to be of another input.
+    pub fn load(path: &Path, input: &str) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            warn!(
+                "there is no checkpoint {} to resume from; all cells will be quantified.",
+                path.display()
+            );
+            return Ok(None);
+        }
+        let ckpt: Self = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        anyhow::ensure!(
+            ckpt.input == input,
+            "the checkpoint {} is of a run quantifying {}, not {}",
+            path.display(),
+            ckpt.input,
+            input
+        );
+        Ok(Some(ckpt))
+    }
+
+    /// The number of cells whose output is complete.
+    pub fn num_cells(&self) -> usize {
+        self.num_cells
+    }
+
+    /// The barcodes of the cells whose output is complete, read from the
+    /// barcodes file `barcode_path`.
+    pub fn completed_barcodes(&self, barcode_path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut barcodes = Vec::with_capacity(self.num_cells);
+        for line in BufReader::new(File::open(barcode_path)?)
+            .split(b'\n')
+            .take(self.num_cells)
+        {
+            barcodes.push(line?);
+        }
+        anyhow::ensure!(
+            barcodes.len() == self.num_cells,
+            "{} lists fewer than the {} cells of the checkpoint",
+            barcode_path.display(),
+            self.num_cells
+        );
+        Ok(barcodes)
+    }
+}
+
+/// The formatted output of a quantified cell.
+pub struct CellRecord {
+    /// the barcode of the cell
+    barcode: Vec<u8>,
+    /// the line of the per-cell QC file
+    qc_line: Vec<u8>,
+    /// the (1-based) `row col value` lines of the count matrix
+    entries: Vec<u8>,
+    nnz: u64,
+    /// the lines of the gene-level count matrix, if one is written
+    gene_entries: Vec<u8>,
+    gene_nnz: u64,
+}
+
+impl CellRecord {
+    pub fn new(barcode: Vec<u8>, qc_line: Vec<u8>) -> Self {
+        Self {
+            barcode,
+            qc_line,
+            entries: Vec::new(),
+            nnz: 0,
+            gene_entries: Vec::new(),
+            gene_nnz: 0,
+        }
+    }
+
+    /// Add the count `val` of column `col` of the cell with the (0-based) row `row`.
+    pub fn push_entry(&mut self, row: usize, col: u32, val: f32) {
+        // writing to a Vec<u8> can't fail
+        let _ = writeln!(&mut self.entries, "{} {} {}", row + 1, col + 1, val);
+        self.nnz += 1;
+    }
+
+    /// Add the count `val` of column `col` of the gene-level matrix, as in [CellRecord::push_entry].
+    pub fn push_gene_entry(&mut self, row: usize, col: u32, val: f32) {
+        let _ = writeln!(&mut self.gene_entries, "{} {} {}", row + 1, col + 1, val);
+        self.gene_nnz += 1;
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.barcode.len() + self.qc_line.len() + self.entries.len() + self.gene_entries.len()
+    }
+}
+
+/// A cell awaiting output, either held in memory or spilled to disk.
+enum PendingCell {
+    InMemory(CellRecord),
+    Spilled {
+        offset: u64,
+        lens: [usize; 4],
+        nnz: u64,
+        gene_nnz: u64,
+    },
+}
+
+/// Writes the per-cell outputs (the barcodes, the count matrix, the QC metrics and,
+/// if requested, the gene-level count matrix and the spots) of the single-cell mode
+/// as the cells are quantified.
+/// The cells are quantified out of order, so each is given its index in the collated
+/// input, and the cells are written in this order; those quantified ahead of their
+/// turn are held until it comes, and spilled to a temporary file once they take up
+/// more than `max_pending_bytes`.
+pub struct CollatedCellWriter {
+    barcode_file: BufWriter<File>,
+    qc_file: BufWriter<File>,
+    spot_file: Option<BufWriter<File>>,
+    pub spatial_summary: Option<SpatialSummary>,
+    matrix_file: BufWriter<File>,
+    nnz: u64,
+    /// the gene-level count matrix and its number of columns, if one is written
+    gene_matrix: Option<(BufWriter<File>, usize)>,
+    gene_nnz: u64,
+    next_cell: usize,
+    pending: BTreeMap<usize, PendingCell>,
+    pending_bytes: usize,
+    max_pending_bytes: usize,
+    spill: Option<(StagedFile, File)>,
+    spill_len: u64,
+    num_spilled: usize,
+    /// the input whose cells are quantified, and where its checkpoints are written
+    checkpoint_input: String,
+    checkpoint_path: PathBuf,
+}
+
+fn create_output(path: &Path) -> std::io::Result<BufWriter<File>> {
+    Ok(BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?,
+    ))
+}
+
+/// Open the output at `path` to append to its first `len` bytes, which were written
+/// by the run being resumed.
+fn resume_output(path: &Path, len: u64) -> anyhow::Result<BufWriter<File>> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    anyhow::ensure!(
+        file.metadata()?.len() >= len,
+        "{} is shorter than recorded in the checkpoint",
+        path.display()
+    );
+    file.set_len(len)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(BufWriter::new(file))
+}
+
+/// Create the Matrix Market file at `path`, leaving room for its size line.
+fn create_matrix(path: &Path) -> std::io::Result<BufWriter<File>> {
+    let mut matrix_file = create_output(path)?;
+    matrix_file.write_all(MTX_HEADER.as_bytes())?;
+    writeln!(matrix_file, "{:width$}", "", width = MTX_SIZE_LINE_WIDTH)?;
+    Ok(matrix_file)
+}
+
+/// The length of the (flushed) output `writer`.
+fn flushed_len(writer: &mut BufWriter<File>) -> std::io::Result<u64> {
+    writer.flush()?;
+    writer.get_mut().stream_position()
+}
+
+/// Flush the Matrix Market file `matrix_file` and fill in its size line.
+fn finish_matrix(
+    matrix_file: &mut BufWriter<File>,
+    num_rows: usize,
+    num_cols: usize,
+    nnz: u64,
+) -> std::io::Result<()> {
+    matrix_file.flush()?;
+    let file = matrix_file.get_mut();
+    file.seek(SeekFrom::Start(MTX_HEADER.len() as u64))?;
+    let size_line = format!("{} {} {}", num_rows, num_cols, nnz);
+    write!(file, "{:width$}", size_line, width = MTX_SIZE_LINE_WIDTH)?;
+    file.flush()
+}
+
+impl CollatedCellWriter {
+    /// Create the writer of the count matrix `matrix_path`, the barcodes `barcode_path`,
+    /// the QC metrics `qc_path` (with the header `qc_header`) and, if given, the gene-level
+    /// count matrix `gene_matrix_path` (with `num_gene_cols` columns) and the spots
+    /// `spot_path` (with the summary `spatial_summary` of the spot map). A checkpoint of the
+    /// outputs, of the cells of `checkpoint_input`, is periodically written to `checkpoint_path`;
+    /// if `resume_from` is given, the outputs of the cells it records are kept, and the
+    /// following cells are appended to them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        matrix_path: &Path,
+        barcode_path: &Path,
+        (qc_path, qc_header): (&Path, &str),
+        gene_matrix: Option<(&Path, usize)>,
+        spots: Option<(&Path, SpatialSummary)>,
+        max_pending_bytes: usize,
+        (checkpoint_path, checkpoint_input): (&Path, &str),
+        resume_from: Option<WriterCheckpoint>,
+    ) -> anyhow::Result<Self> {
+        if let Some(ckpt) = resume_from {
+            return Self::resume(
+                matrix_path,
+                barcode_path,
+                qc_path,
+                gene_matrix,
+                spots.map(|(path, _)| path),
+                max_pending_bytes,
+                checkpoint_path,
+                ckpt,
+            );
+        }
+        let matrix_file = create_matrix(matrix_path)?;
+        let gene_matrix = match gene_matrix {
+            Some((path, num_gene_cols)) => Some((create_matrix(path)?, num_gene_cols)),
+            None => None,
+        };
+        let mut qc_file = create_output(qc_path)?;
+        provenance::write_tsv_comment_without_inputs(&mut qc_file)?;
+        writeln!(qc_file, "{}", qc_header)?;
+        let (spot_file, spatial_summary) = match spots {
+            Some((path, summary)) => {
+                let mut f = create_output(path)?;
+                provenance::write_tsv_comment_without_inputs(&mut f)?;
+                writeln!(f, "{}", spatial::SPOTS_HEADER)?;
+                (Some(f), Some(summary))
+            }
+            None => (None, None),
+        };
+        Ok(Self {
+            barcode_file: create_output(barcode_path)?,
+            qc_file,
+            spot_file,
+            spatial_summary,
+            matrix_file,
+            nnz: 0,
+            gene_matrix,
+            gene_nnz: 0,
+            next_cell: 0,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            max_pending_bytes,
+            spill: None,
+            spill_len: 0,
+            num_spilled: 0,
+            checkpoint_input: checkpoint_input.to_owned(),
+            checkpoint_path: checkpoint_path.to_path_buf(),
+        })
+    }
+
+    /// Reopen the outputs of the run recorded by the checkpoint `ckpt`, keeping the
+    /// outputs of the cells it records (see [CollatedCellWriter::new]).
+    #[allow(clippy::too_many_arguments)]
+    fn resume(
+        matrix_path: &Path,
+        barcode_path: &Path,
+        qc_path: &Path,
+        gene_matrix: Option<(&Path, usize)>,
+        spot_path: Option<&Path>,
+        max_pending_bytes: usize,
+        checkpoint_path: &Path,
+        ckpt: WriterCheckpoint,
+    ) -> anyhow::Result<Self> {
+        let gene_matrix = match (gene_matrix, ckpt.gene_matrix_len) {
+            (Some((path, num_gene_cols)), Some(len)) => {
+                Some((resume_output(path, len)?, num_gene_cols))
+            }
+            (None, None) => None,
+            _ => anyhow::bail!(
+                "the checkpointed run was made with a different --txp-to-gene setting; it can not be resumed"
+            ),
+        };
+        let spot_file = match (spot_path, ckpt.spots_len) {
+            (Some(path), Some(len)) => Some(resume_output(path, len)?),
+            (None, None) => None,
+            _ => anyhow::bail!(
+                "the checkpointed run was made with a different --spot-coordinates setting; it can not be resumed"
+            ),
+        };
+        info!(
+            "resuming from the checkpoint {}; the first {} cells are already quantified.",
+            checkpoint_path.display(),
+            ckpt.num_cells.to_formatted_string(&Locale::en)
+        );
+        Ok(Self {
+            barcode_file: resume_output(barcode_path, ckpt.barcodes_len)?,
+            qc_file: resume_output(qc_path, ckpt.qc_len)?,
+            spot_file,
+            spatial_summary: ckpt.spatial_summary,
+            matrix_file: resume_output(matrix_path, ckpt.matrix_len)?,
+            nnz: ckpt.nnz,
+            gene_matrix,
+            gene_nnz: ckpt.gene_nnz,
+            next_cell: ckpt.num_cells,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            max_pending_bytes,
+            spill: None,
+            spill_len: 0,
+            num_spilled: 0,
+            checkpoint_input: ckpt.input,
+            checkpoint_path: checkpoint_path.to_path_buf(),
+        })
+    }
+
+    /// Record the state of the outputs in the checkpoint file, replacing it atomically.
+    fn write_checkpoint(&mut self) -> anyhow::Result<()> {
+        let ckpt = WriterCheckpoint {
+            input: self.checkpoint_input.clone(),
+            num_cells: self.next_cell,
+            nnz: self.nnz,
+            gene_nnz: self.gene_nnz,
+            barcodes_len: flushed_len(&mut self.barcode_file)?,
+            qc_len: flushed_len(&mut self.qc_file)?,
+            matrix_len: flushed_len(&mut self.matrix_file)?,
+            gene_matrix_len: self
+                .gene_matrix
+                .as_mut()
+                .map(|(f, _)| flushed_len(f))
+                .transpose()?,
+            spots_len: self.spot_file.as_mut().map(flushed_len).transpose()?,
+            spatial_summary: self.spatial_summary.clone(),
+        };
+        let tmp_path = self.checkpoint_path.with_additional_extension(".tmp");
+        {
+            let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer(&mut tmp, &ckpt)?;
+            tmp.flush()?;
+        }
+        std::fs::rename(&tmp_path, &self.checkpoint_path)?;
+        Ok(())
+    }
+
+    /// Add the output `record` of the cell with index `cell` (in the collated input),
+    /// writing it, and any pending cells that follow it, if it is the next to be written.
+    pub fn push(
+        &mut self,
+        cell: usize,
+        record: CellRecord,
+        spots: Option<&SpotMap>,
+    ) -> anyhow::Result<()> {
+        if cell != self.next_cell {
+            self.pending_bytes += record.num_bytes();
+            self.pending.insert(cell, PendingCell::InMemory(record));
+            if self.pending_bytes > self.max_pending_bytes {
+                self.spill_pending()?;
+            }
+            return Ok(());
+        }
+        self.write_cell(&record, spots)?;
+        while let Some(pending) = self.pending.remove(&self.next_cell) {
+            let record = self.load(pending)?;
+            self.write_cell(&record, spots)?;
+        }
+        Ok(())
+    }
+
+    fn write_cell(&mut self, record: &CellRecord, spots: Option<&SpotMap>) -> anyhow::Result<()> {
+        self.barcode_file.write_all(&record.barcode)?;
+        self.barcode_file.write_all(b"\n")?;
+        if let (Some(spot_map), Some(spot_file), Some(summary)) = (
+            spots,
+            self.spot_file.as_mut(),
+            self.spatial_summary.as_mut(),
+        ) {
+            spatial::write_spot(spot_file, spot_map, &record.barcode, summary)?;
+        }
+        self.qc_file.write_all(&record.qc_line)?;
+        self.matrix_file.write_all(&record.entries)?;
+        self.nnz += record.nnz;
+        if let Some((gene_matrix_file, _)) = self.gene_matrix.as_mut() {
+            gene_matrix_file.write_all(&record.gene_entries)?;
+            self.gene_nnz += record.gene_nnz;
+        }
+        self.next_cell += 1;
+        if self.next_cell % CHECKPOINT_INTERVAL_CELLS == 0 {
+            self.write_checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Move all of the pending cells held in memory to the spill file.
+    fn spill_pending(&mut self) -> anyhow::Result<()> {
+        if self.spill.is_none() {
+            let path =
+                std::env::temp_dir().join(format!("oarfish-{}-sc-spill.bin", std::process::id()));
+            // the file is removed, even if writing it fails, once this is dropped
+            let staged = StagedFile::new(path);
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(staged.path())?;
+            info!(
+                "more than {} bytes of quantified cells are awaiting output; spilling them to {}.",
+                self.max_pending_bytes.to_formatted_string(&Locale::en),
+                staged.path().display()
+            );
+            self.spill = Some((staged, file));
+        }
+        let (_, file) = self.spill.as_mut().expect("the spill file was created");
+        file.seek(SeekFrom::Start(self.spill_len))?;
+        let mut writer = BufWriter::new(file);
+        for pending in self.pending.values_mut() {
+            if let PendingCell::InMemory(record) = pending {
+                let lens = [
+                    record.barcode.len(),
+                    record.qc_line.len(),
+                    record.entries.len(),
+                    record.gene_entries.len(),
+                ];
+                writer.write_all(&record.barcode)?;
+                writer.write_all(&record.qc_line)?;
+                writer.write_all(&record.entries)?;
+                writer.write_all(&record.gene_entries)?;
+                *pending = PendingCell::Spilled {
+                    offset: self.spill_len,
+                    lens,
+                    nnz: record.nnz,
+                    gene_nnz: record.gene_nnz,
+                };
+                self.spill_len += lens.iter().sum::<usize>() as u64;
+                self.num_spilled += 1;
+            }
+        }
+        writer.flush()?;
+        self.pending_bytes = 0;
+        Ok(())
+    }
+
+    /// Get the record of a pending cell, reading it from the spill file if needed.
+    fn load(&mut self, pending: PendingCell) -> anyhow::Result<CellRecord> {
+        match pending {
+            PendingCell::InMemory(record) => {
+                self.pending_bytes -= record.num_bytes();
+                Ok(record)
+            }
+            PendingCell::Spilled {
+                offset,
+                lens,
+                nnz,
+                gene_nnz,
+            } => {
+                let (_, file) = self
+                    .spill
+                    .as_mut()
+                    .expect("a spilled cell has a spill file");
+                file.seek(SeekFrom::Start(offset))?;
+                let mut read_part = |len: usize| -> std::io::Result<Vec<u8>> {
+                    let mut buf = vec![0_u8; len];
+                    file.read_exact(&mut buf)?;
+                    Ok(buf)
+                };
+                Ok(CellRecord {
+                    barcode: read_part(lens[0])?,
+                    qc_line: read_part(lens[1])?,
+                    entries: read_part(lens[2])?,
+                    nnz,
+                    gene_entries: read_part(lens[3])?,
+                    gene_nnz,
+                })
+            }
+        }
+    }
+
+    /// Flush all of the outputs and fill in the size lines of the count matrices; the
+    /// (transcript or USA-mode) count matrix has `num_cols` columns, and each matrix
+    /// has a row for each cell. Returns the number of cells.
+    pub fn finish(&mut self, num_cols: usize) -> anyhow::Result<usize> {
+        anyhow::ensure!(
+            self.pending.is_empty(),
+            "{} quantified cells follow a cell that was not quantified",
+            self.pending.len()
+        );
+        self.barcode_file.flush()?;
+        self.qc_file.flush()?;
+        if let Some(spot_file) = self.spot_file.as_mut() {
+            spot_file.flush()?;
+        }
+        finish_matrix(&mut self.matrix_file, self.next_cell, num_cols, self.nnz)?;
+        if let Some((gene_matrix_file, num_gene_cols)) = self.gene_matrix.as_mut() {
+            finish_matrix(
+                gene_matrix_file,
+                self.next_cell,
+                *num_gene_cols,
+                self.gene_nnz,
+            )?;
+        }
+        if self.num_spilled > 0 {
+            info!(
+                "{} cells were spilled to disk while awaiting output.",
+                self.num_spilled
+            );
+        }
+        // the spill file and the checkpoint are no longer needed
+        self.spill = None;
+        if self.checkpoint_path.exists() {
+            std::fs::remove_file(&self.checkpoint_path)?;
+        }
+        Ok(self.next_cell)
+    }
+}
@@ -0,0 +1,263 @@
+use crate::prog_opts::BarcodeSuffix;
+use crate::util::output_schema::{add_schema_info, read_output_info};
+use anyhow::Context;
+use num_format::{Locale, ToFormattedString};
+use path_tools::WithAdditionalExtension;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::Serialize;
+use serde_json::json;
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The barcode collision statistics of one of the merged samples.
+#[derive(Debug, Serialize)]
+struct SampleMergeSummary {
+    name: String,
+    prefix: PathBuf,
+    /// the row of the merged matrix holding the first cell of this sample
+    first_row: usize,
+    num_cells: usize,
+    /// the number of barcodes of this sample that also occur in another sample
+    num_colliding: usize,
+    collision_rate: f64,
+}
+
+fn read_lines(path: &Path) -> anyhow::Result<Vec<String>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    BufReader::new(file)
+        .lines()
+        .map(|l| l.with_context(|| format!("could not read {}", path.display())))
+        .collect()
+}
+
+/// The default name of the sample written to the output prefix `prefix`,
+/// i.e. its final component.
+fn default_sample_name(prefix: &Path) -> String {
+    prefix
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| prefix.display().to_string())
+}
+
+/// Merge the single-cell outputs with the prefixes `inputs` into a single
+/// count matrix (with the cells of each sample in turn), written with the
+/// prefix `output`. All inputs must have been quantified against the same
+/// transcripts. Barcodes that occur in more than one sample (or, with
+/// [BarcodeSuffix::All], every barcode) are suffixed with `-<sample name>`
+/// so that the cells of the merged matrix remain distinguishable, and the
+/// collision rate of each sample is reported in `<output>.meta_info.json`.
+pub fn merge_single_cell_outputs(
+    inputs: &[PathBuf],
+    sample_names: Option<&[String]>,
+    suffix: BarcodeSuffix,
+    output: &PathBuf,
+) -> anyhow::Result<()> {
+    let names: Vec<String> = match sample_names {
+        Some(names) => {
+            anyhow::ensure!(
+                names.len() == inputs.len(),
+                "{} sample names were given for {} inputs",
+                names.len(),
+                inputs.len()
+            );
+            names.to_vec()
+        }
+        None => inputs.iter().map(|p| default_sample_name(p)).collect(),
+    };
+    let mut seen = FxHashSet::default();
+    for n in names.iter() {
+        if !seen.insert(n.as_str()) {
+            anyhow::bail!(
+                "the sample name {} is used for more than one input; please provide distinct names with --sample-names",
+                n
+            );
+        }
+    }
+
+    // read the features and barcodes of every sample
+    let mut features: Option<Vec<String>> = None;
+    let mut barcodes = Vec::with_capacity(inputs.len());
+    let mut usa_mode = None;
+    for prefix in inputs {
+        let out = read_output_info(prefix)?;
+        anyhow::ensure!(
+            out.is_single_cell(),
+            "{} is not the output of a single-cell run",
+            prefix.display()
+        );
+        // the classes of each sample are numbered in the order in which they were seen
+        anyhow::ensure!(
+            !out.is_tcc(),
+            "{} holds TCC counts, whose classes differ between samples; it can not be merged",
+            prefix.display()
+        );
+        match usa_mode {
+            Some(u) => anyhow::ensure!(
+                u == out.is_usa_mode(),
+                "{} and {} do not both hold USA-mode counts; they can not be merged",
+                prefix.display(),
+                inputs[0].display()
+            ),
+            None => usa_mode = Some(out.is_usa_mode()),
+        }
+        let feats = read_lines(&prefix.with_additional_extension(".features.txt"))?;
+        match features {
+            Some(ref f) => anyhow::ensure!(
+                *f == feats,
+                "the features of {} differ from those of {}; only samples quantified against the same transcripts can be merged",
+                prefix.display(),
+                inputs[0].display()
+            ),
+            None => features = Some(feats),
+        }
+        barcodes.push(read_lines(
+            &prefix.with_additional_extension(".barcodes.txt"),
+        )?);
+    }
+    let features = features.unwrap_or_default();
+    let usa_mode = usa_mode.unwrap_or(false);
+    // in USA mode, each gene has a spliced, an unspliced and an ambiguous column
+    let num_cols = if usa_mode {
+        3 * features.len()
+    } else {
+        features.len()
+    };
+
+    // the number of samples in which each barcode occurs
+    let mut num_samples: FxHashMap<&str, u32> = FxHashMap::default();
+    for bcs in barcodes.iter() {
+        let mut in_sample = FxHashSet::default();
+        for bc in bcs.iter() {
+            if in_sample.insert(bc.as_str()) {
+                *num_samples.entry(bc.as_str()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    // stack the count matrices of the samples
+    let mut row_ids = Vec::<u32>::new();
+    let mut col_ids = Vec::<u32>::new();
+    let mut vals = Vec::<f32>::new();
+    let mut merged_barcodes = Vec::<String>::new();
+    let mut summaries = Vec::with_capacity(inputs.len());
+    for ((prefix, name), bcs) in inputs.iter().zip(names.iter()).zip(barcodes.iter()) {
+        let mtx_path = prefix.with_additional_extension(".count.mtx");
+        let counts: sprs::TriMatI<f32, u32> = sprs::io::read_matrix_market(&mtx_path)
+            .with_context(|| format!("could not read the count matrix {}", mtx_path.display()))?;
+        anyhow::ensure!(
+            counts.rows() == bcs.len() && counts.cols() == num_cols,
+            "the count matrix {} is {} x {}, but there are {} barcodes and {} columns expected",
+            mtx_path.display(),
+            counts.rows(),
+            counts.cols(),
+            bcs.len(),
+            num_cols
+        );
+
+        let first_row = merged_barcodes.len();
+        for (v, (r, c)) in counts.triplet_iter() {
+            row_ids.push((first_row + r as usize) as u32);
+            col_ids.push(c);
+            vals.push(*v);
+        }
+
+        let mut num_colliding = 0_usize;
+        for bc in bcs.iter() {
+            let colliding = num_samples.get(bc.as_str()).copied().unwrap_or(0) > 1;
+            num_colliding += colliding as usize;
+            if colliding || suffix == BarcodeSuffix::All {
+                merged_barcodes.push(format!("{}-{}", bc, name));
+            } else {
+                merged_barcodes.push(bc.clone());
+            }
+        }
+
+        let collision_rate = if bcs.is_empty() {
+            0.0
+        } else {
+            num_colliding as f64 / bcs.len() as f64
+        };
+        info!(
+            "sample {} ({}) : {} cells, {} ({:.2}%) with a barcode that also occurs in another sample",
+            name,
+            prefix.display(),
+            bcs.len().to_formatted_string(&Locale::en),
+            num_colliding.to_formatted_string(&Locale::en),
+            100.0 * collision_rate
+        );
+        summaries.push(SampleMergeSummary {
+            name: name.clone(),
+            prefix: prefix.clone(),
+            first_row,
+            num_cells: bcs.len(),
+            num_colliding,
+            collision_rate,
+        });
+    }
+
+    let num_cells = merged_barcodes.len();
+    let num_colliding_barcodes = num_samples.values().filter(|n| **n > 1).count();
+    if num_colliding_barcodes > 0 {
+        warn!(
+            "{} barcodes occur in more than one sample; these have been suffixed with the sample name.",
+            num_colliding_barcodes.to_formatted_string(&Locale::en)
+        );
+    }
+
+    // write the merged output
+    if let Some(p) = output.parent() {
+        if p != Path::new("") {
+            create_dir_all(p)?;
+        }
+    }
+
+    let mut info = json!({
+        "single_cell": true,
+        "usa_mode": usa_mode,
+        "merged_samples": &summaries,
+        "barcode_suffix": suffix,
+        "num_cells": num_cells,
+        "num_colliding_barcodes": num_colliding_barcodes,
+    });
+    add_schema_info(&mut info);
+    {
+        let info_path = output.with_additional_extension(".meta_info.json");
+        let write = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&info_path)
+            .with_context(|| format!("could not create {}", info_path.display()))?;
+        serde_json::ser::to_writer_pretty(write, &info)?;
+    }
+
+    let trimat =
+        sprs::TriMatI::<f32, u32>::from_triplets((num_cells, num_cols), row_ids, col_ids, vals);
+    sprs::io::write_matrix_market(output.with_additional_extension(".count.mtx"), &trimat)?;
+
+    for (ext, lines) in [
+        (".barcodes.txt", &merged_barcodes),
+        (".features.txt", &features),
+    ] {
+        let out_path = output.with_additional_extension(ext);
+        let write = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&out_path)
+            .with_context(|| format!("could not create {}", out_path.display()))?;
+        let mut writer = BufWriter::new(write);
+        for l in lines.iter() {
+            writeln!(writer, "{}", l)?;
+        }
+    }
+
+    info!(
+        "merged {} cells from {} samples.",
+        num_cells.to_formatted_string(&Locale::en),
+        inputs.len()
+    );
+    Ok(())
+}
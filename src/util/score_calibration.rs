@@ -0,0 +1,165 @@
+use crate::util::oarfish_types::AlnRecordLike;
+use noodles_bam as bam;
+use noodles_sam::alignment::RecordBuf;
+use num_format::{Locale, ToFormattedString};
+use serde::Serialize;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// The minimum number of alignments of each class (presumed correct and presumed
+/// incorrect) from which a calibration curve is fit.
+const MIN_CALIBRATION_EXAMPLES: usize = 100;
+/// The number of Newton iterations used to fit the calibration curve.
+const CALIBRATION_ITERS: usize = 50;
+/// The smallest calibrated probability of an alignment.
+const MIN_CALIBRATED_PROB: f64 = 1e-6;
+
+/// A calibration curve mapping the alignment score of an alignment, normalized by the
+/// length of its read, to the probability that the alignment is correct; a logistic
+/// function `1 / (1 + exp(-(intercept + slope * score / read_len)))`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoreCalibration {
+    pub intercept: f64,
+    pub slope: f64,
+    /// the number of alignments (of uniquely mapping reads) presumed correct
+    pub num_correct: usize,
+    /// the number of (suboptimal) alignments of multimapping reads presumed incorrect
+    pub num_incorrect: usize,
+}
+
+impl ScoreCalibration {
+    /// The calibrated probability that an alignment with score `score`, of a read of
+    /// length `read_len`, is correct.
+    pub fn prob(&self, score: i32, read_len: usize) -> f32 {
+        let x = score as f64 / read_len.max(1) as f64;
+        let p = 1.0 / (1.0 + (-(self.intercept + self.slope * x)).exp());
+        p.max(MIN_CALIBRATED_PROB) as f32
+    }
+}
+
+/// Fit a logistic curve to the normalized scores `xs` with correctness labels `ys`, by
+/// Newton's method; the classes are weighted equally, however many examples each has.
+fn fit_logistic(xs: &[f64], ys: &[bool]) -> (f64, f64) {
+    let num_pos = ys.iter().filter(|y| **y).count() as f64;
+    let num_neg = ys.len() as f64 - num_pos;
+    let (w_pos, w_neg) = (0.5 / num_pos, 0.5 / num_neg);
+    let (mut a, mut b) = (0.0_f64, 0.0_f64);
+    for _ in 0..CALIBRATION_ITERS {
+        // the gradient and Hessian of the (weighted) log likelihood
+        let (mut ga, mut gb, mut haa, mut hab, mut hbb) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let p = 1.0 / (1.0 + (-(a + b * x)).exp());
+            let w = if *y { w_pos } else { w_neg };
+            let r = w * ((*y as u8 as f64) - p);
+            ga += r;
+            gb += r * x;
+            let v = w * p * (1.0 - p);
+            haa += v;
+            hab += v * x;
+            hbb += v * x * x;
+        }
+        let det = haa * hbb - hab * hab;
+        if det.abs() < 1e-12 {
+            break;
+        }
+        let da = (hbb * ga - hab * gb) / det;
+        let db = (haa * gb - hab * ga) / det;
+        a += da;
+        b += db;
+        if da.abs() < 1e-9 && db.abs() < 1e-9 {
+            break;
+        }
+    }
+    (a, b)
+}
+
+/// Add the alignments of the read whose records are `group` to the calibration
+/// examples; the alignment of a uniquely mapping read is presumed correct, and the
+/// alignments of a multimapping read scoring below its best are presumed incorrect.
+fn add_examples(group: &[RecordBuf], xs: &mut Vec<f64>, ys: &mut Vec<bool>) {
+    // only the primary record is sure to carry the sequence of the read
+    let read_len = group
+        .iter()
+        .find_map(|r| r.opt_sequence_len().filter(|l| *l > 0))
+        .unwrap_or(0);
+    if read_len == 0 {
+        return;
+    }
+    let scores: Vec<i64> = group
+        .iter()
+        .filter(|r| !r.is_unmapped() && !r.is_supp())
+        .filter_map(|r| r.aln_score())
+        .collect();
+    let norm = |s: i64| s as f64 / read_len as f64;
+    match scores.as_slice() {
+        [] => {}
+        [s] => {
+            xs.push(norm(*s));
+            ys.push(true);
+        }
+        _ => {
+            let best = *scores.iter().max().expect("scores are non-empty");
+            for s in scores.iter().filter(|s| **s < best) {
+                xs.push(norm(*s));
+                ys.push(false);
+            }
+        }
+    }
+}
+
+/// Fit the calibration of the alignment scores from (at most) the first `max_reads` reads
+/// of the name-collated BAM file at `path`, in a first pass over the file. Returns [None]
+/// if there are too few alignments presumed correct or incorrect to fit the curve.
+pub fn fit_score_calibration(
+    path: &Path,
+    max_reads: u64,
+) -> anyhow::Result<Option<ScoreCalibration>> {
+    info!(
+        "calibrating the alignment scores from the first {} reads of {}.",
+        max_reads.to_formatted_string(&Locale::en),
+        path.display()
+    );
+    let mut reader = std::fs::File::open(path).map(bam::io::Reader::new)?;
+    let header = reader.read_header()?;
+
+    let mut xs = Vec::new();
+    let mut ys = Vec::new();
+    let mut group = Vec::<RecordBuf>::new();
+    let mut num_reads = 0_u64;
+    for result in reader.record_bufs(&header) {
+        let rec = result?;
+        if group.first().is_some_and(|r| r.name() != rec.name()) {
+            add_examples(&group, &mut xs, &mut ys);
+            group.clear();
+            num_reads += 1;
+            if num_reads >= max_reads {
+                break;
+            }
+        }
+        group.push(rec);
+    }
+    if num_reads < max_reads {
+        add_examples(&group, &mut xs, &mut ys);
+    }
+
+    let num_correct = ys.iter().filter(|y| **y).count();
+    let num_incorrect = ys.len() - num_correct;
+    if num_correct < MIN_CALIBRATION_EXAMPLES || num_incorrect < MIN_CALIBRATION_EXAMPLES {
+        warn!(
+            "too few alignments to calibrate the alignment scores ({} presumed correct, {} presumed incorrect); the scores will not be calibrated.",
+            num_correct, num_incorrect
+        );
+        return Ok(None);
+    }
+    let (intercept, slope) = fit_logistic(&xs, &ys);
+    info!(
+        "calibrated the alignment scores from {} presumed correct and {} presumed incorrect alignments (intercept = {:.3}, slope = {:.3}).",
+        num_correct, num_incorrect, intercept, slope
+    );
+    Ok(Some(ScoreCalibration {
+        intercept,
+        slope,
+        num_correct,
+        num_incorrect,
+    }))
+}
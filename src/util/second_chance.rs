@@ -0,0 +1,42 @@
+use crate::util::oarfish_types::AlnInfo;
+use serde::{Deserialize, Serialize};
+
+/// The fit of the read of length `read_len` by its retained alignments `alns`; the
+/// fraction of the read covered by the longest of them (0 if there are none).
+pub fn read_fit(alns: &[AlnInfo], read_len: usize) -> f32 {
+    if read_len == 0 {
+        return 0.0;
+    }
+    let span = alns.iter().map(|a| a.alignment_span()).max().unwrap_or(0);
+    (span as f32 / read_len as f32).min(1.0)
+}
+
+/// The outcome of the second-chance re-alignment of the reads that were poorly
+/// explained by their alignments under the default mapping parameters.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SecondChanceStats {
+    /// the minimum fit below which reads were re-aligned
+    pub min_fit: f32,
+    /// the number of reads re-aligned
+    pub num_realigned: usize,
+    /// the number of re-aligned reads that had no retained alignment, but gained one
+    pub num_rescued: usize,
+    /// the number of re-aligned reads that had retained alignments, which were replaced
+    /// by the better fitting alignments of the second pass
+    pub num_improved: usize,
+}
+
+impl SecondChanceStats {
+    pub fn new(min_fit: f32) -> Self {
+        Self {
+            min_fit,
+            ..Default::default()
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.num_realigned += other.num_realigned;
+        self.num_rescued += other.num_rescued;
+        self.num_improved += other.num_improved;
+    }
+}
@@ -0,0 +1,222 @@
+use crate::LoadedIndex;
+use crate::prog_opts::{Args, SequencingTech};
+use crate::util::{archive, atomic_output, logging, resource_usage};
+use clap::Parser;
+use crossbeam::channel::{Sender, bounded, unbounded};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+use tracing_subscriber::{EnvFilter, reload};
+
+/// A connection on which no job is received for this long is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A job received on a connection, and where its response is to be sent.
+type QueuedJob = (String, Sender<Value>);
+
+/// A job sent to the server: either the options of a run to quantify, or a request to stop.
+#[derive(Debug, Deserialize)]
+struct Job {
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    shutdown: bool,
+}
+
+/// Quantify the sample described by the options `job_args` against `index`, as `oarfish`
+/// does, returning the output prefix of the run.
+fn run_job<S>(
+    job_args: &[String],
+    index: &LoadedIndex,
+    seq_tech: &SequencingTech,
+    reload_handle: &reload::Handle<EnvFilter, S>,
+) -> anyhow::Result<PathBuf> {
+    let seq_tech = clap::ValueEnum::to_possible_value(seq_tech)
+        .expect("every sequencing technology has a name");
+    let reference = index.path.to_string_lossy();
+    let argv = [
+        "oarfish",
+        "--reference",
+        &reference,
+        "--seq-tech",
+        seq_tech.get_name(),
+    ]
+    .into_iter()
+    .map(str::to_owned)
+    .chain(job_args.iter().cloned());
+    let mut args = Args::try_parse_from(argv)?;
+    if args.control_alignments.is_some() {
+        anyhow::bail!("`--control-alignments` is not supported by `oarfish serve`");
+    }
+
+    resource_usage::restart();
+    logging::set_filter(reload_handle, &args, false)?;
+    crate::configure_run(&args)?;
+    let output = args.output.clone();
+    logging::run_start(&output);
+    let staging = if args.validate_only {
+        None
+    } else {
+        let staging = atomic_output::OutputStaging::new(&args.output, args.resume)?;
+        args.output = staging.staged_output();
+        Some(staging)
+    };
+    let staged_output = args.output.clone();
+    let pack_output = args.archive && !args.validate_only;
+    crate::quantify(args, reload_handle, Some(index))?;
+    if pack_output {
+        archive::archive_output(&staged_output)?;
+    }
+    if let Some(staging) = staging {
+        staging.commit()?;
+    }
+    logging::run_end(resource_usage::wall_time_secs());
+    Ok(output)
+}
+
+/// The response to the job on the line `line`, and whether the server should stop.
+fn handle_job<S>(
+    line: &str,
+    index: &LoadedIndex,
+    seq_tech: &SequencingTech,
+    reload_handle: &reload::Handle<EnvFilter, S>,
+) -> (Value, bool) {
+    let job: Job = match serde_json::from_str(line) {
+        Ok(job) => job,
+        Err(e) => {
+            return (
+                json!({ "ok": false, "error": format!("could not parse the job: {}", e) }),
+                false,
+            );
+        }
+    };
+    if job.shutdown {
+        return (json!({ "ok": true, "shutdown": true }), true);
+    }
+    info!("running the job {:?}.", job.args);
+    // a job that panics fails on its own, rather than stopping the server (this needs a
+    // build that unwinds on a panic, such as with the `serve` profile)
+    let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        run_job(&job.args, index, seq_tech, reload_handle)
+    }))
+    .unwrap_or_else(|_| Err(anyhow::anyhow!("the job panicked")));
+    let response = match res {
+        Ok(output) => json!({
+            "ok": true,
+            "output": output,
+            "wall_time_secs": resource_usage::wall_time_secs(),
+        }),
+        Err(e) => {
+            warn!("the job {:?} failed: {:#}", job.args, e);
+            json!({ "ok": false, "error": format!("{:#}", e) })
+        }
+    };
+    (response, false)
+}
+
+/// Read the jobs sent on the connection `stream`, one per line, and queue each on `jobs`,
+/// answering it once it has run. The connection is closed once it has been idle for
+/// [IDLE_TIMEOUT], so that an idle client does not hold on to it.
+fn serve_connection(stream: UnixStream, jobs: Sender<QueuedJob>) {
+    let mut writer = match stream
+        .set_read_timeout(Some(IDLE_TIMEOUT))
+        .and_then(|_| stream.try_clone())
+    {
+        Ok(writer) => writer,
+        Err(e) => {
+            warn!("could not set up a connection: {}", e);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        // the connection was closed, or was idle for too long
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (respond, response) = bounded(1);
+        let response = match jobs.send((line, respond)) {
+            Ok(()) => response.recv().unwrap_or_else(
+                |_| json!({ "ok": false, "error": "the server stopped before running the job" }),
+            ),
+            Err(_) => json!({ "ok": false, "error": "the server has stopped" }),
+        };
+        if let Err(e) = writeln!(writer, "{}", response) {
+            warn!("could not send the response to a job: {}", e);
+            break;
+        }
+    }
+}
+
+/// Bind the socket at `socket`, replacing a stale socket left by a server that has stopped.
+fn bind(socket: &Path) -> anyhow::Result<UnixListener> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            anyhow::bail!(
+                "another server is already listening on {}",
+                socket.display()
+            );
+        }
+        std::fs::remove_file(socket)?;
+    }
+    Ok(UnixListener::bind(socket)?)
+}
+
+/// Load the minimap2 index at `index` (built with the preset of `seq_tech`) once, and run
+/// the jobs sent to the socket at `socket`, one at a time, until a job asks to stop. Each
+/// line of a connection is a job, and is answered by a line holding a JSON object, with
+/// `"ok": true` and the `output` prefix of the run if it succeeded, and `"ok": false` and
+/// the `error` otherwise. Each connection is read by its own thread, which queues its jobs
+/// to be run in the order in which they are received.
+pub fn serve<S>(
+    index: &Path,
+    seq_tech: &SequencingTech,
+    socket: &Path,
+    threads: usize,
+    reload_handle: &reload::Handle<EnvFilter, S>,
+) -> anyhow::Result<()> {
+    let loaded = LoadedIndex::load(index, seq_tech, threads)?;
+    let listener = bind(socket)?;
+    info!(
+        "serving the index {} on {}.",
+        index.display(),
+        socket.display()
+    );
+
+    // the connections are accepted (and read) by other threads, while the jobs are run on
+    // this one
+    let (job_tx, job_rx) = unbounded::<QueuedJob>();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let jobs = job_tx.clone();
+                    std::thread::spawn(move || serve_connection(stream, jobs));
+                }
+                Err(e) => warn!("could not accept a connection: {}", e),
+            }
+        }
+    });
+    for (line, respond) in job_rx.iter() {
+        let (response, stop) = handle_job(&line, &loaded, seq_tech, reload_handle);
+        // the client may have gone away while its job ran
+        let _ = respond.send(response);
+        if stop {
+            break;
+        }
+    }
+    // the jobs queued behind the request to stop are not run
+    for (_, respond) in job_rx.try_iter() {
+        let _ = respond.send(json!({ "ok": false, "error": "the server has stopped" }));
+    }
+    std::fs::remove_file(socket)?;
+    info!("stopped serving on {}.", socket.display());
+    Ok(())
+}
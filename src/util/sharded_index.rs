@@ -0,0 +1,229 @@
+use crate::prog_opts::SequencingTech;
+use crate::util::digest_utils;
+use crate::util::object_store_io::StagedFile;
+use anyhow::{Context, bail};
+use needletail::parse_fastx_file;
+use num_format::{Locale, ToFormattedString};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tracing::info;
+
+/// The magic number at the start of each part of a minimap2 index.
+const MM_IDX_MAGIC: &[u8; 4] = b"MMI\x02";
+/// The magic number at the end of the digest footer appended by oarfish to the indices it builds.
+const OARFISH_FOOTER_MAGIC: &[u8] = b"OARFISHSIG";
+
+/// Parse a shard given as `<i>/<n>`, returning `(i, n)`.
+fn parse_shard(shard: &str) -> anyhow::Result<(usize, usize)> {
+    let parsed = shard
+        .split_once('/')
+        .and_then(|(i, n)| Some((i.trim().parse().ok()?, n.trim().parse().ok()?)));
+    match parsed {
+        Some((i, n)) if n > 0 && i < n => Ok((i, n)),
+        _ => bail!(
+            "the shard {:?} is not of the form <i>/<n>, with 0 <= i < n",
+            shard
+        ),
+    }
+}
+
+/// Write the `shard`-th of `num_shards` contiguous blocks of the targets of the FASTA
+/// `reference` to a temporary file, returning it and the number of targets it holds.
+fn write_shard_reference(
+    reference: &Path,
+    shard: usize,
+    num_shards: usize,
+) -> anyhow::Result<(StagedFile, usize)> {
+    // count the targets, so that the shards hold (nearly) equal numbers of them
+    let mut num_targets = 0_usize;
+    let mut reader = parse_fastx_file(reference)
+        .with_context(|| format!("could not read {}", reference.display()))?;
+    while let Some(result) = reader.next() {
+        result?;
+        num_targets += 1;
+    }
+    let start = shard * num_targets / num_shards;
+    let end = (shard + 1) * num_targets / num_shards;
+
+    let local =
+        std::env::temp_dir().join(format!("oarfish-{}-shard-{}.fa", std::process::id(), shard));
+    // the file is removed, even if writing it fails, once this is dropped
+    let staged = StagedFile::new(local);
+    let mut writer = BufWriter::new(File::create(staged.path())?);
+    let mut reader = parse_fastx_file(reference)?;
+    let mut i = 0_usize;
+    while let Some(result) = reader.next() {
+        let record = result?;
+        if i >= end {
+            break;
+        }
+        if i >= start {
+            writer.write_all(b">")?;
+            writer.write_all(record.id())?;
+            writer.write_all(b"\n")?;
+            writer.write_all(&record.seq())?;
+            writer.write_all(b"\n")?;
+        }
+        i += 1;
+    }
+    writer.flush()?;
+    Ok((staged, end - start))
+}
+
+/// Build the minimap2 index of the shard `shard` (given as `<i>/<n>`) of the FASTA
+/// `reference`, with the preset of `seq_tech`, and write it to `output`. The shards are
+/// contiguous blocks of the targets, so that merging the shard indices in order (with
+/// [merge_sketches]) yields an index of the targets in the order of the reference.
+pub fn build_shard(
+    reference: &Path,
+    seq_tech: SequencingTech,
+    shard: &str,
+    output: &Path,
+    threads: usize,
+) -> anyhow::Result<()> {
+    let (shard, num_shards) = parse_shard(shard)?;
+    let (shard_ref, num_targets) = write_shard_reference(reference, shard, num_shards)?;
+    anyhow::ensure!(
+        num_targets > 0,
+        "shard {} of {} of {} holds no targets",
+        shard,
+        num_shards,
+        reference.display()
+    );
+    info!(
+        "indexing shard {} of {} ({} targets) of {}.",
+        shard,
+        num_shards,
+        num_targets.to_formatted_string(&Locale::en),
+        reference.display()
+    );
+
+    let output_str = output
+        .to_str()
+        .context("could not convert the output path to a string")?;
+    let builder = match seq_tech {
+        SequencingTech::OntCDNA | SequencingTech::OntDRNA => minimap2::Aligner::builder().map_ont(),
+        SequencingTech::PacBio => minimap2::Aligner::builder().map_pb(),
+        SequencingTech::PacBioHifi => minimap2::Aligner::builder().map_hifi(),
+    };
+    let aligner = builder
+        .with_index_threads(threads.max(1))
+        .with_index(shard_ref.path(), Some(output_str))
+        .map_err(|e| anyhow::anyhow!("could not construct minimap2 index : {}", e))?;
+    info!(
+        "wrote the index of {} sequences to {}.",
+        aligner.n_seq().to_formatted_string(&Locale::en),
+        output.display()
+    );
+    Ok(())
+}
+
+/// The index parameters (`w`, `k`, `b` and `flag`) recorded in the header of the
+/// first part of the minimap2 index `path`.
+fn read_index_params(path: &Path) -> anyhow::Result<[u32; 4]> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("could not open {}", path.display()))?,
+    );
+    let mut magic = [0_u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MM_IDX_MAGIC {
+        bail!("{} is not a minimap2 index", path.display());
+    }
+    // w, k, b, n_seq and flag, as little-endian 32-bit integers
+    let mut fields = [0_u32; 5];
+    for f in fields.iter_mut() {
+        let mut buf = [0_u8; 4];
+        reader.read_exact(&mut buf)?;
+        *f = u32::from_le_bytes(buf);
+    }
+    Ok([fields[0], fields[1], fields[2], fields[4]])
+}
+
+/// The length of the minimap2 index `path`, excluding the digest footer appended
+/// by oarfish (if present).
+fn index_data_len(path: &Path) -> anyhow::Result<u64> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let magic_len = OARFISH_FOOTER_MAGIC.len() as u64;
+    // the footer is the digest json, its length (8 bytes), a version byte and the magic
+    if len < magic_len + 9 {
+        return Ok(len);
+    }
+    file.seek(SeekFrom::End(-(magic_len as i64)))?;
+    let mut magic = vec![0_u8; magic_len as usize];
+    file.read_exact(&mut magic)?;
+    if magic != OARFISH_FOOTER_MAGIC {
+        return Ok(len);
+    }
+    file.seek(SeekFrom::End(-(magic_len as i64 + 9)))?;
+    let mut json_len = [0_u8; 8];
+    file.read_exact(&mut json_len)?;
+    let footer_len = u64::from_le_bytes(json_len) + 9 + magic_len;
+    anyhow::ensure!(
+        footer_len <= len,
+        "the digest footer of {} is corrupt",
+        path.display()
+    );
+    Ok(len - footer_len)
+}
+
+/// Merge the shard indices `shards` (built with [build_shard]), given in the order of
+/// their shards, into the single multi-part minimap2 index `output`. If the FASTA
+/// `reference` of the shards is given, its digest is appended to the merged index (as
+/// for the indices built by oarfish), so that it need not be recomputed on each use.
+pub fn merge_sketches(
+    shards: &[std::path::PathBuf],
+    reference: Option<&Path>,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let params = read_index_params(&shards[0])?;
+    for s in shards.iter().skip(1) {
+        let p = read_index_params(s)?;
+        anyhow::ensure!(
+            p == params,
+            "the index parameters (w, k, b, flag) of {} are {:?}, but those of {} are {:?}; all shards must be built with the same --seq-tech",
+            s.display(),
+            p,
+            shards[0].display(),
+            params
+        );
+    }
+
+    let mut writer = BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&output)
+            .with_context(|| format!("could not create {}", output.display()))?,
+    );
+    // a multi-part minimap2 index is the concatenation of its parts
+    for s in shards {
+        let len = index_data_len(s)?;
+        let mut reader = BufReader::new(File::open(s)?).take(len);
+        std::io::copy(&mut reader, &mut writer)?;
+        info!("merged {} ({} bytes).", s.display(), len);
+    }
+    writer.flush()?;
+    drop(writer);
+
+    let output_str = output
+        .to_str()
+        .context("could not convert the output path to a string")?;
+    if let Some(reference) = reference {
+        info!("generating reference digest");
+        let mut seqcol_obj = seqcol_rs::SeqCol::try_from_fasta_file(reference.to_path_buf())?;
+        let digest = seqcol_obj.digest(seqcol_rs::DigestConfig {
+            level: seqcol_rs::DigestLevel::Level1,
+            additional_attr: vec![seqcol_rs::KnownAttr::SortedNameLengthPairs],
+        })?;
+        digest_utils::append_digest_to_mm2_index(output_str, &digest)?;
+    }
+    info!(
+        "merged {} shards into the index {}.",
+        shards.len(),
+        output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use anyhow::{Context, bail};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use tracing::info;
+
+/// The location of a spot of a spatial transcriptomics array.
+#[derive(Debug, Clone, Copy)]
+pub struct Spot {
+    pub x: f64,
+    pub y: f64,
+    /// the (row, column) of the spot on the array, if known
+    pub array_pos: Option<(i64, i64)>,
+    /// whether the spot is covered by the tissue, if known
+    pub in_tissue: Option<bool>,
+}
+
+/// The spots of a spatial chemistry, keyed by their barcodes.
+pub struct SpotMap {
+    spots: FxHashMap<Vec<u8>, Spot>,
+}
+
+/// The barcode without the `-<n>` suffix that 10x tools append to barcodes
+/// (e.g. `ACGT-1`), so that barcodes with and without it can be matched.
+fn strip_barcode_suffix(barcode: &[u8]) -> &[u8] {
+    match barcode.iter().rposition(|c| *c == b'-') {
+        Some(p) if p + 1 < barcode.len() && barcode[p + 1..].iter().all(u8::is_ascii_digit) => {
+            &barcode[..p]
+        }
+        _ => barcode,
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(
+    field: Option<&str>,
+    lnum: usize,
+    path: &Path,
+) -> anyhow::Result<T> {
+    field
+        .and_then(|f| f.trim().parse::<T>().ok())
+        .with_context(|| format!("could not parse line {} of {}", lnum + 1, path.display()))
+}
+
+/// Read the spots of a spatial chemistry from `path`, which is either a Space Ranger
+/// `tissue_positions` CSV file (with the columns `barcode`, `in_tissue`, `array_row`,
+/// `array_col`, `pxl_row_in_fullres` and `pxl_col_in_fullres`, with or without a header;
+/// the pixel column and row are taken as the x and y coordinates of the spot), or a
+/// tab-separated file with lines of the form `<barcode>\t<x>\t<y>`.
+pub fn read_spot_map(path: &Path) -> anyhow::Result<SpotMap> {
+    let file = File::open(path)
+        .with_context(|| format!("could not open spot coordinates {}", path.display()))?;
+    let is_csv = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("csv"));
+    let mut spots = FxHashMap::default();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("barcode") {
+            continue;
+        }
+        let (barcode, spot) = if is_csv {
+            let fields: Vec<&str> = line.split(',').collect();
+            if fields.len() < 6 {
+                bail!(
+                    "line {} of {} does not have the 6 columns of a tissue positions file",
+                    lnum + 1,
+                    path.display()
+                );
+            }
+            let in_tissue: u8 = parse_field(Some(fields[1]), lnum, path)?;
+            let spot = Spot {
+                x: parse_field(Some(fields[5]), lnum, path)?,
+                y: parse_field(Some(fields[4]), lnum, path)?,
+                array_pos: Some((
+                    parse_field(Some(fields[2]), lnum, path)?,
+                    parse_field(Some(fields[3]), lnum, path)?,
+                )),
+                in_tissue: Some(in_tissue != 0),
+            };
+            (fields[0], spot)
+        } else {
+            let mut fields = line.split('\t');
+            let barcode = fields.next().unwrap_or_default();
+            let spot = Spot {
+                x: parse_field(fields.next(), lnum, path)?,
+                y: parse_field(fields.next(), lnum, path)?,
+                array_pos: None,
+                in_tissue: None,
+            };
+            (barcode, spot)
+        };
+        let barcode = barcode.trim().to_ascii_uppercase();
+        spots.insert(strip_barcode_suffix(barcode.as_bytes()).to_vec(), spot);
+    }
+    anyhow::ensure!(
+        !spots.is_empty(),
+        "no spots were read from {}",
+        path.display()
+    );
+    info!(
+        "read the coordinates of {} spots from {}.",
+        spots.len(),
+        path.display()
+    );
+    Ok(SpotMap { spots })
+}
+
+impl SpotMap {
+    pub fn num_spots(&self) -> usize {
+        self.spots.len()
+    }
+
+    /// The spot with the (upper case) cell barcode `barcode`, if any.
+    pub fn get(&self, barcode: &[u8]) -> Option<&Spot> {
+        self.spots.get(strip_barcode_suffix(barcode))
+    }
+}
+
+/// The header line of the spots file.
+pub const SPOTS_HEADER: &str = "barcode\tx\ty\tarray_row\tarray_col\tin_tissue";
+
+/// A summary of the matching of the quantified barcodes to the spots.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpatialSummary {
+    pub num_spots: usize,
+    pub num_barcodes_with_spot: usize,
+    pub num_barcodes_without_spot: usize,
+    /// the number of quantified barcodes whose spot is covered by the tissue
+    pub num_barcodes_in_tissue: usize,
+}
+
+/// Write the spot of the barcode `barcode`, if it is known, as a line of the spots
+/// file (in the same order as the barcodes file), and count it in `summary`.
+pub fn write_spot<W: Write>(
+    writer: &mut W,
+    spots: &SpotMap,
+    barcode: &[u8],
+    summary: &mut SpatialSummary,
+) -> std::io::Result<()> {
+    let bc = String::from_utf8_lossy(barcode);
+    match spots.get(barcode) {
+        Some(s) => {
+            summary.num_barcodes_with_spot += 1;
+            summary.num_barcodes_in_tissue += (s.in_tissue == Some(true)) as usize;
+            let (row, col) = s
+                .array_pos
+                .map_or((String::from("NA"), String::from("NA")), |(r, c)| {
+                    (r.to_string(), c.to_string())
+                });
+            let in_tissue = s.in_tissue.map_or(String::from("NA"), |t| t.to_string());
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                bc, s.x, s.y, row, col, in_tissue
+            )
+        }
+        None => {
+            summary.num_barcodes_without_spot += 1;
+            writeln!(writer, "{}\tNA\tNA\tNA\tNA\tNA", bc)
+        }
+    }
+}
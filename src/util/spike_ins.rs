@@ -0,0 +1,308 @@
+use crate::prog_opts::OutputCompression;
+use crate::util::compressed_writer::CompressedWriter;
+use crate::util::provenance;
+use anyhow::bail;
+use path_tools::WithAdditionalExtension;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The spike-in transcripts of the reference (e.g. SIRVs or ERCCs), identified by the
+/// prefix of their names, and their expected concentrations (if known).
+pub struct SpikeIns {
+    /// for each transcript, true if it is a spike-in
+    pub is_spike_in: Vec<bool>,
+    /// for each transcript, its expected concentration, if it is a spike-in listed in the
+    /// concentration sheet
+    pub concentrations: Vec<Option<f64>>,
+}
+
+/// The agreement between the expected concentrations of the spike-ins and their
+/// estimated counts.
+#[derive(Debug, Serialize)]
+pub struct SpikeInCorrelation {
+    /// the number of spike-ins with a known (positive) concentration
+    pub num_spike_ins: usize,
+    /// the number of these spike-ins with a non-zero estimate
+    pub num_detected: usize,
+    /// the Pearson correlation between the log10 concentrations and the log10 estimated
+    /// counts of the detected spike-ins
+    pub pearson_log10: Option<f64>,
+    /// the Spearman correlation between the concentrations and the estimated counts of
+    /// all spike-ins with a known concentration
+    pub spearman: Option<f64>,
+    /// the slope of the log10 estimated counts on the log10 concentrations (1 if the
+    /// counts are proportional to the concentrations)
+    pub slope_log10: Option<f64>,
+}
+
+/// The spike-in report of a sample.
+#[derive(Debug, Serialize)]
+pub struct SpikeInReport {
+    pub prefixes: Vec<String>,
+    pub num_spike_in_txps: usize,
+    /// the estimated number of reads arising from the spike-ins
+    pub num_spike_in_reads: f64,
+    /// the estimated number of reads arising from the other (endogenous) transcripts
+    pub num_endogenous_reads: f64,
+    /// the fraction of the assigned reads arising from the spike-ins
+    pub frac_spike_in: f64,
+    /// the factor by which to multiply the estimated counts to express them per million
+    /// spike-in reads; since the same amount of spike-ins is added to each sample, this
+    /// normalizes for the amount of input RNA rather than for sequencing depth
+    pub scaling_factor: Option<f64>,
+    /// the expected amount (in the units of the concentration sheet) of input RNA per read,
+    /// estimated from the spike-ins with a known concentration
+    pub amount_per_read: Option<f64>,
+    pub correlation: Option<SpikeInCorrelation>,
+}
+
+fn pearson(x: &[f64], y: &[f64]) -> Option<f64> {
+    let n = x.len() as f64;
+    if x.len() < 3 {
+        return None;
+    }
+    let (mx, my) = (x.iter().sum::<f64>() / n, y.iter().sum::<f64>() / n);
+    let (mut sxy, mut sxx, mut syy) = (0.0, 0.0, 0.0);
+    for (a, b) in x.iter().zip(y.iter()) {
+        sxy += (a - mx) * (b - my);
+        sxx += (a - mx) * (a - mx);
+        syy += (b - my) * (b - my);
+    }
+    (sxx > 0.0 && syy > 0.0).then(|| sxy / (sxx * syy).sqrt())
+}
+
+fn slope(x: &[f64], y: &[f64]) -> Option<f64> {
+    let n = x.len() as f64;
+    if x.len() < 3 {
+        return None;
+    }
+    let (mx, my) = (x.iter().sum::<f64>() / n, y.iter().sum::<f64>() / n);
+    let sxy: f64 = x
+        .iter()
+        .zip(y.iter())
+        .map(|(a, b)| (a - mx) * (b - my))
+        .sum();
+    let sxx: f64 = x.iter().map(|a| (a - mx) * (a - mx)).sum();
+    (sxx > 0.0).then(|| sxy / sxx)
+}
+
+/// The (1-based) ranks of `vals`, with ties given their mean rank.
+fn ranks(vals: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..vals.len()).collect();
+    order.sort_unstable_by(|a, b| vals[*a].total_cmp(&vals[*b]));
+    let mut ranks = vec![0.0; vals.len()];
+    let mut i = 0;
+    while i < order.len() {
+        let mut j = i;
+        while j + 1 < order.len() && vals[order[j + 1]] == vals[order[i]] {
+            j += 1;
+        }
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        for k in order[i..=j].iter() {
+            ranks[*k] = rank;
+        }
+        i = j + 1;
+    }
+    ranks
+}
+
+/// Read the expected concentration of each spike-in from `path`, a tab-separated file
+/// with lines of the form `<spike-in>\t<concentration>`. Empty lines, lines starting
+/// with `#` and a header line are ignored.
+fn read_concentrations(path: &Path) -> anyhow::Result<HashMap<String, f64>> {
+    let file = File::open(path)?;
+    let mut concentrations = HashMap::new();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t').map(str::trim);
+        let (Some(name), Some(conc)) = (fields.next(), fields.next()) else {
+            bail!(
+                "line {} of spike-in concentration file {} should be of the form `<spike-in>\\t<concentration>`",
+                lnum + 1,
+                path.display()
+            );
+        };
+        match conc.parse::<f64>() {
+            Ok(c) if c >= 0.0 => {
+                concentrations.insert(name.to_owned(), c);
+            }
+            // the first line may be a header
+            _ if concentrations.is_empty() && lnum == 0 => {}
+            _ => bail!(
+                "could not parse the concentration {} of spike-in {} in {}",
+                conc,
+                name,
+                path.display()
+            ),
+        }
+    }
+    Ok(concentrations)
+}
+
+impl SpikeIns {
+    /// The spike-ins among the transcripts `txps_name` (those whose names start with one of
+    /// the `prefixes`), along with their concentrations from the sheet at `concentrations`,
+    /// if given.
+    pub fn new(
+        txps_name: &[String],
+        prefixes: &[String],
+        concentrations: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let is_spike_in: Vec<bool> = txps_name
+            .iter()
+            .map(|n| prefixes.iter().any(|p| n.starts_with(p.as_str())))
+            .collect();
+        let num_spike_ins = is_spike_in.iter().filter(|s| **s).count();
+        if num_spike_ins == 0 {
+            warn!(
+                "no transcript of the reference has a name starting with a spike-in prefix ({}); spike-in sequences must be part of the reference the reads are aligned to.",
+                prefixes.join(", ")
+            );
+        } else {
+            info!("found {} spike-in transcripts.", num_spike_ins);
+        }
+
+        let concentrations = match concentrations {
+            Some(path) => {
+                let sheet = read_concentrations(path)?;
+                let concs: Vec<Option<f64>> = txps_name
+                    .iter()
+                    .zip(is_spike_in.iter())
+                    .map(|(n, s)| if *s { sheet.get(n).copied() } else { None })
+                    .collect();
+                let num_found = concs.iter().filter(|c| c.is_some()).count();
+                if num_found < sheet.len() {
+                    warn!(
+                        "{} of the spike-ins in {} are not spike-in transcripts of the reference, and are ignored.",
+                        sheet.len() - num_found,
+                        path.display()
+                    );
+                }
+                concs
+            }
+            None => vec![None; txps_name.len()],
+        };
+        Ok(Self {
+            is_spike_in,
+            concentrations,
+        })
+    }
+
+    /// Summarize the estimated reads (`counts`) of the spike-ins relative to those of the
+    /// endogenous transcripts, and compare them to the expected concentrations, if known.
+    pub fn summarize(&self, counts: &[f64], prefixes: &[String]) -> SpikeInReport {
+        let mut num_spike_in_reads = 0.0;
+        let mut num_endogenous_reads = 0.0;
+        for (s, c) in self.is_spike_in.iter().zip(counts.iter()) {
+            if *s {
+                num_spike_in_reads += c;
+            } else {
+                num_endogenous_reads += c;
+            }
+        }
+        let total = num_spike_in_reads + num_endogenous_reads;
+
+        let known: Vec<(f64, f64)> = self
+            .concentrations
+            .iter()
+            .zip(counts.iter())
+            .filter_map(|(conc, c)| conc.filter(|x| *x > 0.0).map(|x| (x, *c)))
+            .collect();
+        let (amount_per_read, correlation) = if known.is_empty() {
+            (None, None)
+        } else {
+            let (concs, obs): (Vec<f64>, Vec<f64>) = known.iter().copied().unzip();
+            let (log_concs, log_obs): (Vec<f64>, Vec<f64>) = known
+                .iter()
+                .filter(|(_, c)| *c > 0.0)
+                .map(|(x, c)| (x.log10(), c.log10()))
+                .unzip();
+            let known_reads: f64 = obs.iter().sum();
+            let amount_per_read =
+                (known_reads > 0.0).then(|| concs.iter().sum::<f64>() / known_reads);
+            let correlation = SpikeInCorrelation {
+                num_spike_ins: known.len(),
+                num_detected: log_obs.len(),
+                pearson_log10: pearson(&log_concs, &log_obs),
+                spearman: pearson(&ranks(&concs), &ranks(&obs)),
+                slope_log10: slope(&log_concs, &log_obs),
+            };
+            (amount_per_read, Some(correlation))
+        };
+
+        let report = SpikeInReport {
+            prefixes: prefixes.to_vec(),
+            num_spike_in_txps: self.is_spike_in.iter().filter(|s| **s).count(),
+            num_spike_in_reads,
+            num_endogenous_reads,
+            frac_spike_in: if total > 0.0 {
+                num_spike_in_reads / total
+            } else {
+                0.0
+            },
+            scaling_factor: (num_spike_in_reads > 0.0).then(|| 1e6 / num_spike_in_reads),
+            amount_per_read,
+            correlation,
+        };
+        info!(
+            "spike-ins: {:.2} reads ({:.2}% of assigned reads); endogenous: {:.2} reads.",
+            report.num_spike_in_reads,
+            100.0 * report.frac_spike_in,
+            report.num_endogenous_reads
+        );
+        if let Some(ref corr) = report.correlation {
+            info!(
+                "{} of {} spike-ins with a known concentration detected; Pearson correlation (log10) of expected and observed: {}",
+                corr.num_detected,
+                corr.num_spike_ins,
+                corr.pearson_log10
+                    .map_or("NA".to_string(), |r| format!("{:.3}", r))
+            );
+        }
+        report
+    }
+}
+
+/// Write the estimated `counts` of the spike-ins to `<output>.spike_ins.quant`, with their
+/// expected concentrations (`NA` if unknown) and their counts per million spike-in reads.
+pub fn write_spike_in_quant(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    counts: &[f64],
+    spike_ins: &SpikeIns,
+    report: &SpikeInReport,
+    compression: OutputCompression,
+) -> anyhow::Result<()> {
+    let out_path = output.with_additional_extension(".spike_ins.quant");
+    let mut writer = CompressedWriter::create(&out_path, compression)?;
+    provenance::write_tsv_comment(&mut writer)?;
+    writeln!(
+        writer,
+        "tname\tlen\tnum_reads\tper_million_spike_in\texpected"
+    )?;
+    let factor = report.scaling_factor.unwrap_or(0.0);
+    for (i, (rseq, rmap)) in header.reference_sequences().iter().enumerate() {
+        if !spike_ins.is_spike_in[i] {
+            continue;
+        }
+        let expected = spike_ins.concentrations[i].map_or("NA".to_string(), |c| c.to_string());
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            rseq,
+            rmap.length(),
+            counts[i],
+            counts[i] * factor,
+            expected
+        )?;
+    }
+    writer.finish()?;
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use crate::util::oarfish_types::TranscriptInfo;
+use rayon::prelude::*;
+use tracing::{info, instrument};
+
+/// The number of (equally spaced) relative positions along a transcript at which the
+/// positional coverage profile of each length class is estimated.
+const NUM_PROFILE_KNOTS: usize = 20;
+/// The number of classes, delimited by quantiles of the lengths of the covered
+/// transcripts, for which a separate profile is fit.
+const NUM_LENGTH_CLASSES: usize = 5;
+/// The smallest (relative) coverage assigned to any position.
+const MIN_PROFILE_VALUE: f64 = 1e-3;
+
+/// A natural cubic spline interpolating the points `(xs[i], ys[i])`, whose `xs` are
+/// increasing; it is extrapolated linearly beyond the first and last points.
+struct NaturalCubicSpline {
+    xs: Vec<f64>,
+    ys: Vec<f64>,
+    /// the second derivatives of the spline at each of the `xs`
+    m: Vec<f64>,
+}
+
+impl NaturalCubicSpline {
+    fn new(xs: Vec<f64>, ys: Vec<f64>) -> Self {
+        let n = xs.len();
+        let mut m = vec![0.0; n];
+        if n > 2 {
+            // solve the tridiagonal system for the interior second derivatives
+            // (Thomas algorithm); those at the ends are 0 for a natural spline
+            let mut c_prime = vec![0.0; n];
+            let mut d_prime = vec![0.0; n];
+            for i in 1..n - 1 {
+                let h0 = xs[i] - xs[i - 1];
+                let h1 = xs[i + 1] - xs[i];
+                let a = h0;
+                let b = 2.0 * (h0 + h1);
+                let c = h1;
+                let d = 6.0 * ((ys[i + 1] - ys[i]) / h1 - (ys[i] - ys[i - 1]) / h0);
+                let denom = b - a * c_prime[i - 1];
+                c_prime[i] = c / denom;
+                d_prime[i] = (d - a * d_prime[i - 1]) / denom;
+            }
+            for i in (1..n - 1).rev() {
+                m[i] = d_prime[i] - c_prime[i] * m[i + 1];
+            }
+        }
+        Self { xs, ys, m }
+    }
+
+    fn eval(&self, x: f64) -> f64 {
+        let n = self.xs.len();
+        match n {
+            0 => return 1.0,
+            1 => return self.ys[0],
+            _ => {}
+        }
+        if x <= self.xs[0] {
+            let h = self.xs[1] - self.xs[0];
+            let slope = (self.ys[1] - self.ys[0]) / h - h * (2.0 * self.m[0] + self.m[1]) / 6.0;
+            return self.ys[0] + slope * (x - self.xs[0]);
+        }
+        if x >= self.xs[n - 1] {
+            let h = self.xs[n - 1] - self.xs[n - 2];
+            let slope = (self.ys[n - 1] - self.ys[n - 2]) / h
+                + h * (self.m[n - 2] + 2.0 * self.m[n - 1]) / 6.0;
+            return self.ys[n - 1] + slope * (x - self.xs[n - 1]);
+        }
+        let i = self.xs.partition_point(|xi| *xi <= x) - 1;
+        let h = self.xs[i + 1] - self.xs[i];
+        let a = (self.xs[i + 1] - x) / h;
+        let b = (x - self.xs[i]) / h;
+        a * self.ys[i]
+            + b * self.ys[i + 1]
+            + ((a * a * a - a) * self.m[i] + (b * b * b - b) * self.m[i + 1]) * h * h / 6.0
+    }
+}
+
+/// The relative position of the center of each bin of `t`, and the coverage density of
+/// the bin relative to the mean density of `t`.
+fn relative_profile(t: &TranscriptInfo) -> Vec<(f64, f64)> {
+    let (bin_counts, bin_lengths) = t.get_normalized_counts_and_lengths();
+    let densities: Vec<f64> = bin_counts
+        .iter()
+        .zip(bin_lengths.iter())
+        .map(|(c, l)| *c as f64 / *l as f64)
+        .collect();
+    let mean = densities.iter().sum::<f64>() / densities.len().max(1) as f64;
+    if mean <= 0.0 {
+        return Vec::new();
+    }
+    let mut start = 0.0_f64;
+    bin_lengths
+        .iter()
+        .zip(densities.iter())
+        .map(|(l, d)| {
+            let center = (start + *l as f64 / 2.0) / t.lenf;
+            start += *l as f64;
+            (center, d / mean)
+        })
+        .collect()
+}
+
+/// The relative position of the center of each bin of `t`.
+fn relative_profile_positions(t: &TranscriptInfo) -> impl Iterator<Item = f64> + '_ {
+    let num_bins = t.coverage_bins.len();
+    let bin_width = t.bin_length();
+    (0..num_bins).map(move |i| (((i as f64 + 0.5) * bin_width).min(t.lenf)) / t.lenf)
+}
+
+/// Compute the coverage probabilities of the bins of each transcript from a positional
+/// coverage profile shared by the transcripts of similar length (as in the positional
+/// bias model of salmon). The transcripts are divided into length classes, the coverage
+/// of the transcripts of each class (relative to their mean coverage, and weighted by
+/// their total coverage) is averaged at [NUM_PROFILE_KNOTS] relative positions, and the
+/// profile of each class is a natural cubic spline through these averages; the coverage
+/// probability of a bin is the value of the profile of its transcript's class at its
+/// center. Unlike the logistic model, which penalizes each bin by its departure from
+/// uniform coverage within its own transcript, this captures systematic biases (e.g. the
+/// 3' bias of many ONT cDNA libraries) without penalizing the reads that follow them.
+#[instrument(skip(txps))]
+pub fn spline_prob(txps: &mut [TranscriptInfo], threads: usize) {
+    info!("fitting positional coverage profiles");
+
+    // the length classes are delimited by quantiles of the lengths of the covered transcripts
+    let mut covered_lens: Vec<f64> = txps
+        .iter()
+        .filter(|t| t.total_weight > 0.0)
+        .map(|t| t.lenf)
+        .collect();
+    covered_lens.sort_unstable_by(f64::total_cmp);
+    let bounds: Vec<f64> = (1..NUM_LENGTH_CLASSES)
+        .filter_map(|c| {
+            let i = c * covered_lens.len() / NUM_LENGTH_CLASSES;
+            covered_lens.get(i).copied()
+        })
+        .collect();
+    let class_of = |t: &TranscriptInfo| bounds.partition_point(|b| *b <= t.lenf);
+
+    // accumulate the (weighted) relative coverage of each class at each knot
+    let mut sums = vec![[0.0_f64; NUM_PROFILE_KNOTS]; NUM_LENGTH_CLASSES];
+    let mut weights = vec![[0.0_f64; NUM_PROFILE_KNOTS]; NUM_LENGTH_CLASSES];
+    for t in txps.iter().filter(|t| t.total_weight > 0.0) {
+        let class = class_of(t);
+        for (pos, rel) in relative_profile(t) {
+            let k = ((pos * NUM_PROFILE_KNOTS as f64) as usize).min(NUM_PROFILE_KNOTS - 1);
+            sums[class][k] += t.total_weight * rel;
+            weights[class][k] += t.total_weight;
+        }
+    }
+    let splines: Vec<NaturalCubicSpline> = sums
+        .iter()
+        .zip(weights.iter())
+        .map(|(s, w)| {
+            let (xs, ys): (Vec<f64>, Vec<f64>) = (0..NUM_PROFILE_KNOTS)
+                .filter(|k| w[*k] > 0.0)
+                .map(|k| {
+                    (
+                        (k as f64 + 0.5) / NUM_PROFILE_KNOTS as f64,
+                        (s[k] / w[k]).max(MIN_PROFILE_VALUE),
+                    )
+                })
+                .unzip();
+            NaturalCubicSpline::new(xs, ys)
+        })
+        .collect();
+
+    let compute_txp_coverage_probs = |t: &mut TranscriptInfo| {
+        let spline = &splines[class_of(t)];
+        t.coverage_prob = relative_profile_positions(t)
+            .map(|pos| spline.eval(pos).max(MIN_PROFILE_VALUE))
+            .collect();
+    };
+
+    // if we are requesting only a single thread, then don't bother with
+    // the overhead of e.g. creating a thread pool and doing parallel
+    // iteration, etc.
+    if threads > 1 {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            txps.par_iter_mut().for_each(compute_txp_coverage_probs);
+        });
+    } else {
+        txps.iter_mut().for_each(compute_txp_coverage_probs);
+    }
+    info!("done");
+}
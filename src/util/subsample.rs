@@ -0,0 +1,78 @@
+use crate::prog_opts::Args;
+use rustc_hash::FxHasher;
+use std::hash::Hasher;
+use tracing::warn;
+
+/// Restricts the quantification to a subsample of the input reads: the first
+/// `--first-n-reads` reads of the input and/or a `--subsample-fraction` of them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadSubsample {
+    first_n: Option<u64>,
+    fraction: Option<f32>,
+    seed: u64,
+}
+
+impl ReadSubsample {
+    pub fn from_args(args: &Args) -> Self {
+        let subsample = Self {
+            first_n: args.first_n_reads,
+            fraction: args.subsample_fraction,
+            seed: seed(args),
+        };
+        if subsample.is_active() {
+            warn!(
+                "quantifying a subsample of the reads{}{}; the estimates are not those of the full sample.",
+                subsample
+                    .first_n
+                    .map_or(String::new(), |n| format!(" (the first {})", n)),
+                subsample
+                    .fraction
+                    .map_or(String::new(), |p| format!(" (a fraction {} of them)", p))
+            );
+        }
+        subsample
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.first_n.is_some() || self.fraction.is_some()
+    }
+
+    /// Whether `num_seen` reads exhaust the subsample, so that no more need be read.
+    #[inline]
+    pub fn is_done(&self, num_seen: u64) -> bool {
+        self.first_n.is_some_and(|n| num_seen >= n)
+    }
+
+    /// Whether the read `name` is in the subsample. Each read is kept with probability
+    /// `fraction`, as decided by a hash of its name and the seed, so that the same reads
+    /// are kept in every run with the same seed, whatever the order of the input.
+    #[inline]
+    pub fn keep(&self, name: &[u8]) -> bool {
+        let Some(fraction) = self.fraction else {
+            return true;
+        };
+        read_key(name, self.seed) < fraction as f64
+    }
+}
+
+/// The seed that determines which reads are kept with `--subsample-fraction`: the
+/// `--subsample-seed` if given, and otherwise the `--seed` of the run (or 0).
+pub fn seed(args: &Args) -> u64 {
+    args.subsample_seed.or(args.seed).unwrap_or(0)
+}
+
+/// A pseudo-random key in [0, 1) for the read `name`, determined by a hash of the name and
+/// `seed`. The reads whose keys are below `p` form a subsample of (about) a fraction `p` of
+/// the reads, and the subsamples of smaller fractions are nested within those of larger ones.
+#[inline]
+pub fn read_key(name: &[u8], seed: u64) -> f64 {
+    let mut hasher = FxHasher::default();
+    hasher.write_u64(seed);
+    hasher.write(name);
+    // the FxHash of short keys is poorly mixed, so finish it as in splitmix64
+    let mut h = hasher.finish();
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^= h >> 31;
+    (h >> 11) as f64 / (1_u64 << 53) as f64
+}
@@ -0,0 +1,259 @@
+use crate::prog_opts::TaxRank;
+use crate::util::oarfish_types::InMemoryAlignmentStore;
+use crate::util::provenance;
+use anyhow::bail;
+use path_tools::WithAdditionalExtension;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The taxon reported for sequences that are not listed in the taxonomy file,
+/// or whose lineage does not extend to the rank in question.
+pub const UNCLASSIFIED_TAXON: &str = "unclassified";
+
+/// The ranks of a lineage without rank prefixes, in order.
+const RANK_ORDER: [TaxRank; 8] = [
+    TaxRank::Domain,
+    TaxRank::Phylum,
+    TaxRank::Class,
+    TaxRank::Order,
+    TaxRank::Family,
+    TaxRank::Genus,
+    TaxRank::Species,
+    TaxRank::Strain,
+];
+
+/// The rank denoted by the (GTDB / Kraken style) prefix `<p>__` of a taxon.
+fn rank_of_prefix(p: &str) -> Option<TaxRank> {
+    match p {
+        "d" | "k" => Some(TaxRank::Domain),
+        "p" => Some(TaxRank::Phylum),
+        "c" => Some(TaxRank::Class),
+        "o" => Some(TaxRank::Order),
+        "f" => Some(TaxRank::Family),
+        "g" => Some(TaxRank::Genus),
+        "s" => Some(TaxRank::Species),
+        "t" => Some(TaxRank::Strain),
+        _ => None,
+    }
+}
+
+/// The taxon of `lineage` at `rank`, if the lineage extends to it. Taxa carrying
+/// a rank prefix (e.g. `g__Escherichia`) are matched by their prefix, and the others
+/// by their position in the lineage (domain, phylum, ..., species, strain).
+fn taxon_at_rank(lineage: &str, rank: TaxRank) -> Option<&str> {
+    lineage
+        .split(';')
+        .map(str::trim)
+        .enumerate()
+        .find(|(i, taxon)| {
+            let r = match taxon.split_once("__") {
+                Some((p, _)) => rank_of_prefix(p),
+                None => RANK_ORDER.get(*i).copied(),
+            };
+            r == Some(rank)
+        })
+        .map(|(_, taxon)| taxon)
+        .filter(|taxon| !taxon.is_empty() && !taxon.ends_with("__"))
+}
+
+/// The assignment of the sequences of the reference to the taxa of each rank.
+pub struct Taxonomy {
+    pub ranks: Vec<TaxRank>,
+    /// `names[r]` lists the taxa of rank `ranks[r]`
+    pub names: Vec<Vec<String>>,
+    /// `taxon_of[r][t]` is the index (into `names[r]`) of the taxon of sequence `t`
+    pub taxon_of: Vec<Vec<usize>>,
+}
+
+/// Read the lineage of each sequence from the tab-separated file at `path`, with
+/// lines of the form `<sequence>\t<lineage>` (empty lines and lines starting with
+/// `#` are ignored), and assign each sequence in `txps_name` to its taxon at each
+/// of the `ranks`.
+pub fn read_taxonomy(
+    path: &Path,
+    txps_name: &[String],
+    ranks: &[TaxRank],
+) -> anyhow::Result<Taxonomy> {
+    let file = File::open(path)?;
+    let mut lineages = HashMap::new();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, lineage)) = line.split_once('\t') else {
+            bail!(
+                "line {} of taxonomy file {} was not of the form <sequence>\\t<lineage>",
+                lnum + 1,
+                path.display()
+            );
+        };
+        lineages.insert(name.trim().to_owned(), lineage.trim().to_owned());
+    }
+
+    let num_found = txps_name
+        .iter()
+        .filter(|name| lineages.contains_key(name.as_str()))
+        .count();
+    if num_found < txps_name.len() {
+        warn!(
+            "{} sequences in the reference did not appear in the taxonomy file {}; they will be reported as {}.",
+            txps_name.len() - num_found,
+            path.display(),
+            UNCLASSIFIED_TAXON
+        );
+    }
+    info!(
+        "read lineages for {} of {} sequences.",
+        num_found,
+        txps_name.len()
+    );
+
+    let mut names = Vec::with_capacity(ranks.len());
+    let mut taxon_of = Vec::with_capacity(ranks.len());
+    for rank in ranks {
+        let mut rank_names = Vec::<String>::new();
+        let mut ids = HashMap::<&str, usize>::new();
+        let rank_taxa = txps_name
+            .iter()
+            .map(|name| {
+                let taxon = lineages
+                    .get(name)
+                    .and_then(|l| taxon_at_rank(l, *rank))
+                    .unwrap_or(UNCLASSIFIED_TAXON);
+                *ids.entry(taxon).or_insert_with(|| {
+                    rank_names.push(taxon.to_owned());
+                    rank_names.len() - 1
+                })
+            })
+            .collect();
+        names.push(rank_names);
+        taxon_of.push(rank_taxa);
+    }
+
+    Ok(Taxonomy {
+        ranks: ranks.to_vec(),
+        names,
+        taxon_of,
+    })
+}
+
+/// The aggregate estimates of all sequences of a single taxon.
+#[derive(Debug, Serialize)]
+pub struct TaxonSummary {
+    pub rank: TaxRank,
+    pub taxon: String,
+    /// the number of sequences of this taxon
+    pub num_seqs: usize,
+    /// the number of sequences of this taxon with a non-zero estimated count
+    pub num_expressed: usize,
+    /// the estimated number of reads arising from sequences of this taxon
+    pub num_reads: f64,
+    /// the number of reads all of whose alignments are to sequences of this taxon
+    pub unique_reads: usize,
+    /// the fraction of all estimated reads arising from sequences of this taxon
+    pub rel_abundance: f64,
+}
+
+/// Aggregate the estimated `counts` of the sequences by their taxa at each rank of
+/// `taxonomy`, returning the summaries of each rank (in the order of the ranks), and
+/// within each rank ordered by decreasing number of reads.
+///
+/// Since the EM distributes each read among the sequences to which it aligns, a read
+/// shared by several strains of a species contributes (in total) one read to the
+/// species, however it is split between the strains; the aggregate of a taxon is
+/// therefore well determined even when the estimates of its individual sequences are
+/// not. The `unique_reads` of each taxon count the reads that are unambiguous at its
+/// rank, even if they are ambiguous among its sequences.
+pub fn summarize_taxa(
+    taxonomy: &Taxonomy,
+    store: &InMemoryAlignmentStore,
+    counts: &[f64],
+) -> Vec<TaxonSummary> {
+    let total: f64 = counts.iter().sum();
+    let mut summaries = Vec::new();
+    for ((rank, names), taxon_of) in taxonomy
+        .ranks
+        .iter()
+        .zip(taxonomy.names.iter())
+        .zip(taxonomy.taxon_of.iter())
+    {
+        let mut rank_summaries: Vec<TaxonSummary> = names
+            .iter()
+            .map(|name| TaxonSummary {
+                rank: *rank,
+                taxon: name.clone(),
+                num_seqs: 0,
+                num_expressed: 0,
+                num_reads: 0.0,
+                unique_reads: 0,
+                rel_abundance: 0.0,
+            })
+            .collect();
+        for (t, c) in taxon_of.iter().zip(counts.iter()) {
+            let s = &mut rank_summaries[*t];
+            s.num_seqs += 1;
+            s.num_expressed += (*c > 0.0) as usize;
+            s.num_reads += c;
+        }
+        for (alns, _probs, _coverage_probs) in store.iter() {
+            let Some(first) = alns.first() else {
+                continue;
+            };
+            let taxon = taxon_of[first.ref_id as usize];
+            if alns.iter().all(|a| taxon_of[a.ref_id as usize] == taxon) {
+                rank_summaries[taxon].unique_reads += 1;
+            }
+        }
+        for s in rank_summaries.iter_mut() {
+            s.rel_abundance = if total > 0.0 {
+                s.num_reads / total
+            } else {
+                0.0
+            };
+        }
+        rank_summaries.sort_by(|a, b| {
+            b.num_reads
+                .total_cmp(&a.num_reads)
+                .then_with(|| a.taxon.cmp(&b.taxon))
+        });
+        summaries.extend(rank_summaries);
+    }
+    summaries
+}
+
+/// Write the per-taxon aggregates in `summaries` to `<output>.taxa.tsv`.
+pub fn write_taxon_summary(output: &PathBuf, summaries: &[TaxonSummary]) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".taxa.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    writeln!(
+        writer,
+        "rank\ttaxon\tnum_seqs\tnum_expressed\tnum_reads\tunique_reads\trel_abundance"
+    )?;
+    for s in summaries {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            s.rank,
+            s.taxon,
+            s.num_seqs,
+            s.num_expressed,
+            s.num_reads,
+            s.unique_reads,
+            s.rel_abundance
+        )?;
+    }
+    writer.flush()
+}
@@ -0,0 +1,116 @@
+use crate::util::oarfish_types::InMemoryAlignmentStore;
+use num_format::{Locale, ToFormattedString};
+use rustc_hash::FxHashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::info;
+
+/// The transcript-compatibility classes (the distinct sets of transcripts to which
+/// the reads align) seen across all cells, each with an id that is a column of the
+/// TCC count matrix. As in kallisto, the class of the single transcript `t` has id
+/// `t`, and the classes of several transcripts are numbered from the number of
+/// transcripts on, in the order in which they are first seen.
+pub struct TccTable {
+    num_txps: usize,
+    classes: Mutex<FxHashMap<Vec<u32>, u32>>,
+}
+
+impl TccTable {
+    pub fn new(num_txps: usize) -> Self {
+        Self {
+            num_txps,
+            classes: Mutex::new(FxHashMap::default()),
+        }
+    }
+
+    /// The number of classes, i.e. the number of columns of the TCC count matrix.
+    pub fn num_classes(&self) -> usize {
+        self.num_txps + self.classes.lock().unwrap().len()
+    }
+
+    /// Count the reads of each class among the reads in `store` (of a single cell),
+    /// returning the (class id, count) pairs in increasing order of class id. Each read
+    /// is also divided equally among its transcripts in `counts`, which takes the place of
+    /// the EM estimates in the metrics of the cell.
+    pub fn cell_counts(
+        &self,
+        store: &InMemoryAlignmentStore,
+        counts: &mut Vec<f64>,
+    ) -> Vec<(u32, f32)> {
+        counts.clear();
+        counts.resize(self.num_txps, 0.0);
+        let mut cell_classes = FxHashMap::<Vec<u32>, u32>::default();
+        let mut txps = Vec::<u32>::new();
+        for (alns, _probs, _coverage_probs) in store.iter() {
+            txps.clear();
+            txps.extend(alns.iter().map(|a| a.ref_id));
+            txps.sort_unstable();
+            txps.dedup();
+            if txps.is_empty() {
+                continue;
+            }
+            let share = 1.0 / txps.len() as f64;
+            for t in txps.iter() {
+                counts[*t as usize] += share;
+            }
+            match cell_classes.get_mut(txps.as_slice()) {
+                Some(n) => *n += 1,
+                None => {
+                    cell_classes.insert(txps.clone(), 1);
+                }
+            }
+        }
+
+        // the ids of the classes of several transcripts are shared by all cells, so
+        // they are looked up (or assigned) together, under a single lock
+        let mut entries = Vec::with_capacity(cell_classes.len());
+        {
+            let mut classes = self.classes.lock().unwrap();
+            for (txps, n) in cell_classes {
+                let id = if txps.len() == 1 {
+                    txps[0]
+                } else {
+                    let next_id = (self.num_txps + classes.len()) as u32;
+                    *classes.entry(txps).or_insert(next_id)
+                };
+                entries.push((id, n as f32));
+            }
+        }
+        entries.sort_unstable_by_key(|e| e.0);
+        entries
+    }
+
+    /// Write the classes to `path`, one line (`<id>\t<comma-separated transcript indices>`)
+    /// per class in order of id, as in the `matrix.ec` file of kallisto; the transcript
+    /// indices are (0-based) lines of the features file.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let classes = self.classes.lock().unwrap();
+        let mut multi: Vec<(&u32, &Vec<u32>)> = classes.iter().map(|(t, id)| (id, t)).collect();
+        multi.sort_unstable_by_key(|e| *e.0);
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        for t in 0..self.num_txps {
+            writeln!(writer, "{}\t{}", t, t)?;
+        }
+        for (id, txps) in multi {
+            write!(writer, "{}\t", id)?;
+            for (i, t) in txps.iter().enumerate() {
+                if i > 0 {
+                    writer.write_all(b",")?;
+                }
+                write!(writer, "{}", t)?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()?;
+        info!(
+            "wrote {} transcript-compatibility classes ({} of several transcripts) to {}.",
+            (self.num_txps + classes.len()).to_formatted_string(&Locale::en),
+            classes.len().to_formatted_string(&Locale::en),
+            path.display()
+        );
+        Ok(())
+    }
+}
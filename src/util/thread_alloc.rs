@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// How often the [ThreadBalancer] samples the fill of the queues of its stage.
+const MONITOR_INTERVAL: Duration = Duration::from_millis(100);
+/// The number of samples between successive adjustments of the number of active workers.
+const SAMPLES_PER_ADJUSTMENT: usize = 5;
+/// The weight given to each new sample in the moving average of a queue's fill.
+const FILL_SMOOTHING: f64 = 0.3;
+/// A queue whose (smoothed) fill exceeds this fraction of its capacity is backed up.
+const HIGH_FILL: f64 = 0.75;
+/// A queue whose (smoothed) fill is below this fraction of its capacity is starved.
+const LOW_FILL: f64 = 0.1;
+/// How long an inactive worker waits before checking again whether it may run.
+const PARKED_WAIT: Duration = Duration::from_millis(50);
+
+/// The initial allocation of the available threads when reading alignments from a BAM file.
+#[derive(Debug, Clone, Copy)]
+pub struct BamThreadPlan {
+    /// the number of bgzf decompression threads active at the start
+    pub decompression: usize,
+    /// the number of bgzf decompression threads that a [ThreadBalancer] may
+    /// activate at runtime
+    pub max_decompression: usize,
+    /// the (maximum) number of quantification workers running concurrently
+    /// with parsing (in single-cell mode), or the number of threads available
+    /// for quantification once parsing has finished (in bulk mode)
+    pub workers: usize,
+}
+
+impl BamThreadPlan {
+    /// Plan the use of `threads` threads. In bulk mode, the alignments are parsed
+    /// before any quantification takes place, so all but one thread (used for parsing)
+    /// are devoted to decompression. In single-cell mode, quantification is overlapped
+    /// with parsing, so only a few threads initially decompress the input, and the rest
+    /// are quantification workers. Both are throttled by [ThreadBalancer]s at runtime,
+    /// and the decompression threads may grow to all but one thread when decompression
+    /// is the bottleneck (the quantification workers, starved of cells, then pause).
+    pub fn new(threads: usize, single_cell: bool) -> Self {
+        if single_cell {
+            // <= 6 threads, use only 1 for decompression
+            // 7-8 threads, use 2 for decompression
+            // > 8 threads, use 3 for decompression
+            let decompression = match threads {
+                0..=6 => 1,
+                7 | 8 => 2,
+                _ => 3,
+            };
+            Self {
+                decompression,
+                max_decompression: threads.saturating_sub(1).max(decompression),
+                workers: threads.saturating_sub(decompression).max(1),
+            }
+        } else {
+            let decompression = threads.saturating_sub(1).max(1);
+            Self {
+                decompression,
+                max_decompression: decompression,
+                workers: threads.max(1),
+            }
+        }
+    }
+}
+
+/// Throttles the workers of a pipeline stage: limits the number of them that run at
+/// any time, and adjusts this limit at runtime according to the backpressure observed
+/// on the queues feeding (and, optionally, draining) the stage. If the input queue backs
+/// up, the stage is the bottleneck and more workers are activated; if the input
+/// queue is starved (the upstream stages, e.g. decompression and parsing, are the
+/// bottleneck) or the output queue backs up (the downstream stage is the bottleneck),
+/// workers are deactivated, leaving their cores to the other stages.
+///
+/// Each worker has an id in `[0, max_active)` and calls [ThreadBalancer::wait_turn]
+/// before taking more work; workers whose id is at least the current limit wait
+/// until they are reactivated, or until [ThreadBalancer::release_all] is called.
+#[derive(Debug)]
+pub struct ThreadBalancer {
+    stage: &'static str,
+    max_active: usize,
+    active: AtomicUsize,
+    released: AtomicBool,
+    num_adjustments: AtomicUsize,
+    lock: Mutex<()>,
+    cvar: Condvar,
+}
+
+/// Calls [ThreadBalancer::release_all] when dropped (including on panic), so that
+/// parked workers are never left waiting on a stage that has finished.
+pub struct ReleaseGuard(Arc<ThreadBalancer>);
+
+impl Drop for ReleaseGuard {
+    fn drop(&mut self) {
+        self.0.release_all();
+    }
+}
+
+impl ThreadBalancer {
+    /// A balancer for `max_active` workers of the pipeline stage `stage`
+    /// (used only for logging), all of which are initially active.
+    pub fn new(stage: &'static str, max_active: usize) -> Arc<Self> {
+        Self::with_active(stage, max_active, max_active)
+    }
+
+    /// A balancer for `max_active` workers of the pipeline stage `stage`, of which
+    /// `active` (at least one) are initially active.
+    pub fn with_active(stage: &'static str, max_active: usize, active: usize) -> Arc<Self> {
+        let max_active = max_active.max(1);
+        Arc::new(Self {
+            stage,
+            max_active,
+            active: AtomicUsize::new(active.clamp(1, max_active)),
+            released: AtomicBool::new(false),
+            num_adjustments: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            cvar: Condvar::new(),
+        })
+    }
+
+    #[inline]
+    pub fn num_active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// true if the worker with id `worker_id` may currently take more work.
+    #[inline]
+    pub fn is_active(&self, worker_id: usize) -> bool {
+        worker_id < self.num_active() || self.released.load(Ordering::Acquire)
+    }
+
+    /// Block the worker `worker_id` until it may take more work.
+    pub fn wait_turn(&self, worker_id: usize) {
+        if self.is_active(worker_id) {
+            return;
+        }
+        let mut guard = self.lock.lock().expect("thread balancer lock poisoned");
+        while !self.is_active(worker_id) {
+            guard = self
+                .cvar
+                .wait_timeout(guard, PARKED_WAIT)
+                .expect("thread balancer lock poisoned")
+                .0;
+        }
+    }
+
+    /// Activate all workers permanently (e.g. once the input of the stage is
+    /// exhausted, so that every worker can drain the remaining work and exit).
+    pub fn release_all(&self) {
+        self.released.store(true, Ordering::Release);
+        let _guard = self.lock.lock().expect("thread balancer lock poisoned");
+        self.cvar.notify_all();
+    }
+
+    /// A guard that calls [ThreadBalancer::release_all] when it is dropped.
+    pub fn release_on_drop(self: &Arc<Self>) -> ReleaseGuard {
+        ReleaseGuard(self.clone())
+    }
+
+    fn set_active(&self, n: usize) {
+        let prev = self.active.swap(n, Ordering::Relaxed);
+        if prev != n {
+            self.num_adjustments.fetch_add(1, Ordering::Relaxed);
+            debug!("{} : {} -> {} active workers", self.stage, prev, n);
+            if n > prev {
+                let _guard = self.lock.lock().expect("thread balancer lock poisoned");
+                self.cvar.notify_all();
+            }
+        }
+    }
+
+    /// Adjust the number of active workers (by at most one) given the smoothed
+    /// fill (as a fraction of capacity) of the input queue of the stage and, if
+    /// there is one, of its output queue.
+    pub fn adjust(&self, input_fill: f64, output_fill: Option<f64>) {
+        let n = self.num_active();
+        if output_fill.is_some_and(|f| f > HIGH_FILL) || input_fill < LOW_FILL {
+            self.set_active(n.saturating_sub(1).max(1));
+        } else if input_fill > HIGH_FILL {
+            self.set_active((n + 1).min(self.max_active));
+        }
+    }
+
+    /// Periodically sample the fill of the queues of the stage with `sample`
+    /// (returning the fill of the input queue and, optionally, the output queue,
+    /// as fractions of their capacities) and adjust the number of active workers
+    /// accordingly, until [ThreadBalancer::release_all] is called. This is meant
+    /// to be run on its own (mostly sleeping) thread.
+    pub fn monitor<F>(&self, sample: F)
+    where
+        F: Fn() -> (f64, Option<f64>),
+    {
+        let mut input_fill = 0.5_f64;
+        let mut output_fill: Option<f64> = None;
+        let mut num_samples = 0_usize;
+        while !self.released.load(Ordering::Acquire) {
+            std::thread::sleep(MONITOR_INTERVAL);
+            let (inf, outf) = sample();
+            input_fill += FILL_SMOOTHING * (inf - input_fill);
+            output_fill = outf.map(|f| {
+                let prev = output_fill.unwrap_or(f);
+                prev + FILL_SMOOTHING * (f - prev)
+            });
+            num_samples += 1;
+            if num_samples % SAMPLES_PER_ADJUSTMENT == 0 {
+                self.adjust(input_fill, output_fill);
+            }
+        }
+        info!(
+            "{} : finished with {} of {} workers active ({} adjustments).",
+            self.stage,
+            self.num_active(),
+            self.max_active,
+            self.num_adjustments.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// The fill of a queue of length `len` and capacity `cap`, as a fraction of its capacity.
+#[inline]
+pub fn queue_fill(len: usize, cap: Option<usize>) -> f64 {
+    match cap {
+        Some(c) if c > 0 => len as f64 / c as f64,
+        _ => 0.0,
+    }
+}
@@ -0,0 +1,235 @@
+use crate::util::bam_output::revcomp;
+use anyhow::Context;
+use needletail::parse_fastx_file;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::info;
+
+/// The name of the built-in set of the primers of the ONT cDNA kits.
+pub const ONT_CDNA: &str = "ont-cdna";
+
+/// The strand-switching (SSP) and VN (VNP) primers of the ONT cDNA-PCR kits, as used by
+/// pychopper.
+const ONT_CDNA_PRIMERS: [(&str, &[u8]); 2] = [
+    ("SSP", b"TTTCTGTTGGTGCTGATATTGCTGGG"),
+    ("VNP", b"ACTTGCCTGTCGCTCTATCTTC"),
+];
+
+/// An adapter or primer sequence to trim, which is searched for in both orientations.
+pub(crate) struct Adapter {
+    pub(crate) name: String,
+    pub(crate) len: usize,
+    /// the sequence and its reverse complement, searched for at the start of a read
+    pub(crate) forward: [Vec<u8>; 2],
+    /// the reverse of each of `forward`, searched for in the reversed end of a read
+    pub(crate) reversed: [Vec<u8>; 2],
+}
+
+impl Adapter {
+    pub(crate) fn new(name: String, seq: Vec<u8>) -> Self {
+        let rc = revcomp(&seq);
+        let reversed = [
+            seq.iter().rev().copied().collect(),
+            rc.iter().rev().copied().collect(),
+        ];
+        Self {
+            name,
+            len: seq.len(),
+            forward: [seq, rc],
+            reversed,
+        }
+    }
+}
+
+/// The match of an adapter within the end of a read.
+#[derive(Debug, Clone, Copy)]
+struct AdapterMatch {
+    /// the index of the adapter
+    adapter: usize,
+    /// the number of edits of the match
+    dist: usize,
+    /// the number of bases trimmed from the end of the read by the match
+    trimmed: usize,
+}
+
+/// Trims the adapter and primer sequences found at either end of the reads before
+/// they are mapped.
+pub struct AdapterTrimmer {
+    adapters: Vec<Adapter>,
+    /// the number of bases at each end of a read that are searched
+    search_len: usize,
+    /// the largest fraction of the bases of an adapter that may be edited in a match
+    max_error_rate: f32,
+}
+
+/// The number of reads, and of their bases, trimmed before mapping.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TrimStats {
+    /// the adapter and primer sequences searched for (by `--trim-adapters`)
+    pub adapters: String,
+    /// the number of reads searched
+    pub num_reads: usize,
+    /// the number of reads trimmed at their start
+    pub num_trimmed_start: usize,
+    /// the number of reads trimmed at their end
+    pub num_trimmed_end: usize,
+    /// the number of reads trimmed at both ends
+    pub num_trimmed_both: usize,
+    /// the number of bases trimmed, over all reads
+    pub num_bases_trimmed: u64,
+    /// the number of matches of each adapter, in either orientation and at either end
+    pub adapter_matches: Vec<(String, usize)>,
+}
+
+impl TrimStats {
+    pub fn merge(&mut self, other: &Self) {
+        self.num_reads += other.num_reads;
+        self.num_trimmed_start += other.num_trimmed_start;
+        self.num_trimmed_end += other.num_trimmed_end;
+        self.num_trimmed_both += other.num_trimmed_both;
+        self.num_bases_trimmed += other.num_bases_trimmed;
+        for ((_, n), (_, m)) in self
+            .adapter_matches
+            .iter_mut()
+            .zip(other.adapter_matches.iter())
+        {
+            *n += m;
+        }
+    }
+}
+
+/// The best match of `pattern` in `text` (with at most `max_dist` edits), as the number
+/// of edits and the position following the last base of the match in `text`. Among the
+/// matches with the fewest edits, the one ending furthest into `text` is chosen. This
+/// is Sellers' algorithm, in which a match may begin anywhere in `text`.
+pub(crate) fn best_match(pattern: &[u8], text: &[u8], max_dist: usize) -> Option<(usize, usize)> {
+    let m = pattern.len();
+    // col[i] is the fewest edits of a match of pattern[..i] ending at the current base
+    let mut col: Vec<usize> = (0..=m).collect();
+    let mut best: Option<(usize, usize)> = None;
+    for (j, &t) in text.iter().enumerate() {
+        let mut diag = col[0];
+        for i in 1..=m {
+            let sub = diag + usize::from(!pattern[i - 1].eq_ignore_ascii_case(&t));
+            diag = col[i];
+            col[i] = sub.min(col[i] + 1).min(col[i - 1] + 1);
+        }
+        let dist = col[m];
+        if dist <= max_dist && best.is_none_or(|(d, _)| dist <= d) {
+            best = Some((dist, j + 1));
+        }
+    }
+    best
+}
+
+impl AdapterTrimmer {
+    /// A trimmer for the adapters `spec`, either the name of a built-in set ([`ONT_CDNA`])
+    /// or the path of a FASTA file holding the sequences to trim.
+    pub fn new(spec: &str, search_len: usize, max_error_rate: f32) -> anyhow::Result<Self> {
+        let named: Vec<(String, Vec<u8>)> = if spec == ONT_CDNA {
+            ONT_CDNA_PRIMERS
+                .iter()
+                .map(|(name, seq)| (name.to_string(), seq.to_vec()))
+                .collect()
+        } else {
+            let mut reader = parse_fastx_file(Path::new(spec))
+                .with_context(|| format!("could not read the adapters in {}", spec))?;
+            let mut named = Vec::new();
+            while let Some(rec) = reader.next() {
+                let rec = rec?;
+                let id = rec.id();
+                let name = id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id);
+                named.push((
+                    String::from_utf8_lossy(name).into_owned(),
+                    rec.seq().to_ascii_uppercase(),
+                ));
+            }
+            named
+        };
+        anyhow::ensure!(
+            !named.is_empty(),
+            "no adapter or primer sequences were found in {}",
+            spec
+        );
+        info!(
+            "trimming the adapters {} from the {} bases at each end of the reads.",
+            named
+                .iter()
+                .map(|(n, _)| n.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            search_len
+        );
+        Ok(Self {
+            adapters: named
+                .into_iter()
+                .map(|(name, seq)| Adapter::new(name, seq))
+                .collect(),
+            search_len,
+            max_error_rate,
+        })
+    }
+
+    /// Empty statistics for the adapters of this trimmer, described as `spec`.
+    pub fn new_stats(&self, spec: &str) -> TrimStats {
+        TrimStats {
+            adapters: spec.to_owned(),
+            adapter_matches: self.adapters.iter().map(|a| (a.name.clone(), 0)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The best match of any adapter (in either orientation) within the first bases of
+    /// `window`, which is the start of a read or, reversed, its end.
+    fn best_end_match(&self, window: &[u8], reversed: bool) -> Option<AdapterMatch> {
+        let mut best: Option<AdapterMatch> = None;
+        for (k, a) in self.adapters.iter().enumerate() {
+            let max_dist = (self.max_error_rate * a.len as f32).floor() as usize;
+            let patterns = if reversed { &a.reversed } else { &a.forward };
+            for pattern in patterns {
+                let Some((dist, trimmed)) = best_match(pattern, window, max_dist) else {
+                    continue;
+                };
+                // the fewest edits win, and then the match trimming the most bases
+                if best.is_none_or(|b| dist < b.dist || (dist == b.dist && trimmed > b.trimmed)) {
+                    best = Some(AdapterMatch {
+                        adapter: k,
+                        dist,
+                        trimmed,
+                    });
+                }
+            }
+        }
+        best
+    }
+
+    /// The part of `seq` left once the adapters found at either of its ends have been
+    /// trimmed, recording the trimming in `stats`.
+    pub fn trim<'a>(&self, seq: &'a [u8], stats: &mut TrimStats) -> &'a [u8] {
+        stats.num_reads += 1;
+        let w = self.search_len.min(seq.len());
+        let start = self
+            .best_end_match(&seq[..w], false)
+            .inspect(|m| stats.adapter_matches[m.adapter].1 += 1)
+            .map_or(0, |m| m.trimmed);
+        let rev_end: Vec<u8> = seq[seq.len() - w..].iter().rev().copied().collect();
+        let end = self
+            .best_end_match(&rev_end, true)
+            .inspect(|m| stats.adapter_matches[m.adapter].1 += 1)
+            .map_or(seq.len(), |m| seq.len() - m.trimmed);
+        if start > 0 {
+            stats.num_trimmed_start += 1;
+        }
+        if end < seq.len() {
+            stats.num_trimmed_end += 1;
+        }
+        if start > 0 && end < seq.len() {
+            stats.num_trimmed_both += 1;
+        }
+        // a read made entirely of adapters (or short enough for the matches at either end
+        // to overlap) is left empty
+        let end = end.max(start);
+        stats.num_bases_trimmed += (seq.len() - (end - start)) as u64;
+        &seq[start..end]
+    }
+}
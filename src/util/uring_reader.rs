@@ -0,0 +1,253 @@
+use crossbeam::channel::{Receiver, Sender, bounded};
+use io_uring::{IoUring, opcode, types};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// The size of each read submitted to the ring.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// The number of reads that are in flight at once.
+const QUEUE_DEPTH: usize = 8;
+/// The number of chunks that have been read, but not yet consumed.
+const PREFETCH_CHUNKS: usize = 8;
+
+/// A chunk of the file being read into by the ring.
+struct Slot {
+    buf: Vec<u8>,
+    offset: u64,
+    filled: usize,
+}
+
+/// Submit a read of the unfilled part of `slot` (identified by `id`) to `ring`.
+fn submit_read(ring: &mut IoUring, fd: types::Fd, slot: &mut Slot, id: u64) -> io::Result<()> {
+    let rest = &mut slot.buf[slot.filled..];
+    let entry = opcode::Read::new(fd, rest.as_mut_ptr(), rest.len() as u32)
+        .offset(slot.offset + slot.filled as u64)
+        .build()
+        .user_data(id);
+    // SAFETY: the buffer of the slot stays alive (and is not otherwise accessed) until
+    // the completion of this read has been reaped
+    unsafe {
+        ring.submission()
+            .push(&entry)
+            .map_err(|_| io::Error::other("the io_uring submission queue is full"))?;
+    }
+    Ok(())
+}
+
+/// Read `file` (of `size` bytes) through `ring`, keeping up to [QUEUE_DEPTH] reads of
+/// consecutive chunks in flight, and hand the chunks over to `tx` in order.
+fn pump(
+    ring: &mut IoUring,
+    file: &File,
+    size: u64,
+    tx: &Sender<io::Result<Vec<u8>>>,
+    pending: &mut VecDeque<Slot>,
+    in_flight: &mut usize,
+) -> io::Result<()> {
+    let fd = types::Fd(file.as_raw_fd());
+    // the id of the first slot of `pending`
+    let mut first_id = 0_u64;
+    let mut next_offset = 0_u64;
+    loop {
+        while pending.len() < QUEUE_DEPTH && next_offset < size {
+            let len = (CHUNK_SIZE as u64).min(size - next_offset) as usize;
+            pending.push_back(Slot {
+                buf: vec![0_u8; len],
+                offset: next_offset,
+                filled: 0,
+            });
+            let id = first_id + pending.len() as u64 - 1;
+            let slot = pending.back_mut().expect("a slot was just added");
+            submit_read(ring, fd, slot, id)?;
+            *in_flight += 1;
+            next_offset += len as u64;
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        ring.submit_and_wait(1)?;
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|c| (c.user_data(), c.result()))
+            .collect();
+        *in_flight -= completed.len();
+        for (id, res) in completed {
+            if res < 0 {
+                return Err(io::Error::from_raw_os_error(-res));
+            }
+            if res == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the file ended before its expected size",
+                ));
+            }
+            let slot = &mut pending[(id - first_id) as usize];
+            slot.filled += res as usize;
+            // a short read; read the rest of the chunk
+            if slot.filled < slot.buf.len() {
+                submit_read(ring, fd, slot, id)?;
+                *in_flight += 1;
+            }
+        }
+
+        while pending.front().is_some_and(|s| s.filled == s.buf.len()) {
+            let slot = pending.pop_front().expect("the front slot exists");
+            first_id += 1;
+            // stop if the reader has gone away
+            if tx.send(Ok(slot.buf)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Reads a local file through io_uring; a background thread keeps several reads of
+/// consecutive chunks of the file in flight, so that the disk is kept busy while the
+/// chunks already read are decompressed, and hands them over in order.
+pub struct UringReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl UringReader {
+    /// Open the file at `path`, returning the reader and the size of the file. This fails
+    /// if an io_uring can not be created (e.g. if the kernel is too old, or io_uring is
+    /// disabled).
+    pub fn open(path: &Path) -> io::Result<(Self, u64)> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut ring = IoUring::new(QUEUE_DEPTH as u32)?;
+
+        let (tx, rx) = bounded(PREFETCH_CHUNKS);
+        std::thread::spawn(move || {
+            let mut pending = VecDeque::with_capacity(QUEUE_DEPTH);
+            let mut in_flight = 0_usize;
+            let res = pump(&mut ring, &file, size, &tx, &mut pending, &mut in_flight);
+            // the buffers of the reads still in flight must outlive them
+            while in_flight > 0 {
+                if ring.submit_and_wait(1).is_err() {
+                    // the buffers are leaked rather than risk their being written once freed
+                    std::mem::forget(pending);
+                    break;
+                }
+                in_flight -= ring.completion().count();
+            }
+            if let Err(e) = res {
+                let _ = tx.send(Err(e));
+            }
+        });
+
+        Ok((
+            Self {
+                chunks: rx,
+                current: Vec::new(),
+                pos: 0,
+            },
+            size,
+        ))
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => {
+                    self.current = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(e),
+                // the whole file has been read
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("oarfish-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// Open `path` with a [UringReader], or [None] if io_uring is unavailable here.
+    fn open(path: &Path) -> Option<(UringReader, u64)> {
+        match UringReader::open(path) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                eprintln!("skipping the io_uring test: {}", e);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn reads_whole_file_in_order() {
+        // more chunks than are ever in flight, and a partial last chunk
+        let len = CHUNK_SIZE * (QUEUE_DEPTH + PREFETCH_CHUNKS + 1) + 12_345;
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let path = temp_file("uring-whole.bin", &data);
+        if let Some((mut reader, size)) = open(&path) {
+            assert_eq!(size, len as u64);
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).unwrap();
+            assert!(out == data, "the file was not read back intact");
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_small_and_empty_files() {
+        let path = temp_file("uring-small.bin", b"@read1\nACGT\n+\nIIII\n");
+        if let Some((mut reader, _)) = open(&path) {
+            // small reads, that split the chunk
+            let mut out = Vec::new();
+            let mut buf = [0_u8; 3];
+            loop {
+                let n = reader.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                out.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(out, b"@read1\nACGT\n+\nIIII\n");
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let path = temp_file("uring-empty.bin", b"");
+        if let Some((mut reader, size)) = open(&path) {
+            assert_eq!(size, 0);
+            let mut out = Vec::new();
+            assert_eq!(reader.read_to_end(&mut out).unwrap(), 0);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stops_when_dropped_early() {
+        let data = vec![7_u8; CHUNK_SIZE * (QUEUE_DEPTH + PREFETCH_CHUNKS + 2)];
+        let path = temp_file("uring-drop.bin", &data);
+        if let Some((mut reader, _)) = open(&path) {
+            let mut buf = [0_u8; 16];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [7_u8; 16]);
+            // the background thread must notice, rather than block on the full channel
+            drop(reader);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+use crate::util::constants::EM_DENOM_THRESH;
+use crate::util::oarfish_types::InMemoryAlignmentStore;
+use anyhow::{Context, bail};
+use itertools::izip;
+use num_format::{Locale, ToFormattedString};
+use rustc_hash::FxHashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// The splicing status of the reads assigned to a gene, in the order in which
+/// the corresponding blocks of columns appear in a USA-mode count matrix.
+const NUM_SPLICING_STATUSES: usize = 3;
+const SPLICED: usize = 0;
+const UNSPLICED: usize = 1;
+const AMBIGUOUS: usize = 2;
+
+/// The gene, and splicing status, of each target of a reference containing
+/// both spliced (transcript) and unspliced (intron-containing) targets.
+pub struct UsaMap {
+    /// the genes, in order of their first appearance in the map
+    genes: Vec<String>,
+    /// the gene of each target, and whether it is an unspliced target
+    txp_gene: Vec<Option<(u32, bool)>>,
+}
+
+/// Read the 3-column (`<target>\t<gene>\t<S|U>`) transcript-to-gene file at `path`
+/// (as used by alevin-fry for a spliced+intronic reference), for the targets named
+/// `txps_name`.
+pub fn read_usa_map(path: &Path, txps_name: &[String]) -> anyhow::Result<UsaMap> {
+    let file = File::open(path)
+        .with_context(|| format!("could not open USA-mode map {}", path.display()))?;
+    let mut gene_ids: FxHashMap<String, u32> = FxHashMap::default();
+    let mut genes = Vec::new();
+    let mut target_genes: FxHashMap<String, (u32, bool)> = FxHashMap::default();
+    for (lnum, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let [name, gene, status] = fields[..] else {
+            bail!(
+                "line {} of USA-mode map {} was not of the form <target>\\t<gene>\\t<S|U>",
+                lnum + 1,
+                path.display()
+            );
+        };
+        let unspliced = match status {
+            "S" | "s" => false,
+            "U" | "u" => true,
+            _ => bail!(
+                "line {} of USA-mode map {} has splicing status {:?}; expected S or U",
+                lnum + 1,
+                path.display(),
+                status
+            ),
+        };
+        let gid = *gene_ids.entry(gene.to_owned()).or_insert_with(|| {
+            genes.push(gene.to_owned());
+            (genes.len() - 1) as u32
+        });
+        target_genes.insert(name.to_owned(), (gid, unspliced));
+    }
+
+    let txp_gene: Vec<Option<(u32, bool)>> = txps_name
+        .iter()
+        .map(|n| target_genes.get(n).copied())
+        .collect();
+    let num_found = txp_gene.iter().filter(|g| g.is_some()).count();
+    if num_found < txps_name.len() {
+        warn!(
+            "{} targets did not appear in the USA-mode map {}; reads assigned to them will not be counted.",
+            (txps_name.len() - num_found).to_formatted_string(&Locale::en),
+            path.display()
+        );
+    }
+    let num_unspliced = txp_gene.iter().flatten().filter(|(_, u)| *u).count();
+    if num_unspliced == 0 {
+        warn!(
+            "none of the targets are unspliced according to {}; the reference should contain the unspliced (intronic) targets of the genes.",
+            path.display()
+        );
+    }
+    info!(
+        "USA mode : {} genes, with {} spliced and {} unspliced targets.",
+        genes.len().to_formatted_string(&Locale::en),
+        (num_found - num_unspliced).to_formatted_string(&Locale::en),
+        num_unspliced.to_formatted_string(&Locale::en)
+    );
+    Ok(UsaMap { genes, txp_gene })
+}
+
+impl UsaMap {
+    pub fn genes(&self) -> &[String] {
+        &self.genes
+    }
+
+    /// The gene id (the index in [UsaMap::genes]) of each target.
+    pub fn txp_gene_ids(&self) -> Vec<Option<u32>> {
+        self.txp_gene
+            .iter()
+            .map(|g| g.map(|(gid, _)| gid))
+            .collect()
+    }
+
+    /// The number of columns of the USA-mode count matrix; the spliced, unspliced
+    /// and ambiguous counts of all genes, in that order.
+    pub fn num_cols(&self) -> usize {
+        NUM_SPLICING_STATUSES * self.genes.len()
+    }
+
+    /// The name of the column `col` of the USA-mode count matrix: its gene and its
+    /// splicing status.
+    pub fn col_name(&self, col: usize) -> String {
+        let status = match col / self.genes.len() {
+            SPLICED => "spliced",
+            UNSPLICED => "unspliced",
+            _ => "ambiguous",
+        };
+        format!("{} ({})", self.genes[col % self.genes.len()], status)
+    }
+
+    /// Fill `gene_counts` with the spliced, unspliced and ambiguous counts of each gene,
+    /// given the estimated target `counts` of the reads in `store`. Each read is divided
+    /// among the genes according to its posterior assignment probabilities; its share of a
+    /// gene is spliced (unspliced) if it aligns only to spliced (unspliced) targets of that
+    /// gene, and ambiguous if it aligns to both.
+    pub fn cell_counts(
+        &self,
+        store: &InMemoryAlignmentStore,
+        counts: &[f64],
+        gene_counts: &mut Vec<f64>,
+    ) {
+        let num_genes = self.genes.len();
+        gene_counts.clear();
+        gene_counts.resize(self.num_cols(), 0.0);
+        let model_coverage = store.filter_opts.model_coverage;
+
+        // the genes to which the current read aligns, with the read's
+        // assignment probability and the splicing status of its alignments
+        let mut read_genes: Vec<(u32, f64, bool, bool)> = Vec::with_capacity(4);
+        for (alns, probs, coverage_probs) in store.iter() {
+            let mut denom = 0.0_f64;
+            for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
+                let cov_prob = if model_coverage { *cp } else { 1.0 };
+                denom += counts[a.ref_id as usize] * (*p as f64) * cov_prob;
+            }
+            if denom <= EM_DENOM_THRESH {
+                continue;
+            }
+
+            read_genes.clear();
+            for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
+                let target_id = a.ref_id as usize;
+                let Some((gene, unspliced)) = self.txp_gene[target_id] else {
+                    continue;
+                };
+                let cov_prob = if model_coverage { *cp } else { 1.0 };
+                let prob = (counts[target_id] * (*p as f64) * cov_prob) / denom;
+                match read_genes.iter_mut().find(|(g, ..)| *g == gene) {
+                    Some((_, gp, s, u)) => {
+                        *gp += prob;
+                        *s |= !unspliced;
+                        *u |= unspliced;
+                    }
+                    None => read_genes.push((gene, prob, !unspliced, unspliced)),
+                }
+            }
+
+            for (gene, prob, s, u) in read_genes.iter() {
+                let status = match (s, u) {
+                    (true, true) => AMBIGUOUS,
+                    (false, true) => UNSPLICED,
+                    _ => SPLICED,
+                };
+                gene_counts[status * num_genes + *gene as usize] += prob;
+            }
+        }
+    }
+}
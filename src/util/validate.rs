@@ -0,0 +1,245 @@
+use crate::alignment_parser;
+use crate::prog_opts::{Args, ReferenceMismatchMode};
+use crate::util::alignment_cache;
+use crate::util::digest_utils;
+use crate::util::oarfish_types::AlignmentFilters;
+use crate::util::object_store_io;
+use crate::util::output_schema::OUTPUT_SCHEMA_VERSION;
+use crate::util::reference_mismatch;
+use anyhow::{Context, bail};
+use needletail::parse_fastx_file;
+use noodles_bam as bam;
+use serde_json::{Value, json};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Check that the header of each BAM file of the sample (and of the control sample, if any)
+/// can be parsed, and that all of them are against the same reference; if a reference is
+/// given with `--verify-reference`, also check that it agrees with the headers.
+fn check_alignments(args: &Args) -> anyhow::Result<Value> {
+    let mut header = None;
+    let mut files = Vec::new();
+    let paths = args.alignments.iter().chain(args.control_alignments.iter());
+    for path in paths {
+        let (afile, _) = object_store_io::open_input(path, args.io_backend)?;
+        let mut reader = bam::io::Reader::new(afile);
+        let file_header = alignment_parser::read_and_verify_header(
+            &mut reader,
+            path,
+            args.single_cell && !args.assume_collated,
+        )
+        .with_context(|| format!("could not parse the header of {}", path.display()))?;
+        // the first record is parsed too, to catch files that are not BAM past the header
+        let has_records = match reader.record_bufs(&file_header).next() {
+            Some(Ok(_)) => true,
+            Some(Err(e)) => {
+                bail!("could not parse the records of {}: {}", path.display(), e)
+            }
+            None => {
+                warn!("{} contains no alignment records.", path.display());
+                false
+            }
+        };
+        files.push(json!({
+            "path": path,
+            "num_targets": file_header.reference_sequences().len(),
+            "has_records": has_records,
+        }));
+        match header {
+            None => header = Some(file_header),
+            Some(ref first) => alignment_parser::verify_headers_agree(
+                first,
+                &args.alignments[0],
+                &file_header,
+                path,
+            )?,
+        }
+    }
+    let header = header.expect("at least one alignment file");
+    let digest = digest_utils::digest_from_header(&header)?;
+
+    let reference_agrees = match args.verify_reference {
+        Some(ref reference) if object_store_io::is_remote(reference) => {
+            warn!(
+                "not verifying the remote reference {} during validation.",
+                reference.display()
+            );
+            None
+        }
+        Some(ref reference) => Some(digest_utils::verify_reference_digest(
+            &digest,
+            reference,
+            crate::is_fasta(reference)?,
+            args.reference_mismatch == ReferenceMismatchMode::Fail,
+        )?),
+        None => None,
+    };
+    Ok(json!({
+        "files": files,
+        "digest": digest.to_json(),
+        "reference_agrees": reference_agrees,
+    }))
+}
+
+/// Check that the first record of each of the read files can be parsed.
+fn check_reads(reads: &[std::path::PathBuf]) -> anyhow::Result<Value> {
+    let mut files = Vec::new();
+    for path in reads {
+        if object_store_io::is_remote(path) {
+            warn!(
+                "not checking the remote read file {} during validation.",
+                path.display()
+            );
+            files.push(json!({ "path": path, "checked": false }));
+            continue;
+        }
+        let mut reader = parse_fastx_file(path)
+            .with_context(|| format!("could not read the read file {}", path.display()))?;
+        let format = match reader.next() {
+            Some(rec) => {
+                let rec = rec
+                    .with_context(|| format!("could not parse the reads of {}", path.display()))?;
+                if rec.qual().is_some() {
+                    "fastq"
+                } else {
+                    "fasta"
+                }
+            }
+            None => bail!("the read file {} contains no reads", path.display()),
+        };
+        files.push(json!({ "path": path, "checked": true, "format": format }));
+    }
+    Ok(json!(files))
+}
+
+/// Check that the reference of a read-based run can be parsed (if it is a FASTA file), or
+/// read the digest of the reference from its footer (if it is a minimap2 index), without
+/// building the index.
+fn check_reference(reference: &Path, args: &Args) -> anyhow::Result<Value> {
+    if object_store_io::is_remote(reference) {
+        warn!(
+            "not checking the remote reference {} during validation.",
+            reference.display()
+        );
+        return Ok(json!({ "path": reference, "checked": false }));
+    }
+    if !reference.is_file() {
+        bail!("the reference {} does not exist", reference.display());
+    }
+    if crate::is_fasta(reference)? {
+        let seqs = reference_mismatch::read_reference_seqs(reference, true)?;
+        if seqs.is_empty() {
+            bail!(
+                "the reference {} contains no sequences",
+                reference.display()
+            );
+        }
+        Ok(json!({
+            "path": reference,
+            "checked": true,
+            "kind": "fasta",
+            "num_targets": seqs.len(),
+        }))
+    } else {
+        if args.decoys.is_some() {
+            bail!("`--decoys` requires the `--reference` to be a FASTA file, rather than an index");
+        }
+        let path = reference
+            .to_str()
+            .context("could not convert reference path to string")?;
+        // an index not built by oarfish has no digest footer; its digest is computed
+        // from the index itself once it is loaded
+        let digest = digest_utils::read_digest_from_mm2_index(path)
+            .ok()
+            .map(|d| d.to_json());
+        Ok(json!({
+            "path": reference,
+            "checked": true,
+            "kind": "minimap2_index",
+            "digest": digest,
+        }))
+    }
+}
+
+/// Check that each of the auxiliary input files (e.g. `--txp-to-gene`) exists.
+fn check_files(args: &Args) -> anyhow::Result<Value> {
+    let files = [
+        ("keep-transcripts", &args.keep_transcripts),
+        ("exclude-transcripts", &args.exclude_transcripts),
+        ("read-filter-plugin", &args.read_filter_plugin),
+        ("sequencing-summary", &args.sequencing_summary),
+        ("usa-t2g", &args.usa_t2g),
+        ("hto-reads", &args.hto_reads),
+        ("hto-list", &args.hto_list),
+        ("spot-coordinates", &args.spot_coordinates),
+        ("mito-txps", &args.mito_txps),
+        ("collapse-rules", &args.collapse_rules),
+        ("txp-weights", &args.txp_weights),
+        ("eff-len-dist", &args.eff_len_dist),
+        ("txp-to-gene", &args.txp_to_gene),
+        ("adaptive-sampling", &args.adaptive_sampling),
+        ("biotypes", &args.biotypes),
+        ("contaminants", &args.contaminants),
+        ("spike-in-concentrations", &args.spike_in_concentrations),
+        ("taxonomy", &args.taxonomy),
+        ("kde-model", &args.kde_model),
+    ];
+    let mut checked = serde_json::Map::new();
+    for (name, path) in files {
+        let Some(path) = path else {
+            continue;
+        };
+        if !object_store_io::is_remote(path) && !path.is_file() {
+            bail!("the --{} file {} does not exist", name, path.display());
+        }
+        checked.insert(name.to_owned(), json!(path));
+    }
+    Ok(Value::Object(checked))
+}
+
+/// Check the inputs and options of the run described by `args` (with the resolved alignment
+/// filters `filter_opts`) without aligning or quantifying any reads, and print the resolved
+/// configuration, along with a summary of the inputs, as JSON to stdout.
+pub fn validate_only(args: &Args, filter_opts: &AlignmentFilters) -> anyhow::Result<()> {
+    info!("validating the inputs and options of the run, without quantifying.");
+    let mut inputs = serde_json::Map::new();
+    if !args.alignments.is_empty() {
+        inputs.insert("alignments".to_owned(), check_alignments(args)?);
+    }
+    if let Some(ref reads) = args.reads {
+        inputs.insert("reads".to_owned(), check_reads(reads)?);
+    }
+    if let Some(ref cache) = args.from_alignment_cache {
+        let header = alignment_cache::read_cache_header(cache)?;
+        inputs.insert(
+            "alignment_cache".to_owned(),
+            json!({
+                "oarfish_version": header.oarfish_version,
+                "num_targets": header.targets.len(),
+                "num_reads": header.num_reads,
+                "num_alignments": header.num_alignments,
+                "has_names": header.has_names,
+            }),
+        );
+    }
+    if let Some(ref reference) = args.reference {
+        inputs.insert("reference".to_owned(), check_reference(reference, args)?);
+    }
+    inputs.insert("files".to_owned(), check_files(args)?);
+    let output_dir = args.output.parent().filter(|p| !p.as_os_str().is_empty());
+    inputs.insert(
+        "output_dir_exists".to_owned(),
+        json!(output_dir.is_none_or(Path::is_dir)),
+    );
+
+    let config = json!({
+        "oarfish_version": env!("CARGO_PKG_VERSION"),
+        "output_schema_version": OUTPUT_SCHEMA_VERSION,
+        "args": args,
+        "filter_options": filter_opts,
+        "inputs": inputs,
+    });
+    println!("{}", serde_json::to_string_pretty(&config)?);
+    info!("the inputs and options are valid.");
+    Ok(())
+}
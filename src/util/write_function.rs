@@ -1,17 +1,26 @@
-use crate::prog_opts::ReadAssignmentProbOut;
-use crate::util::oarfish_types::EMInfo;
-use crate::util::parquet_utils;
+use crate::prog_opts::{OutputCompression, OutputFormat, ReadAssignmentProbOut};
+use crate::util::adaptive_sampling::AdaptiveSamplingResult;
+use crate::util::compressed_writer::{CompressedWriter, Encoder};
+use crate::util::duplicates::DuplicateResult;
+use crate::util::infrep_spill::ReplicateSpill;
+use crate::util::lanes::LaneResult;
+use crate::util::oarfish_types::{AlnInfo, ChimeraTable, EMInfo, EmIteration};
+use crate::util::parquet_utils::{self, TableWriter};
+use crate::util::provenance;
+use crate::util::read_length_strata::StrataResult;
 use itertools::izip;
 
+use anyhow::Context;
 use arrow2::{
-    array::Array,
+    array::{Array, Float64Array, UInt64Array, Utf8Array},
     chunk::Chunk,
-    datatypes::{Field, Schema},
+    datatypes::{DataType, Field, Schema},
 };
-use either::Either;
-use lz4::EncoderBuilder;
+use crossbeam::channel::bounded;
+use num_format::{Locale, ToFormattedString};
 use path_tools::WithAdditionalExtension;
 use swapvec::SwapVec;
+use tracing::info;
 
 use std::path::{Path, PathBuf};
 use std::{
@@ -22,11 +31,17 @@ use std::{
     io::{self, BufWriter, Write},
 };
 
+/// Write the metadata of the single-cell counts (whose matrix is written as the
+/// cells are quantified). The features are the transcripts of `header` or, for
+/// USA-mode counts, the `genes` (listed once, although each has a spliced, an
+/// unspliced and an ambiguous column). If a gene-level count matrix was also
+/// written, its columns, the `gene_matrix_genes`, are written as well.
 pub fn write_single_cell_output(
     output: &PathBuf,
     info: serde_json::Value,
     header: &noodles_sam::header::Header,
-    counts: &sprs::TriMatI<f32, u32>,
+    genes: Option<&[String]>,
+    gene_matrix_genes: Option<&[String]>,
 ) -> io::Result<()> {
     // if there is a parent directory
     if let Some(p) = output.parent() {
@@ -44,27 +59,44 @@ pub fn write_single_cell_output(
             .write(true)
             .create(true)
             .truncate(true)
-            .open(info_path)
-            .expect("Couldn't create output file");
+            .open(info_path)?;
 
         serde_json::ser::to_writer_pretty(write, &info)?;
     }
 
-    let out_path = output.with_additional_extension(".count.mtx");
-    sprs::io::write_matrix_market(out_path, counts)?;
-
     let out_path = output.with_additional_extension(".features.txt");
     File::create(&out_path)?;
     let write = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(out_path)
-        .expect("Couldn't create output file");
+        .open(out_path)?;
     let mut writer = BufWriter::new(write);
 
-    for (rseq, _rmap) in header.reference_sequences().iter() {
-        writeln!(writer, "{}", rseq).expect("Couldn't write to output file.");
+    match genes {
+        Some(genes) => {
+            for g in genes {
+                writeln!(writer, "{}", g)?;
+            }
+        }
+        None => {
+            for (rseq, _rmap) in header.reference_sequences().iter() {
+                writeln!(writer, "{}", rseq)?;
+            }
+        }
+    }
+
+    // the columns of the gene-level count matrix, if one was written
+    if let Some(genes) = gene_matrix_genes {
+        let write = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output.with_additional_extension(".gene.features.txt"))?;
+        let mut writer = BufWriter::new(write);
+        for g in genes {
+            writeln!(writer, "{}", g)?;
+        }
     }
     Ok(())
 }
@@ -76,7 +108,13 @@ pub fn write_output(
     header: &noodles_sam::header::Header,
     counts: &[f64],
     aux_counts: &[crate::util::aux_counts::CountInfo],
-) -> io::Result<()> {
+    excluded: Option<&[bool]>,
+    format: OutputFormat,
+    compression: Option<OutputCompression>,
+) -> anyhow::Result<()> {
+    // the transcripts excluded from the output (if any) are skipped
+    let is_excluded = |i: usize| excluded.is_some_and(|ex| ex[i]);
+
     // if there is a parent directory
     if let Some(p) = output.parent() {
         // unless this was a relative path with one component,
@@ -93,57 +131,301 @@ pub fn write_output(
             .write(true)
             .create(true)
             .truncate(true)
-            .open(info_path)
-            .expect("Couldn't create output file");
+            .open(&info_path)
+            .with_context(|| format!("could not create {}", info_path.display()))?;
 
         serde_json::ser::to_writer_pretty(write, &info)?;
     }
 
+    let text_compression = compression.unwrap_or(OutputCompression::None);
     let out_path = output.with_additional_extension(".quant");
-    File::create(&out_path)?;
+    let mut writer = CompressedWriter::create(&out_path, text_compression)?;
 
-    let write = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(out_path)
-        .expect("Couldn't create output file");
-    let mut writer = BufWriter::new(write);
-
-    writeln!(writer, "tname\tlen\tnum_reads").expect("Couldn't write to output file.");
+    provenance::write_tsv_comment(&mut writer)?;
+    writeln!(writer, "tname\tlen\tnum_reads")?;
     // loop over the transcripts in the header and fill in the relevant
     // information here.
 
     for (i, (rseq, rmap)) in header.reference_sequences().iter().enumerate() {
-        writeln!(writer, "{}\t{}\t{}", rseq, rmap.length(), counts[i])
-            .expect("Couldn't write to output file.");
+        if is_excluded(i) {
+            continue;
+        }
+        writeln!(writer, "{}\t{}\t{}", rseq, rmap.length(), counts[i])?;
+    }
+    writer.finish()?;
+
+    // the same table, in the requested tabular format
+    if let Some(ext) = format.table_extension() {
+        write_quant_table(
+            &output.with_additional_extension(&format!(".quant.{}", ext)),
+            header,
+            counts,
+            excluded,
+            format,
+            compression,
+        )?;
     }
 
     // write the auxiliary count info
     let out_path = output.with_additional_extension(".ambig_info.tsv");
-    File::create(&out_path)?;
-
-    let write = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(out_path)
-        .expect("Couldn't create output file");
-    let mut writer = BufWriter::new(write);
+    let mut writer = CompressedWriter::create(&out_path, text_compression)?;
+    provenance::write_tsv_comment(&mut writer)?;
 
-    writeln!(writer, "unique_reads\tambig_reads\ttotal_reads")
-        .expect("Couldn't write to output file.");
+    writeln!(writer, "unique_reads\tambig_reads\ttotal_reads")?;
     // loop over the transcripts in the header and fill in the relevant
     // information here.
 
     for (i, (_rseq, _rmap)) in header.reference_sequences().iter().enumerate() {
+        if is_excluded(i) {
+            continue;
+        }
         let total = aux_counts[i].total_count;
         let unique = aux_counts[i].unique_count;
         let ambig = total.saturating_sub(unique);
-        writeln!(writer, "{}\t{}\t{}", unique, ambig, total)
-            .expect("Couldn't write to output file.");
+        writeln!(writer, "{}\t{}\t{}", unique, ambig, total)?;
     }
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Write the estimated `counts` of the transcripts in `header` (other than the `excluded`
+/// ones) as a table (with the columns of the `.quant` file) in `format` to `path`.
+fn write_quant_table(
+    path: &Path,
+    header: &noodles_sam::header::Header,
+    counts: &[f64],
+    excluded: Option<&[bool]>,
+    format: OutputFormat,
+    compression: Option<OutputCompression>,
+) -> anyhow::Result<()> {
+    let kept: Vec<usize> = (0..counts.len())
+        .filter(|i| !excluded.is_some_and(|ex| ex[*i]))
+        .collect();
+    let refs = header.reference_sequences();
+    let names = Utf8Array::<i32>::from_iter_values(kept.iter().map(|i| {
+        let (name, _) = refs.get_index(*i).expect("valid transcript id");
+        name.to_string()
+    }));
+    let lens = UInt64Array::from_vec(
+        kept.iter()
+            .map(|i| {
+                let (_, rmap) = refs.get_index(*i).expect("valid transcript id");
+                rmap.length().get() as u64
+            })
+            .collect(),
+    );
+    let num_reads = Float64Array::from_vec(kept.iter().map(|i| counts[*i]).collect());
+    let schema = Schema::from(vec![
+        Field::new("tname", DataType::Utf8, false),
+        Field::new("len", DataType::UInt64, false),
+        Field::new("num_reads", DataType::Float64, false),
+    ]);
+    let chunk = Chunk::new(vec![names.boxed(), lens.boxed(), num_reads.boxed()]);
+    parquet_utils::write_table(path, schema, chunk, format, compression)
+}
+
+/// Write the putative fusions recorded in `chimeras` to the file
+/// `<output>.fusion_candidates.tsv` (compressed with `compression`), ordered by
+/// decreasing read support.
+pub fn write_fusion_candidates(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    chimeras: &ChimeraTable,
+    compression: OutputCompression,
+) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".fusion_candidates.tsv");
+    let mut writer = CompressedWriter::create(&out_path, compression)?;
+    provenance::write_tsv_comment(&mut writer)?;
+
+    let mut pairs = chimeras.fusion_pairs.iter().collect::<Vec<_>>();
+    pairs.sort_unstable_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+    let refs = header.reference_sequences();
+    writeln!(writer, "tname_a\ttname_b\tnum_reads")?;
+    for ((a, b), count) in pairs {
+        let (aname, _) = refs.get_index(*a as usize).expect("valid transcript id");
+        let (bname, _) = refs.get_index(*b as usize).expect("valid transcript id");
+        writeln!(writer, "{}\t{}\t{}", aname, bname, count)?;
+    }
+    writer.finish()
+}
+
+/// Write the number of alignments to each transcript, and how many of them were flagged
+/// as likely internal-priming artifacts, as given by `txp_counts`, to the file
+/// `<output>.internal_priming.tsv`.
+pub fn write_internal_priming(
+    output: &PathBuf,
+    txps_name: &[String],
+    txp_counts: &[(u32, u32)],
+    compression: OutputCompression,
+) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".internal_priming.tsv");
+    let mut writer = CompressedWriter::create(&out_path, compression)?;
+    provenance::write_tsv_comment(&mut writer)?;
+
+    writeln!(
+        writer,
+        "tname\tnum_alignments\tnum_internal_priming\tartifact_rate"
+    )?;
+    for (name, (total, flagged)) in txps_name.iter().zip(txp_counts.iter()) {
+        let rate = if *total > 0 {
+            format!("{}", *flagged as f64 / *total as f64)
+        } else {
+            "NA".to_string()
+        };
+        writeln!(writer, "{}\t{}\t{}\t{}", name, total, flagged, rate)?;
+    }
+    writer.finish()
+}
+
+/// Write the per-stratum read-length stratified estimates in `strata` to
+/// the file `<output>.read_length_strata.tsv`.
+pub fn write_read_length_strata(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    strata: &StrataResult,
+) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".read_length_strata.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    let labels = strata
+        .summaries
+        .iter()
+        .map(|s| s.label())
+        .collect::<Vec<String>>()
+        .join("\t");
+    writeln!(writer, "tname\t{}", labels)?;
+
+    for (i, (rseq, _rmap)) in header.reference_sequences().iter().enumerate() {
+        let vals = strata
+            .counts
+            .iter()
+            .map(|c| format!("{}", c[i]))
+            .collect::<Vec<String>>()
+            .join("\t");
+        writeln!(writer, "{}\t{}", rseq, vals)?;
+    }
+    Ok(())
+}
+
+pub fn write_lane_quant(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    lanes: &LaneResult,
+) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".lane_quant.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    let labels = (0..lanes.counts.len())
+        .map(|l| format!("lane_{}", l))
+        .collect::<Vec<String>>()
+        .join("\t");
+    writeln!(writer, "tname\t{}", labels)?;
+
+    for (i, (rseq, _rmap)) in header.reference_sequences().iter().enumerate() {
+        let vals = lanes
+            .counts
+            .iter()
+            .map(|c| format!("{}", c[i]))
+            .collect::<Vec<String>>()
+            .join("\t");
+        writeln!(writer, "{}\t{}", rseq, vals)?;
+    }
+    Ok(())
+}
+
+/// Write the per-decision adaptive sampling estimates in `asr`, along with the
+/// corrected estimates, to the file `<output>.adaptive_sampling.tsv`.
+/// Write the iterations `trace` of the EM to `<output>.em_trace.tsv`.
+pub fn write_em_trace(output: &PathBuf, trace: &[EmIteration]) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".em_trace.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    writeln!(
+        writer,
+        "iteration\tlog_likelihood\tmax_rel_diff\tnum_active_txps"
+    )?;
+    for it in trace {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            it.iteration, it.log_likelihood, it.max_rel_diff, it.num_active_txps
+        )?;
+    }
+    writer.flush()
+}
+
+pub fn write_adaptive_sampling(
+    output: &PathBuf,
+    header: &noodles_sam::header::Header,
+    asr: &AdaptiveSamplingResult,
+) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".adaptive_sampling.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
+
+    let labels = asr
+        .summaries
+        .iter()
+        .map(|s| s.decision.label())
+        .collect::<Vec<&str>>()
+        .join("\t");
+    writeln!(writer, "tname\t{}\tcorrected", labels)?;
+
+    for (i, (rseq, _rmap)) in header.reference_sequences().iter().enumerate() {
+        let vals = asr
+            .counts
+            .iter()
+            .map(|c| format!("{}", c[i]))
+            .collect::<Vec<String>>()
+            .join("\t");
+        writeln!(writer, "{}\t{}\t{}", rseq, vals, asr.corrected[i])?;
+    }
+    Ok(())
+}
+
+pub fn write_duplicates(
+    output: &PathBuf,
+    txps_name: &[String],
+    dups: &DuplicateResult,
+) -> io::Result<()> {
+    let out_path = output.with_additional_extension(".duplicates.tsv");
+    let write = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_path)?;
+    let mut writer = BufWriter::new(write);
+    provenance::write_tsv_comment(&mut writer)?;
 
+    writeln!(writer, "tname\tnum_duplicates")?;
+    for (name, ndup) in txps_name.iter().zip(dups.per_txp.iter()) {
+        writeln!(writer, "{}\t{}", name, ndup)?;
+    }
     Ok(())
 }
 
@@ -177,11 +459,10 @@ pub fn write_out_cdf(
         .write(true)
         .create(true)
         .truncate(true)
-        .open(out_path)
-        .expect("Couldn't create output file");
+        .open(out_path)?;
     let mut writer_cdf = BufWriter::new(write_cdf);
 
-    writeln!(writer_cdf, "Txps_Name\tCDF_Values").expect("Couldn't write to output file.");
+    writeln!(writer_cdf, "Txps_Name\tCDF_Values")?;
     for (i, txp) in txps_name.iter().enumerate() {
         let cdf_values: String = emi.txp_info[i]
             .coverage_prob
@@ -190,30 +471,208 @@ pub fn write_out_cdf(
             .collect::<Vec<String>>()
             .join("\t");
 
-        writeln!(writer_cdf, "{}\t{}", *txp, cdf_values,).expect("Couldn't write to output file.");
+        writeln!(writer_cdf, "{}\t{}", *txp, cdf_values,)?;
     }
 
     Ok(())
 }
 
+/// Write the inferential replicates in `chunk` to `<output>.infreps.pq` or, if
+/// `format` is [OutputFormat::Arrow], to `<output>.infreps.arrow`, compressed with
+/// `compression` (zstd by default).
 pub(crate) fn write_infrep_file(
     output_path: &Path,
     fields: Vec<Field>,
     chunk: Chunk<Box<dyn Array>>,
+    format: OutputFormat,
+    compression: Option<OutputCompression>,
 ) -> anyhow::Result<()> {
+    let (output_path, format) = infrep_path(output_path, format);
+    let schema = Schema::from(fields);
+    parquet_utils::write_table(&output_path, schema, chunk, format, compression)
+}
+
+/// The path (`<output>.infreps.pq` or `<output>.infreps.arrow`) and format of the
+/// inferential replicates of the run with output prefix `output_path`.
+fn infrep_path(output_path: &Path, format: OutputFormat) -> (PathBuf, OutputFormat) {
+    let format = match format {
+        OutputFormat::Arrow => OutputFormat::Arrow,
+        OutputFormat::Tsv | OutputFormat::Parquet => OutputFormat::Parquet,
+    };
+    let ext = format.table_extension().unwrap_or("pq");
     let output_path = output_path
         .to_path_buf()
-        .with_additional_extension(".infreps.pq");
-    let schema = Schema::from(fields);
-    parquet_utils::write_chunk_to_file(output_path.to_str().unwrap(), schema, chunk)
+        .with_additional_extension(&format!(".infreps.{}", ext));
+    (output_path, format)
+}
+
+/// The (approximate) number of bytes of replicates read back from a [ReplicateSpill] for
+/// each row group (or record batch) of the inferential replicates.
+const INFREP_CHUNK_BYTES: usize = 64 << 20;
+
+/// Write the inferential replicates held in `spill` as [write_infrep_file] would, a block
+/// of rows (transcripts) at a time, so that only a block of each replicate is held in memory.
+pub(crate) fn write_spilled_infrep_file(
+    output_path: &Path,
+    spill: &ReplicateSpill,
+    format: OutputFormat,
+    compression: Option<OutputCompression>,
+) -> anyhow::Result<()> {
+    let (output_path, format) = infrep_path(output_path, format);
+    let fields: Vec<Field> = (0..spill.num_reps())
+        .map(|i| Field::new(format!("bootstrap.{}", i), DataType::Float64, false))
+        .collect();
+    let mut writer = TableWriter::try_new(&output_path, Schema::from(fields), format, compression)?;
+    let rows_per_chunk = (INFREP_CHUNK_BYTES / (8 * spill.num_reps().max(1))).max(1024);
+    let mut start = 0;
+    while start < spill.num_rows() {
+        let len = rows_per_chunk.min(spill.num_rows() - start);
+        let arrays = (0..spill.num_reps())
+            .map(|rep| Ok(Float64Array::from_vec(spill.read_rows(rep, start, len)?).boxed()))
+            .collect::<io::Result<Vec<Box<dyn Array>>>>()?;
+        writer.write(Chunk::new(arrays))?;
+        start += len;
+    }
+    writer.finish()
+}
+
+/// Write the reads that align to more than one transcript, along with the
+/// transcripts with which they are compatible and the (normalized) alignment
+/// probability of each, to `<output>.ambiguous_reads.tsv` (compressed with
+/// `compression`). This is used when the EM is skipped, so that the ambiguous
+/// reads can be resolved externally.
+pub fn write_ambiguous_reads(
+    output: &PathBuf,
+    emi: &EMInfo,
+    names_vec: SwapVec<String>,
+    txps_name: &[String],
+    compression: OutputCompression,
+) -> anyhow::Result<()> {
+    let out_path = output.with_additional_extension(".ambiguous_reads.tsv");
+    let mut writer = CompressedWriter::create(&out_path, compression)?;
+    provenance::write_tsv_comment(&mut writer)?;
+
+    writeln!(writer, "read_name\tnum_txps\ttxps\taln_probs")?;
+
+    let mut num_ambig = 0_usize;
+    for ((alns, probs, _coverage_probs), name) in izip!(emi.eq_map.iter(), names_vec.into_iter()) {
+        let rn = name.expect("could not extract read name from file");
+        if alns.len() < 2 {
+            continue;
+        }
+        num_ambig += 1;
+        let read = rn.trim_end_matches('\0');
+        let denom: f32 = probs.iter().sum();
+        let txps = alns
+            .iter()
+            .map(|a| txps_name[a.ref_id as usize].as_str())
+            .collect::<Vec<&str>>()
+            .join(",");
+        let prob_vals = probs
+            .iter()
+            .map(|p| format!("{:.3}", p / denom))
+            .collect::<Vec<String>>()
+            .join(",");
+        writeln!(writer, "{}\t{}\t{}\t{}", read, alns.len(), txps, prob_vals)?;
+    }
+    writer.finish()?;
+    info!(
+        "wrote {} ambiguous reads.",
+        num_ambig.to_formatted_string(&Locale::en)
+    );
+    Ok(())
 }
 
+/// The (approximate) size, in bytes, of the formatted chunks of
+/// assignment probabilities handed to the writer threads.
+const PROB_CHUNK_SIZE: usize = 1 << 20;
+/// The number of formatted chunks that may be queued for a writer
+/// thread before the formatting side blocks.
+const PROB_CHUNK_QUEUE_LEN: usize = 8;
+
+/// Write `vals` to `buf` separated by tabs, formatting each with `fmt`.
+fn write_tab_separated<T>(
+    buf: &mut Vec<u8>,
+    vals: &[T],
+    fmt: impl Fn(&mut Vec<u8>, &T) -> io::Result<()>,
+) -> io::Result<()> {
+    for (i, v) in vals.iter().enumerate() {
+        if i > 0 {
+            buf.push(b'\t');
+        }
+        fmt(buf, v)?;
+    }
+    Ok(())
+}
+
+/// Compute the posterior probabilities of the transcripts to which a read, with
+/// alignments `alns` (with probabilities `probs` and coverage probabilities
+/// `coverage_probs`), is assigned given the estimated `counts` (and, if present, the
+/// `txp_weights` multiplied into the likelihoods by the EM); the transcripts with a
+/// probability of at least [DISPLAY_THRESH] are placed in `txps`, and their probabilities,
+/// renormalized to sum to 1, in `txp_probs`.
+fn read_assignment_probs(
+    alns: &[AlnInfo],
+    probs: &[f32],
+    coverage_probs: &[f64],
+    counts: &[f64],
+    model_coverage: bool,
+    txp_weights: Option<&[f64]>,
+    txps: &mut Vec<usize>,
+    txp_probs: &mut Vec<f64>,
+) {
+    let mut denom = 0.0_f64;
+
+    for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
+        let target_id = a.ref_id as usize;
+        let prob = *p as f64;
+        let cov_prob = if model_coverage { *cp } else { 1.0 };
+        let txp_weight = txp_weights.map_or(1.0, |w| w[target_id]);
+        denom += counts[target_id] * prob * cov_prob * txp_weight;
+    }
+
+    txps.clear();
+    txp_probs.clear();
+
+    let mut denom2 = 0.0_f64;
+
+    for (a, p, cp) in izip!(alns.iter(), probs.iter(), coverage_probs.iter()) {
+        let target_id = a.ref_id as usize;
+        let prob = *p as f64;
+        let cov_prob = if model_coverage { *cp } else { 1.0 };
+        let txp_weight = txp_weights.map_or(1.0, |w| w[target_id]);
+        let nprob = ((counts[target_id] * prob * cov_prob * txp_weight) / denom).clamp(0.0, 1.0);
+        if nprob >= DISPLAY_THRESH {
+            txps.push(target_id);
+            txp_probs.push(nprob);
+            denom2 += nprob;
+        }
+    }
+
+    for p in txp_probs.iter_mut() {
+        *p /= denom2;
+    }
+}
+
+/// The smallest assignment probability of a read to a transcript that is reported.
+const DISPLAY_THRESH: f64 = 0.001;
+
+/// Write the per-read transcript assignment probabilities. If `format` is tabular, they
+/// are written by [write_out_prob_table]; otherwise, the lines are formatted
+/// on the calling thread into large chunks that are passed, through a bounded channel,
+/// to a dedicated writer thread that performs the (optionally compressed) output.
+/// If `num_shards` is greater than 1, the reads are split into `num_shards`
+/// contiguous blocks, each written to its own file (`<output>.prob.<i>`) by its
+/// own writer thread; every shard begins with its own header.
 pub fn write_out_prob(
     output: &PathBuf,
     emi: &EMInfo,
     counts: &[f64],
     names_vec: SwapVec<String>,
     txps_name: &[String],
+    num_shards: usize,
+    format: OutputFormat,
+    compression: Option<OutputCompression>,
 ) -> anyhow::Result<()> {
     if let Some(p) = output.parent() {
         // unless this was a relative path with one component,
@@ -224,97 +683,265 @@ pub fn write_out_prob(
         }
     }
 
+    if format.table_extension().is_some() {
+        return write_out_prob_table(
+            output,
+            emi,
+            counts,
+            names_vec,
+            txps_name,
+            num_shards,
+            format,
+            compression,
+        );
+    }
+
+    // lz4 compression, if requested explicitly, takes precedence over `compression`
     let compressed = matches!(
         emi.eq_map.filter_opts.write_assignment_probs_type,
         Some(ReadAssignmentProbOut::Compressed)
     );
+    let compression = compression.unwrap_or(OutputCompression::None);
+
+    let num_reads = emi.eq_map.len();
+    // never create empty shards
+    let num_shards = num_shards.clamp(1, num_reads.max(1));
+    // shard `i` holds the reads in [shard_bounds[i], shard_bounds[i+1])
+    let shard_bounds: Vec<usize> = (0..=num_shards)
+        .map(|i| i * num_reads / num_shards)
+        .collect();
+
+    let shard_path = |i: usize| -> PathBuf {
+        let mut extension = if num_shards > 1 {
+            format!(".prob.{}", i)
+        } else {
+            String::from(".prob")
+        };
+        if compressed {
+            extension.push_str(".lz4");
+        } else {
+            extension.push_str(compression.extension());
+        }
+        output.with_additional_extension(&extension)
+    };
 
-    let extension = if compressed { ".prob.lz4" } else { ".prob" };
-    let out_path = output.with_additional_extension(extension);
-    File::create(&out_path)?;
+    let model_coverage = emi.eq_map.filter_opts.model_coverage;
 
-    let write_prob = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(out_path)
-        .expect("Couldn't create output file");
+    std::thread::scope(|s| -> anyhow::Result<()> {
+        let mut senders = Vec::with_capacity(num_shards);
+        let mut handles = Vec::with_capacity(num_shards);
+
+        for i in 0..num_shards {
+            let (tx, rx) = bounded::<Vec<u8>>(PROB_CHUNK_QUEUE_LEN);
+            let out_path = shard_path(i);
+            let shard_reads = shard_bounds[i + 1] - shard_bounds[i];
+            handles.push(s.spawn(move || -> anyhow::Result<()> {
+                let mut writer_prob = if compressed {
+                    Encoder::create_lz4(&out_path)?
+                } else {
+                    Encoder::create(&out_path, compression)?
+                };
+
+                writeln!(writer_prob, "{}\t{}", txps_name.len(), shard_reads)?;
+                for tname in txps_name {
+                    writeln!(writer_prob, "{}", tname)?;
+                }
+
+                for chunk in rx {
+                    writer_prob.write_all(&chunk)?;
+                }
+
+                writer_prob.finish()?;
+                Ok(())
+            }));
+            senders.push(tx);
+        }
 
-    let mut writer_prob = if compressed {
-        Either::Right(EncoderBuilder::new().level(4).build(write_prob)?)
-    } else {
-        Either::Left(BufWriter::with_capacity(1024 * 1024, write_prob))
-    };
+        let names_iter = names_vec.into_iter();
+
+        let mut txps = Vec::<usize>::new();
+        let mut txp_probs = Vec::<f64>::new();
+        let mut buf = Vec::<u8>::with_capacity(PROB_CHUNK_SIZE);
+        let mut shard = 0_usize;
+
+        // a chunk can't be sent only if the writer thread of its shard has stopped on
+        // an error, which is reported when the thread is joined below
+        'reads: for (read_idx, ((alns, probs, coverage_probs), name)) in
+            izip!(emi.eq_map.iter(), names_iter).enumerate()
+        {
+            // move on to the next shard, handing off whatever
+            // remains for the current one.
+            while read_idx >= shard_bounds[shard + 1] {
+                if !buf.is_empty() {
+                    let chunk = std::mem::replace(&mut buf, Vec::with_capacity(PROB_CHUNK_SIZE));
+                    if senders[shard].send(chunk).is_err() {
+                        break 'reads;
+                    }
+                }
+                shard += 1;
+            }
 
-    writeln!(writer_prob, "{}\t{}", txps_name.len(), emi.eq_map.len())
-        .expect("couldn't write to prob output file");
-    for tname in txps_name {
-        writeln!(writer_prob, "{}", tname).expect("couldn't write to prob output file");
-    }
+            let rn = name.expect("could not extract read name from file");
+            let read = rn.trim_end_matches('\0');
+
+            read_assignment_probs(
+                &alns,
+                &probs,
+                &coverage_probs,
+                counts,
+                model_coverage,
+                emi.txp_weights.as_deref(),
+                &mut txps,
+                &mut txp_probs,
+            );
+
+            write!(buf, "{}\t{}\t", read, txps.len())?;
+            write_tab_separated(&mut buf, &txps, |b, x| write!(b, "{}", x))?;
+            buf.push(b'\t');
+            write_tab_separated(&mut buf, &txp_probs, |b, x| write!(b, "{:.3}", x))?;
+            buf.push(b'\n');
+
+            if buf.len() >= PROB_CHUNK_SIZE {
+                let chunk = std::mem::replace(&mut buf, Vec::with_capacity(PROB_CHUNK_SIZE));
+                if senders[shard].send(chunk).is_err() {
+                    break 'reads;
+                }
+            }
+        }
 
-    let model_coverage = emi.eq_map.filter_opts.model_coverage;
-    //let names_vec = emi.eq_map.take_read_names_vec()?;
+        let all_sent = buf.is_empty() || senders[shard].send(buf).is_ok();
+        // closing the channels lets the writer threads finish
+        drop(senders);
 
-    let names_iter = names_vec.into_iter();
+        // join every writer, reporting the first error of any of them
+        let mut result = Ok(());
+        for h in handles {
+            let r = h.join().expect("prob writer thread panicked");
+            if result.is_ok() {
+                result = r;
+            }
+        }
+        result?;
+        anyhow::ensure!(
+            all_sent,
+            "a writer of the assignment probabilities stopped early"
+        );
+        Ok(())
+    })
+}
+
+/// The number of (read, transcript) rows of assignment probabilities in each
+/// row group (or record batch) of a tabular output.
+const PROB_TABLE_CHUNK_ROWS: usize = 1 << 20;
+
+/// Write the accumulated rows of assignment probabilities (if any) to `writer`, and
+/// clear them.
+fn flush_prob_rows(
+    writer: &mut TableWriter,
+    read_col: &mut Vec<String>,
+    txp_col: &mut Vec<&str>,
+    prob_col: &mut Vec<f64>,
+) -> anyhow::Result<()> {
+    if read_col.is_empty() {
+        return Ok(());
+    }
+    let chunk = Chunk::new(vec![
+        Utf8Array::<i32>::from_slice(read_col.as_slice()).boxed(),
+        Utf8Array::<i32>::from_slice(txp_col.as_slice()).boxed(),
+        Float64Array::from_slice(prob_col.as_slice()).boxed(),
+    ]);
+    read_col.clear();
+    txp_col.clear();
+    prob_col.clear();
+    writer.write(chunk)
+}
 
+/// Write the per-read transcript assignment probabilities as a table in `format`
+/// (compressed with `compression`), with
+/// one row (`read_name`, `tname`, `prob`) for each transcript to which a read is assigned,
+/// to `<output>.prob.<ext>`. If `num_shards` is greater than 1, the reads are split into
+/// `num_shards` contiguous blocks, each written to its own file (`<output>.prob.<i>.<ext>`).
+fn write_out_prob_table(
+    output: &Path,
+    emi: &EMInfo,
+    counts: &[f64],
+    names_vec: SwapVec<String>,
+    txps_name: &[String],
+    num_shards: usize,
+    format: OutputFormat,
+    compression: Option<OutputCompression>,
+) -> anyhow::Result<()> {
+    let ext = format
+        .table_extension()
+        .expect("tabular output must have an extension");
+    let num_reads = emi.eq_map.len();
+    // never create empty shards
+    let num_shards = num_shards.clamp(1, num_reads.max(1));
+    let shard_bounds: Vec<usize> = (0..=num_shards)
+        .map(|i| i * num_reads / num_shards)
+        .collect();
+    let shard_path = |i: usize| -> PathBuf {
+        if num_shards > 1 {
+            output.with_additional_extension(&format!(".prob.{}.{}", i, ext))
+        } else {
+            output.with_additional_extension(&format!(".prob.{}", ext))
+        }
+    };
+    let schema = Schema::from(vec![
+        Field::new("read_name", DataType::Utf8, false),
+        Field::new("tname", DataType::Utf8, false),
+        Field::new("prob", DataType::Float64, false),
+    ]);
+
+    let mut read_col = Vec::<String>::with_capacity(PROB_TABLE_CHUNK_ROWS);
+    let mut txp_col = Vec::<&str>::with_capacity(PROB_TABLE_CHUNK_ROWS);
+    let mut prob_col = Vec::<f64>::with_capacity(PROB_TABLE_CHUNK_ROWS);
+    let model_coverage = emi.eq_map.filter_opts.model_coverage;
     let mut txps = Vec::<usize>::new();
     let mut txp_probs = Vec::<f64>::new();
+    let mut shard = 0_usize;
+    let mut writer = TableWriter::try_new(&shard_path(0), schema.clone(), format, compression)?;
 
-    for ((alns, probs, coverage_probs), name) in izip!(emi.eq_map.iter(), names_iter) {
-        let mut denom = 0.0_f64;
-
-        for (a, p, cp) in izip!(alns, probs, coverage_probs) {
-            let target_id = a.ref_id as usize;
-            let prob = *p as f64;
-            let cov_prob = if model_coverage { *cp } else { 1.0 };
-            denom += counts[target_id] * prob * cov_prob;
+    for (read_idx, ((alns, probs, coverage_probs), name)) in
+        izip!(emi.eq_map.iter(), names_vec.into_iter()).enumerate()
+    {
+        // move on to the next shard, finishing the current one
+        while read_idx >= shard_bounds[shard + 1] {
+            flush_prob_rows(&mut writer, &mut read_col, &mut txp_col, &mut prob_col)?;
+            shard += 1;
+            let next =
+                TableWriter::try_new(&shard_path(shard), schema.clone(), format, compression)?;
+            std::mem::replace(&mut writer, next).finish()?;
         }
 
         let rn = name.expect("could not extract read name from file");
         let read = rn.trim_end_matches('\0');
 
-        write!(writer_prob, "{}\t", read).expect("couldn't write to prob output file");
-
-        txps.clear();
-        txp_probs.clear();
-
-        const DISPLAY_THRESH: f64 = 0.001;
-        let mut denom2 = 0.0_f64;
-
-        for (a, p, cp) in izip!(alns, probs, coverage_probs) {
-            let target_id = a.ref_id as usize;
-            let prob = *p as f64;
-            let cov_prob = if model_coverage { *cp } else { 1.0 };
-            let nprob = ((counts[target_id] * prob * cov_prob) / denom).clamp(0.0, 1.0);
-            if nprob >= DISPLAY_THRESH {
-                txps.push(target_id);
-                txp_probs.push(nprob);
-                denom2 += nprob;
-            }
+        read_assignment_probs(
+            &alns,
+            &probs,
+            &coverage_probs,
+            counts,
+            model_coverage,
+            emi.txp_weights.as_deref(),
+            &mut txps,
+            &mut txp_probs,
+        );
+        for (t, p) in txps.iter().zip(txp_probs.iter()) {
+            read_col.push(read.to_owned());
+            txp_col.push(txps_name[*t].as_str());
+            prob_col.push(*p);
         }
 
-        for p in txp_probs.iter_mut() {
-            *p /= denom2;
+        if read_col.len() >= PROB_TABLE_CHUNK_ROWS {
+            flush_prob_rows(&mut writer, &mut read_col, &mut txp_col, &mut prob_col)?;
         }
-
-        let txp_ids = txps
-            .iter()
-            .map(|x| format!("{}", x))
-            .collect::<Vec<String>>()
-            .join("\t");
-        let prob_vals = txp_probs
-            .iter()
-            .map(|x| format!("{:.3}", x))
-            .collect::<Vec<String>>()
-            .join("\t");
-        writeln!(writer_prob, "{}\t{}\t{}", txps.len(), txp_ids, prob_vals)
-            .expect("couldn't write to prob output file");
-    }
-
-    if let Either::Right(lz4) = writer_prob {
-        let (_output, result) = lz4.finish();
-        result?;
     }
-
+    flush_prob_rows(&mut writer, &mut read_col, &mut txp_col, &mut prob_col)?;
+    writer.finish()?;
+    info!(
+        "wrote the assignment probabilities of {} reads.",
+        num_reads.to_formatted_string(&Locale::en)
+    );
     Ok(())
 }